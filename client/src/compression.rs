@@ -0,0 +1,138 @@
+use std::borrow::Cow;
+use std::io::Read;
+
+use anyhow::{Context, Result, bail};
+use backup_sync_protocol::{Compression, FileOperation};
+
+/// Content smaller than this is sent as is, compressing it would not save much
+pub const COMPRESSION_THRESHOLD: usize = 4 * 1024;
+
+/// Level origins compress at, zstd's default
+pub const DEFAULT_LEVEL: i32 = 3;
+
+/// Largest content a backup decompresses outside of transfers, whose chunks are limited
+/// to their chunk size
+pub const MAX_DECOMPRESSED_LEN: usize = 256 * 1024 * 1024;
+
+/// Bytes looked at to tell whether content is already compressed
+const SAMPLE_LEN: usize = 4 * 1024;
+
+/// Entropy of the sample, in bits per byte, above which content is taken as already
+/// compressed (archives, media) and sent as is
+const MAX_ENTROPY: f64 = 7.5;
+
+/// Compresses the content of `operation` when it is worth it. Operations without
+/// content, or already compressed, are returned unchanged.
+#[must_use]
+pub fn compress_operation(operation: FileOperation) -> FileOperation {
+    match operation {
+        FileOperation::CreateFile {
+            relative_path,
+            content,
+            hash,
+            compression: None,
+        } => {
+            let (content, compression) = compress(content);
+            FileOperation::CreateFile {
+                relative_path,
+                content,
+                hash,
+                compression,
+            }
+        }
+        FileOperation::ApplyDelta {
+            transfer_id,
+            relative_path,
+            delta,
+            hash,
+            compression: None,
+        } => {
+            let (delta, compression) = compress(delta);
+            FileOperation::ApplyDelta {
+                transfer_id,
+                relative_path,
+                delta,
+                hash,
+                compression,
+            }
+        }
+        FileOperation::FileChunk {
+            transfer_id,
+            chunk_index,
+            data,
+            compression: None,
+        } => {
+            let (data, compression) = compress(data);
+            FileOperation::FileChunk {
+                transfer_id,
+                chunk_index,
+                data,
+                compression,
+            }
+        }
+        operation => operation,
+    }
+}
+
+/// `data` compressed with zstd, or unchanged when it is small, looks already compressed
+/// or does not shrink.
+#[must_use]
+pub fn compress(data: Vec<u8>) -> (Vec<u8>, Option<Compression>) {
+    if data.len() < COMPRESSION_THRESHOLD || looks_compressed(&data) {
+        return (data, None);
+    }
+    match zstd::bulk::compress(&data, DEFAULT_LEVEL) {
+        Ok(compressed) if compressed.len() < data.len() => (
+            compressed,
+            Some(Compression::Zstd {
+                level: DEFAULT_LEVEL,
+            }),
+        ),
+        _ => (data, None),
+    }
+}
+
+/// Content as it was before compression, refusing content that decompresses to more
+/// than `max_len` bytes.
+pub fn decompress(
+    data: &[u8],
+    compression: Option<Compression>,
+    max_len: usize,
+) -> Result<Cow<'_, [u8]>> {
+    match compression {
+        None => Ok(Cow::Borrowed(data)),
+        Some(Compression::Zstd { .. }) => {
+            let mut decompressed = Vec::new();
+            zstd::stream::read::Decoder::new(data)
+                .and_then(|decoder| {
+                    decoder
+                        .take(max_len as u64 + 1)
+                        .read_to_end(&mut decompressed)
+                })
+                .context("Failed to decompress zstd content")?;
+            if decompressed.len() > max_len {
+                bail!("Decompressed content is larger than {max_len} bytes");
+            }
+            Ok(Cow::Owned(decompressed))
+        }
+    }
+}
+
+/// Cheap guess from the byte distribution of the start of `data`
+fn looks_compressed(data: &[u8]) -> bool {
+    let sample = &data[..data.len().min(SAMPLE_LEN)];
+    let mut counts = [0u32; 256];
+    for &byte in sample {
+        counts[usize::from(byte)] += 1;
+    }
+    let len = sample.len() as f64;
+    let entropy: f64 = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = f64::from(count) / len;
+            -p * p.log2()
+        })
+        .sum();
+    entropy > MAX_ENTROPY
+}
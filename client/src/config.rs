@@ -0,0 +1,169 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+use crate::ignore_rules::IgnoreRules;
+use crate::logging::LogFormat;
+use crate::synchronizer::SyncOptions;
+
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(200);
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+
+/// A source folder and the folder it is mirrored into.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FolderPair {
+    pub source: PathBuf,
+    pub backup: PathBuf,
+}
+
+impl FolderPair {
+    /// Parses `SRC:DST` as given to `--pair`. The pair is split on the last `:` that is
+    /// not the colon of a Windows drive letter, so `C:\src:D:\dst` is two folders.
+    pub fn parse(pair: &str) -> Result<Self, String> {
+        let separator = pair
+            .match_indices(':')
+            .map(|(index, _)| index)
+            .rfind(|&index| !is_drive_colon(pair, index));
+        match separator.map(|index| (&pair[..index], &pair[index + 1..])) {
+            Some((source, backup)) if !source.is_empty() && !backup.is_empty() => Ok(Self {
+                source: PathBuf::from(source),
+                backup: PathBuf::from(backup),
+            }),
+            _ => Err(format!("expected SRC:DST, got {pair:?}")),
+        }
+    }
+}
+
+/// Whether the `:` at `index` follows a drive letter starting one of the paths of `pair`
+/// and is followed by a path separator, as in `C:\`.
+fn is_drive_colon(pair: &str, index: usize) -> bool {
+    let bytes = pair.as_bytes();
+    let starts_path = index == 1 || (index >= 2 && bytes[index - 2] == b':');
+    starts_path
+        && bytes[index - 1].is_ascii_alphabetic()
+        && matches!(bytes.get(index + 1), Some(b'\\' | b'/'))
+}
+
+/// Client settings read from a TOML file.
+///
+/// ```toml
+/// log_level = "debug"
+/// log_format = "json"
+/// debounce_ms = 500
+///
+/// [[folders]]
+/// source = "/home/me/documents"
+/// backup = "/mnt/backup/documents"
+///
+/// [sync]
+/// when_delete_keep_backup = true
+/// excludes = ["*.log", "target/"]
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub folders: Vec<FolderPair>,
+    pub sync: SyncOptions,
+    pub debounce_ms: Option<u64>,
+    pub log_level: Option<String>,
+    pub log_format: Option<LogFormat>,
+}
+
+/// Values passed explicitly on the command line; they take precedence over the file.
+#[derive(Debug, Clone, Default)]
+pub struct CliOverrides {
+    /// Replace the pairs of the file when not empty
+    pub folders: Vec<FolderPair>,
+    pub when_missing_preserve_backup: bool,
+    pub when_conflict_preserve_backup: bool,
+    pub when_delete_keep_backup: bool,
+    pub dry_run: bool,
+    /// Added to the excludes of the file rather than replacing them
+    pub excludes: Vec<String>,
+    pub debounce_ms: Option<u64>,
+    pub log_level: Option<String>,
+    pub log_format: Option<LogFormat>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file: {path:?}"))?;
+        Self::parse(&content).with_context(|| format!("Invalid config file: {path:?}"))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        // toml's error already points at the offending key and line
+        let config: Self =
+            toml::from_str(content).map_err(|e| anyhow::anyhow!("{}", e.to_string().trim_end()))?;
+        for pattern in config.sync.excludes() {
+            IgnoreRules::parse_exclude(pattern)
+                .map_err(|e| anyhow::anyhow!("sync.excludes: {e}"))?;
+        }
+        Ok(config)
+    }
+
+    /// Applies the command line on top of the file. Boolean flags can only switch
+    /// an option on, since an absent flag is indistinguishable from `false`.
+    #[must_use]
+    pub fn merge(mut self, cli: CliOverrides) -> Self {
+        if !cli.folders.is_empty() {
+            self.folders = cli.folders;
+        }
+
+        let sync = self.sync;
+        let when_missing = sync.when_missing_preserve_backup() || cli.when_missing_preserve_backup;
+        let when_conflict =
+            sync.when_conflict_preserve_backup() || cli.when_conflict_preserve_backup;
+        let when_delete = sync.when_delete_keep_backup() || cli.when_delete_keep_backup;
+        let dry_run = sync.is_dry_run() || cli.dry_run;
+        let mut excludes = sync.excludes().to_vec();
+        excludes.extend(cli.excludes);
+        self.sync = sync
+            .with_when_missing_preserve_backup(when_missing)
+            .with_when_conflict_preserve_backup(when_conflict)
+            .with_when_delete_keep_backup(when_delete)
+            .with_dry_run(dry_run)
+            .with_excludes(excludes);
+
+        if cli.debounce_ms.is_some() {
+            self.debounce_ms = cli.debounce_ms;
+        }
+        if cli.log_level.is_some() {
+            self.log_level = cli.log_level;
+        }
+        if cli.log_format.is_some() {
+            self.log_format = cli.log_format;
+        }
+        self
+    }
+
+    /// The folder pairs to synchronize, at least one.
+    pub fn folders(&self) -> Result<&[FolderPair]> {
+        if self.folders.is_empty() {
+            bail!(
+                "No folder pair given: pass --pair, --source-local and --backup-local or set [[folders]] in the config file"
+            );
+        }
+        Ok(&self.folders)
+    }
+
+    #[must_use]
+    pub fn debounce(&self) -> Duration {
+        self.debounce_ms
+            .map_or(DEFAULT_DEBOUNCE, Duration::from_millis)
+    }
+
+    #[must_use]
+    pub fn log_level(&self) -> &str {
+        self.log_level.as_deref().unwrap_or(DEFAULT_LOG_LEVEL)
+    }
+
+    #[must_use]
+    pub fn log_format(&self) -> LogFormat {
+        self.log_format.unwrap_or_default()
+    }
+}
@@ -1,7 +1,7 @@
 use anyhow::{Context, Result, anyhow};
-use backup_sync_protocol::FileOperation;
+use backup_sync_protocol::{FileOperation, RelativePath, TransferKind};
 use blake3::Hasher;
-use librsync::whole::{delta, patch};
+use librsync::whole::{delta, patch, signature};
 use std::fs::File;
 use std::io::{self, BufReader, Cursor, Read, Write};
 use std::path::{Path, PathBuf};
@@ -22,8 +22,8 @@ impl<R: Read> HashingReader<R> {
             hasher: Hasher::new(),
         }
     }
-    fn finalize(&self) -> String {
-        self.hasher.finalize().to_hex().to_string()
+    fn finalize(&self) -> blake3::Hash {
+        self.hasher.finalize()
     }
 }
 
@@ -72,6 +72,7 @@ impl ChunkedDeltaWriter {
             transfer_id: self.transfer_id,
             chunk_index: self.chunk_counter,
             data: chunk_data,
+            compression: None,
         };
 
         self.chunk_counter += 1;
@@ -113,6 +114,7 @@ impl Write for ChunkedDeltaWriter {
 #[instrument(skip(signature_data, tx))]
 pub fn generate_delta_streamed(
     path: PathBuf,
+    relative_path: RelativePath,
     signature_data: Vec<u8>,
     transfer_id: u64,
     tx: mpsc::Sender<FileOperation>,
@@ -134,7 +136,7 @@ pub fn generate_delta_streamed(
     // This avoids the overhead of StartTransfer -> Chunks -> EndTransfer
     if file_size < CHUNK_SIZE as u64 {
         let mut delta_buffer = Vec::new();
-        delta(&mut signature, &mut reader, &mut delta_buffer)
+        delta(&mut reader, &mut signature, &mut delta_buffer)
             .map_err(|e| anyhow!("Failed to compute delta: {e}"))?;
 
         let final_hash = reader.finalize();
@@ -142,9 +144,10 @@ pub fn generate_delta_streamed(
         return tx
             .send(FileOperation::ApplyDelta {
                 transfer_id,
-                relative_path: path,
+                relative_path,
                 delta: delta_buffer,
-                expected_hash: final_hash,
+                hash: final_hash.into(),
+                compression: None,
             })
             .context("Problem by sending ApplyDelta");
     }
@@ -155,14 +158,16 @@ pub fn generate_delta_streamed(
     // 6. Send "StartTransfer" message
     tx.send(FileOperation::StartTransfer {
         transfer_id,
-        relative_path: path.clone(), // Simplify path as needed
+        relative_path,
+        kind: TransferKind::Delta,
         total_size: file_size,
+        chunk_size: CHUNK_SIZE as u64,
     })
     .context("Problem by sending StartTransfer")?;
 
     // 7. Compute Delta (The Heavy Lift)
     // The reader feeds data to librsync, librsync feeds delta to our writer
-    delta(&mut signature, &mut reader, &mut writer)
+    delta(&mut reader, &mut signature, &mut writer)
         .map_err(|e| anyhow!("Failed to compute delta: {e}"))?;
 
     // 8. Finalize
@@ -173,13 +178,34 @@ pub fn generate_delta_streamed(
     // We send a special "Last Chunk" or a specific "End" message containing the Hash.
     tx.send(FileOperation::EndTransfer {
         transfer_id,
-        expected_hash: final_hash,
+        chunk_count: writer.chunk_counter,
+        expected_hash: final_hash.to_hex().to_string(),
     })
     .with_context(|| format!("Failed to send EndTransfer: {path:?}"))?;
 
     Ok(())
 }
 
+/// Rsync signature of the file at `path`, which the origin computes a delta against
+pub fn file_signature(path: &Path) -> Result<Vec<u8>> {
+    let file = File::open(path).with_context(|| format!("Failed to open file: {path:?}"))?;
+    let mut output = Vec::new();
+    signature(&mut BufReader::new(file), &mut output)
+        .map_err(|e| anyhow!("Failed to compute signature of {path:?}: {e}"))?;
+    Ok(output)
+}
+
+/// Delta turning the file behind `signature_data` into the file at `path`, in memory,
+/// with the Blake3 hash of the file at `path`
+pub fn delta_against(path: &Path, signature_data: &[u8]) -> Result<(Vec<u8>, [u8; 32])> {
+    let file = File::open(path).with_context(|| format!("Failed to open file: {path:?}"))?;
+    let mut reader = HashingReader::new(BufReader::new(file));
+    let mut delta_buffer = Vec::new();
+    delta(&mut reader, &mut Cursor::new(signature_data), &mut delta_buffer)
+        .map_err(|e| anyhow!("Failed to compute delta of {path:?}: {e}"))?;
+    Ok((delta_buffer, reader.finalize().into()))
+}
+
 #[instrument(skip(delta))]
 pub fn apply_delta_securely(
     base_path: &Path,
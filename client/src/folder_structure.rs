@@ -13,8 +13,12 @@ pub(crate) struct FolderStructure {
 }
 
 impl FolderStructure {
-    #[instrument(skip(root))]
-    pub(crate) fn new(root: impl Into<PathBuf>) -> std::io::Result<Self> {
+    /// Reads the tree under `root`, calling `on_entry` after each entry.
+    #[instrument(skip(root, on_entry))]
+    pub(crate) fn scan(
+        root: impl Into<PathBuf>,
+        on_entry: &mut impl FnMut(),
+    ) -> std::io::Result<Self> {
         let root = fs::canonicalize(root.into())?;
         let mut entries = HashMap::new();
 
@@ -35,11 +39,20 @@ impl FolderStructure {
             let file_entry = FileEntry::new(path.clone(), sig);
 
             entries.insert(path, file_entry);
+            on_entry();
         }
 
         Ok(Self { root, entries })
     }
 
+    /// A tree without any entry, for a `root` that does not exist yet
+    pub(crate) fn empty(root: PathBuf) -> Self {
+        Self {
+            root,
+            entries: HashMap::new(),
+        }
+    }
+
     pub(crate) fn root(&self) -> &PathBuf {
         &self.root
     }
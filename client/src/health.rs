@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+/// Coarse health level derived from a [`HealthStatus`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HealthLevel {
+    Healthy,
+    /// Still syncing, but some files are waiting to be retried
+    Degraded,
+    /// The backup is unreachable or the last full sync failed
+    Failed,
+}
+
+/// Snapshot of a running [`crate::state::AppState`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthStatus {
+    pub level: HealthLevel,
+    /// Whether the backup root could be stat'ed when the snapshot was taken
+    pub backup_reachable: bool,
+    /// When the last watcher event was processed successfully
+    pub last_event_at: Option<SystemTime>,
+    /// Number of files waiting to be retried because they were locked
+    pub retry_queue_len: usize,
+    /// Outcome of the last full sync, `None` if none ran yet (e.g. in dry run)
+    pub last_full_sync_ok: Option<bool>,
+}
+
+impl HealthStatus {
+    #[must_use]
+    pub fn new(
+        backup_reachable: bool,
+        last_event_at: Option<SystemTime>,
+        retry_queue_len: usize,
+        last_full_sync_ok: Option<bool>,
+    ) -> Self {
+        let level = if !backup_reachable || last_full_sync_ok == Some(false) {
+            HealthLevel::Failed
+        } else if retry_queue_len > 0 {
+            HealthLevel::Degraded
+        } else {
+            HealthLevel::Healthy
+        };
+        Self {
+            level,
+            backup_reachable,
+            last_event_at,
+            retry_queue_len,
+            last_full_sync_ok,
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use globset::Glob;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::path::Path;
+use tracing::warn;
+
+/// Name of the gitignore-style file read from the source root
+pub const IGNORE_FILE_NAME: &str = ".backupsyncignore";
+
+/// Paths excluded from synchronization, combining programmatic excludes with the
+/// rules found in the [`IGNORE_FILE_NAME`] file of the source root.
+#[derive(Debug, Clone)]
+pub struct IgnoreRules {
+    matcher: Gitignore,
+}
+
+impl IgnoreRules {
+    /// Builds the rules for `root`. Programmatic excludes are added first so that the
+    /// ignore file can re-include paths with `!` patterns. Invalid patterns are skipped.
+    #[must_use]
+    pub fn load(root: &Path, excludes: &[String]) -> Self {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in excludes {
+            if let Err(e) = builder.add_line(None, pattern) {
+                warn!("ignoring invalid exclude pattern {pattern:?}: {e}");
+            }
+        }
+
+        let ignore_file = root.join(IGNORE_FILE_NAME);
+        if ignore_file.is_file()
+            && let Some(e) = builder.add(&ignore_file)
+        {
+            warn!("some rules in {ignore_file:?} could not be loaded: {e}");
+        }
+
+        let matcher = builder.build().unwrap_or_else(|e| {
+            warn!("failed to build ignore rules for {root:?}: {e}");
+            Gitignore::empty()
+        });
+        Self { matcher }
+    }
+
+    /// Checks that `pattern` is a valid gitignore-style exclude, so that mistakes in
+    /// user input are reported up front instead of being skipped with a warning. The
+    /// glob is compiled on its own as well, since the builder leaves e.g. unclosed
+    /// classes like `src/[` to match literally.
+    pub fn parse_exclude(pattern: &str) -> Result<String, String> {
+        let invalid =
+            |e: &dyn std::fmt::Display| format!("invalid exclude pattern {pattern:?}: {e}");
+        GitignoreBuilder::new("")
+            .add_line(None, pattern)
+            .map_err(|e| invalid(&e))?;
+        let glob = pattern.strip_prefix('!').unwrap_or(pattern);
+        Glob::new(glob).map_err(|e| invalid(&e))?;
+        Ok(pattern.to_string())
+    }
+
+    /// Whether a path relative to the root, or any of its parent directories, is ignored.
+    #[must_use]
+    pub fn is_ignored(&self, relative: &Path, is_dir: bool) -> bool {
+        if relative.as_os_str().is_empty() || relative.has_root() {
+            return false;
+        }
+        self.matcher
+            .matched_path_or_any_parents(relative, is_dir)
+            .is_ignore()
+    }
+}
@@ -0,0 +1,126 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use fs2::FileExt;
+use tracing::{debug, warn};
+
+/// Overrides where instance locks and other runtime state are kept
+pub const STATE_DIR_ENV: &str = "BACKUP_SYNC_STATE_DIR";
+
+/// Directory for runtime state: `$BACKUP_SYNC_STATE_DIR`, then `$XDG_STATE_HOME/backup-sync`,
+/// then `~/.local/state/backup-sync`, falling back to the system temp directory.
+#[must_use]
+pub fn default_state_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os(STATE_DIR_ENV) {
+        return PathBuf::from(dir);
+    }
+    if let Some(dir) = std::env::var_os("XDG_STATE_HOME") {
+        return PathBuf::from(dir).join("backup-sync");
+    }
+    if let Some(home) = std::env::var_os("HOME") {
+        return PathBuf::from(home).join(".local/state/backup-sync");
+    }
+    std::env::temp_dir().join("backup-sync")
+}
+
+/// Exclusive claim on a source root, so that two processes never watch the same tree.
+///
+/// The lock file lives in the state directory rather than the source so that it is not
+/// picked up by the watcher or mirrored into the backup. It holds the owner's pid; the
+/// lock is released when the file is closed, including when the process dies.
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl InstanceLock {
+    pub fn acquire(state_dir: &Path, source_root: &Path) -> Result<Self> {
+        fs::create_dir_all(state_dir)
+            .with_context(|| format!("Failed to create state directory: {state_dir:?}"))?;
+        let path = Self::lock_path(state_dir, source_root);
+
+        // Not truncated before locking, so a running instance's pid stays readable
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open instance lock: {path:?}"))?;
+
+        if let Err(e) = <File as FileExt>::try_lock_exclusive(&file) {
+            if e.raw_os_error() != fs2::lock_contended_error().raw_os_error() {
+                return Err(e).with_context(|| format!("Failed to lock instance file: {path:?}"));
+            }
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            bail!("already running (pid {}) for {source_root:?}", pid.trim());
+        }
+
+        file.set_len(0)
+            .and_then(|()| file.rewind())
+            .and_then(|()| write!(file, "{}", std::process::id()))
+            .and_then(|()| file.sync_data())
+            .with_context(|| format!("Failed to write instance lock: {path:?}"))?;
+        debug!(?path, "acquired instance lock");
+        Ok(Self { _file: file, path })
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether some process currently holds the lock of `source_root`.
+    #[must_use]
+    pub fn is_held(state_dir: &Path, source_root: &Path) -> bool {
+        let Ok(file) = File::open(Self::lock_path(state_dir, source_root)) else {
+            return false;
+        };
+        <File as FileExt>::try_lock_shared(&file).is_err()
+    }
+
+    fn lock_path(state_dir: &Path, source_root: &Path) -> PathBuf {
+        state_dir.join(format!("instance-{}.lock", source_key(source_root)))
+    }
+}
+
+/// Stable name for the per-source files of the state directory, derived from a hash of
+/// the canonical source path.
+#[must_use]
+pub fn source_key(source_root: &Path) -> String {
+    let canonical = fs::canonicalize(source_root).unwrap_or_else(|_| source_root.to_path_buf());
+    let hash = blake3::hash(canonical.as_os_str().as_encoded_bytes());
+    hash.to_hex()[..16].to_string()
+}
+
+/// A file holding the process id, removed again on graceful shutdown.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        fs::write(&path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Failed to write pidfile: {path:?}"))?;
+        Ok(Self { path })
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(path = ?self.path, "failed to remove pidfile: {e}");
+        }
+    }
+}
@@ -1,6 +1,19 @@
+pub mod compression;
+pub mod config;
 pub mod file_streaming;
 pub mod folder_structure;
+pub mod health;
+pub mod ignore_rules;
+pub mod instance;
 pub mod local_file_ops;
+pub mod logging;
+pub mod manifest;
 pub mod origin;
+pub mod pairs;
+pub mod registration;
+pub mod remote;
 pub mod state;
+pub mod status;
 pub mod synchronizer;
+pub mod transfer;
+pub mod watchdog;
@@ -1,12 +1,16 @@
 use std::fs::{self, File};
 use std::io::{Seek, Write};
 use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result, anyhow};
 use fs2::FileExt;
 use librsync::whole::{delta, patch, signature};
 use tracing::instrument;
 
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
 pub struct LocalFileOps;
 
 impl LocalFileOps {
@@ -85,6 +89,49 @@ impl LocalFileOps {
         Ok(file)
     }
 
+    /// Tries to take a shared lock, waiting at most `timeout` for a conflicting lock to be
+    /// released. Returns `None` if the file is still locked by someone else.
+    #[instrument]
+    pub fn try_lock_shared(path: &Path, timeout: Duration) -> Result<Option<File>> {
+        let file = Self::open_for_read(path)?;
+        let locked = Self::wait_for_lock(&file, path, timeout, <File as FileExt>::try_lock_shared)?;
+        Ok(locked.then_some(file))
+    }
+
+    /// Tries to take an exclusive lock, waiting at most `timeout` for a conflicting lock to be
+    /// released. Returns `None` if the file is still locked by someone else.
+    #[instrument]
+    pub fn try_lock_exclusive(path: &Path, timeout: Duration) -> Result<Option<File>> {
+        let file = Self::open_for_read_write(path)?;
+        let locked =
+            Self::wait_for_lock(&file, path, timeout, <File as FileExt>::try_lock_exclusive)?;
+        Ok(locked.then_some(file))
+    }
+
+    fn wait_for_lock(
+        file: &File,
+        path: &Path,
+        timeout: Duration,
+        try_lock: impl Fn(&File) -> std::io::Result<()>,
+    ) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match try_lock(file) {
+                Ok(()) => return Ok(true),
+                Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(false);
+                    }
+                    thread::sleep(LOCK_RETRY_INTERVAL.min(deadline - now));
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to acquire lock on: {path:?}"));
+                }
+            }
+        }
+    }
+
     fn truncate_and_write(file: &mut File, data: &[u8], path: &Path) -> Result<()> {
         file.set_len(0)
             .with_context(|| format!("Failed to truncate file: {path:?}"))?;
@@ -104,6 +151,16 @@ impl LocalFileOps {
         Ok(sig)
     }
 
+    /// BLAKE3 hash of the whole file content.
+    #[instrument]
+    pub fn hash_file(path: &Path) -> Result<blake3::Hash> {
+        let mut file = Self::open_for_read(path)?;
+        let mut hasher = blake3::Hasher::new();
+        std::io::copy(&mut file, &mut hasher)
+            .with_context(|| format!("Failed to hash file: {path:?}"))?;
+        Ok(hasher.finalize())
+    }
+
     #[instrument(skip(old_sig))]
     pub fn calculate_delta(old_sig: &[u8], path: &Path) -> Result<Vec<u8>> {
         let mut new_file = Self::open_for_read(path)?;
@@ -0,0 +1,60 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Levels selectable with `-v`/`-q`, from the quietest to the most verbose
+const LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Pretty,
+    /// One JSON object per line, including the fields of the enclosing spans
+    Json,
+}
+
+/// Moves `base` up by `verbose` and down by `quiet` levels. A `base` that is not a plain
+/// level, such as a list of directives, is kept unless `-v`/`-q` were given.
+#[must_use]
+pub fn filter_directive(base: &str, verbose: u8, quiet: u8) -> String {
+    if verbose == 0 && quiet == 0 {
+        return base.to_string();
+    }
+    let current = LEVELS
+        .iter()
+        .position(|level| level.eq_ignore_ascii_case(base))
+        .unwrap_or(2);
+    let index = (current + usize::from(verbose))
+        .saturating_sub(usize::from(quiet))
+        .min(LEVELS.len() - 1);
+    LEVELS[index].to_string()
+}
+
+/// Builds the filter from `RUST_LOG` when set, otherwise from `directive`.
+#[must_use]
+pub fn env_filter(directive: &str) -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(directive))
+}
+
+/// Installs the global subscriber writing to `writer`, colored only with `ansi`, so that
+/// log files and journald don't get escape codes.
+pub fn init<W>(format: LogFormat, filter: EnvFilter, writer: W, ansi: bool)
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .with_env_filter(filter);
+    match format {
+        LogFormat::Pretty => builder.init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init(),
+    }
+}
@@ -1,19 +1,252 @@
-use backup_sync_client::state;
-use backup_sync_client::synchronizer::SyncOptions;
-use clap::{ArgGroup, Parser};
+use anyhow::{Context, Result};
+use backup_sync_client::config::{CliOverrides, Config, FolderPair};
+use backup_sync_client::ignore_rules::IgnoreRules;
+use backup_sync_client::instance::PidFile;
+use backup_sync_client::logging::{self, LogFormat};
+use backup_sync_client::pairs::{PairSet, validate_pairs};
+use backup_sync_client::registration::{
+    self, REGISTRATION_FILE_NAME, Registration, default_config_dir,
+};
+use backup_sync_client::remote::{self, RemoteOptions, Role, SymlinkFallback};
+use backup_sync_client::status::{StatusSnapshot, unix_secs};
+use backup_sync_client::synchronizer::{SyncAction, SyncReport, Synchronizer};
+use backup_sync_client::watchdog::{self, Heartbeat};
+use backup_sync_protocol::{ComputerId, Encoding, FolderId, UserId};
+use clap::{ArgAction, ArgGroup, Args, Parser, Subcommand};
 use notify::RecursiveMode;
 use notify_debouncer_full::new_debouncer;
-use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
-use std::path::PathBuf;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, SystemTime};
+
+mod progress;
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Failures that get their own exit code, so that scripts can tell them apart from a
+/// sync that ran and hit errors (exit code 1).
+#[derive(Debug)]
+enum CliError {
+    /// Arguments or configuration that can never work
+    InvalidArguments(String),
+    SourceMissing(PathBuf),
+    BackupNotWritable(PathBuf, std::io::Error),
+    Watch(PathBuf, notify::Error),
+}
+
+impl CliError {
+    fn exit_code(&self) -> ExitCode {
+        match self {
+            Self::InvalidArguments(_) => ExitCode::from(2),
+            Self::SourceMissing(_) => ExitCode::from(3),
+            Self::BackupNotWritable(..) => ExitCode::from(4),
+            Self::Watch(..) => ExitCode::from(5),
+        }
+    }
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidArguments(message) => write!(f, "{message}"),
+            Self::SourceMissing(path) => {
+                write!(
+                    f,
+                    "Source folder {path:?} does not exist or is not a directory"
+                )
+            }
+            Self::BackupNotWritable(path, e) => {
+                write!(f, "Backup folder {path:?} is not writable: {e}")
+            }
+            Self::Watch(path, e) => write!(f, "Failed to watch {path:?}: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+/// Prints the error and picks the exit code of the most specific [`CliError`] in its chain.
+fn report(error: &anyhow::Error) -> ExitCode {
+    eprintln!("{error:#}");
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<CliError>())
+        .map_or(ExitCode::FAILURE, CliError::exit_code)
+}
 
 #[derive(Parser)]
 #[command(
     about,
     version,
-    group = ArgGroup::new("sources").required(true),
-    group = ArgGroup::new("backups").required(true),
+    after_help = "Exit codes: 0 success, 1 failure while running or differences found by \
+                  verify, 2 invalid arguments, 3 missing source, 4 backup not writable, \
+                  5 watch registration failed"
 )]
 pub struct Cli {
+    /// TOML file with the folder pair and options; explicit flags take precedence
+    #[arg(long, value_name = "FILE", global = true)]
+    config: Option<PathBuf>,
+
+    /// Log output format
+    #[arg(long, value_enum, global = true)]
+    log_format: Option<LogFormat>,
+
+    /// Log more; repeat for even more detail
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Log less; repeat to only log errors
+    #[arg(short, long, action = ArgAction::Count, global = true, conflicts_with = "verbose")]
+    quiet: u8,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Mirror the source into the backup and keep watching it for changes
+    Watch(WatchArgs),
+    /// Run a single reconciliation, print a report and exit
+    Sync(SyncArgs),
+    /// Copy the backup back into the source without modifying the backup
+    Restore(RestoreArgs),
+    /// Compare both trees by content hash without modifying either of them
+    Verify(VerifyArgs),
+    /// Show the state of running or last-run watchers, read from the state directory
+    Status(StatusArgs),
+    /// Serve a folder through the WebSocket server, as its origin or as a backup
+    Connect(ConnectArgs),
+    /// Provision this computer on the HTTP server and remember its id
+    Register(RegisterArgs),
+}
+
+#[derive(Args)]
+struct RegisterArgs {
+    /// HTTP server, e.g. `http://localhost:3000`
+    #[arg(long, value_name = "URL")]
+    server: String,
+
+    #[arg(long, value_name = "USER")]
+    user: String,
+
+    /// Read from `BACKUP_SYNC_PASSWORD` when omitted
+    #[arg(long, value_name = "PASSWORD")]
+    password: Option<String>,
+
+    /// Name of this computer
+    #[arg(long, value_name = "NAME")]
+    name: String,
+}
+
+#[derive(Args)]
+struct ConnectArgs {
+    /// WebSocket server, e.g. `ws://localhost:9000`
+    #[arg(long, value_name = "URL")]
+    url: String,
+
+    /// Defaults to the user of the saved registration
+    #[arg(long, value_name = "USER")]
+    user: Option<UserId>,
+
+    /// Id of this computer; defaults to the one saved by `register`
+    #[arg(long, value_name = "COMPUTER")]
+    computer: Option<ComputerId>,
+
+    /// Token the server authenticates this computer with; defaults to the one saved
+    /// by `register`
+    #[arg(long, value_name = "TOKEN")]
+    token: Option<String>,
+
+    /// Id of the sync folder
+    #[arg(long, value_name = "FOLDER")]
+    folder: FolderId,
+
+    #[arg(long, value_enum)]
+    role: Role,
+
+    /// Local copy of the folder
+    #[arg(long, value_name = "DIR")]
+    path: PathBuf,
+
+    /// Gitignore-style pattern the origin does not publish; can be repeated
+    #[arg(long = "exclude", value_name = "PATTERN", value_parser = IgnoreRules::parse_exclude)]
+    excludes: Vec<String>,
+
+    /// How a backup applies symlinks on platforms that cannot create them
+    #[arg(long, value_enum, default_value_t = SymlinkFallback::Skip)]
+    symlink_fallback: SymlinkFallback,
+
+    /// Wire encoding to ask the server for: `json` or `postcard`
+    #[arg(long, value_name = "ENCODING", default_value = "json", value_parser = parse_encoding)]
+    encoding: Encoding,
+}
+
+fn parse_encoding(value: &str) -> Result<Encoding, String> {
+    match value {
+        "json" => Ok(Encoding::Json),
+        "postcard" => Ok(Encoding::Postcard),
+        other => Err(format!(
+            "unknown encoding {other:?}, expected json or postcard"
+        )),
+    }
+}
+
+#[derive(Args)]
+struct StatusArgs {
+    /// Print the status as JSON
+    #[arg(long, default_value_t = false)]
+    json: bool,
+
+    /// Directory holding the status files, if not the default one
+    #[arg(long, value_name = "DIR")]
+    state_dir: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    #[command(flatten)]
+    sync: SyncArgs,
+
+    /// Print the report as JSON
+    #[arg(long, default_value_t = false)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct WatchArgs {
+    #[command(flatten)]
+    sync: SyncArgs,
+
+    /// Write the process id to this file, removed again on shutdown
+    #[arg(long, value_name = "FILE")]
+    pidfile: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct RestoreArgs {
+    #[command(flatten)]
+    sync: SyncArgs,
+
+    /// Keep the source version of files that differ instead of the backup's
+    #[arg(long, default_value_t = false)]
+    prefer_source: bool,
+}
+
+#[derive(Args)]
+#[command(
+    group = ArgGroup::new("sources").requires("backups"),
+    group = ArgGroup::new("backups").requires("sources"),
+)]
+struct SyncArgs {
+    /// A source and backup folder as `SRC:DST`; can be repeated
+    #[arg(long = "pair", value_name = "SRC:DST", value_parser = FolderPair::parse, conflicts_with = "sources")]
+    pairs: Vec<FolderPair>,
+
     #[arg(short, long, value_name = "DIR", group = "sources")]
     source_local: Option<PathBuf>,
     #[arg(short, long, value_name = "DIR", group = "backups")]
@@ -27,35 +260,531 @@ pub struct Cli {
 
     #[arg(long, default_value_t = false)]
     when_delete_keep_backup: bool,
-}
 
-fn main() {
-    tracing_subscriber::fmt::init();
+    /// Only log what would change, without touching the backup
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
 
-    let (tx, rx) = std::sync::mpsc::channel();
-    let mut debouncer = new_debouncer(std::time::Duration::from_millis(200), None, tx).unwrap();
+    /// Gitignore-style pattern to leave out of the backup; can be repeated
+    #[arg(long = "exclude", value_name = "PATTERN", value_parser = IgnoreRules::parse_exclude)]
+    excludes: Vec<String>,
+
+    /// How long to wait for file events to settle before syncing
+    #[arg(long, value_name = "MS")]
+    debounce_ms: Option<u64>,
+
+    /// Log filter used when RUST_LOG is not set, e.g. `debug`
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<String>,
+}
 
+impl SyncArgs {
+    fn overrides(&self) -> CliOverrides {
+        let mut folders = self.pairs.clone();
+        folders.extend(
+            self.source_local
+                .clone()
+                .zip(self.backup_local.clone())
+                .map(|(source, backup)| FolderPair { source, backup }),
+        );
+        CliOverrides {
+            folders,
+            when_missing_preserve_backup: self.when_missing_preserve_backup,
+            when_conflict_preserve_backup: self.when_conflict_preserve_backup,
+            when_delete_keep_backup: self.when_delete_keep_backup,
+            dry_run: self.dry_run,
+            excludes: self.excludes.clone(),
+            debounce_ms: self.debounce_ms,
+            log_level: self.log_level.clone(),
+            log_format: None,
+        }
+    }
+}
+
+fn main() -> ExitCode {
     let cli = Cli::parse();
-    let options = SyncOptions::default()
-        .with_when_delete_keep_backup(cli.when_delete_keep_backup)
-        .with_when_conflict_preserve_backup(cli.when_conflict_preserve_backup)
-        .with_when_missing_preserve_backup(cli.when_missing_preserve_backup);
+    let mut overrides = match &cli.command {
+        Command::Sync(args) => args.overrides(),
+        Command::Watch(WatchArgs { sync, .. })
+        | Command::Restore(RestoreArgs { sync, .. })
+        | Command::Verify(VerifyArgs { sync, .. }) => sync.overrides(),
+        Command::Connect(args) => CliOverrides {
+            excludes: args.excludes.clone(),
+            ..CliOverrides::default()
+        },
+        Command::Status(_) | Command::Register(_) => CliOverrides::default(),
+    };
+    overrides.log_format = cli.log_format;
+    let file_config = match &cli.config {
+        Some(path) => match Config::load(path) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{e:#}");
+                return ExitCode::from(2);
+            }
+        },
+        None => Config::default(),
+    };
+    let config = file_config.merge(overrides);
+
+    // Logs go to stderr so that reports on stdout stay parseable
+    let directive = logging::filter_directive(config.log_level(), cli.verbose, cli.quiet);
+    logging::init(
+        config.log_format(),
+        logging::env_filter(&directive),
+        std::io::stderr,
+        std::io::stderr().is_terminal(),
+    );
+
+    if let Command::Status(args) = &cli.command {
+        let state_dir = args
+            .state_dir
+            .clone()
+            .unwrap_or_else(|| config.sync.state_dir());
+        return status(&state_dir, args.json);
+    }
+    if let Command::Connect(args) = &cli.command {
+        return connect(args, &config);
+    }
+    if let Command::Register(args) = &cli.command {
+        return register(args);
+    }
+    tracing::info!(excludes = ?config.sync.excludes(), "effective exclude patterns");
+
+    let writes_backup = match &cli.command {
+        Command::Watch(_) => true,
+        Command::Sync(_) => !config.sync.is_dry_run(),
+        _ => false,
+    };
+    // Restoring is how a lost source comes back, so it may be missing
+    let restores = matches!(cli.command, Command::Restore(_));
+    let folders = match check_folders(&config, restores, writes_backup) {
+        Ok(folders) => folders,
+        Err(e) => return report(&e),
+    };
+
+    if let Command::Watch(args) = cli.command {
+        return match watch(&folders, &config, args.pidfile) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => report(&e),
+        };
+    }
+
+    // One-shot commands run pair by pair and report the first failure
+    let multiple = folders.len() > 1;
+    let mut exit_code = ExitCode::SUCCESS;
+    for FolderPair { source, backup } in folders {
+        if multiple {
+            eprintln!("{} -> {}", source.display(), backup.display());
+        }
+        let code = match &cli.command {
+            Command::Watch(_) | Command::Status(_) | Command::Connect(_) | Command::Register(_) => {
+                unreachable!("handled above")
+            }
+            Command::Sync(_) => sync_once(source, backup, &config),
+            Command::Restore(args) => restore(source, backup, &config, args.prefer_source),
+            Command::Verify(args) => verify(source, backup, &config, args.json),
+        };
+        if exit_code == ExitCode::SUCCESS {
+            exit_code = code;
+        }
+    }
+    exit_code
+}
+
+/// Rejects folder pairs that can never be synchronized before any work starts. Missing
+/// sources are rejected, unless `restores`: they are then created, or left missing on a
+/// dry run. The backup is created when missing, and probed for writability when
+/// `writes_backup`.
+fn check_folders(config: &Config, restores: bool, writes_backup: bool) -> Result<Vec<FolderPair>> {
+    let folders = config
+        .folders()
+        .map_err(|e| CliError::InvalidArguments(format!("{e:#}")))?;
+    for FolderPair { source, backup } in folders {
+        if restores {
+            if !config.sync.is_dry_run() {
+                std::fs::create_dir_all(source)
+                    .with_context(|| format!("Failed to create source folder {source:?}"))?;
+            }
+        } else if !source.is_dir() {
+            return Err(CliError::SourceMissing(source.clone()).into());
+        }
+        let source = resolve_path(source)?;
+        if source == resolve_path(backup)? {
+            return Err(CliError::InvalidArguments(format!(
+                "Source and backup are the same folder: {source:?}"
+            ))
+            .into());
+        }
+        if writes_backup {
+            std::fs::create_dir_all(backup)
+                .and_then(|()| tempfile::tempfile_in(backup))
+                .map_err(|e| CliError::BackupNotWritable(backup.clone(), e))?;
+        }
+    }
+    validate_pairs(folders).map_err(|e| CliError::InvalidArguments(format!("{e:#}")))?;
+    Ok(folders.to_vec())
+}
 
-    if let Some(source) = cli.source_local
-        && let Some(backup) = cli.backup_local
+/// Canonical form of `path`, or its absolute form when it does not exist yet.
+fn resolve_path(path: &Path) -> Result<PathBuf, CliError> {
+    std::fs::canonicalize(path)
+        .or_else(|_| std::path::absolute(path))
+        .map_err(|e| CliError::InvalidArguments(format!("Invalid path {path:?}: {e}")))
+}
+
+/// Runs until interrupted. The instance locks and pidfile are released on the way out.
+///
+/// A single debouncer watches every source root, so each batch of events covers all pairs.
+fn watch(folders: &[FolderPair], config: &Config, pidfile: Option<PathBuf>) -> Result<()> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let shutdown = Arc::new(AtomicBool::new(false));
     {
-        debouncer.watch(&source, RecursiveMode::Recursive).unwrap();
-        let global_state = state::AppState::new_with_local_sync(source, backup, options).unwrap();
-
-        while let Ok(res) = rx.recv() {
-            match res {
-                Ok(events) => {
-                    events
-                        .par_iter()
-                        .for_each(|x| global_state.process_debounced_event(x).unwrap());
+        let shutdown = Arc::clone(&shutdown);
+        let tx = tx.clone();
+        ctrlc::set_handler(move || {
+            shutdown.store(true, Ordering::SeqCst);
+            // An empty batch wakes the loop up so it can notice the shutdown
+            let _ = tx.send(Ok(Vec::new()));
+        })?;
+    }
+    let mut debouncer =
+        new_debouncer(config.debounce(), None, tx).context("Failed to create file watcher")?;
+
+    for source in validate_pairs(folders)? {
+        debouncer
+            .watch(&source, RecursiveMode::Recursive)
+            .map_err(|e| CliError::Watch(source.clone(), e))?;
+    }
+    let pairs = PairSet::new(
+        folders,
+        &config.sync.clone().with_progress(progress::hook()),
+    )?;
+    let _pidfile = pidfile.map(PidFile::create).transpose()?;
+
+    // The loop must beat well within the watchdog timeout, even when idle
+    let tick = watchdog::watchdog_timeout()
+        .map_or(RETRY_INTERVAL, |timeout| RETRY_INTERVAL.min(timeout / 2));
+    let heartbeat = Arc::new(Heartbeat::new());
+    heartbeat.beat(pairs.is_healthy());
+    let _watchdog = watchdog::spawn_watchdog(Arc::clone(&heartbeat));
+    if let Err(e) = watchdog::notify_ready() {
+        tracing::warn!("failed to notify readiness: {e}");
+    }
+
+    while !shutdown.load(Ordering::SeqCst) {
+        match rx.recv_timeout(tick) {
+            Ok(Ok(events)) => pairs.process_events(&events),
+            Ok(Err(e)) => tracing::error!("watch error: {e:?}"),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if let Err(e) = pairs.process_retry_queues() {
+            tracing::error!("retry error: {e:?}");
+        }
+        pairs.write_status();
+        heartbeat.beat(pairs.is_healthy());
+    }
+    tracing::info!("shutting down");
+    if let Err(e) = watchdog::notify_stopping() {
+        tracing::warn!("failed to notify shutdown: {e}");
+    }
+    Ok(())
+}
+
+/// Serves the folder through the server until interrupted, reconnecting when the
+/// connection drops.
+fn connect(args: &ConnectArgs, config: &Config) -> ExitCode {
+    let (user, computer, token) = match (&args.user, &args.computer, &args.token) {
+        (Some(user), Some(computer), Some(token)) => {
+            (user.clone(), computer.clone(), token.clone())
+        }
+        (user, computer, token) => {
+            let path = default_config_dir().join(REGISTRATION_FILE_NAME);
+            match Registration::load(&path) {
+                Ok(mut registration) => {
+                    if token.is_none()
+                        && registration.refresh_token.is_some()
+                        && let Err(e) = refresh_registration(&mut registration, &path)
+                    {
+                        tracing::warn!("failed to refresh the registration: {e:#}");
+                    }
+                    (
+                        user.clone().unwrap_or(registration.user_id),
+                        computer.clone().unwrap_or(registration.computer_id),
+                        token.clone().unwrap_or(registration.token),
+                    )
+                }
+                Err(e) => {
+                    eprintln!("{e:#}");
+                    eprintln!("Run `register` first, or pass --user, --computer and --token");
+                    return ExitCode::from(2);
                 }
-                Err(e) => tracing::error!("watch error: {e:?}"),
             }
         }
+    };
+    let options = RemoteOptions::new(
+        args.url.clone(),
+        user,
+        computer,
+        args.folder.clone(),
+        args.role,
+        args.path.clone(),
+    )
+    .with_excludes(config.sync.excludes().to_vec())
+    .with_symlink_fallback(args.symlink_fallback)
+    .with_encoding(args.encoding)
+    .with_debounce(config.debounce())
+    .with_state_dir(config.sync.state_dir())
+    .with_token(token);
+
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    if let Err(e) = ctrlc::set_handler(move || {
+        let _ = shutdown_tx.send(true);
+    }) {
+        eprintln!("{e:#}");
+        return ExitCode::FAILURE;
+    }
+    let result = tokio::runtime::Runtime::new()
+        .map_err(anyhow::Error::from)
+        .and_then(|runtime| runtime.block_on(remote::run(options, shutdown_rx)));
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Swaps the tokens of the registration for new ones and saves them, as the old refresh
+/// token is spent.
+fn refresh_registration(registration: &mut Registration, path: &Path) -> anyhow::Result<()> {
+    tokio::runtime::Runtime::new()?.block_on(registration.refresh())?;
+    registration.save(path)?;
+    tracing::info!(?path, "registration tokens refreshed");
+    Ok(())
+}
+
+/// Registers the computer and saves its id for later `connect` runs.
+fn register(args: &RegisterArgs) -> ExitCode {
+    let Some(password) = args
+        .password
+        .clone()
+        .or_else(|| std::env::var("BACKUP_SYNC_PASSWORD").ok())
+    else {
+        eprintln!("A password is required: pass --password or set BACKUP_SYNC_PASSWORD");
+        return ExitCode::from(2);
+    };
+    let path = default_config_dir().join(REGISTRATION_FILE_NAME);
+    let result = tokio::runtime::Runtime::new()
+        .map_err(anyhow::Error::from)
+        .and_then(|runtime| {
+            runtime.block_on(registration::register(
+                &args.server,
+                &args.user,
+                &password,
+                &args.name,
+            ))
+        })
+        .and_then(|registration| {
+            registration.save(&path)?;
+            Ok(registration)
+        });
+    match result {
+        Ok(registration) => {
+            tracing::info!(?path, "registration saved");
+            println!("{}", registration.computer_id);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Runs a single reconciliation. Fails when any entry could not be synchronized.
+fn sync_once(source: PathBuf, backup: PathBuf, config: &Config) -> ExitCode {
+    let options = config.sync.clone().with_progress(progress::hook());
+    let mut syncer = match Synchronizer::new_with_options(source, backup, options) {
+        Ok(syncer) => syncer,
+        Err(e) => {
+            eprintln!("{e:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if config.sync.is_dry_run() {
+        return print_plan(syncer.plan());
+    }
+    print_report(syncer.sync())
+}
+
+/// Copies the backup into the source. Fails when any entry could not be restored.
+fn restore(source: PathBuf, backup: PathBuf, config: &Config, prefer_source: bool) -> ExitCode {
+    let mut syncer = match Synchronizer::new_for_restore(source, backup, config.sync.clone()) {
+        Ok(syncer) => syncer,
+        Err(e) => {
+            eprintln!("{e:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if config.sync.is_dry_run() {
+        return print_plan(syncer.plan_restore(prefer_source));
+    }
+    print_report(syncer.restore(prefer_source))
+}
+
+/// Exits with 0 when the trees match, 1 when they differ and 2 when they could not be compared.
+fn verify(source: PathBuf, backup: PathBuf, config: &Config, json: bool) -> ExitCode {
+    let report = match Synchronizer::new(source, backup)
+        .and_then(|syncer| syncer.with_options(config.sync.clone()).verify())
+    {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{e:#}");
+            return ExitCode::from(2);
+        }
+    };
+
+    if json {
+        let output = serde_json::json!({
+            "version": 1,
+            "clean": report.is_clean(),
+            "mismatched": report.mismatched,
+            "missing": report.missing,
+            "extra": report.extra,
+            "unreadable": report.unreadable,
+        });
+        println!("{output}");
+    } else {
+        for relative in &report.mismatched {
+            println!("mismatched: {}", relative.display());
+        }
+        for relative in &report.missing {
+            println!("missing: {}", relative.display());
+        }
+        for relative in &report.extra {
+            println!("extra: {}", relative.display());
+        }
+        for relative in &report.unreadable {
+            println!("unreadable: {}", relative.display());
+        }
+        println!(
+            "mismatched: {}, missing: {}, extra: {}, unreadable: {}",
+            report.mismatched.len(),
+            report.missing.len(),
+            report.extra.len(),
+            report.unreadable.len()
+        );
+    }
+
+    if report.is_clean() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Prints one line per pair, or all snapshots as a JSON array.
+fn status(state_dir: &Path, json: bool) -> ExitCode {
+    let snapshots = match StatusSnapshot::read_all(state_dir) {
+        Ok(snapshots) => snapshots,
+        Err(e) => {
+            eprintln!("{e:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if json {
+        let output: Vec<_> = snapshots
+            .iter()
+            .map(|snapshot| {
+                serde_json::json!({
+                    "running": snapshot.is_running(state_dir),
+                    "status": snapshot,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::Value::Array(output));
+        return ExitCode::SUCCESS;
+    }
+
+    if snapshots.is_empty() {
+        println!("no status found in {}", state_dir.display());
+        return ExitCode::SUCCESS;
+    }
+    let now = unix_secs(SystemTime::now());
+    let ago = |time: Option<u64>| {
+        time.map_or_else(
+            || "never".to_string(),
+            |time| format!("{}s ago", now.saturating_sub(time)),
+        )
+    };
+    println!(
+        "{:<8} {:<8} {:<12} {:<12} {:>5} {:>6}  PAIR",
+        "STATE", "HEALTH", "LAST EVENT", "LAST SYNC", "RETRY", "ERRORS"
+    );
+    for snapshot in &snapshots {
+        let state = if snapshot.is_running(state_dir) {
+            "running"
+        } else {
+            "stopped"
+        };
+        let level = format!("{:?}", snapshot.level);
+        println!(
+            "{state:<8} {level:<8} {:<12} {:<12} {:>5} {:>6}  {} -> {}",
+            ago(snapshot.last_event_at),
+            ago(snapshot.last_full_sync_at),
+            snapshot.retry_queue_len,
+            snapshot.errors.len(),
+            snapshot.source.display(),
+            snapshot.backup.display()
+        );
+        for error in &snapshot.errors {
+            println!("    error: {}: {}", error.path.display(), error.error);
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+fn print_plan(plan: Result<Vec<SyncAction>>) -> ExitCode {
+    match plan {
+        Ok(actions) => {
+            for action in &actions {
+                println!("would apply: {action:?}");
+            }
+            println!("{} action(s) planned", actions.len());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn print_report(report: Result<SyncReport>) -> ExitCode {
+    match report {
+        Ok(report) => {
+            println!("{report}");
+            for relative in &report.skipped_locked {
+                println!("skipped (locked): {}", relative.display());
+            }
+            for (relative, error) in &report.errors {
+                eprintln!("error: {}: {error}", relative.display());
+            }
+            if report.has_errors() {
+                ExitCode::FAILURE
+            } else {
+                ExitCode::SUCCESS
+            }
+        }
+        Err(e) => {
+            eprintln!("{e:#}");
+            ExitCode::FAILURE
+        }
     }
 }
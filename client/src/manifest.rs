@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context, Result};
+use backup_sync_protocol::{FileOperation, Manifest, ManifestEntry, RelativePath};
+use tracing::warn;
+use walkdir::WalkDir;
+
+use crate::ignore_rules::IgnoreRules;
+
+/// Manifest of the regular files under `root` that `rules` do not ignore. Symlinks and
+/// directories are left out, they are cheap to send whole.
+pub fn build_manifest(root: &Path, rules: &IgnoreRules) -> Result<Manifest> {
+    let mut entries = Vec::new();
+    let walk = WalkDir::new(root)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            entry
+                .path()
+                .strip_prefix(root)
+                .is_ok_and(|relative| !rules.is_ignored(relative, entry.file_type().is_dir()))
+        });
+    for entry in walk {
+        let entry = entry.with_context(|| format!("Failed to walk {root:?}"))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let Ok(relative_path) = RelativePath::new(path.strip_prefix(root)?) else {
+            warn!(?path, "skipping file whose path cannot be synced");
+            continue;
+        };
+        let metadata = entry
+            .metadata()
+            .with_context(|| format!("Failed to read metadata of {path:?}"))?;
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .and_then(|since_epoch| i64::try_from(since_epoch.as_secs()).ok());
+        entries.push(ManifestEntry {
+            relative_path,
+            size: metadata.len(),
+            mtime,
+            hash: hash_file(path)?,
+        });
+    }
+    Ok(Manifest { entries })
+}
+
+/// Operations bringing the copy described by `remote` to the files of `local`, the
+/// manifest of `root`: removals of the files `local` does not have, then the content of
+/// the files missing or different in `remote`. Files that `rules` ignore are left alone.
+pub fn corrective_operations(
+    root: &Path,
+    rules: &IgnoreRules,
+    local: &Manifest,
+    remote: &Manifest,
+) -> Result<Vec<FileOperation>> {
+    let local_hashes: HashMap<&Path, &[u8; 32]> = local
+        .entries
+        .iter()
+        .map(|entry| (entry.relative_path.as_path(), &entry.hash))
+        .collect();
+    let remote_hashes: HashMap<&Path, &[u8; 32]> = remote
+        .entries
+        .iter()
+        .map(|entry| (entry.relative_path.as_path(), &entry.hash))
+        .collect();
+
+    // Removed first, so that a file removed from the backup can make room for a
+    // directory of the same name
+    let mut operations: Vec<FileOperation> = remote
+        .entries
+        .iter()
+        .filter(|entry| {
+            !local_hashes.contains_key(entry.relative_path.as_path())
+                && !rules.is_ignored(&entry.relative_path, false)
+        })
+        .map(|entry| FileOperation::RemoveFile {
+            relative_path: entry.relative_path.clone(),
+        })
+        .collect();
+    for entry in &local.entries {
+        if remote_hashes.get(entry.relative_path.as_path()) == Some(&&entry.hash) {
+            continue;
+        }
+        let path = root.join(&entry.relative_path);
+        let content = fs::read(&path).with_context(|| format!("Failed to read {path:?}"))?;
+        let hash = blake3::hash(&content).into();
+        operations.push(FileOperation::CreateFile {
+            relative_path: entry.relative_path.clone(),
+            content,
+            hash,
+            compression: None,
+        });
+    }
+    Ok(operations)
+}
+
+fn hash_file(path: &Path) -> Result<[u8; 32]> {
+    let mut file = fs::File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
@@ -0,0 +1,170 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use notify::event::{CreateKind, RemoveKind};
+use notify::{Event, EventKind};
+use notify_debouncer_full::DebouncedEvent;
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use tracing::{debug, error, instrument, warn};
+
+use crate::config::FolderPair;
+use crate::health::HealthLevel;
+use crate::state::AppState;
+use crate::synchronizer::SyncOptions;
+
+/// Several folder pairs served by one process, each with its own [`AppState`].
+///
+/// A batch of events is split by pair. Pairs are independent and processed in parallel,
+/// while the events of one pair are processed one after the other in the order they
+/// were reported.
+pub struct PairSet {
+    /// Canonical source root of each pair, with its state
+    pairs: Vec<(PathBuf, AppState)>,
+}
+
+/// Canonicalizes the source roots, rejecting duplicate or nested sources, duplicate or
+/// nested backups, and backups placed inside a source. Sources that do not exist yet, as
+/// that of a restore, are made absolute instead.
+pub fn validate_pairs(pairs: &[FolderPair]) -> Result<Vec<PathBuf>> {
+    let mut sources: Vec<PathBuf> = Vec::with_capacity(pairs.len());
+    for pair in pairs {
+        let source = fs::canonicalize(&pair.source)
+            .or_else(|_| std::path::absolute(&pair.source))
+            .with_context(|| format!("Invalid source folder: {:?}", pair.source))?;
+        for other in &sources {
+            if *other == source {
+                bail!("Duplicate source root: {source:?}");
+            }
+            if source.starts_with(other) || other.starts_with(&source) {
+                bail!("Source roots overlap: {other:?} and {source:?}");
+            }
+        }
+        sources.push(source);
+    }
+
+    let mut backups: Vec<PathBuf> = Vec::with_capacity(pairs.len());
+    for pair in pairs {
+        let backup = fs::canonicalize(&pair.backup).unwrap_or_else(|_| pair.backup.clone());
+        if let Some(source) = sources.iter().find(|source| backup.starts_with(source)) {
+            bail!("Backup {backup:?} is inside source root {source:?}");
+        }
+        for other in &backups {
+            if *other == backup {
+                bail!("Duplicate backup root: {backup:?}");
+            }
+            if backup.starts_with(other) || other.starts_with(&backup) {
+                bail!("Backup roots overlap: {other:?} and {backup:?}");
+            }
+        }
+        backups.push(backup);
+    }
+    Ok(sources)
+}
+
+impl PairSet {
+    pub fn new(pairs: &[FolderPair], options: &SyncOptions) -> Result<Self> {
+        let sources = validate_pairs(pairs)?;
+        let pairs = sources
+            .into_iter()
+            .zip(pairs)
+            .map(|(source, pair)| {
+                let state = AppState::new_with_local_sync(
+                    source.clone(),
+                    pair.backup.clone(),
+                    options.clone(),
+                )?;
+                Ok((source, state))
+            })
+            .collect::<Result<_>>()?;
+        let pair_set = Self { pairs };
+        pair_set.write_status();
+        Ok(pair_set)
+    }
+
+    /// Canonical source roots, in the order the pairs were given.
+    pub fn sources(&self) -> impl Iterator<Item = &Path> {
+        self.pairs.iter().map(|(source, _)| source.as_path())
+    }
+
+    pub fn states(&self) -> impl Iterator<Item = &AppState> {
+        self.pairs.iter().map(|(_, state)| state)
+    }
+
+    /// Index of the pair whose source root contains `path`.
+    fn route(&self, path: &Path) -> Option<usize> {
+        self.pairs
+            .iter()
+            .position(|(source, _)| path.starts_with(source))
+    }
+
+    /// Splits a batch into one list of events per pair. A rename across two pairs becomes
+    /// a removal in the first and a creation in the second.
+    fn split(&self, events: &[DebouncedEvent]) -> Vec<Vec<DebouncedEvent>> {
+        let mut batches = vec![Vec::new(); self.pairs.len()];
+        for event in events {
+            let routes: Vec<Option<usize>> = event.paths.iter().map(|p| self.route(p)).collect();
+            match routes.as_slice() {
+                [Some(from), Some(to)] if from != to => {
+                    let mut remove = event.clone();
+                    remove.event = Event::new(EventKind::Remove(RemoveKind::Any))
+                        .add_path(event.paths[0].clone());
+                    let mut create = event.clone();
+                    create.event = Event::new(EventKind::Create(CreateKind::Any))
+                        .add_path(event.paths[1].clone());
+                    batches[*from].push(remove);
+                    batches[*to].push(create);
+                }
+                [Some(index), ..] => batches[*index].push(event.clone()),
+                _ => debug!(paths = ?event.paths, "event outside of any source root"),
+            }
+        }
+        batches
+    }
+
+    /// Processes a batch of events. Failures are only logged, so that one bad event
+    /// neither holds back the rest of its pair nor the other pairs.
+    #[instrument(level = "debug", skip_all, fields(events = events.len()))]
+    pub fn process_events(&self, events: &[DebouncedEvent]) {
+        let batches = self.split(events);
+        let work: Vec<_> = self.pairs.iter().zip(batches).collect();
+        work.par_iter().for_each(|((source, state), batch)| {
+            for event in batch {
+                if let Err(e) = state.process_debounced_event(event) {
+                    error!(
+                        ?source,
+                        paths = ?event.paths,
+                        outcome = "failed",
+                        "event processing error: {e:#}"
+                    );
+                }
+            }
+        });
+    }
+
+    /// Refreshes the status file of every pair. Failures are only logged, since a stale
+    /// status must not stop synchronization.
+    pub fn write_status(&self) {
+        for (source, state) in &self.pairs {
+            if let Err(e) = state.write_status() {
+                warn!(?source, "failed to write status file: {e:#}");
+            }
+        }
+    }
+
+    /// Whether no pair is in [`HealthLevel::Failed`] health.
+    #[must_use]
+    pub fn is_healthy(&self) -> bool {
+        self.states().all(|state| {
+            state
+                .health()
+                .is_ok_and(|health| health.level != HealthLevel::Failed)
+        })
+    }
+
+    pub fn process_retry_queues(&self) -> Result<()> {
+        self.pairs
+            .par_iter()
+            .try_for_each(|(_, state)| state.process_retry_queue())
+    }
+}
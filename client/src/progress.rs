@@ -0,0 +1,128 @@
+use std::io::IsTerminal;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use backup_sync_client::synchronizer::{Progress, ProgressHook, SyncPhase};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+/// How often progress is logged when stderr is not a terminal
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Progress display for the initial sync: a bar when stderr is a terminal, periodic log
+/// lines otherwise. Drawn on stderr so that reports on stdout stay parseable.
+pub fn hook() -> ProgressHook {
+    if std::io::stderr().is_terminal() {
+        bar_hook()
+    } else {
+        log_hook(LOG_INTERVAL)
+    }
+}
+
+fn phase_name(phase: SyncPhase) -> &'static str {
+    match phase {
+        SyncPhase::Missing => "copying",
+        SyncPhase::Extra => "removing",
+        SyncPhase::Conflicts => "comparing",
+    }
+}
+
+/// A spinner counting entries while scanning, then one bar per phase.
+fn bar_hook() -> ProgressHook {
+    let bar = ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr());
+    let current: Mutex<Option<SyncPhase>> = Mutex::new(None);
+    ProgressHook::new(move |progress| {
+        let Ok(mut current) = current.lock() else {
+            return;
+        };
+        match progress {
+            Progress::Scanning { entries } => {
+                if entries == 1 {
+                    bar.reset();
+                    bar.set_length(0);
+                    bar.set_style(
+                        ProgressStyle::with_template(
+                            "{spinner} scanning: {pos} entries ({elapsed})",
+                        )
+                        .unwrap_or_else(|_| ProgressStyle::default_spinner()),
+                    );
+                    bar.enable_steady_tick(Duration::from_millis(100));
+                    *current = None;
+                }
+                bar.set_position(entries as u64);
+            }
+            Progress::Phase { phase, done, total } => {
+                if *current != Some(phase) {
+                    *current = Some(phase);
+                    bar.disable_steady_tick();
+                    bar.reset();
+                    bar.set_style(
+                        ProgressStyle::with_template(
+                            "{msg:9} [{bar:40}] {pos}/{len} ({per_sec}, {eta})",
+                        )
+                        .unwrap_or_else(|_| ProgressStyle::default_bar())
+                        .progress_chars("=> "),
+                    );
+                    bar.set_message(phase_name(phase));
+                    bar.set_length(total as u64);
+                }
+                bar.set_position(done as u64);
+                // Comparing is the last phase of a sync
+                if phase == SyncPhase::Conflicts && done == total {
+                    bar.finish_and_clear();
+                }
+            }
+        }
+    })
+}
+
+/// Logs at most once per `interval`, plus once when each phase completes.
+fn log_hook(interval: Duration) -> ProgressHook {
+    struct LogState {
+        last_log: Option<Instant>,
+        phase: Option<(SyncPhase, Instant)>,
+    }
+    let state = Mutex::new(LogState {
+        last_log: None,
+        phase: None,
+    });
+    ProgressHook::new(move |progress| {
+        let Ok(mut state) = state.lock() else {
+            return;
+        };
+        let now = Instant::now();
+        if let Progress::Phase { phase, .. } = progress
+            && state.phase.is_none_or(|(current, _)| current != phase)
+        {
+            state.phase = Some((phase, now));
+        }
+        let finished = matches!(progress, Progress::Phase { done, total, .. } if done == total);
+        let due = state
+            .last_log
+            .is_none_or(|last| now.duration_since(last) >= interval);
+        if !due && !finished {
+            return;
+        }
+        state.last_log = Some(now);
+
+        match progress {
+            Progress::Scanning { entries } => tracing::info!(entries, "scanning"),
+            Progress::Phase { phase, done, total } => {
+                let elapsed = state
+                    .phase
+                    .map_or(Duration::ZERO, |(_, started)| now.duration_since(started));
+                let per_sec = if elapsed.is_zero() {
+                    0.0
+                } else {
+                    done as f64 / elapsed.as_secs_f64()
+                };
+                tracing::info!(
+                    phase = phase_name(phase),
+                    done,
+                    total,
+                    per_sec = (per_sec * 10.0).round() / 10.0,
+                    "sync progress"
+                );
+            }
+        }
+    })
+}
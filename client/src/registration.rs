@@ -0,0 +1,180 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use backup_sync_protocol::{Computer, ComputerId, UserId};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument};
+
+/// Overrides where the registration file is kept
+pub const CONFIG_DIR_ENV: &str = "BACKUP_SYNC_CONFIG_DIR";
+
+/// Name of the file written by `register` in the config directory
+pub const REGISTRATION_FILE_NAME: &str = "registration.toml";
+
+/// Directory for user configuration: `$BACKUP_SYNC_CONFIG_DIR`, then
+/// `$XDG_CONFIG_HOME/backup-sync`, then `~/.config/backup-sync`, falling back to the
+/// system temp directory.
+#[must_use]
+pub fn default_config_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os(CONFIG_DIR_ENV) {
+        return PathBuf::from(dir);
+    }
+    if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME") {
+        return PathBuf::from(dir).join("backup-sync");
+    }
+    if let Some(home) = std::env::var_os("HOME") {
+        return PathBuf::from(home).join(".config/backup-sync");
+    }
+    std::env::temp_dir().join("backup-sync")
+}
+
+/// A computer provisioned on the HTTP server, kept so that later commands can omit
+/// the ids.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Registration {
+    pub server: String,
+    pub user_id: UserId,
+    pub computer_id: ComputerId,
+    pub computer_name: String,
+    pub token: String,
+    /// Exchanged by [`Registration::refresh`] for a new `token`; missing from
+    /// registrations saved before it was kept
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Credentials<'a> {
+    name: &'a str,
+    password: &'a str,
+}
+
+#[derive(Deserialize)]
+struct AuthResponse {
+    token: String,
+    user_id: UserId,
+    refresh_token: String,
+}
+
+#[derive(Serialize)]
+struct RefreshRequest<'a> {
+    refresh_token: &'a str,
+}
+
+#[derive(Serialize)]
+struct CreateComputerRequest<'a> {
+    name: &'a str,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    message: String,
+}
+
+impl Registration {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read registration: {path:?}"))?;
+        toml::from_str(&content).with_context(|| format!("Invalid registration: {path:?}"))
+    }
+
+    /// Writes the registration through a temp file. The temp file is only readable by
+    /// its owner, which keeps the token private once it is renamed into place.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create config directory: {dir:?}"))?;
+        let mut file = tempfile::NamedTempFile::new_in(dir)
+            .with_context(|| format!("Failed to create temp file in {dir:?}"))?;
+        file.write_all(toml::to_string(self)?.as_bytes())?;
+        file.persist(path)
+            .with_context(|| format!("Failed to write registration: {path:?}"))?;
+        Ok(())
+    }
+
+    /// Exchanges the refresh token for a new token and refresh token. The old refresh
+    /// token is spent once the server answers, so the registration has to be saved again.
+    #[instrument(skip(self), fields(server = %self.server))]
+    pub async fn refresh(&mut self) -> Result<()> {
+        let Some(refresh_token) = &self.refresh_token else {
+            bail!("The registration has no refresh token; run `register` again");
+        };
+        let response = reqwest::Client::new()
+            .post(format!("{}/v1/auth/refresh", self.server))
+            .json(&RefreshRequest { refresh_token })
+            .send()
+            .await
+            .with_context(|| format!("Failed to reach server at {}", self.server))?;
+        let auth: AuthResponse = check(response).await?.json().await?;
+        debug!(user_id = %auth.user_id, "refreshed token");
+        self.token = auth.token;
+        self.refresh_token = Some(auth.refresh_token);
+        Ok(())
+    }
+}
+
+/// Logs in to the HTTP server at `server` and registers a computer named `computer_name`.
+#[instrument(skip(password))]
+pub async fn register(
+    server: &str,
+    user: &str,
+    password: &str,
+    computer_name: &str,
+) -> Result<Registration> {
+    let server = server.trim_end_matches('/');
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(format!("{server}/v1/auth/login"))
+        .json(&Credentials {
+            name: user,
+            password,
+        })
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach server at {server}"))?;
+    let auth: AuthResponse = check(response).await?.json().await?;
+    debug!(user_id = %auth.user_id, "logged in");
+
+    let response = client
+        .post(format!("{server}/v1/computers"))
+        .bearer_auth(&auth.token)
+        .json(&CreateComputerRequest {
+            name: computer_name,
+        })
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach server at {server}"))?;
+    let computer: Computer = check(response).await?.json().await?;
+
+    Ok(Registration {
+        server: server.to_string(),
+        user_id: auth.user_id,
+        computer_id: computer.id,
+        computer_name: computer.name,
+        token: auth.token,
+        refresh_token: Some(auth.refresh_token),
+    })
+}
+
+/// Turns error statuses into errors carrying the server's message.
+async fn check(response: reqwest::Response) -> Result<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let message = response
+        .json::<ErrorBody>()
+        .await
+        .map_or_else(|_| status.to_string(), |body| body.message);
+    if status == StatusCode::UNAUTHORIZED {
+        bail!(
+            "Not authorized by the server ({message}); authenticate first with a valid user and password"
+        );
+    }
+    bail!("Server responded with {status}: {message}")
+}
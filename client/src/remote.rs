@@ -0,0 +1,1618 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{Context, Result, anyhow, bail};
+use backup_sync_protocol::codec::{self, Frame};
+use backup_sync_protocol::{
+    ClientMessage, ComputerId, Encoding, FileOperation, FolderId, MIN_SUPPORTED_VERSION, Manifest,
+    PROTOCOL_VERSION, RelativePath, ServerMessage, UserId,
+};
+use clap::ValueEnum;
+use futures_util::{SinkExt, StreamExt};
+use notify::event::{ModifyKind, RenameMode};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode};
+use notify_debouncer_full::{DebounceEventResult, DebouncedEvent, Debouncer, RecommendedCache};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::{debug, info, instrument, warn};
+use walkdir::WalkDir;
+
+use crate::compression::{MAX_DECOMPRESSED_LEN, compress_operation, decompress};
+use crate::config::DEFAULT_DEBOUNCE;
+use crate::file_streaming::{apply_delta_securely, delta_against, file_signature};
+use crate::ignore_rules::IgnoreRules;
+use crate::instance::{default_state_dir, source_key};
+use crate::local_file_ops::LocalFileOps;
+use crate::manifest::{build_manifest, corrective_operations};
+use crate::transfer::{self, CHUNK_SIZE, Transfers};
+
+/// Well under the idle timeout of the server and of common proxies
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A connection to the server and the encoding of the messages sent on it
+struct Connection {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    encoding: Encoding,
+}
+
+/// Part this computer plays for the folder on the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Watches the local folder and publishes every change
+    Origin,
+    /// Applies the operations published by the origin to the local folder
+    Backup,
+}
+
+/// What a backup does with symlinks on a platform that cannot create them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkFallback {
+    /// Leave the link out and report the operation as failed
+    #[default]
+    Skip,
+    /// Copy the file the link points to, when it is inside the folder
+    Copy,
+}
+
+/// Where to connect and which folder to serve.
+#[derive(Debug, Clone)]
+pub struct RemoteOptions {
+    url: String,
+    user_id: UserId,
+    computer_id: ComputerId,
+    folder_id: FolderId,
+    role: Role,
+    path: PathBuf,
+    excludes: Vec<String>,
+    symlinks: SymlinkFallback,
+    encoding: Encoding,
+    debounce: Duration,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    ping_interval: Duration,
+    state_dir: Option<PathBuf>,
+    token: String,
+}
+
+impl RemoteOptions {
+    #[must_use]
+    pub fn new(
+        url: String,
+        user_id: UserId,
+        computer_id: ComputerId,
+        folder_id: FolderId,
+        role: Role,
+        path: PathBuf,
+    ) -> Self {
+        Self {
+            url,
+            user_id,
+            computer_id,
+            folder_id,
+            role,
+            path,
+            excludes: Vec::new(),
+            symlinks: SymlinkFallback::default(),
+            encoding: Encoding::default(),
+            debounce: DEFAULT_DEBOUNCE,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            ping_interval: DEFAULT_PING_INTERVAL,
+            state_dir: None,
+            token: String::new(),
+        }
+    }
+
+    /// Gitignore-style patterns the origin never publishes.
+    #[must_use]
+    pub fn with_excludes(mut self, excludes: Vec<String>) -> Self {
+        self.excludes = excludes;
+        self
+    }
+
+    /// Only used by backups on platforms without symlinks.
+    #[must_use]
+    pub fn with_symlink_fallback(mut self, symlinks: SymlinkFallback) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    /// Encoding to ask the server for; the connection stays on JSON with servers that
+    /// do not support it.
+    #[must_use]
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    #[must_use]
+    pub fn with_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Delay before the first reconnection attempt, doubled after every failed attempt
+    /// up to `max`.
+    #[must_use]
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// How often to ping the server on an otherwise quiet connection, so that it and
+    /// any proxy in between keep the connection open.
+    #[must_use]
+    pub fn with_ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    /// Where backups keep the sequence number of the last operation they applied.
+    /// Defaults to [`default_state_dir`].
+    #[must_use]
+    pub fn with_state_dir(mut self, state_dir: PathBuf) -> Self {
+        self.state_dir = Some(state_dir);
+        self
+    }
+
+    /// Token the HTTP server issued on login, checked by servers requiring one.
+    #[must_use]
+    pub fn with_token(mut self, token: String) -> Self {
+        self.token = token;
+        self
+    }
+
+    #[must_use]
+    pub fn state_dir(&self) -> PathBuf {
+        self.state_dir.clone().unwrap_or_else(default_state_dir)
+    }
+
+    #[must_use]
+    pub fn role(&self) -> Role {
+        self.role
+    }
+}
+
+/// How a connection to the server ended.
+enum SessionEnd {
+    /// The server closed the connection
+    Closed,
+    /// The connection failed or dropped; worth retrying
+    Lost(anyhow::Error),
+    /// The server refused this computer or folder; retrying would not help
+    Rejected(anyhow::Error),
+    /// The origin of the folder switched, and this computer now plays this role for it
+    Switched(Role),
+}
+
+/// Debounced watcher of the origin folder. It outlives connections, so that changes
+/// made while disconnected are published once the connection is back.
+struct OriginWatcher {
+    _debouncer: Debouncer<RecommendedWatcher, RecommendedCache>,
+    events: mpsc::UnboundedReceiver<DebounceEventResult>,
+}
+
+impl OriginWatcher {
+    fn start(root: &Path, debounce: Duration) -> Result<Self> {
+        let (tx, events) = mpsc::unbounded_channel();
+        let mut debouncer = notify_debouncer_full::new_debouncer(debounce, None, move |result| {
+            let _ = tx.send(result);
+        })
+        .context("Failed to create file watcher")?;
+        debouncer
+            .watch(root, RecursiveMode::Recursive)
+            .with_context(|| format!("Failed to watch {root:?}"))?;
+        Ok(Self {
+            _debouncer: debouncer,
+            events,
+        })
+    }
+}
+
+/// Serves the folder until `shutdown` turns true, reconnecting with exponential backoff
+/// whenever the connection is lost. Fails right away if the server rejects the
+/// computer or the folder.
+///
+/// On every connection the origin publishes the whole tree before streaming changes,
+/// so backups catch up with what they missed while either side was offline.
+///
+/// When the origin of the folder switches to another computer, or to this one, the
+/// computer reconnects right away in its new role.
+pub async fn run(mut options: RemoteOptions, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    let root = fs::canonicalize(&options.path)
+        .with_context(|| format!("Folder does not exist: {:?}", options.path))?;
+    let mut watcher = match options.role {
+        Role::Origin => Some(OriginWatcher::start(&root, options.debounce)?),
+        Role::Backup => None,
+    };
+
+    let mut backoff = options.initial_backoff;
+    while !*shutdown.borrow() {
+        let mut established = false;
+        let end = tokio::select! {
+            end = session(&options, &root, watcher.as_mut(), &mut established) => end,
+            _ = shutdown.changed() => break,
+        };
+        match end {
+            SessionEnd::Closed => info!(url = %options.url, "connection closed by server"),
+            SessionEnd::Lost(e) => warn!(url = %options.url, "connection lost: {e:#}"),
+            SessionEnd::Rejected(e) => return Err(e),
+            SessionEnd::Switched(role) => {
+                info!(?role, "origin switched, serving the folder in a new role");
+                options.role = role;
+                watcher = match role {
+                    Role::Origin => Some(OriginWatcher::start(&root, options.debounce)?),
+                    Role::Backup => None,
+                };
+                backoff = options.initial_backoff;
+                continue;
+            }
+        }
+        if established {
+            backoff = options.initial_backoff;
+        }
+
+        info!(delay = ?backoff, "reconnecting");
+        tokio::select! {
+            () = tokio::time::sleep(backoff) => {}
+            _ = shutdown.changed() => break,
+        }
+        backoff = (backoff * 2).min(options.max_backoff);
+    }
+    info!("shutting down");
+    Ok(())
+}
+
+#[instrument(skip_all, fields(url = %options.url, role = ?options.role, folder = %options.folder_id))]
+async fn session(
+    options: &RemoteOptions,
+    root: &Path,
+    watcher: Option<&mut OriginWatcher>,
+    established: &mut bool,
+) -> SessionEnd {
+    let mut ws = match tokio_tungstenite::connect_async(options.url.as_str()).await {
+        Ok((stream, _)) => Connection {
+            stream,
+            encoding: Encoding::Json,
+        },
+        Err(e) => return SessionEnd::Lost(anyhow!(e).context("Failed to connect")),
+    };
+    let resumed = match handshake(&mut ws, options, root).await {
+        Ok(resumed) => resumed,
+        Err(end) => return end,
+    };
+    *established = true;
+    info!("connected");
+
+    let result = match watcher {
+        Some(watcher) => serve_origin(&mut ws, options, root, watcher).await,
+        None => serve_backup(&mut ws, options, root, resumed).await,
+    };
+    result.unwrap_or_else(SessionEnd::Lost)
+}
+
+/// Authenticates and makes sure this computer has the requested role for the folder,
+/// joining it as a backup when needed. Returns whether the server replays what this
+/// backup missed, which it does for folders the computer already backed up.
+async fn handshake(
+    ws: &mut Connection,
+    options: &RemoteOptions,
+    root: &Path,
+) -> Result<bool, SessionEnd> {
+    match recv(ws).await.map_err(SessionEnd::Lost)? {
+        Some(ServerMessage::Welcome {
+            protocol_version,
+            min_supported,
+        }) => {
+            if protocol_version < MIN_SUPPORTED_VERSION || min_supported > PROTOCOL_VERSION {
+                return Err(SessionEnd::Rejected(anyhow!(
+                    "Incompatible server: it speaks protocol version {protocol_version} and \
+                     requires at least {min_supported}, this client speaks {PROTOCOL_VERSION}"
+                )));
+            }
+            debug!(protocol_version, "server welcomed connection");
+        }
+        other => return Err(unexpected(other)),
+    }
+
+    if options.encoding != Encoding::Json {
+        send(
+            ws,
+            &ClientMessage::Hello {
+                encoding: options.encoding,
+            },
+        )
+        .await
+        .map_err(SessionEnd::Lost)?;
+        match recv(ws).await.map_err(SessionEnd::Lost)? {
+            Some(ServerMessage::EncodingSelected { encoding }) => ws.encoding = encoding,
+            Some(ServerMessage::Error { message, .. }) => {
+                warn!(encoding = ?options.encoding, "encoding not supported by server: {message}");
+            }
+            other => return Err(unexpected(other)),
+        }
+    }
+
+    send(
+        ws,
+        &ClientMessage::Authenticate {
+            user_id: options.user_id.clone(),
+            computer_id: options.computer_id.clone(),
+            protocol_version: PROTOCOL_VERSION,
+            token: options.token.clone(),
+            last_applied: match options.role {
+                Role::Origin => None,
+                Role::Backup => Some(BTreeMap::from([(
+                    options.folder_id.clone(),
+                    AppliedSequence::load(&options.state_dir(), root).last,
+                )])),
+            },
+        },
+    )
+    .await
+    .map_err(SessionEnd::Lost)?;
+    let user = match recv_reply(ws).await.map_err(SessionEnd::Lost)? {
+        Some(ServerMessage::Authenticated { user }) => user,
+        other => return Err(unexpected(other)),
+    };
+
+    let Some(folder) = user
+        .sync_folders
+        .iter()
+        .find(|folder| folder.id == options.folder_id)
+    else {
+        return Err(SessionEnd::Rejected(anyhow!(
+            "Folder {} not found for user {}",
+            options.folder_id,
+            options.user_id
+        )));
+    };
+    let is_origin = folder.origin_computer == options.computer_id;
+    match options.role {
+        Role::Origin if !is_origin => Err(SessionEnd::Rejected(anyhow!(
+            "Computer {} is not the origin of folder {}",
+            options.computer_id,
+            options.folder_id
+        ))),
+        Role::Backup if is_origin => Err(SessionEnd::Rejected(anyhow!(
+            "Computer {} is the origin of folder {}, not a backup",
+            options.computer_id,
+            options.folder_id
+        ))),
+        Role::Backup if !folder.backup_computers.contains(&options.computer_id) => {
+            send(
+                ws,
+                &ClientMessage::JoinSyncFolder {
+                    folder_id: options.folder_id.clone(),
+                },
+            )
+            .await
+            .map_err(SessionEnd::Lost)?;
+            match recv_reply(ws).await.map_err(SessionEnd::Lost)? {
+                Some(ServerMessage::JoinedSyncFolder { .. }) => Ok(false),
+                other => Err(unexpected(other)),
+            }
+        }
+        Role::Origin => Ok(false),
+        Role::Backup => Ok(true),
+    }
+}
+
+/// Next reply during the handshake, skipping the status changes of the user's other
+/// computers that may arrive once authenticated.
+async fn recv_reply(ws: &mut Connection) -> Result<Option<ServerMessage>> {
+    loop {
+        match recv(ws).await? {
+            Some(ServerMessage::ComputerStatusChanged { .. }) => {}
+            reply => return Ok(reply),
+        }
+    }
+}
+
+fn unexpected(message: Option<ServerMessage>) -> SessionEnd {
+    match message {
+        Some(ServerMessage::Error { message, .. }) => SessionEnd::Rejected(anyhow!(message)),
+        Some(ServerMessage::NotAuthenticated { reason }) => {
+            SessionEnd::Rejected(anyhow!("Not authenticated: {reason}"))
+        }
+        Some(other) => SessionEnd::Lost(anyhow!("Unexpected message from server: {other:?}")),
+        None => SessionEnd::Lost(anyhow!("Connection closed during handshake")),
+    }
+}
+
+/// Publishes the folder until the connection closes, or another computer becomes its
+/// origin. Changes the server refuses while the backups are behind are kept in a
+/// [`HeldBack`] until they catch up.
+async fn serve_origin(
+    ws: &mut Connection,
+    options: &RemoteOptions,
+    root: &Path,
+    watcher: &mut OriginWatcher,
+) -> Result<SessionEnd> {
+    // Reloaded on every connection, so edits to the ignore file apply after a reconnect
+    let rules = IgnoreRules::load(root, &options.excludes);
+    let snapshot = snapshot_operations(root, &rules)?;
+    debug!(operations = snapshot.len(), "publishing snapshot");
+    publish_all(ws, options, snapshot).await?;
+
+    let mut pings = Pings::new(options.ping_interval);
+    let mut held_back = HeldBack::new(options);
+    loop {
+        tokio::select! {
+            _ = pings.interval.tick() => pings.send(ws).await?,
+            batch = watcher.events.recv() => match batch {
+                Some(Ok(events)) => {
+                    let operations: Vec<FileOperation> = events
+                        .iter()
+                        .flat_map(|event| operations_for_event(root, &rules, event))
+                        .collect();
+                    if held_back.is_empty() {
+                        publish_all(ws, options, operations).await?;
+                    } else {
+                        held_back.queue(&operations);
+                    }
+                }
+                Some(Err(errors)) => warn!(?errors, "watch error"),
+                None => bail!("File watcher stopped"),
+            },
+            () = wait_until(held_back.retry_at) => {
+                let paths = held_back.take();
+                debug!(paths = paths.len(), "publishing changes held back");
+                let operations = paths
+                    .iter()
+                    .flat_map(|path| path_operations(root, &root.join(path.as_path())))
+                    .collect();
+                publish_all(ws, options, operations).await?;
+            }
+            message = recv(ws) => match message? {
+                Some(ServerMessage::Error { message, trace_id }) => {
+                    warn!(outcome = "failed", trace_id, "server error: {message}");
+                }
+                Some(ServerMessage::Backpressure {
+                    folder_id,
+                    paths,
+                    pending_operations,
+                    limit,
+                }) if folder_id == options.folder_id => {
+                    if held_back.is_empty() {
+                        warn!(
+                            pending_operations,
+                            limit,
+                            "backups are behind, holding changes back until they catch up"
+                        );
+                    }
+                    held_back.refuse(paths);
+                }
+                Some(ServerMessage::OperationComplete { .. }) => held_back.reset_backoff(),
+                // The operation is lost to the backups, until one of them asks for a full sync
+                Some(ServerMessage::RateLimited { retry_after_ms }) => {
+                    warn!(retry_after_ms, outcome = "failed", "server throttled an operation");
+                }
+                Some(
+                    ServerMessage::PayloadTooLarge { reason, .. }
+                    | ServerMessage::PathRejected { reason },
+                ) => {
+                    warn!(outcome = "failed", "server refused an operation: {reason}");
+                }
+                Some(ServerMessage::OperationFailed {
+                    operation_id,
+                    computer_id,
+                    reason,
+                }) => warn!(
+                    operation_id,
+                    %computer_id,
+                    outcome = "failed",
+                    "backup failed to apply operation: {reason}"
+                ),
+                Some(ServerMessage::SignatureResponse {
+                    folder_id,
+                    computer_id,
+                    relative_path,
+                    signature,
+                }) if folder_id == options.folder_id => {
+                    send_delta(ws, options, root, computer_id, relative_path, signature).await?;
+                }
+                Some(ServerMessage::Manifest {
+                    folder_id,
+                    computer_id,
+                    manifest,
+                }) if folder_id == options.folder_id => {
+                    send_corrections(ws, options, root, &rules, computer_id, manifest).await?;
+                }
+                Some(ServerMessage::FullSyncRequested {
+                    folder_id,
+                    computer_id,
+                }) if folder_id == options.folder_id => {
+                    send_full_sync(ws, options, root, &rules, computer_id).await?;
+                }
+                Some(ServerMessage::OriginSwitched {
+                    folder_id,
+                    new_origin,
+                }) if folder_id == options.folder_id && new_origin != options.computer_id => {
+                    info!(%new_origin, "another computer became the origin");
+                    return Ok(SessionEnd::Switched(Role::Backup));
+                }
+                Some(message) => debug!(?message, "server message"),
+                None => return Ok(SessionEnd::Closed),
+            },
+        }
+    }
+}
+
+/// Changes of the folder the server refused with `Backpressure`, published again as
+/// they are by then once the backoff elapses. Changes made meanwhile queue behind them,
+/// for none to overtake an older change to the same path.
+struct HeldBack {
+    paths: BTreeSet<RelativePath>,
+    retry_at: Option<Instant>,
+    backoff: Duration,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl HeldBack {
+    fn new(options: &RemoteOptions) -> Self {
+        Self {
+            paths: BTreeSet::new(),
+            retry_at: None,
+            backoff: options.initial_backoff,
+            initial_backoff: options.initial_backoff,
+            max_backoff: options.max_backoff,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.paths.is_empty() && self.retry_at.is_none()
+    }
+
+    /// Holds back refused paths, retried after the backoff, which doubles with every
+    /// retry refused in turn
+    fn refuse(&mut self, paths: Vec<RelativePath>) {
+        self.paths.extend(paths);
+        if self.retry_at.is_none() {
+            self.retry_at = Some(Instant::now() + self.backoff);
+            self.backoff = (self.backoff * 2).min(self.max_backoff);
+        }
+    }
+
+    fn queue(&mut self, operations: &[FileOperation]) {
+        self.paths.extend(
+            operations
+                .iter()
+                .flat_map(FileOperation::relative_paths)
+                .cloned(),
+        );
+    }
+
+    /// The paths to publish again, parents before their children
+    fn take(&mut self) -> BTreeSet<RelativePath> {
+        self.retry_at = None;
+        std::mem::take(&mut self.paths)
+    }
+
+    /// The backups answer operations again
+    fn reset_backoff(&mut self) {
+        self.backoff = self.initial_backoff;
+    }
+}
+
+/// Sends a backup the delta from its copy of a file, described by `signature`, to the
+/// origin's copy.
+async fn send_delta(
+    ws: &mut Connection,
+    options: &RemoteOptions,
+    root: &Path,
+    computer_id: ComputerId,
+    relative_path: RelativePath,
+    signature: Vec<u8>,
+) -> Result<()> {
+    let path = match resolve(root, &relative_path) {
+        Ok(path) => path,
+        Err(e) => {
+            warn!(%computer_id, "ignoring signature: {e:#}");
+            return Ok(());
+        }
+    };
+    let computed = tokio::task::spawn_blocking(move || delta_against(&path, &signature)).await?;
+    let (delta, hash) = match computed {
+        Ok(computed) => computed,
+        Err(e) => {
+            warn!(
+                %computer_id,
+                path = ?relative_path,
+                outcome = "failed",
+                "failed to compute delta: {e:#}"
+            );
+            return Ok(());
+        }
+    };
+    let transfer_id = NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed);
+    debug!(%computer_id, path = ?relative_path, size = delta.len(), "publishing delta");
+    send(
+        ws,
+        &ClientMessage::TargetedOperation {
+            folder_id: options.folder_id.clone(),
+            computer_id,
+            operation: compress_operation(FileOperation::ApplyDelta {
+                transfer_id,
+                relative_path,
+                delta,
+                hash,
+                compression: None,
+            }),
+        },
+    )
+    .await
+}
+
+/// Sends a backup the operations converging its copy, described by `manifest`, to the
+/// origin's copy.
+async fn send_corrections(
+    ws: &mut Connection,
+    options: &RemoteOptions,
+    root: &Path,
+    rules: &IgnoreRules,
+    computer_id: ComputerId,
+    manifest: Manifest,
+) -> Result<()> {
+    let computed = {
+        let root = root.to_path_buf();
+        let rules = rules.clone();
+        tokio::task::spawn_blocking(move || {
+            let local = build_manifest(&root, &rules)?;
+            corrective_operations(&root, &rules, &local, &manifest)
+        })
+        .await?
+    };
+    let operations = match computed {
+        Ok(operations) => operations,
+        Err(e) => {
+            warn!(
+                %computer_id,
+                outcome = "failed",
+                "failed to compare manifests: {e:#}"
+            );
+            return Ok(());
+        }
+    };
+    debug!(%computer_id, operations = operations.len(), "publishing corrections");
+    for operation in operations {
+        publish(ws, options, Some(&computer_id), operation).await?;
+    }
+    Ok(())
+}
+
+/// Sends a backup that asked for a full sync the whole folder, to it only.
+async fn send_full_sync(
+    ws: &mut Connection,
+    options: &RemoteOptions,
+    root: &Path,
+    rules: &IgnoreRules,
+    computer_id: ComputerId,
+) -> Result<()> {
+    let computed = {
+        let root = root.to_path_buf();
+        let rules = rules.clone();
+        tokio::task::spawn_blocking(move || snapshot_operations(&root, &rules)).await?
+    };
+    let operations = match computed {
+        Ok(operations) => operations,
+        Err(e) => {
+            warn!(%computer_id, outcome = "failed", "failed to read folder for full sync: {e:#}");
+            return Ok(());
+        }
+    };
+    info!(%computer_id, operations = operations.len(), "publishing full sync");
+    for operation in operations {
+        publish(ws, options, Some(&computer_id), operation).await?;
+    }
+    Ok(())
+}
+
+/// Applies what the origin publishes until the connection closes, the folder is
+/// deleted, or this computer becomes its origin. Unless `resumed` on authentication,
+/// first asks to catch up.
+async fn serve_backup(
+    ws: &mut Connection,
+    options: &RemoteOptions,
+    root: &Path,
+    resumed: bool,
+) -> Result<SessionEnd> {
+    // Per connection: the origin restarts unfinished transfers when it reconnects
+    let transfers = Arc::new(Mutex::new(Transfers::new(root.to_path_buf())));
+    let mut applied_sequence = AppliedSequence::load(&options.state_dir(), root);
+    let mut reorder = Reorder::default();
+    // Replays what was missed while away, or tells how far behind this backup is; sent
+    // again whenever operations turn out to be missing
+    let mut catching_up = true;
+    if !resumed {
+        send(
+            ws,
+            &ClientMessage::CatchUp {
+                folder_id: options.folder_id.clone(),
+                last_sequence: applied_sequence.last,
+            },
+        )
+        .await?;
+    }
+    let mut pings = Pings::new(options.ping_interval);
+    // When to ask again for a full sync the origin could not serve
+    let mut full_sync_retry: Option<Instant> = None;
+    loop {
+        let message = tokio::select! {
+            _ = pings.interval.tick() => {
+                pings.send(ws).await?;
+                continue;
+            }
+            () = wait_until(full_sync_retry) => {
+                full_sync_retry = None;
+                info!("requesting a full sync again");
+                let folder_id = options.folder_id.clone();
+                send(ws, &ClientMessage::RequestFullSync { folder_id }).await?;
+                continue;
+            }
+            message = recv(ws) => match message? {
+                Some(message) => message,
+                None => return Ok(SessionEnd::Closed),
+            },
+        };
+        let (operation_id, sequence, operations) = match message {
+            ServerMessage::FolderOperation {
+                folder_id,
+                operation_id,
+                sequence,
+                operation,
+            } if folder_id == options.folder_id => (operation_id, sequence, vec![operation]),
+            ServerMessage::FolderOperationBatch {
+                folder_id,
+                operation_id,
+                sequence,
+                operations,
+            } if folder_id == options.folder_id => (operation_id, sequence, operations),
+            ServerMessage::FolderSequence {
+                folder_id,
+                sequence,
+            } if folder_id == options.folder_id => {
+                catching_up = false;
+                let last = applied_sequence.last;
+                if sequence > last {
+                    info!(
+                        last_applied = last,
+                        sequence, "missed operations are gone, requesting a full sync"
+                    );
+                    send(ws, &ClientMessage::RequestFullSync { folder_id }).await?;
+                    // The full sync stands for the missing operations, and those held
+                    // meanwhile go after them
+                    applied_sequence.record(sequence);
+                    apply_in_order(
+                        ws,
+                        options,
+                        root,
+                        &transfers,
+                        &mut applied_sequence,
+                        &mut reorder,
+                    )
+                    .await?;
+                } else if sequence < last {
+                    // Sequences restart with the server
+                    debug!(last_applied = last, sequence, "server sequence went back");
+                    applied_sequence.record(sequence);
+                }
+                continue;
+            }
+            ServerMessage::FullSyncUnavailable {
+                folder_id,
+                reason,
+                retry_after_secs,
+            } if folder_id == options.folder_id => {
+                warn!(retry_after_secs, "full sync unavailable: {reason}");
+                full_sync_retry = Some(Instant::now() + Duration::from_secs(retry_after_secs));
+                continue;
+            }
+            ServerMessage::ManifestRequested { folder_id } if folder_id == options.folder_id => {
+                let root = root.to_path_buf();
+                let rules = IgnoreRules::load(&root, &options.excludes);
+                let built =
+                    tokio::task::spawn_blocking(move || build_manifest(&root, &rules)).await?;
+                match built {
+                    Ok(manifest) => {
+                        send(
+                            ws,
+                            &ClientMessage::Manifest {
+                                folder_id,
+                                manifest,
+                            },
+                        )
+                        .await?;
+                    }
+                    Err(e) => warn!(outcome = "failed", "cannot send manifest: {e:#}"),
+                }
+                continue;
+            }
+            ServerMessage::SignatureRequested {
+                folder_id,
+                relative_path,
+            } if folder_id == options.folder_id => {
+                let path = resolve(root, &relative_path);
+                let computed = tokio::task::spawn_blocking(move || {
+                    path.and_then(|path| file_signature(&path))
+                })
+                .await?;
+                match computed {
+                    Ok(signature) => {
+                        send(
+                            ws,
+                            &ClientMessage::SignatureResponse {
+                                folder_id,
+                                relative_path,
+                                signature,
+                            },
+                        )
+                        .await?;
+                    }
+                    // The origin then has no delta to send this backup
+                    Err(e) => warn!(path = ?relative_path, "cannot send signature: {e:#}"),
+                }
+                continue;
+            }
+            // Reconnecting then fails, as the folder no longer exists
+            ServerMessage::SyncFolderDeleted { folder_id } if folder_id == options.folder_id => {
+                info!("folder deleted by its origin");
+                return Ok(SessionEnd::Closed);
+            }
+            ServerMessage::OriginSwitched {
+                folder_id,
+                new_origin,
+            } if folder_id == options.folder_id => {
+                if new_origin == options.computer_id {
+                    info!("this computer became the origin");
+                    return Ok(SessionEnd::Switched(Role::Origin));
+                }
+                info!(%new_origin, "origin switched");
+                continue;
+            }
+            ServerMessage::Error { message, trace_id } => {
+                warn!(outcome = "failed", trace_id, "server error: {message}");
+                continue;
+            }
+            message => {
+                debug!(?message, "server message");
+                continue;
+            }
+        };
+        reorder.push(sequence, operation_id, operations);
+        apply_in_order(
+            ws,
+            options,
+            root,
+            &transfers,
+            &mut applied_sequence,
+            &mut reorder,
+        )
+        .await?;
+        if reorder.is_waiting() && !catching_up {
+            info!(
+                last_applied = applied_sequence.last,
+                "operations missing, catching up"
+            );
+            send(
+                ws,
+                &ClientMessage::CatchUp {
+                    folder_id: options.folder_id.clone(),
+                    last_sequence: applied_sequence.last,
+                },
+            )
+            .await?;
+            catching_up = true;
+        }
+    }
+}
+
+/// Applies the operations `reorder` holds for as long as the next one in sequence is
+/// there, answering each with an ack or a nack. A failed operation still takes its
+/// place in the sequence, as the origin is told about it.
+async fn apply_in_order(
+    ws: &mut Connection,
+    options: &RemoteOptions,
+    root: &Path,
+    transfers: &Arc<Mutex<Transfers>>,
+    applied_sequence: &mut AppliedSequence,
+    reorder: &mut Reorder,
+) -> Result<()> {
+    while let Some((sequence, operation_id, operations)) = reorder.pop(applied_sequence.last) {
+        let root = root.to_path_buf();
+        let symlinks = options.symlinks;
+        let transfers = Arc::clone(transfers);
+        let applied = tokio::task::spawn_blocking(move || match operations.as_slice() {
+            [operation] => apply_received(&root, &transfers, operation, symlinks),
+            operations => apply_batch(&root, &transfers, operations, symlinks),
+        })
+        .await?;
+        // Targeted operations reuse the sequence of an operation already applied
+        if sequence > applied_sequence.last {
+            applied_sequence.record(sequence);
+        }
+        match applied {
+            Ok(()) => {
+                debug!(
+                    operation_id,
+                    sequence,
+                    outcome = "applied",
+                    "operation applied"
+                );
+                send(ws, &ClientMessage::Ack { operation_id }).await?;
+            }
+            Err(e) => {
+                warn!(
+                    operation_id,
+                    outcome = "failed",
+                    "failed to apply operation: {e:#}"
+                );
+                let reason = format!("{e:#}");
+                send(
+                    ws,
+                    &ClientMessage::Nack {
+                        operation_id,
+                        reason,
+                    },
+                )
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Operations received by a backup ahead of some it has not got yet, released in the
+/// order of the folder's sequence. They arrive in order from the server, except after
+/// a gap, which a catch up fills with the missing ones and repeats of the others.
+#[derive(Default)]
+struct Reorder {
+    /// By sequence, then operation id, for targeted operations to come after the
+    /// operation whose sequence they reuse
+    pending: BTreeMap<(u64, u64), Vec<FileOperation>>,
+}
+
+impl Reorder {
+    /// Holds an operation until its turn; repeats of one held already are dropped
+    fn push(&mut self, sequence: u64, operation_id: u64, operations: Vec<FileOperation>) {
+        self.pending
+            .entry((sequence, operation_id))
+            .or_insert(operations);
+    }
+
+    /// Next operation to apply after the one of sequence `last`, if it was received
+    fn pop(&mut self, last: u64) -> Option<(u64, u64, Vec<FileOperation>)> {
+        let entry = self.pending.first_entry()?;
+        let (sequence, operation_id) = *entry.key();
+        (sequence <= last + 1).then(|| (sequence, operation_id, entry.remove()))
+    }
+
+    /// Whether operations are held, waiting for missing ones
+    fn is_waiting(&self) -> bool {
+        !self.pending.is_empty()
+    }
+}
+
+/// Completes at `deadline`, never without one.
+async fn wait_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Sequence number of the last operation applied to a backup folder, kept in the state
+/// directory so that a restarted backup knows what it missed.
+struct AppliedSequence {
+    path: PathBuf,
+    last: u64,
+}
+
+impl AppliedSequence {
+    /// Starts from 0 when nothing was recorded yet or the file is unreadable.
+    fn load(state_dir: &Path, root: &Path) -> Self {
+        let path = sequence_path(state_dir, root);
+        let last = fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| content.trim().parse().ok())
+            .unwrap_or(0);
+        Self { path, last }
+    }
+
+    fn record(&mut self, sequence: u64) {
+        self.last = sequence;
+        if let Err(e) = self.save() {
+            warn!("failed to save the applied sequence: {e:#}");
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        let dir = self.path.parent().context("State file has no directory")?;
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create state directory: {dir:?}"))?;
+        let mut file = tempfile::NamedTempFile::new_in(dir)
+            .with_context(|| format!("Failed to create temp file in {dir:?}"))?;
+        write!(file, "{}", self.last)?;
+        file.persist(&self.path)
+            .with_context(|| format!("Failed to write {:?}", self.path))?;
+        Ok(())
+    }
+}
+
+/// File holding the [`AppliedSequence`] of the backup folder at `root`
+#[must_use]
+pub fn sequence_path(state_dir: &Path, root: &Path) -> PathBuf {
+    state_dir.join(format!("sequence-{}.txt", source_key(root)))
+}
+
+/// Heartbeat of a connection, answered by the server with `Pong`
+struct Pings {
+    interval: tokio::time::Interval,
+    nonce: u64,
+}
+
+impl Pings {
+    fn new(period: Duration) -> Self {
+        // Not ticking right away, the handshake just proved the connection alive
+        let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        Self { interval, nonce: 0 }
+    }
+
+    async fn send(&mut self, ws: &mut Connection) -> Result<()> {
+        self.nonce += 1;
+        debug!(nonce = self.nonce, "pinging server");
+        send(ws, &ClientMessage::Ping { nonce: self.nonce }).await
+    }
+}
+
+/// Content size above which a batch is sent without waiting for more operations
+const MAX_BATCH_BYTES: usize = 1024 * 1024;
+
+/// Publishes `operations` in order, grouping the ones small enough into batches so that
+/// a burst of changes takes a few messages instead of one per file.
+async fn publish_all(
+    ws: &mut Connection,
+    options: &RemoteOptions,
+    operations: Vec<FileOperation>,
+) -> Result<()> {
+    let mut batch = Vec::new();
+    let mut batch_bytes = 0;
+    for operation in operations {
+        let size = payload_len(&operation);
+        if size > CHUNK_SIZE {
+            // Sent as a transfer on its own, after everything before it
+            publish_batch(ws, options, std::mem::take(&mut batch)).await?;
+            batch_bytes = 0;
+            publish(ws, options, None, operation).await?;
+            continue;
+        }
+        batch.push(operation);
+        batch_bytes += size;
+        if batch_bytes >= MAX_BATCH_BYTES {
+            publish_batch(ws, options, std::mem::take(&mut batch)).await?;
+            batch_bytes = 0;
+        }
+    }
+    publish_batch(ws, options, batch).await
+}
+
+async fn publish_batch(
+    ws: &mut Connection,
+    options: &RemoteOptions,
+    mut operations: Vec<FileOperation>,
+) -> Result<()> {
+    match operations.len() {
+        0 => Ok(()),
+        1 => publish(ws, options, None, operations.remove(0)).await,
+        count => {
+            debug!(operations = count, "publishing batch");
+            send(
+                ws,
+                &ClientMessage::FolderOperationBatch {
+                    folder_id: options.folder_id.clone(),
+                    operations: operations.into_iter().map(compress_operation).collect(),
+                },
+            )
+            .await
+        }
+    }
+}
+
+fn payload_len(operation: &FileOperation) -> usize {
+    match operation {
+        FileOperation::CreateFile { content, .. } => content.len(),
+        FileOperation::ApplyDelta { delta, .. } => delta.len(),
+        FileOperation::FileChunk { data, .. } => data.len(),
+        _ => 0,
+    }
+}
+
+/// Ids of the transfers published by this process
+static NEXT_TRANSFER_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Publishes `operation` to the backups of the folder, or to the `target` backup only,
+/// splitting files larger than [`CHUNK_SIZE`] into a transfer so that no message holds a
+/// whole large file.
+async fn publish(
+    ws: &mut Connection,
+    options: &RemoteOptions,
+    target: Option<&ComputerId>,
+    operation: FileOperation,
+) -> Result<()> {
+    let operations = match operation {
+        FileOperation::CreateFile {
+            relative_path,
+            content,
+            ..
+        } if content.len() > CHUNK_SIZE => {
+            let transfer_id = NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed);
+            debug!(transfer_id, path = ?relative_path, size = content.len(), "publishing transfer");
+            transfer::split(transfer_id, relative_path, &content, CHUNK_SIZE)
+        }
+        operation => {
+            debug!(?operation, "publishing operation");
+            vec![operation]
+        }
+    };
+    for operation in operations {
+        let folder_id = options.folder_id.clone();
+        let operation = compress_operation(operation);
+        let message = match target {
+            Some(computer_id) => ClientMessage::TargetedOperation {
+                folder_id,
+                computer_id: computer_id.clone(),
+                operation,
+            },
+            None => ClientMessage::FolderOperation {
+                folder_id,
+                operation,
+            },
+        };
+        send(ws, &message).await?;
+    }
+    Ok(())
+}
+
+async fn send(ws: &mut Connection, message: &ClientMessage) -> Result<()> {
+    let message = match codec::encode(message, ws.encoding)? {
+        Frame::Text(text) => Message::Text(text.into()),
+        Frame::Binary(bytes) => Message::Binary(bytes.into()),
+    };
+    ws.stream
+        .send(message)
+        .await
+        .context("Failed to send message")
+}
+
+/// Next message from the server, or `None` once the connection is closed.
+async fn recv(ws: &mut Connection) -> Result<Option<ServerMessage>> {
+    while let Some(message) = ws.stream.next().await {
+        let decoded = match message.context("Failed to receive message")? {
+            Message::Text(text) => codec::decode_text(&text),
+            Message::Binary(bytes) => codec::decode_binary(&bytes),
+            Message::Close(_) => return Ok(None),
+            _ => continue,
+        };
+        match decoded {
+            Ok(message) => return Ok(Some(message)),
+            // Skipped rather than failing the connection, as it is most likely an
+            // operation added in a newer version that this client cannot apply anyway
+            Err(e) => warn!("skipping unsupported message from server: {e}"),
+        }
+    }
+    Ok(None)
+}
+
+/// Operations recreating the whole tree under `root`, parents before their children.
+/// File contents are sent whole, followed by the permissions and mtime of the file.
+pub fn snapshot_operations(root: &Path, rules: &IgnoreRules) -> Result<Vec<FileOperation>> {
+    let mut operations = Vec::new();
+    let entries = WalkDir::new(root)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            entry
+                .path()
+                .strip_prefix(root)
+                .is_ok_and(|relative| !rules.is_ignored(relative, entry.file_type().is_dir()))
+        });
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to walk {root:?}"))?;
+        if let Some(operation) = current_state(root, entry.path())? {
+            operations.push(operation);
+        }
+        if entry.file_type().is_file()
+            && let Some(operation) = metadata_operation(root, entry.path())
+        {
+            operations.push(operation);
+        }
+    }
+    Ok(operations)
+}
+
+/// Operations publishing one watcher event. Apart from renames, an event is published as
+/// the current state of its paths, so a path that no longer exists becomes a removal.
+#[must_use]
+pub fn operations_for_event(
+    root: &Path,
+    rules: &IgnoreRules,
+    event: &DebouncedEvent,
+) -> Vec<FileOperation> {
+    let relative = |path: &Path| {
+        let relative = path.strip_prefix(root).ok()?;
+        if rules.is_ignored(relative, path.is_dir()) {
+            return None;
+        }
+        RelativePath::new(relative).ok()
+    };
+    match (event.kind, event.paths.as_slice()) {
+        (EventKind::Access(_), _) => Vec::new(),
+        (EventKind::Modify(ModifyKind::Metadata(_)), paths) => paths
+            .iter()
+            .filter(|path| relative(path.as_path()).is_some())
+            .filter_map(|path| metadata_operation(root, path))
+            .collect(),
+        (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) => {
+            match (relative(from.as_path()), relative(to.as_path())) {
+                (Some(from_relative), Some(to_relative)) => vec![FileOperation::RenameFile {
+                    from_relative,
+                    to_relative,
+                }],
+                // Renamed into or out of an ignored path
+                _ => [from, to]
+                    .into_iter()
+                    .filter_map(|path| state_or_warn(root, path))
+                    .collect(),
+            }
+        }
+        (_, paths) => paths
+            .iter()
+            .filter(|path| relative(path.as_path()).is_some())
+            .filter_map(|path| state_or_warn(root, path))
+            .collect(),
+    }
+}
+
+/// Operations publishing the current state of `path`, with the metadata of files
+fn path_operations(root: &Path, path: &Path) -> Vec<FileOperation> {
+    let mut operations: Vec<_> = state_or_warn(root, path).into_iter().collect();
+    if path.is_file()
+        && let Some(operation) = metadata_operation(root, path)
+    {
+        operations.push(operation);
+    }
+    operations
+}
+
+fn state_or_warn(root: &Path, path: &Path) -> Option<FileOperation> {
+    current_state(root, path).unwrap_or_else(|e| {
+        warn!(?path, outcome = "failed", "failed to read change: {e:#}");
+        None
+    })
+}
+
+/// Operation bringing a backup's copy of `path` to its current state, `None` for paths
+/// outside of `root` or entries that are neither files, directories nor symlinks.
+fn current_state(root: &Path, path: &Path) -> Result<Option<FileOperation>> {
+    let Ok(relative) = path.strip_prefix(root) else {
+        return Ok(None);
+    };
+    if relative.as_os_str().is_empty() {
+        return Ok(None);
+    }
+    let relative_path =
+        RelativePath::new(relative).with_context(|| format!("Cannot sync {path:?}"))?;
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return Ok(Some(FileOperation::RemoveFile { relative_path }));
+    };
+    if metadata.is_symlink() {
+        let target = fs::read_link(path)
+            .with_context(|| format!("Failed to read link {path:?}"))?
+            .into_os_string()
+            .into_string()
+            .map_err(|target| anyhow!("Link target is not valid UTF-8: {target:?}"))?;
+        Ok(Some(FileOperation::CreateSymlink {
+            relative_path,
+            target,
+        }))
+    } else if metadata.is_dir() {
+        Ok(Some(FileOperation::CreateDir { relative_path }))
+    } else if metadata.is_file() {
+        let content = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+        let hash = blake3::hash(&content).into();
+        Ok(Some(FileOperation::CreateFile {
+            relative_path,
+            content,
+            hash,
+            compression: None,
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Permissions and mtime of `path`, `None` for the root, symlinks or paths that no
+/// longer exist.
+fn metadata_operation(root: &Path, path: &Path) -> Option<FileOperation> {
+    let relative_path = RelativePath::new(path.strip_prefix(root).ok()?).ok()?;
+    let metadata = fs::symlink_metadata(path).ok()?;
+    if metadata.is_symlink() {
+        return None;
+    }
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode() & 0o7777)
+    };
+    #[cfg(not(unix))]
+    let mode = None;
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .and_then(|since_epoch| i64::try_from(since_epoch.as_secs()).ok());
+    Some(FileOperation::SetMetadata {
+        relative_path,
+        mode,
+        readonly: Some(metadata.permissions().readonly()),
+        mtime,
+    })
+}
+
+/// Applies the fields of a [`FileOperation::SetMetadata`] that are set.
+fn apply_metadata(
+    path: &Path,
+    mode: Option<u32>,
+    readonly: Option<bool>,
+    mtime: Option<i64>,
+) -> Result<()> {
+    // Before the permissions, which may make the file read-only
+    if let Some(mtime) = mtime {
+        let modified = UNIX_EPOCH + Duration::from_secs(u64::try_from(mtime).unwrap_or(0));
+        fs::File::open(path)
+            .and_then(|file| file.set_modified(modified))
+            .with_context(|| format!("Failed to set modification time of {path:?}"))?;
+    }
+
+    let mut permissions = fs::metadata(path)
+        .with_context(|| format!("Failed to read metadata of {path:?}"))?
+        .permissions();
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        permissions.set_mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    if let Some(readonly) = readonly {
+        permissions.set_readonly(readonly);
+    }
+    fs::set_permissions(path, permissions)
+        .with_context(|| format!("Failed to set permissions of {path:?}"))
+}
+
+/// Replaces whatever is at `path` with a link to `target`. The target is not resolved:
+/// it is relative to the link, and may point outside of the folder or nowhere at all.
+fn create_symlink(root: &Path, path: &Path, target: &str, fallback: SymlinkFallback) -> Result<()> {
+    let parent = path.parent().unwrap_or(root);
+    LocalFileOps::create_dir_all(parent)?;
+
+    #[cfg(unix)]
+    {
+        let _ = fallback;
+        remove_existing(path)?;
+        std::os::unix::fs::symlink(target, path)
+            .with_context(|| format!("Failed to create symlink {path:?} -> {target:?}"))
+    }
+    #[cfg(not(unix))]
+    match fallback {
+        SymlinkFallback::Skip => {
+            bail!("Symlinks are not supported on this platform, skipping {path:?}")
+        }
+        SymlinkFallback::Copy => {
+            let root = fs::canonicalize(root)?;
+            let source = fs::canonicalize(parent.join(target))
+                .with_context(|| format!("Symlink target does not exist: {target:?}"))?;
+            if !source.starts_with(&root) || !source.is_file() {
+                bail!("Cannot copy symlink target outside of the folder or not a file: {target:?}");
+            }
+            remove_existing(path)?;
+            fs::copy(&source, path).with_context(|| format!("Failed to copy {source:?}"))?;
+            Ok(())
+        }
+    }
+}
+
+/// Removes the file, link or directory at `path`, if any.
+fn remove_existing(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => LocalFileOps::remove_dir_all(path),
+        Ok(_) => LocalFileOps::remove_file(path),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Joins a relative path received from the network to `root`, refusing anything that
+/// could point outside of it.
+///
+/// Checking the path alone is not enough: a symlink created by an earlier operation
+/// could sit in one of its parents and lead anywhere, so parents that are links are
+/// refused too. The last component may be a link, which operations replace or remove.
+pub(crate) fn resolve(root: &Path, relative: &Path) -> Result<PathBuf> {
+    let is_safe = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !is_safe || relative.as_os_str().is_empty() {
+        bail!("Refusing path outside of the folder: {relative:?}");
+    }
+    let path = root.join(relative);
+    let mut parent = root.to_path_buf();
+    let mut components = relative.components().peekable();
+    while let Some(component) = components.next() {
+        if components.peek().is_none() {
+            break;
+        }
+        parent.push(component);
+        match fs::symlink_metadata(&parent) {
+            Ok(metadata) if metadata.is_symlink() => {
+                bail!("Refusing path through the symlink {parent:?}: {relative:?}")
+            }
+            Ok(_) => {}
+            // Nothing below a missing parent exists either
+            Err(_) => break,
+        }
+    }
+    Ok(path)
+}
+
+/// Like [`resolve`], also refusing a path that is itself a symlink, for operations that
+/// would follow it.
+fn resolve_no_follow(root: &Path, relative: &Path) -> Result<PathBuf> {
+    let path = resolve(root, relative)?;
+    if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_symlink()) {
+        bail!("Refusing to follow the symlink {path:?}");
+    }
+    Ok(path)
+}
+
+/// Applies the operations of a batch in order and stops at the first one that fails.
+/// The operations before it stay applied, and the error names the index of the failed
+/// one; the batch is acked only if all of them applied.
+pub fn apply_batch(
+    root: &Path,
+    transfers: &Mutex<Transfers>,
+    operations: &[FileOperation],
+    symlinks: SymlinkFallback,
+) -> Result<()> {
+    for (index, operation) in operations.iter().enumerate() {
+        apply_received(root, transfers, operation, symlinks).with_context(|| {
+            format!(
+                "Operation {index} of a batch of {} failed",
+                operations.len()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Applies an operation received from the origin, routing the pieces of transfers
+/// through `transfers`.
+fn apply_received(
+    root: &Path,
+    transfers: &Mutex<Transfers>,
+    operation: &FileOperation,
+    symlinks: SymlinkFallback,
+) -> Result<()> {
+    if Transfers::handles(operation) {
+        let mut transfers = transfers
+            .lock()
+            .map_err(|_| anyhow!("Transfers lock poisoned"))?;
+        transfers.apply(operation).map(|_| ())
+    } else {
+        apply_operation(root, operation, symlinks)
+    }
+}
+
+/// Applies an operation received from the origin to the backup folder at `root`.
+#[instrument(skip(operation))]
+pub fn apply_operation(
+    root: &Path,
+    operation: &FileOperation,
+    symlinks: SymlinkFallback,
+) -> Result<()> {
+    match operation {
+        FileOperation::CreateFile {
+            relative_path,
+            content,
+            hash,
+            compression,
+        } => {
+            let path = resolve(root, relative_path)?;
+            let content = decompress(content, *compression, MAX_DECOMPRESSED_LEN)
+                .with_context(|| format!("Invalid content for {path:?}"))?;
+            let expected = blake3::Hash::from_bytes(*hash);
+            let actual = blake3::hash(&content);
+            if actual != expected {
+                bail!(
+                    "Integrity check failed for {path:?}: expected {}, got {}",
+                    expected.to_hex(),
+                    actual.to_hex()
+                );
+            }
+            let parent = path.parent().unwrap_or(root);
+            LocalFileOps::create_dir_all(parent)?;
+            if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_dir()) {
+                LocalFileOps::remove_dir_all(&path)?;
+            }
+            // Written aside and renamed, so readers never see a partial file
+            let mut file = tempfile::NamedTempFile::new_in(parent)
+                .with_context(|| format!("Failed to create temp file in {parent:?}"))?;
+            file.write_all(&content)
+                .with_context(|| format!("Failed to write {path:?}"))?;
+            file.persist(&path)
+                .with_context(|| format!("Failed to write {path:?}"))?;
+            Ok(())
+        }
+        FileOperation::CreateDir { relative_path } => {
+            let path = resolve(root, relative_path)?;
+            if fs::symlink_metadata(&path).is_ok_and(|metadata| !metadata.is_dir()) {
+                LocalFileOps::remove_file(&path)?;
+            }
+            LocalFileOps::create_dir_all(&path)
+        }
+        FileOperation::RemoveFile { relative_path }
+        | FileOperation::RemoveDir { relative_path } => {
+            remove_existing(&resolve(root, relative_path)?)
+        }
+        FileOperation::CreateSymlink {
+            relative_path,
+            target,
+        } => create_symlink(root, &resolve(root, relative_path)?, target, symlinks),
+        FileOperation::RenameFile {
+            from_relative,
+            to_relative,
+        } => {
+            LocalFileOps::rename_file(&resolve(root, from_relative)?, &resolve(root, to_relative)?)
+        }
+        FileOperation::SetMetadata {
+            relative_path,
+            mode,
+            readonly,
+            mtime,
+        } => apply_metadata(
+            &resolve_no_follow(root, relative_path)?,
+            *mode,
+            *readonly,
+            *mtime,
+        ),
+        FileOperation::ApplyDelta {
+            relative_path,
+            delta,
+            hash,
+            compression,
+            ..
+        } => {
+            let path = resolve_no_follow(root, relative_path)?;
+            let delta = decompress(delta, *compression, MAX_DECOMPRESSED_LEN)
+                .with_context(|| format!("Invalid delta for {path:?}"))?;
+            let expected_hash = blake3::Hash::from_bytes(*hash).to_hex().to_string();
+            apply_delta_securely(root, relative_path, delta.into_owned(), expected_hash)
+        }
+        other => bail!("Unsupported operation for a backup: {other:?}"),
+    }
+}
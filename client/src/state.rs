@@ -1,41 +1,352 @@
-use crate::synchronizer::{SyncOptions, Synchronizer};
+use crate::health::HealthStatus;
+use crate::ignore_rules::IGNORE_FILE_NAME;
+use crate::instance::InstanceLock;
+use crate::status::{FileError, STATUS_VERSION, StatusSnapshot, unix_secs};
+use crate::synchronizer::{SyncAction, SyncOptions, SyncReport, Synchronizer};
 use anyhow::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use notify::EventKind;
 use notify::event::{ModifyKind, RenameMode};
 use notify_debouncer_full::DebouncedEvent;
 use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
-use std::path::PathBuf;
-use std::sync::RwLock;
-use tracing::{debug, info, instrument};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::time::SystemTime;
+use tracing::{debug, error, info, instrument, warn};
+
+/// Times a path of the retry queue is retried before it is given up on
+pub const MAX_RETRY_ATTEMPTS: u32 = 10;
 
 pub struct AppState {
     syncer: RwLock<Synchronizer>,
+    /// Original paths skipped because another process held a lock on them, with the
+    /// times they were retried
+    retry_queue: Mutex<HashMap<PathBuf, u32>>,
+    dry_run: AtomicBool,
+    /// File names of editor temp files that are never propagated to the backup
+    temp_patterns: GlobSet,
+    last_event_at: Mutex<Option<SystemTime>>,
+    last_full_sync_ok: Mutex<Option<bool>>,
+    /// When the last full sync finished, with the per-file errors it recorded
+    last_full_sync: Mutex<Option<(SystemTime, Vec<FileError>)>>,
+    /// Held for the lifetime of the state so a second instance cannot watch the same source
+    instance: Option<InstanceLock>,
+}
+
+/// How a rename relates to an editor's atomic save.
+enum RenameKind {
+    /// A regular rename that is mirrored to the backup
+    Plain,
+    /// An untracked temp file was renamed over the destination, so the destination changed
+    TempReplacesTarget,
+    /// The original was moved aside to a temp name before being replaced
+    TargetMovedAside,
+}
+
+fn build_temp_patterns(patterns: &[String]) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        match Glob::new(pattern) {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(e) => warn!("ignoring invalid temp file pattern {pattern:?}: {e}"),
+        }
+    }
+    builder.build().unwrap_or_else(|e| {
+        warn!("failed to build temp file patterns: {e}");
+        GlobSet::empty()
+    })
 }
 
 impl AppState {
     #[must_use]
     pub fn new(sync: Synchronizer) -> Self {
         Self {
+            dry_run: AtomicBool::new(sync.options().is_dry_run()),
+            temp_patterns: build_temp_patterns(&sync.options().temp_patterns()),
             syncer: RwLock::new(sync),
+            retry_queue: Mutex::new(HashMap::new()),
+            last_event_at: Mutex::new(None),
+            last_full_sync_ok: Mutex::new(None),
+            last_full_sync: Mutex::new(None),
+            instance: None,
         }
     }
 
+    /// Claims the source root for this process, then mirrors it into the backup.
+    /// Fails if another instance is already watching the same source.
     pub fn new_with_local_sync(
         original: PathBuf,
         backup: PathBuf,
         options: SyncOptions,
     ) -> Result<Self> {
-        let mut syncer = Synchronizer::new(original.clone(), backup.clone())
+        let instance = InstanceLock::acquire(&options.state_dir(), &original)?;
+        let syncer = Synchronizer::new_with_options(original.clone(), backup.clone(), options)
             .with_context(|| {
                 format!("Failed to create synchronizer for {original:?} -> {backup:?}")
-            })?
-            .with_options(options);
-        syncer.sync().context("Failed to perform initial sync")?;
-        Ok(Self::new(syncer))
+            })?;
+        let mut state = Self::new(syncer);
+        state.instance = Some(instance);
+        if state.is_dry_run() {
+            for action in state.plan().context("Failed to plan initial sync")? {
+                info!(?action, "dry run: would apply");
+            }
+        } else {
+            state
+                .full_sync()
+                .context("Failed to perform initial sync")?;
+        }
+        Ok(state)
+    }
+
+    /// The claim on the source root, when the state was created by
+    /// [`AppState::new_with_local_sync`].
+    #[must_use]
+    pub fn instance_lock(&self) -> Option<&InstanceLock> {
+        self.instance.as_ref()
+    }
+
+    #[must_use]
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run.load(Ordering::SeqCst)
+    }
+
+    /// Switches dry-run mode. Leaving dry-run runs a full sync so the backup catches up
+    /// with everything that was only logged in the meantime.
+    pub fn set_dry_run(&self, dry_run: bool) -> Result<()> {
+        let was_dry_run = self.dry_run.swap(dry_run, Ordering::SeqCst);
+        if was_dry_run && !dry_run {
+            self.full_sync()
+                .context("Failed to sync after leaving dry run")?;
+        }
+        Ok(())
+    }
+
+    /// Actions a full sync would currently perform.
+    pub fn plan(&self) -> Result<Vec<SyncAction>> {
+        self.syncer
+            .read()
+            .map_err(|e| anyhow::anyhow!("Failed to acquire read lock on syncer: {e}"))?
+            .plan()
+    }
+
+    /// Runs a full reconciliation, queueing files that were locked for a later retry.
+    pub fn full_sync(&self) -> Result<SyncReport> {
+        let mut syncer = self
+            .syncer
+            .write()
+            .map_err(|e| anyhow::anyhow!("Failed to acquire write lock on syncer: {e}"))?;
+        let result = syncer.sync();
+        *self
+            .last_full_sync_ok
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to acquire lock on sync status: {e}"))? =
+            Some(result.as_ref().is_ok_and(|report| !report.has_errors()));
+        let report = result?;
+        let errors = report
+            .errors
+            .iter()
+            .map(|(path, error)| FileError {
+                path: path.clone(),
+                error: error.clone(),
+            })
+            .collect();
+        *self
+            .last_full_sync
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to acquire lock on sync status: {e}"))? =
+            Some((SystemTime::now(), errors));
+        let mut queue = self.lock_retry_queue()?;
+        for relative in &report.skipped_locked {
+            queue
+                .entry(syncer.original_root().join(relative))
+                .or_insert(0);
+        }
+        drop(queue);
+        Ok(report)
+    }
+
+    pub fn retry_queue_len(&self) -> Result<usize> {
+        Ok(self.lock_retry_queue()?.len())
+    }
+
+    /// Takes a health snapshot, actively probing that the backup root is still there.
+    pub fn health(&self) -> Result<HealthStatus> {
+        let backup_root = self.read_syncer()?.backup_root().clone();
+        let backup_reachable = fs::metadata(&backup_root).is_ok_and(|m| m.is_dir());
+        let last_event_at = *self
+            .last_event_at
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to acquire lock on event status: {e}"))?;
+        let last_full_sync_ok = *self
+            .last_full_sync_ok
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to acquire lock on sync status: {e}"))?;
+        Ok(HealthStatus::new(
+            backup_reachable,
+            last_event_at,
+            self.retry_queue_len()?,
+            last_full_sync_ok,
+        ))
+    }
+
+    /// The current state in the form persisted for `status`.
+    pub fn status_snapshot(&self) -> Result<StatusSnapshot> {
+        let health = self.health()?;
+        let (source, backup) = {
+            let syncer = self.read_syncer()?;
+            (syncer.original_root().clone(), syncer.backup_root().clone())
+        };
+        let (last_full_sync_at, errors) = self
+            .last_full_sync
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to acquire lock on sync status: {e}"))?
+            .clone()
+            .map_or((None, Vec::new()), |(at, errors)| {
+                (Some(unix_secs(at)), errors)
+            });
+        Ok(StatusSnapshot {
+            version: STATUS_VERSION,
+            pid: std::process::id(),
+            source,
+            backup,
+            level: health.level,
+            updated_at: unix_secs(SystemTime::now()),
+            last_event_at: health.last_event_at.map(unix_secs),
+            last_full_sync_at,
+            last_full_sync_ok: health.last_full_sync_ok,
+            retry_queue_len: health.retry_queue_len,
+            errors,
+        })
+    }
+
+    /// Persists the status snapshot into the state directory. Called periodically by the
+    /// watcher so that `status` can report on a running daemon from another process.
+    pub fn write_status(&self) -> Result<()> {
+        let state_dir = self.read_syncer()?.options().state_dir();
+        self.status_snapshot()?.write(&state_dir)
+    }
+
+    fn record_event_processed(&self) -> Result<()> {
+        *self
+            .last_event_at
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to acquire lock on event status: {e}"))? =
+            Some(SystemTime::now());
+        Ok(())
+    }
+
+    /// Re-processes every path that was skipped because it was locked. Paths that are
+    /// still locked or fail go back onto the queue, until they were retried
+    /// [`MAX_RETRY_ATTEMPTS`] times; paths that vanished in the meantime are dropped.
+    #[instrument(level = "debug", skip(self))]
+    pub fn process_retry_queue(&self) -> Result<()> {
+        let pending = std::mem::take(&mut *self.lock_retry_queue()?);
+        let mut retried = Vec::with_capacity(pending.len());
+        for (path, attempts) in pending {
+            if !path.exists() {
+                continue;
+            }
+            let failed = match self.process_create_path(&path) {
+                Ok(()) => false,
+                Err(e) => {
+                    warn!("retry failed: {path:?}: {e:#}");
+                    true
+                }
+            };
+            retried.push((path, attempts + 1, failed));
+        }
+
+        let mut queue = self.lock_retry_queue()?;
+        for (path, attempts, failed) in retried {
+            // Paths still locked were queued again by the retry itself
+            if !failed && !queue.contains_key(&path) {
+                continue;
+            }
+            if attempts >= MAX_RETRY_ATTEMPTS {
+                queue.remove(&path);
+                error!("giving up on {path:?} after {attempts} retries");
+            } else {
+                queue.insert(path, attempts);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_syncer(&self) -> Result<RwLockReadGuard<'_, Synchronizer>> {
+        self.syncer
+            .read()
+            .map_err(|e| anyhow::anyhow!("Failed to acquire read lock on syncer: {e}"))
+    }
+
+    /// Whether the path looks like an editor temp file we have never mirrored.
+    fn is_untracked_temp(&self, syncer: &Synchronizer, path: &PathBuf) -> bool {
+        self.is_temp_name(path) && !syncer.is_tracked(path)
+    }
+
+    /// Whether events for the path must not reach the backup at all.
+    fn should_skip(&self, syncer: &Synchronizer, path: &PathBuf) -> bool {
+        self.is_untracked_temp(syncer, path) || syncer.is_ignored(path)
+    }
+
+    fn is_temp_name(&self, path: &Path) -> bool {
+        path.file_name()
+            .is_some_and(|name| self.temp_patterns.is_match(name))
+    }
+
+    fn classify_rename(&self, syncer: &Synchronizer, from: &PathBuf, to: &Path) -> RenameKind {
+        if self.is_untracked_temp(syncer, from) {
+            RenameKind::TempReplacesTarget
+        } else if self.is_temp_name(to) {
+            RenameKind::TargetMovedAside
+        } else {
+            RenameKind::Plain
+        }
+    }
+
+    fn lock_retry_queue(&self) -> Result<MutexGuard<'_, HashMap<PathBuf, u32>>> {
+        self.retry_queue
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to acquire lock on retry queue: {e}"))
+    }
+
+    /// Returns `true` if the path was locked and has been queued for a later retry.
+    fn defer_if_locked(&self, original_path: &PathBuf) -> Result<bool> {
+        if !original_path.is_file() {
+            return Ok(false);
+        }
+        let lock = self
+            .syncer
+            .read()
+            .map_err(|e| anyhow::anyhow!("Failed to acquire read lock on syncer: {e}"))?
+            .try_lock_original(original_path)?;
+        if lock.is_some() {
+            return Ok(false);
+        }
+        warn!("file is locked by another process, retrying later: {original_path:?}");
+        self.lock_retry_queue()?
+            .entry(original_path.clone())
+            .or_insert(0);
+        Ok(true)
     }
 
-    #[instrument(skip(self))]
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(kind = ?event.kind, paths = event.paths.len())
+    )]
     pub fn process_debounced_event(&self, event: &DebouncedEvent) -> Result<()> {
+        self.reload_ignore_rules_if_changed(event)?;
+        if self.is_dry_run() {
+            for action in self.plan_debounced_event(event)? {
+                info!(?action, "dry run: would apply");
+            }
+            return self.record_event_processed();
+        }
         match event.kind {
             EventKind::Modify(ModifyKind::Data(_)) => {
                 event
@@ -43,10 +354,8 @@ impl AppState {
                     .par_iter()
                     .try_for_each(|x| self.process_modified_path(x))?;
             }
-            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
-                if event.paths.len() >= 2 {
-                    self.process_rename_path(&event.paths[0], &event.paths[1])?;
-                }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() >= 2 => {
+                self.process_rename_path(&event.paths[0], &event.paths[1])?;
             }
             EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
                 event
@@ -62,11 +371,96 @@ impl AppState {
             }
             _ => {}
         }
+        self.record_event_processed()
+    }
+
+    /// Re-reads the ignore rules when the event touches the ignore file of the source root.
+    fn reload_ignore_rules_if_changed(&self, event: &DebouncedEvent) -> Result<()> {
+        let ignore_file = self.read_syncer()?.original_root().join(IGNORE_FILE_NAME);
+        if event.paths.contains(&ignore_file) {
+            info!("ignore rules changed, reloading: {ignore_file:?}");
+            self.syncer
+                .write()
+                .map_err(|e| anyhow::anyhow!("Failed to acquire write lock on syncer: {e}"))?
+                .reload_ignore_rules();
+        }
         Ok(())
     }
 
-    #[instrument(skip(self))]
+    /// Resolves an event to the actions processing it would perform, without applying them.
+    pub fn plan_debounced_event(&self, event: &DebouncedEvent) -> Result<Vec<SyncAction>> {
+        let syncer = self.read_syncer()?;
+        let mut actions = Vec::new();
+        let paths = event
+            .paths
+            .iter()
+            .filter(|path| !self.should_skip(&syncer, path));
+        match event.kind {
+            EventKind::Modify(ModifyKind::Data(_)) => {
+                for path in paths {
+                    actions.extend(syncer.plan_original_modified(path)?);
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() >= 2 => {
+                let (from, to) = (&event.paths[0], &event.paths[1]);
+                match (syncer.is_ignored(from), syncer.is_ignored(to)) {
+                    (true, true) => return Ok(actions),
+                    (true, false) => {
+                        actions.push(syncer.plan_original_created(to)?);
+                        return Ok(actions);
+                    }
+                    (false, true) => {
+                        actions.extend(syncer.plan_original_deleted(from)?);
+                        return Ok(actions);
+                    }
+                    (false, false) => {}
+                }
+                match self.classify_rename(&syncer, from, to) {
+                    RenameKind::Plain => {
+                        actions.push(syncer.plan_original_renamed(from, to)?);
+                    }
+                    RenameKind::TempReplacesTarget if syncer.is_tracked(to) => {
+                        actions.extend(syncer.plan_original_modified(to)?);
+                    }
+                    RenameKind::TempReplacesTarget => {
+                        actions.push(syncer.plan_original_created(to)?);
+                    }
+                    RenameKind::TargetMovedAside if from.is_file() => {
+                        actions.extend(syncer.plan_original_modified(from)?);
+                    }
+                    RenameKind::TargetMovedAside => {
+                        actions.extend(syncer.plan_original_deleted(from)?);
+                    }
+                }
+            }
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                for path in paths {
+                    actions.push(syncer.plan_original_created(path)?);
+                }
+            }
+            EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                for path in paths {
+                    if path.is_file() && syncer.is_tracked(path) {
+                        actions.extend(syncer.plan_original_modified(path)?);
+                    } else {
+                        actions.extend(syncer.plan_original_deleted(path)?);
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(actions)
+    }
+
+    #[instrument(level = "debug", skip(self))]
     fn process_modified_path(&self, original_path: &PathBuf) -> Result<()> {
+        if self.should_skip(&*self.read_syncer()?, original_path) {
+            debug!("skipping ignored or temp file: {original_path:?}");
+            return Ok(());
+        }
+        if self.defer_if_locked(original_path)? {
+            return Ok(());
+        }
         let delta = self
             .syncer
             .read()
@@ -85,8 +479,15 @@ impl AppState {
             .with_context(|| format!("Failed to apply delta for: {original_path:?}"))
     }
 
-    #[instrument(skip(self))]
+    #[instrument(level = "debug", skip(self))]
     fn process_create_path(&self, original_path: &PathBuf) -> Result<()> {
+        if self.should_skip(&*self.read_syncer()?, original_path) {
+            debug!("skipping ignored or temp file: {original_path:?}");
+            return Ok(());
+        }
+        if self.defer_if_locked(original_path)? {
+            return Ok(());
+        }
         info!("created file: {original_path:?}");
         self.syncer
             .write()
@@ -95,8 +496,20 @@ impl AppState {
             .with_context(|| format!("Failed to handle created file: {original_path:?}"))
     }
 
-    #[instrument(skip(self))]
+    #[instrument(level = "debug", skip(self))]
     fn process_delete_path(&self, original_path: &PathBuf) -> Result<()> {
+        {
+            let syncer = self.read_syncer()?;
+            if self.should_skip(&syncer, original_path) {
+                debug!("skipping ignored or temp file: {original_path:?}");
+                return Ok(());
+            }
+            if original_path.is_file() && syncer.is_tracked(original_path) {
+                // Replaced before the event was processed, as editors do when saving atomically
+                drop(syncer);
+                return self.process_modified_path(original_path);
+            }
+        }
         info!("deleted file: {original_path:?}");
         self.syncer
             .write()
@@ -105,8 +518,38 @@ impl AppState {
             .with_context(|| format!("Failed to handle deleted file: {original_path:?}"))
     }
 
-    #[instrument(skip(self))]
+    #[instrument(level = "debug", skip(self))]
     fn process_rename_path(&self, from_path: &PathBuf, to_path: &PathBuf) -> Result<()> {
+        let (kind, to_tracked, ignored) = {
+            let syncer = self.read_syncer()?;
+            (
+                self.classify_rename(&syncer, from_path, to_path),
+                syncer.is_tracked(to_path),
+                (syncer.is_ignored(from_path), syncer.is_ignored(to_path)),
+            )
+        };
+        match ignored {
+            (true, true) => return Ok(()),
+            (true, false) => return self.process_create_path(to_path),
+            (false, true) => return self.process_delete_path(from_path),
+            (false, false) => {}
+        }
+        match kind {
+            RenameKind::TempReplacesTarget if to_tracked => {
+                info!("atomic save replaced file: {to_path:?}");
+                return self.process_modified_path(to_path);
+            }
+            RenameKind::TempReplacesTarget => {
+                info!("atomic save created file: {to_path:?}");
+                return self.process_create_path(to_path);
+            }
+            RenameKind::TargetMovedAside if from_path.is_file() => {
+                debug!("file moved aside before being replaced: {from_path:?}");
+                return self.process_modified_path(from_path);
+            }
+            RenameKind::TargetMovedAside => return self.process_delete_path(from_path),
+            RenameKind::Plain => {}
+        }
         info!("renamed file: {from_path:?} -> {to_path:?}");
         self.syncer
             .write()
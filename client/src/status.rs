@@ -0,0 +1,105 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::health::HealthLevel;
+use crate::instance::{InstanceLock, source_key};
+
+/// Bumped whenever a field changes meaning or is removed
+pub const STATUS_VERSION: u32 = 1;
+
+/// A per-file error recorded by the last full sync
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileError {
+    pub path: PathBuf,
+    pub error: String,
+}
+
+/// State of one folder pair as persisted in the state directory, so that it can be
+/// read by another process. Times are seconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusSnapshot {
+    pub version: u32,
+    pub pid: u32,
+    pub source: PathBuf,
+    pub backup: PathBuf,
+    pub level: HealthLevel,
+    pub updated_at: u64,
+    pub last_event_at: Option<u64>,
+    pub last_full_sync_at: Option<u64>,
+    pub last_full_sync_ok: Option<bool>,
+    pub retry_queue_len: usize,
+    pub errors: Vec<FileError>,
+}
+
+#[must_use]
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Status file of the pair whose source is `source_root`.
+#[must_use]
+pub fn status_path(state_dir: &Path, source_root: &Path) -> PathBuf {
+    state_dir.join(format!("status-{}.json", source_key(source_root)))
+}
+
+impl StatusSnapshot {
+    /// Writes the snapshot through a temp file, so that readers never see it half written.
+    pub fn write(&self, state_dir: &Path) -> Result<()> {
+        fs::create_dir_all(state_dir)
+            .with_context(|| format!("Failed to create state directory: {state_dir:?}"))?;
+        let path = status_path(state_dir, &self.source);
+        let mut file = tempfile::NamedTempFile::new_in(state_dir)
+            .with_context(|| format!("Failed to create temp file in {state_dir:?}"))?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.flush()?;
+        file.persist(&path)
+            .with_context(|| format!("Failed to write status file: {path:?}"))?;
+        Ok(())
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let content =
+            fs::read(path).with_context(|| format!("Failed to read status file: {path:?}"))?;
+        serde_json::from_slice(&content).with_context(|| format!("Invalid status file: {path:?}"))
+    }
+
+    /// Every snapshot found in `state_dir`, ordered by source. Unreadable files are skipped.
+    pub fn read_all(state_dir: &Path) -> Result<Vec<Self>> {
+        if !state_dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut snapshots = Vec::new();
+        for entry in fs::read_dir(state_dir)
+            .with_context(|| format!("Failed to read state directory: {state_dir:?}"))?
+        {
+            let path = entry?.path();
+            let is_status = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("status-") && name.ends_with(".json"));
+            if !is_status {
+                continue;
+            }
+            match Self::read(&path) {
+                Ok(snapshot) => snapshots.push(snapshot),
+                Err(e) => warn!("skipping status file: {e:#}"),
+            }
+        }
+        snapshots.sort_by(|a, b| a.source.cmp(&b.source));
+        Ok(snapshots)
+    }
+
+    /// Whether an instance currently holds the source, as opposed to a snapshot left
+    /// behind by a daemon that has stopped.
+    #[must_use]
+    pub fn is_running(&self, state_dir: &Path) -> bool {
+        InstanceLock::is_held(state_dir, &self.source)
+    }
+}
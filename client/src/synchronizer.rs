@@ -1,18 +1,89 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use crate::folder_structure::FolderStructure;
+use crate::ignore_rules::IgnoreRules;
+use crate::instance::default_state_dir;
 use crate::local_file_ops::LocalFileOps;
 use crate::origin::FileEntry;
 use anyhow::{Context, Result, anyhow};
-use tracing::instrument;
+use serde::{Deserialize, Serialize};
+use tracing::field::Empty;
+use tracing::{Span, debug, instrument, warn};
 
-#[derive(Debug, Clone, Default)]
+/// File names editors use for short-lived files during an atomic save. Suffixes after
+/// `.tmp` must start with a digit, so that names like `page.tmpl` stay regular files.
+pub const DEFAULT_TEMP_PATTERNS: &[&str] = &[
+    "*.tmp",
+    "*.tmp[0-9]*",
+    "*~",
+    ".*.sw?",
+    "4913",
+    ".goutputstream-*",
+    "~$*",
+    "*___jb_tmp___",
+    "*___jb_old___",
+];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct SyncOptions {
     when_missing_preserve_backup: bool,
     when_conflict_preserve_backup: bool,
     when_delete_keep_backup: bool,
+    #[serde(rename = "lock_timeout_ms", with = "duration_ms")]
+    lock_timeout: Duration,
+    dry_run: bool,
+    temp_patterns: Option<Vec<String>>,
+    excludes: Vec<String>,
+    state_dir: Option<PathBuf>,
+    #[serde(skip)]
+    progress: Option<ProgressHook>,
+}
+
+/// Phase of [`Synchronizer::sync`] reported through [`Progress`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPhase {
+    /// Copying entries missing from the backup
+    Missing,
+    /// Removing entries that only exist in the backup
+    Extra,
+    /// Comparing entries present on both sides
+    Conflicts,
+}
+
+/// Progress of a scan or a sync, reported to the hook set with
+/// [`SyncOptions::with_progress`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    /// Reading both trees; the number of entries is not known until the scan finishes
+    Scanning { entries: usize },
+    /// `done` of the `total` entries handled by a phase
+    Phase {
+        phase: SyncPhase,
+        done: usize,
+        total: usize,
+    },
+}
+
+/// Callback receiving [`Progress`] updates. It runs on the syncing thread, so it should
+/// return quickly.
+#[derive(Clone)]
+pub struct ProgressHook(Arc<dyn Fn(Progress) + Send + Sync>);
+
+impl ProgressHook {
+    pub fn new(hook: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
+}
+
+impl std::fmt::Debug for ProgressHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProgressHook")
+    }
 }
 
 impl SyncOptions {
@@ -32,6 +103,193 @@ impl SyncOptions {
         self.when_delete_keep_backup = on_delete;
         self
     }
+
+    #[must_use]
+    pub fn when_missing_preserve_backup(&self) -> bool {
+        self.when_missing_preserve_backup
+    }
+
+    #[must_use]
+    pub fn when_conflict_preserve_backup(&self) -> bool {
+        self.when_conflict_preserve_backup
+    }
+
+    #[must_use]
+    pub fn when_delete_keep_backup(&self) -> bool {
+        self.when_delete_keep_backup
+    }
+
+    /// How long to wait for a file locked by another process before skipping it.
+    /// Defaults to zero, which means a single attempt.
+    #[must_use]
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Only log what would change instead of touching the backup.
+    #[must_use]
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    #[must_use]
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Glob patterns matched against file names to recognize editor temp files.
+    /// Defaults to [`DEFAULT_TEMP_PATTERNS`]; pass an empty list to disable the detection.
+    #[must_use]
+    pub fn with_temp_patterns(mut self, patterns: Vec<String>) -> Self {
+        self.temp_patterns = Some(patterns);
+        self
+    }
+
+    /// Gitignore-style patterns excluded from synchronization, in addition to the
+    /// rules of the source root's ignore file.
+    #[must_use]
+    pub fn with_excludes(mut self, excludes: Vec<String>) -> Self {
+        self.excludes = excludes;
+        self
+    }
+
+    #[must_use]
+    pub fn excludes(&self) -> &[String] {
+        &self.excludes
+    }
+
+    /// Where the instance lock and other runtime state are kept.
+    /// Defaults to [`default_state_dir`].
+    #[must_use]
+    pub fn with_progress(mut self, hook: ProgressHook) -> Self {
+        self.progress = Some(hook);
+        self
+    }
+
+    fn report_progress(&self, progress: Progress) {
+        if let Some(ProgressHook(hook)) = &self.progress {
+            hook(progress);
+        }
+    }
+
+    #[must_use]
+    pub fn with_state_dir(mut self, state_dir: PathBuf) -> Self {
+        self.state_dir = Some(state_dir);
+        self
+    }
+
+    #[must_use]
+    pub fn state_dir(&self) -> PathBuf {
+        self.state_dir.clone().unwrap_or_else(default_state_dir)
+    }
+
+    #[must_use]
+    pub fn temp_patterns(&self) -> Vec<String> {
+        self.temp_patterns.clone().unwrap_or_else(|| {
+            DEFAULT_TEMP_PATTERNS
+                .iter()
+                .map(ToString::to_string)
+                .collect()
+        })
+    }
+}
+
+/// Durations are written as whole milliseconds in config files.
+mod duration_ms {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(u64::try_from(duration.as_millis()).unwrap_or(u64::MAX))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_millis)
+    }
+}
+
+/// A change the synchronizer would make, expressed with paths relative to the roots.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SyncAction {
+    CreateDir { relative: PathBuf },
+    CopyToBackup { relative: PathBuf },
+    CopyToOriginal { relative: PathBuf },
+    ApplyDelta { relative: PathBuf, bytes: usize },
+    Rename { from: PathBuf, to: PathBuf },
+    Remove { relative: PathBuf },
+    CreateOriginalDir { relative: PathBuf },
+}
+
+/// Outcome of a full [`Synchronizer::sync`] run.
+#[derive(Debug, Clone, Default)]
+pub struct SyncReport {
+    /// Entries copied into the backup because they were missing there
+    pub created: usize,
+    /// Files whose content differed and was reconciled
+    pub updated: usize,
+    /// Extra entries removed from the backup
+    pub deleted: usize,
+    /// Relative paths left untouched because another process held a conflicting lock
+    pub skipped_locked: Vec<PathBuf>,
+    /// Relative paths that could not be synchronized, with the error that stopped them
+    pub errors: Vec<(PathBuf, String)>,
+}
+
+impl SyncReport {
+    #[must_use]
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    fn record_error(&mut self, relative: &Path, error: &anyhow::Error) {
+        warn!(
+            ?relative,
+            outcome = "failed",
+            "failed to sync entry: {error:#}"
+        );
+        self.errors
+            .push((relative.to_path_buf(), format!("{error:#}")));
+    }
+}
+
+impl std::fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "created: {}, updated: {}, deleted: {}, skipped (locked): {}, errors: {}",
+            self.created,
+            self.updated,
+            self.deleted,
+            self.skipped_locked.len(),
+            self.errors.len()
+        )
+    }
+}
+
+/// Differences found by [`Synchronizer::verify`], as paths relative to the roots.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct VerifyReport {
+    /// Present on both sides with different content or type
+    pub mismatched: Vec<PathBuf>,
+    /// Present in the original only
+    pub missing: Vec<PathBuf>,
+    /// Present in the backup only
+    pub extra: Vec<PathBuf>,
+    /// Present on both sides but could not be read on at least one of them
+    pub unreadable: Vec<PathBuf>,
+}
+
+impl VerifyReport {
+    #[must_use]
+    pub fn is_clean(&self) -> bool {
+        self.mismatched.is_empty()
+            && self.missing.is_empty()
+            && self.extra.is_empty()
+            && self.unreadable.is_empty()
+    }
 }
 
 #[derive(Debug)]
@@ -40,14 +298,54 @@ pub struct Synchronizer {
     backup: FolderStructure,
     path_mapping: HashMap<PathBuf, PathBuf>,
     options: SyncOptions,
+    ignore: IgnoreRules,
 }
 
 impl Synchronizer {
     pub fn new(original_root: PathBuf, backup_root: PathBuf) -> Result<Self> {
-        let original = FolderStructure::new(&original_root).with_context(|| {
-            format!("Failed to read original folder structure: {original_root:?}")
-        })?;
-        let backup = FolderStructure::new(&backup_root)
+        Self::new_with_options(original_root, backup_root, SyncOptions::default())
+    }
+
+    /// Like [`Synchronizer::new`], reporting the scan of both trees to the progress hook
+    /// of `options`.
+    pub fn new_with_options(
+        original_root: PathBuf,
+        backup_root: PathBuf,
+        options: SyncOptions,
+    ) -> Result<Self> {
+        Self::scan(original_root, backup_root, options, false)
+    }
+
+    /// Like [`Synchronizer::new_with_options`] for a restore, whose original may be
+    /// missing, as when the drive it was on died: it is then taken as empty.
+    pub fn new_for_restore(
+        original_root: PathBuf,
+        backup_root: PathBuf,
+        options: SyncOptions,
+    ) -> Result<Self> {
+        let original_missing = !original_root.exists();
+        Self::scan(original_root, backup_root, options, original_missing)
+    }
+
+    fn scan(
+        original_root: PathBuf,
+        backup_root: PathBuf,
+        options: SyncOptions,
+        original_missing: bool,
+    ) -> Result<Self> {
+        let mut scanned = 0;
+        let mut on_entry = || {
+            scanned += 1;
+            options.report_progress(Progress::Scanning { entries: scanned });
+        };
+        let original = if original_missing {
+            FolderStructure::empty(std::path::absolute(&original_root)?)
+        } else {
+            FolderStructure::scan(&original_root, &mut on_entry).with_context(|| {
+                format!("Failed to read original folder structure: {original_root:?}")
+            })?
+        };
+        let backup = FolderStructure::scan(&backup_root, &mut on_entry)
             .with_context(|| format!("Failed to read backup folder structure: {backup_root:?}"))?;
 
         let mut path_mapping = HashMap::new();
@@ -59,20 +357,77 @@ impl Synchronizer {
             }
         }
 
+        let ignore = IgnoreRules::load(original.root(), &options.excludes);
+
         Ok(Self {
             original,
             backup,
             path_mapping,
-            options: SyncOptions::default(),
+            options,
+            ignore,
         })
     }
 
     #[must_use]
     pub fn with_options(mut self, options: SyncOptions) -> Self {
         self.options = options;
+        self.reload_ignore_rules();
         self
     }
 
+    /// Re-reads the ignore file of the source root. Paths that become ignored are left
+    /// alone from now on; their existing backup copies are never deleted.
+    pub fn reload_ignore_rules(&mut self) {
+        self.ignore = IgnoreRules::load(self.original.root(), &self.options.excludes);
+    }
+
+    /// Whether an original path is excluded by the ignore rules.
+    #[must_use]
+    pub fn is_ignored(&self, original_path: &Path) -> bool {
+        original_path
+            .strip_prefix(self.original.root())
+            .is_ok_and(|relative| self.ignore.is_ignored(relative, original_path.is_dir()))
+    }
+
+    fn remove_ignored(
+        &self,
+        relatives: &mut HashMap<PathBuf, PathBuf>,
+        structure: &FolderStructure,
+    ) {
+        relatives.retain(|relative, path| {
+            let is_dir = structure.get_entry(path).is_some_and(FileEntry::is_dir);
+            !self.ignore.is_ignored(relative, is_dir)
+        });
+    }
+
+    #[must_use]
+    pub fn options(&self) -> &SyncOptions {
+        &self.options
+    }
+
+    #[must_use]
+    pub fn original_root(&self) -> &PathBuf {
+        self.original.root()
+    }
+
+    #[must_use]
+    pub fn backup_root(&self) -> &PathBuf {
+        self.backup.root()
+    }
+
+    fn relative_path(&self, original_path: &Path) -> Result<PathBuf> {
+        original_path
+            .strip_prefix(self.original.root())
+            .map(Path::to_path_buf)
+            .with_context(|| format!("Path is outside the original root: {original_path:?}"))
+    }
+
+    /// Whether the original path is known to have a counterpart in the backup.
+    #[must_use]
+    pub fn is_tracked(&self, original_path: &PathBuf) -> bool {
+        self.path_mapping.contains_key(original_path)
+    }
+
     #[must_use]
     pub fn get_backup_path(&self, original_path: &PathBuf) -> Option<PathBuf> {
         if let Some(path) = self.path_mapping.get(original_path) {
@@ -84,6 +439,13 @@ impl Synchronizer {
         }
     }
 
+    /// Records the path relative to the original root on the current span.
+    fn record_relative(&self, original_path: &Path) {
+        if let Ok(relative) = original_path.strip_prefix(self.original.root()) {
+            Span::current().record("relative", tracing::field::debug(relative));
+        }
+    }
+
     fn get_backup_signature(&self, path: &PathBuf) -> Result<&[u8]> {
         self.backup
             .get_entry(path)
@@ -91,29 +453,52 @@ impl Synchronizer {
             .map(FileEntry::signature)
     }
 
-    #[instrument(skip(self))]
+    /// Takes a shared lock on an original file, waiting up to the configured lock timeout.
+    /// Returns `None` when another process holds a conflicting lock.
+    pub fn try_lock_original(&self, original_path: &Path) -> Result<Option<File>> {
+        LocalFileOps::try_lock_shared(original_path, self.options.lock_timeout)
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "modify", relative = Empty, bytes = Empty, outcome = Empty),
+        err(level = "warn")
+    )]
     pub fn handle_original_modified_calculate_delta(
         &self,
         original_path: &PathBuf,
     ) -> Result<Vec<u8>> {
+        self.record_relative(original_path);
         let new_sig = LocalFileOps::create_signature(original_path)?;
         let backup_path = self
             .get_backup_path(original_path)
             .with_context(|| format!("Failed to get backup path for: {original_path:?}"))?;
         let old_sig = self.get_backup_signature(&backup_path)?;
         if new_sig == old_sig {
+            Span::current().record("outcome", "unchanged");
+            debug!(outcome = "unchanged", "signatures match");
             return Ok(vec![]);
         }
         let dlt = LocalFileOps::calculate_delta(old_sig, original_path)?;
+        Span::current().record("bytes", dlt.len());
+        Span::current().record("outcome", "delta");
+        debug!(outcome = "delta", "calculated delta");
         Ok(dlt)
     }
 
-    #[instrument(skip(self, dlt))]
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "modify", relative = Empty, bytes = dlt.len(), outcome = Empty),
+        err(level = "warn")
+    )]
     pub fn handle_original_modified_apply_delta(
         &mut self,
         original_path: &PathBuf,
         dlt: &[u8],
     ) -> Result<()> {
+        self.record_relative(original_path);
         let backup_path = self
             .get_backup_path(original_path)
             .with_context(|| format!("Failed to get backup path for: {original_path:?}"))?;
@@ -125,16 +510,25 @@ impl Synchronizer {
         self.backup
             .update_entry(&backup_path)
             .with_context(|| format!("Failed to update backup entry: {backup_path:?}"))?;
+        Span::current().record("outcome", "applied");
+        debug!(outcome = "applied", "applied delta to backup");
         Ok(())
     }
 
-    #[instrument(skip(self))]
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "create", relative = Empty, bytes = Empty, outcome = Empty),
+        err(level = "warn")
+    )]
     pub fn handle_original_created(&mut self, original_path: PathBuf) -> Result<()> {
+        self.record_relative(&original_path);
         let backup_path = self
             .get_backup_path(&original_path)
             .with_context(|| format!("Cannot determine backup path for: {original_path:?}"))?;
 
-        LocalFileOps::copy_file(&original_path, &backup_path)?;
+        let bytes = LocalFileOps::copy_file(&original_path, &backup_path)?;
+        Span::current().record("bytes", bytes);
 
         self.original
             .update_entry(&original_path)
@@ -144,12 +538,22 @@ impl Synchronizer {
             .with_context(|| format!("Failed to update backup entry: {backup_path:?}"))?;
         self.path_mapping.insert(original_path, backup_path);
 
+        Span::current().record("outcome", "copied");
+        debug!(outcome = "copied", "copied file to backup");
         Ok(())
     }
 
-    #[instrument(skip(self))]
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "delete", relative = Empty, outcome = Empty),
+        err(level = "warn")
+    )]
     pub fn handle_original_deleted(&mut self, original_path: &PathBuf) -> Result<()> {
+        self.record_relative(original_path);
         if self.options.when_delete_keep_backup {
+            Span::current().record("outcome", "kept");
+            debug!(outcome = "kept", "keeping backup of deleted file");
             return Ok(());
         }
         if let Some(backup_path) = self.path_mapping.remove(original_path) {
@@ -158,15 +562,26 @@ impl Synchronizer {
         }
         self.original.remove_entry(original_path);
 
+        Span::current().record("outcome", "removed");
+        debug!(outcome = "removed", "removed file from backup");
         Ok(())
     }
 
-    #[instrument(skip(self))]
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "rename", from = Empty, relative = Empty, outcome = Empty),
+        err(level = "warn")
+    )]
     pub fn handle_original_renamed(
         &mut self,
         from_path: &PathBuf,
         to_path: &PathBuf,
     ) -> Result<()> {
+        if let Ok(from) = from_path.strip_prefix(self.original.root()) {
+            Span::current().record("from", tracing::field::debug(from));
+        }
+        self.record_relative(to_path);
         let new_backup_path = self
             .get_backup_path(to_path)
             .with_context(|| format!("Cannot determine backup path for: {to_path:?}"))?;
@@ -186,141 +601,561 @@ impl Synchronizer {
             .with_context(|| format!("Failed to update backup entry: {new_backup_path:?}"))?;
         self.path_mapping.insert(to_path.clone(), new_backup_path);
 
+        Span::current().record("outcome", "renamed");
+        debug!(outcome = "renamed", "renamed file in backup");
         Ok(())
     }
 
-    #[instrument(skip(self))]
-    pub fn sync(&mut self) -> Result<()> {
-        let _locks = self
+    /// Computes the actions a full [`Synchronizer::sync`] would perform, without applying them.
+    #[instrument(level = "debug", skip_all)]
+    pub fn plan(&self) -> Result<Vec<SyncAction>> {
+        let (original_relatives, backup_relatives) = self.unignored_relatives();
+        let mut actions = Vec::new();
+
+        for (relative, original_path) in &original_relatives {
+            let original_entry = self
+                .original
+                .get_entry(original_path)
+                .with_context(|| format!("Failed to get original entry: {original_path:?}"))?;
+            let relative = relative.clone();
+            match backup_relatives.get(&relative) {
+                None if original_entry.is_dir() => actions.push(SyncAction::CreateDir { relative }),
+                None => actions.push(SyncAction::CopyToBackup { relative }),
+                Some(backup_path) => {
+                    let backup_entry = self
+                        .backup
+                        .get_entry(backup_path)
+                        .with_context(|| format!("Failed to get backup entry: {backup_path:?}"))?;
+                    if original_entry.is_dir()
+                        || backup_entry.is_dir()
+                        || original_entry.signature() == backup_entry.signature()
+                    {
+                        continue;
+                    }
+                    if self.options.when_conflict_preserve_backup {
+                        actions.push(SyncAction::CopyToOriginal { relative });
+                    } else {
+                        actions.push(SyncAction::CopyToBackup { relative });
+                    }
+                }
+            }
+        }
+
+        if !self.options.when_missing_preserve_backup {
+            for relative in backup_relatives.keys() {
+                if !original_relatives.contains_key(relative) {
+                    actions.push(SyncAction::Remove {
+                        relative: relative.clone(),
+                    });
+                }
+            }
+        }
+
+        actions.sort();
+        Ok(actions)
+    }
+
+    /// The action [`Synchronizer::handle_original_modified_apply_delta`] would perform,
+    /// or `None` if the file is unchanged.
+    pub fn plan_original_modified(&self, original_path: &PathBuf) -> Result<Option<SyncAction>> {
+        let relative = self.relative_path(original_path)?;
+        let delta = self.handle_original_modified_calculate_delta(original_path)?;
+        if delta.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(SyncAction::ApplyDelta {
+            relative,
+            bytes: delta.len(),
+        }))
+    }
+
+    /// The action [`Synchronizer::handle_original_created`] would perform.
+    pub fn plan_original_created(&self, original_path: &Path) -> Result<SyncAction> {
+        let relative = self.relative_path(original_path)?;
+        if original_path.is_dir() {
+            Ok(SyncAction::CreateDir { relative })
+        } else {
+            Ok(SyncAction::CopyToBackup { relative })
+        }
+    }
+
+    /// The action [`Synchronizer::handle_original_deleted`] would perform,
+    /// or `None` if the backup is kept.
+    pub fn plan_original_deleted(&self, original_path: &Path) -> Result<Option<SyncAction>> {
+        let relative = self.relative_path(original_path)?;
+        if self.options.when_delete_keep_backup {
+            return Ok(None);
+        }
+        Ok(Some(SyncAction::Remove { relative }))
+    }
+
+    /// The action [`Synchronizer::handle_original_renamed`] would perform.
+    pub fn plan_original_renamed(&self, from_path: &Path, to_path: &Path) -> Result<SyncAction> {
+        Ok(SyncAction::Rename {
+            from: self.relative_path(from_path)?,
+            to: self.relative_path(to_path)?,
+        })
+    }
+
+    /// Errors on individual entries do not stop the run; they are collected in the report.
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(original = ?self.original.root(), backup = ?self.backup.root(), skipped = Empty)
+    )]
+    pub fn sync(&mut self) -> Result<SyncReport> {
+        let (_locks, skipped) = self
             .acquire_locks()
             .context("Failed to acquire file locks")?;
 
-        let original_relatives = self.original.get_relatives();
-        let backup_relatives = self.backup.get_relatives();
+        let mut original_relatives = self.original.get_relatives();
+        let mut backup_relatives = self.backup.get_relatives();
+        original_relatives.retain(|relative, _| !skipped.contains(relative));
+        backup_relatives.retain(|relative, _| !skipped.contains(relative));
+        self.remove_ignored(&mut original_relatives, &self.original);
+        self.remove_ignored(&mut backup_relatives, &self.backup);
+
+        let mut report = SyncReport::default();
+        self.sync_missing_in_backup(&original_relatives, &backup_relatives, &mut report);
+        self.sync_extra_in_backup(&original_relatives, &backup_relatives, &mut report);
+        self.sync_conflicts(&original_relatives, &backup_relatives, &mut report);
+
+        report.skipped_locked = skipped.into_iter().collect();
+        report.skipped_locked.sort();
+        report.errors.sort();
+        Span::current().record("skipped", report.skipped_locked.len());
+        debug!(%report, "sync finished");
+        Ok(report)
+    }
+
+    /// Compares both trees by hashing the full content of every file. Neither tree is
+    /// modified. Ignored paths are left out, as they are when syncing, and files that
+    /// cannot be read are reported as unreadable instead of aborting the comparison.
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(original = ?self.original.root(), backup = ?self.backup.root())
+    )]
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let (original_relatives, backup_relatives) = self.unignored_relatives();
+        let mut report = VerifyReport::default();
+
+        for (relative, original_path) in &original_relatives {
+            let Some(backup_path) = backup_relatives.get(relative) else {
+                report.missing.push(relative.clone());
+                continue;
+            };
+            let original_is_dir = original_path.is_dir();
+            let matches = if original_is_dir || backup_path.is_dir() {
+                original_is_dir == backup_path.is_dir()
+            } else {
+                match (
+                    LocalFileOps::hash_file(original_path),
+                    LocalFileOps::hash_file(backup_path),
+                ) {
+                    (Ok(original_hash), Ok(backup_hash)) => original_hash == backup_hash,
+                    (Err(e), _) | (_, Err(e)) => {
+                        warn!("failed to read {relative:?} for verification: {e:#}");
+                        report.unreadable.push(relative.clone());
+                        continue;
+                    }
+                }
+            };
+            if !matches {
+                report.mismatched.push(relative.clone());
+            }
+        }
+        report.extra = backup_relatives
+            .into_keys()
+            .filter(|relative| !original_relatives.contains_key(relative))
+            .collect();
+
+        report.mismatched.sort();
+        report.missing.sort();
+        report.extra.sort();
+        report.unreadable.sort();
+        debug!(clean = report.is_clean(), "verify finished");
+        Ok(report)
+    }
+
+    /// The actions [`Synchronizer::restore`] would perform.
+    pub fn plan_restore(&self, prefer_original: bool) -> Result<Vec<SyncAction>> {
+        let (original_relatives, backup_relatives) = self.unignored_relatives();
+        let mut actions = Vec::new();
+
+        for (relative, backup_path) in &backup_relatives {
+            let backup_entry = self
+                .backup
+                .get_entry(backup_path)
+                .with_context(|| format!("Failed to get backup entry: {backup_path:?}"))?;
+            let relative = relative.clone();
+            match original_relatives.get(&relative) {
+                None if backup_entry.is_dir() => {
+                    actions.push(SyncAction::CreateOriginalDir { relative });
+                }
+                None => actions.push(SyncAction::CopyToOriginal { relative }),
+                Some(_) if prefer_original => {}
+                Some(original_path) => {
+                    let original_entry =
+                        self.original.get_entry(original_path).with_context(|| {
+                            format!("Failed to get original entry: {original_path:?}")
+                        })?;
+                    if !original_entry.is_dir()
+                        && !backup_entry.is_dir()
+                        && original_entry.signature() != backup_entry.signature()
+                    {
+                        actions.push(SyncAction::CopyToOriginal { relative });
+                    }
+                }
+            }
+        }
+
+        actions.sort();
+        Ok(actions)
+    }
+
+    /// Copies the backup back into the original tree after the original was lost.
+    ///
+    /// The backup is only ever read. Entries missing from the original are recreated and
+    /// files that differ take the backup's content, unless `prefer_original` is set.
+    /// Entries that only exist in the original are left alone.
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(original = ?self.original.root(), backup = ?self.backup.root(), prefer_original)
+    )]
+    pub fn restore(&mut self, prefer_original: bool) -> Result<SyncReport> {
+        let (original_relatives, backup_relatives) = self.unignored_relatives();
+        // Parents sort before their children, so directories exist before their files
+        let mut backup_relatives: Vec<_> = backup_relatives.into_iter().collect();
+        backup_relatives.sort();
 
-        self.sync_missing_in_backup(&original_relatives, &backup_relatives)
-            .context("Failed to sync missing files in backup")?;
-        self.sync_extra_in_backup(&original_relatives, &backup_relatives)
-            .context("Failed to sync extra files in backup")?;
-        self.sync_conflicts(&original_relatives, &backup_relatives)
-            .context("Failed to sync conflicting files")?;
+        let mut report = SyncReport::default();
+        for (relative, backup_path) in &backup_relatives {
+            let outcome = match original_relatives.get(relative) {
+                None => self.restore_missing_entry(relative, backup_path).map(|()| {
+                    report.created += 1;
+                }),
+                Some(_) if prefer_original => Ok(()),
+                Some(original_path) => self
+                    .restore_conflict(original_path, backup_path)
+                    .map(|updated| report.updated += usize::from(updated)),
+            };
+            if let Err(e) = outcome {
+                report.record_error(relative, &e);
+            }
+        }
+
+        report.errors.sort();
+        debug!(%report, "restore finished");
+        Ok(report)
+    }
 
+    fn unignored_relatives(&self) -> (HashMap<PathBuf, PathBuf>, HashMap<PathBuf, PathBuf>) {
+        let mut original_relatives = self.original.get_relatives();
+        let mut backup_relatives = self.backup.get_relatives();
+        self.remove_ignored(&mut original_relatives, &self.original);
+        self.remove_ignored(&mut backup_relatives, &self.backup);
+        (original_relatives, backup_relatives)
+    }
+
+    fn restore_missing_entry(&mut self, relative: &Path, backup_path: &PathBuf) -> Result<()> {
+        let entry = self
+            .backup
+            .get_entry(backup_path)
+            .with_context(|| format!("Failed to get backup entry: {backup_path:?}"))?;
+        let original_path = self.original.root().join(relative);
+        if entry.is_dir() {
+            LocalFileOps::create_dir_all(&original_path)?;
+        } else {
+            let bytes = LocalFileOps::copy_file(backup_path, &original_path)?;
+            debug!(
+                op = "restore",
+                ?relative,
+                bytes,
+                outcome = "copied",
+                "restored entry"
+            );
+        }
+        self.original
+            .update_entry(&original_path)
+            .with_context(|| format!("Failed to update original entry: {original_path:?}"))?;
+        self.path_mapping.insert(original_path, backup_path.clone());
         Ok(())
     }
 
+    /// Overwrites the original with the backup when both are files with different content.
+    fn restore_conflict(&mut self, original_path: &PathBuf, backup_path: &PathBuf) -> Result<bool> {
+        let original_entry = self
+            .original
+            .get_entry(original_path)
+            .with_context(|| format!("Failed to get original entry: {original_path:?}"))?;
+        let backup_entry = self
+            .backup
+            .get_entry(backup_path)
+            .with_context(|| format!("Failed to get backup entry: {backup_path:?}"))?;
+
+        if original_entry.is_dir()
+            || backup_entry.is_dir()
+            || original_entry.signature() == backup_entry.signature()
+        {
+            return Ok(false);
+        }
+
+        let bytes = LocalFileOps::copy_file(backup_path, original_path)?;
+        self.original
+            .update_entry(original_path)
+            .with_context(|| format!("Failed to update original entry: {original_path:?}"))?;
+        debug!(
+            op = "restore",
+            ?original_path,
+            bytes,
+            outcome = "backup_wins",
+            "restored conflict"
+        );
+        Ok(true)
+    }
+
+    /// Locks every file of both trees. Files that stay locked by another process past the
+    /// lock timeout are returned as relative paths so the caller can leave them alone.
     #[instrument(skip(self))]
-    fn acquire_locks(&self) -> Result<Vec<File>> {
+    fn acquire_locks(&self) -> Result<(Vec<File>, HashSet<PathBuf>)> {
         let mut locks = Vec::new();
+        let mut skipped = HashSet::new();
 
         for entry in self.original.files() {
             if !entry.is_dir() {
                 let path = entry.path();
-                let file = LocalFileOps::lock_shared(path)?;
-                locks.push(file);
+                match LocalFileOps::try_lock_shared(path, self.options.lock_timeout)? {
+                    Some(file) => locks.push(file),
+                    None => Self::skip_locked(self.original.root(), path, &mut skipped),
+                }
             }
         }
 
         for entry in self.backup.files() {
             if !entry.is_dir() {
                 let path = entry.path();
-                let file = LocalFileOps::lock_exclusive(path)?;
-                locks.push(file);
+                match LocalFileOps::try_lock_exclusive(path, self.options.lock_timeout)? {
+                    Some(file) => locks.push(file),
+                    None => Self::skip_locked(self.backup.root(), path, &mut skipped),
+                }
             }
         }
 
-        Ok(locks)
+        Ok((locks, skipped))
     }
 
-    #[instrument(skip(self, original_relatives, backup_relatives))]
+    fn skip_locked(root: &Path, path: &Path, skipped: &mut HashSet<PathBuf>) {
+        warn!(
+            ?path,
+            outcome = "skipped",
+            "file is locked by another process, skipping"
+        );
+        if let Ok(relative) = path.strip_prefix(root) {
+            skipped.insert(relative.to_path_buf());
+        }
+    }
+
+    #[instrument(level = "debug", skip_all, fields(phase = "missing", count = Empty))]
     fn sync_missing_in_backup(
         &mut self,
         original_relatives: &HashMap<PathBuf, PathBuf>,
         backup_relatives: &HashMap<PathBuf, PathBuf>,
-    ) -> Result<()> {
-        for (relative, original_path) in original_relatives {
-            if !backup_relatives.contains_key(relative) {
-                let entry = self
-                    .original
-                    .get_entry(original_path)
-                    .with_context(|| format!("Failed to get original entry: {original_path:?}"))?;
-                if entry.is_dir() {
-                    let backup_path = self.backup.root().join(relative);
-                    LocalFileOps::create_dir_all(&backup_path)?;
-                    self.backup.update_entry(&backup_path).with_context(|| {
-                        format!("Failed to update backup entry: {backup_path:?}")
-                    })?;
-                    self.path_mapping.insert(original_path.clone(), backup_path);
-                } else {
-                    self.handle_original_created(original_path.clone())?;
-                }
+        report: &mut SyncReport,
+    ) {
+        let missing: Vec<_> = original_relatives
+            .iter()
+            .filter(|(relative, _)| !backup_relatives.contains_key(*relative))
+            .collect();
+        let total = missing.len();
+        self.options.report_progress(Progress::Phase {
+            phase: SyncPhase::Missing,
+            done: 0,
+            total,
+        });
+        for (done, (relative, original_path)) in missing.into_iter().enumerate() {
+            match self.copy_missing_entry(relative, original_path) {
+                Ok(()) => report.created += 1,
+                Err(e) => report.record_error(relative, &e),
             }
+            self.options.report_progress(Progress::Phase {
+                phase: SyncPhase::Missing,
+                done: done + 1,
+                total,
+            });
+        }
+        Span::current().record("count", total);
+        debug!("phase finished");
+    }
+
+    fn copy_missing_entry(&mut self, relative: &Path, original_path: &PathBuf) -> Result<()> {
+        let entry = self
+            .original
+            .get_entry(original_path)
+            .with_context(|| format!("Failed to get original entry: {original_path:?}"))?;
+        if entry.is_dir() {
+            let backup_path = self.backup.root().join(relative);
+            LocalFileOps::create_dir_all(&backup_path)?;
+            self.backup
+                .update_entry(&backup_path)
+                .with_context(|| format!("Failed to update backup entry: {backup_path:?}"))?;
+            self.path_mapping.insert(original_path.clone(), backup_path);
+            Ok(())
+        } else {
+            self.handle_original_created(original_path.clone())
         }
-        Ok(())
     }
 
-    #[instrument(skip(self, original_relatives, backup_relatives))]
+    #[instrument(level = "debug", skip_all, fields(phase = "extra", count = Empty))]
     fn sync_extra_in_backup(
         &mut self,
         original_relatives: &HashMap<PathBuf, PathBuf>,
         backup_relatives: &HashMap<PathBuf, PathBuf>,
-    ) -> Result<()> {
+        report: &mut SyncReport,
+    ) {
         if self.options.when_missing_preserve_backup {
-            return Ok(());
+            debug!(outcome = "kept", "preserving extra files in backup");
+            return;
         }
 
-        for (relative, backup_path) in backup_relatives {
-            if !original_relatives.contains_key(relative) {
-                let entry = self
-                    .backup
-                    .get_entry(backup_path)
-                    .with_context(|| format!("Failed to get backup entry: {backup_path:?}"))?;
-                if entry.is_dir() {
-                    LocalFileOps::remove_dir_all(backup_path)?;
-                } else {
-                    LocalFileOps::remove_file(backup_path)?;
+        let extra: Vec<_> = backup_relatives
+            .iter()
+            .filter(|(relative, _)| !original_relatives.contains_key(*relative))
+            .collect();
+        let total = extra.len();
+        self.options.report_progress(Progress::Phase {
+            phase: SyncPhase::Extra,
+            done: 0,
+            total,
+        });
+        for (done, (relative, backup_path)) in extra.into_iter().enumerate() {
+            match self.remove_extra_entry(backup_path) {
+                Ok(()) => {
+                    report.deleted += 1;
+                    debug!(
+                        op = "delete",
+                        ?relative,
+                        outcome = "removed",
+                        "removed extra entry"
+                    );
                 }
-                self.backup.remove_entry(backup_path);
+                Err(e) => report.record_error(relative, &e),
             }
+            self.options.report_progress(Progress::Phase {
+                phase: SyncPhase::Extra,
+                done: done + 1,
+                total,
+            });
         }
+        Span::current().record("count", total);
+        debug!("phase finished");
+    }
+
+    fn remove_extra_entry(&mut self, backup_path: &PathBuf) -> Result<()> {
+        let entry = self
+            .backup
+            .get_entry(backup_path)
+            .with_context(|| format!("Failed to get backup entry: {backup_path:?}"))?;
+        if !backup_path.exists() {
+            // Already gone with a parent directory removed earlier in this phase
+        } else if entry.is_dir() {
+            LocalFileOps::remove_dir_all(backup_path)?;
+        } else {
+            LocalFileOps::remove_file(backup_path)?;
+        }
+        self.backup.remove_entry(backup_path);
         Ok(())
     }
 
-    #[instrument(skip(self, original_relatives, backup_relatives))]
+    #[instrument(level = "debug", skip_all, fields(phase = "conflicts", count = Empty))]
     fn sync_conflicts(
         &mut self,
         original_relatives: &HashMap<PathBuf, PathBuf>,
         backup_relatives: &HashMap<PathBuf, PathBuf>,
-    ) -> Result<()> {
-        for (relative, original_path) in original_relatives {
-            if let Some(backup_path) = backup_relatives.get(relative) {
-                let original_entry = self
-                    .original
-                    .get_entry(original_path)
-                    .with_context(|| format!("Failed to get original entry: {original_path:?}"))?;
-                let backup_entry = self
-                    .backup
-                    .get_entry(backup_path)
-                    .with_context(|| format!("Failed to get backup entry: {backup_path:?}"))?;
-
-                if original_entry.is_dir() || backup_entry.is_dir() {
-                    continue;
-                }
-
-                if original_entry.signature() != backup_entry.signature() {
-                    if self.options.when_conflict_preserve_backup {
-                        LocalFileOps::copy_file(backup_path, original_path)?;
-                        self.original.update_entry(original_path).with_context(|| {
-                            format!("Failed to update original entry: {original_path:?}")
-                        })?;
-                    } else {
-                        LocalFileOps::copy_file(original_path, backup_path)?;
-                        self.backup.update_entry(backup_path).with_context(|| {
-                            format!("Failed to update backup entry: {backup_path:?}")
-                        })?;
-                    }
+        report: &mut SyncReport,
+    ) {
+        let shared: Vec<_> = original_relatives
+            .iter()
+            .filter_map(|(relative, original_path)| {
+                let backup_path = backup_relatives.get(relative)?;
+                Some((relative, original_path, backup_path))
+            })
+            .collect();
+        let total = shared.len();
+        self.options.report_progress(Progress::Phase {
+            phase: SyncPhase::Conflicts,
+            done: 0,
+            total,
+        });
+        let mut count = 0usize;
+        for (done, (relative, original_path, backup_path)) in shared.into_iter().enumerate() {
+            match self.resolve_conflict(relative, original_path, backup_path) {
+                Ok(true) => {
+                    count += 1;
+                    report.updated += 1;
                 }
+                Ok(false) => {}
+                Err(e) => report.record_error(relative, &e),
             }
+            self.options.report_progress(Progress::Phase {
+                phase: SyncPhase::Conflicts,
+                done: done + 1,
+                total,
+            });
         }
-        Ok(())
+        Span::current().record("count", count);
+        debug!("phase finished");
+    }
+
+    /// Reconciles a file present on both sides. Returns whether the contents differed.
+    fn resolve_conflict(
+        &mut self,
+        relative: &Path,
+        original_path: &PathBuf,
+        backup_path: &PathBuf,
+    ) -> Result<bool> {
+        let original_entry = self
+            .original
+            .get_entry(original_path)
+            .with_context(|| format!("Failed to get original entry: {original_path:?}"))?;
+        let backup_entry = self
+            .backup
+            .get_entry(backup_path)
+            .with_context(|| format!("Failed to get backup entry: {backup_path:?}"))?;
+
+        if original_entry.is_dir()
+            || backup_entry.is_dir()
+            || original_entry.signature() == backup_entry.signature()
+        {
+            return Ok(false);
+        }
+
+        if self.options.when_conflict_preserve_backup {
+            let bytes = LocalFileOps::copy_file(backup_path, original_path)?;
+            self.original
+                .update_entry(original_path)
+                .with_context(|| format!("Failed to update original entry: {original_path:?}"))?;
+            debug!(
+                op = "conflict",
+                ?relative,
+                bytes,
+                outcome = "backup_wins",
+                "resolved conflict"
+            );
+        } else {
+            let bytes = LocalFileOps::copy_file(original_path, backup_path)?;
+            self.backup
+                .update_entry(backup_path)
+                .with_context(|| format!("Failed to update backup entry: {backup_path:?}"))?;
+            debug!(
+                op = "conflict",
+                ?relative,
+                bytes,
+                outcome = "original_wins",
+                "resolved conflict"
+            );
+        }
+        Ok(true)
     }
 }
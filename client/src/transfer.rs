@@ -0,0 +1,288 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow, bail};
+use backup_sync_protocol::{FileOperation, MAX_CHUNK_SIZE, RelativePath, TransferKind};
+use tempfile::NamedTempFile;
+use tracing::{debug, instrument, warn};
+
+use crate::compression::decompress;
+use crate::file_streaming::apply_delta_securely;
+use crate::local_file_ops::LocalFileOps;
+use crate::remote::resolve;
+
+/// Size of the chunks files are split into; content up to this size is sent in a
+/// single `CreateFile`.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Bytes a delta may take beyond twice the size of the file it builds, for its header
+/// and commands
+const DELTA_OVERHEAD: u64 = 64 * 1024;
+
+/// Operations writing `content` to `relative_path` in chunks of `chunk_size`: a start,
+/// the chunks in order, then an end carrying the hash of the content.
+#[must_use]
+pub fn split(
+    transfer_id: u64,
+    relative_path: RelativePath,
+    content: &[u8],
+    chunk_size: usize,
+) -> Vec<FileOperation> {
+    let chunks: Vec<&[u8]> = content.chunks(chunk_size.max(1)).collect();
+    let chunk_count = chunks.len() as u64;
+    let mut operations = Vec::with_capacity(chunks.len() + 2);
+    operations.push(FileOperation::StartTransfer {
+        transfer_id,
+        relative_path,
+        kind: TransferKind::Content,
+        total_size: content.len() as u64,
+        chunk_size: chunk_size as u64,
+    });
+    operations.extend(chunks.into_iter().enumerate().map(|(index, data)| {
+        FileOperation::FileChunk {
+            transfer_id,
+            chunk_index: index as u64,
+            data: data.to_vec(),
+            compression: None,
+        }
+    }));
+    operations.push(FileOperation::EndTransfer {
+        transfer_id,
+        chunk_count,
+        expected_hash: blake3::hash(content).to_hex().to_string(),
+    });
+    operations
+}
+
+/// A transfer being reassembled in a temp file next to its destination.
+struct Transfer {
+    relative_path: PathBuf,
+    kind: TransferKind,
+    total_size: u64,
+    chunk_size: u64,
+    file: NamedTempFile,
+    received: HashSet<u64>,
+    /// Chunk count and hash, once the end of the transfer arrived
+    end: Option<(u64, String)>,
+}
+
+impl Transfer {
+    /// Bytes the transfer may write: the size of the file, or for a delta building it,
+    /// as much as a delta of that file could take
+    fn max_len(&self) -> u64 {
+        match self.kind {
+            TransferKind::Content => self.total_size,
+            TransferKind::Delta => self
+                .total_size
+                .saturating_mul(2)
+                .saturating_add(DELTA_OVERHEAD),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.end
+            .as_ref()
+            .is_some_and(|(chunk_count, _)| self.received.len() as u64 == *chunk_count)
+    }
+}
+
+/// Transfers in progress on a backup folder, keyed by transfer id.
+pub struct Transfers {
+    root: PathBuf,
+    active: HashMap<u64, Transfer>,
+}
+
+impl Transfers {
+    #[must_use]
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            active: HashMap::new(),
+        }
+    }
+
+    /// Whether `operation` is part of a transfer, and should go through [`Self::apply`].
+    #[must_use]
+    pub fn handles(operation: &FileOperation) -> bool {
+        matches!(
+            operation,
+            FileOperation::StartTransfer { .. }
+                | FileOperation::FileChunk { .. }
+                | FileOperation::EndTransfer { .. }
+                | FileOperation::AbortTransfer { .. }
+        )
+    }
+
+    /// Number of transfers started and not yet applied or aborted
+    #[must_use]
+    pub fn pending(&self) -> usize {
+        self.active.len()
+    }
+
+    /// Records a piece of a transfer. Returns the path of the file once the transfer is
+    /// complete and applied. A transfer that fails to apply is dropped.
+    #[instrument(skip_all, fields(root = ?self.root))]
+    pub fn apply(&mut self, operation: &FileOperation) -> Result<Option<PathBuf>> {
+        let transfer_id = match operation {
+            FileOperation::StartTransfer {
+                transfer_id,
+                relative_path,
+                kind,
+                total_size,
+                chunk_size,
+            } => {
+                if *chunk_size == 0 || *chunk_size > MAX_CHUNK_SIZE {
+                    bail!("Invalid chunk size {chunk_size} for transfer {transfer_id}");
+                }
+                let path = resolve(&self.root, relative_path)?;
+                let parent = path.parent().unwrap_or(&self.root);
+                LocalFileOps::create_dir_all(parent)?;
+                let file = NamedTempFile::new_in(parent)
+                    .with_context(|| format!("Failed to create temp file in {parent:?}"))?;
+                // A restarted origin may reuse the id of a transfer it never finished
+                if self.active.contains_key(transfer_id) {
+                    warn!(transfer_id, "replacing unfinished transfer");
+                }
+                self.active.insert(
+                    *transfer_id,
+                    Transfer {
+                        relative_path: relative_path.to_path_buf(),
+                        kind: *kind,
+                        total_size: *total_size,
+                        chunk_size: *chunk_size,
+                        file,
+                        received: HashSet::new(),
+                        end: None,
+                    },
+                );
+                *transfer_id
+            }
+            FileOperation::FileChunk {
+                transfer_id,
+                chunk_index,
+                data,
+                compression,
+            } => {
+                let transfer = self.get(*transfer_id)?;
+                let max_len = usize::try_from(transfer.chunk_size).unwrap_or(usize::MAX);
+                let data = decompress(data, *compression, max_len).with_context(|| {
+                    format!("Chunk {chunk_index} of transfer {transfer_id} is invalid")
+                })?;
+                if data.len() as u64 > transfer.chunk_size {
+                    bail!("Chunk {chunk_index} of transfer {transfer_id} is too large");
+                }
+                if let Some((chunk_count, _)) = &transfer.end
+                    && chunk_index >= chunk_count
+                {
+                    bail!("Chunk {chunk_index} of transfer {transfer_id} is past its end");
+                }
+                let offset = chunk_index
+                    .checked_mul(transfer.chunk_size)
+                    .ok_or_else(|| anyhow!("Chunk {chunk_index} is out of range"))?;
+                if offset.saturating_add(data.len() as u64) > transfer.max_len() {
+                    bail!("Chunk {chunk_index} of transfer {transfer_id} is past its size");
+                }
+                let file = transfer.file.as_file_mut();
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(&data)
+                    .with_context(|| format!("Failed to write chunk {chunk_index}"))?;
+                transfer.received.insert(*chunk_index);
+                *transfer_id
+            }
+            FileOperation::EndTransfer {
+                transfer_id,
+                chunk_count,
+                expected_hash,
+            } => {
+                let transfer = self.get(*transfer_id)?;
+                if transfer.received.iter().any(|index| index >= chunk_count) {
+                    let transfer_id = *transfer_id;
+                    self.active.remove(&transfer_id);
+                    bail!("Transfer {transfer_id} received chunks past its end");
+                }
+                transfer.end = Some((*chunk_count, expected_hash.clone()));
+                *transfer_id
+            }
+            FileOperation::AbortTransfer {
+                transfer_id,
+                reason,
+            } => {
+                if self.active.remove(transfer_id).is_some() {
+                    debug!(transfer_id, reason, "transfer aborted");
+                }
+                return Ok(None);
+            }
+            other => bail!("Not part of a transfer: {other:?}"),
+        };
+
+        if !self
+            .active
+            .get(&transfer_id)
+            .is_some_and(Transfer::is_complete)
+        {
+            return Ok(None);
+        }
+        let Some(transfer) = self.active.remove(&transfer_id) else {
+            return Ok(None);
+        };
+        let relative_path = transfer.relative_path.clone();
+        self.finish(transfer)?;
+        debug!(transfer_id, path = ?relative_path, "transfer applied");
+        Ok(Some(relative_path))
+    }
+
+    fn get(&mut self, transfer_id: u64) -> Result<&mut Transfer> {
+        self.active
+            .get_mut(&transfer_id)
+            .ok_or_else(|| anyhow!("Unknown transfer {transfer_id}"))
+    }
+
+    /// Verifies the reassembled file and moves it into place, or patches the current
+    /// copy with it for deltas.
+    fn finish(&self, mut transfer: Transfer) -> Result<()> {
+        let Some((_, expected_hash)) = transfer.end.take() else {
+            bail!("Transfer is not finished");
+        };
+        let path = resolve(&self.root, &transfer.relative_path)?;
+        transfer.file.as_file_mut().flush()?;
+
+        match transfer.kind {
+            TransferKind::Content => {
+                let size = transfer.file.as_file().metadata()?.len();
+                if size != transfer.total_size {
+                    bail!(
+                        "Size mismatch for {path:?}: expected {}, got {size}",
+                        transfer.total_size
+                    );
+                }
+                let hash = hash_file(transfer.file.path())?;
+                if hash != expected_hash {
+                    bail!(
+                        "Integrity check failed for {path:?}: expected {expected_hash}, got {hash}"
+                    );
+                }
+                if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_dir()) {
+                    LocalFileOps::remove_dir_all(&path)?;
+                }
+                transfer
+                    .file
+                    .persist(&path)
+                    .with_context(|| format!("Failed to write {path:?}"))?;
+                Ok(())
+            }
+            TransferKind::Delta => {
+                let delta = fs::read(transfer.file.path())?;
+                apply_delta_securely(&self.root, &transfer.relative_path, delta, expected_hash)
+            }
+        }
+    }
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
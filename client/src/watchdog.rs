@@ -0,0 +1,88 @@
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+#[cfg(feature = "systemd")]
+use tracing::{debug, warn};
+
+/// Liveness of the event loop as seen by the watchdog thread. The loop beats once per
+/// iteration with the health it observed; a loop that hangs stops beating.
+#[derive(Debug, Default)]
+pub struct Heartbeat {
+    last: Mutex<Option<(Instant, bool)>>,
+}
+
+impl Heartbeat {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that the loop is alive, and whether every pair was in good health.
+    pub fn beat(&self, healthy: bool) {
+        if let Ok(mut last) = self.last.lock() {
+            *last = Some((Instant::now(), healthy));
+        }
+    }
+
+    /// Whether the loop beat within `max_age` and was healthy at the time.
+    #[must_use]
+    pub fn is_alive(&self, max_age: Duration) -> bool {
+        self.last
+            .lock()
+            .is_ok_and(|last| last.is_some_and(|(at, healthy)| healthy && at.elapsed() <= max_age))
+    }
+}
+
+/// Tells the service manager that the initial sync is done. A no-op when not started
+/// by systemd or when built without the `systemd` feature.
+pub fn notify_ready() -> Result<()> {
+    #[cfg(feature = "systemd")]
+    sd_notify::notify(false, &[sd_notify::NotifyState::Ready])?;
+    Ok(())
+}
+
+/// Tells the service manager that the process is shutting down.
+pub fn notify_stopping() -> Result<()> {
+    #[cfg(feature = "systemd")]
+    sd_notify::notify(false, &[sd_notify::NotifyState::Stopping])?;
+    Ok(())
+}
+
+/// Watchdog timeout configured by the service manager, if any.
+#[must_use]
+pub fn watchdog_timeout() -> Option<Duration> {
+    #[cfg(feature = "systemd")]
+    {
+        let mut usec = 0;
+        if sd_notify::watchdog_enabled(false, &mut usec) {
+            return Some(Duration::from_micros(usec));
+        }
+    }
+    None
+}
+
+/// Starts a thread pinging the watchdog at half the configured timeout, as long as
+/// `heartbeat` is recent and healthy. Returns `None` when no watchdog is configured.
+#[must_use]
+pub fn spawn_watchdog(heartbeat: Arc<Heartbeat>) -> Option<JoinHandle<()>> {
+    let timeout = watchdog_timeout()?;
+    Some(thread::spawn(move || {
+        let interval = timeout / 2;
+        loop {
+            thread::sleep(interval);
+            if heartbeat.is_alive(timeout) {
+                ping();
+            }
+        }
+    }))
+}
+
+fn ping() {
+    #[cfg(feature = "systemd")]
+    match sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog]) {
+        Ok(()) => debug!("watchdog ping sent"),
+        Err(e) => warn!("failed to ping watchdog: {e}"),
+    }
+}
@@ -1,5 +1,8 @@
-use backup_sync_client::state::AppState;
-use backup_sync_client::synchronizer::SyncOptions;
+use backup_sync_client::health::HealthLevel;
+use backup_sync_client::instance::PidFile;
+use backup_sync_client::state::{AppState, MAX_RETRY_ATTEMPTS};
+use backup_sync_client::status::{STATUS_VERSION, StatusSnapshot};
+use backup_sync_client::synchronizer::{SyncAction, SyncOptions};
 use notify::EventKind;
 use notify::event::{CreateKind, ModifyKind, RemoveKind, RenameMode};
 use notify_debouncer_full::DebouncedEvent;
@@ -7,7 +10,7 @@ use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 
 fn create_file(dir: &std::path::Path, name: &str, content: &str) -> PathBuf {
@@ -24,6 +27,11 @@ fn read_file_content(path: &std::path::Path) -> String {
     fs::read_to_string(path).unwrap()
 }
 
+/// Options keeping instance locks and status files out of the user's state directory
+fn test_options() -> SyncOptions {
+    SyncOptions::default().with_state_dir(std::env::temp_dir().join("backup-sync-tests"))
+}
+
 fn create_debounced_event(kind: EventKind, paths: Vec<PathBuf>) -> DebouncedEvent {
     DebouncedEvent {
         event: notify::Event {
@@ -45,7 +53,7 @@ fn test_app_state_new_with_local_sync_creates_backup() {
     let _state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -64,7 +72,7 @@ fn test_app_state_new_with_local_sync_nested_files() {
     let _state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -81,7 +89,7 @@ fn test_app_state_process_create_event() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -106,7 +114,7 @@ fn test_app_state_process_create_event_nested() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -137,7 +145,7 @@ fn test_app_state_process_delete_event() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -167,7 +175,7 @@ fn test_app_state_process_delete_event_keeps_backup_with_option() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default().with_when_delete_keep_backup(true),
+        test_options().with_when_delete_keep_backup(true),
     )
     .unwrap();
 
@@ -199,7 +207,7 @@ fn test_app_state_process_rename_event() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -237,7 +245,7 @@ fn test_app_state_process_modify_event() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -270,7 +278,7 @@ fn test_app_state_process_modify_event_no_change() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -301,7 +309,7 @@ fn test_app_state_process_rename_to_event() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -331,7 +339,7 @@ fn test_app_state_process_rename_from_event() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -365,7 +373,7 @@ fn test_app_state_with_sync_options_preserve_backup() {
     let _state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default().with_when_missing_preserve_backup(true),
+        test_options().with_when_missing_preserve_backup(true),
     )
     .unwrap();
 
@@ -386,7 +394,7 @@ fn test_app_state_with_sync_options_conflict_preserve_backup() {
     let _state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default().with_when_conflict_preserve_backup(true),
+        test_options().with_when_conflict_preserve_backup(true),
     )
     .unwrap();
 
@@ -409,7 +417,7 @@ fn test_app_state_multiple_files_sync() {
     let _state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -442,7 +450,7 @@ fn test_app_state_empty_file_sync() {
     let _state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -462,7 +470,7 @@ fn test_app_state_large_file_sync() {
     let _state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -479,7 +487,7 @@ fn test_app_state_process_multiple_create_events_batch() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -514,7 +522,7 @@ fn test_app_state_process_multiple_delete_events_batch() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -546,7 +554,7 @@ fn test_app_state_process_modify_event_with_large_change() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -575,7 +583,7 @@ fn test_app_state_deeply_nested_create() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -602,7 +610,7 @@ fn test_app_state_special_characters_in_filename() {
     let _state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -622,7 +630,7 @@ fn test_app_state_binary_file_sync() {
     let _state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -641,7 +649,7 @@ fn test_app_state_rapid_sequential_modifications() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -670,7 +678,7 @@ fn test_app_state_create_delete_same_file() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -706,7 +714,7 @@ fn test_app_state_concurrent_create_events() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -760,7 +768,7 @@ fn test_app_state_concurrent_modify_different_files() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -813,7 +821,7 @@ fn test_app_state_stress_test_many_files() {
     let _state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -835,7 +843,7 @@ fn test_app_state_nonexistent_original_directory() {
     let result = AppState::new_with_local_sync(
         nonexistent_path,
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     );
 
     assert!(result.is_err());
@@ -849,7 +857,7 @@ fn test_app_state_nonexistent_backup_directory() {
     let result = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         nonexistent_path,
-        SyncOptions::default(),
+        test_options(),
     );
 
     assert!(result.is_err());
@@ -863,7 +871,7 @@ fn test_app_state_process_event_with_empty_paths() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -882,7 +890,7 @@ fn test_app_state_process_unknown_event_kind() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -903,7 +911,7 @@ fn test_app_state_rename_incomplete_event() {
     let state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default(),
+        test_options(),
     )
     .unwrap();
 
@@ -931,7 +939,7 @@ fn test_app_state_all_options_combined() {
     let _state = AppState::new_with_local_sync(
         original_dir.path().to_path_buf(),
         backup_dir.path().to_path_buf(),
-        SyncOptions::default()
+        test_options()
             .with_when_missing_preserve_backup(true)
             .with_when_conflict_preserve_backup(true)
             .with_when_delete_keep_backup(true),
@@ -948,3 +956,721 @@ fn test_app_state_all_options_combined() {
         "backup version"
     );
 }
+
+#[test]
+fn test_app_state_locked_file_is_queued_for_retry() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "file.txt", "initial");
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        test_options().with_lock_timeout(Duration::from_millis(20)),
+    )
+    .unwrap();
+
+    let original_path = fs::canonicalize(original_dir.path().join("file.txt")).unwrap();
+    fs::write(&original_path, "modified").unwrap();
+    let holder = File::options().write(true).open(&original_path).unwrap();
+    fs2::FileExt::lock_exclusive(&holder).unwrap();
+
+    let event = create_debounced_event(
+        EventKind::Modify(ModifyKind::Data(notify::event::DataChange::Content)),
+        vec![original_path],
+    );
+    state.process_debounced_event(&event).unwrap();
+
+    assert_eq!(state.retry_queue_len().unwrap(), 1);
+    assert_eq!(
+        read_file_content(&backup_dir.path().join("file.txt")),
+        "initial"
+    );
+
+    drop(holder);
+    state.process_retry_queue().unwrap();
+
+    assert_eq!(state.retry_queue_len().unwrap(), 0);
+    assert_eq!(
+        read_file_content(&backup_dir.path().join("file.txt")),
+        "modified"
+    );
+}
+
+#[test]
+fn test_app_state_retry_queue_keeps_failed_paths() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        test_options().with_lock_timeout(Duration::from_millis(20)),
+    )
+    .unwrap();
+
+    let root = fs::canonicalize(original_dir.path()).unwrap();
+    let paths = [
+        create_file(&root, "docs/blocked.txt", "blocked"),
+        create_file(&root, "first.txt", "first"),
+        create_file(&root, "second.txt", "second"),
+    ];
+    let holders: Vec<File> = paths
+        .iter()
+        .map(|path| {
+            let holder = File::options().write(true).open(path).unwrap();
+            fs2::FileExt::lock_exclusive(&holder).unwrap();
+            holder
+        })
+        .collect();
+    for path in &paths {
+        let event = create_debounced_event(EventKind::Create(CreateKind::File), vec![path.clone()]);
+        state.process_debounced_event(&event).unwrap();
+    }
+    assert_eq!(state.retry_queue_len().unwrap(), 3);
+
+    // A regular file where the backup folder should be makes the copy fail
+    drop(holders);
+    fs::write(backup_dir.path().join("docs"), "not a folder").unwrap();
+    state.process_retry_queue().unwrap();
+
+    assert_eq!(state.retry_queue_len().unwrap(), 1);
+    assert_eq!(
+        read_file_content(&backup_dir.path().join("first.txt")),
+        "first"
+    );
+    assert_eq!(
+        read_file_content(&backup_dir.path().join("second.txt")),
+        "second"
+    );
+}
+
+#[test]
+fn test_app_state_retry_queue_gives_up_after_max_attempts() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        test_options().with_lock_timeout(Duration::from_millis(20)),
+    )
+    .unwrap();
+
+    let root = fs::canonicalize(original_dir.path()).unwrap();
+    let file_path = create_file(&root, "locked.txt", "locked");
+    let holder = File::options().write(true).open(&file_path).unwrap();
+    fs2::FileExt::lock_exclusive(&holder).unwrap();
+
+    let event = create_debounced_event(EventKind::Create(CreateKind::File), vec![file_path]);
+    state.process_debounced_event(&event).unwrap();
+    for _ in 1..MAX_RETRY_ATTEMPTS {
+        state.process_retry_queue().unwrap();
+        assert_eq!(state.retry_queue_len().unwrap(), 1);
+    }
+
+    // The last attempt drops the path even though it is still locked
+    state.process_retry_queue().unwrap();
+    assert_eq!(state.retry_queue_len().unwrap(), 0);
+    assert!(!backup_dir.path().join("locked.txt").exists());
+    drop(holder);
+}
+
+#[test]
+fn test_app_state_dry_run_does_not_touch_backup() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "file.txt", "content");
+    create_file(backup_dir.path(), "extra.txt", "extra");
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        test_options().with_dry_run(true),
+    )
+    .unwrap();
+
+    assert!(state.is_dry_run());
+    assert!(!backup_dir.path().join("file.txt").exists());
+    assert!(backup_dir.path().join("extra.txt").exists());
+    assert_eq!(
+        state.plan().unwrap(),
+        vec![
+            SyncAction::CopyToBackup {
+                relative: PathBuf::from("file.txt")
+            },
+            SyncAction::Remove {
+                relative: PathBuf::from("extra.txt")
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_app_state_dry_run_event_resolves_action_only() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        test_options().with_dry_run(true),
+    )
+    .unwrap();
+
+    let new_file = create_file(original_dir.path(), "new.txt", "new content");
+    let new_file = fs::canonicalize(new_file).unwrap();
+    let event = create_debounced_event(EventKind::Create(CreateKind::File), vec![new_file]);
+
+    assert_eq!(
+        state.plan_debounced_event(&event).unwrap(),
+        vec![SyncAction::CopyToBackup {
+            relative: PathBuf::from("new.txt")
+        }]
+    );
+    state.process_debounced_event(&event).unwrap();
+    assert!(!backup_dir.path().join("new.txt").exists());
+}
+
+#[test]
+fn test_app_state_leaving_dry_run_syncs() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "file.txt", "content");
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        test_options().with_dry_run(true),
+    )
+    .unwrap();
+    assert!(!backup_dir.path().join("file.txt").exists());
+
+    state.set_dry_run(false).unwrap();
+
+    assert!(!state.is_dry_run());
+    assert_eq!(
+        read_file_content(&backup_dir.path().join("file.txt")),
+        "content"
+    );
+    assert!(state.plan().unwrap().is_empty());
+}
+
+// ==================== ATOMIC SAVE TESTS ====================
+
+#[test]
+fn test_app_state_atomic_save_rename_over_target_linux() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "file.txt", "old content");
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        test_options(),
+    )
+    .unwrap();
+
+    // Editor writes the new content to a temp file, then renames it over the target
+    let root = fs::canonicalize(original_dir.path()).unwrap();
+    let temp = create_file(&root, "file.txt.tmp8f2a", "new content");
+    let target = root.join("file.txt");
+    let events = [
+        create_debounced_event(EventKind::Create(CreateKind::File), vec![temp.clone()]),
+        create_debounced_event(
+            EventKind::Modify(ModifyKind::Data(notify::event::DataChange::Content)),
+            vec![temp.clone()],
+        ),
+    ];
+    for event in &events {
+        state.process_debounced_event(event).unwrap();
+    }
+    fs::rename(&temp, &target).unwrap();
+    state
+        .process_debounced_event(&create_debounced_event(
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+            vec![temp, target],
+        ))
+        .unwrap();
+
+    assert_eq!(
+        read_file_content(&backup_dir.path().join("file.txt")),
+        "new content"
+    );
+    assert!(!backup_dir.path().join("file.txt.tmp8f2a").exists());
+}
+
+#[test]
+fn test_app_state_atomic_save_move_aside_linux() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "file.txt", "old content");
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        test_options(),
+    )
+    .unwrap();
+
+    // Vim-style save: move the original aside, write the target, delete the aside copy
+    let root = fs::canonicalize(original_dir.path()).unwrap();
+    let target = root.join("file.txt");
+    let aside = root.join("file.txt~");
+    fs::rename(&target, &aside).unwrap();
+    create_file(&root, "file.txt", "new content");
+    fs::remove_file(&aside).unwrap();
+
+    let events = [
+        create_debounced_event(
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+            vec![target.clone(), aside.clone()],
+        ),
+        create_debounced_event(EventKind::Create(CreateKind::File), vec![target]),
+        create_debounced_event(EventKind::Remove(RemoveKind::File), vec![aside]),
+    ];
+    for event in &events {
+        state.process_debounced_event(event).unwrap();
+    }
+
+    assert_eq!(
+        read_file_content(&backup_dir.path().join("file.txt")),
+        "new content"
+    );
+    assert!(!backup_dir.path().join("file.txt~").exists());
+}
+
+#[test]
+fn test_app_state_atomic_save_windows_sequence() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "report.docx", "old content");
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        test_options(),
+    )
+    .unwrap();
+
+    // Windows reports each half of a rename separately: the target is renamed to a
+    // temp name, the freshly written temp file takes its place, then the old copy is removed
+    let root = fs::canonicalize(original_dir.path()).unwrap();
+    let target = root.join("report.docx");
+    let aside = root.join("~WRL0001.tmp");
+    let written = create_file(&root, "~WRD0002.tmp", "new content");
+    fs::rename(&target, &aside).unwrap();
+    fs::rename(&written, &target).unwrap();
+    fs::remove_file(&aside).unwrap();
+
+    let events = [
+        create_debounced_event(EventKind::Create(CreateKind::File), vec![written.clone()]),
+        create_debounced_event(
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+            vec![target.clone()],
+        ),
+        create_debounced_event(
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+            vec![aside.clone()],
+        ),
+        create_debounced_event(
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+            vec![written],
+        ),
+        create_debounced_event(
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+            vec![target],
+        ),
+        create_debounced_event(EventKind::Remove(RemoveKind::File), vec![aside]),
+    ];
+    for event in &events {
+        state.process_debounced_event(event).unwrap();
+    }
+
+    assert_eq!(
+        read_file_content(&backup_dir.path().join("report.docx")),
+        "new content"
+    );
+    assert!(!backup_dir.path().join("~WRL0001.tmp").exists());
+    assert!(!backup_dir.path().join("~WRD0002.tmp").exists());
+}
+
+#[test]
+fn test_app_state_temp_patterns_can_be_disabled() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        test_options().with_temp_patterns(vec![]),
+    )
+    .unwrap();
+
+    let root = fs::canonicalize(original_dir.path()).unwrap();
+    let temp = create_file(&root, "notes.tmp", "keep me");
+    state
+        .process_debounced_event(&create_debounced_event(
+            EventKind::Create(CreateKind::File),
+            vec![temp],
+        ))
+        .unwrap();
+
+    assert!(backup_dir.path().join("notes.tmp").exists());
+}
+
+#[test]
+fn test_app_state_temp_patterns_spare_similar_names() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        test_options(),
+    )
+    .unwrap();
+
+    let root = fs::canonicalize(original_dir.path()).unwrap();
+    for name in ["page.tmpl", "x.tmpfile", "foo.tmp.json"] {
+        let path = create_file(&root, name, "keep me");
+        state
+            .process_debounced_event(&create_debounced_event(
+                EventKind::Create(CreateKind::File),
+                vec![path],
+            ))
+            .unwrap();
+
+        assert!(backup_dir.path().join(name).exists(), "{name}");
+    }
+}
+
+// ==================== IGNORE RULES TESTS ====================
+
+#[test]
+fn test_app_state_reloads_ignore_file_on_change() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "app.log", "first");
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        test_options(),
+    )
+    .unwrap();
+    assert!(backup_dir.path().join("app.log").exists());
+
+    let root = fs::canonicalize(original_dir.path()).unwrap();
+    let ignore_file = create_file(&root, ".backupsyncignore", "*.log\n");
+    state
+        .process_debounced_event(&create_debounced_event(
+            EventKind::Create(CreateKind::File),
+            vec![ignore_file],
+        ))
+        .unwrap();
+
+    // Newly ignored files are no longer mirrored...
+    let new_log = create_file(&root, "other.log", "noise");
+    state
+        .process_debounced_event(&create_debounced_event(
+            EventKind::Create(CreateKind::File),
+            vec![new_log],
+        ))
+        .unwrap();
+    assert!(!backup_dir.path().join("other.log").exists());
+
+    // ...and existing backup copies are never deleted
+    let old_log = root.join("app.log");
+    fs::remove_file(&old_log).unwrap();
+    state
+        .process_debounced_event(&create_debounced_event(
+            EventKind::Remove(RemoveKind::File),
+            vec![old_log],
+        ))
+        .unwrap();
+    assert_eq!(
+        read_file_content(&backup_dir.path().join("app.log")),
+        "first"
+    );
+}
+
+#[test]
+fn test_app_state_with_excludes_does_not_mirror_excluded_files() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "keep.txt", "keep");
+    create_file(original_dir.path(), "debug.log", "noise");
+    create_file(original_dir.path(), "target/build.o", "artifact");
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        test_options().with_excludes(vec!["*.log".to_string(), "target/".to_string()]),
+    )
+    .unwrap();
+
+    assert!(backup_dir.path().join("keep.txt").exists());
+    assert!(!backup_dir.path().join("debug.log").exists());
+    assert!(!backup_dir.path().join("target").exists());
+
+    let root = fs::canonicalize(original_dir.path()).unwrap();
+    let new_log = create_file(&root, "later.log", "more noise");
+    let new_artifact = create_file(&root, "target/other.o", "artifact");
+    state
+        .process_debounced_event(&create_debounced_event(
+            EventKind::Create(CreateKind::File),
+            vec![new_log, new_artifact],
+        ))
+        .unwrap();
+
+    assert!(!backup_dir.path().join("later.log").exists());
+    assert!(!backup_dir.path().join("target").exists());
+}
+
+// ==================== HEALTH TESTS ====================
+
+#[test]
+fn test_app_state_health_is_healthy_after_sync() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "file.txt", "content");
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        test_options(),
+    )
+    .unwrap();
+
+    let health = state.health().unwrap();
+    assert_eq!(health.level, HealthLevel::Healthy);
+    assert!(health.backup_reachable);
+    assert_eq!(health.last_full_sync_ok, Some(true));
+    assert_eq!(health.retry_queue_len, 0);
+    assert!(health.last_event_at.is_none());
+
+    let new_file = create_file(original_dir.path(), "new.txt", "new");
+    let event = create_debounced_event(
+        EventKind::Create(CreateKind::File),
+        vec![fs::canonicalize(new_file).unwrap()],
+    );
+    state.process_debounced_event(&event).unwrap();
+
+    assert!(state.health().unwrap().last_event_at.is_some());
+}
+
+#[test]
+fn test_app_state_health_fails_when_backup_is_unplugged() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        test_options(),
+    )
+    .unwrap();
+
+    fs::remove_dir_all(backup_dir.path()).unwrap();
+
+    let health = state.health().unwrap();
+    assert_eq!(health.level, HealthLevel::Failed);
+    assert!(!health.backup_reachable);
+}
+
+#[test]
+fn test_app_state_health_degraded_with_pending_retries() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "file.txt", "initial");
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        test_options(),
+    )
+    .unwrap();
+
+    let original_path = fs::canonicalize(original_dir.path().join("file.txt")).unwrap();
+    let holder = File::options().write(true).open(&original_path).unwrap();
+    fs2::FileExt::lock_exclusive(&holder).unwrap();
+    let event = create_debounced_event(
+        EventKind::Modify(ModifyKind::Data(notify::event::DataChange::Content)),
+        vec![original_path],
+    );
+    state.process_debounced_event(&event).unwrap();
+
+    let health = state.health().unwrap();
+    assert_eq!(health.level, HealthLevel::Degraded);
+    assert_eq!(health.retry_queue_len, 1);
+}
+
+// ==================== INSTANCE LOCK TESTS ====================
+
+#[test]
+fn test_app_state_second_instance_on_same_source_fails() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let other_backup_dir = TempDir::new().unwrap();
+    let state_dir = TempDir::new().unwrap();
+    let options = SyncOptions::default().with_state_dir(state_dir.path().to_path_buf());
+
+    let first = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        options.clone(),
+    )
+    .unwrap();
+    let lock_path = first.instance_lock().unwrap().path().to_path_buf();
+    assert_eq!(
+        fs::read_to_string(&lock_path).unwrap(),
+        std::process::id().to_string()
+    );
+
+    let Err(err) = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        other_backup_dir.path().to_path_buf(),
+        options.clone(),
+    ) else {
+        panic!("second instance should not start");
+    };
+    assert!(
+        err.to_string()
+            .contains(&format!("already running (pid {})", std::process::id())),
+        "{err}"
+    );
+
+    drop(first);
+    AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        other_backup_dir.path().to_path_buf(),
+        options,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_app_state_instances_on_different_sources_coexist() {
+    let first_dir = TempDir::new().unwrap();
+    let second_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let other_backup_dir = TempDir::new().unwrap();
+    let state_dir = TempDir::new().unwrap();
+    let options = SyncOptions::default().with_state_dir(state_dir.path().to_path_buf());
+
+    let _first = AppState::new_with_local_sync(
+        first_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        options.clone(),
+    )
+    .unwrap();
+    let _second = AppState::new_with_local_sync(
+        second_dir.path().to_path_buf(),
+        other_backup_dir.path().to_path_buf(),
+        options,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_pidfile_is_removed_on_drop() {
+    let state_dir = TempDir::new().unwrap();
+    let path = state_dir.path().join("backup-sync.pid");
+
+    let pidfile = PidFile::create(&path).unwrap();
+    assert_eq!(
+        fs::read_to_string(&path).unwrap().trim(),
+        std::process::id().to_string()
+    );
+
+    drop(pidfile);
+    assert!(!path.exists());
+}
+
+// ==================== STATUS TESTS ====================
+
+#[test]
+fn test_app_state_writes_status_snapshot() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let state_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "file.txt", "content");
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        SyncOptions::default().with_state_dir(state_dir.path().to_path_buf()),
+    )
+    .unwrap();
+    state.write_status().unwrap();
+
+    let snapshots = StatusSnapshot::read_all(state_dir.path()).unwrap();
+    assert_eq!(snapshots.len(), 1);
+    let snapshot = &snapshots[0];
+    assert_eq!(snapshot.version, STATUS_VERSION);
+    assert_eq!(snapshot.pid, std::process::id());
+    assert_eq!(
+        snapshot.source,
+        fs::canonicalize(original_dir.path()).unwrap()
+    );
+    assert_eq!(snapshot.level, HealthLevel::Healthy);
+    assert_eq!(snapshot.last_full_sync_ok, Some(true));
+    assert!(snapshot.last_full_sync_at.is_some());
+    assert_eq!(snapshot.last_event_at, None);
+    assert!(snapshot.errors.is_empty());
+    assert!(snapshot.is_running(state_dir.path()));
+
+    // The snapshot outlives the instance, which then shows as stopped
+    drop(state);
+    let snapshots = StatusSnapshot::read_all(state_dir.path()).unwrap();
+    assert_eq!(snapshots.len(), 1);
+    assert!(!snapshots[0].is_running(state_dir.path()));
+}
+
+#[test]
+fn test_status_snapshot_records_last_event() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let state_dir = TempDir::new().unwrap();
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        SyncOptions::default().with_state_dir(state_dir.path().to_path_buf()),
+    )
+    .unwrap();
+
+    let root = fs::canonicalize(original_dir.path()).unwrap();
+    let file = create_file(&root, "new.txt", "content");
+    state
+        .process_debounced_event(&create_debounced_event(
+            EventKind::Create(CreateKind::File),
+            vec![file],
+        ))
+        .unwrap();
+
+    let snapshot = state.status_snapshot().unwrap();
+    assert!(snapshot.last_event_at.is_some());
+    assert_eq!(snapshot.retry_queue_len, 0);
+}
+
+#[test]
+fn test_status_read_all_on_missing_dir_is_empty() {
+    let state_dir = TempDir::new().unwrap();
+
+    let snapshots = StatusSnapshot::read_all(&state_dir.path().join("missing")).unwrap();
+
+    assert!(snapshots.is_empty());
+}
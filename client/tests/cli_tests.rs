@@ -0,0 +1,251 @@
+use backup_sync_client::state::AppState;
+use backup_sync_client::synchronizer::SyncOptions;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+use tempfile::TempDir;
+
+fn run_cli(args: &[&str], source: &Path, backup: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_backup_sync_client"))
+        .args(args)
+        .arg("--source-local")
+        .arg(source)
+        .arg("--backup-local")
+        .arg(backup)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_verify_exits_zero_when_trees_match() {
+    let source = TempDir::new().unwrap();
+    let backup = TempDir::new().unwrap();
+    fs::write(source.path().join("file.txt"), "content").unwrap();
+    fs::write(backup.path().join("file.txt"), "content").unwrap();
+
+    let output = run_cli(&["verify"], source.path(), backup.path());
+
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_verify_json_reports_corrupted_backup() {
+    let source = TempDir::new().unwrap();
+    let backup = TempDir::new().unwrap();
+    fs::write(source.path().join("file.txt"), "good content").unwrap();
+    fs::write(backup.path().join("file.txt"), "bad! content").unwrap();
+    fs::write(source.path().join("new.txt"), "new").unwrap();
+
+    let output = run_cli(&["verify", "--json"], source.path(), backup.path());
+
+    assert_eq!(output.status.code(), Some(1));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        report,
+        serde_json::json!({
+            "version": 1,
+            "clean": false,
+            "mismatched": ["file.txt"],
+            "missing": ["new.txt"],
+            "extra": [],
+            "unreadable": [],
+        })
+    );
+    assert_eq!(
+        fs::read_to_string(backup.path().join("file.txt")).unwrap(),
+        "bad! content"
+    );
+    assert!(!backup.path().join("new.txt").exists());
+}
+
+#[test]
+fn test_status_json_lists_pairs_from_state_dir() {
+    let source = TempDir::new().unwrap();
+    let backup = TempDir::new().unwrap();
+    let state_dir = TempDir::new().unwrap();
+    let state = AppState::new_with_local_sync(
+        source.path().to_path_buf(),
+        backup.path().to_path_buf(),
+        SyncOptions::default().with_state_dir(state_dir.path().to_path_buf()),
+    )
+    .unwrap();
+    state.write_status().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_backup_sync_client"))
+        .args(["status", "--json", "--state-dir"])
+        .arg(state_dir.path())
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let status: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(status.as_array().unwrap().len(), 1);
+    assert_eq!(status[0]["running"], true);
+    assert_eq!(
+        status[0]["status"]["source"],
+        fs::canonicalize(source.path()).unwrap().to_str().unwrap()
+    );
+    assert_eq!(status[0]["status"]["last_full_sync_ok"], true);
+}
+
+#[test]
+fn test_json_log_format_writes_json_lines_to_stderr() {
+    let source = TempDir::new().unwrap();
+    let backup = TempDir::new().unwrap();
+    let state_dir = TempDir::new().unwrap();
+    fs::write(source.path().join("file.txt"), "content").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_backup_sync_client"))
+        .args(["--log-format", "json", "-v", "sync", "--source-local"])
+        .arg(source.path())
+        .arg("--backup-local")
+        .arg(backup.path())
+        .env("BACKUP_SYNC_STATE_DIR", state_dir.path())
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let line = stderr
+        .lines()
+        .find(|line| line.contains("effective exclude patterns"))
+        .unwrap();
+    let log: serde_json::Value = serde_json::from_str(line).unwrap();
+    assert_eq!(log["level"], "INFO");
+    assert_eq!(log["fields"]["message"], "effective exclude patterns");
+}
+
+#[test]
+fn test_missing_source_exits_with_dedicated_code() {
+    let dir = TempDir::new().unwrap();
+    let state_dir = TempDir::new().unwrap();
+
+    assert_cmd::Command::cargo_bin("backup_sync_client")
+        .unwrap()
+        .env("BACKUP_SYNC_STATE_DIR", state_dir.path())
+        .args(["sync", "--source-local"])
+        .arg(dir.path().join("missing"))
+        .arg("--backup-local")
+        .arg(dir.path().join("backup"))
+        .assert()
+        .code(3)
+        .stderr(predicates::str::contains("does not exist"));
+}
+
+#[test]
+fn test_restore_recreates_deleted_source() {
+    let dir = TempDir::new().unwrap();
+    let backup = TempDir::new().unwrap();
+    let state_dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    fs::create_dir_all(backup.path().join("docs")).unwrap();
+    fs::write(backup.path().join("docs/file.txt"), "content").unwrap();
+
+    assert_cmd::Command::cargo_bin("backup_sync_client")
+        .unwrap()
+        .env("BACKUP_SYNC_STATE_DIR", state_dir.path())
+        .args(["restore", "--source-local"])
+        .arg(&source)
+        .arg("--backup-local")
+        .arg(backup.path())
+        .assert()
+        .success();
+
+    assert_eq!(
+        fs::read_to_string(source.join("docs/file.txt")).unwrap(),
+        "content"
+    );
+}
+
+#[test]
+fn test_restore_dry_run_leaves_missing_source_alone() {
+    let dir = TempDir::new().unwrap();
+    let backup = TempDir::new().unwrap();
+    let state_dir = TempDir::new().unwrap();
+    let source = dir.path().join("source");
+    fs::create_dir_all(backup.path().join("docs")).unwrap();
+    fs::write(backup.path().join("docs/file.txt"), "content").unwrap();
+
+    assert_cmd::Command::cargo_bin("backup_sync_client")
+        .unwrap()
+        .env("BACKUP_SYNC_STATE_DIR", state_dir.path())
+        .args(["restore", "--dry-run", "--source-local"])
+        .arg(&source)
+        .arg("--backup-local")
+        .arg(backup.path())
+        .assert()
+        .success()
+        // The source itself, its folder and its file
+        .stdout(predicates::str::contains("3 action(s) planned"));
+
+    assert!(!source.exists());
+}
+
+#[test]
+fn test_same_source_and_backup_is_rejected() {
+    let dir = TempDir::new().unwrap();
+    let state_dir = TempDir::new().unwrap();
+
+    assert_cmd::Command::cargo_bin("backup_sync_client")
+        .unwrap()
+        .env("BACKUP_SYNC_STATE_DIR", state_dir.path())
+        .args(["sync", "--source-local"])
+        .arg(dir.path())
+        .arg("--backup-local")
+        .arg(dir.path().join("."))
+        .assert()
+        .code(2)
+        .stderr(predicates::str::contains("same folder"));
+}
+
+#[test]
+fn test_unwritable_backup_exits_with_dedicated_code() {
+    let source = TempDir::new().unwrap();
+    let dir = TempDir::new().unwrap();
+    let state_dir = TempDir::new().unwrap();
+    // A regular file where the backup folder should be
+    let backup = dir.path().join("backup");
+    fs::write(&backup, "not a folder").unwrap();
+
+    assert_cmd::Command::cargo_bin("backup_sync_client")
+        .unwrap()
+        .env("BACKUP_SYNC_STATE_DIR", state_dir.path())
+        .args(["sync", "--source-local"])
+        .arg(source.path())
+        .arg("--backup-local")
+        .arg(&backup)
+        .assert()
+        .code(4)
+        .stderr(predicates::str::contains("not writable"));
+}
+
+#[test]
+fn test_sync_logs_progress_when_not_a_terminal() {
+    let source = TempDir::new().unwrap();
+    let backup = TempDir::new().unwrap();
+    let state_dir = TempDir::new().unwrap();
+    for i in 0..5 {
+        fs::write(source.path().join(format!("file_{i}.txt")), "content").unwrap();
+    }
+
+    let output = Command::new(env!("CARGO_BIN_EXE_backup_sync_client"))
+        .args(["sync", "--source-local"])
+        .arg(source.path())
+        .arg("--backup-local")
+        .arg(backup.path())
+        .env("BACKUP_SYNC_STATE_DIR", state_dir.path())
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("scanning"), "{stderr}");
+    assert!(
+        stderr
+            .lines()
+            .any(|line| line.contains("sync progress") && line.contains("done=5")),
+        "{stderr}"
+    );
+}
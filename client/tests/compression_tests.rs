@@ -0,0 +1,147 @@
+use backup_sync_client::compression::{self, COMPRESSION_THRESHOLD, compress_operation};
+use backup_sync_client::remote::{self, SymlinkFallback};
+use backup_sync_client::transfer::{self, Transfers};
+use backup_sync_protocol::{Compression, FileOperation, RelativePath};
+use std::fs;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+fn relative(path: &str) -> RelativePath {
+    RelativePath::try_from(path).unwrap()
+}
+
+/// Text-like content, which compresses well
+fn text(size: usize) -> Vec<u8> {
+    (0..size).map(|i| b"the quick brown fox "[i % 20]).collect()
+}
+
+/// Content that looks already compressed
+fn noise(size: usize) -> Vec<u8> {
+    let mut data = vec![0; size];
+    blake3::Hasher::new().finalize_xof().fill(&mut data);
+    data
+}
+
+fn create_file(content: &[u8]) -> FileOperation {
+    FileOperation::CreateFile {
+        relative_path: relative("dump.sql"),
+        content: content.to_vec(),
+        hash: blake3::hash(content).into(),
+        compression: None,
+    }
+}
+
+#[test]
+fn test_compressible_content_round_trips() {
+    let data = text(100_000);
+
+    let (compressed, compression) = compression::compress(data.clone());
+
+    assert!(matches!(compression, Some(Compression::Zstd { .. })));
+    assert!(compressed.len() < data.len() / 10);
+    let decompressed = compression::decompress(&compressed, compression, data.len()).unwrap();
+    assert_eq!(decompressed.as_ref(), data.as_slice());
+}
+
+#[test]
+fn test_small_and_high_entropy_content_is_sent_as_is() {
+    for data in [text(COMPRESSION_THRESHOLD - 1), noise(100_000)] {
+        let (sent, compression) = compression::compress(data.clone());
+
+        assert_eq!(compression, None);
+        assert_eq!(sent, data);
+        let decompressed = compression::decompress(&sent, None, 0).unwrap();
+        assert_eq!(decompressed.as_ref(), data.as_slice());
+    }
+}
+
+#[test]
+fn test_corrupt_payload_is_rejected() {
+    let (mut compressed, compression) = compression::compress(text(100_000));
+    compressed.truncate(compressed.len() / 2);
+
+    let err = compression::decompress(&compressed, compression, 100_000).unwrap_err();
+
+    assert!(err.to_string().contains("Failed to decompress"), "{err}");
+}
+
+#[test]
+fn test_decompression_is_limited() {
+    let (compressed, compression) = compression::compress(text(100_000));
+
+    let err = compression::decompress(&compressed, compression, 99_999).unwrap_err();
+
+    assert!(err.to_string().contains("larger than"), "{err}");
+}
+
+#[test]
+fn test_apply_operation_decompresses_before_checking_hash() {
+    let root = TempDir::new().unwrap();
+    let data = text(100_000);
+    let operation = compress_operation(create_file(&data));
+    assert!(matches!(
+        operation,
+        FileOperation::CreateFile {
+            compression: Some(_),
+            ..
+        }
+    ));
+
+    remote::apply_operation(root.path(), &operation, SymlinkFallback::Skip).unwrap();
+
+    assert_eq!(fs::read(root.path().join("dump.sql")).unwrap(), data);
+}
+
+#[test]
+fn test_apply_operation_refuses_corrupt_compressed_content() {
+    let root = TempDir::new().unwrap();
+    let FileOperation::CreateFile {
+        relative_path,
+        mut content,
+        hash,
+        compression,
+    } = compress_operation(create_file(&text(100_000)))
+    else {
+        panic!("Expected CreateFile");
+    };
+    content.truncate(content.len() / 2);
+
+    let err = remote::apply_operation(
+        root.path(),
+        &FileOperation::CreateFile {
+            relative_path,
+            content,
+            hash,
+            compression,
+        },
+        SymlinkFallback::Skip,
+    )
+    .unwrap_err();
+
+    assert!(
+        format!("{err:#}").contains("Failed to decompress"),
+        "{err:#}"
+    );
+    assert!(!root.path().join("dump.sql").exists());
+}
+
+#[test]
+fn test_transfer_reassembles_compressed_chunks() {
+    let root = TempDir::new().unwrap();
+    let data = text(64 * 1024);
+    let mut transfers = Transfers::new(root.path().to_path_buf());
+
+    let mut applied = None;
+    for operation in transfer::split(1, relative("big.sql"), &data, 16 * 1024) {
+        let operation = compress_operation(operation);
+        if let FileOperation::FileChunk { compression, .. } = &operation {
+            assert!(compression.is_some());
+        }
+        if let Some(path) = transfers.apply(&operation).unwrap() {
+            applied = Some(path);
+        }
+    }
+
+    assert_eq!(applied, Some(PathBuf::from("big.sql")));
+    assert_eq!(fs::read(root.path().join("big.sql")).unwrap(), data);
+}
@@ -0,0 +1,219 @@
+use backup_sync_client::config::{CliOverrides, Config, DEFAULT_DEBOUNCE, FolderPair};
+use backup_sync_client::ignore_rules::IgnoreRules;
+use backup_sync_client::logging::{LogFormat, filter_directive};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const FULL_CONFIG: &str = r#"
+log_level = "debug"
+debounce_ms = 500
+
+[[folders]]
+source = "/data/source"
+backup = "/data/backup"
+
+[sync]
+when_delete_keep_backup = true
+lock_timeout_ms = 250
+excludes = ["*.log", "target/"]
+"#;
+
+fn write_config(content: &str) -> (TempDir, PathBuf) {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("backup-sync.toml");
+    fs::write(&path, content).unwrap();
+    (dir, path)
+}
+
+#[test]
+fn test_load_full_config() {
+    let (_dir, path) = write_config(FULL_CONFIG);
+
+    let config = Config::load(&path).unwrap();
+
+    assert_eq!(
+        config.folders().unwrap(),
+        [FolderPair {
+            source: PathBuf::from("/data/source"),
+            backup: PathBuf::from("/data/backup"),
+        }]
+    );
+    assert!(config.sync.when_delete_keep_backup());
+    assert!(!config.sync.when_missing_preserve_backup());
+    assert_eq!(config.sync.excludes(), ["*.log", "target/"]);
+    assert_eq!(config.debounce(), Duration::from_millis(500));
+    assert_eq!(config.log_level(), "debug");
+}
+
+#[test]
+fn test_empty_config_uses_defaults() {
+    let config = Config::parse("").unwrap();
+
+    assert!(config.folders().is_err());
+    assert!(!config.sync.is_dry_run());
+    assert_eq!(config.debounce(), DEFAULT_DEBOUNCE);
+    assert_eq!(config.log_level(), "info");
+}
+
+#[test]
+fn test_cli_overrides_file_values() {
+    let (_dir, path) = write_config(FULL_CONFIG);
+    let cli = CliOverrides {
+        folders: vec![FolderPair {
+            source: PathBuf::from("/cli/source"),
+            backup: PathBuf::from("/cli/backup"),
+        }],
+        when_missing_preserve_backup: true,
+        dry_run: true,
+        debounce_ms: Some(50),
+        ..CliOverrides::default()
+    };
+
+    let config = Config::load(&path).unwrap().merge(cli);
+
+    assert_eq!(
+        config.folders().unwrap()[0].source,
+        PathBuf::from("/cli/source")
+    );
+    assert!(config.sync.when_missing_preserve_backup());
+    assert!(config.sync.is_dry_run());
+    assert_eq!(config.debounce(), Duration::from_millis(50));
+    // Values not given on the command line come from the file
+    assert!(config.sync.when_delete_keep_backup());
+    assert_eq!(config.sync.excludes(), ["*.log", "target/"]);
+    assert_eq!(config.log_level(), "debug");
+}
+
+#[test]
+fn test_cli_excludes_extend_file_excludes() {
+    let (_dir, path) = write_config(FULL_CONFIG);
+    let cli = CliOverrides {
+        excludes: vec!["*.tmp".to_string()],
+        ..CliOverrides::default()
+    };
+
+    let config = Config::load(&path).unwrap().merge(cli);
+
+    assert_eq!(config.sync.excludes(), ["*.log", "target/", "*.tmp"]);
+}
+
+#[test]
+fn test_invalid_exclude_in_file_is_reported() {
+    let err = format!(
+        "{:#}",
+        Config::parse("[sync]\nexcludes = [\"src/[\"]\n").unwrap_err()
+    );
+
+    assert!(err.contains("sync.excludes"), "{err}");
+    assert!(err.contains("src/["), "{err}");
+}
+
+#[test]
+fn test_parse_exclude_validates_globs() {
+    assert_eq!(
+        IgnoreRules::parse_exclude("target/").unwrap(),
+        "target/".to_string()
+    );
+    assert!(IgnoreRules::parse_exclude("*.{log,tmp").is_err());
+    assert!(IgnoreRules::parse_exclude("src/[").is_err());
+}
+
+#[test]
+fn test_unknown_key_is_reported() {
+    let (_dir, path) = write_config("[sync]\nwhen_delete_keep_bakup = true\n");
+
+    let err = format!("{:#}", Config::load(&path).unwrap_err());
+
+    assert!(err.contains("when_delete_keep_bakup"), "{err}");
+    assert!(err.contains("backup-sync.toml"), "{err}");
+}
+
+#[test]
+fn test_wrong_type_is_reported() {
+    let err = format!(
+        "{:#}",
+        Config::parse("debounce_ms = \"fast\"\n").unwrap_err()
+    );
+
+    assert!(err.contains("debounce_ms"), "{err}");
+}
+
+#[test]
+fn test_multiple_folder_pairs() {
+    let config = Config::parse(
+        r#"
+[[folders]]
+source = "a"
+backup = "b"
+
+[[folders]]
+source = "c"
+backup = "d"
+"#,
+    )
+    .unwrap();
+
+    assert_eq!(config.folders().unwrap().len(), 2);
+}
+
+#[test]
+fn test_parse_pair_argument() {
+    assert_eq!(
+        FolderPair::parse("/src:/dst").unwrap(),
+        FolderPair {
+            source: PathBuf::from("/src"),
+            backup: PathBuf::from("/dst"),
+        }
+    );
+    assert!(FolderPair::parse("/src").is_err());
+    assert!(FolderPair::parse(":/dst").is_err());
+}
+
+#[test]
+fn test_parse_pair_argument_with_drive_letters() {
+    assert_eq!(
+        FolderPair::parse(r"C:\src:D:\dst").unwrap(),
+        FolderPair {
+            source: PathBuf::from(r"C:\src"),
+            backup: PathBuf::from(r"D:\dst"),
+        }
+    );
+    assert_eq!(
+        FolderPair::parse("C:/src:/mnt/dst").unwrap(),
+        FolderPair {
+            source: PathBuf::from("C:/src"),
+            backup: PathBuf::from("/mnt/dst"),
+        }
+    );
+    assert_eq!(
+        FolderPair::parse("a:b").unwrap(),
+        FolderPair {
+            source: PathBuf::from("a"),
+            backup: PathBuf::from("b"),
+        }
+    );
+    assert!(FolderPair::parse(r"C:\src").is_err());
+}
+
+#[test]
+fn test_filter_directive_shifts_level_with_verbosity_flags() {
+    assert_eq!(filter_directive("info", 0, 0), "info");
+    assert_eq!(filter_directive("info", 1, 0), "debug");
+    assert_eq!(filter_directive("info", 5, 0), "trace");
+    assert_eq!(filter_directive("info", 0, 1), "warn");
+    assert_eq!(filter_directive("warn", 0, 3), "error");
+    assert_eq!(
+        filter_directive("client=debug,notify=warn", 0, 0),
+        "client=debug,notify=warn"
+    );
+}
+
+#[test]
+fn test_parse_log_format() {
+    let config = Config::parse("log_format = \"json\"\n").unwrap();
+
+    assert_eq!(config.log_format(), LogFormat::Json);
+    assert_eq!(Config::default().log_format(), LogFormat::Pretty);
+}
@@ -0,0 +1,108 @@
+use backup_sync_client::ignore_rules::IgnoreRules;
+use backup_sync_client::manifest::{build_manifest, corrective_operations};
+use backup_sync_client::remote::{self, SymlinkFallback};
+use backup_sync_protocol::FileOperation;
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn write(root: &Path, relative: &str, content: &str) {
+    let path = root.join(relative);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, content).unwrap();
+}
+
+fn no_rules(root: &Path) -> IgnoreRules {
+    IgnoreRules::load(root, &[])
+}
+
+#[test]
+fn test_manifest_lists_files_with_size_and_hash() {
+    let root = TempDir::new().unwrap();
+    write(root.path(), "b.txt", "bee");
+    write(root.path(), "a/nested.txt", "nested");
+    fs::create_dir(root.path().join("empty")).unwrap();
+
+    let manifest = build_manifest(root.path(), &no_rules(root.path())).unwrap();
+
+    let paths: Vec<&Path> = manifest
+        .entries
+        .iter()
+        .map(|entry| entry.relative_path.as_path())
+        .collect();
+    assert_eq!(paths, [Path::new("a/nested.txt"), Path::new("b.txt")]);
+    assert_eq!(manifest.entries[1].size, 3);
+    assert_eq!(manifest.entries[1].hash, *blake3::hash(b"bee").as_bytes());
+    assert!(manifest.entries[1].mtime.is_some());
+}
+
+#[test]
+fn test_manifest_skips_excluded_files() {
+    let root = TempDir::new().unwrap();
+    write(root.path(), "keep.txt", "keep");
+    write(root.path(), "debug.log", "noise");
+    let rules = IgnoreRules::load(root.path(), &["*.log".to_string()]);
+
+    let manifest = build_manifest(root.path(), &rules).unwrap();
+
+    assert_eq!(manifest.entries.len(), 1);
+    assert_eq!(manifest.entries[0].relative_path, Path::new("keep.txt"));
+}
+
+#[test]
+fn test_backup_receives_one_operation_per_difference() {
+    let origin = TempDir::new().unwrap();
+    let backup = TempDir::new().unwrap();
+    write(origin.path(), "same.txt", "same");
+    write(backup.path(), "same.txt", "same");
+    write(origin.path(), "stale.txt", "new content");
+    write(backup.path(), "stale.txt", "old content");
+    write(origin.path(), "docs/missing.txt", "missing");
+    write(backup.path(), "extra.txt", "extra");
+    let rules = no_rules(origin.path());
+    let local = build_manifest(origin.path(), &rules).unwrap();
+    let remote = build_manifest(backup.path(), &no_rules(backup.path())).unwrap();
+
+    let operations = corrective_operations(origin.path(), &rules, &local, &remote).unwrap();
+
+    assert_eq!(operations.len(), 3, "{operations:?}");
+    assert!(matches!(
+        &operations[0],
+        FileOperation::RemoveFile { relative_path } if relative_path == Path::new("extra.txt")
+    ));
+    let created: Vec<&Path> = operations[1..]
+        .iter()
+        .map(|operation| match operation {
+            FileOperation::CreateFile { relative_path, .. } => relative_path.as_path(),
+            other => panic!("Expected CreateFile, got {other:?}"),
+        })
+        .collect();
+    assert_eq!(
+        created,
+        [Path::new("docs/missing.txt"), Path::new("stale.txt")]
+    );
+
+    for operation in &operations {
+        remote::apply_operation(backup.path(), operation, SymlinkFallback::Skip).unwrap();
+    }
+    let converged = build_manifest(backup.path(), &no_rules(backup.path())).unwrap();
+    assert!(
+        corrective_operations(origin.path(), &rules, &local, &converged)
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_ignored_files_of_the_backup_are_left_alone() {
+    let origin = TempDir::new().unwrap();
+    let backup = TempDir::new().unwrap();
+    write(backup.path(), "debug.log", "noise");
+    let rules = IgnoreRules::load(origin.path(), &["*.log".to_string()]);
+    let local = build_manifest(origin.path(), &rules).unwrap();
+    let remote = build_manifest(backup.path(), &no_rules(backup.path())).unwrap();
+
+    let operations = corrective_operations(origin.path(), &rules, &local, &remote).unwrap();
+
+    assert!(operations.is_empty(), "{operations:?}");
+}
@@ -0,0 +1,183 @@
+use backup_sync_client::config::FolderPair;
+use backup_sync_client::pairs::{PairSet, validate_pairs};
+use backup_sync_client::synchronizer::SyncOptions;
+use notify::EventKind;
+use notify::event::{CreateKind, ModifyKind, RenameMode};
+use notify_debouncer_full::DebouncedEvent;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Instant;
+use tempfile::TempDir;
+
+fn create_debounced_event(kind: EventKind, paths: Vec<PathBuf>) -> DebouncedEvent {
+    DebouncedEvent {
+        event: notify::Event {
+            kind,
+            paths,
+            attrs: Default::default(),
+        },
+        time: Instant::now(),
+    }
+}
+
+fn pair(source: &Path, backup: &Path) -> FolderPair {
+    FolderPair {
+        source: source.to_path_buf(),
+        backup: backup.to_path_buf(),
+    }
+}
+
+fn isolated_options(state_dir: &TempDir) -> SyncOptions {
+    SyncOptions::default().with_state_dir(state_dir.path().to_path_buf())
+}
+
+#[test]
+fn test_validate_pairs_rejects_duplicate_sources() {
+    let source = TempDir::new().unwrap();
+    let backup_a = TempDir::new().unwrap();
+    let backup_b = TempDir::new().unwrap();
+
+    let err = validate_pairs(&[
+        pair(source.path(), backup_a.path()),
+        pair(source.path(), backup_b.path()),
+    ])
+    .unwrap_err();
+
+    assert!(err.to_string().contains("Duplicate source root"), "{err}");
+}
+
+#[test]
+fn test_validate_pairs_rejects_nested_sources() {
+    let source = TempDir::new().unwrap();
+    let nested = source.path().join("nested");
+    fs::create_dir(&nested).unwrap();
+    let backup_a = TempDir::new().unwrap();
+    let backup_b = TempDir::new().unwrap();
+
+    let err = validate_pairs(&[
+        pair(source.path(), backup_a.path()),
+        pair(&nested, backup_b.path()),
+    ])
+    .unwrap_err();
+
+    assert!(err.to_string().contains("overlap"), "{err}");
+}
+
+#[test]
+fn test_validate_pairs_rejects_backup_inside_source() {
+    let source = TempDir::new().unwrap();
+    let backup = source.path().join("backup");
+    fs::create_dir(&backup).unwrap();
+
+    let err = validate_pairs(&[pair(source.path(), &backup)]).unwrap_err();
+
+    assert!(err.to_string().contains("inside source root"), "{err}");
+}
+
+#[test]
+fn test_validate_pairs_rejects_shared_and_nested_backups() {
+    let source_a = TempDir::new().unwrap();
+    let source_b = TempDir::new().unwrap();
+    let backup = TempDir::new().unwrap();
+    let nested = backup.path().join("nested");
+    fs::create_dir(&nested).unwrap();
+
+    let err = validate_pairs(&[
+        pair(source_a.path(), backup.path()),
+        pair(source_b.path(), backup.path()),
+    ])
+    .unwrap_err();
+    assert!(err.to_string().contains("Duplicate backup root"), "{err}");
+
+    let err = validate_pairs(&[
+        pair(source_a.path(), backup.path()),
+        pair(source_b.path(), &nested),
+    ])
+    .unwrap_err();
+    assert!(err.to_string().contains("Backup roots overlap"), "{err}");
+}
+
+#[test]
+fn test_pair_set_routes_concurrent_events_to_their_pair() {
+    let source_a = TempDir::new().unwrap();
+    let source_b = TempDir::new().unwrap();
+    let backup_a = TempDir::new().unwrap();
+    let backup_b = TempDir::new().unwrap();
+    let state_dir = TempDir::new().unwrap();
+
+    let pairs = PairSet::new(
+        &[
+            pair(source_a.path(), backup_a.path()),
+            pair(source_b.path(), backup_b.path()),
+        ],
+        &isolated_options(&state_dir),
+    )
+    .unwrap();
+    let roots: Vec<PathBuf> = pairs.sources().map(Path::to_path_buf).collect();
+
+    // Both sources get files at the same time, as they would from two busy writers
+    let writers: Vec<_> = roots
+        .iter()
+        .cloned()
+        .map(|root| {
+            thread::spawn(move || {
+                (0..10)
+                    .map(|i| {
+                        let path = root.join(format!("file_{i}.txt"));
+                        fs::write(&path, format!("content {i}")).unwrap();
+                        path
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    let created: Vec<PathBuf> = writers
+        .into_iter()
+        .flat_map(|writer| writer.join().unwrap())
+        .collect();
+
+    let events: Vec<DebouncedEvent> = created
+        .into_iter()
+        .map(|path| create_debounced_event(EventKind::Create(CreateKind::File), vec![path]))
+        .collect();
+    pairs.process_events(&events);
+
+    for i in 0..10 {
+        let name = format!("file_{i}.txt");
+        assert!(backup_a.path().join(&name).exists(), "{name} in backup a");
+        assert!(backup_b.path().join(&name).exists(), "{name} in backup b");
+    }
+}
+
+#[test]
+fn test_pair_set_rename_across_pairs_moves_backup() {
+    let source_a = TempDir::new().unwrap();
+    let source_b = TempDir::new().unwrap();
+    let backup_a = TempDir::new().unwrap();
+    let backup_b = TempDir::new().unwrap();
+    let state_dir = TempDir::new().unwrap();
+    fs::write(source_a.path().join("moved.txt"), "content").unwrap();
+
+    let pairs = PairSet::new(
+        &[
+            pair(source_a.path(), backup_a.path()),
+            pair(source_b.path(), backup_b.path()),
+        ],
+        &isolated_options(&state_dir),
+    )
+    .unwrap();
+    assert!(backup_a.path().join("moved.txt").exists());
+
+    let roots: Vec<PathBuf> = pairs.sources().map(Path::to_path_buf).collect();
+    let from = roots[0].join("moved.txt");
+    let to = roots[1].join("moved.txt");
+    fs::rename(&from, &to).unwrap();
+    pairs.process_events(&[create_debounced_event(
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+        vec![from, to],
+    )]);
+
+    assert!(!backup_a.path().join("moved.txt").exists());
+    assert!(backup_b.path().join("moved.txt").exists());
+}
@@ -0,0 +1,112 @@
+use backup_sync_client::registration::{self, Registration};
+use backup_sync_server::create_app;
+use std::net::SocketAddr;
+use tempfile::TempDir;
+
+async fn start_server() -> SocketAddr {
+    let app = create_app().await.unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    addr
+}
+
+async fn sign_up(server: &str, name: &str, password: &str) {
+    let response = reqwest::Client::new()
+        .post(format!("{server}/v1/auth/signup"))
+        .json(&serde_json::json!({ "name": name, "password": password }))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+}
+
+#[tokio::test]
+async fn test_register_provisions_computer() {
+    let server = format!("http://{}", start_server().await);
+    sign_up(&server, "alice", "password123").await;
+
+    let registration = registration::register(&server, "alice", "password123", "laptop")
+        .await
+        .unwrap();
+
+    assert_eq!(registration.computer_name, "laptop");
+    assert!(registration.computer_id.as_uuid().is_some());
+    let computers: serde_json::Value = reqwest::Client::new()
+        .get(format!("{server}/v1/computers"))
+        .bearer_auth(&registration.token)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(computers["items"][0]["id"], registration.computer_id.to_string());
+}
+
+#[tokio::test]
+async fn test_refresh_rotates_the_registration_tokens() {
+    let server = format!("http://{}", start_server().await);
+    sign_up(&server, "alice", "password123").await;
+    let mut registration = registration::register(&server, "alice", "password123", "laptop")
+        .await
+        .unwrap();
+    let spent = registration.clone();
+
+    registration.refresh().await.unwrap();
+
+    assert_ne!(registration.refresh_token, spent.refresh_token);
+    let response = reqwest::Client::new()
+        .get(format!("{server}/v1/computers"))
+        .bearer_auth(&registration.token)
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    let err = spent.clone().refresh().await.unwrap_err();
+    assert!(err.to_string().contains("authenticate first"), "{err}");
+}
+
+#[tokio::test]
+async fn test_register_with_wrong_password_asks_to_authenticate() {
+    let server = format!("http://{}", start_server().await);
+    sign_up(&server, "alice", "password123").await;
+
+    let err = registration::register(&server, "alice", "wrong", "laptop")
+        .await
+        .unwrap_err();
+
+    assert!(err.to_string().contains("authenticate first"), "{err}");
+}
+
+#[test]
+fn test_registration_round_trips_through_file() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("nested/registration.toml");
+    let registration = Registration {
+        server: "http://localhost:3000".to_string(),
+        user_id: "user".into(),
+        computer_id: "computer".into(),
+        computer_name: "laptop".to_string(),
+        token: "token".to_string(),
+        refresh_token: Some("refresh".to_string()),
+    };
+
+    registration.save(&path).unwrap();
+
+    assert_eq!(Registration::load(&path).unwrap(), registration);
+}
+
+#[test]
+fn test_registration_without_refresh_token_loads() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("registration.toml");
+    std::fs::write(
+        &path,
+        "server = \"http://localhost:3000\"\nuser_id = \"user\"\ncomputer_id = \"computer\"\n\
+         computer_name = \"laptop\"\ntoken = \"token\"\n",
+    )
+    .unwrap();
+
+    assert_eq!(Registration::load(&path).unwrap().refresh_token, None);
+}
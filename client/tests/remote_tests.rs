@@ -0,0 +1,1073 @@
+use backup_sync_client::file_streaming;
+use backup_sync_client::ignore_rules::IgnoreRules;
+use backup_sync_client::manifest::{build_manifest, corrective_operations};
+use backup_sync_client::remote::{self, RemoteOptions, Role, SymlinkFallback};
+use backup_sync_client::transfer::{self, CHUNK_SIZE, Transfers};
+use backup_sync_protocol::codec;
+use backup_sync_protocol::{
+    ClientMessage, Computer, ComputerId, Encoding, FileOperation, PROTOCOL_VERSION, RelativePath,
+    ServerMessage, SyncFolder, UserId,
+};
+use backup_sync_ws::server::{ServerConfig, run_server};
+use backup_sync_ws::state::ServerState;
+use futures_util::{SinkExt, StreamExt};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::sync::{RwLock, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+
+const USER: &str = "alice";
+const ORIGIN: &str = "laptop";
+const BACKUP: &str = "nas";
+const FOLDER: &str = "documents";
+
+fn relative(path: &str) -> RelativePath {
+    RelativePath::try_from(path).unwrap()
+}
+
+/// Address and state of a running server, and the sender that shuts it down, which it
+/// also does once dropped at the end of the test
+type TestServer = (SocketAddr, Arc<RwLock<ServerState>>, watch::Sender<bool>);
+
+async fn start_server(addr: &str) -> TestServer {
+    start_server_with(ServerConfig {
+        addrs: vec![addr.to_string()],
+        broadcast_capacity: 100,
+        ..ServerConfig::default()
+    })
+    .await
+}
+
+async fn start_server_with(config: ServerConfig) -> TestServer {
+    let config = ServerConfig {
+        allow_insecure_auth: true,
+        ..config
+    };
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(run_server(config, Some(ready_tx), shutdown_rx));
+    let ready = ready_rx.await.expect("Server failed to start");
+    seed(&ready.state).await;
+    (ready.addr, ready.state, shutdown_tx)
+}
+
+/// One user with an origin and a backup computer sharing a folder
+async fn seed(state: &RwLock<ServerState>) {
+    let mut state = state.write().await;
+    let user_id = UserId::from(USER);
+    state.get_or_create_user(&user_id);
+    for id in [ORIGIN, BACKUP] {
+        state.register_computer(
+            &user_id,
+            Computer {
+                id: id.into(),
+                name: id.to_string(),
+                online: false,
+                last_seen: None,
+            },
+        );
+    }
+    state.create_sync_folder(
+        &user_id,
+        SyncFolder {
+            id: FOLDER.into(),
+            name: FOLDER.to_string(),
+            origin_computer: ORIGIN.into(),
+            backup_computers: vec![BACKUP.into()],
+            is_synced: true,
+            pending_operations: 0,
+            backup_status: BTreeMap::new(),
+        },
+    );
+}
+
+fn options(addr: SocketAddr, computer: &str, role: Role, path: &Path) -> RemoteOptions {
+    RemoteOptions::new(
+        format!("ws://{addr}"),
+        USER.into(),
+        computer.into(),
+        FOLDER.into(),
+        role,
+        path.to_path_buf(),
+    )
+    .with_debounce(Duration::from_millis(50))
+    .with_backoff(Duration::from_millis(50), Duration::from_millis(200))
+    .with_state_dir(state_dir())
+}
+
+fn state_dir() -> PathBuf {
+    std::env::temp_dir().join("backup-sync-tests")
+}
+
+fn spawn(
+    options: RemoteOptions,
+    shutdown: &watch::Receiver<bool>,
+) -> JoinHandle<anyhow::Result<()>> {
+    tokio::spawn(remote::run(options, shutdown.clone()))
+}
+
+async fn wait_until(what: &str, mut condition: impl AsyncFnMut() -> bool) {
+    for _ in 0..200 {
+        if condition().await {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("timed out waiting for {what}");
+}
+
+async fn is_online(state: &RwLock<ServerState>, computer: &str) -> bool {
+    state
+        .read()
+        .await
+        .get_user(&USER.into())
+        .is_some_and(|user| user.computers.iter().any(|c| c.id == computer && c.online))
+}
+
+async fn connection_of(state: &RwLock<ServerState>, computer: &str) -> Option<SocketAddr> {
+    state
+        .read()
+        .await
+        .computer_connections
+        .get(&(UserId::from(USER), ComputerId::from(computer)))
+        .copied()
+}
+
+fn read(path: PathBuf) -> Option<String> {
+    fs::read_to_string(path).ok()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_mirrors_origin_to_backup() {
+    let (addr, state, _shutdown) = start_server("127.0.0.1:0").await;
+    let origin_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    fs::write(origin_dir.path().join("existing.txt"), "existing").unwrap();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let backup = spawn(
+        options(addr, BACKUP, Role::Backup, backup_dir.path()),
+        &shutdown_rx,
+    );
+    wait_until("backup online", async || is_online(&state, BACKUP).await).await;
+    let origin = spawn(
+        options(addr, ORIGIN, Role::Origin, origin_dir.path()),
+        &shutdown_rx,
+    );
+
+    // Files present before connecting are published as a snapshot
+    wait_until("snapshot", async || {
+        read(backup_dir.path().join("existing.txt")).as_deref() == Some("existing")
+    })
+    .await;
+
+    // Later changes are picked up by the watcher
+    fs::create_dir(origin_dir.path().join("sub")).unwrap();
+    fs::write(origin_dir.path().join("sub/new.txt"), "new content").unwrap();
+    wait_until("new file", async || {
+        read(backup_dir.path().join("sub/new.txt")).as_deref() == Some("new content")
+    })
+    .await;
+
+    fs::remove_file(origin_dir.path().join("existing.txt")).unwrap();
+    wait_until("removal", async || {
+        !backup_dir.path().join("existing.txt").exists()
+    })
+    .await;
+
+    // The backup records how far it got, for the next time it connects
+    let sequence = state.read().await.folder_sequence(&FOLDER.into());
+    wait_until("recorded sequence", async || {
+        read(remote::sequence_path(&state_dir(), backup_dir.path())) == Some(sequence.to_string())
+    })
+    .await;
+
+    shutdown_tx.send(true).unwrap();
+    origin.await.unwrap().unwrap();
+    backup.await.unwrap().unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_retries_until_server_is_up() {
+    // Reserve a port, then release it so that nothing listens there yet
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let backup = spawn(
+        options(addr, BACKUP, Role::Backup, backup_dir.path()),
+        &shutdown_rx,
+    );
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!backup.is_finished());
+
+    let (_, state, _shutdown) = start_server(&addr.to_string()).await;
+    wait_until("backup online", async || is_online(&state, BACKUP).await).await;
+
+    shutdown_tx.send(true).unwrap();
+    backup.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_connect_rejects_wrong_role() {
+    let (addr, _, _shutdown) = start_server("127.0.0.1:0").await;
+    let dir = TempDir::new().unwrap();
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let err = remote::run(options(addr, BACKUP, Role::Origin, dir.path()), shutdown_rx)
+        .await
+        .unwrap_err();
+
+    assert!(err.to_string().contains("is not the origin"), "{err}");
+}
+
+#[test]
+fn test_operations_with_paths_outside_folder_do_not_decode() {
+    let json = r#"{"CreateFile":{"relative_path":"../escaped.txt","content":[],"hash":[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],"compression":null}}"#;
+
+    let err = codec::decode_text::<FileOperation>(json).unwrap_err();
+
+    assert!(err.invalid_path().is_some(), "{err}");
+}
+
+#[test]
+fn test_apply_operation_refuses_content_not_matching_hash() {
+    let root = TempDir::new().unwrap();
+
+    let err = remote::apply_operation(
+        root.path(),
+        &FileOperation::CreateFile {
+            relative_path: relative("file.txt"),
+            content: b"corrupted".to_vec(),
+            hash: blake3::hash(b"content").into(),
+            compression: None,
+        },
+        SymlinkFallback::Skip,
+    )
+    .unwrap_err();
+
+    assert!(err.to_string().contains("Integrity check failed"), "{err}");
+    assert!(!root.path().join("file.txt").exists());
+}
+
+#[test]
+fn test_apply_batch_stops_at_first_failure() {
+    let root = TempDir::new().unwrap();
+    let transfers = Mutex::new(Transfers::new(root.path().to_path_buf()));
+    let file = |name: &str, content: &[u8], hashed: &[u8]| FileOperation::CreateFile {
+        relative_path: relative(name),
+        content: content.to_vec(),
+        hash: blake3::hash(hashed).into(),
+        compression: None,
+    };
+
+    let err = remote::apply_batch(
+        root.path(),
+        &transfers,
+        &[
+            file("first.txt", b"first", b"first"),
+            file("second.txt", b"corrupted", b"second"),
+            file("third.txt", b"third", b"third"),
+        ],
+        SymlinkFallback::Skip,
+    )
+    .unwrap_err();
+
+    assert!(
+        err.to_string().contains("Operation 1 of a batch of 3"),
+        "{err}"
+    );
+    assert!(root.path().join("first.txt").exists());
+    assert!(!root.path().join("second.txt").exists());
+    assert!(!root.path().join("third.txt").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_apply_operation_sets_mode_and_mtime() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let root = TempDir::new().unwrap();
+    fs::write(root.path().join("script.sh"), "#!/bin/sh\n").unwrap();
+
+    remote::apply_operation(
+        root.path(),
+        &FileOperation::SetMetadata {
+            relative_path: relative("script.sh"),
+            mode: Some(0o750),
+            readonly: None,
+            mtime: Some(1_700_000_000),
+        },
+        SymlinkFallback::Skip,
+    )
+    .unwrap();
+
+    let metadata = fs::metadata(root.path().join("script.sh")).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o750);
+    let mtime = metadata.modified().unwrap();
+    assert_eq!(
+        mtime.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        1_700_000_000
+    );
+    assert!(mtime < SystemTime::now());
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_mirrors_symlinks_without_resolving_targets() {
+    use std::os::unix::fs::symlink;
+
+    let (addr, state, _shutdown) = start_server("127.0.0.1:0").await;
+    let origin_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    fs::write(origin_dir.path().join("target.txt"), "target").unwrap();
+    symlink("target.txt", origin_dir.path().join("link.txt")).unwrap();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let backup = spawn(
+        options(addr, BACKUP, Role::Backup, backup_dir.path()),
+        &shutdown_rx,
+    );
+    wait_until("backup online", async || is_online(&state, BACKUP).await).await;
+    let origin = spawn(
+        options(addr, ORIGIN, Role::Origin, origin_dir.path()),
+        &shutdown_rx,
+    );
+
+    wait_until("snapshot link", async || {
+        fs::read_link(backup_dir.path().join("link.txt")).ok() == Some(PathBuf::from("target.txt"))
+    })
+    .await;
+    assert_eq!(
+        read(backup_dir.path().join("link.txt")).as_deref(),
+        Some("target")
+    );
+
+    // Dangling links are mirrored as they are
+    symlink("sub/../missing", origin_dir.path().join("dangling")).unwrap();
+    wait_until("dangling link", async || {
+        fs::read_link(backup_dir.path().join("dangling")).ok()
+            == Some(PathBuf::from("sub/../missing"))
+    })
+    .await;
+
+    fs::remove_file(origin_dir.path().join("link.txt")).unwrap();
+    wait_until("link removal", async || {
+        fs::symlink_metadata(backup_dir.path().join("link.txt")).is_err()
+    })
+    .await;
+    assert_eq!(
+        read(backup_dir.path().join("target.txt")).as_deref(),
+        Some("target")
+    );
+
+    shutdown_tx.send(true).unwrap();
+    origin.await.unwrap().unwrap();
+    backup.await.unwrap().unwrap();
+}
+
+#[test]
+fn test_symlinks_outside_folder_do_not_decode() {
+    let json = r#"{"CreateSymlink":{"relative_path":"/tmp/escaped","target":"anything"}}"#;
+
+    let err = codec::decode_text::<FileOperation>(json).unwrap_err();
+
+    assert!(err.invalid_path().is_some(), "{err}");
+}
+
+/// Each link passes the path checks on its own, but the second one is created through
+/// the first and lands two levels up from where its path says
+#[cfg(unix)]
+#[test]
+fn test_apply_operation_refuses_paths_through_symlinks() {
+    let outer = TempDir::new().unwrap();
+    let root = outer.path().join("backup");
+    fs::create_dir(&root).unwrap();
+    let apply = |operation: FileOperation| {
+        remote::apply_operation(&root, &operation, SymlinkFallback::Skip)
+    };
+
+    apply(FileOperation::CreateSymlink {
+        relative_path: relative("x/a"),
+        target: "..".to_string(),
+    })
+    .unwrap();
+    let err = apply(FileOperation::CreateSymlink {
+        relative_path: relative("x/a/l"),
+        target: "../..".to_string(),
+    })
+    .unwrap_err();
+    assert!(err.to_string().contains("through the symlink"), "{err}");
+
+    let err = apply(FileOperation::CreateFile {
+        relative_path: relative("x/a/l/escaped.txt"),
+        content: b"escaped".to_vec(),
+        hash: blake3::hash(b"escaped").into(),
+        compression: None,
+    })
+    .unwrap_err();
+    assert!(err.to_string().contains("through the symlink"), "{err}");
+    let err = apply(FileOperation::SetMetadata {
+        relative_path: relative("x/a"),
+        mode: Some(0o777),
+        readonly: None,
+        mtime: None,
+    })
+    .unwrap_err();
+    assert!(err.to_string().contains("follow the symlink"), "{err}");
+
+    assert!(fs::symlink_metadata(root.join("x/a/l")).is_err());
+    assert!(!outer.path().join("escaped.txt").exists());
+    assert!(!root.join("escaped.txt").exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_transfers_large_files_in_chunks() {
+    let (addr, state, _shutdown) = start_server("127.0.0.1:0").await;
+    let origin_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let large: Vec<u8> = (0..CHUNK_SIZE * 3 + 17).map(|i| (i % 251) as u8).collect();
+    fs::write(origin_dir.path().join("large.bin"), &large).unwrap();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let backup = spawn(
+        options(addr, BACKUP, Role::Backup, backup_dir.path()),
+        &shutdown_rx,
+    );
+    wait_until("backup online", async || is_online(&state, BACKUP).await).await;
+    let origin = spawn(
+        options(addr, ORIGIN, Role::Origin, origin_dir.path()),
+        &shutdown_rx,
+    );
+
+    wait_until("large file", async || {
+        fs::read(backup_dir.path().join("large.bin")).is_ok_and(|content| content == large)
+    })
+    .await;
+
+    shutdown_tx.send(true).unwrap();
+    origin.await.unwrap().unwrap();
+    backup.await.unwrap().unwrap();
+}
+
+type RawStream =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// An origin driven by hand, to send what the regular origin never does
+async fn connect_raw_origin(addr: SocketAddr) -> RawStream {
+    connect_raw(addr, ORIGIN).await
+}
+
+/// A connection of `computer` driven by hand
+async fn connect_raw(addr: SocketAddr, computer: &str) -> RawStream {
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
+        .await
+        .unwrap();
+    ws.next().await.unwrap().unwrap();
+    let authenticate = ClientMessage::Authenticate {
+        user_id: USER.into(),
+        computer_id: computer.into(),
+        protocol_version: PROTOCOL_VERSION,
+        token: String::new(),
+        last_applied: None,
+    };
+    ws.send(Message::Text(
+        serde_json::to_string(&authenticate).unwrap().into(),
+    ))
+    .await
+    .unwrap();
+    let reply = ws.next().await.unwrap().unwrap().into_text().unwrap();
+    assert!(matches!(
+        serde_json::from_str(&reply).unwrap(),
+        ServerMessage::Authenticated { .. }
+    ));
+    ws
+}
+
+async fn send_raw(ws: &mut RawStream, operation: FileOperation) {
+    let message = ClientMessage::FolderOperation {
+        folder_id: FOLDER.into(),
+        operation,
+    };
+    ws.send(Message::Text(
+        serde_json::to_string(&message).unwrap().into(),
+    ))
+    .await
+    .unwrap();
+}
+
+/// Sends a transfer from a hand-driven origin with its chunks reversed and the first
+/// chunk after the end, which the regular origin never does.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_reassembles_out_of_order_transfer() {
+    let (addr, state, _shutdown) = start_server("127.0.0.1:0").await;
+    let backup_dir = TempDir::new().unwrap();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let backup = spawn(
+        options(addr, BACKUP, Role::Backup, backup_dir.path()),
+        &shutdown_rx,
+    );
+    wait_until("backup online", async || is_online(&state, BACKUP).await).await;
+
+    let mut ws = connect_raw_origin(addr).await;
+
+    let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+    let mut operations = transfer::split(42, relative("shuffled.bin"), &data, 100);
+    let end = operations.pop().unwrap();
+    let first_chunk = operations.remove(1);
+    operations[1..].reverse();
+    operations.push(end);
+    operations.push(first_chunk);
+    for operation in operations {
+        send_raw(&mut ws, operation).await;
+    }
+
+    wait_until("reassembled file", async || {
+        fs::read(backup_dir.path().join("shuffled.bin")).is_ok_and(|content| content == data)
+    })
+    .await;
+
+    shutdown_tx.send(true).unwrap();
+    backup.await.unwrap().unwrap();
+}
+
+/// Next message for a hand-driven origin, skipping the ones it does not care about
+async fn recv_raw(ws: &mut RawStream) -> ServerMessage {
+    loop {
+        let frame = tokio::time::timeout(Duration::from_secs(5), ws.next())
+            .await
+            .expect("Timed out waiting for a message")
+            .unwrap()
+            .unwrap();
+        if let Message::Text(text) = frame {
+            match serde_json::from_str(&text).unwrap() {
+                ServerMessage::ComputerStatusChanged { .. } => {}
+                message => return message,
+            }
+        }
+    }
+}
+
+/// A hand-driven backup takes over as origin. The running origin is told, reconnects as
+/// a backup, and applies what the new origin publishes.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_origin_becomes_backup_when_origin_switches() {
+    let (addr, state, _shutdown) = start_server("127.0.0.1:0").await;
+    let origin_dir = TempDir::new().unwrap();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let origin = spawn(
+        options(addr, ORIGIN, Role::Origin, origin_dir.path()),
+        &shutdown_rx,
+    );
+    wait_until("origin online", async || is_online(&state, ORIGIN).await).await;
+    let first_connection = connection_of(&state, ORIGIN).await;
+
+    let mut ws = connect_raw(addr, BACKUP).await;
+    let request = ClientMessage::RequestOriginSwitch {
+        folder_id: FOLDER.into(),
+    };
+    ws.send(Message::Text(
+        serde_json::to_string(&request).unwrap().into(),
+    ))
+    .await
+    .unwrap();
+    assert!(matches!(
+        recv_raw(&mut ws).await,
+        ServerMessage::OriginSwitched { .. }
+    ));
+
+    wait_until("reconnection as a backup", async || {
+        connection_of(&state, ORIGIN)
+            .await
+            .is_some_and(|addr| Some(addr) != first_connection)
+    })
+    .await;
+    let content = b"from the new origin".to_vec();
+    send_raw(
+        &mut ws,
+        FileOperation::CreateFile {
+            relative_path: relative("switched.txt"),
+            hash: blake3::hash(&content).into(),
+            content,
+            compression: None,
+        },
+    )
+    .await;
+    wait_until("file from the new origin", async || {
+        read(origin_dir.path().join("switched.txt")).as_deref() == Some("from the new origin")
+    })
+    .await;
+
+    shutdown_tx.send(true).unwrap();
+    origin.await.unwrap().unwrap();
+}
+
+/// A hand-driven origin sends operations as fast as it can. The backup applies them in
+/// the order they were sent, which its acks, and so the completions, follow.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_applies_rapid_operations_in_order() {
+    const OPERATIONS: u64 = 500;
+    // Enough for every message of the test, not to lose completions to a lagging origin
+    let (addr, state, _shutdown) = start_server_with(ServerConfig {
+        addrs: vec!["127.0.0.1:0".to_string()],
+        broadcast_capacity: 4096,
+        ..ServerConfig::default()
+    })
+    .await;
+    let backup_dir = TempDir::new().unwrap();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let backup = spawn(
+        options(addr, BACKUP, Role::Backup, backup_dir.path()),
+        &shutdown_rx,
+    );
+    wait_until("backup online", async || is_online(&state, BACKUP).await).await;
+
+    let mut ws = connect_raw_origin(addr).await;
+    for i in 0..OPERATIONS {
+        let content = i.to_string().into_bytes();
+        send_raw(
+            &mut ws,
+            FileOperation::CreateFile {
+                relative_path: relative("counter.txt"),
+                hash: blake3::hash(&content).into(),
+                content,
+                compression: None,
+            },
+        )
+        .await;
+    }
+
+    let mut completed = Vec::new();
+    while completed.len() < OPERATIONS as usize {
+        match recv_raw(&mut ws).await {
+            ServerMessage::OperationComplete { operation_id } => completed.push(operation_id),
+            ServerMessage::OperationFailed { reason, .. } => panic!("Operation failed: {reason}"),
+            _ => {}
+        }
+    }
+    assert!(
+        completed.windows(2).all(|pair| pair[0] < pair[1]),
+        "{completed:?}"
+    );
+    assert_eq!(
+        read(backup_dir.path().join("counter.txt")),
+        Some((OPERATIONS - 1).to_string())
+    );
+    assert_eq!(
+        state.read().await.folder_sequence(&FOLDER.into()),
+        OPERATIONS
+    );
+
+    shutdown_tx.send(true).unwrap();
+    backup.await.unwrap().unwrap();
+}
+
+/// A hand-driven origin asks for the signature of a file, computes the delta against
+/// the backup's answer and sends it to that backup only.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_applies_delta_computed_from_backup_signature() {
+    let (addr, state, _shutdown) = start_server("127.0.0.1:0").await;
+    let origin_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let old: String = (0..2000).map(|i| format!("line {i}\n")).collect();
+    let new = old.replace("line 1000\n", "line one thousand\n");
+    fs::write(backup_dir.path().join("notes.txt"), &old).unwrap();
+    fs::write(origin_dir.path().join("notes.txt"), &new).unwrap();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let backup = spawn(
+        options(addr, BACKUP, Role::Backup, backup_dir.path()),
+        &shutdown_rx,
+    );
+    wait_until("backup online", async || is_online(&state, BACKUP).await).await;
+
+    let mut ws = connect_raw_origin(addr).await;
+    let request = ClientMessage::RequestSignature {
+        folder_id: FOLDER.into(),
+        relative_path: relative("notes.txt"),
+    };
+    ws.send(Message::Text(
+        serde_json::to_string(&request).unwrap().into(),
+    ))
+    .await
+    .unwrap();
+    let (computer_id, signature) = match recv_raw(&mut ws).await {
+        ServerMessage::SignatureResponse {
+            computer_id,
+            relative_path,
+            signature,
+            ..
+        } => {
+            assert_eq!(relative_path, Path::new("notes.txt"));
+            (computer_id, signature)
+        }
+        message => panic!("Expected SignatureResponse, got {message:?}"),
+    };
+    assert_eq!(computer_id, BACKUP);
+
+    let (delta, hash) =
+        file_streaming::delta_against(&origin_dir.path().join("notes.txt"), &signature).unwrap();
+    assert!(delta.len() < new.len() / 10);
+    let operation = ClientMessage::TargetedOperation {
+        folder_id: FOLDER.into(),
+        computer_id,
+        operation: FileOperation::ApplyDelta {
+            transfer_id: 1,
+            relative_path: relative("notes.txt"),
+            delta,
+            hash,
+            compression: None,
+        },
+    };
+    ws.send(Message::Text(
+        serde_json::to_string(&operation).unwrap().into(),
+    ))
+    .await
+    .unwrap();
+
+    wait_until("patched file", async || {
+        read(backup_dir.path().join("notes.txt")).as_deref() == Some(new.as_str())
+    })
+    .await;
+
+    shutdown_tx.send(true).unwrap();
+    backup.await.unwrap().unwrap();
+}
+
+/// A hand-driven origin asks for the backup's manifest and sends that backup only the
+/// operations it needs: one stale, one missing and one extra file.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_converges_backup_from_its_manifest() {
+    let (addr, state, _shutdown) = start_server("127.0.0.1:0").await;
+    let origin_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    fs::write(origin_dir.path().join("same.txt"), "same").unwrap();
+    fs::write(backup_dir.path().join("same.txt"), "same").unwrap();
+    fs::write(origin_dir.path().join("stale.txt"), "new").unwrap();
+    fs::write(backup_dir.path().join("stale.txt"), "old").unwrap();
+    fs::write(origin_dir.path().join("missing.txt"), "missing").unwrap();
+    fs::write(backup_dir.path().join("extra.txt"), "extra").unwrap();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let backup = spawn(
+        options(addr, BACKUP, Role::Backup, backup_dir.path()),
+        &shutdown_rx,
+    );
+    wait_until("backup online", async || is_online(&state, BACKUP).await).await;
+
+    let mut ws = connect_raw_origin(addr).await;
+    let request = ClientMessage::RequestManifest {
+        folder_id: FOLDER.into(),
+    };
+    ws.send(Message::Text(
+        serde_json::to_string(&request).unwrap().into(),
+    ))
+    .await
+    .unwrap();
+    let (computer_id, manifest) = match recv_raw(&mut ws).await {
+        ServerMessage::Manifest {
+            computer_id,
+            manifest,
+            ..
+        } => (computer_id, manifest),
+        message => panic!("Expected Manifest, got {message:?}"),
+    };
+    assert_eq!(computer_id, BACKUP);
+    assert_eq!(manifest.entries.len(), 3);
+
+    let rules = IgnoreRules::load(origin_dir.path(), &[]);
+    let local = build_manifest(origin_dir.path(), &rules).unwrap();
+    let operations = corrective_operations(origin_dir.path(), &rules, &local, &manifest).unwrap();
+    assert_eq!(operations.len(), 3, "{operations:?}");
+    for operation in operations {
+        let message = ClientMessage::TargetedOperation {
+            folder_id: FOLDER.into(),
+            computer_id: computer_id.clone(),
+            operation,
+        };
+        ws.send(Message::Text(
+            serde_json::to_string(&message).unwrap().into(),
+        ))
+        .await
+        .unwrap();
+    }
+
+    wait_until("converged backup", async || {
+        read(backup_dir.path().join("stale.txt")).as_deref() == Some("new")
+            && read(backup_dir.path().join("missing.txt")).as_deref() == Some("missing")
+            && !backup_dir.path().join("extra.txt").exists()
+    })
+    .await;
+    assert_eq!(
+        read(backup_dir.path().join("same.txt")).as_deref(),
+        Some("same")
+    );
+
+    shutdown_tx.send(true).unwrap();
+    backup.await.unwrap().unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_full_sync_catches_up_backup_that_was_away() {
+    let (addr, state, _shutdown) = start_server("127.0.0.1:0").await;
+    let origin_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    fs::write(origin_dir.path().join("a.txt"), "a").unwrap();
+    fs::write(origin_dir.path().join("b.txt"), "b").unwrap();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    // Published while the backup is away
+    let origin = spawn(
+        options(addr, ORIGIN, Role::Origin, origin_dir.path()),
+        &shutdown_rx,
+    );
+    wait_until("snapshot published", async || {
+        state.read().await.folder_sequence(&FOLDER.into()) > 0
+    })
+    .await;
+
+    let backup = spawn(
+        options(addr, BACKUP, Role::Backup, backup_dir.path()),
+        &shutdown_rx,
+    );
+    wait_until("full sync", async || {
+        read(backup_dir.path().join("a.txt")).as_deref() == Some("a")
+            && read(backup_dir.path().join("b.txt")).as_deref() == Some("b")
+    })
+    .await;
+
+    shutdown_tx.send(true).unwrap();
+    origin.await.unwrap().unwrap();
+    backup.await.unwrap().unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_origin_holds_changes_back_until_backup_catches_up() {
+    let (addr, state, _shutdown) = start_server_with(ServerConfig {
+        addrs: vec!["127.0.0.1:0".to_string()],
+        max_pending_operations: Some(3),
+        ..ServerConfig::default()
+    })
+    .await;
+    let origin_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let origin = spawn(
+        options(addr, ORIGIN, Role::Origin, origin_dir.path()),
+        &shutdown_rx,
+    );
+    wait_until("origin online", async || is_online(&state, ORIGIN).await).await;
+
+    // Published one at a time while the backup is away, until the server holds back
+    // what follows the third operation
+    let names = ["1.txt", "2.txt", "3.txt", "4.txt", "5.txt"];
+    for (sequence, name) in (1..=3).zip(names) {
+        fs::write(origin_dir.path().join(name), name).unwrap();
+        wait_until("operation published", async || {
+            state.read().await.folder_sequence(&FOLDER.into()) >= sequence
+        })
+        .await;
+    }
+    for name in &names[3..] {
+        fs::write(origin_dir.path().join(name), name).unwrap();
+    }
+    wait_until("changes held back", async || {
+        state
+            .read()
+            .await
+            .metrics
+            .values()
+            .errors
+            .contains_key("Backpressure")
+    })
+    .await;
+    assert_eq!(state.read().await.pending_count(&FOLDER.into()), 3);
+
+    let backup = spawn(
+        options(addr, BACKUP, Role::Backup, backup_dir.path()),
+        &shutdown_rx,
+    );
+    wait_until("held back changes", async || {
+        names
+            .iter()
+            .all(|name| read(backup_dir.path().join(name)).as_deref() == Some(*name))
+    })
+    .await;
+
+    shutdown_tx.send(true).unwrap();
+    origin.await.unwrap().unwrap();
+    backup.await.unwrap().unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_mirrors_with_postcard_encoding() {
+    let (addr, state, _shutdown) = start_server("127.0.0.1:0").await;
+    let origin_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    fs::write(origin_dir.path().join("binary.bin"), [0, 159, 255]).unwrap();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let backup = spawn(
+        options(addr, BACKUP, Role::Backup, backup_dir.path()).with_encoding(Encoding::Postcard),
+        &shutdown_rx,
+    );
+    wait_until("backup online", async || is_online(&state, BACKUP).await).await;
+    let origin = spawn(
+        options(addr, ORIGIN, Role::Origin, origin_dir.path()).with_encoding(Encoding::Postcard),
+        &shutdown_rx,
+    );
+
+    wait_until("binary file", async || {
+        fs::read(backup_dir.path().join("binary.bin")).is_ok_and(|content| content == [0, 159, 255])
+    })
+    .await;
+
+    shutdown_tx.send(true).unwrap();
+    origin.await.unwrap().unwrap();
+    backup.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_connect_rejects_incompatible_server() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let welcome = ServerMessage::Welcome {
+            protocol_version: 0,
+            min_supported: 0,
+        };
+        ws.send(Message::Text(
+            serde_json::to_string(&welcome).unwrap().into(),
+        ))
+        .await
+        .unwrap();
+        // Keep the connection open: the client must give up by itself
+        while ws.next().await.is_some() {}
+    });
+    let backup_dir = TempDir::new().unwrap();
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let err = tokio::time::timeout(
+        Duration::from_secs(5),
+        remote::run(
+            options(addr, BACKUP, Role::Backup, backup_dir.path()),
+            shutdown_rx,
+        ),
+    )
+    .await
+    .expect("client kept waiting on an incompatible server")
+    .unwrap_err();
+
+    assert!(err.to_string().contains("Incompatible server"), "{err}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_pings_keep_idle_connection_open() {
+    let (addr, state, _shutdown) = start_server_with(ServerConfig {
+        addrs: vec!["127.0.0.1:0".to_string()],
+        idle_timeout: Duration::from_millis(400),
+        ..ServerConfig::default()
+    })
+    .await;
+    let backup_dir = TempDir::new().unwrap();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let backup = spawn(
+        options(addr, BACKUP, Role::Backup, backup_dir.path())
+            .with_ping_interval(Duration::from_millis(100)),
+        &shutdown_rx,
+    );
+    wait_until("backup online", async || is_online(&state, BACKUP).await).await;
+    let connection = connection_of(&state, BACKUP).await;
+
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+
+    // Still the first connection: the server never dropped it for being idle
+    assert_eq!(connection_of(&state, BACKUP).await, connection);
+    shutdown_tx.send(true).unwrap();
+    backup.await.unwrap().unwrap();
+}
+
+/// Content corrupted between the origin and the backup is refused by the backup, which
+/// reports it to the origin
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_refuses_tampered_content() {
+    let (addr, state, _shutdown) = start_server("127.0.0.1:0").await;
+    let backup_dir = TempDir::new().unwrap();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let backup = spawn(
+        options(addr, BACKUP, Role::Backup, backup_dir.path()),
+        &shutdown_rx,
+    );
+    wait_until("backup online", async || is_online(&state, BACKUP).await).await;
+    let mut ws = connect_raw_origin(addr).await;
+
+    send_raw(
+        &mut ws,
+        FileOperation::CreateFile {
+            relative_path: relative("tampered.txt"),
+            content: b"tampered in transit".to_vec(),
+            hash: blake3::hash(b"original content").into(),
+            compression: None,
+        },
+    )
+    .await;
+    // Operations are applied in order, so this one landing means the first was handled
+    send_raw(
+        &mut ws,
+        FileOperation::CreateFile {
+            relative_path: relative("intact.txt"),
+            content: b"intact".to_vec(),
+            hash: blake3::hash(b"intact").into(),
+            compression: None,
+        },
+    )
+    .await;
+    wait_until("intact file", async || {
+        read(backup_dir.path().join("intact.txt")).is_some()
+    })
+    .await;
+
+    assert!(!backup_dir.path().join("tampered.txt").exists());
+
+    // The origin hears about the failure from the backup
+    let failure = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let frame = ws.next().await.unwrap().unwrap().into_text().unwrap();
+            if let ServerMessage::OperationFailed {
+                operation_id,
+                computer_id,
+                reason,
+            } = serde_json::from_str(&frame).unwrap()
+            {
+                return (operation_id, computer_id, reason);
+            }
+        }
+    })
+    .await
+    .expect("origin never received OperationFailed");
+    assert_eq!(failure.1, BACKUP);
+    assert!(
+        failure.2.contains("Integrity check failed"),
+        "{}",
+        failure.2
+    );
+    let s = state.read().await;
+    let folder = s.get_folder(&USER.into(), &FOLDER.into()).unwrap();
+    assert!(!folder.is_synced);
+    assert!(folder.backup_status[BACKUP].last_failure.is_some());
+    drop(s);
+
+    shutdown_tx.send(true).unwrap();
+    backup.await.unwrap().unwrap();
+}
@@ -1,9 +1,10 @@
-use backup_sync_client::synchronizer::{SyncOptions, Synchronizer};
+use backup_sync_client::synchronizer::{SyncAction, SyncOptions, Synchronizer};
 use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
 use std::sync::Mutex;
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 
 fn create_file(dir: &std::path::Path, name: &str, content: &str) -> PathBuf {
@@ -209,6 +210,57 @@ fn test_sync_combined_operations() {
     );
 }
 
+#[test]
+fn test_sync_report_counts_operations() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "new_a.txt", "a");
+    create_file(original_dir.path(), "new_b.txt", "b");
+    create_file(original_dir.path(), "both.txt", "original version");
+    create_file(original_dir.path(), "same.txt", "same");
+    create_file(backup_dir.path(), "both.txt", "backup version");
+    create_file(backup_dir.path(), "same.txt", "same");
+    create_file(backup_dir.path(), "only_backup.txt", "backup only");
+
+    let mut syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap();
+
+    let report = syncer.sync().unwrap();
+
+    assert_eq!(report.created, 2);
+    assert_eq!(report.updated, 1);
+    assert_eq!(report.deleted, 1);
+    assert!(!report.has_errors());
+    assert_eq!(
+        report.to_string(),
+        "created: 2, updated: 1, deleted: 1, skipped (locked): 0, errors: 0"
+    );
+}
+
+#[test]
+fn test_sync_removes_nested_extra_directory_without_errors() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(backup_dir.path(), "extra/deeper/file.txt", "content");
+    create_file(backup_dir.path(), "extra/other.txt", "content");
+
+    let mut syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap();
+
+    let report = syncer.sync().unwrap();
+
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    assert!(!backup_dir.path().join("extra").exists());
+}
+
 #[test]
 fn test_handle_original_created_copies_file_to_backup() {
     let original_dir = TempDir::new().unwrap();
@@ -943,6 +995,450 @@ fn test_concurrent_different_file_operations() {
     }
 }
 
+// ==================== PLAN TESTS ====================
+
+#[test]
+fn test_plan_lists_actions_without_applying() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "new.txt", "new");
+    create_file(original_dir.path(), "dir/nested.txt", "nested");
+    create_file(original_dir.path(), "conflict.txt", "original version");
+    create_file(backup_dir.path(), "conflict.txt", "backup version");
+    create_file(backup_dir.path(), "extra.txt", "extra");
+
+    let syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap()
+    .with_options(SyncOptions::default().with_when_conflict_preserve_backup(true));
+
+    let plan = syncer.plan().unwrap();
+
+    assert_eq!(
+        plan,
+        vec![
+            SyncAction::CreateDir {
+                relative: PathBuf::from("dir")
+            },
+            SyncAction::CopyToBackup {
+                relative: PathBuf::from("dir/nested.txt")
+            },
+            SyncAction::CopyToBackup {
+                relative: PathBuf::from("new.txt")
+            },
+            SyncAction::CopyToOriginal {
+                relative: PathBuf::from("conflict.txt")
+            },
+            SyncAction::Remove {
+                relative: PathBuf::from("extra.txt")
+            },
+        ]
+    );
+    assert!(!backup_dir.path().join("new.txt").exists());
+    assert_eq!(
+        read_file_content(&original_dir.path().join("conflict.txt")),
+        "original version"
+    );
+}
+
+// ==================== RESTORE TESTS ====================
+
+#[test]
+fn test_restore_recovers_wiped_original() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "top.txt", "top level");
+    create_file(original_dir.path(), "a/b/c/deep.txt", "deeply nested");
+    create_file(
+        original_dir.path(),
+        "a/sibling.bin",
+        "\u{0}\u{1}binary\u{2}",
+    );
+    fs::create_dir_all(original_dir.path().join("empty/dir")).unwrap();
+
+    let mut syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap();
+    syncer.sync().unwrap();
+
+    for entry in fs::read_dir(original_dir.path()).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            fs::remove_dir_all(path).unwrap();
+        } else {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    let mut syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap();
+    let report = syncer.restore(false).unwrap();
+
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    for name in ["top.txt", "a/b/c/deep.txt", "a/sibling.bin"] {
+        assert_eq!(
+            fs::read(original_dir.path().join(name)).unwrap(),
+            fs::read(backup_dir.path().join(name)).unwrap(),
+            "{name}"
+        );
+    }
+    assert!(original_dir.path().join("empty/dir").is_dir());
+}
+
+#[test]
+fn test_restore_backup_wins_conflict_by_default() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "file.txt", "corrupted");
+    create_file(backup_dir.path(), "file.txt", "good copy");
+
+    let mut syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap();
+    let report = syncer.restore(false).unwrap();
+
+    assert_eq!(report.updated, 1);
+    assert_eq!(
+        read_file_content(&original_dir.path().join("file.txt")),
+        "good copy"
+    );
+}
+
+#[test]
+fn test_restore_prefer_original_keeps_conflicting_file() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "file.txt", "newer work");
+    create_file(backup_dir.path(), "file.txt", "old copy");
+    create_file(backup_dir.path(), "lost.txt", "lost file");
+
+    let mut syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap();
+    let report = syncer.restore(true).unwrap();
+
+    assert_eq!(report.created, 1);
+    assert_eq!(report.updated, 0);
+    assert_eq!(
+        read_file_content(&original_dir.path().join("file.txt")),
+        "newer work"
+    );
+    assert_eq!(
+        read_file_content(&original_dir.path().join("lost.txt")),
+        "lost file"
+    );
+}
+
+#[test]
+fn test_restore_never_touches_backup() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "only_original.txt", "original only");
+    create_file(original_dir.path(), "file.txt", "original version");
+    create_file(backup_dir.path(), "file.txt", "backup version");
+
+    let mut syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap();
+    syncer.restore(false).unwrap();
+
+    assert!(!backup_dir.path().join("only_original.txt").exists());
+    assert!(original_dir.path().join("only_original.txt").exists());
+    assert_eq!(
+        read_file_content(&backup_dir.path().join("file.txt")),
+        "backup version"
+    );
+}
+
+#[test]
+fn test_plan_restore_lists_actions_without_applying() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "file.txt", "original version");
+    create_file(backup_dir.path(), "file.txt", "backup version");
+    create_file(backup_dir.path(), "dir/lost.txt", "lost");
+
+    let syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        syncer.plan_restore(false).unwrap(),
+        vec![
+            SyncAction::CopyToOriginal {
+                relative: PathBuf::from("dir/lost.txt")
+            },
+            SyncAction::CopyToOriginal {
+                relative: PathBuf::from("file.txt")
+            },
+            SyncAction::CreateOriginalDir {
+                relative: PathBuf::from("dir")
+            },
+        ]
+    );
+    assert_eq!(
+        syncer.plan_restore(true).unwrap(),
+        vec![
+            SyncAction::CopyToOriginal {
+                relative: PathBuf::from("dir/lost.txt")
+            },
+            SyncAction::CreateOriginalDir {
+                relative: PathBuf::from("dir")
+            },
+        ]
+    );
+    assert!(!original_dir.path().join("dir").exists());
+}
+
+// ==================== VERIFY TESTS ====================
+
+#[test]
+fn test_verify_clean_after_sync() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "file.txt", "content");
+    create_file(original_dir.path(), "nested/deep.txt", "deep");
+
+    let mut syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap();
+    syncer.sync().unwrap();
+
+    let report = syncer.verify().unwrap();
+
+    assert!(report.is_clean(), "{report:?}");
+}
+
+#[test]
+fn test_verify_reports_differences_without_modifying() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    // Same size, different content: only a content hash tells them apart
+    create_file(original_dir.path(), "corrupted.txt", "abcdef");
+    create_file(backup_dir.path(), "corrupted.txt", "abcxef");
+    create_file(original_dir.path(), "missing.txt", "missing");
+    create_file(backup_dir.path(), "extra.txt", "extra");
+
+    let syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap();
+
+    let report = syncer.verify().unwrap();
+
+    assert_eq!(report.mismatched, vec![PathBuf::from("corrupted.txt")]);
+    assert_eq!(report.missing, vec![PathBuf::from("missing.txt")]);
+    assert_eq!(report.extra, vec![PathBuf::from("extra.txt")]);
+    assert_eq!(
+        read_file_content(&backup_dir.path().join("corrupted.txt")),
+        "abcxef"
+    );
+    assert!(!backup_dir.path().join("missing.txt").exists());
+    assert!(backup_dir.path().join("extra.txt").exists());
+}
+
+#[test]
+fn test_verify_reports_unreadable_files_and_continues() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "gone.txt", "gone");
+    create_file(backup_dir.path(), "gone.txt", "gone");
+    create_file(original_dir.path(), "corrupted.txt", "abcdef");
+    create_file(backup_dir.path(), "corrupted.txt", "abcxef");
+
+    let syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap();
+    // Removed after the scan, so hashing it fails
+    fs::remove_file(original_dir.path().join("gone.txt")).unwrap();
+
+    let report = syncer.verify().unwrap();
+
+    assert_eq!(report.unreadable, vec![PathBuf::from("gone.txt")]);
+    assert_eq!(report.mismatched, vec![PathBuf::from("corrupted.txt")]);
+    assert!(!report.is_clean());
+}
+
+// ==================== IGNORE RULES TESTS ====================
+
+#[test]
+fn test_sync_honors_ignore_file_with_negation() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(
+        original_dir.path(),
+        ".backupsyncignore",
+        "*.log\n!important.log\n",
+    );
+    create_file(original_dir.path(), "debug.log", "noise");
+    create_file(original_dir.path(), "important.log", "keep");
+    create_file(original_dir.path(), "file.txt", "content");
+
+    let mut syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap();
+    syncer.sync().unwrap();
+
+    assert!(!backup_dir.path().join("debug.log").exists());
+    assert!(backup_dir.path().join("important.log").exists());
+    assert!(backup_dir.path().join("file.txt").exists());
+    assert!(backup_dir.path().join(".backupsyncignore").exists());
+}
+
+#[test]
+fn test_sync_honors_directory_rules() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), ".backupsyncignore", "target/\n");
+    create_file(original_dir.path(), "target/debug/app", "binary");
+    create_file(original_dir.path(), "src/target.rs", "source");
+
+    let mut syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap();
+    syncer.sync().unwrap();
+
+    assert!(!backup_dir.path().join("target").exists());
+    assert!(backup_dir.path().join("src/target.rs").exists());
+}
+
+#[test]
+fn test_sync_merges_ignore_file_with_programmatic_excludes() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), ".backupsyncignore", "!keep.bak\n");
+    create_file(original_dir.path(), "drop.bak", "drop");
+    create_file(original_dir.path(), "keep.bak", "keep");
+
+    let mut syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap()
+    .with_options(SyncOptions::default().with_excludes(vec!["*.bak".to_string()]));
+    syncer.sync().unwrap();
+
+    assert!(!backup_dir.path().join("drop.bak").exists());
+    assert!(backup_dir.path().join("keep.bak").exists());
+}
+
+#[test]
+fn test_sync_never_deletes_ignored_backup_copies() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), ".backupsyncignore", "*.log\n");
+    create_file(backup_dir.path(), "old.log", "already backed up");
+
+    let mut syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap();
+    syncer.sync().unwrap();
+
+    assert_eq!(
+        read_file_content(&backup_dir.path().join("old.log")),
+        "already backed up"
+    );
+}
+
+// ==================== LOCKING TESTS ====================
+
+#[test]
+fn test_sync_skips_file_locked_by_another_process() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "locked.txt", "new content");
+    create_file(original_dir.path(), "free.txt", "free content");
+    let locked_backup = create_file(backup_dir.path(), "locked.txt", "old content");
+
+    let holder = File::open(&locked_backup).unwrap();
+    fs2::FileExt::lock_exclusive(&holder).unwrap();
+
+    let mut syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap()
+    .with_options(SyncOptions::default().with_lock_timeout(Duration::from_millis(50)));
+
+    let report = syncer.sync().unwrap();
+
+    assert_eq!(report.skipped_locked, vec![PathBuf::from("locked.txt")]);
+    assert_eq!(read_file_content(&locked_backup), "old content");
+    assert_eq!(
+        read_file_content(&backup_dir.path().join("free.txt")),
+        "free content"
+    );
+}
+
+#[test]
+fn test_sync_waits_for_lock_within_timeout() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "file.txt", "new content");
+    let backup_file = create_file(backup_dir.path(), "file.txt", "old content");
+
+    let holder = File::open(&backup_file).unwrap();
+    fs2::FileExt::lock_exclusive(&holder).unwrap();
+    let releaser = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        drop(holder);
+    });
+
+    let mut syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap()
+    .with_options(SyncOptions::default().with_lock_timeout(Duration::from_secs(5)));
+
+    let report = syncer.sync().unwrap();
+    releaser.join().unwrap();
+
+    assert!(report.skipped_locked.is_empty());
+    assert_eq!(read_file_content(&backup_file), "new content");
+}
+
 // ==================== ERROR HANDLING TESTS ====================
 
 #[test]
@@ -0,0 +1,125 @@
+use backup_sync_client::state::AppState;
+use backup_sync_client::synchronizer::{SyncOptions, Synchronizer};
+use notify::EventKind;
+use notify::event::{ModifyKind, RenameMode};
+use notify_debouncer_full::DebouncedEvent;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Instant;
+use tempfile::TempDir;
+use tracing_test::traced_test;
+
+fn create_file(dir: &std::path::Path, name: &str, content: &str) -> PathBuf {
+    let path = dir.join(name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).unwrap();
+    }
+    let mut file = File::create(&path).unwrap();
+    file.write_all(content.as_bytes()).unwrap();
+    path
+}
+
+#[test]
+#[traced_test]
+fn test_sync_phases_emit_spans() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "file.txt", "content");
+    create_file(backup_dir.path(), "extra.txt", "extra");
+
+    let mut syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap();
+    syncer.sync().unwrap();
+
+    assert!(logs_contain("sync{"));
+    assert!(logs_contain("phase=\"missing\" count=1"));
+    assert!(logs_contain("phase=\"extra\" count=1"));
+    assert!(logs_contain("phase=\"conflicts\" count=0"));
+    assert!(logs_contain("op=\"create\" relative=\"file.txt\" bytes=7"));
+    assert!(logs_contain("outcome=\"copied\""));
+}
+
+#[test]
+#[traced_test]
+fn test_handle_original_deleted_emits_span() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "file.txt", "content");
+
+    let mut syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap()
+    .with_options(SyncOptions::default().with_when_delete_keep_backup(true));
+    syncer.sync().unwrap();
+
+    let original_path = fs::canonicalize(original_dir.path().join("file.txt")).unwrap();
+    syncer.handle_original_deleted(&original_path).unwrap();
+
+    assert!(logs_contain("op=\"delete\" relative=\"file.txt\""));
+    assert!(logs_contain("outcome=\"kept\""));
+}
+
+#[test]
+#[traced_test]
+fn test_failed_operation_emits_warning() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    let mut syncer = Synchronizer::new(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+    )
+    .unwrap();
+
+    let missing = fs::canonicalize(original_dir.path())
+        .unwrap()
+        .join("missing.txt");
+    assert!(syncer.handle_original_created(missing).is_err());
+
+    assert!(logs_contain("WARN"));
+    assert!(logs_contain("op=\"create\" relative=\"missing.txt\""));
+}
+
+#[test]
+#[traced_test]
+fn test_debounced_event_span_carries_kind() {
+    let original_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+
+    create_file(original_dir.path(), "old.txt", "content");
+
+    let state = AppState::new_with_local_sync(
+        original_dir.path().to_path_buf(),
+        backup_dir.path().to_path_buf(),
+        SyncOptions::default().with_state_dir(std::env::temp_dir().join("backup-sync-tests")),
+    )
+    .unwrap();
+
+    let root = fs::canonicalize(original_dir.path()).unwrap();
+    fs::rename(root.join("old.txt"), root.join("new.txt")).unwrap();
+    let event = DebouncedEvent {
+        event: notify::Event {
+            kind: EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+            paths: vec![root.join("old.txt"), root.join("new.txt")],
+            attrs: Default::default(),
+        },
+        time: Instant::now(),
+    };
+    state.process_debounced_event(&event).unwrap();
+
+    assert!(logs_contain(
+        "process_debounced_event{kind=Modify(Name(Both)) paths=2}"
+    ));
+    assert!(logs_contain(
+        "op=\"rename\" from=\"old.txt\" relative=\"new.txt\""
+    ));
+    assert!(logs_contain("outcome=\"renamed\""));
+}
@@ -0,0 +1,198 @@
+use backup_sync_client::transfer::{self, Transfers};
+use backup_sync_protocol::codec;
+use backup_sync_protocol::{FileOperation, MAX_CHUNK_SIZE, RelativePath, TransferKind};
+use std::fs;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+fn relative(path: &str) -> RelativePath {
+    RelativePath::try_from(path).unwrap()
+}
+
+fn content(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i % 251) as u8).collect()
+}
+
+fn apply_all(transfers: &mut Transfers, operations: &[FileOperation]) -> Option<PathBuf> {
+    let mut applied = None;
+    for operation in operations {
+        if let Some(path) = transfers.apply(operation).unwrap() {
+            applied = Some(path);
+        }
+    }
+    applied
+}
+
+#[test]
+fn test_split_covers_content_in_order() {
+    let data = content(10);
+    let operations = transfer::split(7, relative("a.bin"), &data, 4);
+
+    assert_eq!(operations.len(), 5);
+    assert!(matches!(
+        operations[0],
+        FileOperation::StartTransfer {
+            transfer_id: 7,
+            total_size: 10,
+            chunk_size: 4,
+            ..
+        }
+    ));
+    let chunks: Vec<u8> = operations[1..4]
+        .iter()
+        .flat_map(|operation| match operation {
+            FileOperation::FileChunk { data, .. } => data.clone(),
+            other => panic!("Expected FileChunk, got {other:?}"),
+        })
+        .collect();
+    assert_eq!(chunks, data);
+    assert!(matches!(
+        operations[4],
+        FileOperation::EndTransfer { chunk_count: 3, .. }
+    ));
+}
+
+#[test]
+fn test_transfer_reassembles_file() {
+    let root = TempDir::new().unwrap();
+    let data = content(1000);
+    let mut transfers = Transfers::new(root.path().to_path_buf());
+
+    let applied = apply_all(
+        &mut transfers,
+        &transfer::split(1, relative("sub/file.bin"), &data, 64),
+    );
+
+    assert_eq!(applied, Some(PathBuf::from("sub/file.bin")));
+    assert_eq!(fs::read(root.path().join("sub/file.bin")).unwrap(), data);
+    assert_eq!(transfers.pending(), 0);
+}
+
+#[test]
+fn test_transfer_accepts_chunks_out_of_order() {
+    let root = TempDir::new().unwrap();
+    let data = content(1000);
+    let mut operations = transfer::split(1, relative("file.bin"), &data, 64);
+    let end = operations.pop().unwrap();
+    operations[1..].reverse();
+    operations.push(end);
+    let mut transfers = Transfers::new(root.path().to_path_buf());
+
+    apply_all(&mut transfers, &operations);
+
+    assert_eq!(fs::read(root.path().join("file.bin")).unwrap(), data);
+}
+
+#[test]
+fn test_transfer_waits_for_chunks_after_end() {
+    let root = TempDir::new().unwrap();
+    let data = content(200);
+    let operations = transfer::split(1, relative("file.bin"), &data, 64);
+    let mut transfers = Transfers::new(root.path().to_path_buf());
+
+    // Start, the first chunks, then the end before the last chunk
+    let (last_chunk, rest) = operations[..operations.len() - 1].split_last().unwrap();
+    assert_eq!(apply_all(&mut transfers, rest), None);
+    assert_eq!(
+        transfers.apply(operations.last().unwrap()).unwrap(),
+        None,
+        "applied before the last chunk"
+    );
+    assert!(!root.path().join("file.bin").exists());
+
+    let applied = transfers.apply(last_chunk).unwrap();
+
+    assert_eq!(applied, Some(PathBuf::from("file.bin")));
+    assert_eq!(fs::read(root.path().join("file.bin")).unwrap(), data);
+}
+
+#[test]
+fn test_transfer_rejects_wrong_hash() {
+    let root = TempDir::new().unwrap();
+    let mut operations = transfer::split(1, relative("file.bin"), &content(200), 64);
+    if let Some(FileOperation::EndTransfer { expected_hash, .. }) = operations.last_mut() {
+        *expected_hash = blake3::hash(b"something else").to_hex().to_string();
+    }
+    let mut transfers = Transfers::new(root.path().to_path_buf());
+    let (end, rest) = operations.split_last().unwrap();
+    apply_all(&mut transfers, rest);
+
+    let err = transfers.apply(end).unwrap_err();
+
+    assert!(err.to_string().contains("Integrity check failed"), "{err}");
+    assert!(!root.path().join("file.bin").exists());
+    assert_eq!(transfers.pending(), 0);
+}
+
+#[test]
+fn test_aborted_transfer_is_dropped() {
+    let root = TempDir::new().unwrap();
+    let operations = transfer::split(1, relative("file.bin"), &content(200), 64);
+    let mut transfers = Transfers::new(root.path().to_path_buf());
+    apply_all(&mut transfers, &operations[..2]);
+
+    transfers
+        .apply(&FileOperation::AbortTransfer {
+            transfer_id: 1,
+            reason: "file removed".to_string(),
+        })
+        .unwrap();
+
+    assert_eq!(transfers.pending(), 0);
+    assert!(transfers.apply(&operations[2]).is_err());
+    assert_eq!(fs::read_dir(root.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn test_transfer_with_too_large_chunks_is_refused() {
+    let root = TempDir::new().unwrap();
+    let mut transfers = Transfers::new(root.path().to_path_buf());
+
+    let err = transfers
+        .apply(&FileOperation::StartTransfer {
+            transfer_id: 1,
+            relative_path: relative("file.bin"),
+            kind: TransferKind::Content,
+            total_size: 10,
+            chunk_size: MAX_CHUNK_SIZE + 1,
+        })
+        .unwrap_err();
+
+    assert!(err.to_string().contains("Invalid chunk size"), "{err}");
+    assert_eq!(transfers.pending(), 0);
+}
+
+#[test]
+fn test_delta_chunk_past_its_bound_is_refused() {
+    let root = TempDir::new().unwrap();
+    let mut transfers = Transfers::new(root.path().to_path_buf());
+    transfers
+        .apply(&FileOperation::StartTransfer {
+            transfer_id: 1,
+            relative_path: relative("file.bin"),
+            kind: TransferKind::Delta,
+            total_size: 10,
+            chunk_size: 64,
+        })
+        .unwrap();
+
+    let err = transfers
+        .apply(&FileOperation::FileChunk {
+            transfer_id: 1,
+            chunk_index: u64::MAX / 64,
+            data: vec![0; 64],
+            compression: None,
+        })
+        .unwrap_err();
+
+    assert!(err.to_string().contains("past its size"), "{err}");
+}
+
+#[test]
+fn test_transfer_with_path_outside_folder_does_not_decode() {
+    let json = r#"{"StartTransfer":{"transfer_id":1,"relative_path":"../escaped.bin","kind":"Content","total_size":10,"chunk_size":4}}"#;
+
+    let err = codec::decode_text::<FileOperation>(json).unwrap_err();
+
+    assert!(err.invalid_path().is_some(), "{err}");
+}
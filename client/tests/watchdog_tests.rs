@@ -0,0 +1,79 @@
+use backup_sync_client::watchdog::Heartbeat;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn test_heartbeat_requires_recent_healthy_beat() {
+    let heartbeat = Heartbeat::new();
+    assert!(!heartbeat.is_alive(Duration::from_secs(1)));
+
+    heartbeat.beat(true);
+    assert!(heartbeat.is_alive(Duration::from_secs(1)));
+
+    heartbeat.beat(false);
+    assert!(!heartbeat.is_alive(Duration::from_secs(1)));
+
+    heartbeat.beat(true);
+    thread::sleep(Duration::from_millis(50));
+    assert!(!heartbeat.is_alive(Duration::from_millis(10)));
+}
+
+#[cfg(not(feature = "systemd"))]
+#[test]
+fn test_notifications_are_noops_without_feature() {
+    backup_sync_client::watchdog::notify_ready().unwrap();
+    assert!(backup_sync_client::watchdog::watchdog_timeout().is_none());
+}
+
+/// Both checks share the process environment, so they run in a single test.
+#[cfg(all(feature = "systemd", unix))]
+#[test]
+fn test_readiness_and_watchdog_pings_reach_notify_socket() {
+    use backup_sync_client::watchdog;
+    use std::os::unix::net::UnixDatagram;
+    use std::sync::Arc;
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("notify.sock");
+    let socket = UnixDatagram::bind(&path).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    // SAFETY: no other test in this binary reads or writes these variables
+    unsafe {
+        std::env::set_var("NOTIFY_SOCKET", &path);
+        std::env::set_var("WATCHDOG_USEC", "200000");
+        std::env::remove_var("WATCHDOG_PID");
+    }
+    let mut buffer = [0; 64];
+
+    watchdog::notify_ready().unwrap();
+    let len = socket.recv(&mut buffer).unwrap();
+    assert_eq!(
+        std::str::from_utf8(&buffer[..len]).unwrap().trim(),
+        "READY=1"
+    );
+
+    let heartbeat = Arc::new(watchdog::Heartbeat::new());
+    heartbeat.beat(true);
+    let _thread = watchdog::spawn_watchdog(Arc::clone(&heartbeat)).unwrap();
+    let len = socket.recv(&mut buffer).unwrap();
+    assert_eq!(
+        std::str::from_utf8(&buffer[..len]).unwrap().trim(),
+        "WATCHDOG=1"
+    );
+
+    // A loop that stopped beating no longer keeps the unit alive
+    heartbeat.beat(false);
+    thread::sleep(Duration::from_millis(150));
+    socket.set_nonblocking(true).unwrap();
+    while socket.recv(&mut buffer).is_ok() {}
+    socket.set_nonblocking(false).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    assert!(
+        socket.recv(&mut buffer).is_err(),
+        "watchdog pinged while unhealthy"
+    );
+}
@@ -0,0 +1,88 @@
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::relative_path::INVALID_PATH;
+
+/// How messages are encoded on the wire. Connections start with JSON text frames and
+/// may switch with `ClientMessage::Hello`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// JSON in text frames
+    #[default]
+    Json,
+    /// Postcard in binary frames, without the overhead of JSON byte arrays
+    Postcard,
+}
+
+/// An encoded message, mapped to the websocket frame of the same kind
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Json(serde_json::Error),
+    Postcard(postcard::Error),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json(e) => write!(f, "invalid JSON message: {e}"),
+            Self::Postcard(e) => write!(f, "invalid postcard message: {e}"),
+        }
+    }
+}
+
+impl CodecError {
+    /// Why the message was refused, when it was for an invalid [`RelativePath`]. Only
+    /// JSON errors tell; postcard does not keep the reason of such errors.
+    ///
+    /// [`RelativePath`]: crate::RelativePath
+    #[must_use]
+    pub fn invalid_path(&self) -> Option<String> {
+        match self {
+            Self::Json(e) => {
+                let message = e.to_string();
+                message.contains(INVALID_PATH).then_some(message)
+            }
+            Self::Postcard(_) => None,
+        }
+    }
+}
+
+impl std::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Json(e) => Some(e),
+            Self::Postcard(e) => Some(e),
+        }
+    }
+}
+
+/// Encodes `message` into the frame kind used by `encoding`.
+pub fn encode<T: Serialize>(message: &T, encoding: Encoding) -> Result<Frame, CodecError> {
+    match encoding {
+        Encoding::Json => serde_json::to_string(message)
+            .map(Frame::Text)
+            .map_err(CodecError::Json),
+        Encoding::Postcard => postcard::to_stdvec(message)
+            .map(Frame::Binary)
+            .map_err(CodecError::Postcard),
+    }
+}
+
+/// Decodes a text frame, always JSON.
+pub fn decode_text<T: DeserializeOwned>(text: &str) -> Result<T, CodecError> {
+    serde_json::from_str(text).map_err(CodecError::Json)
+}
+
+/// Decodes a binary frame, always postcard.
+pub fn decode_binary<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+    postcard::from_bytes(bytes).map_err(CodecError::Postcard)
+}
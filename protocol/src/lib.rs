@@ -1,20 +1,39 @@
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
 
-pub type UserId = String;
-pub type ComputerId = String;
-pub type FolderId = String;
+pub mod codec;
+mod ids;
+mod relative_path;
+
+pub use codec::Encoding;
+pub use ids::{ComputerId, FolderId, UserId};
+pub use relative_path::{MAX_COMPONENT_LEN, MAX_PATH_LEN, RelativePath, RelativePathError};
+
+/// Version of the messages defined in this crate, bumped on incompatible changes
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest version of the peer this crate can still talk to
+pub const MIN_SUPPORTED_VERSION: u32 = 1;
+
+/// Largest `chunk_size` a transfer may declare, as receivers decompress each chunk into
+/// up to that many bytes
+pub const MAX_CHUNK_SIZE: u64 = 4 << 20;
 
 /// A computer registered by a user
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct Computer {
     pub id: ComputerId,
     pub name: String,
     pub online: bool,
+    /// When the computer last disconnected, in seconds since the Unix epoch
+    #[serde(default)]
+    pub last_seen: Option<i64>,
 }
 
 /// A sync folder with an origin and multiple backups
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct SyncFolder {
     pub id: FolderId,
     pub name: String,
@@ -26,10 +45,44 @@ pub struct SyncFolder {
     pub is_synced: bool,
     /// Number of pending operations waiting to be applied
     pub pending_operations: u64,
+    /// Progress of each backup computer, missing for backups that never received an
+    /// operation
+    #[serde(default)]
+    pub backup_status: BTreeMap<ComputerId, BackupStatus>,
 }
 
-/// User with their computers and sync folders
+/// How far one backup computer is behind the origin
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct BackupStatus {
+    /// Last operation the backup acknowledged
+    pub last_acked_operation: Option<u64>,
+    /// When the backup last acknowledged an operation, in seconds since the Unix epoch
+    pub last_seen: Option<i64>,
+    /// Operations sent to the backup and not acknowledged yet
+    pub pending_operations: u64,
+    /// Why the backup failed to apply the last operation it could not apply
+    #[serde(default)]
+    pub last_failure: Option<String>,
+}
+
+/// A sync folder as seen by one computer, without the membership of the others
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncFolderSummary {
+    pub id: FolderId,
+    pub name: String,
+    pub origin_computer: ComputerId,
+    /// Whether the requesting computer is the origin
+    pub is_origin: bool,
+    /// Whether the requesting computer has a backup copy
+    pub is_backup: bool,
+    pub is_synced: bool,
+    pub pending_operations: u64,
+}
+
+/// User with their computers and sync folders
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct User {
     pub id: UserId,
     pub name: String,
@@ -37,73 +90,228 @@ pub struct User {
     pub sync_folders: Vec<SyncFolder>,
 }
 
+/// A regular file of a folder, as listed in a [`Manifest`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub relative_path: RelativePath,
+    pub size: u64,
+    /// Modification time in seconds since the Unix epoch
+    pub mtime: Option<i64>,
+    /// Blake3 hash of the content
+    pub hash: [u8; 32],
+}
+
+/// The regular files of one computer's copy of a folder, compared by the origin with its
+/// own to find what a backup is missing
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Sorted by path
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// What the chunks of a transfer carry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferKind {
+    /// The whole content of the file
+    Content,
+    /// An rsync delta against the backup's current copy of the file
+    Delta,
+}
+
+/// How the content carried by an operation is compressed. Hashes are always of the
+/// uncompressed content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    /// Zstandard, compressed at `level`
+    Zstd { level: i32 },
+}
+
+/// A change to a sync folder. Its paths are [`RelativePath`]s, so operations that could
+/// reach outside of the folder fail to decode.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum FileOperation {
     /// Create a new file with content
     CreateFile {
-        relative_path: PathBuf,
+        relative_path: RelativePath,
         content: Vec<u8>,
+        /// Blake3 hash of the uncompressed `content`, checked by the backup before
+        /// writing it
+        hash: [u8; 32],
+        #[serde(default)]
+        compression: Option<Compression>,
     },
     /// Create a directory
-    CreateDir { relative_path: PathBuf },
+    CreateDir { relative_path: RelativePath },
     /// Delete a file
-    RemoveFile { relative_path: PathBuf },
+    RemoveFile { relative_path: RelativePath },
     /// Delete a directory recursively
-    RemoveDir { relative_path: PathBuf },
+    RemoveDir { relative_path: RelativePath },
     /// Rename/move a file
     RenameFile {
-        from_relative: PathBuf,
-        to_relative: PathBuf,
+        from_relative: RelativePath,
+        to_relative: RelativePath,
     },
-    /// Start a large file transfer (Chunked upload)
+    /// Create a symbolic link. The target is stored as-is and may dangle, but servers
+    /// refuse targets that are absolute or go up out of the folder. The link is removed
+    /// like a file, with `RemoveFile`.
+    CreateSymlink {
+        relative_path: RelativePath,
+        target: String,
+    },
+    /// Update permissions and modification time. `None` fields are left unchanged.
+    SetMetadata {
+        relative_path: RelativePath,
+        /// Unix permission bits, ignored on other platforms
+        mode: Option<u32>,
+        readonly: Option<bool>,
+        /// Modification time in seconds since the Unix epoch
+        mtime: Option<i64>,
+    },
+    /// Start a transfer split over several `FileChunk`s, for content too large for
+    /// a single message
     StartTransfer {
         transfer_id: u64,
-        relative_path: PathBuf,
+        relative_path: RelativePath,
+        kind: TransferKind,
+        /// Size of the file once the transfer is applied
         total_size: u64,
+        /// Size of every chunk but the last one
+        chunk_size: u64,
     },
-    /// A chunk of data to modify a file (rsync-style)
+    /// A piece of a transfer, starting at `chunk_index * chunk_size`. Chunks may
+    /// arrive in any order.
     FileChunk {
         transfer_id: u64,
         chunk_index: u64,
         data: Vec<u8>, // Keep this under ~64KB
+        /// Compression of `data` alone; `chunk_size` is the size of uncompressed chunks
+        #[serde(default)]
+        compression: Option<Compression>,
     },
-    /// Sent when the delta generation is done.
-    /// The Backup accumulates all chunks, then applies the Delta logic using this info.
+    /// Sent after the last chunk. The backup applies the transfer once all
+    /// `chunk_count` chunks arrived, even if some of them arrive after this message.
     EndTransfer {
         transfer_id: u64,
+        chunk_count: u64,
         expected_hash: String, // The Authoritative Hash calculated by Origin
     },
+    /// Drop a transfer without applying it
+    AbortTransfer { transfer_id: u64, reason: String },
     /// Apply delta (Modified to include integrity check)
     ApplyDelta {
         transfer_id: u64,
-        relative_path: PathBuf,
+        relative_path: RelativePath,
         delta: Vec<u8>,
-        expected_hash: String, // Hash of the file AFTER patch is applied
+        /// Blake3 hash of the file after the patch is applied
+        hash: [u8; 32],
+        #[serde(default)]
+        compression: Option<Compression>,
     },
-    /// Request signature for a file (for delta calculation)
-    RequestSignature { relative_path: PathBuf },
-    /// Response with file signature
+    /// Request signature for a file (for delta calculation). Sent between computers
+    /// with `ClientMessage::RequestSignature`, which carries the folder.
+    RequestSignature { relative_path: RelativePath },
+    /// Response with file signature, sent with `ClientMessage::SignatureResponse`
     SignatureResponse {
-        relative_path: PathBuf,
+        relative_path: RelativePath,
         signature: Vec<u8>,
     },
 }
 
+impl FileOperation {
+    /// Bytes of file data the operation carries, compressed or not: content, chunk,
+    /// delta or signature
+    #[must_use]
+    pub fn payload_len(&self) -> usize {
+        match self {
+            Self::CreateFile { content, .. } => content.len(),
+            Self::FileChunk { data, .. } => data.len(),
+            Self::ApplyDelta { delta, .. } => delta.len(),
+            Self::SignatureResponse { signature, .. } => signature.len(),
+            _ => 0,
+        }
+    }
+
+    /// Size of the chunks a `StartTransfer` declares, 0 for other operations
+    #[must_use]
+    pub fn chunk_size(&self) -> u64 {
+        match self {
+            Self::StartTransfer { chunk_size, .. } => *chunk_size,
+            _ => 0,
+        }
+    }
+
+    /// Paths of the folder the operation changes or reads, none for the operations
+    /// continuing a transfer
+    #[must_use]
+    pub fn relative_paths(&self) -> Vec<&RelativePath> {
+        match self {
+            Self::CreateFile { relative_path, .. }
+            | Self::CreateDir { relative_path }
+            | Self::RemoveFile { relative_path }
+            | Self::RemoveDir { relative_path }
+            | Self::CreateSymlink { relative_path, .. }
+            | Self::SetMetadata { relative_path, .. }
+            | Self::StartTransfer { relative_path, .. }
+            | Self::ApplyDelta { relative_path, .. }
+            | Self::RequestSignature { relative_path }
+            | Self::SignatureResponse { relative_path, .. } => vec![relative_path],
+            Self::RenameFile {
+                from_relative,
+                to_relative,
+            } => vec![from_relative, to_relative],
+            Self::FileChunk { .. } | Self::EndTransfer { .. } | Self::AbortTransfer { .. } => {
+                Vec::new()
+            }
+        }
+    }
+
+    /// Whether the operation belongs to a transfer started by an earlier `StartTransfer`
+    #[must_use]
+    pub fn continues_transfer(&self) -> bool {
+        matches!(
+            self,
+            Self::FileChunk { .. } | Self::EndTransfer { .. } | Self::AbortTransfer { .. }
+        )
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ClientMessage {
+    /// Ask for another encoding for the rest of the connection. Servers that do not
+    /// know this message answer with an `Error`, and the connection stays on JSON.
+    Hello { encoding: Encoding },
     /// Authenticate as a user on a specific computer
     Authenticate {
         user_id: UserId,
         computer_id: ComputerId,
+        /// `PROTOCOL_VERSION` of the client
+        protocol_version: u32,
+        /// Token issued by the HTTP server on login, for `user_id`
+        #[serde(default)]
+        token: String,
+        /// Last sequence applied of the folders this computer backs up, whose missed
+        /// operations the server replays right after `Authenticated`, each followed by
+        /// a `FolderSequence` as for `CatchUp`
+        #[serde(default)]
+        last_applied: Option<BTreeMap<FolderId, u64>>,
     },
     /// Register a new computer for this user
     RegisterComputer { name: String },
+    /// Remove a computer of this user, possibly this one, from the user and its folders
+    RemoveComputer { computer_id: ComputerId },
     /// Create a new sync folder with this computer as origin
     CreateSyncFolder { name: String },
     /// Add this computer as a backup for a sync folder
     JoinSyncFolder { folder_id: FolderId },
     /// Leave a sync folder (remove this computer from backups)
     LeaveSyncFolder { folder_id: FolderId },
+    /// Delete a sync folder (origin only, once every operation is acknowledged)
+    DeleteSyncFolder { folder_id: FolderId },
+    /// Rename a sync folder (origin or backups)
+    RenameSyncFolder {
+        folder_id: FolderId,
+        new_name: String,
+    },
     /// Request to become the new origin (only allowed when folder is synced)
     RequestOriginSwitch { folder_id: FolderId },
     /// File operation for a specific folder
@@ -111,28 +319,113 @@ pub enum ClientMessage {
         folder_id: FolderId,
         operation: FileOperation,
     },
+    /// Operations for a folder applied in order and acknowledged as a single operation
+    FolderOperationBatch {
+        folder_id: FolderId,
+        operations: Vec<FileOperation>,
+    },
+    /// File operation for a single backup of a folder, like a delta computed against
+    /// that backup's signature (origin only)
+    TargetedOperation {
+        folder_id: FolderId,
+        computer_id: ComputerId,
+        operation: FileOperation,
+    },
+    /// Ask the backups of a folder for the rsync signature of their copy of a file
+    /// (origin only); each answers with `SignatureResponse`
+    RequestSignature {
+        folder_id: FolderId,
+        relative_path: RelativePath,
+    },
+    /// Signature of this backup's copy of a file, routed to the origin of the folder only
+    SignatureResponse {
+        folder_id: FolderId,
+        relative_path: RelativePath,
+        signature: Vec<u8>,
+    },
+    /// Ask the backups of a folder for the manifest of their copy (origin only); each
+    /// answers with `Manifest`
+    RequestManifest { folder_id: FolderId },
+    /// Manifest of this backup's copy of a folder, routed to the origin of the folder only
+    Manifest {
+        folder_id: FolderId,
+        manifest: Manifest,
+    },
     /// Acknowledge receipt of operation
     Ack { operation_id: u64 },
-    /// Request full sync for a folder
+    /// Report an operation this backup failed to apply
+    Nack { operation_id: u64, reason: String },
+    /// Ask for the sequence number of the last operation of a folder
+    GetFolderSequence { folder_id: FolderId },
+    /// Sent by a backup after authenticating with the sequence of the last operation it
+    /// applied. The server replays the operations it missed, when it still has them all,
+    /// then answers with `FolderSequence`.
+    CatchUp {
+        folder_id: FolderId,
+        last_sequence: u64,
+    },
+    /// Ask the origin of a folder, through the server, for the whole content of the
+    /// folder. Sent by backups that missed operations.
     RequestFullSync { folder_id: FolderId },
+    /// Ask for file content the server stores for a folder, by its hash (origin or
+    /// backups of the folder); answered with `Content`
+    FetchContent { folder_id: FolderId, hash: [u8; 32] },
     /// Get current user state
     GetUserState,
+    /// List the user's folders, without the details of every computer
+    ListFolders,
+    /// Keeps the connection alive through idle timeouts; answered with `Pong`
+    Ping { nonce: u64 },
+    /// Wraps a message so that its response comes back as `ServerMessage::Response` with
+    /// the same id, errors included. Messages without a response, like `Ack`, get none.
+    Request {
+        request_id: u64,
+        message: Box<ClientMessage>,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerMessage {
-    /// Welcome message after connection
-    Welcome,
+    /// Welcome message after connection, with the server's `PROTOCOL_VERSION` and
+    /// `MIN_SUPPORTED_VERSION`
+    Welcome {
+        protocol_version: u32,
+        min_supported: u32,
+    },
+    /// Answer to `Hello`, already in the selected encoding
+    EncodingSelected { encoding: Encoding },
     /// Authentication successful, here's your user state
     Authenticated { user: User },
     /// New computer registered
     ComputerRegistered { computer: Computer },
+    /// A computer of the user connected or disconnected
+    ComputerStatusChanged {
+        computer_id: ComputerId,
+        online: bool,
+    },
+    /// Computer removed; also sent to the user's other computers
+    ComputerRemoved { computer_id: ComputerId },
+    /// Removal denied, with the folders the computer is still the origin of
+    ComputerRemovalDenied {
+        computer_id: ComputerId,
+        reason: String,
+        blocking_folders: Vec<FolderId>,
+    },
     /// Sync folder created
     SyncFolderCreated { folder: SyncFolder },
     /// Joined a sync folder as backup
     JoinedSyncFolder { folder: SyncFolder },
     /// Left a sync folder
     LeftSyncFolder { folder_id: FolderId },
+    /// Sync folder deleted; also sent to its backups, which stop applying operations
+    SyncFolderDeleted { folder_id: FolderId },
+    /// Sync folder renamed; sent to every connected computer of the user
+    SyncFolderRenamed {
+        folder_id: FolderId,
+        new_name: String,
+    },
+    /// Deletion denied (requester not the origin or operations still pending)
+    SyncFolderDeletionDenied { folder_id: FolderId, reason: String },
     /// Origin switched to a new computer
     OriginSwitched {
         folder_id: FolderId,
@@ -144,18 +437,136 @@ pub enum ServerMessage {
     FolderOperation {
         folder_id: FolderId,
         operation_id: u64,
+        /// Position of the operation in the folder, starting at 1 and without gaps.
+        /// Targeted operations reuse the sequence of the last operation of the folder, as
+        /// the other backups never see them. Backups apply operations in this order, and
+        /// hold those received after a gap until a `CatchUp` fills it.
+        sequence: u64,
         operation: FileOperation,
     },
+    /// Forward a batch to backup clients under a single operation id. Backups stop at
+    /// the first operation that fails and only ack batches applied whole.
+    FolderOperationBatch {
+        folder_id: FolderId,
+        operation_id: u64,
+        /// Position of the batch in the folder, shared by all of its operations
+        sequence: u64,
+        operations: Vec<FileOperation>,
+    },
+    /// An origin asked for the signature of a file, sent to the backups of the folder
+    SignatureRequested {
+        folder_id: FolderId,
+        relative_path: RelativePath,
+    },
+    /// Signature of a backup's copy of a file, sent to the origin of the folder only
+    SignatureResponse {
+        folder_id: FolderId,
+        computer_id: ComputerId,
+        relative_path: RelativePath,
+        signature: Vec<u8>,
+    },
+    /// An origin asked for the manifest of a folder, sent to the backups of the folder
+    ManifestRequested { folder_id: FolderId },
+    /// Manifest of a backup's copy of a folder, sent to the origin of the folder only
+    Manifest {
+        folder_id: FolderId,
+        computer_id: ComputerId,
+        manifest: Manifest,
+    },
+    /// A backup asked for the whole content of a folder, sent to the origin of the folder
+    /// only. The origin answers with targeted operations for that backup. While the
+    /// origin is offline, a server storing the content of the folder sends them itself.
+    FullSyncRequested {
+        folder_id: FolderId,
+        computer_id: ComputerId,
+    },
+    /// A full sync cannot start now, sent to the backup that asked for it. It should ask
+    /// again after `retry_after_secs`.
+    FullSyncUnavailable {
+        folder_id: FolderId,
+        reason: String,
+        retry_after_secs: u64,
+    },
+    /// File content the server stores for a folder, answer to `FetchContent`. `hash` is
+    /// the Blake3 hash of `content`.
+    Content {
+        folder_id: FolderId,
+        hash: [u8; 32],
+        content: Vec<u8>,
+    },
+    /// Sequence number of the last operation of a folder, 0 before the first one
+    FolderSequence { folder_id: FolderId, sequence: u64 },
     /// Operation acknowledged by all backups
     OperationComplete { operation_id: u64 },
+    /// A backup failed to apply an operation, sent to the origin of the folder
+    OperationFailed {
+        operation_id: u64,
+        computer_id: ComputerId,
+        reason: String,
+    },
     /// Folder sync status changed
     SyncStatusChanged {
         folder_id: FolderId,
         is_synced: bool,
         pending_operations: u64,
+        /// Status of each backup computer
+        #[serde(default)]
+        backups: BTreeMap<ComputerId, BackupStatus>,
+        /// The backups are so far behind that the server refuses new operations, which
+        /// the origin keeps until they catch up
+        #[serde(default)]
+        backpressure: bool,
     },
     /// Current user state
     UserState { user: User },
-    /// Error message
-    Error { message: String },
+    /// The user's folders, from the point of view of the requesting computer
+    FolderList { folders: Vec<SyncFolderSummary> },
+    /// Answer to `ClientMessage::Ping`, with the same nonce
+    Pong { nonce: u64 },
+    /// A message was refused because one of its paths could reach outside of the folder
+    PathRejected { reason: String },
+    /// `Authenticate` was refused because its token is invalid, expired or issued to
+    /// another user
+    NotAuthenticated { reason: String },
+    /// The connection did not authenticate within `timeout_secs` of being accepted. The
+    /// server closes it right after.
+    AuthTimeout { timeout_secs: u64 },
+    /// A folder operation was refused, and not forwarded, because the connection sent
+    /// too many; it may be sent again after `retry_after_ms`
+    RateLimited { retry_after_ms: u64 },
+    /// A folder operation was refused, and not forwarded, because the backups of the
+    /// folder have `limit` operations to answer already. The origin keeps the changes to
+    /// `paths`, and publishes them again as they are by then once the backups catch up.
+    Backpressure {
+        folder_id: FolderId,
+        paths: Vec<RelativePath>,
+        pending_operations: u64,
+        limit: u64,
+    },
+    /// A message was refused because it is over a size limit of the server. File
+    /// content of `limit` bytes or more is to be sent with `StartTransfer` and
+    /// `FileChunk` instead.
+    PayloadTooLarge {
+        size: u64,
+        limit: u64,
+        reason: String,
+    },
+    /// The computer authenticated again on another connection, which replaces this one.
+    /// The server closes this connection right after.
+    Superseded,
+    /// The server is shutting down and closes the connection right after; clients
+    /// reconnect once it is back
+    Goodbye,
+    /// Error message. `trace_id` is the id the server logged the failed message with,
+    /// for the error to be found in its logs.
+    Error {
+        message: String,
+        #[serde(default)]
+        trace_id: Option<u64>,
+    },
+    /// Response to a `ClientMessage::Request`. Broadcasts are never wrapped.
+    Response {
+        request_id: u64,
+        message: Box<ServerMessage>,
+    },
 }
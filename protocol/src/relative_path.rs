@@ -0,0 +1,189 @@
+use std::fmt;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use unicode_normalization::UnicodeNormalization;
+
+/// Longest path accepted, in bytes
+pub const MAX_PATH_LEN: usize = 4096;
+
+/// Longest name of a single file or directory accepted, in bytes
+pub const MAX_COMPONENT_LEN: usize = 255;
+
+/// Start of the message of every error deserializing a [`RelativePath`], so that
+/// decoders can tell rejected paths from unknown messages
+pub(crate) const INVALID_PATH: &str = "invalid relative path";
+
+/// Why a path cannot be a [`RelativePath`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelativePathError {
+    Empty,
+    NotUtf8,
+    Absolute,
+    ParentDir,
+    Nul,
+    TooLong,
+    ComponentTooLong,
+}
+
+impl fmt::Display for RelativePathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => f.write_str("path is empty"),
+            Self::NotUtf8 => f.write_str("path is not valid UTF-8"),
+            Self::Absolute => f.write_str("path is absolute"),
+            Self::ParentDir => f.write_str("path goes up with `..`"),
+            Self::Nul => f.write_str("path contains a NUL byte"),
+            Self::TooLong => write!(f, "path is longer than {MAX_PATH_LEN} bytes"),
+            Self::ComponentTooLong => {
+                write!(
+                    f,
+                    "a name in the path is longer than {MAX_COMPONENT_LEN} bytes"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for RelativePathError {}
+
+/// A path inside a sync folder, checked to stay inside of it whatever the platform of
+/// the computer applying it: not empty, not absolute, no `..`, no NUL and within the
+/// length limits. Kept in Unicode NFC with `/` separators, and without `.` or empty
+/// components, so that every computer names the same file the same way.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RelativePath(PathBuf);
+
+impl RelativePath {
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, RelativePathError> {
+        let path = path.as_ref().to_str().ok_or(RelativePathError::NotUtf8)?;
+        Self::parse(path)
+    }
+
+    fn parse(path: &str) -> Result<Self, RelativePathError> {
+        if path.contains('\0') {
+            return Err(RelativePathError::Nul);
+        }
+        let path: String = path.nfc().collect();
+        // Backslashes and drive letters are checked too, as they mean something to
+        // Windows backups
+        let bytes = path.as_bytes();
+        if path.starts_with(['/', '\\'])
+            || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':')
+        {
+            return Err(RelativePathError::Absolute);
+        }
+        let mut components = Vec::new();
+        for component in path.split(['/', '\\']) {
+            match component {
+                "" | "." => {}
+                ".." => return Err(RelativePathError::ParentDir),
+                name if name.len() > MAX_COMPONENT_LEN => {
+                    return Err(RelativePathError::ComponentTooLong);
+                }
+                name => components.push(name),
+            }
+        }
+        if components.is_empty() {
+            return Err(RelativePathError::Empty);
+        }
+        let path = components.join("/");
+        if path.len() > MAX_PATH_LEN {
+            return Err(RelativePathError::TooLong);
+        }
+        Ok(Self(PathBuf::from(path)))
+    }
+
+    #[must_use]
+    pub fn as_path(&self) -> &Path {
+        &self.0
+    }
+
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        // Only built from strings
+        self.0.to_str().unwrap_or_default()
+    }
+
+    #[must_use]
+    pub fn into_path_buf(self) -> PathBuf {
+        self.0
+    }
+}
+
+impl Deref for RelativePath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for RelativePath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl TryFrom<&str> for RelativePath {
+    type Error = RelativePathError;
+
+    fn try_from(path: &str) -> Result<Self, Self::Error> {
+        Self::parse(path)
+    }
+}
+
+impl TryFrom<&Path> for RelativePath {
+    type Error = RelativePathError;
+
+    fn try_from(path: &Path) -> Result<Self, Self::Error> {
+        Self::new(path)
+    }
+}
+
+impl TryFrom<PathBuf> for RelativePath {
+    type Error = RelativePathError;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        Self::new(path)
+    }
+}
+
+impl fmt::Display for RelativePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl PartialEq<Path> for RelativePath {
+    fn eq(&self, other: &Path) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&Path> for RelativePath {
+    fn eq(&self, other: &&Path) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialEq<&str> for RelativePath {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == Path::new(other)
+    }
+}
+
+impl Serialize for RelativePath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for RelativePath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let path = String::deserialize(deserializer)?;
+        Self::parse(&path)
+            .map_err(|e| serde::de::Error::custom(format!("{INVALID_PATH} {path:?}: {e}")))
+    }
+}
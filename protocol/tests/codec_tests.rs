@@ -0,0 +1,492 @@
+use backup_sync_protocol::codec::{self, Encoding, Frame};
+use backup_sync_protocol::{
+    BackupStatus, ClientMessage, Compression, Computer, ComputerId, FileOperation,
+    MIN_SUPPORTED_VERSION, Manifest, ManifestEntry, PROTOCOL_VERSION, RelativePath, ServerMessage,
+    SyncFolder, SyncFolderSummary, TransferKind, User,
+};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+
+const ENCODINGS: [Encoding; 2] = [Encoding::Json, Encoding::Postcard];
+
+/// Messages have no `PartialEq`; their debug output covers every field.
+fn assert_round_trip<T: Serialize + DeserializeOwned + Debug>(message: &T) {
+    for encoding in ENCODINGS {
+        let decoded: T = match codec::encode(message, encoding).unwrap() {
+            Frame::Text(text) => {
+                assert_eq!(encoding, Encoding::Json);
+                codec::decode_text(&text).unwrap()
+            }
+            Frame::Binary(bytes) => {
+                assert_eq!(encoding, Encoding::Postcard);
+                codec::decode_binary(&bytes).unwrap()
+            }
+        };
+        assert_eq!(
+            format!("{decoded:?}"),
+            format!("{message:?}"),
+            "{encoding:?}"
+        );
+    }
+}
+
+fn relative(path: &str) -> RelativePath {
+    RelativePath::try_from(path).unwrap()
+}
+
+fn operations() -> Vec<FileOperation> {
+    vec![
+        FileOperation::CreateFile {
+            relative_path: relative("a/b.txt"),
+            content: vec![0, 1, 2, 255],
+            hash: [9; 32],
+            compression: None,
+        },
+        FileOperation::CreateFile {
+            relative_path: relative("a/c.txt"),
+            content: vec![40, 181, 47, 253],
+            hash: [9; 32],
+            compression: Some(Compression::Zstd { level: 3 }),
+        },
+        FileOperation::CreateDir {
+            relative_path: relative("a"),
+        },
+        FileOperation::RemoveFile {
+            relative_path: relative("a/b.txt"),
+        },
+        FileOperation::RemoveDir {
+            relative_path: relative("a"),
+        },
+        FileOperation::RenameFile {
+            from_relative: relative("a.txt"),
+            to_relative: relative("b.txt"),
+        },
+        FileOperation::CreateSymlink {
+            relative_path: relative("link"),
+            target: "../target".to_string(),
+        },
+        FileOperation::SetMetadata {
+            relative_path: relative("a.txt"),
+            mode: Some(0o644),
+            readonly: None,
+            mtime: Some(-1),
+        },
+        FileOperation::StartTransfer {
+            transfer_id: 1,
+            relative_path: relative("big.bin"),
+            kind: TransferKind::Content,
+            total_size: 1 << 40,
+            chunk_size: 65536,
+        },
+        FileOperation::FileChunk {
+            transfer_id: 1,
+            chunk_index: 3,
+            data: vec![7; 100],
+            compression: None,
+        },
+        FileOperation::EndTransfer {
+            transfer_id: 1,
+            chunk_count: 4,
+            expected_hash: "abc".to_string(),
+        },
+        FileOperation::AbortTransfer {
+            transfer_id: 1,
+            reason: "removed".to_string(),
+        },
+        FileOperation::ApplyDelta {
+            transfer_id: 2,
+            relative_path: relative("a.txt"),
+            delta: vec![1, 2, 3],
+            hash: [3; 32],
+            compression: None,
+        },
+        FileOperation::RequestSignature {
+            relative_path: relative("a.txt"),
+        },
+        FileOperation::SignatureResponse {
+            relative_path: relative("a.txt"),
+            signature: vec![4, 5, 6],
+        },
+    ]
+}
+
+fn folder() -> SyncFolder {
+    SyncFolder {
+        id: "folder".into(),
+        name: "Documents".to_string(),
+        origin_computer: "laptop".into(),
+        backup_computers: vec!["nas".into()],
+        is_synced: false,
+        pending_operations: 3,
+        backup_status: backup_status(),
+    }
+}
+
+fn backup_status() -> BTreeMap<ComputerId, BackupStatus> {
+    BTreeMap::from([(
+        "nas".into(),
+        BackupStatus {
+            last_acked_operation: Some(6),
+            last_seen: Some(1_700_000_000),
+            pending_operations: 3,
+            last_failure: Some("disk full".to_string()),
+        },
+    )])
+}
+
+fn manifest() -> Manifest {
+    Manifest {
+        entries: vec![
+            ManifestEntry {
+                relative_path: relative("notes.txt"),
+                size: 5,
+                mtime: Some(1_700_000_000),
+                hash: [7; 32],
+            },
+            ManifestEntry {
+                relative_path: relative("photos/cat.jpg"),
+                size: 0,
+                mtime: None,
+                hash: [0; 32],
+            },
+        ],
+    }
+}
+
+fn user() -> User {
+    User {
+        id: "alice".into(),
+        name: "Alice".to_string(),
+        computers: vec![Computer {
+            id: "laptop".into(),
+            name: "Laptop".to_string(),
+            online: true,
+            last_seen: None,
+        }],
+        sync_folders: vec![folder()],
+    }
+}
+
+#[test]
+fn test_client_messages_round_trip() {
+    let mut messages = vec![
+        ClientMessage::Hello {
+            encoding: Encoding::Postcard,
+        },
+        ClientMessage::Authenticate {
+            user_id: "alice".into(),
+            computer_id: "laptop".into(),
+            protocol_version: PROTOCOL_VERSION,
+            token: String::new(),
+            last_applied: Some(BTreeMap::from([("folder".into(), 3)])),
+        },
+        ClientMessage::RegisterComputer {
+            name: "Laptop".to_string(),
+        },
+        ClientMessage::RemoveComputer {
+            computer_id: "laptop".into(),
+        },
+        ClientMessage::CreateSyncFolder {
+            name: "Documents".to_string(),
+        },
+        ClientMessage::JoinSyncFolder {
+            folder_id: "folder".into(),
+        },
+        ClientMessage::LeaveSyncFolder {
+            folder_id: "folder".into(),
+        },
+        ClientMessage::DeleteSyncFolder {
+            folder_id: "folder".into(),
+        },
+        ClientMessage::RenameSyncFolder {
+            folder_id: "folder".into(),
+            new_name: "Photos".to_string(),
+        },
+        ClientMessage::RequestOriginSwitch {
+            folder_id: "folder".into(),
+        },
+        ClientMessage::Ack { operation_id: 9 },
+        ClientMessage::Nack {
+            operation_id: 9,
+            reason: "disk full".to_string(),
+        },
+        ClientMessage::RequestSignature {
+            folder_id: "folder".into(),
+            relative_path: relative("notes.txt"),
+        },
+        ClientMessage::SignatureResponse {
+            folder_id: "folder".into(),
+            relative_path: relative("notes.txt"),
+            signature: vec![1, 2, 3],
+        },
+        ClientMessage::RequestManifest {
+            folder_id: "folder".into(),
+        },
+        ClientMessage::Manifest {
+            folder_id: "folder".into(),
+            manifest: manifest(),
+        },
+        ClientMessage::GetFolderSequence {
+            folder_id: "folder".into(),
+        },
+        ClientMessage::CatchUp {
+            folder_id: "folder".into(),
+            last_sequence: 4,
+        },
+        ClientMessage::RequestFullSync {
+            folder_id: "folder".into(),
+        },
+        ClientMessage::FetchContent {
+            folder_id: "folder".into(),
+            hash: [7; 32],
+        },
+        ClientMessage::GetUserState,
+        ClientMessage::ListFolders,
+        ClientMessage::Ping { nonce: 42 },
+        ClientMessage::Request {
+            request_id: 3,
+            message: Box::new(ClientMessage::GetUserState),
+        },
+    ];
+    messages.push(ClientMessage::FolderOperationBatch {
+        folder_id: "folder".into(),
+        operations: operations(),
+    });
+    messages.extend(
+        operations()
+            .into_iter()
+            .map(|operation| ClientMessage::TargetedOperation {
+                folder_id: "folder".into(),
+                computer_id: "nas".into(),
+                operation,
+            }),
+    );
+    messages.extend(
+        operations()
+            .into_iter()
+            .map(|operation| ClientMessage::FolderOperation {
+                folder_id: "folder".into(),
+                operation,
+            }),
+    );
+
+    for message in &messages {
+        assert_round_trip(message);
+    }
+}
+
+#[test]
+fn test_server_messages_round_trip() {
+    let mut messages = vec![
+        ServerMessage::Welcome {
+            protocol_version: PROTOCOL_VERSION,
+            min_supported: MIN_SUPPORTED_VERSION,
+        },
+        ServerMessage::EncodingSelected {
+            encoding: Encoding::Postcard,
+        },
+        ServerMessage::Authenticated { user: user() },
+        ServerMessage::ComputerRegistered {
+            computer: Computer {
+                id: "nas".into(),
+                name: "NAS".to_string(),
+                online: false,
+                last_seen: None,
+            },
+        },
+        ServerMessage::ComputerStatusChanged {
+            computer_id: "nas".into(),
+            online: true,
+        },
+        ServerMessage::ComputerRemoved {
+            computer_id: "nas".into(),
+        },
+        ServerMessage::ComputerRemovalDenied {
+            computer_id: "laptop".into(),
+            reason: "origin".to_string(),
+            blocking_folders: vec!["folder".into()],
+        },
+        ServerMessage::SyncFolderCreated { folder: folder() },
+        ServerMessage::JoinedSyncFolder { folder: folder() },
+        ServerMessage::LeftSyncFolder {
+            folder_id: "folder".into(),
+        },
+        ServerMessage::SyncFolderDeleted {
+            folder_id: "folder".into(),
+        },
+        ServerMessage::SyncFolderRenamed {
+            folder_id: "folder".into(),
+            new_name: "Photos".to_string(),
+        },
+        ServerMessage::SyncFolderDeletionDenied {
+            folder_id: "folder".into(),
+            reason: "pending".to_string(),
+        },
+        ServerMessage::OriginSwitched {
+            folder_id: "folder".into(),
+            new_origin: "nas".into(),
+        },
+        ServerMessage::OriginSwitchDenied {
+            folder_id: "folder".into(),
+            reason: "not synced".to_string(),
+        },
+        ServerMessage::SignatureRequested {
+            folder_id: "folder".into(),
+            relative_path: relative("notes.txt"),
+        },
+        ServerMessage::SignatureResponse {
+            folder_id: "folder".into(),
+            computer_id: "nas".into(),
+            relative_path: relative("notes.txt"),
+            signature: vec![1, 2, 3],
+        },
+        ServerMessage::ManifestRequested {
+            folder_id: "folder".into(),
+        },
+        ServerMessage::Manifest {
+            folder_id: "folder".into(),
+            computer_id: "nas".into(),
+            manifest: manifest(),
+        },
+        ServerMessage::FullSyncRequested {
+            folder_id: "folder".into(),
+            computer_id: "nas".into(),
+        },
+        ServerMessage::FullSyncUnavailable {
+            folder_id: "folder".into(),
+            reason: "origin offline".to_string(),
+            retry_after_secs: 30,
+        },
+        ServerMessage::Content {
+            folder_id: "folder".into(),
+            hash: [7; 32],
+            content: b"hello".to_vec(),
+        },
+        ServerMessage::FolderSequence {
+            folder_id: "folder".into(),
+            sequence: 12,
+        },
+        ServerMessage::OperationComplete { operation_id: 9 },
+        ServerMessage::OperationFailed {
+            operation_id: 9,
+            computer_id: "nas".into(),
+            reason: "disk full".to_string(),
+        },
+        ServerMessage::SyncStatusChanged {
+            folder_id: "folder".into(),
+            is_synced: true,
+            pending_operations: 0,
+            backups: backup_status(),
+            backpressure: false,
+        },
+        ServerMessage::UserState { user: user() },
+        ServerMessage::FolderList {
+            folders: vec![SyncFolderSummary {
+                id: "folder".into(),
+                name: "Documents".to_string(),
+                origin_computer: "laptop".into(),
+                is_origin: false,
+                is_backup: true,
+                is_synced: false,
+                pending_operations: 3,
+            }],
+        },
+        ServerMessage::Pong { nonce: 42 },
+        ServerMessage::NotAuthenticated {
+            reason: "Token expired".to_string(),
+        },
+        ServerMessage::AuthTimeout { timeout_secs: 30 },
+        ServerMessage::Superseded,
+        ServerMessage::Goodbye,
+        ServerMessage::PayloadTooLarge {
+            size: 2048,
+            limit: 1024,
+            reason: "Too large".to_string(),
+        },
+        ServerMessage::RateLimited {
+            retry_after_ms: 250,
+        },
+        ServerMessage::Backpressure {
+            folder_id: "folder".into(),
+            paths: vec![relative("notes.txt"), relative("docs/report.pdf")],
+            pending_operations: 1000,
+            limit: 1000,
+        },
+        ServerMessage::Error {
+            message: "nope".to_string(),
+            trace_id: None,
+        },
+        ServerMessage::Response {
+            request_id: 3,
+            message: Box::new(ServerMessage::UserState { user: user() }),
+        },
+    ];
+    messages.push(ServerMessage::FolderOperationBatch {
+        folder_id: "folder".into(),
+        operation_id: 10,
+        sequence: 4,
+        operations: operations(),
+    });
+    messages.extend(
+        operations()
+            .into_iter()
+            .enumerate()
+            .map(|(index, operation)| ServerMessage::FolderOperation {
+                folder_id: "folder".into(),
+                operation_id: index as u64,
+                sequence: index as u64 + 1,
+                operation,
+            }),
+    );
+
+    for message in &messages {
+        assert_round_trip(message);
+    }
+}
+
+#[test]
+fn test_postcard_is_smaller_for_binary_payloads() {
+    let message = ClientMessage::FolderOperation {
+        folder_id: "folder".into(),
+        operation: FileOperation::CreateFile {
+            relative_path: relative("a.bin"),
+            content: vec![200; 4096],
+            hash: [0; 32],
+            compression: None,
+        },
+    };
+
+    let Frame::Text(json) = codec::encode(&message, Encoding::Json).unwrap() else {
+        panic!("JSON is sent as text");
+    };
+    let Frame::Binary(postcard) = codec::encode(&message, Encoding::Postcard).unwrap() else {
+        panic!("postcard is sent as binary");
+    };
+
+    assert!(
+        postcard.len() * 3 < json.len(),
+        "{} vs {}",
+        postcard.len(),
+        json.len()
+    );
+}
+
+#[test]
+fn test_encoding_names() {
+    assert_eq!(
+        serde_json::to_string(&Encoding::Postcard).unwrap(),
+        "\"postcard\""
+    );
+    assert_eq!(Encoding::default(), Encoding::Json);
+}
+
+#[test]
+fn test_folder_without_backup_status_parses() {
+    let json = r#"{"id":"folder","name":"Documents","origin_computer":"laptop","backup_computers":["nas"],"is_synced":true,"pending_operations":0}"#;
+
+    let folder: SyncFolder = codec::decode_text(json).unwrap();
+
+    assert!(folder.backup_status.is_empty());
+}
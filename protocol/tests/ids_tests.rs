@@ -0,0 +1,83 @@
+use backup_sync_protocol::codec::{self, Encoding, Frame};
+use backup_sync_protocol::{ClientMessage, ComputerId, FolderId, UserId};
+use std::collections::HashSet;
+
+#[test]
+fn test_new_ids_are_unique_uuids() {
+    let ids: HashSet<FolderId> = (0..1000).map(|_| FolderId::new_v4()).collect();
+
+    assert_eq!(ids.len(), 1000);
+    assert!(ids.iter().all(|id| id.as_uuid().is_some()));
+}
+
+#[test]
+fn test_ids_generated_concurrently_never_collide() {
+    let handles: Vec<_> = (0..8)
+        .map(|_| std::thread::spawn(|| (0..2000).map(|_| ComputerId::new_v4()).collect::<Vec<_>>()))
+        .collect();
+
+    let mut ids = HashSet::new();
+    for handle in handles {
+        for id in handle.join().unwrap() {
+            assert!(ids.insert(id), "Duplicate id generated");
+        }
+    }
+    assert_eq!(ids.len(), 16_000);
+}
+
+#[test]
+fn test_ids_display_as_parsed() {
+    let id = ComputerId::new_v4();
+    let parsed: ComputerId = id.to_string().parse().unwrap();
+    assert_eq!(parsed, id);
+
+    // Ids created before they were UUIDs keep working
+    let legacy: ComputerId = "laptop".parse().unwrap();
+    assert_eq!(legacy.as_uuid(), None);
+    assert_eq!(legacy.to_string(), "laptop");
+    assert_eq!(legacy, "laptop");
+
+    // UUIDs in another form than hyphenated stay as they were written
+    let simple = id.as_uuid().unwrap().simple().to_string();
+    assert_eq!(ComputerId::from(simple.as_str()).to_string(), simple);
+}
+
+#[test]
+fn test_ids_are_strings_on_the_wire() {
+    let user_id = UserId::new_v4();
+    let message = ClientMessage::Authenticate {
+        user_id: user_id.clone(),
+        computer_id: "laptop".into(),
+        protocol_version: 1,
+        token: "token".to_string(),
+        last_applied: None,
+    };
+
+    let Frame::Text(json) = codec::encode(&message, Encoding::Json).unwrap() else {
+        panic!("Expected a text frame");
+    };
+    assert_eq!(
+        json,
+        format!(
+            r#"{{"Authenticate":{{"user_id":"{user_id}","computer_id":"laptop","protocol_version":1,"token":"token","last_applied":null}}}}"#
+        )
+    );
+
+    for encoding in [Encoding::Json, Encoding::Postcard] {
+        let decoded: ClientMessage = match codec::encode(&message, encoding).unwrap() {
+            Frame::Text(text) => codec::decode_text(&text).unwrap(),
+            Frame::Binary(bytes) => codec::decode_binary(&bytes).unwrap(),
+        };
+        match decoded {
+            ClientMessage::Authenticate {
+                user_id: decoded_user,
+                computer_id,
+                ..
+            } => {
+                assert_eq!(decoded_user, user_id);
+                assert_eq!(computer_id, "laptop");
+            }
+            other => panic!("Expected Authenticate, got {other:?}"),
+        }
+    }
+}
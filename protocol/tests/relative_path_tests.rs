@@ -0,0 +1,105 @@
+use backup_sync_protocol::codec::{self, Encoding, Frame};
+use backup_sync_protocol::{
+    ClientMessage, FileOperation, MAX_COMPONENT_LEN, MAX_PATH_LEN, RelativePath, RelativePathError,
+};
+use std::path::Path;
+
+fn rejected(path: &str) -> RelativePathError {
+    RelativePath::try_from(path).unwrap_err()
+}
+
+#[test]
+fn test_paths_inside_folder_are_accepted() {
+    assert_eq!(RelativePath::try_from("a/b.txt").unwrap(), "a/b.txt");
+    assert_eq!(RelativePath::try_from("notes").unwrap(), "notes");
+    assert_eq!(
+        RelativePath::new(Path::new("dir/..hidden")).unwrap(),
+        "dir/..hidden"
+    );
+}
+
+#[test]
+fn test_paths_are_normalized() {
+    assert_eq!(RelativePath::try_from("./a//b/./c").unwrap(), "a/b/c");
+    assert_eq!(RelativePath::try_from("a\\b.txt").unwrap(), "a/b.txt");
+    assert_eq!(RelativePath::try_from("a/").unwrap(), "a");
+    // "é" decomposed into "e" and a combining accent
+    assert_eq!(
+        RelativePath::try_from("cafe\u{301}.txt").unwrap(),
+        RelativePath::try_from("caf\u{e9}.txt").unwrap()
+    );
+}
+
+#[test]
+fn test_traversal_is_rejected() {
+    assert_eq!(rejected("../escaped"), RelativePathError::ParentDir);
+    assert_eq!(rejected("a/../../escaped"), RelativePathError::ParentDir);
+    assert_eq!(rejected("a\\..\\..\\escaped"), RelativePathError::ParentDir);
+    assert_eq!(rejected(".."), RelativePathError::ParentDir);
+}
+
+#[test]
+fn test_absolute_paths_are_rejected() {
+    assert_eq!(rejected("/etc/passwd"), RelativePathError::Absolute);
+    assert_eq!(rejected("\\Windows\\System32"), RelativePathError::Absolute);
+    assert_eq!(rejected("C:\\Windows"), RelativePathError::Absolute);
+    assert_eq!(rejected("c:relative"), RelativePathError::Absolute);
+}
+
+#[test]
+fn test_empty_and_nul_paths_are_rejected() {
+    assert_eq!(rejected(""), RelativePathError::Empty);
+    assert_eq!(rejected("./"), RelativePathError::Empty);
+    assert_eq!(rejected("a\0b"), RelativePathError::Nul);
+}
+
+#[test]
+fn test_length_limits() {
+    let name = "a".repeat(MAX_COMPONENT_LEN);
+    assert!(RelativePath::try_from(name.as_str()).is_ok());
+    assert_eq!(
+        rejected(&format!("{name}a")),
+        RelativePathError::ComponentTooLong
+    );
+
+    let long = vec!["a"; MAX_PATH_LEN / 2 + 1].join("/");
+    assert_eq!(rejected(&long), RelativePathError::TooLong);
+}
+
+#[test]
+fn test_operation_with_traversal_does_not_decode() {
+    let json = r#"{"FolderOperation":{"folder_id":"folder1","operation":{"RemoveFile":{"relative_path":"../../etc/cron.d/evil"}}}}"#;
+
+    let err = codec::decode_text::<ClientMessage>(json).unwrap_err();
+
+    let reason = err.invalid_path().unwrap();
+    assert!(reason.contains("../../etc/cron.d/evil"), "{reason}");
+}
+
+#[test]
+fn test_unknown_operation_is_not_an_invalid_path() {
+    let json = r#"{"SetXattr":{"relative_path":"a.txt"}}"#;
+
+    let err = codec::decode_text::<FileOperation>(json).unwrap_err();
+
+    assert_eq!(err.invalid_path(), None);
+}
+
+#[test]
+fn test_postcard_operation_with_traversal_does_not_decode() {
+    // Built by hand, as a valid operation cannot hold such a path: variant 2 is
+    // RemoveFile, then the length of the path and its bytes
+    let path = b"../escaped";
+    let mut bytes = vec![2, path.len() as u8];
+    bytes.extend_from_slice(path);
+
+    assert!(codec::decode_binary::<FileOperation>(&bytes).is_err());
+
+    let valid = FileOperation::RemoveFile {
+        relative_path: RelativePath::try_from("escaped").unwrap(),
+    };
+    match codec::encode(&valid, Encoding::Postcard).unwrap() {
+        Frame::Binary(encoded) => assert_eq!(encoded, [&[2, 7][..], b"escaped"].concat()),
+        Frame::Text(_) => panic!("Expected a binary frame"),
+    }
+}
@@ -0,0 +1,9 @@
+-- Add up migration script here
+CREATE TABLE refresh_tokens
+(
+    token_hash TEXT PRIMARY KEY NOT NULL,
+    user_id    TEXT             NOT NULL,
+    expires_at BIGINT           NOT NULL,
+    revoked    BOOLEAN          NOT NULL DEFAULT FALSE,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
@@ -0,0 +1,4 @@
+-- Add up migration script here
+ALTER TABLE computers DROP COLUMN online;
+ALTER TABLE computers DROP COLUMN last_seen;
+ALTER TABLE computers ADD COLUMN last_seen BIGINT;
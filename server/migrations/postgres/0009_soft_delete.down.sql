@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE folders DROP COLUMN deleted_at;
+ALTER TABLE computers DROP COLUMN deleted_at;
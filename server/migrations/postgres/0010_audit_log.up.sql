@@ -0,0 +1,14 @@
+-- Add up migration script here
+CREATE TABLE audit_log
+(
+    id          BIGSERIAL PRIMARY KEY NOT NULL,
+    user_id     TEXT                  NOT NULL,
+    computer_id TEXT,
+    action      TEXT                  NOT NULL,
+    target      TEXT,
+    created_at  BIGINT                NOT NULL,
+    request_id  TEXT,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX audit_log_user_id ON audit_log (user_id, id);
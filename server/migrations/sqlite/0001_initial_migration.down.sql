@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP TABLE folder_backups;
+DROP TABLE folders;
+DROP TABLE computers;
+DROP TABLE users;
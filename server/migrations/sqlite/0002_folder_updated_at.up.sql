@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE folders ADD COLUMN updated_at TIMESTAMP;
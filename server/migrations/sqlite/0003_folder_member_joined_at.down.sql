@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE folder_backups DROP COLUMN joined_at;
+ALTER TABLE folders DROP COLUMN origin_joined_at;
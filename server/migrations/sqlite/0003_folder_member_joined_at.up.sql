@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE folders ADD COLUMN origin_joined_at INTEGER;
+ALTER TABLE folder_backups ADD COLUMN joined_at INTEGER;
@@ -0,0 +1,6 @@
+-- Add up migration script here
+CREATE TABLE revoked_access_tokens
+(
+    jti        TEXT PRIMARY KEY NOT NULL,
+    expires_at INTEGER          NOT NULL
+);
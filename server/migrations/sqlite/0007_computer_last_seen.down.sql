@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE computers DROP COLUMN last_seen;
+ALTER TABLE computers ADD COLUMN last_seen TIMESTAMP;
+ALTER TABLE computers ADD COLUMN online BOOLEAN NOT NULL DEFAULT FALSE;
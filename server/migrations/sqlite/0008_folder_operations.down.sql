@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE operation_acks;
+DROP TABLE folder_operations;
@@ -0,0 +1,21 @@
+-- Add up migration script here
+CREATE TABLE folder_operations
+(
+    folder_id  TEXT    NOT NULL,
+    id         INTEGER NOT NULL,
+    kind       TEXT    NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (folder_id, id),
+    FOREIGN KEY (folder_id) REFERENCES folders (id) ON DELETE CASCADE
+);
+
+CREATE TABLE operation_acks
+(
+    folder_id    TEXT    NOT NULL,
+    operation_id INTEGER NOT NULL,
+    computer_id  TEXT    NOT NULL,
+    acked_at     INTEGER NOT NULL,
+    PRIMARY KEY (folder_id, operation_id, computer_id),
+    FOREIGN KEY (folder_id, operation_id) REFERENCES folder_operations (folder_id, id) ON DELETE CASCADE,
+    FOREIGN KEY (computer_id) REFERENCES computers (id) ON DELETE CASCADE
+);
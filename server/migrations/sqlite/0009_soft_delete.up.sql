@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE computers ADD COLUMN deleted_at INTEGER;
+ALTER TABLE folders ADD COLUMN deleted_at INTEGER;
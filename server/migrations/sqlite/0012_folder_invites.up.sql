@@ -0,0 +1,17 @@
+-- Add up migration script here
+CREATE TABLE folder_invites
+(
+    id         TEXT PRIMARY KEY NOT NULL,
+    folder_id  TEXT             NOT NULL,
+    inviter_id TEXT             NOT NULL,
+    invitee_id TEXT             NOT NULL,
+    status     TEXT             NOT NULL DEFAULT 'pending',
+    created_at INTEGER          NOT NULL,
+    expires_at INTEGER          NOT NULL,
+    FOREIGN KEY (folder_id) REFERENCES folders (id) ON DELETE CASCADE,
+    FOREIGN KEY (inviter_id) REFERENCES users (id) ON DELETE CASCADE,
+    FOREIGN KEY (invitee_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX folder_invites_invitee_id ON folder_invites (invitee_id, status);
+CREATE INDEX folder_invites_folder_id ON folder_invites (folder_id, invitee_id);
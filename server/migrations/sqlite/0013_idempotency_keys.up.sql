@@ -0,0 +1,15 @@
+-- Add up migration script here
+CREATE TABLE idempotency_keys
+(
+    user_id         TEXT    NOT NULL,
+    idempotency_key TEXT    NOT NULL,
+    request_hash    TEXT    NOT NULL,
+    -- Unset while the first request with the key is running
+    status          INTEGER,
+    body            TEXT,
+    created_at      INTEGER NOT NULL,
+    PRIMARY KEY (user_id, idempotency_key),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX idempotency_keys_created_at ON idempotency_keys (created_at);
@@ -0,0 +1,485 @@
+//! OpenAPI document of the HTTP API, served at `/api-docs` when `API_DOCS` is set
+
+use crate::error::ErrorBody;
+use crate::handlers::admin_handler::{AdminFolder, AdminUser, UpdateUserRequest};
+use crate::handlers::audit_handler::{AuditEntry, AuditQuery};
+use crate::handlers::auth_handler::{
+    AuthResponse, LoginRequest, RefreshRequest, RegisterResponse, RegisterUserRequest,
+};
+use crate::handlers::discovery_handler::ApiRoute;
+use crate::handlers::folder_handler::{
+    CreateFolderRequest, DeleteFolderQuery, FolderDetail, FolderOperations, JoinFolderRequest,
+    OperationsQuery, SwitchOriginRequest, UpdateFolderRequest,
+};
+use crate::handlers::invite_handler::{CreateInviteRequest, Invite};
+use crate::handlers::pagination::{ListQuery, Page};
+use crate::handlers::user_handler::{CreateComputerRequest, UpdateComputerRequest};
+use crate::middleware_layer::MAX_IDEMPOTENCY_KEY_LEN;
+use crate::{API_PREFIX, AppState};
+use axum::{Json, Router, http::StatusCode, routing::get};
+use backup_sync_protocol::{Computer, SyncFolder, User};
+use schemars::{JsonSchema, Schema, SchemaGenerator, generate::SchemaSettings};
+use serde_json::{Map, Value, json};
+
+type SchemaFn = fn(&mut SchemaGenerator) -> Schema;
+
+fn schema<T: JsonSchema>(generator: &mut SchemaGenerator) -> Schema {
+    generator.subschema_for::<T>()
+}
+
+/// What a route answers with on success
+enum Content {
+    Empty,
+    Text,
+    Json(SchemaFn),
+}
+
+/// One route of the API, documented by hand next to the router in `create_app`
+struct Operation {
+    method: &'static str,
+    path: &'static str,
+    tag: &'static str,
+    summary: &'static str,
+    /// Whether the route needs a bearer token
+    authenticated: bool,
+    deprecated: bool,
+    /// Whether the route takes an `Idempotency-Key`, see
+    /// [`crate::middleware_layer::idempotency`]
+    idempotent: bool,
+    query: Option<SchemaFn>,
+    body: Option<SchemaFn>,
+    status: StatusCode,
+    response: Content,
+    /// Statuses answered with an [`ErrorBody`], besides 401 on authenticated routes and
+    /// 400 on routes taking parameters or a body
+    errors: &'static [StatusCode],
+}
+
+impl Operation {
+    const fn new(method: &'static str, path: &'static str, tag: &'static str) -> Self {
+        Self {
+            method,
+            path,
+            tag,
+            summary: "",
+            authenticated: true,
+            deprecated: false,
+            idempotent: false,
+            query: None,
+            body: None,
+            status: StatusCode::OK,
+            response: Content::Empty,
+            errors: &[],
+        }
+    }
+
+    const fn summary(mut self, summary: &'static str) -> Self {
+        self.summary = summary;
+        self
+    }
+
+    const fn public(mut self) -> Self {
+        self.authenticated = false;
+        self
+    }
+
+    const fn deprecated(mut self) -> Self {
+        self.deprecated = true;
+        self
+    }
+
+    const fn idempotent(mut self) -> Self {
+        self.idempotent = true;
+        self
+    }
+
+    const fn query(mut self, query: SchemaFn) -> Self {
+        self.query = Some(query);
+        self
+    }
+
+    const fn body(mut self, body: SchemaFn) -> Self {
+        self.body = Some(body);
+        self
+    }
+
+    const fn responds(mut self, status: StatusCode, response: Content) -> Self {
+        self.status = status;
+        self.response = response;
+        self
+    }
+
+    const fn errors(mut self, errors: &'static [StatusCode]) -> Self {
+        self.errors = errors;
+        self
+    }
+}
+
+const BAD_REQUEST: StatusCode = StatusCode::BAD_REQUEST;
+const FORBIDDEN: StatusCode = StatusCode::FORBIDDEN;
+const NOT_FOUND: StatusCode = StatusCode::NOT_FOUND;
+const CONFLICT: StatusCode = StatusCode::CONFLICT;
+
+const OPERATIONS: &[Operation] = &[
+    Operation::new("post", "/auth/signup", "auth")
+        .summary("Creates a user")
+        .public()
+        .body(schema::<RegisterUserRequest>)
+        .responds(
+            StatusCode::CREATED,
+            Content::Json(schema::<RegisterResponse>),
+        )
+        .errors(&[CONFLICT]),
+    Operation::new("post", "/register", "auth")
+        .summary("Creates a user, use `/auth/signup` instead")
+        .public()
+        .deprecated()
+        .body(schema::<RegisterUserRequest>)
+        .responds(
+            StatusCode::CREATED,
+            Content::Json(schema::<RegisterResponse>),
+        )
+        .errors(&[CONFLICT]),
+    Operation::new("post", "/auth/login", "auth")
+        .summary("Exchanges a name and password for tokens")
+        .public()
+        .body(schema::<LoginRequest>)
+        .responds(StatusCode::OK, Content::Json(schema::<AuthResponse>))
+        .errors(&[StatusCode::UNAUTHORIZED]),
+    Operation::new("post", "/login", "auth")
+        .summary("Exchanges a name and password for tokens, use `/auth/login` instead")
+        .public()
+        .deprecated()
+        .body(schema::<LoginRequest>)
+        .responds(StatusCode::OK, Content::Json(schema::<AuthResponse>))
+        .errors(&[StatusCode::UNAUTHORIZED]),
+    Operation::new("post", "/auth/refresh", "auth")
+        .summary("Exchanges a refresh token for new tokens")
+        .public()
+        .body(schema::<RefreshRequest>)
+        .responds(StatusCode::OK, Content::Json(schema::<AuthResponse>))
+        .errors(&[StatusCode::UNAUTHORIZED]),
+    Operation::new("post", "/auth/logout", "auth")
+        .summary("Revokes a refresh token and the access token of the request")
+        .body(schema::<RefreshRequest>)
+        .responds(StatusCode::NO_CONTENT, Content::Empty),
+    Operation::new("post", "/auth/logout-all", "auth")
+        .summary("Revokes every refresh token of the user")
+        .responds(StatusCode::NO_CONTENT, Content::Empty),
+    Operation::new("post", "/computers", "computers")
+        .summary("Registers a computer")
+        .idempotent()
+        .body(schema::<CreateComputerRequest>)
+        .responds(StatusCode::CREATED, Content::Json(schema::<Computer>))
+        .errors(&[BAD_REQUEST]),
+    Operation::new("get", "/computers", "computers")
+        .summary("Lists the computers, sortable by `name`, `last_seen`, `online` or `id`")
+        .query(schema::<ListQuery>)
+        .responds(StatusCode::OK, Content::Json(schema::<Page<Computer>>))
+        .errors(&[BAD_REQUEST]),
+    Operation::new("patch", "/computers/{id}", "computers")
+        .summary("Renames a computer")
+        .body(schema::<UpdateComputerRequest>)
+        .responds(StatusCode::OK, Content::Json(schema::<Computer>))
+        .errors(&[BAD_REQUEST, FORBIDDEN, NOT_FOUND, CONFLICT]),
+    Operation::new("delete", "/computers/{id}", "computers")
+        .summary("Removes a computer, restorable until the retention window ends")
+        .responds(StatusCode::NO_CONTENT, Content::Empty)
+        .errors(&[FORBIDDEN]),
+    Operation::new("post", "/computers/{id}/restore", "computers")
+        .summary("Restores a removed computer along with its folders")
+        .responds(StatusCode::OK, Content::Json(schema::<Computer>))
+        .errors(&[FORBIDDEN, NOT_FOUND, CONFLICT]),
+    Operation::new("post", "/computers/{id}/heartbeat", "computers")
+        .summary("Marks a computer as online")
+        .responds(StatusCode::OK, Content::Json(schema::<Computer>))
+        .errors(&[FORBIDDEN, NOT_FOUND]),
+    Operation::new("get", "/computers/{id}/folders", "computers")
+        .summary("Lists the folders a computer takes part in")
+        .responds(StatusCode::OK, Content::Json(schema::<Vec<SyncFolder>>))
+        .errors(&[FORBIDDEN]),
+    Operation::new("get", "/user/state", "user")
+        .summary("The user with their computers and folders, live from websocket connections")
+        .responds(StatusCode::OK, Content::Json(schema::<User>)),
+    Operation::new("get", "/user/audit", "user")
+        .summary("The user's audit log, most recent entries first")
+        .query(schema::<AuditQuery>)
+        .responds(StatusCode::OK, Content::Json(schema::<Vec<AuditEntry>>))
+        .errors(&[BAD_REQUEST]),
+    Operation::new("get", "/ws", "sync")
+        .summary("Upgrades to a websocket speaking the sync protocol, starting with `Authenticate`")
+        .responds(StatusCode::SWITCHING_PROTOCOLS, Content::Empty)
+        .errors(&[BAD_REQUEST]),
+    Operation::new("post", "/folders", "folders")
+        .summary("Creates a folder with a computer as origin")
+        .idempotent()
+        .body(schema::<CreateFolderRequest>)
+        .responds(StatusCode::CREATED, Content::Json(schema::<SyncFolder>))
+        .errors(&[BAD_REQUEST, FORBIDDEN]),
+    Operation::new("post", "/folders/bulk", "folders")
+        .summary("Creates up to 50 folders at once, all of them or none")
+        .idempotent()
+        .body(schema::<Vec<CreateFolderRequest>>)
+        .responds(
+            StatusCode::CREATED,
+            Content::Json(schema::<Vec<SyncFolder>>),
+        )
+        .errors(&[BAD_REQUEST, FORBIDDEN]),
+    Operation::new("get", "/folders", "folders")
+        .summary("Lists the folders, sortable by `name`, `pending_operations` or `id`")
+        .query(schema::<ListQuery>)
+        .responds(StatusCode::OK, Content::Json(schema::<Page<SyncFolder>>))
+        .errors(&[BAD_REQUEST]),
+    Operation::new("get", "/folders/{id}", "folders")
+        .summary("A folder with its members")
+        .responds(StatusCode::OK, Content::Json(schema::<FolderDetail>))
+        .errors(&[FORBIDDEN, NOT_FOUND]),
+    Operation::new("patch", "/folders/{id}", "folders")
+        .summary("Renames a folder")
+        .body(schema::<UpdateFolderRequest>)
+        .responds(StatusCode::OK, Content::Json(schema::<SyncFolder>))
+        .errors(&[BAD_REQUEST, FORBIDDEN, NOT_FOUND, CONFLICT]),
+    Operation::new("delete", "/folders/{id}", "folders")
+        .summary("Deletes a folder, refused while operations are pending unless `force`")
+        .query(schema::<DeleteFolderQuery>)
+        .responds(StatusCode::NO_CONTENT, Content::Empty)
+        .errors(&[FORBIDDEN, NOT_FOUND, CONFLICT]),
+    Operation::new("post", "/folders/{id}/restore", "folders")
+        .summary("Restores a deleted folder along with its backups")
+        .responds(StatusCode::OK, Content::Json(schema::<SyncFolder>))
+        .errors(&[FORBIDDEN, NOT_FOUND, CONFLICT]),
+    Operation::new("post", "/folders/{id}/join", "folders")
+        .summary("Adds a computer as backup of a folder")
+        .body(schema::<JoinFolderRequest>)
+        .responds(StatusCode::OK, Content::Text)
+        .errors(&[FORBIDDEN, NOT_FOUND, CONFLICT]),
+    Operation::new("post", "/folders/{id}/leave", "folders")
+        .summary("Removes a backup computer from a folder")
+        .body(schema::<JoinFolderRequest>)
+        .responds(StatusCode::NO_CONTENT, Content::Empty)
+        .errors(&[FORBIDDEN, NOT_FOUND, CONFLICT]),
+    Operation::new("get", "/folders/{id}/operations", "folders")
+        .summary("The pending operations of a folder and how far each backup is behind")
+        .query(schema::<OperationsQuery>)
+        .responds(StatusCode::OK, Content::Json(schema::<FolderOperations>))
+        .errors(&[BAD_REQUEST, FORBIDDEN, NOT_FOUND]),
+    Operation::new("post", "/folders/{id}/switch-origin", "folders")
+        .summary("Makes a backup computer the origin of a folder")
+        .body(schema::<SwitchOriginRequest>)
+        .responds(StatusCode::OK, Content::Json(schema::<SyncFolder>))
+        .errors(&[BAD_REQUEST, FORBIDDEN, NOT_FOUND, CONFLICT]),
+    Operation::new("post", "/folders/{id}/invites", "invites")
+        .summary("Invites a user to back up a folder on their computers")
+        .body(schema::<CreateInviteRequest>)
+        .responds(StatusCode::CREATED, Content::Json(schema::<Invite>))
+        .errors(&[BAD_REQUEST, FORBIDDEN, NOT_FOUND, CONFLICT]),
+    Operation::new("get", "/invites", "invites")
+        .summary("Lists the pending invites addressed to the user, most recent first")
+        .responds(StatusCode::OK, Content::Json(schema::<Vec<Invite>>)),
+    Operation::new("post", "/invites/{id}/accept", "invites")
+        .summary("Accepts an invite, letting the user's computers join the folder")
+        .responds(StatusCode::OK, Content::Json(schema::<Invite>))
+        .errors(&[NOT_FOUND, CONFLICT]),
+    Operation::new("post", "/invites/{id}/decline", "invites")
+        .summary("Declines an invite")
+        .responds(StatusCode::OK, Content::Json(schema::<Invite>))
+        .errors(&[NOT_FOUND, CONFLICT]),
+    Operation::new("get", "/admin/users", "admin")
+        .summary("Lists every user, sortable by `name`, `role` or `id`. Admins only.")
+        .query(schema::<ListQuery>)
+        .responds(StatusCode::OK, Content::Json(schema::<Page<AdminUser>>))
+        .errors(&[BAD_REQUEST, FORBIDDEN]),
+    Operation::new("patch", "/admin/users/{id}", "admin")
+        .summary("Promotes a user to admin or demotes them. Admins only.")
+        .body(schema::<UpdateUserRequest>)
+        .responds(StatusCode::OK, Content::Json(schema::<AdminUser>))
+        .errors(&[BAD_REQUEST, FORBIDDEN, NOT_FOUND, CONFLICT]),
+    Operation::new("delete", "/admin/users/{id}", "admin")
+        .summary("Deletes a user with everything they own. Admins only.")
+        .responds(StatusCode::NO_CONTENT, Content::Empty)
+        .errors(&[FORBIDDEN, NOT_FOUND, CONFLICT]),
+    Operation::new("get", "/admin/folders", "admin")
+        .summary("Lists the folders of every user, sortable like `/folders`. Admins only.")
+        .query(schema::<ListQuery>)
+        .responds(StatusCode::OK, Content::Json(schema::<Page<AdminFolder>>))
+        .errors(&[BAD_REQUEST, FORBIDDEN]),
+];
+
+fn json_content(schema: impl serde::Serialize) -> Value {
+    json!({ "application/json": { "schema": schema } })
+}
+
+fn description(status: StatusCode) -> &'static str {
+    status.canonical_reason().unwrap_or("")
+}
+
+/// The `{name}` segments of the path, the fields of the query, then the
+/// `Idempotency-Key` header
+fn parameters(operation: &Operation) -> Vec<Value> {
+    let mut parameters: Vec<Value> = operation
+        .path
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .map(|name| {
+            json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": { "type": "string" },
+            })
+        })
+        .collect();
+
+    if let Some(query) = operation.query {
+        let mut generator = SchemaSettings::openapi3()
+            .with(|settings| settings.inline_subschemas = true)
+            .into_generator();
+        let schema = query(&mut generator);
+        let required = schema.get("required").and_then(Value::as_array);
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (name, property) in properties.into_iter().flatten() {
+            let is_required =
+                required.is_some_and(|required| required.iter().any(|field| field == name));
+            parameters.push(json!({
+                "name": name,
+                "in": "query",
+                "required": is_required,
+                "schema": property,
+            }));
+        }
+    }
+
+    if operation.idempotent {
+        parameters.push(json!({
+            "name": "Idempotency-Key",
+            "in": "header",
+            "required": false,
+            "description": "Replays the response of the last request with the same key and \
+                body for 24 hours, 409 answering another body with the key",
+            "schema": { "type": "string", "maxLength": MAX_IDEMPOTENCY_KEY_LEN },
+        }));
+    }
+
+    parameters
+}
+
+/// Every route of the API, with its path under [`API_PREFIX`]
+pub fn routes() -> Vec<ApiRoute> {
+    OPERATIONS
+        .iter()
+        .map(|operation| ApiRoute {
+            method: operation.method.to_uppercase(),
+            path: format!("{API_PREFIX}{}", operation.path),
+            summary: operation.summary.to_owned(),
+            deprecated: operation.deprecated,
+        })
+        .collect()
+}
+
+/// The OpenAPI 3.0 document of every route of the API
+pub fn openapi() -> Value {
+    let mut generator = SchemaSettings::openapi3().into_generator();
+    let error = schema::<ErrorBody>(&mut generator);
+    let mut paths = Map::new();
+
+    for operation in OPERATIONS {
+        let mut responses = Map::new();
+        let success = match operation.response {
+            Content::Empty => json!({ "description": description(operation.status) }),
+            Content::Text => json!({
+                "description": description(operation.status),
+                "content": { "text/plain": { "schema": { "type": "string" } } },
+            }),
+            Content::Json(response) => json!({
+                "description": description(operation.status),
+                "content": json_content(response(&mut generator)),
+            }),
+        };
+        responses.insert(operation.status.as_str().to_string(), success);
+
+        let unauthorized = operation.authenticated.then_some(StatusCode::UNAUTHORIZED);
+        // Malformed parameters and bodies are refused before reaching the handler
+        let takes_input =
+            operation.body.is_some() || operation.query.is_some() || operation.path.contains('{');
+        let bad_request = takes_input.then_some(BAD_REQUEST);
+        let reused_key = operation.idempotent.then_some(CONFLICT);
+        for &status in unauthorized
+            .iter()
+            .chain(&bad_request)
+            .chain(&reused_key)
+            .chain(operation.errors)
+        {
+            responses.insert(
+                status.as_str().to_string(),
+                json!({
+                    "description": description(status),
+                    "content": json_content(&error),
+                }),
+            );
+        }
+        // Every route is rate limited, per user or per address
+        let too_many = StatusCode::TOO_MANY_REQUESTS;
+        responses.insert(
+            too_many.as_str().to_string(),
+            json!({
+                "description": description(too_many),
+                "headers": {
+                    "Retry-After": {
+                        "description": "Seconds until the rate limit lets the request through",
+                        "schema": { "type": "integer" },
+                    },
+                },
+                "content": json_content(&error),
+            }),
+        );
+
+        let mut document = json!({
+            "tags": [operation.tag],
+            "summary": operation.summary,
+            "parameters": parameters(operation),
+            "responses": responses,
+        });
+        if let Some(body) = operation.body {
+            document["requestBody"] = json!({
+                "required": true,
+                "content": json_content(body(&mut generator)),
+            });
+        }
+        if operation.authenticated {
+            document["security"] = json!([{ "bearer": [] }]);
+        }
+        if operation.deprecated {
+            document["deprecated"] = json!(true);
+        }
+
+        paths.entry(operation.path).or_insert_with(|| json!({}))[operation.method] = document;
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "backup-sync",
+            "description": env!("CARGO_PKG_DESCRIPTION"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": API_PREFIX }],
+        "paths": paths,
+        "components": {
+            "schemas": generator.take_definitions(true),
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer", "bearerFormat": "JWT" },
+            },
+        },
+    })
+}
+
+pub fn router() -> Router<AppState> {
+    let document = openapi();
+    Router::new().route(
+        "/api-docs",
+        get(move || {
+            let document = document.clone();
+            async move { Json(document) }
+        }),
+    )
+}
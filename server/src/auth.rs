@@ -1,7 +1,64 @@
+use crate::error::ApiError;
+use axum::{extract::FromRequestParts, http::request::Parts};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
     pub sub: String, // User ID
     pub exp: usize,
+    /// Unique id of the token, used to revoke it before it expires
+    #[serde(default)]
+    pub jti: Option<String>,
+    /// The user's role when the token was issued. Tokens from before roles count as a
+    /// user's.
+    #[serde(default)]
+    pub role: Role,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Self> {
+        match role {
+            "user" => Some(Role::User),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// Claims of a request made with an admin's token, refusing everyone else with a 403
+#[derive(Debug, Clone)]
+pub struct RequireAdmin(pub Claims);
+
+impl<S: Send + Sync> FromRequestParts<S> for RequireAdmin {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let claims = parts
+            .extensions
+            .get::<Claims>()
+            .ok_or(ApiError::AuthenticationFailed(
+                "Missing token claims".to_string(),
+            ))?;
+        if claims.role != Role::Admin {
+            return Err(ApiError::PermissionDenied("Admins only".to_string()));
+        }
+        Ok(RequireAdmin(claims.clone()))
+    }
 }
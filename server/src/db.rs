@@ -1,20 +1,76 @@
-use anyhow::Context;
-use sqlx::{Pool, Sqlite, sqlite::SqlitePoolOptions};
+use anyhow::{Context, bail};
+use sqlx::any::{AnyPoolOptions, install_default_drivers};
+use sqlx::migrate::Migrator;
+use sqlx::{Any, AnyConnection, Connection, Pool};
+use uuid::Uuid;
 
-#[derive(Clone)]
-pub struct AppState {
-    pub db: Pool<Sqlite>,
-    pub jwt_secret: String,
+// The SQL of the two backends diverges in column types, so each has its own migrations,
+// numbered alike
+static SQLITE_MIGRATIONS: Migrator = sqlx::migrate!("./migrations/sqlite");
+static POSTGRES_MIGRATIONS: Migrator = sqlx::migrate!("./migrations/postgres");
+
+/// Connects to the database named by `DATABASE_URL`, a `sqlite:` or `postgres:` URL.
+/// Without it the data lives in memory and is lost when the server stops.
+pub async fn init_db() -> anyhow::Result<Pool<Any>> {
+    match std::env::var("DATABASE_URL") {
+        Ok(url) => connect(&url).await,
+        Err(_) => connect(&memory_url()).await,
+    }
+}
+
+/// A database of its own for a test: in memory, or a new schema of the Postgres database
+/// named by `TEST_DATABASE_URL` when set
+pub async fn test_db() -> anyhow::Result<Pool<Any>> {
+    let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+        return connect(&memory_url()).await;
+    };
+
+    install_default_drivers();
+    let schema = format!("test_{}", Uuid::new_v4().simple());
+    let mut conn = AnyConnection::connect(&url)
+        .await
+        .context("Failed to connect to the test database")?;
+    sqlx::query(&format!("CREATE SCHEMA {schema}"))
+        .execute(&mut conn)
+        .await?;
+    conn.close().await?;
+
+    let separator = if url.contains('?') { '&' } else { '?' };
+    connect(&format!("{url}{separator}options[search_path]={schema}")).await
 }
 
-pub async fn init_db() -> anyhow::Result<Pool<Sqlite>> {
-    let pool = SqlitePoolOptions::new()
-        .max_connections(5)
-        .connect("sqlite::memory:")
+/// An in-memory sqlite database of its own, shared by the connections of a pool
+fn memory_url() -> String {
+    format!("sqlite:file:backup-sync-{}?mode=memory", Uuid::new_v4())
+}
+
+/// Connects to `url` and brings its schema up to date
+pub async fn connect(url: &str) -> anyhow::Result<Pool<Any>> {
+    install_default_drivers();
+
+    let migrator = if url.starts_with("sqlite:") {
+        &SQLITE_MIGRATIONS
+    } else if url.starts_with("postgres:") || url.starts_with("postgresql:") {
+        &POSTGRES_MIGRATIONS
+    } else {
+        bail!("Database URL must start with sqlite: or postgres:");
+    };
+
+    let mut options = AnyPoolOptions::new().max_connections(5);
+    if url.contains("mode=memory") || url.contains(":memory:") {
+        // An in-memory database is gone once its last connection closes
+        options = options
+            .min_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None);
+    }
+
+    let pool = options
+        .connect(url)
         .await
-        .context("Failed to connect to sqlite database")?;
+        .context("Failed to connect to database")?;
 
-    sqlx::migrate!("./migrations")
+    migrator
         .run(&pool)
         .await
         .context("Failed to run migrations")?;
@@ -1,14 +1,42 @@
 use axum::{
-    http::StatusCode,
+    http::{header, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
-use serde_json::json;
+use std::time::Duration;
+
+/// Body of every error response
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+pub struct ErrorBody {
+    /// Names the kind of failure, like `not_found`, `token_expired` or the folder rule broken
+    pub code: String,
+    pub message: String,
+    /// The fields at fault when `code` is `validation_failed`
+    #[serde(default)]
+    pub details: Vec<FieldIssue>,
+    /// The `x-request-id` of the request, to find it in the server logs
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// What is wrong with one field of a request
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+pub struct FieldIssue {
+    /// Path to the field in the body, query or route, like `name` or `items[0].id`
+    pub field: String,
+    pub issue: String,
+}
 
 #[derive(thiserror::Error, Debug)]
 pub enum ApiError {
     #[error("Authentication failed: {0}")]
     AuthenticationFailed(String),
+    #[error("Token expired")]
+    TokenExpired,
+    #[error("Invalid token")]
+    InvalidToken,
+    #[error("Token revoked")]
+    TokenRevoked,
     #[error("User not found")]
     UserNotFound,
     #[error("Permission denied: {0}")]
@@ -17,23 +45,94 @@ pub enum ApiError {
     NotFound(String),
     #[error("Invalid request: {0}")]
     InvalidRequest(String),
+    /// Fields of the request that don't hold acceptable values
+    #[error("Validation failed: {0:?}")]
+    Validation(Vec<FieldIssue>),
+    /// A request the extractors refused before reaching the handler, e.g. for not being
+    /// JSON, with the status they answer with
+    #[error("Rejected request: {2}")]
+    Rejected(StatusCode, &'static str, String),
     #[error("Conflict: {0}")]
     Conflict(String),
+    /// A request breaking one of the folder rules, with a code naming the rule
+    #[error("Rule violation: {1}")]
+    RuleViolation(&'static str, String),
+    /// The caller used up its rate limit, until a token is available again
+    #[error("Rate limited for {0:?}")]
+    RateLimited(Duration),
     #[error("Internal server error: {0}")]
     InternalError(#[from] anyhow::Error),
     #[error("Database error: {0}")]
     DatabaseError(#[from] sqlx::Error),
 }
 
+impl ApiError {
+    /// A validation failure of the single `field`
+    pub fn invalid_field(field: &str, issue: impl Into<String>) -> Self {
+        ApiError::Validation(vec![FieldIssue {
+            field: field.to_owned(),
+            issue: issue.into(),
+        }])
+    }
+
+    /// Lets clients act on the kind of failure, e.g. refresh an expired token
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::AuthenticationFailed(_) => "authentication_failed",
+            ApiError::TokenExpired => "token_expired",
+            ApiError::InvalidToken => "invalid_token",
+            ApiError::TokenRevoked => "token_revoked",
+            ApiError::UserNotFound => "user_not_found",
+            ApiError::PermissionDenied(_) => "permission_denied",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::InvalidRequest(_) => "invalid_request",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::Rejected(_, code, _) => code,
+            ApiError::Conflict(_) => "conflict",
+            ApiError::RuleViolation(code, _) => code,
+            ApiError::RateLimited(_) => "too_many_requests",
+            ApiError::InternalError(_) | ApiError::DatabaseError(_) => "internal_error",
+        }
+    }
+}
+
 impl IntoResponse for ApiError {
-    fn into_response(self) -> Response {
+    fn into_response(mut self) -> Response {
+        let code = self.code();
+        let details = match &mut self {
+            ApiError::Validation(issues) => std::mem::take(issues),
+            _ => Vec::new(),
+        };
+        // Whole seconds, rounded up so clients retrying on time are let through
+        let retry_after = match self {
+            ApiError::RateLimited(wait) => {
+                Some(wait.as_secs() + u64::from(wait.subsec_nanos() > 0))
+            }
+            _ => None,
+        };
+
         let (status, message) = match self {
             ApiError::AuthenticationFailed(msg) => (StatusCode::UNAUTHORIZED, msg),
+            ApiError::TokenExpired => (StatusCode::UNAUTHORIZED, "Token expired".to_string()),
+            ApiError::InvalidToken => (StatusCode::UNAUTHORIZED, "Invalid token".to_string()),
+            ApiError::TokenRevoked => (StatusCode::UNAUTHORIZED, "Token revoked".to_string()),
             ApiError::UserNotFound => (StatusCode::UNAUTHORIZED, "User not found".to_string()),
             ApiError::PermissionDenied(msg) => (StatusCode::FORBIDDEN, msg),
             ApiError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
             ApiError::InvalidRequest(msg) => (StatusCode::BAD_REQUEST, msg),
+            ApiError::Validation(_) => {
+                let issues: Vec<String> = details
+                    .iter()
+                    .map(|detail| format!("{}: {}", detail.field, detail.issue))
+                    .collect();
+                (StatusCode::BAD_REQUEST, issues.join("; "))
+            }
+            ApiError::Rejected(status, _, msg) => (status, msg),
             ApiError::Conflict(msg) => (StatusCode::CONFLICT, msg),
+            ApiError::RuleViolation(_, msg) => (StatusCode::CONFLICT, msg),
+            ApiError::RateLimited(_) => {
+                (StatusCode::TOO_MANY_REQUESTS, "Too many requests".to_string())
+            }
             ApiError::InternalError(err) => {
                 tracing::error!("Internal server error: {:?}", err);
                 (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error".to_string())
@@ -44,10 +143,19 @@ impl IntoResponse for ApiError {
             }
         };
 
-        let body = Json(json!({
-            "error": message
-        }));
+        // The request id is filled in by `middleware_layer::error_request_id`
+        let body = ErrorBody {
+            code: code.to_owned(),
+            message,
+            details,
+            request_id: None,
+        };
 
-        (status, body).into_response()
+        let mut response = (status, Json(body.clone())).into_response();
+        response.extensions_mut().insert(body);
+        if let Some(secs) = retry_after {
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response
     }
 }
@@ -0,0 +1,143 @@
+//! `Json`, `Path` and `Query` extractors answering malformed requests with an
+//! [`ErrorBody`](crate::error::ErrorBody), like every other error, rather than axum's
+//! plain-text rejections
+
+use crate::error::{ApiError, FieldIssue};
+use axum::{
+    extract::{
+        FromRequest, FromRequestParts, Request,
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+    },
+    http::request::Parts,
+    response::{IntoResponse, Response},
+};
+use serde::{Serialize, de::DeserializeOwned};
+use std::error::Error as StdError;
+
+/// A JSON body, and a JSON response like [`axum::Json`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned, S: Send + Sync> FromRequest<S> for Json<T> {
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::Json(value) = axum::Json::from_request(req, state).await?;
+        Ok(Json(value))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+/// Parameters of the route, like [`axum::extract::Path`]
+#[derive(Debug)]
+pub struct Path<T>(pub T);
+
+impl<T: DeserializeOwned + Send, S: Send + Sync> FromRequestParts<S> for Path<T> {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Path(value) =
+            axum::extract::Path::from_request_parts(parts, state).await?;
+        Ok(Path(value))
+    }
+}
+
+/// The query string, like [`axum::extract::Query`]
+#[derive(Debug)]
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned, S: Send + Sync> FromRequestParts<S> for Query<T> {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Query(value) =
+            axum::extract::Query::from_request_parts(parts, state).await?;
+        Ok(Query(value))
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        match rejection {
+            JsonRejection::JsonDataError(ref err) => ApiError::Validation(vec![
+                field_issue::<serde_json::Error>(err, "body").unwrap_or_else(|| FieldIssue {
+                    field: "body".to_owned(),
+                    issue: rejection.body_text(),
+                }),
+            ]),
+            JsonRejection::JsonSyntaxError(_) => {
+                ApiError::Rejected(rejection.status(), "invalid_json", rejection.body_text())
+            }
+            JsonRejection::MissingJsonContentType(_) => ApiError::Rejected(
+                rejection.status(),
+                "unsupported_media_type",
+                rejection.body_text(),
+            ),
+            _ => ApiError::Rejected(rejection.status(), "invalid_request", rejection.body_text()),
+        }
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        use axum::extract::path::ErrorKind;
+
+        let PathRejection::FailedToDeserializePathParams(err) = &rejection else {
+            return ApiError::Rejected(
+                rejection.status(),
+                "invalid_request",
+                rejection.body_text(),
+            );
+        };
+        let (field, issue) = match err.kind() {
+            ErrorKind::ParseErrorAtKey {
+                key, expected_type, ..
+            } => (key.clone(), format!("expected {expected_type}")),
+            ErrorKind::DeserializeError { key, message, .. } => (key.clone(), message.clone()),
+            ErrorKind::InvalidUtf8InPathParam { key } => (key.clone(), "invalid UTF-8".to_owned()),
+            kind => ("path".to_owned(), kind.to_string()),
+        };
+        ApiError::Validation(vec![FieldIssue { field, issue }])
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        let issue = field_issue::<serde_urlencoded::de::Error>(&rejection, "query");
+        match issue {
+            Some(issue) => ApiError::Validation(vec![issue]),
+            None => {
+                ApiError::Rejected(rejection.status(), "invalid_request", rejection.body_text())
+            }
+        }
+    }
+}
+
+/// The field a deserialization error of axum points at, `whole` standing for the whole
+/// body or query string
+fn field_issue<E: StdError + 'static>(
+    rejection: &(dyn StdError + 'static),
+    whole: &str,
+) -> Option<FieldIssue> {
+    let mut source = rejection.source();
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<serde_path_to_error::Error<E>>() {
+            let field = err.path().to_string();
+            return Some(FieldIssue {
+                field: if field == "." {
+                    whole.to_owned()
+                } else {
+                    field
+                },
+                issue: err.inner().to_string(),
+            });
+        }
+        source = err.source();
+    }
+    None
+}
@@ -0,0 +1,101 @@
+use crate::AppState;
+use crate::auth::{RequireAdmin, Role};
+use crate::error::ApiError;
+use crate::extract::{Json, Path, Query};
+use crate::handlers::audit_handler::AuditAction;
+use crate::handlers::pagination::ListQuery;
+use axum::{Extension, extract::State, http::StatusCode, response::IntoResponse};
+use backup_sync_protocol::{SyncFolder, UserId};
+use tower_http::request_id::RequestId;
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct AdminUser {
+    pub id: UserId,
+    pub name: String,
+    pub role: Role,
+}
+
+/// A folder of any user
+#[derive(Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct AdminFolder {
+    #[serde(flatten)]
+    pub folder: SyncFolder,
+    /// The user whose computer the folder originates from
+    pub owner: UserId,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct UpdateUserRequest {
+    pub role: Role,
+}
+
+/// Lists every user a page at a time, sortable by `name`, `role` or `id`
+pub async fn list_users(
+    State(state): State<AppState>,
+    RequireAdmin(_): RequireAdmin,
+    Query(query): Query<ListQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    query.validate()?;
+    let users = crate::logic::admin::list_users_page(&state.db, &query).await?;
+
+    Ok((StatusCode::OK, Json(users)))
+}
+
+/// Lists the folders of every user a page at a time, sortable like `GET /folders`
+pub async fn list_folders(
+    State(state): State<AppState>,
+    RequireAdmin(_): RequireAdmin,
+    Query(query): Query<ListQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    query.validate()?;
+    let folders = crate::logic::admin::list_folders_page(&state.db, &query).await?;
+
+    Ok((StatusCode::OK, Json(folders)))
+}
+
+/// Promotes a user to admin or demotes them. Tokens they already hold keep their old
+/// role until refreshed.
+pub async fn update_user(
+    State(state): State<AppState>,
+    RequireAdmin(claims): RequireAdmin,
+    Extension(request_id): Extension<RequestId>,
+    Path(user_id): Path<UserId>,
+    Json(payload): Json<UpdateUserRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user_id = user_id.to_string();
+    let user =
+        crate::logic::admin::set_role(&state.db, &claims.sub, &user_id, payload.role).await?;
+    state
+        .audit(
+            &request_id,
+            &claims.sub,
+            AuditAction::RoleChanged,
+            None,
+            Some(&user_id),
+        )
+        .await;
+
+    Ok((StatusCode::OK, Json(user)))
+}
+
+/// Deletes a user and everything they own
+pub async fn delete_user(
+    State(state): State<AppState>,
+    RequireAdmin(claims): RequireAdmin,
+    Extension(request_id): Extension<RequestId>,
+    Path(user_id): Path<UserId>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user_id = user_id.to_string();
+    crate::logic::admin::delete_user(&state.db, &claims.sub, &user_id).await?;
+    state
+        .audit(
+            &request_id,
+            &claims.sub,
+            AuditAction::UserDeleted,
+            None,
+            Some(&user_id),
+        )
+        .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
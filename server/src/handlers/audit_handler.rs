@@ -0,0 +1,121 @@
+use crate::error::ApiError;
+use crate::extract::{Json, Query};
+use crate::{AppState, auth::Claims};
+use axum::{Extension, extract::State, http::StatusCode, response::IntoResponse};
+use backup_sync_protocol::ComputerId;
+
+/// Entries returned when the request names no `limit`
+pub const DEFAULT_AUDIT_LIMIT: u32 = 50;
+pub const MAX_AUDIT_LIMIT: u32 = 200;
+
+/// What a user did, or tried to do
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    ComputerRegistered,
+    ComputerRemoved,
+    ComputerRestored,
+    FolderCreated,
+    FolderDeleted,
+    FolderRestored,
+    FolderJoined,
+    FolderLeft,
+    OriginSwitched,
+    LoginFailed,
+    /// An admin changed the role of the user in `target`
+    RoleChanged,
+    /// An admin deleted the user in `target`
+    UserDeleted,
+    /// The folder owner invited another user to back up the folder in `target`
+    InviteSent,
+    /// The user accepted an invite to back up the folder in `target`
+    InviteAccepted,
+    /// The user declined an invite to back up the folder in `target`
+    InviteDeclined,
+}
+
+impl AuditAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditAction::ComputerRegistered => "computer_registered",
+            AuditAction::ComputerRemoved => "computer_removed",
+            AuditAction::ComputerRestored => "computer_restored",
+            AuditAction::FolderCreated => "folder_created",
+            AuditAction::FolderDeleted => "folder_deleted",
+            AuditAction::FolderRestored => "folder_restored",
+            AuditAction::FolderJoined => "folder_joined",
+            AuditAction::FolderLeft => "folder_left",
+            AuditAction::OriginSwitched => "origin_switched",
+            AuditAction::LoginFailed => "login_failed",
+            AuditAction::RoleChanged => "role_changed",
+            AuditAction::UserDeleted => "user_deleted",
+            AuditAction::InviteSent => "invite_sent",
+            AuditAction::InviteAccepted => "invite_accepted",
+            AuditAction::InviteDeclined => "invite_declined",
+        }
+    }
+
+    pub fn parse(action: &str) -> Option<Self> {
+        Some(match action {
+            "computer_registered" => AuditAction::ComputerRegistered,
+            "computer_removed" => AuditAction::ComputerRemoved,
+            "computer_restored" => AuditAction::ComputerRestored,
+            "folder_created" => AuditAction::FolderCreated,
+            "folder_deleted" => AuditAction::FolderDeleted,
+            "folder_restored" => AuditAction::FolderRestored,
+            "folder_joined" => AuditAction::FolderJoined,
+            "folder_left" => AuditAction::FolderLeft,
+            "origin_switched" => AuditAction::OriginSwitched,
+            "login_failed" => AuditAction::LoginFailed,
+            "role_changed" => AuditAction::RoleChanged,
+            "user_deleted" => AuditAction::UserDeleted,
+            "invite_sent" => AuditAction::InviteSent,
+            "invite_accepted" => AuditAction::InviteAccepted,
+            "invite_declined" => AuditAction::InviteDeclined,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub action: AuditAction,
+    /// The computer the action was about or done through, if any
+    pub computer_id: Option<ComputerId>,
+    /// The folder acted on, if any
+    pub target: Option<String>,
+    /// In seconds since the Unix epoch
+    pub created_at: i64,
+    /// The `x-request-id` of the request that did it
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct AuditQuery {
+    pub limit: Option<u32>,
+    /// Only entries older than the one with this id, to page through the log
+    pub before: Option<u64>,
+}
+
+/// Lists the caller's audit log, most recent entries first
+pub async fn get_audit_log(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Query(query): Query<AuditQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let limit = query.limit.unwrap_or(DEFAULT_AUDIT_LIMIT);
+    if !(1..=MAX_AUDIT_LIMIT).contains(&limit) {
+        return Err(ApiError::invalid_field(
+            "limit",
+            format!("must be between 1 and {MAX_AUDIT_LIMIT}"),
+        ));
+    }
+
+    let entries =
+        crate::logic::audit::list_entries(&state.db, &claims.sub, limit, query.before).await?;
+
+    Ok((StatusCode::OK, Json(entries)))
+}
@@ -1,5 +1,8 @@
 use crate::auth::Claims;
 use crate::error::ApiError;
+use crate::extract::Json;
+use crate::handlers::audit_handler::AuditAction;
+use crate::logic::unix_now;
 use crate::AppState;
 use anyhow::Context;
 use argon2::{
@@ -7,40 +10,68 @@ use argon2::{
     Argon2,
 };
 use axum::{
-    extract::{Json, State},
+    extract::State,
+    Extension,
     http::StatusCode,
     response::IntoResponse,
-    routing::post,
+    routing::{post, MethodRouter},
     Router,
 };
+use backup_sync_protocol::UserId;
 // Assuming these exist, but we might need DTOs
 use jsonwebtoken::{encode, EncodingKey, Header};
-use std::time::{SystemTime, UNIX_EPOCH};
+use tower_http::request_id::RequestId;
 use uuid::Uuid;
 
 // DTOs
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
 pub struct RegisterUserRequest {
+    #[serde(alias = "username")]
     pub name: String,
     pub password: String,
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
 pub struct LoginRequest {
+    #[serde(alias = "username")]
     pub name: String,
     pub password: String,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+pub struct RegisterResponse {
+    pub id: UserId,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 pub struct AuthResponse {
     pub token: String,
-    pub user_id: String,
+    pub user_id: UserId,
+    /// Seconds until `token` expires
+    pub expires_in: u64,
+    /// Opaque token to exchange at `/auth/refresh` for new tokens, valid once
+    pub refresh_token: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
 }
 
 pub async fn register(
     State(state): State<AppState>,
     Json(payload): Json<RegisterUserRequest>,
 ) -> Result<impl IntoResponse, ApiError> {
+    // Checked before hashing so duplicates are cheap to turn down; the unique index
+    // still catches concurrent signups for the same name
+    let taken: Option<String> = sqlx::query_scalar("SELECT id FROM users WHERE name = $1")
+        .bind(&payload.name)
+        .fetch_optional(&state.db)
+        .await?;
+    if taken.is_some() {
+        return Err(name_taken());
+    }
+
     let user_id = Uuid::new_v4().to_string();
     let salt = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
@@ -49,35 +80,43 @@ pub async fn register(
         .map_err(|e| ApiError::InternalError(anyhow::anyhow!(e)))?
         .to_string();
 
-    sqlx::query!(
-        "INSERT INTO users (id, name, password_hash) VALUES (?, ?, ?)",
-        user_id,
-        payload.name,
-        password_hash
-    )
-    .execute(&state.db)
-    .await?;
+    sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+        .bind(&user_id)
+        .bind(&payload.name)
+        .bind(password_hash)
+        .execute(&state.db)
+        .await
+        .map_err(|e| {
+            if e.as_database_error()
+                .is_some_and(sqlx::error::DatabaseError::is_unique_violation)
+            {
+                name_taken()
+            } else {
+                ApiError::DatabaseError(e)
+            }
+        })?;
 
     Ok((
         StatusCode::CREATED,
-        Json(serde_json::json!({ "id": user_id })),
+        Json(RegisterResponse { id: user_id.into() }),
     ))
 }
 
+fn name_taken() -> ApiError {
+    ApiError::Conflict("User name already taken".to_string())
+}
+
 pub async fn login(
     State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
     Json(payload): Json<LoginRequest>,
 ) -> Result<impl IntoResponse, ApiError> {
-    let option = sqlx::query!(
-        "SELECT id, password_hash FROM users WHERE name = ?",
-        payload.name
-    )
-    .fetch_optional(&state.db)
-    .await?
-    .ok_or(ApiError::AuthenticationFailed("User not found".to_string()))?;
-
-    let id = option.id;
-    let hash = option.password_hash;
+    let (id, hash): (String, String) =
+        sqlx::query_as("SELECT id, password_hash FROM users WHERE name = $1")
+            .bind(&payload.name)
+            .fetch_optional(&state.db)
+            .await?
+            .ok_or(ApiError::AuthenticationFailed("User not found".to_string()))?;
 
     let parsed_hash =
         PasswordHash::new(&hash).map_err(|e| ApiError::InternalError(anyhow::anyhow!(e)))?;
@@ -86,36 +125,120 @@ pub async fn login(
         .verify_password(payload.password.as_bytes(), &parsed_hash)
         .is_ok()
     {
-        let expiration = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .context("System time is before UNIX EPOCH")
-            .map_err(ApiError::InternalError)?
-            .as_secs() as usize
-            + 3600 * 24; // 24 hours
-
-        let claims = Claims {
-            sub: id.clone(),
-            exp: expiration,
-        };
-
-        let token = encode(
-            &Header::default(),
-            &claims,
-            &EncodingKey::from_secret(state.jwt_secret.as_bytes()),
-        )
-        .context("Failed to encode token")
-        .map_err(ApiError::InternalError)?;
-
-        Ok((StatusCode::OK, Json(AuthResponse { token, user_id: id })))
+        let auth = issue_tokens(&state, id).await?;
+        Ok((StatusCode::OK, Json(auth)))
     } else {
+        state
+            .audit(&request_id, &id, AuditAction::LoginFailed, None, None)
+            .await;
         Err(ApiError::AuthenticationFailed(
             "Invalid credentials".to_string(),
         ))
     }
 }
 
+/// Exchanges a refresh token for a new access token and a new refresh token, revoking
+/// the one presented
+pub async fn refresh(
+    State(state): State<AppState>,
+    Json(payload): Json<RefreshRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user_id = crate::logic::token::consume_refresh_token(
+        &state.db,
+        &payload.refresh_token,
+        unix_now()?,
+    )
+    .await?;
+
+    let auth = issue_tokens(&state, user_id).await?;
+    Ok((StatusCode::OK, Json(auth)))
+}
+
+/// Revokes the presented refresh token along with the access token of the request
+pub async fn logout(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<RefreshRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    crate::logic::token::revoke_refresh_token(&state.db, &claims.sub, &payload.refresh_token)
+        .await?;
+    revoke_access_token(&state, &claims).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Revokes every refresh token of the user, so other sessions end once their access
+/// tokens expire
+pub async fn logout_all(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, ApiError> {
+    crate::logic::token::revoke_all_refresh_tokens(&state.db, &claims.sub).await?;
+    revoke_access_token(&state, &claims).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn revoke_access_token(state: &AppState, claims: &Claims) -> Result<(), ApiError> {
+    if let Some(jti) = &claims.jti {
+        crate::logic::token::revoke_access_token(&state.db, jti, claims.exp as i64, unix_now()?)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn issue_tokens(state: &AppState, user_id: String) -> Result<AuthResponse, ApiError> {
+    let now = unix_now()?;
+    let expires_in = state.access_token_ttl.as_secs();
+
+    let claims = Claims {
+        sub: user_id.clone(),
+        exp: (now as u64 + expires_in) as usize,
+        jti: Some(Uuid::new_v4().to_string()),
+        role: crate::logic::user::get_role(&state.db, &user_id).await?,
+    };
+
+    let token = encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(state.jwt_secret.as_bytes()),
+    )
+    .context("Failed to encode token")
+    .map_err(ApiError::InternalError)?;
+
+    let refresh_token = crate::logic::token::create_refresh_token(
+        &state.db,
+        &user_id,
+        now + state.refresh_token_ttl.as_secs() as i64,
+    )
+    .await?;
+
+    Ok(AuthResponse {
+        token,
+        user_id: user_id.into(),
+        expires_in,
+        refresh_token,
+    })
+}
+
+/// Routes of [`login`], rate limited on their own
+pub const LOGIN_PATHS: [&str; 2] = ["/login", "/auth/login"];
+/// Routes of [`register`], rate limited on their own
+pub const SIGNUP_PATHS: [&str; 2] = ["/register", "/auth/signup"];
+
+/// Routes of the handlers needing no token, by path
+pub fn routes() -> Vec<(&'static str, MethodRouter<AppState>)> {
+    vec![
+        (SIGNUP_PATHS[0], post(register)),
+        (LOGIN_PATHS[0], post(login)),
+        (SIGNUP_PATHS[1], post(register)),
+        (LOGIN_PATHS[1], post(login)),
+        ("/auth/refresh", post(refresh)),
+    ]
+}
+
 pub fn router() -> Router<AppState> {
-    Router::new()
-        .route("/register", post(register))
-        .route("/login", post(login))
+    routes()
+        .into_iter()
+        .fold(Router::new(), |router, (path, route)| router.route(path, route))
 }
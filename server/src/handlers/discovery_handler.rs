@@ -0,0 +1,32 @@
+use crate::API_PREFIX;
+use crate::extract::Json;
+use axum::response::IntoResponse;
+
+/// A route of the API, its path including the version prefix
+#[derive(Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct ApiRoute {
+    pub method: String,
+    pub path: String,
+    pub summary: String,
+    pub deprecated: bool,
+}
+
+/// What the version of the API served at its prefix offers
+#[derive(Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct ApiDiscovery {
+    /// Version of the API, like `v1`
+    pub api_version: String,
+    /// Version of the server
+    pub server_version: String,
+    pub routes: Vec<ApiRoute>,
+}
+
+/// Lists the routes of the API along with the server version, for clients to find out
+/// what they can use
+pub async fn discover() -> impl IntoResponse {
+    Json(ApiDiscovery {
+        api_version: API_PREFIX.trim_start_matches('/').to_owned(),
+        server_version: env!("CARGO_PKG_VERSION").to_owned(),
+        routes: crate::api_docs::routes(),
+    })
+}
@@ -1,50 +1,188 @@
 use crate::error::ApiError;
+use crate::extract::{Json, Path, Query};
+use crate::handlers::audit_handler::AuditAction;
+use crate::handlers::pagination::ListQuery;
 use crate::{auth::Claims, AppState};
+use backup_sync_protocol::{ComputerId, FolderId, SyncFolder};
 use axum::{
-    extract::{Path, State}, http::StatusCode,
+    extract::State, http::StatusCode,
     response::IntoResponse,
     Extension,
-    Json,
 };
+use tower_http::request_id::RequestId;
 
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
 pub struct CreateFolderRequest {
     pub name: String,
-    pub computer_id: String,
+    pub computer_id: ComputerId,
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
 pub struct JoinFolderRequest {
-    pub computer_id: String,
+    pub computer_id: ComputerId,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    serde::Deserialize,
+    serde::Serialize,
+    schemars::JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum MemberRole {
+    Origin,
+    Backup,
+}
+
+/// A computer taking part in a folder
+#[derive(Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct FolderMember {
+    pub computer_id: ComputerId,
+    pub name: String,
+    pub role: MemberRole,
+    /// When the computer joined the folder, in seconds since the Unix epoch
+    pub joined_at: Option<i64>,
+    pub online: bool,
+}
+
+/// A folder with its members, origin first
+#[derive(Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct FolderDetail {
+    #[serde(flatten)]
+    pub folder: SyncFolder,
+    pub members: Vec<FolderMember>,
+}
+
+/// Operations recently listed when the request names no `limit`
+pub const DEFAULT_OPERATIONS_LIMIT: u32 = 20;
+pub const MAX_OPERATIONS_LIMIT: u32 = 200;
+
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct OperationsQuery {
+    pub limit: Option<u32>,
+}
+
+/// How far one backup is behind the origin
+#[derive(Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct BackupLag {
+    pub computer_id: ComputerId,
+    pub pending_operations: u64,
+    pub last_acked_operation: Option<u64>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct OperationRecord {
+    pub id: u64,
+    /// The kind of file operation, e.g. `CreateFile`
+    pub kind: String,
+    /// In seconds since the Unix epoch
+    pub created_at: i64,
+    pub acked_by: Vec<ComputerId>,
+}
+
+/// Why a folder is, or is not, synced
+#[derive(Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct FolderOperations {
+    pub pending_operations: u64,
+    /// When the oldest pending operation was created, in seconds since the Unix epoch
+    pub oldest_pending_at: Option<i64>,
+    pub backups: Vec<BackupLag>,
+    /// Most recent operations first
+    pub recent: Vec<OperationRecord>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct UpdateFolderRequest {
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct SwitchOriginRequest {
+    pub new_origin: ComputerId,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct DeleteFolderQuery {
+    #[serde(default)]
+    pub force: bool,
 }
 
 pub async fn create_folder(
     State(state): State<AppState>,
     Extension(claims): Extension<Claims>,
+    Extension(request_id): Extension<RequestId>,
     Json(payload): Json<CreateFolderRequest>,
 ) -> Result<impl IntoResponse, ApiError> {
+    let computer_id = payload.computer_id.to_string();
     let folder = crate::logic::folder::create_folder(
         &state.db,
         &claims.sub,
         &payload.name,
-        &payload.computer_id
+        &computer_id
     ).await?;
+    state.audit(
+        &request_id,
+        &claims.sub,
+        AuditAction::FolderCreated,
+        Some(&computer_id),
+        Some(&folder.id.to_string()),
+    ).await;
     
     Ok((StatusCode::CREATED, Json(folder)))
 }
 
+/// Creates the folders of the request in one go, in the order given. None of them is
+/// created when one is refused.
+pub async fn create_folders(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Extension(request_id): Extension<RequestId>,
+    Json(payload): Json<Vec<CreateFolderRequest>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let folders: Vec<(String, String)> = payload
+        .into_iter()
+        .map(|folder| (folder.name, folder.computer_id.to_string()))
+        .collect();
+    let created = crate::logic::folder::create_folders(&state.db, &claims.sub, &folders).await?;
+    for folder in &created {
+        state.audit(
+            &request_id,
+            &claims.sub,
+            AuditAction::FolderCreated,
+            Some(&folder.origin_computer.to_string()),
+            Some(&folder.id.to_string()),
+        ).await;
+    }
+
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
 pub async fn join_folder(
     State(state): State<AppState>,
     Extension(claims): Extension<Claims>,
-    Path(folder_id): Path<String>,
+    Extension(request_id): Extension<RequestId>,
+    Path(folder_id): Path<FolderId>,
     Json(payload): Json<JoinFolderRequest>,
 ) -> Result<impl IntoResponse, ApiError> {
+    let (folder_id, computer_id) = (folder_id.to_string(), payload.computer_id.to_string());
     let message = crate::logic::folder::join_folder(
         &state.db,
         &claims.sub,
         &folder_id,
-        &payload.computer_id
+        &computer_id
     ).await?;
+    state.audit(
+        &request_id,
+        &claims.sub,
+        AuditAction::FolderJoined,
+        Some(&computer_id),
+        Some(&folder_id),
+    ).await;
     
     Ok((StatusCode::OK, message))
 }
@@ -52,26 +190,162 @@ pub async fn join_folder(
 pub async fn leave_folder(
     State(state): State<AppState>,
     Extension(claims): Extension<Claims>,
-    Path(folder_id): Path<String>,
+    Extension(request_id): Extension<RequestId>,
+    Path(folder_id): Path<FolderId>,
     Json(payload): Json<JoinFolderRequest>, // Reusing struct as it has computer_id
 ) -> Result<impl IntoResponse, ApiError> {
+    let (folder_id, computer_id) = (folder_id.to_string(), payload.computer_id.to_string());
     crate::logic::folder::leave_folder(
         &state.db,
         &claims.sub,
         &folder_id,
-        &payload.computer_id
+        &computer_id
     ).await?;
+    state.audit(
+        &request_id,
+        &claims.sub,
+        AuditAction::FolderLeft,
+        Some(&computer_id),
+        Some(&folder_id),
+    ).await;
     
     Ok((StatusCode::NO_CONTENT, ""))
 }
 
+pub async fn get_folder(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(folder_id): Path<FolderId>,
+) -> Result<impl IntoResponse, ApiError> {
+    let folder = crate::logic::folder::get_folder_detail(
+        &state.db,
+        &claims.sub,
+        &folder_id.to_string(),
+        state.online_window
+    ).await?;
+
+    Ok((StatusCode::OK, Json(folder)))
+}
+
+pub async fn get_folder_operations(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(folder_id): Path<FolderId>,
+    Query(query): Query<OperationsQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let limit = query.limit.unwrap_or(DEFAULT_OPERATIONS_LIMIT);
+    if !(1..=MAX_OPERATIONS_LIMIT).contains(&limit) {
+        return Err(ApiError::invalid_field(
+            "limit",
+            format!("must be between 1 and {MAX_OPERATIONS_LIMIT}"),
+        ));
+    }
+
+    let operations = crate::logic::operation::get_folder_operations(
+        &state.db,
+        &claims.sub,
+        &folder_id.to_string(),
+        limit
+    ).await?;
+
+    Ok((StatusCode::OK, Json(operations)))
+}
+
+pub async fn update_folder(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(folder_id): Path<FolderId>,
+    Json(payload): Json<UpdateFolderRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let folder = crate::logic::folder::update_folder(
+        &state.db,
+        &claims.sub,
+        &folder_id.to_string(),
+        payload.name.as_deref()
+    ).await?;
+
+    Ok((StatusCode::OK, Json(folder)))
+}
+
+pub async fn switch_origin(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Extension(request_id): Extension<RequestId>,
+    Path(folder_id): Path<FolderId>,
+    Json(payload): Json<SwitchOriginRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let (folder_id, new_origin) = (folder_id.to_string(), payload.new_origin.to_string());
+    let folder = crate::logic::folder::switch_origin(
+        &state.db,
+        &claims.sub,
+        &folder_id,
+        &new_origin
+    ).await?;
+    state.audit(
+        &request_id,
+        &claims.sub,
+        AuditAction::OriginSwitched,
+        Some(&new_origin),
+        Some(&folder_id),
+    ).await;
+
+    Ok((StatusCode::OK, Json(folder)))
+}
+
+pub async fn delete_folder(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Extension(request_id): Extension<RequestId>,
+    Path(folder_id): Path<FolderId>,
+    Query(query): Query<DeleteFolderQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let folder_id = folder_id.to_string();
+    crate::logic::folder::delete_folder(
+        &state.db,
+        &claims.sub,
+        &folder_id,
+        query.force
+    ).await?;
+    state
+        .audit(&request_id, &claims.sub, AuditAction::FolderDeleted, None, Some(&folder_id))
+        .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Undoes `DELETE /folders/{id}` within the retention window
+pub async fn restore_folder(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Extension(request_id): Extension<RequestId>,
+    Path(folder_id): Path<FolderId>,
+) -> Result<impl IntoResponse, ApiError> {
+    let folder_id = folder_id.to_string();
+    let folder = crate::logic::folder::restore_folder(
+        &state.db,
+        &claims.sub,
+        &folder_id,
+        state.deleted_retention
+    ).await?;
+    state
+        .audit(&request_id, &claims.sub, AuditAction::FolderRestored, None, Some(&folder_id))
+        .await;
+
+    Ok((StatusCode::OK, Json(folder)))
+}
+
+/// Lists the user's folders a page at a time, sortable by `name`, `pending_operations`
+/// or `id`
 pub async fn list_folders(
     State(state): State<AppState>,
     Extension(claims): Extension<Claims>,
+    Query(query): Query<ListQuery>,
 ) -> Result<impl IntoResponse, ApiError> {
-    let folders = crate::logic::folder::get_folders_by_user(
+    query.validate()?;
+    let folders = crate::logic::folder::list_folders_page(
         &state.db,
-        &claims.sub
+        &claims.sub,
+        &query
     ).await?;
     
     Ok((StatusCode::OK, Json(folders)))
@@ -80,12 +354,12 @@ pub async fn list_folders(
 pub async fn list_folders_for_computer(
     State(state): State<AppState>,
     Extension(claims): Extension<Claims>,
-    Path(computer_id): Path<String>,
+    Path(computer_id): Path<ComputerId>,
 ) -> Result<impl IntoResponse, ApiError> {
     let folders = crate::logic::folder::get_folders_by_computer(
         &state.db,
         &claims.sub,
-        &computer_id
+        &computer_id.to_string()
     ).await?;
     
     Ok((StatusCode::OK, Json(folders)))
@@ -0,0 +1,158 @@
+use crate::error::ApiError;
+use crate::extract::{Json, Path};
+use crate::handlers::audit_handler::AuditAction;
+use crate::{AppState, auth::Claims};
+use axum::{Extension, extract::State, http::StatusCode, response::IntoResponse};
+use backup_sync_protocol::FolderId;
+use tower_http::request_id::RequestId;
+
+#[derive(serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct CreateInviteRequest {
+    /// Name of the user to invite
+    pub username: String,
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum InviteStatus {
+    Pending,
+    Accepted,
+    Declined,
+}
+
+impl InviteStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            InviteStatus::Pending => "pending",
+            InviteStatus::Accepted => "accepted",
+            InviteStatus::Declined => "declined",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "pending" => Some(InviteStatus::Pending),
+            "accepted" => Some(InviteStatus::Accepted),
+            "declined" => Some(InviteStatus::Declined),
+            _ => None,
+        }
+    }
+}
+
+/// An invite for a user's computers to back up the folder of another user
+#[derive(Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct Invite {
+    pub id: String,
+    pub folder_id: FolderId,
+    pub folder_name: String,
+    /// Name of the folder's owner, who sent the invite
+    pub inviter: String,
+    /// Name of the invited user
+    pub invitee: String,
+    pub status: InviteStatus,
+    /// In seconds since the Unix epoch
+    pub created_at: i64,
+    /// When a pending invite can no longer be answered, in seconds since the Unix epoch
+    pub expires_at: i64,
+}
+
+/// Invites a user to back up one of the caller's folders
+pub async fn create_invite(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Extension(request_id): Extension<RequestId>,
+    Path(folder_id): Path<FolderId>,
+    Json(payload): Json<CreateInviteRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let folder_id = folder_id.to_string();
+    let invite = crate::logic::invite::create_invite(
+        &state.db,
+        &claims.sub,
+        &folder_id,
+        &payload.username,
+        state.invite_ttl,
+    )
+    .await?;
+    state
+        .audit(
+            &request_id,
+            &claims.sub,
+            AuditAction::InviteSent,
+            None,
+            Some(&folder_id),
+        )
+        .await;
+
+    Ok((StatusCode::CREATED, Json(invite)))
+}
+
+/// Lists the pending invites addressed to the caller, most recent first
+pub async fn list_invites(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, ApiError> {
+    let invites = crate::logic::invite::list_invites(&state.db, &claims.sub).await?;
+
+    Ok((StatusCode::OK, Json(invites)))
+}
+
+/// Accepts an invite, letting the caller's computers join the folder as backups
+pub async fn accept_invite(
+    state: State<AppState>,
+    claims: Extension<Claims>,
+    request_id: Extension<RequestId>,
+    Path(invite_id): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    answer_invite(
+        state,
+        claims,
+        request_id,
+        &invite_id,
+        InviteStatus::Accepted,
+    )
+    .await
+}
+
+pub async fn decline_invite(
+    state: State<AppState>,
+    claims: Extension<Claims>,
+    request_id: Extension<RequestId>,
+    Path(invite_id): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    answer_invite(
+        state,
+        claims,
+        request_id,
+        &invite_id,
+        InviteStatus::Declined,
+    )
+    .await
+}
+
+async fn answer_invite(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Extension(request_id): Extension<RequestId>,
+    invite_id: &str,
+    answer: InviteStatus,
+) -> Result<(StatusCode, Json<Invite>), ApiError> {
+    let invite =
+        crate::logic::invite::answer_invite(&state.db, &claims.sub, invite_id, answer).await?;
+    let action = match answer {
+        InviteStatus::Accepted => AuditAction::InviteAccepted,
+        _ => AuditAction::InviteDeclined,
+    };
+    state
+        .audit(
+            &request_id,
+            &claims.sub,
+            action,
+            None,
+            Some(&invite.folder_id.to_string()),
+        )
+        .await;
+
+    Ok((StatusCode::OK, Json(invite)))
+}
@@ -0,0 +1,41 @@
+use crate::AppState;
+use crate::error::ApiError;
+use axum::{
+    extract::State,
+    http::{HeaderMap, header},
+    response::IntoResponse,
+};
+
+/// The metrics for Prometheus to scrape. With `METRICS_TOKEN` set, they need it as a
+/// bearer token.
+pub async fn scrape(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ApiError> {
+    if let Some(metrics_token) = &state.metrics_token {
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if !token.is_some_and(|token| constant_time_eq(token.trim(), metrics_token)) {
+            return Err(ApiError::AuthenticationFailed(
+                "Invalid metrics token".to_owned(),
+            ));
+        }
+    }
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.to_prometheus(),
+    ))
+}
+
+/// Compares without returning early, so the time taken doesn't tell how much of a
+/// guessed token is right
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
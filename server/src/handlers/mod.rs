@@ -1,3 +1,10 @@
+pub mod admin_handler;
+pub mod audit_handler;
 pub mod auth_handler;
+pub mod discovery_handler;
 pub mod folder_handler;
+pub mod invite_handler;
+pub mod metrics_handler;
+pub mod pagination;
 pub mod user_handler;
+pub mod ws_handler;
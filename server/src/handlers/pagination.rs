@@ -0,0 +1,97 @@
+use crate::error::ApiError;
+
+/// Items returned when a list request names no `limit`, enough for any existing client
+pub const DEFAULT_LIMIT: u32 = 1000;
+pub const MAX_LIMIT: u32 = 1000;
+pub const MAX_OFFSET: u32 = 1_000_000;
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Deserialize,
+    serde::Serialize,
+    schemars::JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub fn sql(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+/// Query parameters shared by the list endpoints
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct ListQuery {
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+    /// Field to sort by, each endpoint documents the ones it accepts
+    pub sort: Option<String>,
+    pub order: Option<SortOrder>,
+    /// Only items whose name contains this, ignoring ASCII case
+    pub name: Option<String>,
+}
+
+impl ListQuery {
+    pub fn limit(&self) -> u32 {
+        self.limit.unwrap_or(DEFAULT_LIMIT)
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.offset.unwrap_or(0)
+    }
+
+    pub fn validate(&self) -> Result<(), ApiError> {
+        if !(1..=MAX_LIMIT).contains(&self.limit()) {
+            return Err(ApiError::invalid_field(
+                "limit",
+                format!("must be between 1 and {MAX_LIMIT}"),
+            ));
+        }
+        if self.offset() > MAX_OFFSET {
+            return Err(ApiError::invalid_field(
+                "offset",
+                format!("must be at most {MAX_OFFSET}"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Maps `sort` to one of `columns`, pairs of accepted field and SQL column, falling
+    /// back to the first one
+    pub fn sort_column(&self, columns: &[(&str, &'static str)]) -> Result<&'static str, ApiError> {
+        let Some(sort) = &self.sort else {
+            return Ok(columns[0].1);
+        };
+        columns
+            .iter()
+            .find(|(field, _)| field == sort)
+            .map(|(_, column)| *column)
+            .ok_or_else(|| {
+                let fields: Vec<&str> = columns.iter().map(|(field, _)| *field).collect();
+                ApiError::invalid_field("sort", format!("must be one of {}", fields.join(", ")))
+            })
+    }
+}
+
+/// One page of a list endpoint
+#[derive(Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Items matching the filter, across every page
+    pub total: u64,
+    pub limit: u32,
+    pub offset: u32,
+}
@@ -1,55 +1,138 @@
 use crate::error::ApiError;
+use crate::extract::{Json, Path, Query};
+use crate::handlers::audit_handler::AuditAction;
+use crate::handlers::pagination::ListQuery;
 use crate::{auth::Claims, AppState};
+use backup_sync_protocol::ComputerId;
 use axum::{
-    extract::{Path, State}, http::StatusCode,
+    extract::State, http::StatusCode,
     response::IntoResponse,
     Extension,
-    Json,
 };
+use tower_http::request_id::RequestId;
 
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
 pub struct CreateComputerRequest {
     pub name: String,
 }
 
+#[derive(serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct UpdateComputerRequest {
+    pub name: String,
+}
+
 pub async fn register_computer(
     State(state): State<AppState>,
     Extension(claims): Extension<Claims>,
+    Extension(request_id): Extension<RequestId>,
     Json(payload): Json<CreateComputerRequest>,
 ) -> Result<impl IntoResponse, ApiError> {
     let computer =
         crate::logic::computer::register_computer(&state.db, &claims.sub, &payload.name).await?;
+    let computer_id = computer.id.to_string();
+    state
+        .audit(&request_id, &claims.sub, AuditAction::ComputerRegistered, Some(&computer_id), None)
+        .await;
 
     Ok((StatusCode::CREATED, Json(computer)))
 }
 
+pub async fn rename_computer(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(computer_id): Path<ComputerId>,
+    Json(payload): Json<UpdateComputerRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let computer = crate::logic::computer::rename_computer(
+        &state.db,
+        &claims.sub,
+        &computer_id.to_string(),
+        &payload.name,
+        state.online_window,
+    ).await?;
+
+    Ok((StatusCode::OK, Json(computer)))
+}
+
+pub async fn heartbeat(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(computer_id): Path<ComputerId>,
+) -> Result<impl IntoResponse, ApiError> {
+    let computer = crate::logic::computer::heartbeat(
+        &state.db,
+        &claims.sub,
+        &computer_id.to_string(),
+        state.online_window,
+    ).await?;
+
+    Ok((StatusCode::OK, Json(computer)))
+}
+
 pub async fn remove_computer(
     State(state): State<AppState>,
     Extension(claims): Extension<Claims>,
-    Path(computer_id): Path<String>,
+    Extension(request_id): Extension<RequestId>,
+    Path(computer_id): Path<ComputerId>,
 ) -> Result<impl IntoResponse, ApiError> {
+    let computer_id = computer_id.to_string();
     crate::logic::computer::remove_computer(&state.db, &claims.sub, &computer_id).await?;
+    state
+        .audit(&request_id, &claims.sub, AuditAction::ComputerRemoved, Some(&computer_id), None)
+        .await;
 
     Ok(StatusCode::NO_CONTENT)
 }
 
+/// Undoes `DELETE /computers/{id}` within the retention window
+pub async fn restore_computer(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Extension(request_id): Extension<RequestId>,
+    Path(computer_id): Path<ComputerId>,
+) -> Result<impl IntoResponse, ApiError> {
+    let computer_id = computer_id.to_string();
+    let computer = crate::logic::computer::restore_computer(
+        &state.db,
+        &claims.sub,
+        &computer_id,
+        state.deleted_retention,
+        state.online_window,
+    ).await?;
+    state
+        .audit(&request_id, &claims.sub, AuditAction::ComputerRestored, Some(&computer_id), None)
+        .await;
+
+    Ok((StatusCode::OK, Json(computer)))
+}
+
+/// Lists the user's computers a page at a time, sortable by `name`, `last_seen`,
+/// `online` or `id`
 pub async fn list_computers(
     State(state): State<AppState>,
     Extension(claims): Extension<Claims>,
+    Query(query): Query<ListQuery>,
 ) -> Result<impl IntoResponse, ApiError> {
-    let computers = crate::logic::computer::get_computers_by_user(
+    query.validate()?;
+    let computers = crate::logic::computer::list_computers_page(
         &state.db,
         &claims.sub,
+        &query,
+        state.online_window,
     ).await?;
 
     Ok((StatusCode::OK, Json(computers)))
 }
 
+/// The user with their computers and folders, with the status the websocket
+/// connections know of, see [`crate::ws::WsHub::overlay_live_status`]
 pub async fn get_user_state(
     State(state): State<AppState>,
     Extension(claims): Extension<Claims>,
 ) -> Result<impl IntoResponse, ApiError> {
-    let user = crate::logic::user::get_user_state(&state.db, &claims.sub).await?;
+    let mut user =
+        crate::logic::user::get_user_state(&state.db, &claims.sub, state.online_window).await?;
+    state.ws.overlay_live_status(&mut user).await;
 
     Ok((StatusCode::OK, Json(user)))
 }
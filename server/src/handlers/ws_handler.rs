@@ -0,0 +1,115 @@
+use crate::error::ApiError;
+use crate::{AppState, auth::Claims};
+use axum::{
+    Extension,
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, StatusCode, header},
+    response::Response,
+};
+use hyper_util::rt::TokioIo;
+use std::net::SocketAddr;
+use tokio::time::Instant;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tracing::{Instrument, field};
+
+/// Upgrades the request to a websocket speaking the sync protocol of `backup_sync_ws`.
+/// The caller is loaded into the websocket state first, for the computers and folders
+/// they have over HTTP to be known to it; the connection still starts with
+/// `Authenticate`, which names its computer.
+pub async fn upgrade(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    mut req: Request,
+) -> Result<Response, ApiError> {
+    let key = websocket_key(req.headers())?;
+    // Connections are told apart by their peer address
+    let Some(ConnectInfo(addr)) = req.extensions().get::<ConnectInfo<SocketAddr>>().copied() else {
+        return Err(ApiError::InternalError(anyhow::anyhow!(
+            "The app has to be served with the peer address of connections"
+        )));
+    };
+
+    state
+        .ws
+        .load_user(&state.db, &claims.sub, state.online_window)
+        .await?;
+
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    let hub = state.ws.clone();
+    // Filled in once the connection authenticates
+    let span = tracing::info_span!(
+        "connection",
+        peer = %addr,
+        user_id = field::Empty,
+        computer_id = field::Empty
+    );
+    tokio::spawn(
+        async move {
+            let auth_deadline = Instant::now() + hub.limits.auth_timeout;
+            let upgraded = match on_upgrade.await {
+                Ok(upgraded) => upgraded,
+                Err(err) => {
+                    tracing::warn!("Failed to upgrade connection: {:?}", err);
+                    return;
+                }
+            };
+            tracing::info!("new connection");
+            let ws_stream = WebSocketStream::from_raw_socket(
+                TokioIo::new(upgraded),
+                Role::Server,
+                Some(hub.limits.websocket()),
+            )
+            .await;
+            backup_sync_ws::server::serve_websocket(
+                ws_stream,
+                addr,
+                hub.state.clone(),
+                hub.broadcasts.clone(),
+                hub.limits,
+                hub.shutdown_signal(),
+                auth_deadline,
+            )
+            .await;
+        }
+        .instrument(span),
+    );
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(
+            header::SEC_WEBSOCKET_ACCEPT,
+            derive_accept_key(key.as_bytes()),
+        )
+        .body(Body::empty())
+        .map_err(|err| ApiError::InternalError(err.into()))
+}
+
+/// The `Sec-WebSocket-Key` of a websocket handshake, refusing other requests
+fn websocket_key(headers: &HeaderMap) -> Result<String, ApiError> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let upgrades_connection = header(header::CONNECTION).is_some_and(|connection| {
+        connection
+            .split(',')
+            .any(|option| option.trim().eq_ignore_ascii_case("upgrade"))
+    });
+    let to_websocket =
+        header(header::UPGRADE).is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    if !upgrades_connection || !to_websocket {
+        return Err(ApiError::InvalidRequest(
+            "Expected a websocket upgrade".to_owned(),
+        ));
+    }
+    if header(header::SEC_WEBSOCKET_VERSION) != Some("13") {
+        return Err(ApiError::InvalidRequest(
+            "Only websocket version 13 is supported".to_owned(),
+        ));
+    }
+    header(header::SEC_WEBSOCKET_KEY)
+        .map(str::to_owned)
+        .ok_or_else(|| ApiError::InvalidRequest("Missing Sec-WebSocket-Key".to_owned()))
+}
@@ -1,58 +1,300 @@
+use anyhow::Context;
 use axum::{
     http::{header, StatusCode},
     middleware,
-    routing::{delete, get, post},
+    routing::{get, patch, post, MethodRouter},
     Router,
 };
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
 
+pub mod api_docs;
 pub mod auth;
 pub mod db;
 pub mod error;
+pub mod extract;
 pub mod handlers;
 pub mod logic;
+pub mod metrics;
 pub mod middleware_layer;
+pub mod rate_limit;
+pub mod ws;
 
 use crate::db::init_db;
-use crate::handlers::{auth_handler, folder_handler, user_handler};
+use crate::handlers::audit_handler::AuditAction;
+use crate::handlers::{
+    admin_handler, audit_handler, auth_handler, discovery_handler, folder_handler,
+    invite_handler, metrics_handler, user_handler, ws_handler,
+};
+use crate::metrics::HttpMetrics;
+use crate::rate_limit::{BucketStore, MemoryBuckets, Quota, RateLimits};
+use tower_http::request_id::RequestId;
 use tower_http::trace::{DefaultMakeSpan, DefaultOnResponse};
 
 pub type AppState = Arc<AppStateInner>;
 
+/// Prefix of the routes of the current version of the API. They are also reachable
+/// without it, as deprecated aliases for clients predating versioning.
+pub const API_PREFIX: &str = "/v1";
+
+/// How long access tokens last, unless `JWT_TTL_SECS` says otherwise
+pub const DEFAULT_ACCESS_TOKEN_TTL: Duration = Duration::from_secs(24 * 3600);
+/// How long after its last heartbeat a computer still counts as online, unless
+/// `ONLINE_WINDOW_SECS` says otherwise
+pub const DEFAULT_ONLINE_WINDOW: Duration = Duration::from_secs(120);
+/// How long refresh tokens last, unless `REFRESH_TOKEN_TTL_SECS` says otherwise
+pub const DEFAULT_REFRESH_TOKEN_TTL: Duration = Duration::from_secs(30 * 24 * 3600);
+/// How long removed computers and folders can be restored, unless
+/// `DELETED_RETENTION_SECS` says otherwise
+pub const DEFAULT_DELETED_RETENTION: Duration = Duration::from_secs(30 * 24 * 3600);
+/// How long invites to back up a folder can be answered, unless `INVITE_TTL_SECS` says
+/// otherwise
+pub const DEFAULT_INVITE_TTL: Duration = Duration::from_secs(7 * 24 * 3600);
+/// How long responses are kept for retries with the same `Idempotency-Key`, unless
+/// `IDEMPOTENCY_TTL_SECS` says otherwise
+pub const DEFAULT_IDEMPOTENCY_TTL: Duration = Duration::from_secs(24 * 3600);
+/// How often removed computers and folders past retention are purged
+pub const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+/// How often the totals of users and folders in the metrics are refreshed
+pub const METRICS_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+/// Requests per minute each user can make to authenticated routes, unless
+/// `RATE_LIMIT_API_PER_MINUTE` says otherwise
+pub const DEFAULT_API_REQUESTS_PER_MINUTE: u32 = 300;
+/// Requests per minute each address can make to the auth routes other than login,
+/// unless `RATE_LIMIT_AUTH_PER_MINUTE` says otherwise
+pub const DEFAULT_AUTH_REQUESTS_PER_MINUTE: u32 = 30;
+/// Login attempts per minute each address can make, unless
+/// `RATE_LIMIT_LOGIN_PER_MINUTE` says otherwise
+pub const DEFAULT_LOGIN_ATTEMPTS_PER_MINUTE: u32 = 10;
+/// Signups per minute each address can make, unless `RATE_LIMIT_SIGNUP_PER_MINUTE` says
+/// otherwise
+pub const DEFAULT_SIGNUPS_PER_MINUTE: u32 = 10;
+
 #[derive(Clone)]
 pub struct AppStateInner {
-    pub db: sqlx::Pool<sqlx::Sqlite>,
+    pub db: sqlx::Pool<sqlx::Any>,
     pub jwt_secret: String,
+    pub access_token_ttl: Duration,
+    pub refresh_token_ttl: Duration,
+    pub online_window: Duration,
+    pub deleted_retention: Duration,
+    pub invite_ttl: Duration,
+    pub idempotency_ttl: Duration,
+    pub rate_limits: RateLimits,
+    pub rate_limiter: Arc<dyn BucketStore>,
+    pub ws: Arc<ws::WsHub>,
+    pub metrics: Arc<HttpMetrics>,
+    /// Bearer token `/metrics` requires, from `METRICS_TOKEN`; `None` leaves it open
+    pub metrics_token: Option<String>,
 }
 
-pub async fn create_app() -> anyhow::Result<Router> {
-    let db_pool = init_db().await?;
-    let jwt_secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| "secret".to_string());
+impl AppStateInner {
+    /// Records an action of `user_id` in the audit log. Failing to record it is only
+    /// logged, as the action itself already took place.
+    pub async fn audit(
+        &self,
+        request_id: &RequestId,
+        user_id: &str,
+        action: AuditAction,
+        computer_id: Option<&str>,
+        target: Option<&str>,
+    ) {
+        let request_id = request_id.header_value().to_str().ok();
+        let recorded =
+            logic::audit::record(&self.db, user_id, action, computer_id, target, request_id).await;
+        if let Err(err) = recorded {
+            tracing::error!("Failed to record {:?} in the audit log: {:?}", action, err);
+        }
+    }
+}
 
-    let state = Arc::new(AppStateInner {
-        db: db_pool,
-        jwt_secret,
+/// Whether the switch `name` is set to `1` or `true`
+fn enabled_in_env(name: &str) -> bool {
+    std::env::var(name).is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+}
+
+fn duration_from_env(name: &str, default: Duration) -> anyhow::Result<Duration> {
+    match std::env::var(name) {
+        Ok(secs) => secs
+            .parse()
+            .map(Duration::from_secs)
+            .with_context(|| format!("{name} must be a number of seconds")),
+        Err(_) => Ok(default),
+    }
+}
+
+fn quota_from_env(name: &str, default: u32) -> anyhow::Result<Quota> {
+    let per_minute = match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .with_context(|| format!("{name} must be a number of requests"))?,
+        Err(_) => default,
+    };
+    if per_minute == 0 {
+        anyhow::bail!("{name} must be at least 1");
+    }
+    Ok(Quota { per_minute })
+}
+
+/// Purges removed computers and folders past `retention` every [`PURGE_INTERVAL`]
+fn spawn_purge(db: sqlx::Pool<sqlx::Any>, retention: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match logic::purge_deleted(&db, retention).await {
+                Ok(0) => {}
+                Ok(purged) => tracing::info!("Purged {} removed computers and folders", purged),
+                Err(err) => {
+                    tracing::error!("Failed to purge removed computers and folders: {:?}", err)
+                }
+            }
+        }
     });
+}
 
-    let auth_routes = auth_handler::router();
+/// Refreshes the totals of users and folders in `metrics` every
+/// [`METRICS_REFRESH_INTERVAL`]
+fn spawn_metrics_refresh(db: sqlx::Pool<sqlx::Any>, metrics: Arc<HttpMetrics>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(METRICS_REFRESH_INTERVAL);
+        loop {
+            interval.tick().await;
+            match logic::user::count_users(&db).await {
+                Ok(users) => metrics.users.store(users, Ordering::Relaxed),
+                Err(err) => tracing::error!("Failed to count users: {:?}", err),
+            }
+            match logic::folder::count_folders(&db).await {
+                Ok(folders) => metrics.folders.store(folders, Ordering::Relaxed),
+                Err(err) => tracing::error!("Failed to count folders: {:?}", err),
+            }
+        }
+    });
+}
 
-    let protected_routes = Router::new()
-        .route(
+/// Routes of the API needing a bearer token, by path
+fn protected_routes() -> Vec<(&'static str, MethodRouter<AppState>)> {
+    vec![
+        (
             "/computers",
             post(user_handler::register_computer).get(user_handler::list_computers),
-        )
-        .route("/computers/{id}", delete(user_handler::remove_computer))
-        .route(
+        ),
+        (
+            "/computers/{id}",
+            patch(user_handler::rename_computer).delete(user_handler::remove_computer),
+        ),
+        ("/computers/{id}/heartbeat", post(user_handler::heartbeat)),
+        ("/computers/{id}/restore", post(user_handler::restore_computer)),
+        (
             "/computers/{id}/folders",
             get(folder_handler::list_folders_for_computer),
-        )
-        .route("/user/state", get(user_handler::get_user_state))
-        .route(
+        ),
+        ("/user/state", get(user_handler::get_user_state)),
+        ("/user/audit", get(audit_handler::get_audit_log)),
+        ("/auth/logout", post(auth_handler::logout)),
+        ("/auth/logout-all", post(auth_handler::logout_all)),
+        (
             "/folders",
             post(folder_handler::create_folder).get(folder_handler::list_folders),
-        )
-        .route("/folders/{id}/join", post(folder_handler::join_folder))
-        .route("/folders/{id}/leave", post(folder_handler::leave_folder))
+        ),
+        ("/folders/bulk", post(folder_handler::create_folders)),
+        (
+            "/folders/{id}",
+            get(folder_handler::get_folder)
+                .patch(folder_handler::update_folder)
+                .delete(folder_handler::delete_folder),
+        ),
+        ("/folders/{id}/join", post(folder_handler::join_folder)),
+        ("/folders/{id}/leave", post(folder_handler::leave_folder)),
+        ("/folders/{id}/restore", post(folder_handler::restore_folder)),
+        (
+            "/folders/{id}/operations",
+            get(folder_handler::get_folder_operations),
+        ),
+        (
+            "/folders/{id}/switch-origin",
+            post(folder_handler::switch_origin),
+        ),
+        ("/folders/{id}/invites", post(invite_handler::create_invite)),
+        ("/invites", get(invite_handler::list_invites)),
+        ("/invites/{id}/accept", post(invite_handler::accept_invite)),
+        ("/invites/{id}/decline", post(invite_handler::decline_invite)),
+        ("/admin/users", get(admin_handler::list_users)),
+        (
+            "/admin/users/{id}",
+            patch(admin_handler::update_user).delete(admin_handler::delete_user),
+        ),
+        ("/admin/folders", get(admin_handler::list_folders)),
+        ("/ws", get(ws_handler::upgrade)),
+    ]
+}
+
+/// Paths routed under [`API_PREFIX`], as written in the router
+pub fn api_paths() -> Vec<&'static str> {
+    auth_handler::routes()
+        .into_iter()
+        .chain(protected_routes())
+        .map(|(path, _)| path)
+        .collect()
+}
+
+/// Builds the app on the database named by `DATABASE_URL`, see [`init_db`]
+pub async fn create_app() -> anyhow::Result<Router> {
+    create_app_with_db(init_db().await?)
+}
+
+/// Builds the app on `db_pool`. Removed computers and folders are purged, and the
+/// changes of websocket clients written to the database, in the background, so this has
+/// to run within a Tokio runtime.
+pub fn create_app_with_db(db_pool: sqlx::Pool<sqlx::Any>) -> anyhow::Result<Router> {
+    let jwt_secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| "secret".to_string());
+    let ws = Arc::new(ws::WsHub::new(db_pool.clone(), &jwt_secret));
+
+    let state = Arc::new(AppStateInner {
+        db: db_pool,
+        jwt_secret,
+        access_token_ttl: duration_from_env("JWT_TTL_SECS", DEFAULT_ACCESS_TOKEN_TTL)?,
+        refresh_token_ttl: duration_from_env("REFRESH_TOKEN_TTL_SECS", DEFAULT_REFRESH_TOKEN_TTL)?,
+        online_window: duration_from_env("ONLINE_WINDOW_SECS", DEFAULT_ONLINE_WINDOW)?,
+        deleted_retention: duration_from_env("DELETED_RETENTION_SECS", DEFAULT_DELETED_RETENTION)?,
+        invite_ttl: duration_from_env("INVITE_TTL_SECS", DEFAULT_INVITE_TTL)?,
+        idempotency_ttl: duration_from_env("IDEMPOTENCY_TTL_SECS", DEFAULT_IDEMPOTENCY_TTL)?,
+        rate_limits: RateLimits {
+            api: quota_from_env("RATE_LIMIT_API_PER_MINUTE", DEFAULT_API_REQUESTS_PER_MINUTE)?,
+            auth: quota_from_env("RATE_LIMIT_AUTH_PER_MINUTE", DEFAULT_AUTH_REQUESTS_PER_MINUTE)?,
+            login: quota_from_env(
+                "RATE_LIMIT_LOGIN_PER_MINUTE",
+                DEFAULT_LOGIN_ATTEMPTS_PER_MINUTE,
+            )?,
+            signup: quota_from_env("RATE_LIMIT_SIGNUP_PER_MINUTE", DEFAULT_SIGNUPS_PER_MINUTE)?,
+        },
+        rate_limiter: Arc::new(MemoryBuckets::default()),
+        ws,
+        metrics: Arc::new(HttpMetrics::default()),
+        metrics_token: std::env::var("METRICS_TOKEN").ok().filter(|token| !token.is_empty()),
+    });
+
+    spawn_purge(state.db.clone(), state.deleted_retention);
+    spawn_metrics_refresh(state.db.clone(), state.metrics.clone());
+
+    let auth_routes = auth_handler::router().route_layer(middleware::from_fn_with_state(
+        state.clone(),
+        middleware_layer::ip_rate_limit,
+    ));
+
+    let protected_routes = protected_routes()
+        .into_iter()
+        .fold(Router::new(), |router, (path, route)| router.route(path, route))
+        // Layers added last run first, so users are known once requests are counted
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            middleware_layer::idempotency,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            middleware_layer::user_rate_limit,
+        ))
         .route_layer(middleware::from_fn_with_state(
             state.clone(),
             middleware_layer::auth_middleware,
@@ -70,6 +312,11 @@ pub async fn create_app() -> anyhow::Result<Router> {
             tower_http::request_id::MakeRequestUuid,
         ))
         .layer(tower_http::request_id::PropagateRequestIdLayer::x_request_id())
+        .layer(middleware::from_fn(middleware_layer::error_request_id))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            middleware_layer::record_metrics,
+        ))
         .layer(
             tower_http::trace::TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::new().include_headers(true))
@@ -90,9 +337,25 @@ pub async fn create_app() -> anyhow::Result<Router> {
             std::time::Duration::from_secs(10),
         ));
 
-    Ok(Router::new()
-        .merge(auth_routes)
-        .merge(protected_routes)
+    // Left out of the rate limits, for scrapes not to use up a quota
+    let metrics_routes = Router::new().route("/metrics", get(metrics_handler::scrape));
+
+    // A breaking change gets a router of its own under a new prefix, leaving this one be
+    let api = Router::new().merge(auth_routes).merge(protected_routes);
+    let legacy_routes = api
+        .clone()
+        .layer(middleware::from_fn(middleware_layer::deprecated_alias));
+
+    let mut app = Router::new()
+        .nest(API_PREFIX, api)
+        .route(API_PREFIX, get(discovery_handler::discover))
+        .merge(legacy_routes)
+        .merge(metrics_routes);
+    if enabled_in_env("API_DOCS") {
+        app = app.merge(api_docs::router());
+    }
+
+    Ok(app
         .layer(middlewares)
         .with_state(state))
 }
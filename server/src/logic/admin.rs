@@ -0,0 +1,220 @@
+//! Operations over every user's data, reserved to admins
+
+use super::contains_pattern;
+use super::folder::{FOLDER_SORT_COLUMNS, get_folder};
+use super::user::parse_role;
+use crate::auth::Role;
+use crate::error::ApiError;
+use crate::handlers::admin_handler::{AdminFolder, AdminUser};
+use crate::handlers::pagination::{ListQuery, Page};
+use sqlx::{Any, Pool};
+
+/// Fields user lists can be sorted by, the first one being the default
+pub const USER_SORT_COLUMNS: &[(&str, &str)] = &[("name", "name"), ("role", "role"), ("id", "id")];
+
+pub async fn list_users_page(
+    db: &Pool<Any>,
+    query: &ListQuery,
+) -> Result<Page<AdminUser>, ApiError> {
+    let sort = query.sort_column(USER_SORT_COLUMNS)?;
+    let name = contains_pattern(query.name.as_deref().unwrap_or_default());
+    let (limit, offset) = (query.limit(), query.offset());
+
+    let total: i64 =
+        sqlx::query_scalar(r"SELECT COUNT(*) FROM users WHERE lower(name) LIKE $1 ESCAPE '\'")
+            .bind(&name)
+            .fetch_one(db)
+            .await?;
+
+    // Only the sort column and order are spliced in, both picked from fixed lists
+    let sql = format!(
+        r"
+        SELECT id, name, role
+        FROM users
+        WHERE lower(name) LIKE $1 ESCAPE '\'
+        ORDER BY {sort} {}, id LIMIT $2 OFFSET $3
+    ",
+        query.order.unwrap_or_default().sql()
+    );
+    let rows: Vec<(String, String, String)> = sqlx::query_as(&sql)
+        .bind(&name)
+        .bind(i64::from(limit))
+        .bind(i64::from(offset))
+        .fetch_all(db)
+        .await?;
+
+    Ok(Page {
+        items: rows.into_iter().map(user).collect::<Result<_, _>>()?,
+        total: total as u64,
+        limit,
+        offset,
+    })
+}
+
+/// Lists the live folders of every user, with the user owning each
+pub async fn list_folders_page(
+    db: &Pool<Any>,
+    query: &ListQuery,
+) -> Result<Page<AdminFolder>, ApiError> {
+    let sort = query.sort_column(FOLDER_SORT_COLUMNS)?;
+    let name = contains_pattern(query.name.as_deref().unwrap_or_default());
+    let (limit, offset) = (query.limit(), query.offset());
+
+    let total: i64 = sqlx::query_scalar(
+        r"
+        SELECT COUNT(*)
+        FROM folders f
+        JOIN computers c ON f.origin_computer_id = c.id
+        WHERE f.deleted_at IS NULL AND c.deleted_at IS NULL
+          AND lower(f.name) LIKE $1 ESCAPE '\'
+    ",
+    )
+    .bind(&name)
+    .fetch_one(db)
+    .await?;
+
+    // Only the sort column and order are spliced in, both picked from fixed lists
+    let sql = format!(
+        r"
+        SELECT f.id, c.user_id
+        FROM folders f
+        JOIN computers c ON f.origin_computer_id = c.id
+        WHERE f.deleted_at IS NULL AND c.deleted_at IS NULL
+          AND lower(f.name) LIKE $1 ESCAPE '\'
+        ORDER BY {sort} {}, f.id LIMIT $2 OFFSET $3
+    ",
+        query.order.unwrap_or_default().sql()
+    );
+    let rows: Vec<(String, String)> = sqlx::query_as(&sql)
+        .bind(&name)
+        .bind(i64::from(limit))
+        .bind(i64::from(offset))
+        .fetch_all(db)
+        .await?;
+
+    let mut items = Vec::with_capacity(rows.len());
+    for (id, owner) in rows {
+        items.push(AdminFolder {
+            folder: get_folder(db, &id).await?,
+            owner: owner.into(),
+        });
+    }
+
+    Ok(Page {
+        items,
+        total: total as u64,
+        limit,
+        offset,
+    })
+}
+
+/// Gives `user_id` the `role`. Admins can't change their own role, so there is always
+/// one left.
+pub async fn set_role(
+    db: &Pool<Any>,
+    admin_id: &str,
+    user_id: &str,
+    role: Role,
+) -> Result<AdminUser, ApiError> {
+    if admin_id == user_id {
+        return Err(ApiError::Conflict(
+            "Admins can't change their own role".to_owned(),
+        ));
+    }
+
+    let row: (String, String, String) =
+        sqlx::query_as("UPDATE users SET role = $1 WHERE id = $2 RETURNING id, name, role")
+            .bind(role.as_str())
+            .bind(user_id)
+            .fetch_optional(db)
+            .await?
+            .ok_or(ApiError::NotFound("User not found".to_owned()))?;
+
+    user(row)
+}
+
+/// Deletes `user_id` with their computers, folders and tokens, for good. Their access
+/// tokens are left to expire.
+pub async fn delete_user(db: &Pool<Any>, admin_id: &str, user_id: &str) -> Result<(), ApiError> {
+    if admin_id == user_id {
+        return Err(ApiError::Conflict(
+            "Admins can't delete themselves".to_owned(),
+        ));
+    }
+
+    let result = sqlx::query("DELETE FROM users WHERE id = $1")
+        .bind(user_id)
+        .execute(db)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound("User not found".to_owned()));
+    }
+
+    Ok(())
+}
+
+fn user(row: (String, String, String)) -> Result<AdminUser, ApiError> {
+    let (id, name, role) = row;
+    Ok(AdminUser {
+        id: id.into(),
+        name,
+        role: parse_role(&role)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+    use crate::logic::{computer, folder};
+    use uuid::Uuid;
+
+    async fn insert_user(db: &Pool<Any>, name: &str) -> String {
+        let user_id = Uuid::new_v4().to_string();
+        sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+            .bind(&user_id)
+            .bind(name)
+            .bind("hash")
+            .execute(db)
+            .await
+            .unwrap();
+        user_id
+    }
+
+    #[tokio::test]
+    async fn test_admin_operations() {
+        let db = test_db().await.unwrap();
+        let admin_id = insert_user(&db, "admin").await;
+        let user_id = insert_user(&db, "user").await;
+        let pc = computer::register_computer(&db, &user_id, "pc")
+            .await
+            .unwrap();
+        folder::create_folder(&db, &user_id, "docs", &pc.id.to_string())
+            .await
+            .unwrap();
+
+        let users = list_users_page(&db, &ListQuery::default()).await.unwrap();
+        assert_eq!(users.total, 2);
+        assert!(users.items.iter().all(|user| user.role == Role::User));
+
+        let folders = list_folders_page(&db, &ListQuery::default()).await.unwrap();
+        assert_eq!(folders.items.len(), 1);
+        assert_eq!(folders.items[0].owner.to_string(), user_id);
+
+        let promoted = set_role(&db, &admin_id, &user_id, Role::Admin)
+            .await
+            .unwrap();
+        assert_eq!(promoted.role, Role::Admin);
+        let result = set_role(&db, &admin_id, &admin_id, Role::User).await;
+        assert!(matches!(result, Err(ApiError::Conflict(_))));
+
+        let result = delete_user(&db, &admin_id, &admin_id).await;
+        assert!(matches!(result, Err(ApiError::Conflict(_))));
+        delete_user(&db, &admin_id, &user_id).await.unwrap();
+        let result = delete_user(&db, &admin_id, &user_id).await;
+        assert!(matches!(result, Err(ApiError::NotFound(_))));
+
+        let folders = list_folders_page(&db, &ListQuery::default()).await.unwrap();
+        assert_eq!(folders.total, 0);
+    }
+}
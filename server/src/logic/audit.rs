@@ -0,0 +1,148 @@
+//! Record of what users did to their computers and folders, and of their failed logins
+
+use super::unix_now;
+use crate::error::ApiError;
+use crate::handlers::audit_handler::{AuditAction, AuditEntry};
+use anyhow::anyhow;
+use sqlx::{Any, Pool};
+
+/// Appends an entry to `user_id`'s audit log
+pub async fn record(
+    db: &Pool<Any>,
+    user_id: &str,
+    action: AuditAction,
+    computer_id: Option<&str>,
+    target: Option<&str>,
+    request_id: Option<&str>,
+) -> Result<(), ApiError> {
+    sqlx::query(
+        "
+        INSERT INTO audit_log (user_id, computer_id, action, target, created_at, request_id)
+        VALUES ($1, $2, $3, $4, $5, $6)
+    ",
+    )
+    .bind(user_id)
+    .bind(computer_id)
+    .bind(action.as_str())
+    .bind(target)
+    .bind(unix_now()?)
+    .bind(request_id)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// The id, action, computer, target, time and request id of an entry
+type AuditRow = (
+    i64,
+    String,
+    Option<String>,
+    Option<String>,
+    i64,
+    Option<String>,
+);
+
+/// The `limit` most recent entries of `user_id`'s audit log, older than the entry
+/// `before` when given
+pub async fn list_entries(
+    db: &Pool<Any>,
+    user_id: &str,
+    limit: u32,
+    before: Option<u64>,
+) -> Result<Vec<AuditEntry>, ApiError> {
+    let before = before.map_or(i64::MAX, |id| id.min(i64::MAX as u64) as i64);
+    let rows: Vec<AuditRow> = sqlx::query_as(
+        "
+        SELECT id, action, computer_id, target, created_at, request_id
+        FROM audit_log
+        WHERE user_id = $1 AND id < $2
+        ORDER BY id DESC
+        LIMIT $3
+    ",
+    )
+    .bind(user_id)
+    .bind(before)
+    .bind(i64::from(limit))
+    .fetch_all(db)
+    .await?;
+
+    rows.into_iter().map(entry).collect()
+}
+
+fn entry(row: AuditRow) -> Result<AuditEntry, ApiError> {
+    let (id, action, computer_id, target, created_at, request_id) = row;
+    let action = AuditAction::parse(&action)
+        .ok_or_else(|| ApiError::InternalError(anyhow!("Unknown audit action '{action}'")))?;
+
+    Ok(AuditEntry {
+        id: id as u64,
+        action,
+        computer_id: computer_id.map(Into::into),
+        target,
+        created_at,
+        request_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_list_entries_pages_backwards() {
+        let db = test_db().await.unwrap();
+        let user_id = Uuid::new_v4().to_string();
+        let other_id = Uuid::new_v4().to_string();
+        for (id, name) in [(&user_id, "testuser"), (&other_id, "otheruser")] {
+            sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+                .bind(id)
+                .bind(name)
+                .bind("hash")
+                .execute(&db)
+                .await
+                .unwrap();
+        }
+
+        record(
+            &db,
+            &user_id,
+            AuditAction::ComputerRegistered,
+            Some("pc"),
+            None,
+            Some("r1"),
+        )
+        .await
+        .unwrap();
+        record(&db, &other_id, AuditAction::LoginFailed, None, None, None)
+            .await
+            .unwrap();
+        record(
+            &db,
+            &user_id,
+            AuditAction::FolderCreated,
+            Some("pc"),
+            Some("docs"),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let entries = list_entries(&db, &user_id, 10, None).await.unwrap();
+        let actions: Vec<_> = entries.iter().map(|entry| entry.action).collect();
+        assert_eq!(
+            actions,
+            [AuditAction::FolderCreated, AuditAction::ComputerRegistered]
+        );
+        assert_eq!(entries[0].target.as_deref(), Some("docs"));
+
+        let older = list_entries(&db, &user_id, 10, Some(entries[0].id))
+            .await
+            .unwrap();
+        assert_eq!(older.len(), 1);
+        assert_eq!(older[0].request_id.as_deref(), Some("r1"));
+        assert_eq!(list_entries(&db, &user_id, 1, None).await.unwrap().len(), 1);
+    }
+}
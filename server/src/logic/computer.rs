@@ -1,120 +1,483 @@
+use super::{contains_pattern, unix_now};
 use crate::error::ApiError;
-use backup_sync_protocol::Computer;
-use sqlx::{Pool, Sqlite};
-use uuid::Uuid;
+use crate::handlers::pagination::{ListQuery, Page};
+use backup_sync_protocol::{Computer, ComputerId};
+use sqlx::{Any, Pool};
+use std::time::Duration;
 
+pub const MAX_COMPUTER_NAME_LEN: usize = 255;
+
+/// Fields computer lists can be sorted by, the first one being the default. Sorting by
+/// `online` puts the most recently seen computers last.
+pub const COMPUTER_SORT_COLUMNS: &[(&str, &str)] = &[
+    ("name", "name"),
+    ("last_seen", "last_seen"),
+    ("online", "last_seen"),
+    ("id", "id"),
+];
+
+/// Earliest heartbeat, in seconds since the Unix epoch, that still counts as online
+pub fn online_since(online_window: Duration) -> Result<i64, ApiError> {
+    Ok(unix_now()? - online_window.as_secs() as i64)
+}
+
+fn computer(id: String, name: String, last_seen: Option<i64>, online_since: i64) -> Computer {
+    Computer {
+        id: id.into(),
+        name,
+        online: last_seen.is_some_and(|seen| seen >= online_since),
+        last_seen,
+    }
+}
+
+/// Registers a computer, counting the registration as its first heartbeat
 pub async fn register_computer(
-    db: &Pool<Sqlite>,
+    db: &Pool<Any>,
     user_id: &str,
     name: &str,
 ) -> Result<Computer, ApiError> {
-    let computer_id = Uuid::new_v4().to_string();
+    super::validate_name("Computer", name, MAX_COMPUTER_NAME_LEN)?;
+    let computer_id = ComputerId::new_v4();
+    let id = computer_id.to_string();
+    let now = unix_now()?;
 
-    sqlx::query!(
-        "INSERT INTO computers (id, user_id, name, online) VALUES (?, ?, ?, ?)",
-        computer_id,
-        user_id,
-        name,
-        true
-    )
-    .execute(db)
-    .await?;
+    sqlx::query("INSERT INTO computers (id, user_id, name, last_seen) VALUES ($1, $2, $3, $4)")
+        .bind(&id)
+        .bind(user_id)
+        .bind(name)
+        .bind(now)
+        .execute(db)
+        .await?;
 
     Ok(Computer {
         id: computer_id,
         name: name.to_string(),
         online: true,
+        last_seen: Some(now),
     })
 }
 
 pub async fn get_computers_by_user(
-    db: &Pool<Sqlite>,
+    db: &Pool<Any>,
     user_id: &str,
+    online_window: Duration,
 ) -> Result<Vec<Computer>, ApiError> {
-    let computers = sqlx::query_as!(
-        Computer,
-        "SELECT id, name, online FROM computers WHERE user_id = ?",
-        user_id
+    let online_since = online_since(online_window)?;
+    let computers: Vec<(String, String, Option<i64>)> = sqlx::query_as(
+        "SELECT id, name, last_seen FROM computers WHERE user_id = $1 AND deleted_at IS NULL",
     )
+    .bind(user_id)
     .fetch_all(db)
     .await?;
 
-    Ok(computers)
+    Ok(computers
+        .into_iter()
+        .map(|(id, name, last_seen)| computer(id, name, last_seen, online_since))
+        .collect())
+}
+
+pub async fn list_computers_page(
+    db: &Pool<Any>,
+    user_id: &str,
+    query: &ListQuery,
+    online_window: Duration,
+) -> Result<Page<Computer>, ApiError> {
+    let online_since = online_since(online_window)?;
+    let sort = query.sort_column(COMPUTER_SORT_COLUMNS)?;
+    let name = contains_pattern(query.name.as_deref().unwrap_or_default());
+    let (limit, offset) = (query.limit(), query.offset());
+
+    let total: i64 = sqlx::query_scalar(
+        r"
+        SELECT COUNT(*) FROM computers
+        WHERE user_id = $1 AND deleted_at IS NULL AND lower(name) LIKE $2 ESCAPE '\'
+    ",
+    )
+    .bind(user_id)
+    .bind(&name)
+    .fetch_one(db)
+    .await?;
+
+    // Only the sort column and order are spliced in, both picked from fixed lists
+    let sql = format!(
+        r"
+        SELECT id, name, last_seen FROM computers
+        WHERE user_id = $1 AND deleted_at IS NULL AND lower(name) LIKE $2 ESCAPE '\'
+        ORDER BY {sort} {}, id LIMIT $3 OFFSET $4
+    ",
+        query.order.unwrap_or_default().sql()
+    );
+    let computers: Vec<(String, String, Option<i64>)> = sqlx::query_as(&sql)
+        .bind(user_id)
+        .bind(&name)
+        .bind(i64::from(limit))
+        .bind(i64::from(offset))
+        .fetch_all(db)
+        .await?;
+
+    Ok(Page {
+        items: computers
+            .into_iter()
+            .map(|(id, name, last_seen)| computer(id, name, last_seen, online_since))
+            .collect(),
+        total: total as u64,
+        limit,
+        offset,
+    })
+}
+
+pub async fn rename_computer(
+    db: &Pool<Any>,
+    user_id: &str,
+    computer_id: &str,
+    name: &str,
+    online_window: Duration,
+) -> Result<Computer, ApiError> {
+    computer_owned_by(db, computer_id, user_id).await?;
+    super::validate_name("Computer", name, MAX_COMPUTER_NAME_LEN)?;
+
+    let taken: i64 = sqlx::query_scalar(
+        "
+        SELECT COUNT(*) FROM computers
+        WHERE user_id = $1 AND name = $2 AND id != $3 AND deleted_at IS NULL
+    ",
+    )
+    .bind(user_id)
+    .bind(name)
+    .bind(computer_id)
+    .fetch_one(db)
+    .await?;
+    if taken > 0 {
+        return Err(ApiError::Conflict(format!(
+            "A computer named '{name}' already exists"
+        )));
+    }
+
+    let (id, name, last_seen) = sqlx::query_as(
+        "UPDATE computers SET name = $1 WHERE id = $2 RETURNING id, name, last_seen",
+    )
+    .bind(name)
+    .bind(computer_id)
+    .fetch_one(db)
+    .await?;
+
+    Ok(computer(id, name, last_seen, online_since(online_window)?))
+}
+
+/// Records that the computer is alive
+pub async fn heartbeat(
+    db: &Pool<Any>,
+    user_id: &str,
+    computer_id: &str,
+    online_window: Duration,
+) -> Result<Computer, ApiError> {
+    computer_owned_by(db, computer_id, user_id).await?;
+
+    let now = unix_now()?;
+    let (id, name, last_seen) = sqlx::query_as(
+        "UPDATE computers SET last_seen = $1 WHERE id = $2 RETURNING id, name, last_seen",
+    )
+    .bind(now)
+    .bind(computer_id)
+    .fetch_one(db)
+    .await?;
+
+    Ok(computer(id, name, last_seen, online_since(online_window)?))
+}
+
+/// Tells unknown or deleted computers (404) apart from other users' computers (403)
+async fn computer_owned_by(
+    db: &Pool<Any>,
+    computer_id: &str,
+    user_id: &str,
+) -> Result<(), ApiError> {
+    let owner: String =
+        sqlx::query_scalar("SELECT user_id FROM computers WHERE id = $1 AND deleted_at IS NULL")
+            .bind(computer_id)
+            .fetch_optional(db)
+            .await?
+            .ok_or(ApiError::NotFound("Computer not found".to_owned()))?;
+    if owner != user_id {
+        return Err(ApiError::PermissionDenied(
+            "Computer does not belong to user".to_owned(),
+        ));
+    }
+    Ok(())
 }
 
 pub async fn remove_computer(
-    db: &Pool<Sqlite>,
+    db: &Pool<Any>,
     user_id: &str,
     computer_id: &str,
 ) -> Result<(), ApiError> {
-    // Verify computer belongs to user
-    sqlx::query!(
-        "SELECT id FROM computers WHERE id = ? AND user_id = ?",
-        computer_id,
-        user_id
+    // Only marked as deleted, so the computer keeps its folders until it is purged
+    let removed =
+        sqlx::query("UPDATE computers SET deleted_at = $1 WHERE id = $2 AND user_id = $3 AND deleted_at IS NULL")
+            .bind(unix_now()?)
+            .bind(computer_id)
+            .bind(user_id)
+            .execute(db)
+            .await?;
+    if removed.rows_affected() == 0 {
+        return Err(ApiError::PermissionDenied(
+            "Computer does not belong to user".to_owned(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Brings back a computer removed less than `retention` ago, along with its folders
+pub async fn restore_computer(
+    db: &Pool<Any>,
+    user_id: &str,
+    computer_id: &str,
+    retention: Duration,
+    online_window: Duration,
+) -> Result<Computer, ApiError> {
+    let (owner, deleted_at): (String, Option<i64>) =
+        sqlx::query_as("SELECT user_id, deleted_at FROM computers WHERE id = $1")
+            .bind(computer_id)
+            .fetch_optional(db)
+            .await?
+            .ok_or(ApiError::NotFound("Computer not found".to_owned()))?;
+    if owner != user_id {
+        return Err(ApiError::PermissionDenied(
+            "Computer does not belong to user".to_owned(),
+        ));
+    }
+    match deleted_at {
+        None => {
+            return Err(ApiError::Conflict("Computer is not deleted".to_owned()));
+        }
+        // Past retention it only waits for the purge
+        Some(deleted_at) if deleted_at <= super::retention_cutoff(retention)? => {
+            return Err(ApiError::NotFound("Computer not found".to_owned()));
+        }
+        Some(_) => {}
+    }
+
+    let (id, name, last_seen) = sqlx::query_as(
+        "UPDATE computers SET deleted_at = NULL WHERE id = $1 RETURNING id, name, last_seen",
     )
-    .fetch_optional(db)
-    .await?
-    .ok_or(ApiError::PermissionDenied(
-        "Computer does not belong to user".to_owned(),
-    ))?;
-
-    // With ON DELETE CASCADE, removing the computer removes associated folders and backups
-    sqlx::query!("DELETE FROM computers WHERE id = ?", computer_id)
+    .bind(computer_id)
+    .fetch_one(db)
+    .await?;
+
+    Ok(computer(id, name, last_seen, online_since(online_window)?))
+}
+
+/// Deletes for good the computers removed at or before `deleted_before`, with their
+/// folders and memberships, and returns how many there were
+pub async fn purge_deleted_computers(db: &Pool<Any>, deleted_before: i64) -> Result<u64, ApiError> {
+    let purged = sqlx::query("DELETE FROM computers WHERE deleted_at <= $1")
+        .bind(deleted_before)
         .execute(db)
         .await?;
 
-    Ok(())
+    Ok(purged.rows_affected())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::db::init_db;
+    use crate::db::test_db;
+    use uuid::Uuid;
+
+    const WINDOW: Duration = Duration::from_secs(60);
 
     #[tokio::test]
     async fn test_register_and_get_computer() {
-        let db = init_db().await.unwrap();
+        let db = test_db().await.unwrap();
         // create user first (needed for FK)
         let user_id = Uuid::new_v4().to_string();
-        sqlx::query!(
-            "INSERT INTO users (id, name, password_hash) VALUES (?, ?, ?)",
-            user_id,
-            "testuser",
-            "hash"
-        )
-        .execute(&db)
-        .await
-        .unwrap();
+        sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+            .bind(&user_id)
+            .bind("testuser")
+            .bind("hash")
+            .execute(&db)
+            .await
+            .unwrap();
 
         let computer = register_computer(&db, &user_id, "MyPC").await.unwrap();
         assert_eq!(computer.name, "MyPC");
         assert!(computer.online);
 
-        let computers = get_computers_by_user(&db, &user_id).await.unwrap();
+        let computers = get_computers_by_user(&db, &user_id, WINDOW).await.unwrap();
         assert_eq!(computers.len(), 1);
         assert_eq!(computers[0].id, computer.id);
     }
 
+    #[tokio::test]
+    async fn test_rename_computer() {
+        let db = test_db().await.unwrap();
+        let user_id = Uuid::new_v4().to_string();
+        let other_id = Uuid::new_v4().to_string();
+        for (id, name) in [(&user_id, "testuser"), (&other_id, "otheruser")] {
+            sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+                .bind(id)
+                .bind(name)
+                .bind("hash")
+                .execute(&db)
+                .await
+                .unwrap();
+        }
+
+        let laptop = register_computer(&db, &user_id, "Jonh's Laptop")
+            .await
+            .unwrap();
+        register_computer(&db, &user_id, "Desktop").await.unwrap();
+        let laptop_id = laptop.id.to_string();
+
+        let renamed = rename_computer(&db, &user_id, &laptop_id, "John's Laptop", WINDOW)
+            .await
+            .unwrap();
+        assert_eq!(renamed.id, laptop.id);
+        assert_eq!(renamed.name, "John's Laptop");
+
+        let result = rename_computer(&db, &user_id, &laptop_id, "Desktop", WINDOW).await;
+        assert!(matches!(result, Err(ApiError::Conflict(_))));
+        let result = rename_computer(&db, &user_id, &laptop_id, "", WINDOW).await;
+        assert!(matches!(result, Err(ApiError::Validation(_))));
+        let result = rename_computer(&db, &other_id, &laptop_id, "Mine", WINDOW).await;
+        assert!(matches!(result, Err(ApiError::PermissionDenied(_))));
+        let result = rename_computer(&db, &user_id, "unknown", "Ghost", WINDOW).await;
+        assert!(matches!(result, Err(ApiError::NotFound(_))));
+
+        let computers = get_computers_by_user(&db, &user_id, WINDOW).await.unwrap();
+        assert!(computers.iter().any(|c| c.name == "John's Laptop"));
+    }
+
+    #[tokio::test]
+    async fn test_online_follows_heartbeats() {
+        let db = test_db().await.unwrap();
+        let user_id = Uuid::new_v4().to_string();
+        sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+            .bind(&user_id)
+            .bind("testuser")
+            .bind("hash")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let computer = register_computer(&db, &user_id, "MyPC").await.unwrap();
+        let id = computer.id.to_string();
+
+        // Last heard from well outside the window
+        let long_ago = unix_now().unwrap() - 3600;
+        sqlx::query("UPDATE computers SET last_seen = $1 WHERE id = $2")
+            .bind(long_ago)
+            .bind(&id)
+            .execute(&db)
+            .await
+            .unwrap();
+        let computers = get_computers_by_user(&db, &user_id, WINDOW).await.unwrap();
+        assert!(!computers[0].online);
+        assert_eq!(computers[0].last_seen, Some(long_ago));
+
+        let computer = heartbeat(&db, &user_id, &id, WINDOW).await.unwrap();
+        assert!(computer.online);
+        assert!(computer.last_seen > Some(long_ago));
+        let computers = get_computers_by_user(&db, &user_id, WINDOW).await.unwrap();
+        assert!(computers[0].online);
+
+        let result = heartbeat(&db, "someone else", &id, WINDOW).await;
+        assert!(matches!(result, Err(ApiError::PermissionDenied(_))));
+    }
+
     #[tokio::test]
     async fn test_remove_computer() {
-        let db = init_db().await.unwrap();
+        let db = test_db().await.unwrap();
         let user_id = Uuid::new_v4().to_string();
-        sqlx::query!(
-            "INSERT INTO users (id, name, password_hash) VALUES (?, ?, ?)",
-            user_id,
-            "testuser",
-            "hash"
-        )
-        .execute(&db)
-        .await
-        .unwrap();
+        sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+            .bind(&user_id)
+            .bind("testuser")
+            .bind("hash")
+            .execute(&db)
+            .await
+            .unwrap();
 
         let computer = register_computer(&db, &user_id, "MyPC").await.unwrap();
 
-        remove_computer(&db, &user_id, &computer.id).await.unwrap();
+        remove_computer(&db, &user_id, &computer.id.to_string())
+            .await
+            .unwrap();
 
-        let computers = get_computers_by_user(&db, &user_id).await.unwrap();
+        let computers = get_computers_by_user(&db, &user_id, WINDOW).await.unwrap();
         assert_eq!(computers.len(), 0);
     }
+
+    #[tokio::test]
+    async fn test_restore_and_purge_computer() {
+        const RETENTION: Duration = Duration::from_secs(24 * 3600);
+        let db = test_db().await.unwrap();
+        let user_id = Uuid::new_v4().to_string();
+        sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+            .bind(&user_id)
+            .bind("testuser")
+            .bind("hash")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let old = register_computer(&db, &user_id, "OldPC").await.unwrap();
+        let recent = register_computer(&db, &user_id, "RecentPC").await.unwrap();
+        let (old_id, recent_id) = (old.id.to_string(), recent.id.to_string());
+        crate::logic::folder::create_folder(&db, &user_id, "Docs", &old_id)
+            .await
+            .unwrap();
+
+        remove_computer(&db, &user_id, &old_id).await.unwrap();
+        assert_eq!(
+            get_computers_by_user(&db, &user_id, WINDOW)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        let restored = restore_computer(&db, &user_id, &old_id, RETENTION, WINDOW)
+            .await
+            .unwrap();
+        assert_eq!(restored.id, old.id);
+        assert_eq!(
+            get_computers_by_user(&db, &user_id, WINDOW)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        let result = restore_computer(&db, &user_id, &old_id, RETENTION, WINDOW).await;
+        assert!(matches!(result, Err(ApiError::Conflict(_))));
+
+        // Removed two days ago, past retention
+        remove_computer(&db, &user_id, &old_id).await.unwrap();
+        remove_computer(&db, &user_id, &recent_id).await.unwrap();
+        sqlx::query("UPDATE computers SET deleted_at = $1 WHERE id = $2")
+            .bind(unix_now().unwrap() - 2 * 24 * 3600)
+            .bind(&old_id)
+            .execute(&db)
+            .await
+            .unwrap();
+        let result = restore_computer(&db, &user_id, &old_id, RETENTION, WINDOW).await;
+        assert!(matches!(result, Err(ApiError::NotFound(_))));
+
+        assert_eq!(
+            crate::logic::purge_deleted(&db, RETENTION).await.unwrap(),
+            1
+        );
+        let ids: Vec<String> = sqlx::query_scalar("SELECT id FROM computers")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        assert_eq!(ids, vec![recent_id.clone()]);
+        let folders: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM folders")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(folders, 0);
+
+        restore_computer(&db, &user_id, &recent_id, RETENTION, WINDOW)
+            .await
+            .unwrap();
+    }
 }
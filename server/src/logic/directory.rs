@@ -0,0 +1,216 @@
+//! The users, computers and folders of the websocket server, kept the same as the ones
+//! of the database
+
+use super::{computer, folder, unix_now};
+use crate::error::ApiError;
+use backup_sync_protocol::User;
+use backup_sync_ws::state::DirectoryChange;
+use sqlx::{Any, Pool};
+use std::time::Duration;
+
+/// `user_id` with their live computers and folders, as the websocket server holds them
+pub async fn load_user(
+    db: &Pool<Any>,
+    user_id: &str,
+    online_window: Duration,
+) -> Result<User, ApiError> {
+    let name: String = sqlx::query_scalar("SELECT name FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(db)
+        .await?
+        .ok_or(ApiError::UserNotFound)?;
+
+    Ok(User {
+        id: user_id.into(),
+        name,
+        computers: computer::get_computers_by_user(db, user_id, online_window).await?,
+        sync_folders: folder::get_folders_by_user(db, user_id).await?,
+    })
+}
+
+/// Applies a change websocket clients made. The websocket server already checked it
+/// against its own rules, so it is written as it happened rather than checked again.
+pub async fn apply(db: &Pool<Any>, change: &DirectoryChange) -> Result<(), ApiError> {
+    match change {
+        DirectoryChange::ComputerRegistered { user_id, computer } => {
+            sqlx::query(
+                "
+                INSERT INTO computers (id, user_id, name, last_seen) VALUES ($1, $2, $3, $4)
+                ON CONFLICT DO NOTHING
+            ",
+            )
+            .bind(computer.id.as_str())
+            .bind(user_id.as_str())
+            .bind(&computer.name)
+            .bind(unix_now()?)
+            .execute(db)
+            .await?;
+        }
+        DirectoryChange::ComputerRemoved {
+            user_id,
+            computer_id,
+        } => {
+            computer::remove_computer(db, user_id.as_str(), computer_id.as_str()).await?;
+        }
+        DirectoryChange::FolderCreated { user_id, folder } => {
+            let origin = folder.origin_computer.as_str();
+            folder::computer_belongs_to_user(db, origin, user_id.as_str()).await?;
+            sqlx::query(
+                "
+                INSERT INTO folders (id, name, origin_computer_id, origin_joined_at, is_synced)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT DO NOTHING
+            ",
+            )
+            .bind(folder.id.as_str())
+            .bind(&folder.name)
+            .bind(origin)
+            .bind(unix_now()?)
+            .bind(folder.is_synced)
+            .execute(db)
+            .await?;
+        }
+        DirectoryChange::FolderJoined {
+            user_id,
+            folder_id,
+            computer_id,
+        } => {
+            folder::join_folder(
+                db,
+                user_id.as_str(),
+                folder_id.as_str(),
+                computer_id.as_str(),
+            )
+            .await?;
+        }
+        DirectoryChange::FolderLeft {
+            user_id,
+            folder_id,
+            computer_id,
+        } => {
+            folder::leave_folder(
+                db,
+                user_id.as_str(),
+                folder_id.as_str(),
+                computer_id.as_str(),
+            )
+            .await?;
+        }
+        DirectoryChange::FolderDeleted { user_id, folder_id } => {
+            folder::delete_folder(db, user_id.as_str(), folder_id.as_str(), true).await?;
+        }
+        DirectoryChange::FolderRenamed {
+            user_id,
+            folder_id,
+            name,
+        } => {
+            folder::update_folder(db, user_id.as_str(), folder_id.as_str(), Some(name)).await?;
+        }
+        DirectoryChange::OriginSwitched {
+            user_id,
+            folder_id,
+            new_origin,
+        } => {
+            // Only switched once the websocket server saw every operation acknowledged
+            folder::folder_belongs_to_user(db, folder_id.as_str(), user_id.as_str()).await?;
+            folder::set_folder_sync_status(db, folder_id.as_str(), true, 0).await?;
+            folder::switch_origin(
+                db,
+                user_id.as_str(),
+                folder_id.as_str(),
+                new_origin.as_str(),
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+    use backup_sync_protocol::{Computer, ComputerId, FolderId, SyncFolder, UserId};
+    use std::collections::BTreeMap;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_changes_round_trip() {
+        let db = test_db().await.unwrap();
+        let user_id = UserId::from(Uuid::new_v4().to_string().as_str());
+        sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+            .bind(user_id.as_str())
+            .bind("testuser")
+            .bind("hash")
+            .execute(&db)
+            .await
+            .unwrap();
+        let (laptop, desktop) = (ComputerId::new_v4(), ComputerId::new_v4());
+        let folder_id = FolderId::new_v4();
+
+        let mut changes = Vec::new();
+        for id in [&laptop, &desktop] {
+            changes.push(DirectoryChange::ComputerRegistered {
+                user_id: user_id.clone(),
+                computer: Computer {
+                    id: id.clone(),
+                    name: "pc".to_string(),
+                    online: true,
+                    last_seen: None,
+                },
+            });
+        }
+        changes.push(DirectoryChange::FolderCreated {
+            user_id: user_id.clone(),
+            folder: SyncFolder {
+                id: folder_id.clone(),
+                name: "docs".to_string(),
+                origin_computer: laptop.clone(),
+                backup_computers: Vec::new(),
+                is_synced: true,
+                pending_operations: 0,
+                backup_status: BTreeMap::new(),
+            },
+        });
+        changes.push(DirectoryChange::FolderJoined {
+            user_id: user_id.clone(),
+            folder_id: folder_id.clone(),
+            computer_id: desktop.clone(),
+        });
+        changes.push(DirectoryChange::FolderRenamed {
+            user_id: user_id.clone(),
+            folder_id: folder_id.clone(),
+            name: "documents".to_string(),
+        });
+        changes.push(DirectoryChange::OriginSwitched {
+            user_id: user_id.clone(),
+            folder_id: folder_id.clone(),
+            new_origin: desktop.clone(),
+        });
+        for change in &changes {
+            apply(&db, change).await.unwrap();
+        }
+
+        let user = load_user(&db, user_id.as_str(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(user.name, "testuser");
+        assert_eq!(user.computers.len(), 2);
+        assert_eq!(user.sync_folders.len(), 1);
+        let folder = &user.sync_folders[0];
+        assert_eq!(folder.name, "documents");
+        assert_eq!(folder.origin_computer, desktop);
+        assert_eq!(folder.backup_computers, [laptop]);
+
+        let deleted = DirectoryChange::FolderDeleted {
+            user_id: user_id.clone(),
+            folder_id,
+        };
+        apply(&db, &deleted).await.unwrap();
+        let user = load_user(&db, user_id.as_str(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert!(user.sync_folders.is_empty());
+    }
+}
@@ -1,69 +1,175 @@
-use crate::error::ApiError;
-use backup_sync_protocol::SyncFolder;
-use sqlx::{Pool, Sqlite};
-use uuid::Uuid;
+use super::{contains_pattern, unix_now};
+use crate::error::{ApiError, FieldIssue};
+use crate::handlers::folder_handler::{FolderDetail, FolderMember, MemberRole};
+use crate::handlers::pagination::{ListQuery, Page};
+use backup_sync_protocol::{ComputerId, FolderId, SyncFolder};
+use sqlx::{Any, Pool};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+pub const MAX_FOLDER_NAME_LEN: usize = 255;
+/// Most folders [`create_folders`] creates at once
+pub const MAX_BULK_FOLDERS: usize = 50;
+
+/// Fields folder lists can be sorted by, the first one being the default
+pub const FOLDER_SORT_COLUMNS: &[(&str, &str)] = &[
+    ("name", "f.name"),
+    ("pending_operations", "f.pending_operations"),
+    ("id", "f.id"),
+];
+
+/// Columns of the folders table aliased `f` read into a [`FolderRow`]. Booleans are read
+/// as integers, which every backend can return.
+const FOLDER_COLUMNS: &str =
+    "f.id, f.name, f.origin_computer_id, CAST(f.is_synced AS INTEGER), f.pending_operations";
+
+type FolderRow = (String, String, String, i64, i64);
 
 pub async fn create_folder(
-    db: &Pool<Sqlite>,
+    db: &Pool<Any>,
     user_id: &str,
     name: &str,
     computer_id: &str,
 ) -> Result<SyncFolder, ApiError> {
     computer_belongs_to_user(db, computer_id, user_id).await?;
+    super::validate_name("Folder", name, MAX_FOLDER_NAME_LEN)?;
 
-    let folder_id = Uuid::new_v4().to_string();
+    let folder_id = FolderId::new_v4();
+    let id = folder_id.to_string();
 
-    sqlx::query!(
-        "INSERT INTO folders (id, name, origin_computer_id) VALUES (?, ?, ?)",
-        folder_id,
-        name,
-        computer_id
+    sqlx::query(
+        "INSERT INTO folders (id, name, origin_computer_id, origin_joined_at) VALUES ($1, $2, $3, $4)",
     )
+    .bind(&id)
+    .bind(name)
+    .bind(computer_id)
+    .bind(unix_now()?)
     .execute(db)
     .await?;
 
-    Ok(SyncFolder {
-        id: folder_id,
+    Ok(new_folder(folder_id, name, computer_id))
+}
+
+/// Creates the folders named in `folders`, each with its computer as origin, all at once
+/// or none at all. Names must be unique within the batch and among the user's folders,
+/// each offending entry being reported by its index.
+pub async fn create_folders(
+    db: &Pool<Any>,
+    user_id: &str,
+    folders: &[(String, String)],
+) -> Result<Vec<SyncFolder>, ApiError> {
+    if folders.is_empty() || folders.len() > MAX_BULK_FOLDERS {
+        return Err(ApiError::invalid_field(
+            "body",
+            format!("Between 1 and {MAX_BULK_FOLDERS} folders can be created at once"),
+        ));
+    }
+    let computer_ids: HashSet<&str> = folders.iter().map(|(_, id)| id.as_str()).collect();
+    for computer_id in computer_ids {
+        computer_belongs_to_user(db, computer_id, user_id).await?;
+    }
+
+    let mut tx = db.begin().await?;
+    let existing: Vec<String> = sqlx::query_scalar(
+        "
+        SELECT f.name
+        FROM folders f
+        JOIN computers c ON f.origin_computer_id = c.id
+        WHERE c.user_id = $1 AND f.deleted_at IS NULL AND c.deleted_at IS NULL
+    ",
+    )
+    .bind(user_id)
+    .fetch_all(&mut *tx)
+    .await?;
+    let existing: HashSet<String> = existing.into_iter().collect();
+
+    let mut issues = Vec::new();
+    let mut first_index = HashMap::new();
+    for (index, (name, _)) in folders.iter().enumerate() {
+        let field = format!("[{index}].name");
+        if let Err(ApiError::Validation(invalid)) =
+            super::validate_name("Folder", name, MAX_FOLDER_NAME_LEN)
+        {
+            issues.extend(invalid.into_iter().map(|issue| FieldIssue {
+                field: field.clone(),
+                issue: issue.issue,
+            }));
+        } else if existing.contains(name) {
+            issues.push(FieldIssue {
+                field,
+                issue: format!("A folder named '{name}' already exists"),
+            });
+        } else if let Some(first) = first_index.get(name.as_str()) {
+            issues.push(FieldIssue {
+                field,
+                issue: format!("Folder '{name}' is already created at index {first}"),
+            });
+        } else {
+            first_index.insert(name.as_str(), index);
+        }
+    }
+    if !issues.is_empty() {
+        return Err(ApiError::Validation(issues));
+    }
+
+    let now = unix_now()?;
+    let mut created = Vec::with_capacity(folders.len());
+    for (name, computer_id) in folders {
+        let folder_id = FolderId::new_v4();
+        sqlx::query(
+            "INSERT INTO folders (id, name, origin_computer_id, origin_joined_at) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(folder_id.to_string())
+        .bind(name)
+        .bind(computer_id)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+        created.push(new_folder(folder_id, name, computer_id));
+    }
+    tx.commit().await?;
+
+    Ok(created)
+}
+
+/// A folder just created, with no backups yet
+fn new_folder(id: FolderId, name: &str, computer_id: &str) -> SyncFolder {
+    SyncFolder {
+        id,
         name: name.to_string(),
-        origin_computer: computer_id.to_string(),
+        origin_computer: computer_id.into(),
         backup_computers: vec![],
         is_synced: false,
         pending_operations: 0,
-    })
+        backup_status: BTreeMap::new(),
+    }
 }
 
+/// Adds one of the computers of the folder's owner, or of a user who accepted an invite
+/// to it, as a backup. Joining twice is a no-op and the origin cannot back up its own
+/// folder.
 pub async fn join_folder(
-    db: &Pool<Sqlite>,
+    db: &Pool<Any>,
     user_id: &str,
     folder_id: &str,
     computer_id: &str,
 ) -> Result<String, ApiError> {
+    folder_shared_with_user(db, folder_id, user_id).await?;
     computer_belongs_to_user(db, computer_id, user_id).await?;
 
-    // Verify folder exists and get owner
-    let folder_owner = sqlx::query_scalar!(
-        "
-        SELECT c.user_id
-        FROM folders f
-        JOIN computers c ON f.origin_computer_id = c.id
-        WHERE f.id = ?
-    ",
-        folder_id
-    )
-    .fetch_optional(db)
-    .await?;
-
-    if folder_owner != Some(user_id.to_string()) {
-        return Err(ApiError::NotFound(
-            "Folder not found or access denied".to_string(),
+    if origin_of(db, folder_id).await? == computer_id {
+        return Err(ApiError::RuleViolation(
+            "origin_cannot_join",
+            "The origin computer cannot be a backup of its own folder".to_owned(),
         ));
     }
 
-    let result = sqlx::query!(
-        "INSERT INTO folder_backups (folder_id, computer_id) VALUES (?, ?)",
-        folder_id,
-        computer_id
+    let result = sqlx::query(
+        "INSERT INTO folder_backups (folder_id, computer_id, joined_at) VALUES ($1, $2, $3)",
     )
+    .bind(folder_id)
+    .bind(computer_id)
+    .bind(unix_now()?)
     .execute(db)
     .await;
 
@@ -82,159 +188,587 @@ pub async fn join_folder(
     }
 }
 
+/// Removes a backup from the folder. The origin has to switch origin or delete the
+/// folder instead, and a folder left with pending operations is no longer synced.
 pub async fn leave_folder(
-    db: &Pool<Sqlite>,
+    db: &Pool<Any>,
     user_id: &str,
     folder_id: &str,
     computer_id: &str,
 ) -> Result<(), ApiError> {
+    folder_shared_with_user(db, folder_id, user_id).await?;
     computer_belongs_to_user(db, computer_id, user_id).await?;
 
-    sqlx::query!(
-        "DELETE FROM folder_backups WHERE folder_id = ? AND computer_id = ?",
-        folder_id,
-        computer_id
-    )
-    .execute(db)
-    .await?;
+    if origin_of(db, folder_id).await? == computer_id {
+        return Err(ApiError::RuleViolation(
+            "origin_cannot_leave",
+            "The origin computer cannot leave its folder, switch origin or delete the folder"
+                .to_owned(),
+        ));
+    }
+
+    let mut tx = db.begin().await?;
+    sqlx::query("DELETE FROM folder_backups WHERE folder_id = $1 AND computer_id = $2")
+        .bind(folder_id)
+        .bind(computer_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("UPDATE folders SET is_synced = FALSE WHERE id = $1 AND pending_operations > 0")
+        .bind(folder_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
 
     Ok(())
 }
 
+async fn origin_of(db: &Pool<Any>, folder_id: &str) -> Result<String, ApiError> {
+    sqlx::query_scalar("SELECT origin_computer_id FROM folders WHERE id = $1")
+        .bind(folder_id)
+        .fetch_optional(db)
+        .await?
+        .ok_or(ApiError::NotFound("Folder not found".to_owned()))
+}
+
 pub async fn get_folders_by_user(
-    db: &Pool<Sqlite>,
+    db: &Pool<Any>,
     user_id: &str,
 ) -> Result<Vec<SyncFolder>, ApiError> {
     // Simplification: fetch folders where origin belongs to user
-    let folders_data = sqlx::query!(
+    let folders_data: Vec<FolderRow> = sqlx::query_as(&format!(
         "
-        SELECT f.id, f.name, f.origin_computer_id, f.is_synced, f.pending_operations 
+        SELECT {FOLDER_COLUMNS}
         FROM folders f
         JOIN computers c ON f.origin_computer_id = c.id
-        WHERE c.user_id = ?
+        WHERE c.user_id = $1 AND f.deleted_at IS NULL AND c.deleted_at IS NULL
         GROUP BY f.id
-    ",
-        user_id
-    )
+    "
+    ))
+    .bind(user_id)
     .fetch_all(db)
     .await?;
 
     let mut sync_folders = Vec::new();
     for rec in folders_data {
-        // Fetch backups for this folder
-        let backups_data = sqlx::query_scalar!(
-            "SELECT computer_id FROM folder_backups WHERE folder_id = ?",
-            rec.id
-        )
+        sync_folders.push(with_backups(db, rec).await?);
+    }
+
+    Ok(sync_folders)
+}
+
+pub async fn list_folders_page(
+    db: &Pool<Any>,
+    user_id: &str,
+    query: &ListQuery,
+) -> Result<Page<SyncFolder>, ApiError> {
+    let sort = query.sort_column(FOLDER_SORT_COLUMNS)?;
+    let name = contains_pattern(query.name.as_deref().unwrap_or_default());
+    let (limit, offset) = (query.limit(), query.offset());
+
+    let total: i64 = sqlx::query_scalar(
+        r"
+        SELECT COUNT(*)
+        FROM folders f
+        JOIN computers c ON f.origin_computer_id = c.id
+        WHERE c.user_id = $1 AND f.deleted_at IS NULL AND c.deleted_at IS NULL
+          AND lower(f.name) LIKE $2 ESCAPE '\'
+    ",
+    )
+    .bind(user_id)
+    .bind(&name)
+    .fetch_one(db)
+    .await?;
+
+    // Only the sort column and order are spliced in, both picked from fixed lists
+    let sql = format!(
+        r"
+        SELECT f.id
+        FROM folders f
+        JOIN computers c ON f.origin_computer_id = c.id
+        WHERE c.user_id = $1 AND f.deleted_at IS NULL AND c.deleted_at IS NULL
+          AND lower(f.name) LIKE $2 ESCAPE '\'
+        ORDER BY {sort} {}, f.id LIMIT $3 OFFSET $4
+    ",
+        query.order.unwrap_or_default().sql()
+    );
+    let ids: Vec<String> = sqlx::query_scalar(&sql)
+        .bind(user_id)
+        .bind(&name)
+        .bind(i64::from(limit))
+        .bind(i64::from(offset))
         .fetch_all(db)
         .await?;
 
-        sync_folders.push(SyncFolder {
-            id: rec.id,
-            name: rec.name,
-            origin_computer: rec.origin_computer_id,
-            backup_computers: backups_data,
-            is_synced: rec.is_synced,
-            pending_operations: rec.pending_operations as u64,
-        });
+    let mut items = Vec::with_capacity(ids.len());
+    for id in ids {
+        items.push(get_folder(db, &id).await?);
     }
 
-    Ok(sync_folders)
+    Ok(Page {
+        items,
+        total: total as u64,
+        limit,
+        offset,
+    })
 }
 
 pub async fn get_folders_by_computer(
-    db: &Pool<Sqlite>,
+    db: &Pool<Any>,
     user_id: &str,
     computer_id: &str,
 ) -> Result<Vec<SyncFolder>, ApiError> {
     computer_belongs_to_user(db, computer_id, user_id).await?;
 
     // Fetch folders where this computer is the origin OR where it is a backup
-    let folders_data = sqlx::query!(
+    let folders_data: Vec<FolderRow> = sqlx::query_as(&format!(
         "
-        SELECT DISTINCT f.id, f.name, f.origin_computer_id, f.is_synced, f.pending_operations 
+        SELECT DISTINCT {FOLDER_COLUMNS}
         FROM folders f
+        JOIN computers c ON f.origin_computer_id = c.id
         LEFT JOIN folder_backups fb ON f.id = fb.folder_id
-        WHERE f.origin_computer_id = ? OR fb.computer_id = ?
-    ",
-        computer_id,
-        computer_id,
-    )
+        WHERE (f.origin_computer_id = $1 OR fb.computer_id = $1)
+          AND f.deleted_at IS NULL AND c.deleted_at IS NULL
+    "
+    ))
+    .bind(computer_id)
     .fetch_all(db)
     .await?;
 
     let mut sync_folders = Vec::new();
     for rec in folders_data {
-        // Fetch backups for this folder
-        let backups_data = sqlx::query_scalar!(
-            "SELECT computer_id FROM folder_backups WHERE folder_id = ?",
-            rec.id
+        sync_folders.push(with_backups(db, rec).await?);
+    }
+
+    Ok(sync_folders)
+}
+
+pub async fn delete_folder(
+    db: &Pool<Any>,
+    user_id: &str,
+    folder_id: &str,
+    force: bool,
+) -> Result<(), ApiError> {
+    folder_belongs_to_user(db, folder_id, user_id).await?;
+
+    let backups: i64 = sqlx::query_scalar(
+        "
+        SELECT COUNT(*)
+        FROM folder_backups fb
+        JOIN computers c ON fb.computer_id = c.id
+        WHERE fb.folder_id = $1 AND c.deleted_at IS NULL
+    ",
+    )
+    .bind(folder_id)
+    .fetch_one(db)
+    .await?;
+
+    if backups > 0 && !force {
+        return Err(ApiError::Conflict(
+            "Folder has backup computers attached".to_owned(),
+        ));
+    }
+
+    // Only marked as deleted, so the folder keeps its backups until it is purged
+    sqlx::query("UPDATE folders SET deleted_at = $1 WHERE id = $2")
+        .bind(unix_now()?)
+        .bind(folder_id)
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Brings back a folder deleted less than `retention` ago, along with its backups. The
+/// folder's origin has to be restored first if it was removed too.
+pub async fn restore_folder(
+    db: &Pool<Any>,
+    user_id: &str,
+    folder_id: &str,
+    retention: Duration,
+) -> Result<SyncFolder, ApiError> {
+    let (owner, deleted_at, origin_deleted_at): (String, Option<i64>, Option<i64>) =
+        sqlx::query_as(
+            "
+            SELECT c.user_id, f.deleted_at, c.deleted_at
+            FROM folders f
+            JOIN computers c ON f.origin_computer_id = c.id
+            WHERE f.id = $1
+        ",
         )
-        .fetch_all(db)
+        .bind(folder_id)
+        .fetch_optional(db)
+        .await?
+        .ok_or(ApiError::NotFound("Folder not found".to_owned()))?;
+    if owner != user_id {
+        return Err(ApiError::PermissionDenied(
+            "Folder does not belong to user".to_owned(),
+        ));
+    }
+    match deleted_at {
+        None => return Err(ApiError::Conflict("Folder is not deleted".to_owned())),
+        // Past retention it only waits for the purge
+        Some(deleted_at) if deleted_at <= super::retention_cutoff(retention)? => {
+            return Err(ApiError::NotFound("Folder not found".to_owned()));
+        }
+        Some(_) => {}
+    }
+    if origin_deleted_at.is_some() {
+        return Err(ApiError::Conflict(
+            "The folder's origin computer is removed, restore it first".to_owned(),
+        ));
+    }
+
+    sqlx::query("UPDATE folders SET deleted_at = NULL WHERE id = $1")
+        .bind(folder_id)
+        .execute(db)
         .await?;
 
-        sync_folders.push(SyncFolder {
-            id: rec.id,
-            name: rec.name,
-            origin_computer: rec.origin_computer_id,
-            backup_computers: backups_data,
-            is_synced: rec.is_synced,
-            pending_operations: rec.pending_operations as u64,
-        });
+    get_folder(db, folder_id).await
+}
+
+/// Folders that aren't deleted, of every user, for the metrics
+pub async fn count_folders(db: &Pool<Any>) -> Result<u64, ApiError> {
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM folders WHERE deleted_at IS NULL")
+        .fetch_one(db)
+        .await?;
+    Ok(count as u64)
+}
+
+/// Deletes for good the folders deleted at or before `deleted_before`, with their
+/// backups and operations, and returns how many there were
+pub async fn purge_deleted_folders(db: &Pool<Any>, deleted_before: i64) -> Result<u64, ApiError> {
+    let mut tx = db.begin().await?;
+    sqlx::query(
+        "DELETE FROM folder_backups WHERE folder_id IN (SELECT id FROM folders WHERE deleted_at <= $1)",
+    )
+    .bind(deleted_before)
+    .execute(&mut *tx)
+    .await?;
+    let purged = sqlx::query("DELETE FROM folders WHERE deleted_at <= $1")
+        .bind(deleted_before)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(purged.rows_affected())
+}
+
+pub async fn update_folder(
+    db: &Pool<Any>,
+    user_id: &str,
+    folder_id: &str,
+    name: Option<&str>,
+) -> Result<SyncFolder, ApiError> {
+    folder_belongs_to_user(db, folder_id, user_id).await?;
+
+    if let Some(name) = name {
+        super::validate_name("Folder", name, MAX_FOLDER_NAME_LEN)?;
+
+        let taken: i64 = sqlx::query_scalar(
+            "
+            SELECT COUNT(*)
+            FROM folders f
+            JOIN computers c ON f.origin_computer_id = c.id
+            WHERE c.user_id = $1 AND f.name = $2 AND f.id != $3
+              AND f.deleted_at IS NULL AND c.deleted_at IS NULL
+        ",
+        )
+        .bind(user_id)
+        .bind(name)
+        .bind(folder_id)
+        .fetch_one(db)
+        .await?;
+        if taken > 0 {
+            return Err(ApiError::Conflict(format!(
+                "A folder named '{name}' already exists"
+            )));
+        }
+
+        sqlx::query("UPDATE folders SET name = $1, updated_at = CURRENT_TIMESTAMP WHERE id = $2")
+            .bind(name)
+            .bind(folder_id)
+            .execute(db)
+            .await?;
     }
 
-    Ok(sync_folders)
+    // Read back whatever is stored so concurrent updates are reported as they landed
+    get_folder(db, folder_id).await
 }
 
-async fn computer_belongs_to_user(
-    db: &Pool<Sqlite>,
-    id: &str,
+/// Records a folder's sync status so checks like [`switch_origin`] can rely on it.
+pub async fn set_folder_sync_status(
+    db: &Pool<Any>,
+    folder_id: &str,
+    is_synced: bool,
+    pending_operations: u64,
+) -> Result<(), ApiError> {
+    sqlx::query("UPDATE folders SET is_synced = $1, pending_operations = $2 WHERE id = $3")
+        .bind(is_synced)
+        .bind(pending_operations as i64)
+        .bind(folder_id)
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Makes `new_origin`, currently a backup of the folder, its origin and demotes the
+/// old origin to a backup. Only allowed once the folder is fully synced.
+pub async fn switch_origin(
+    db: &Pool<Any>,
+    user_id: &str,
+    folder_id: &str,
+    new_origin: &str,
+) -> Result<SyncFolder, ApiError> {
+    folder_belongs_to_user(db, folder_id, user_id).await?;
+
+    let mut tx = db.begin().await?;
+    let (origin_computer_id, origin_joined_at, is_synced, pending_operations): (
+        String,
+        Option<i64>,
+        i64,
+        i64,
+    ) = sqlx::query_as(
+        "
+        SELECT origin_computer_id, origin_joined_at, CAST(is_synced AS INTEGER), pending_operations
+        FROM folders WHERE id = $1
+    ",
+    )
+    .bind(folder_id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(ApiError::NotFound("Folder not found".to_owned()))?;
+
+    // Both computers keep the time they originally joined the folder
+    let new_origin_joined_at: Option<i64> = sqlx::query_scalar(
+        "
+        SELECT fb.joined_at
+        FROM folder_backups fb
+        JOIN computers c ON fb.computer_id = c.id
+        WHERE fb.folder_id = $1 AND fb.computer_id = $2 AND c.deleted_at IS NULL
+    ",
+    )
+    .bind(folder_id)
+    .bind(new_origin)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(ApiError::InvalidRequest(
+        "Only backup computers can become origin".to_owned(),
+    ))?;
+
+    if is_synced == 0 || pending_operations != 0 {
+        return Err(ApiError::Conflict(
+            "Folder has pending operations and is not fully synced".to_owned(),
+        ));
+    }
+
+    sqlx::query("DELETE FROM folder_backups WHERE folder_id = $1 AND computer_id = $2")
+        .bind(folder_id)
+        .bind(new_origin)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "UPDATE folders SET origin_computer_id = $1, origin_joined_at = $2, updated_at = CURRENT_TIMESTAMP WHERE id = $3",
+    )
+    .bind(new_origin)
+    .bind(new_origin_joined_at)
+    .bind(folder_id)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        "INSERT INTO folder_backups (folder_id, computer_id, joined_at) VALUES ($1, $2, $3)",
+    )
+    .bind(folder_id)
+    .bind(&origin_computer_id)
+    .bind(origin_joined_at)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    get_folder(db, folder_id).await
+}
+
+pub async fn get_folder_detail(
+    db: &Pool<Any>,
+    user_id: &str,
+    folder_id: &str,
+    online_window: Duration,
+) -> Result<FolderDetail, ApiError> {
+    folder_belongs_to_user(db, folder_id, user_id).await?;
+    let online_since = super::computer::online_since(online_window)?;
+    let folder = get_folder(db, folder_id).await?;
+
+    // The id, name and last heartbeat of a member, and when it joined
+    type MemberRow = (String, String, Option<i64>, Option<i64>);
+
+    let origin: MemberRow = sqlx::query_as(
+        "
+        SELECT c.id, c.name, c.last_seen, f.origin_joined_at
+        FROM folders f
+        JOIN computers c ON f.origin_computer_id = c.id
+        WHERE f.id = $1
+    ",
+    )
+    .bind(folder_id)
+    .fetch_one(db)
+    .await?;
+
+    let backups: Vec<MemberRow> = sqlx::query_as(
+        "
+        SELECT c.id, c.name, c.last_seen, fb.joined_at
+        FROM folder_backups fb
+        JOIN computers c ON fb.computer_id = c.id
+        WHERE fb.folder_id = $1 AND c.deleted_at IS NULL
+        ORDER BY fb.joined_at, c.name
+    ",
+    )
+    .bind(folder_id)
+    .fetch_all(db)
+    .await?;
+
+    let member = |(id, name, last_seen, joined_at): MemberRow, role| FolderMember {
+        computer_id: id.into(),
+        name,
+        role,
+        joined_at,
+        online: last_seen.is_some_and(|seen| seen >= online_since),
+    };
+    let mut members = vec![member(origin, MemberRole::Origin)];
+    members.extend(
+        backups
+            .into_iter()
+            .map(|rec| member(rec, MemberRole::Backup)),
+    );
+
+    Ok(FolderDetail { folder, members })
+}
+
+pub(crate) async fn get_folder(db: &Pool<Any>, folder_id: &str) -> Result<SyncFolder, ApiError> {
+    let rec: FolderRow = sqlx::query_as(&format!(
+        "SELECT {FOLDER_COLUMNS} FROM folders f WHERE f.id = $1"
+    ))
+    .bind(folder_id)
+    .fetch_optional(db)
+    .await?
+    .ok_or(ApiError::NotFound("Folder not found".to_owned()))?;
+
+    with_backups(db, rec).await
+}
+
+/// Completes a folder row with the folder's backups
+async fn with_backups(db: &Pool<Any>, rec: FolderRow) -> Result<SyncFolder, ApiError> {
+    let (id, name, origin_computer_id, is_synced, pending_operations) = rec;
+    let backups_data: Vec<String> = sqlx::query_scalar(
+        "
+        SELECT fb.computer_id
+        FROM folder_backups fb
+        JOIN computers c ON fb.computer_id = c.id
+        WHERE fb.folder_id = $1 AND c.deleted_at IS NULL
+    ",
+    )
+    .bind(&id)
+    .fetch_all(db)
+    .await?;
+
+    Ok(SyncFolder {
+        id: id.into(),
+        name,
+        origin_computer: origin_computer_id.into(),
+        backup_computers: backups_data.into_iter().map(ComputerId::from).collect(),
+        is_synced: is_synced != 0,
+        pending_operations: pending_operations as u64,
+        backup_status: BTreeMap::new(),
+    })
+}
+
+pub(crate) async fn folder_belongs_to_user(
+    db: &Pool<Any>,
+    folder_id: &str,
     user_id: &str,
 ) -> Result<(), ApiError> {
-    // Verify computer belongs to user
-    sqlx::query!(
-        "SELECT id FROM computers WHERE id = ? AND user_id = ?",
-        id,
-        user_id
+    let folder_owner: String = sqlx::query_scalar(
+        "
+        SELECT c.user_id
+        FROM folders f
+        JOIN computers c ON f.origin_computer_id = c.id
+        WHERE f.id = $1 AND f.deleted_at IS NULL AND c.deleted_at IS NULL
+    ",
     )
+    .bind(folder_id)
     .fetch_optional(db)
     .await?
-    .ok_or(ApiError::PermissionDenied(
-        "Computer does not belong to user".to_owned(),
-    ))?;
+    .ok_or(ApiError::NotFound("Folder not found".to_owned()))?;
+
+    if folder_owner != user_id {
+        return Err(ApiError::PermissionDenied(
+            "Folder does not belong to user".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Lets through the owner of the folder and the users who accepted an invite to it,
+/// whose computers can back it up
+async fn folder_shared_with_user(
+    db: &Pool<Any>,
+    folder_id: &str,
+    user_id: &str,
+) -> Result<(), ApiError> {
+    let owned = folder_belongs_to_user(db, folder_id, user_id).await;
+    if matches!(owned, Err(ApiError::PermissionDenied(_)))
+        && super::invite::has_accepted_invite(db, folder_id, user_id).await?
+    {
+        return Ok(());
+    }
+    owned
+}
+
+pub(crate) async fn computer_belongs_to_user(
+    db: &Pool<Any>,
+    id: &str,
+    user_id: &str,
+) -> Result<(), ApiError> {
+    // Verify computer belongs to user
+    sqlx::query("SELECT id FROM computers WHERE id = $1 AND user_id = $2 AND deleted_at IS NULL")
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(db)
+        .await?
+        .ok_or(ApiError::PermissionDenied(
+            "Computer does not belong to user".to_owned(),
+        ))?;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::db::init_db;
+    use crate::db::test_db;
     use crate::logic::computer::register_computer;
+    use uuid::Uuid;
 
     #[tokio::test]
     async fn test_create_and_join_folder() {
-        let db = init_db().await.unwrap();
+        let db = test_db().await.unwrap();
         let user_id = Uuid::new_v4().to_string();
-        sqlx::query!(
-            "INSERT INTO users (id, name, password_hash) VALUES (?, ?, ?)",
-            user_id,
-            "testuser",
-            "hash"
-        )
-        .execute(&db)
-        .await
-        .unwrap();
+        sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+            .bind(&user_id)
+            .bind("testuser")
+            .bind("hash")
+            .execute(&db)
+            .await
+            .unwrap();
 
         let comp1 = register_computer(&db, &user_id, "PC1").await.unwrap();
         let comp2 = register_computer(&db, &user_id, "PC2").await.unwrap();
 
-        let folder = create_folder(&db, &user_id, "Docs", &comp1.id)
+        let folder = create_folder(&db, &user_id, "Docs", &comp1.id.to_string())
             .await
             .unwrap();
         assert_eq!(folder.name, "Docs");
         assert_eq!(folder.origin_computer, comp1.id);
 
-        let result = join_folder(&db, &user_id, &folder.id, &comp2.id)
+        let result = join_folder(&db, &user_id, &folder.id.to_string(), &comp2.id.to_string())
             .await
             .unwrap();
         assert_eq!(result, "Joined folder");
@@ -245,14 +779,391 @@ mod tests {
         assert_eq!(folders[0].backup_computers[0], comp2.id);
 
         // Test get_folders_by_computer
-        let comp1_folders = get_folders_by_computer(&db, &user_id, &comp1.id)
+        let comp1_folders = get_folders_by_computer(&db, &user_id, &comp1.id.to_string())
             .await
             .unwrap();
         assert_eq!(comp1_folders.len(), 1); // Origin
 
-        let comp2_folders = get_folders_by_computer(&db, &user_id, &comp2.id)
+        let comp2_folders = get_folders_by_computer(&db, &user_id, &comp2.id.to_string())
             .await
             .unwrap();
         assert_eq!(comp2_folders.len(), 1); // Backup
     }
+
+    #[tokio::test]
+    async fn test_delete_folder() {
+        let db = test_db().await.unwrap();
+        let user_id = Uuid::new_v4().to_string();
+        let other_id = Uuid::new_v4().to_string();
+        for (id, name) in [(&user_id, "testuser"), (&other_id, "otheruser")] {
+            sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+                .bind(id)
+                .bind(name)
+                .bind("hash")
+                .execute(&db)
+                .await
+                .unwrap();
+        }
+
+        let comp1 = register_computer(&db, &user_id, "PC1").await.unwrap();
+        let comp2 = register_computer(&db, &user_id, "PC2").await.unwrap();
+        let folder = create_folder(&db, &user_id, "Docs", &comp1.id.to_string())
+            .await
+            .unwrap();
+        let folder_id = folder.id.to_string();
+        join_folder(&db, &user_id, &folder_id, &comp2.id.to_string())
+            .await
+            .unwrap();
+
+        let result = delete_folder(&db, &other_id, &folder_id, true).await;
+        assert!(matches!(result, Err(ApiError::PermissionDenied(_))));
+
+        let result = delete_folder(&db, &user_id, &folder_id, false).await;
+        assert!(matches!(result, Err(ApiError::Conflict(_))));
+
+        delete_folder(&db, &user_id, &folder_id, true)
+            .await
+            .unwrap();
+        assert!(get_folders_by_user(&db, &user_id).await.unwrap().is_empty());
+        assert!(
+            get_folders_by_computer(&db, &user_id, &comp2.id.to_string())
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        let result = delete_folder(&db, &user_id, &folder_id, true).await;
+        assert!(matches!(result, Err(ApiError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_update_folder() {
+        let db = test_db().await.unwrap();
+        let user_id = Uuid::new_v4().to_string();
+        sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+            .bind(&user_id)
+            .bind("testuser")
+            .bind("hash")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let comp = register_computer(&db, &user_id, "PC1").await.unwrap();
+        let docs = create_folder(&db, &user_id, "Docs", &comp.id.to_string())
+            .await
+            .unwrap();
+        create_folder(&db, &user_id, "Photos", &comp.id.to_string())
+            .await
+            .unwrap();
+        let docs_id = docs.id.to_string();
+
+        let unchanged = update_folder(&db, &user_id, &docs_id, None).await.unwrap();
+        assert_eq!(unchanged.name, "Docs");
+
+        let renamed = update_folder(&db, &user_id, &docs_id, Some("Papers"))
+            .await
+            .unwrap();
+        assert_eq!(renamed.id, docs.id);
+        assert_eq!(renamed.name, "Papers");
+
+        let result = update_folder(&db, &user_id, &docs_id, Some("Photos")).await;
+        assert!(matches!(result, Err(ApiError::Conflict(_))));
+
+        let result = update_folder(&db, &user_id, &docs_id, Some("  ")).await;
+        assert!(matches!(result, Err(ApiError::Validation(_))));
+
+        let long_name = "a".repeat(MAX_FOLDER_NAME_LEN + 1);
+        let result = update_folder(&db, &user_id, &docs_id, Some(&long_name)).await;
+        assert!(matches!(result, Err(ApiError::Validation(_))));
+
+        // Renaming to its own name is not a conflict
+        let same = update_folder(&db, &user_id, &docs_id, Some("Papers"))
+            .await
+            .unwrap();
+        assert_eq!(same.name, "Papers");
+    }
+
+    #[tokio::test]
+    async fn test_switch_origin() {
+        let db = test_db().await.unwrap();
+        let user_id = Uuid::new_v4().to_string();
+        sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+            .bind(&user_id)
+            .bind("testuser")
+            .bind("hash")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let comp1 = register_computer(&db, &user_id, "PC1").await.unwrap();
+        let comp2 = register_computer(&db, &user_id, "PC2").await.unwrap();
+        let comp3 = register_computer(&db, &user_id, "PC3").await.unwrap();
+        let folder = create_folder(&db, &user_id, "Docs", &comp1.id.to_string())
+            .await
+            .unwrap();
+        let folder_id = folder.id.to_string();
+        join_folder(&db, &user_id, &folder_id, &comp2.id.to_string())
+            .await
+            .unwrap();
+
+        let result = switch_origin(&db, &user_id, &folder_id, &comp3.id.to_string()).await;
+        assert!(matches!(result, Err(ApiError::InvalidRequest(_))));
+
+        let result = switch_origin(&db, &user_id, &folder_id, &comp2.id.to_string()).await;
+        assert!(matches!(result, Err(ApiError::Conflict(_))));
+
+        set_folder_sync_status(&db, &folder_id, true, 2)
+            .await
+            .unwrap();
+        let result = switch_origin(&db, &user_id, &folder_id, &comp2.id.to_string()).await;
+        assert!(matches!(result, Err(ApiError::Conflict(_))));
+
+        set_folder_sync_status(&db, &folder_id, true, 0)
+            .await
+            .unwrap();
+
+        let switched = switch_origin(&db, &user_id, &folder_id, &comp2.id.to_string())
+            .await
+            .unwrap();
+        assert_eq!(switched.origin_computer, comp2.id);
+        assert_eq!(switched.backup_computers, vec![comp1.id]);
+    }
+
+    #[tokio::test]
+    async fn test_get_folder_detail() {
+        let db = test_db().await.unwrap();
+        let user_id = Uuid::new_v4().to_string();
+        sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+            .bind(&user_id)
+            .bind("testuser")
+            .bind("hash")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let comp1 = register_computer(&db, &user_id, "PC1").await.unwrap();
+        let comp2 = register_computer(&db, &user_id, "PC2").await.unwrap();
+        let folder = create_folder(&db, &user_id, "Docs", &comp1.id.to_string())
+            .await
+            .unwrap();
+        let folder_id = folder.id.to_string();
+        join_folder(&db, &user_id, &folder_id, &comp2.id.to_string())
+            .await
+            .unwrap();
+        set_folder_sync_status(&db, &folder_id, true, 0)
+            .await
+            .unwrap();
+
+        let before = get_folder_detail(&db, &user_id, &folder_id, Duration::from_secs(60))
+            .await
+            .unwrap();
+        switch_origin(&db, &user_id, &folder_id, &comp2.id.to_string())
+            .await
+            .unwrap();
+        let after = get_folder_detail(&db, &user_id, &folder_id, Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        assert_eq!(after.members.len(), 2);
+        assert_eq!(after.members[0].computer_id, comp2.id);
+        assert_eq!(after.members[0].role, MemberRole::Origin);
+        assert_eq!(after.members[0].joined_at, before.members[1].joined_at);
+        assert_eq!(after.members[1].computer_id, comp1.id);
+        assert_eq!(after.members[1].role, MemberRole::Backup);
+        assert_eq!(after.members[1].joined_at, before.members[0].joined_at);
+    }
+
+    #[tokio::test]
+    async fn test_join_and_leave_rules() {
+        let db = test_db().await.unwrap();
+        let user_id = Uuid::new_v4().to_string();
+        let other_id = Uuid::new_v4().to_string();
+        for (id, name) in [(&user_id, "testuser"), (&other_id, "otheruser")] {
+            sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+                .bind(id)
+                .bind(name)
+                .bind("hash")
+                .execute(&db)
+                .await
+                .unwrap();
+        }
+
+        let origin = register_computer(&db, &user_id, "PC1").await.unwrap();
+        let backup = register_computer(&db, &user_id, "PC2").await.unwrap();
+        let stranger = register_computer(&db, &other_id, "Theirs").await.unwrap();
+        let folder = create_folder(&db, &user_id, "Docs", &origin.id.to_string())
+            .await
+            .unwrap();
+        let folder_id = folder.id.to_string();
+        let (origin_id, backup_id) = (origin.id.to_string(), backup.id.to_string());
+
+        // Someone else's computer, whether they claim the folder or not
+        let result = join_folder(&db, &user_id, &folder_id, &stranger.id.to_string()).await;
+        assert!(matches!(result, Err(ApiError::PermissionDenied(_))));
+        let result = join_folder(&db, &other_id, &folder_id, &stranger.id.to_string()).await;
+        assert!(matches!(result, Err(ApiError::PermissionDenied(_))));
+
+        let result = join_folder(&db, &user_id, &folder_id, &origin_id).await;
+        assert!(matches!(
+            result,
+            Err(ApiError::RuleViolation("origin_cannot_join", _))
+        ));
+
+        let joined = join_folder(&db, &user_id, &folder_id, &backup_id)
+            .await
+            .unwrap();
+        assert_eq!(joined, "Joined folder");
+        let joined = join_folder(&db, &user_id, &folder_id, &backup_id)
+            .await
+            .unwrap();
+        assert_eq!(joined, "Already joined");
+        let folders = get_folders_by_user(&db, &user_id).await.unwrap();
+        assert_eq!(folders[0].backup_computers, vec![backup.id.clone()]);
+
+        let result = leave_folder(&db, &user_id, &folder_id, &origin_id).await;
+        assert!(matches!(
+            result,
+            Err(ApiError::RuleViolation("origin_cannot_leave", _))
+        ));
+
+        set_folder_sync_status(&db, &folder_id, true, 3)
+            .await
+            .unwrap();
+        leave_folder(&db, &user_id, &folder_id, &backup_id)
+            .await
+            .unwrap();
+        let folders = get_folders_by_user(&db, &user_id).await.unwrap();
+        assert!(folders[0].backup_computers.is_empty());
+        assert!(!folders[0].is_synced);
+
+        let result = leave_folder(&db, &user_id, "unknown", &backup_id).await;
+        assert!(matches!(result, Err(ApiError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_restore_and_purge_folder() {
+        const RETENTION: Duration = Duration::from_secs(24 * 3600);
+        let db = test_db().await.unwrap();
+        let user_id = Uuid::new_v4().to_string();
+        sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+            .bind(&user_id)
+            .bind("testuser")
+            .bind("hash")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let comp1 = register_computer(&db, &user_id, "PC1").await.unwrap();
+        let comp2 = register_computer(&db, &user_id, "PC2").await.unwrap();
+        let folder = create_folder(&db, &user_id, "Docs", &comp1.id.to_string())
+            .await
+            .unwrap();
+        let folder_id = folder.id.to_string();
+        join_folder(&db, &user_id, &folder_id, &comp2.id.to_string())
+            .await
+            .unwrap();
+        let other = create_folder(&db, &user_id, "Music", &comp1.id.to_string())
+            .await
+            .unwrap();
+        let other_id = other.id.to_string();
+
+        delete_folder(&db, &user_id, &folder_id, true)
+            .await
+            .unwrap();
+        assert_eq!(get_folders_by_user(&db, &user_id).await.unwrap().len(), 1);
+        let restored = restore_folder(&db, &user_id, &folder_id, RETENTION)
+            .await
+            .unwrap();
+        assert_eq!(restored.backup_computers, vec![comp2.id]);
+        assert_eq!(get_folders_by_user(&db, &user_id).await.unwrap().len(), 2);
+        let result = restore_folder(&db, &user_id, &folder_id, RETENTION).await;
+        assert!(matches!(result, Err(ApiError::Conflict(_))));
+
+        // Deleted two days ago, past retention
+        delete_folder(&db, &user_id, &folder_id, true)
+            .await
+            .unwrap();
+        delete_folder(&db, &user_id, &other_id, true).await.unwrap();
+        sqlx::query("UPDATE folders SET deleted_at = $1 WHERE id = $2")
+            .bind(unix_now().unwrap() - 2 * 24 * 3600)
+            .bind(&folder_id)
+            .execute(&db)
+            .await
+            .unwrap();
+        let result = restore_folder(&db, &user_id, &folder_id, RETENTION).await;
+        assert!(matches!(result, Err(ApiError::NotFound(_))));
+
+        assert_eq!(
+            crate::logic::purge_deleted(&db, RETENTION).await.unwrap(),
+            1
+        );
+        let ids: Vec<String> = sqlx::query_scalar("SELECT id FROM folders")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        assert_eq!(ids, vec![other_id.clone()]);
+        let backups: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM folder_backups")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(backups, 0);
+
+        restore_folder(&db, &user_id, &other_id, RETENTION)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_create_folders_is_all_or_nothing() {
+        let db = test_db().await.unwrap();
+        let user_id = Uuid::new_v4().to_string();
+        sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+            .bind(&user_id)
+            .bind("testuser")
+            .bind("hash")
+            .execute(&db)
+            .await
+            .unwrap();
+        let pc = register_computer(&db, &user_id, "PC").await.unwrap().id;
+        let laptop = register_computer(&db, &user_id, "Laptop").await.unwrap().id;
+        let batch = |names: &[&str]| -> Vec<(String, String)> {
+            names
+                .iter()
+                .enumerate()
+                .map(|(index, name)| {
+                    let computer = if index % 2 == 0 { &pc } else { &laptop };
+                    (name.to_string(), computer.to_string())
+                })
+                .collect()
+        };
+
+        let created = create_folders(&db, &user_id, &batch(&["Documents", "Pictures"]))
+            .await
+            .unwrap();
+        assert_eq!(created.len(), 2);
+        assert_eq!(created[0].name, "Documents");
+        assert_eq!(created[0].origin_computer, pc);
+        assert_eq!(created[1].name, "Pictures");
+        assert_eq!(created[1].origin_computer, laptop);
+
+        let result = create_folders(
+            &db,
+            &user_id,
+            &batch(&["Projects", "Pictures", "", "Projects"]),
+        )
+        .await;
+        let Err(ApiError::Validation(issues)) = result else {
+            panic!("expected a validation error, got {result:?}");
+        };
+        let fields: Vec<&str> = issues.iter().map(|issue| issue.field.as_str()).collect();
+        assert_eq!(fields, ["[1].name", "[2].name", "[3].name"]);
+        assert_eq!(get_folders_by_user(&db, &user_id).await.unwrap().len(), 2);
+
+        let too_many: Vec<String> = (0..=MAX_BULK_FOLDERS).map(|i| format!("f{i}")).collect();
+        let too_many: Vec<&str> = too_many.iter().map(String::as_str).collect();
+        let result = create_folders(&db, &user_id, &batch(&too_many)).await;
+        assert!(matches!(result, Err(ApiError::Validation(_))));
+        let result = create_folders(&db, &user_id, &[]).await;
+        assert!(matches!(result, Err(ApiError::Validation(_))));
+    }
 }
@@ -0,0 +1,175 @@
+//! Responses kept by `Idempotency-Key`, so retries of a request don't run it twice
+
+use super::unix_now;
+use crate::error::ApiError;
+use sha2::{Digest, Sha256};
+use sqlx::{Any, Pool};
+use std::time::Duration;
+
+/// How long a claimed key waits for the response of its request, after which the
+/// request is taken as abandoned, e.g. timed out, and the key can be claimed again
+pub const ABANDONED_AFTER: Duration = Duration::from_secs(60);
+
+/// What a request carrying an idempotency key should do
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeyUse {
+    /// The key is new, the request runs and its response gets stored with
+    /// [`complete_key`]
+    Claimed,
+    /// The same request already ran with the key, and got this response
+    Replay { status: u16, body: String },
+}
+
+/// Hash of a request to `route` with `body`, telling retries from other requests
+/// reusing a key
+pub fn request_hash(route: &str, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(route.as_bytes());
+    hasher.update([0]);
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+/// Claims `key` of `user_id` for the request hashed to `request_hash`, unless it was
+/// used less than `ttl` ago. Using a key for another request, or while its first request
+/// is still running, is a conflict.
+pub async fn claim_key(
+    db: &Pool<Any>,
+    user_id: &str,
+    key: &str,
+    request_hash: &str,
+    ttl: Duration,
+) -> Result<KeyUse, ApiError> {
+    let now = unix_now()?;
+    // Keys are only needed while retries can replay them, claims while their request
+    // could still answer
+    sqlx::query(
+        "DELETE FROM idempotency_keys WHERE created_at <= $1 OR (status IS NULL AND created_at <= $2)",
+    )
+    .bind(now.saturating_sub(ttl.as_secs() as i64))
+    .bind(now.saturating_sub(ABANDONED_AFTER.as_secs() as i64))
+    .execute(db)
+    .await?;
+
+    let claimed = sqlx::query(
+        "
+        INSERT INTO idempotency_keys (user_id, idempotency_key, request_hash, created_at)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT DO NOTHING
+    ",
+    )
+    .bind(user_id)
+    .bind(key)
+    .bind(request_hash)
+    .bind(now)
+    .execute(db)
+    .await?;
+    if claimed.rows_affected() == 1 {
+        return Ok(KeyUse::Claimed);
+    }
+
+    let (stored_hash, status, body): (String, Option<i64>, Option<String>) = sqlx::query_as(
+        "
+        SELECT request_hash, status, body FROM idempotency_keys
+        WHERE user_id = $1 AND idempotency_key = $2
+    ",
+    )
+    .bind(user_id)
+    .bind(key)
+    .fetch_one(db)
+    .await?;
+    if stored_hash != request_hash {
+        return Err(ApiError::Conflict(
+            "Idempotency-Key was already used for a different request".to_owned(),
+        ));
+    }
+    match (status, body) {
+        (Some(status), Some(body)) => Ok(KeyUse::Replay {
+            status: status as u16,
+            body,
+        }),
+        _ => Err(ApiError::Conflict(
+            "A request with this Idempotency-Key is still running".to_owned(),
+        )),
+    }
+}
+
+/// Stores the response of the request that claimed `key`, for retries to replay it
+pub async fn complete_key(
+    db: &Pool<Any>,
+    user_id: &str,
+    key: &str,
+    status: u16,
+    body: &str,
+) -> Result<(), ApiError> {
+    sqlx::query(
+        "UPDATE idempotency_keys SET status = $1, body = $2 WHERE user_id = $3 AND idempotency_key = $4",
+    )
+    .bind(i64::from(status))
+    .bind(body)
+    .bind(user_id)
+    .bind(key)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Frees `key` after its request failed, for a retry to run it again
+pub async fn release_key(db: &Pool<Any>, user_id: &str, key: &str) -> Result<(), ApiError> {
+    sqlx::query("DELETE FROM idempotency_keys WHERE user_id = $1 AND idempotency_key = $2")
+        .bind(user_id)
+        .bind(key)
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+    use uuid::Uuid;
+
+    const TTL: Duration = Duration::from_secs(3600);
+
+    #[tokio::test]
+    async fn test_keys_replay_the_same_request_only() {
+        let db = test_db().await.unwrap();
+        let user_id = Uuid::new_v4().to_string();
+        sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+            .bind(&user_id)
+            .bind("alice")
+            .bind("hash")
+            .execute(&db)
+            .await
+            .unwrap();
+        let docs = request_hash("/folders", br#"{"name":"docs"}"#);
+        let photos = request_hash("/folders", br#"{"name":"photos"}"#);
+
+        let claimed = claim_key(&db, &user_id, "key", &docs, TTL).await.unwrap();
+        assert_eq!(claimed, KeyUse::Claimed);
+        let running = claim_key(&db, &user_id, "key", &docs, TTL).await;
+        assert!(matches!(running, Err(ApiError::Conflict(_))));
+
+        complete_key(&db, &user_id, "key", 201, "{}").await.unwrap();
+        let replay = claim_key(&db, &user_id, "key", &docs, TTL).await.unwrap();
+        assert_eq!(
+            replay,
+            KeyUse::Replay {
+                status: 201,
+                body: "{}".to_owned()
+            }
+        );
+        let reused = claim_key(&db, &user_id, "key", &photos, TTL).await;
+        assert!(matches!(reused, Err(ApiError::Conflict(_))));
+
+        // Expired keys, like released ones, can be claimed again
+        let claimed = claim_key(&db, &user_id, "key", &photos, Duration::ZERO).await;
+        assert_eq!(claimed.unwrap(), KeyUse::Claimed);
+        release_key(&db, &user_id, "key").await.unwrap();
+        let claimed = claim_key(&db, &user_id, "key", &docs, TTL).await.unwrap();
+        assert_eq!(claimed, KeyUse::Claimed);
+    }
+}
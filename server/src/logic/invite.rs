@@ -0,0 +1,253 @@
+//! Invites letting a user's computers back up the folder of another user
+
+use super::folder::folder_belongs_to_user;
+use super::unix_now;
+use crate::error::ApiError;
+use crate::handlers::invite_handler::{Invite, InviteStatus};
+use sqlx::{Any, Pool};
+use std::time::Duration;
+use uuid::Uuid;
+
+const INVITE_COLUMNS: &str = "
+    i.id, i.folder_id, f.name, inviter.name, invitee.name, i.status, i.created_at, i.expires_at
+";
+
+/// Invites with their folder and users, the invites of removed folders left out
+const INVITE_TABLES: &str = "
+    folder_invites i
+    JOIN folders f ON i.folder_id = f.id
+    JOIN users inviter ON i.inviter_id = inviter.id
+    JOIN users invitee ON i.invitee_id = invitee.id
+";
+
+type InviteRow = (String, String, String, String, String, String, i64, i64);
+
+/// Invites the user named `invitee_name` to back up `folder_id`, which only its owner
+/// can do. The invite can be answered for `ttl`.
+pub async fn create_invite(
+    db: &Pool<Any>,
+    owner_id: &str,
+    folder_id: &str,
+    invitee_name: &str,
+    ttl: Duration,
+) -> Result<Invite, ApiError> {
+    folder_belongs_to_user(db, folder_id, owner_id).await?;
+
+    let invitee_id: String = sqlx::query_scalar("SELECT id FROM users WHERE name = $1")
+        .bind(invitee_name)
+        .fetch_optional(db)
+        .await?
+        .ok_or(ApiError::NotFound("User not found".to_owned()))?;
+    if invitee_id == owner_id {
+        return Err(ApiError::InvalidRequest(
+            "Folders can't be shared with their owner".to_owned(),
+        ));
+    }
+
+    let now = unix_now()?;
+    let invited: Option<String> = sqlx::query_scalar(
+        "
+        SELECT id FROM folder_invites
+        WHERE folder_id = $1 AND invitee_id = $2
+          AND (status = 'accepted' OR (status = 'pending' AND expires_at > $3))
+    ",
+    )
+    .bind(folder_id)
+    .bind(&invitee_id)
+    .bind(now)
+    .fetch_optional(db)
+    .await?;
+    if invited.is_some() {
+        return Err(ApiError::Conflict(
+            "User is already invited to the folder".to_owned(),
+        ));
+    }
+
+    let id = Uuid::new_v4().to_string();
+    sqlx::query(
+        "
+        INSERT INTO folder_invites (id, folder_id, inviter_id, invitee_id, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+    ",
+    )
+    .bind(&id)
+    .bind(folder_id)
+    .bind(owner_id)
+    .bind(&invitee_id)
+    .bind(now)
+    .bind(now + ttl.as_secs() as i64)
+    .execute(db)
+    .await?;
+
+    get_invite(db, &id).await
+}
+
+/// The invites addressed to `user_id` that can still be answered, most recent first
+pub async fn list_invites(db: &Pool<Any>, user_id: &str) -> Result<Vec<Invite>, ApiError> {
+    let rows: Vec<InviteRow> = sqlx::query_as(&format!(
+        "
+        SELECT {INVITE_COLUMNS}
+        FROM {INVITE_TABLES}
+        WHERE i.invitee_id = $1 AND i.status = 'pending' AND i.expires_at > $2
+          AND f.deleted_at IS NULL
+        ORDER BY i.created_at DESC, i.id
+    "
+    ))
+    .bind(user_id)
+    .bind(unix_now()?)
+    .fetch_all(db)
+    .await?;
+
+    rows.into_iter().map(invite).collect()
+}
+
+/// Accepts or declines the invite `invite_id` addressed to `user_id`, if it is still
+/// pending and not expired
+pub async fn answer_invite(
+    db: &Pool<Any>,
+    user_id: &str,
+    invite_id: &str,
+    answer: InviteStatus,
+) -> Result<Invite, ApiError> {
+    let (status, expires_at): (String, i64) = sqlx::query_as(
+        "
+        SELECT i.status, i.expires_at
+        FROM folder_invites i
+        JOIN folders f ON i.folder_id = f.id
+        WHERE i.id = $1 AND i.invitee_id = $2 AND f.deleted_at IS NULL
+    ",
+    )
+    .bind(invite_id)
+    .bind(user_id)
+    .fetch_optional(db)
+    .await?
+    .ok_or(ApiError::NotFound("Invite not found".to_owned()))?;
+
+    if status != InviteStatus::Pending.as_str() {
+        return Err(ApiError::Conflict("Invite was already answered".to_owned()));
+    }
+    if expires_at <= unix_now()? {
+        return Err(ApiError::Conflict("Invite expired".to_owned()));
+    }
+
+    // Only a pending invite is updated, in case it was answered in the meantime
+    let result =
+        sqlx::query("UPDATE folder_invites SET status = $1 WHERE id = $2 AND status = 'pending'")
+            .bind(answer.as_str())
+            .bind(invite_id)
+            .execute(db)
+            .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::Conflict("Invite was already answered".to_owned()));
+    }
+
+    get_invite(db, invite_id).await
+}
+
+/// Whether `user_id` accepted an invite to back up `folder_id`
+pub(crate) async fn has_accepted_invite(
+    db: &Pool<Any>,
+    folder_id: &str,
+    user_id: &str,
+) -> Result<bool, ApiError> {
+    let accepted: Option<String> = sqlx::query_scalar(
+        "
+        SELECT id FROM folder_invites
+        WHERE folder_id = $1 AND invitee_id = $2 AND status = 'accepted'
+    ",
+    )
+    .bind(folder_id)
+    .bind(user_id)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(accepted.is_some())
+}
+
+async fn get_invite(db: &Pool<Any>, invite_id: &str) -> Result<Invite, ApiError> {
+    let row: InviteRow = sqlx::query_as(&format!(
+        "SELECT {INVITE_COLUMNS} FROM {INVITE_TABLES} WHERE i.id = $1"
+    ))
+    .bind(invite_id)
+    .fetch_optional(db)
+    .await?
+    .ok_or(ApiError::NotFound("Invite not found".to_owned()))?;
+
+    invite(row)
+}
+
+fn invite(row: InviteRow) -> Result<Invite, ApiError> {
+    let (id, folder_id, folder_name, inviter, invitee, status, created_at, expires_at) = row;
+    let status = InviteStatus::parse(&status).ok_or_else(|| {
+        ApiError::InternalError(anyhow::anyhow!("Unknown invite status '{status}'"))
+    })?;
+
+    Ok(Invite {
+        id,
+        folder_id: folder_id.into(),
+        folder_name,
+        inviter,
+        invitee,
+        status,
+        created_at,
+        expires_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+    use crate::logic::{computer, folder};
+
+    async fn insert_user(db: &Pool<Any>, name: &str) -> String {
+        let user_id = Uuid::new_v4().to_string();
+        sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+            .bind(&user_id)
+            .bind(name)
+            .bind("hash")
+            .execute(db)
+            .await
+            .unwrap();
+        user_id
+    }
+
+    #[tokio::test]
+    async fn test_invites_expire() {
+        let db = test_db().await.unwrap();
+        let owner_id = insert_user(&db, "owner").await;
+        let guest_id = insert_user(&db, "guest").await;
+        let pc = computer::register_computer(&db, &owner_id, "pc")
+            .await
+            .unwrap();
+        let docs = folder::create_folder(&db, &owner_id, "docs", &pc.id.to_string())
+            .await
+            .unwrap();
+        let docs_id = docs.id.to_string();
+
+        let result = create_invite(&db, &guest_id, &docs_id, "owner", Duration::ZERO).await;
+        assert!(matches!(result, Err(ApiError::PermissionDenied(_))));
+        let result = create_invite(&db, &owner_id, &docs_id, "owner", Duration::ZERO).await;
+        assert!(matches!(result, Err(ApiError::InvalidRequest(_))));
+
+        let expired = create_invite(&db, &owner_id, &docs_id, "guest", Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(expired.status, InviteStatus::Pending);
+        assert!(list_invites(&db, &guest_id).await.unwrap().is_empty());
+        let result = answer_invite(&db, &guest_id, &expired.id, InviteStatus::Accepted).await;
+        assert!(matches!(result, Err(ApiError::Conflict(_))));
+        assert!(!has_accepted_invite(&db, &docs_id, &guest_id).await.unwrap());
+
+        // An expired invite doesn't keep the user from being invited again
+        let ttl = Duration::from_secs(60);
+        let invite = create_invite(&db, &owner_id, &docs_id, "guest", ttl)
+            .await
+            .unwrap();
+        let listed = list_invites(&db, &guest_id).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, invite.id);
+        let result = answer_invite(&db, &owner_id, &invite.id, InviteStatus::Accepted).await;
+        assert!(matches!(result, Err(ApiError::NotFound(_))));
+    }
+}
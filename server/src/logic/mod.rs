@@ -1,3 +1,72 @@
 pub mod user;
+pub mod admin;
+pub mod audit;
 pub mod computer;
+pub mod directory;
 pub mod folder;
+pub mod idempotency;
+pub mod invite;
+pub mod operation;
+pub mod token;
+
+use crate::error::ApiError;
+use anyhow::Context;
+use sqlx::{Any, Pool};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Seconds since the Unix epoch
+pub fn unix_now() -> Result<i64, ApiError> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("System time is before UNIX EPOCH")
+        .map_err(ApiError::InternalError)?
+        .as_secs() as i64)
+}
+
+/// Latest deletion time, in seconds since the Unix epoch, of rows kept no longer than
+/// `retention`. Rows deleted at or before it can no longer be restored.
+pub fn retention_cutoff(retention: Duration) -> Result<i64, ApiError> {
+    Ok(unix_now()? - retention.as_secs() as i64)
+}
+
+/// Deletes for good the computers and folders removed more than `retention` ago and
+/// returns how many there were
+pub async fn purge_deleted(db: &Pool<Any>, retention: Duration) -> Result<u64, ApiError> {
+    let deleted_before = retention_cutoff(retention)?;
+    let folders = folder::purge_deleted_folders(db, deleted_before).await?;
+    let computers = computer::purge_deleted_computers(db, deleted_before).await?;
+    Ok(folders + computers)
+}
+
+/// Rejects blank names and names longer than `max_len` characters as an invalid `name`
+/// field, `what` naming the kind of thing in the error
+fn validate_name(what: &str, name: &str, max_len: usize) -> Result<(), ApiError> {
+    if name.trim().is_empty() {
+        return Err(ApiError::invalid_field(
+            "name",
+            format!("{what} name must not be empty"),
+        ));
+    }
+    if name.chars().count() > max_len {
+        return Err(ApiError::invalid_field(
+            "name",
+            format!("{what} name must be at most {max_len} characters"),
+        ));
+    }
+    Ok(())
+}
+
+/// A `LIKE ... ESCAPE '\'` pattern for values containing `text`, ignoring ASCII case when
+/// matched against a lowercased column
+fn contains_pattern(text: &str) -> String {
+    let mut pattern = String::with_capacity(text.len() + 2);
+    pattern.push('%');
+    for c in text.to_ascii_lowercase().chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
@@ -0,0 +1,282 @@
+//! Operations sent to a folder's backups and their acknowledgements. An operation is
+//! pending for every backup that joined before it was created and has not acknowledged
+//! it yet; backups joining later get the folder through a full sync. Removed backups are
+//! left out until restored.
+
+use super::unix_now;
+use crate::error::ApiError;
+use crate::handlers::folder_handler::{BackupLag, FolderOperations, OperationRecord};
+use backup_sync_protocol::ComputerId;
+use sqlx::{Any, Pool};
+use std::collections::BTreeMap;
+
+/// Records an operation the origin sent for the folder. `kind` names the operation, as
+/// the `FileOperation` variant does.
+pub async fn record_operation(
+    db: &Pool<Any>,
+    folder_id: &str,
+    operation_id: u64,
+    kind: &str,
+) -> Result<(), ApiError> {
+    sqlx::query(
+        "
+        INSERT INTO folder_operations (folder_id, id, kind, created_at) VALUES ($1, $2, $3, $4)
+        ON CONFLICT DO NOTHING
+    ",
+    )
+    .bind(folder_id)
+    .bind(operation_id as i64)
+    .bind(kind)
+    .bind(unix_now()?)
+    .execute(db)
+    .await?;
+
+    update_sync_status(db, folder_id).await
+}
+
+/// Records that a backup applied an operation
+pub async fn record_ack(
+    db: &Pool<Any>,
+    folder_id: &str,
+    operation_id: u64,
+    computer_id: &str,
+) -> Result<(), ApiError> {
+    sqlx::query(
+        "
+        INSERT INTO operation_acks (folder_id, operation_id, computer_id, acked_at)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT DO NOTHING
+    ",
+    )
+    .bind(folder_id)
+    .bind(operation_id as i64)
+    .bind(computer_id)
+    .bind(unix_now()?)
+    .execute(db)
+    .await?;
+
+    update_sync_status(db, folder_id).await
+}
+
+/// Keeps the folder's counters, which origin switches check, in line with the acks
+async fn update_sync_status(db: &Pool<Any>, folder_id: &str) -> Result<(), ApiError> {
+    let (pending, _) = pending_operations(db, folder_id).await?;
+    super::folder::set_folder_sync_status(db, folder_id, pending == 0, pending).await
+}
+
+/// Number of pending operations and when the oldest of them was created
+async fn pending_operations(
+    db: &Pool<Any>,
+    folder_id: &str,
+) -> Result<(u64, Option<i64>), ApiError> {
+    let (count, oldest): (i64, Option<i64>) = sqlx::query_as(
+        "
+        SELECT COUNT(*), MIN(o.created_at)
+        FROM folder_operations o
+        WHERE o.folder_id = $1 AND EXISTS (
+            SELECT 1 FROM folder_backups fb
+            JOIN computers c ON fb.computer_id = c.id
+            WHERE fb.folder_id = o.folder_id
+              AND c.deleted_at IS NULL
+              AND COALESCE(fb.joined_at, 0) <= o.created_at
+              AND NOT EXISTS (
+                  SELECT 1 FROM operation_acks a
+                  WHERE a.folder_id = o.folder_id
+                    AND a.operation_id = o.id
+                    AND a.computer_id = fb.computer_id
+              )
+        )
+    ",
+    )
+    .bind(folder_id)
+    .fetch_one(db)
+    .await?;
+
+    Ok((count as u64, oldest))
+}
+
+/// Summarizes what the folder's backups still have to apply, along with its `limit`
+/// most recent operations
+pub async fn get_folder_operations(
+    db: &Pool<Any>,
+    user_id: &str,
+    folder_id: &str,
+    limit: u32,
+) -> Result<FolderOperations, ApiError> {
+    super::folder::folder_belongs_to_user(db, folder_id, user_id).await?;
+
+    let (pending_operations, oldest_pending_at) = pending_operations(db, folder_id).await?;
+
+    let backups: Vec<(String, i64, Option<i64>)> = sqlx::query_as(
+        "
+        SELECT
+            fb.computer_id,
+            (
+                SELECT COUNT(*) FROM folder_operations o
+                WHERE o.folder_id = fb.folder_id
+                  AND COALESCE(fb.joined_at, 0) <= o.created_at
+                  AND NOT EXISTS (
+                      SELECT 1 FROM operation_acks a
+                      WHERE a.folder_id = o.folder_id
+                        AND a.operation_id = o.id
+                        AND a.computer_id = fb.computer_id
+                  )
+            ),
+            (
+                SELECT MAX(a.operation_id) FROM operation_acks a
+                WHERE a.folder_id = fb.folder_id AND a.computer_id = fb.computer_id
+            )
+        FROM folder_backups fb
+        JOIN computers c ON fb.computer_id = c.id
+        WHERE fb.folder_id = $1 AND c.deleted_at IS NULL
+        ORDER BY fb.joined_at, fb.computer_id
+    ",
+    )
+    .bind(folder_id)
+    .fetch_all(db)
+    .await?;
+
+    let operations: Vec<(i64, String, i64)> = sqlx::query_as(
+        "SELECT id, kind, created_at FROM folder_operations WHERE folder_id = $1 ORDER BY id DESC LIMIT $2",
+    )
+    .bind(folder_id)
+    .bind(i64::from(limit))
+    .fetch_all(db)
+    .await?;
+
+    let oldest_listed = operations.last().map_or(0, |(id, _, _)| *id);
+    let acks: Vec<(i64, String)> = sqlx::query_as(
+        "
+        SELECT operation_id, computer_id FROM operation_acks
+        WHERE folder_id = $1 AND operation_id >= $2
+        ORDER BY acked_at, computer_id
+    ",
+    )
+    .bind(folder_id)
+    .bind(oldest_listed)
+    .fetch_all(db)
+    .await?;
+    let mut acked_by: BTreeMap<i64, Vec<ComputerId>> = BTreeMap::new();
+    for (operation_id, computer_id) in acks {
+        acked_by
+            .entry(operation_id)
+            .or_default()
+            .push(computer_id.into());
+    }
+
+    Ok(FolderOperations {
+        pending_operations,
+        oldest_pending_at,
+        backups: backups
+            .into_iter()
+            .map(|(computer_id, pending, last_acked)| BackupLag {
+                computer_id: computer_id.into(),
+                pending_operations: pending as u64,
+                last_acked_operation: last_acked.map(|id| id as u64),
+            })
+            .collect(),
+        recent: operations
+            .into_iter()
+            .map(|(id, kind, created_at)| OperationRecord {
+                id: id as u64,
+                kind,
+                created_at,
+                acked_by: acked_by.remove(&id).unwrap_or_default(),
+            })
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+    use crate::logic::computer::register_computer;
+    use crate::logic::folder::{create_folder, join_folder};
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_folder_operations_aggregate_acks() {
+        let db = test_db().await.unwrap();
+        let user_id = Uuid::new_v4().to_string();
+        sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+            .bind(&user_id)
+            .bind("testuser")
+            .bind("hash")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let origin = register_computer(&db, &user_id, "PC1").await.unwrap();
+        let desktop = register_computer(&db, &user_id, "PC2").await.unwrap();
+        let nas = register_computer(&db, &user_id, "PC3").await.unwrap();
+        let folder = create_folder(&db, &user_id, "Docs", &origin.id.to_string())
+            .await
+            .unwrap();
+        let folder_id = folder.id.to_string();
+        let (desktop_id, nas_id) = (desktop.id.to_string(), nas.id.to_string());
+        join_folder(&db, &user_id, &folder_id, &desktop_id)
+            .await
+            .unwrap();
+        join_folder(&db, &user_id, &folder_id, &nas_id)
+            .await
+            .unwrap();
+
+        for (id, kind) in [(1, "CreateFile"), (2, "RenameFile"), (3, "RemoveFile")] {
+            record_operation(&db, &folder_id, id, kind).await.unwrap();
+        }
+        for id in [1, 2, 3] {
+            record_ack(&db, &folder_id, id, &desktop_id).await.unwrap();
+        }
+        record_ack(&db, &folder_id, 1, &nas_id).await.unwrap();
+
+        let operations = get_folder_operations(&db, &user_id, &folder_id, 2)
+            .await
+            .unwrap();
+        assert_eq!(operations.pending_operations, 2);
+        assert!(operations.oldest_pending_at.is_some());
+
+        let lag: Vec<_> = operations
+            .backups
+            .iter()
+            .map(|b| {
+                (
+                    b.computer_id.clone(),
+                    b.pending_operations,
+                    b.last_acked_operation,
+                )
+            })
+            .collect();
+        assert!(lag.contains(&(desktop.id.clone(), 0, Some(3))));
+        assert!(lag.contains(&(nas.id.clone(), 2, Some(1))));
+
+        let recent: Vec<_> = operations
+            .recent
+            .iter()
+            .map(|op| (op.id, op.kind.as_str()))
+            .collect();
+        assert_eq!(recent, [(3, "RemoveFile"), (2, "RenameFile")]);
+        assert_eq!(operations.recent[0].acked_by, vec![desktop.id.clone()]);
+
+        // The folder counters follow, so switching origin waits for the NAS
+        let folders = crate::logic::folder::get_folders_by_user(&db, &user_id)
+            .await
+            .unwrap();
+        assert_eq!(folders[0].pending_operations, 2);
+        assert!(!folders[0].is_synced);
+
+        for id in [2, 3] {
+            record_ack(&db, &folder_id, id, &nas_id).await.unwrap();
+        }
+        let operations = get_folder_operations(&db, &user_id, &folder_id, 10)
+            .await
+            .unwrap();
+        assert_eq!(operations.pending_operations, 0);
+        assert_eq!(operations.oldest_pending_at, None);
+        assert_eq!(operations.recent.len(), 3);
+        let folders = crate::logic::folder::get_folders_by_user(&db, &user_id)
+            .await
+            .unwrap();
+        assert!(folders[0].is_synced);
+    }
+}
@@ -0,0 +1,182 @@
+use crate::error::ApiError;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use sqlx::{Any, Pool};
+
+/// Issues a refresh token for `user_id`, valid until `expires_at` in seconds since the
+/// Unix epoch. Only its hash is stored.
+pub async fn create_refresh_token(
+    db: &Pool<Any>,
+    user_id: &str,
+    expires_at: i64,
+) -> Result<String, ApiError> {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let token = hex::encode(bytes);
+    let token_hash = hash_token(&token);
+
+    sqlx::query("INSERT INTO refresh_tokens (token_hash, user_id, expires_at) VALUES ($1, $2, $3)")
+        .bind(token_hash)
+        .bind(user_id)
+        .bind(expires_at)
+        .execute(db)
+        .await?;
+
+    Ok(token)
+}
+
+/// Revokes `token` and returns the user it was issued to, so every refresh token is
+/// exchanged at most once
+pub async fn consume_refresh_token(
+    db: &Pool<Any>,
+    token: &str,
+    now: i64,
+) -> Result<String, ApiError> {
+    let token_hash = hash_token(token);
+
+    sqlx::query_scalar(
+        "
+        UPDATE refresh_tokens SET revoked = TRUE
+        WHERE token_hash = $1 AND NOT revoked AND expires_at > $2
+        RETURNING user_id
+    ",
+    )
+    .bind(token_hash)
+    .bind(now)
+    .fetch_optional(db)
+    .await?
+    .ok_or(ApiError::AuthenticationFailed(
+        "Invalid refresh token".to_owned(),
+    ))
+}
+
+/// Revokes one of `user_id`'s refresh tokens. Unknown tokens are ignored.
+pub async fn revoke_refresh_token(
+    db: &Pool<Any>,
+    user_id: &str,
+    token: &str,
+) -> Result<(), ApiError> {
+    let token_hash = hash_token(token);
+
+    sqlx::query("UPDATE refresh_tokens SET revoked = TRUE WHERE token_hash = $1 AND user_id = $2")
+        .bind(token_hash)
+        .bind(user_id)
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn revoke_all_refresh_tokens(db: &Pool<Any>, user_id: &str) -> Result<(), ApiError> {
+    sqlx::query("UPDATE refresh_tokens SET revoked = TRUE WHERE user_id = $1")
+        .bind(user_id)
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Denies the access token `jti` until it expires on its own at `expires_at`
+pub async fn revoke_access_token(
+    db: &Pool<Any>,
+    jti: &str,
+    expires_at: i64,
+    now: i64,
+) -> Result<(), ApiError> {
+    // Entries are only needed while their token could still pass validation
+    sqlx::query("DELETE FROM revoked_access_tokens WHERE expires_at <= $1")
+        .bind(now)
+        .execute(db)
+        .await?;
+
+    sqlx::query(
+        "INSERT INTO revoked_access_tokens (jti, expires_at) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+    )
+    .bind(jti)
+    .bind(expires_at)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn is_access_token_revoked(db: &Pool<Any>, jti: &str) -> Result<bool, ApiError> {
+    let revoked: Option<String> =
+        sqlx::query_scalar("SELECT jti FROM revoked_access_tokens WHERE jti = $1")
+            .bind(jti)
+            .fetch_optional(db)
+            .await?;
+
+    Ok(revoked.is_some())
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_refresh_token_is_used_once() {
+        let db = test_db().await.unwrap();
+        let user_id = Uuid::new_v4().to_string();
+        sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+            .bind(&user_id)
+            .bind("testuser")
+            .bind("hash")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let token = create_refresh_token(&db, &user_id, 200).await.unwrap();
+        let expired = create_refresh_token(&db, &user_id, 50).await.unwrap();
+
+        assert_eq!(
+            consume_refresh_token(&db, &token, 100).await.unwrap(),
+            user_id
+        );
+        let result = consume_refresh_token(&db, &token, 100).await;
+        assert!(matches!(result, Err(ApiError::AuthenticationFailed(_))));
+
+        let result = consume_refresh_token(&db, &expired, 100).await;
+        assert!(matches!(result, Err(ApiError::AuthenticationFailed(_))));
+
+        let result = consume_refresh_token(&db, "unknown", 100).await;
+        assert!(matches!(result, Err(ApiError::AuthenticationFailed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_revoke_tokens() {
+        let db = test_db().await.unwrap();
+        let user_id = Uuid::new_v4().to_string();
+        sqlx::query("INSERT INTO users (id, name, password_hash) VALUES ($1, $2, $3)")
+            .bind(&user_id)
+            .bind("testuser")
+            .bind("hash")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let first = create_refresh_token(&db, &user_id, 200).await.unwrap();
+        let second = create_refresh_token(&db, &user_id, 200).await.unwrap();
+        let third = create_refresh_token(&db, &user_id, 200).await.unwrap();
+
+        revoke_refresh_token(&db, &user_id, &first).await.unwrap();
+        assert!(consume_refresh_token(&db, &first, 100).await.is_err());
+
+        revoke_all_refresh_tokens(&db, &user_id).await.unwrap();
+        assert!(consume_refresh_token(&db, &second, 100).await.is_err());
+        assert!(consume_refresh_token(&db, &third, 100).await.is_err());
+
+        revoke_access_token(&db, "old", 50, 10).await.unwrap();
+        revoke_access_token(&db, "jti", 200, 100).await.unwrap();
+        assert!(is_access_token_revoked(&db, "jti").await.unwrap());
+        assert!(!is_access_token_revoked(&db, "other").await.unwrap());
+        // Pruned once it expired
+        assert!(!is_access_token_revoked(&db, "old").await.unwrap());
+    }
+}
@@ -1,38 +1,70 @@
+use crate::auth::Role;
 use crate::error::ApiError;
 use backup_sync_protocol::User;
-use sqlx::{Pool, Sqlite};
+use sqlx::{Any, Pool};
+use std::time::Duration;
 
-pub async fn get_user_state(db: &Pool<Sqlite>, user_id: &str) -> Result<User, ApiError> {
+pub async fn get_user_state(
+    db: &Pool<Any>,
+    user_id: &str,
+    online_window: Duration,
+) -> Result<User, ApiError> {
     // Fetch user name
-    let user_name = sqlx::query_scalar!("SELECT name FROM users WHERE id = ?", user_id)
+    let user_name = sqlx::query_scalar("SELECT name FROM users WHERE id = $1")
+        .bind(user_id)
         .fetch_optional(db)
         .await?
         .ok_or(ApiError::UserNotFound)?;
 
     // Fetch computers
-    let computers = crate::logic::computer::get_computers_by_user(db, user_id).await?;
+    let computers =
+        crate::logic::computer::get_computers_by_user(db, user_id, online_window).await?;
 
     // Fetch folders
     let sync_folders = crate::logic::folder::get_folders_by_user(db, user_id).await?;
 
     Ok(User {
-        id: user_id.to_string(),
+        id: user_id.into(),
         name: user_name,
         computers,
         sync_folders,
     })
 }
 
+/// The role stored for `user_id`, which tokens issued from now on carry
+pub async fn get_role(db: &Pool<Any>, user_id: &str) -> Result<Role, ApiError> {
+    let role: String = sqlx::query_scalar("SELECT role FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(db)
+        .await?
+        .ok_or(ApiError::UserNotFound)?;
+
+    parse_role(&role)
+}
+
+/// Registered users, for the metrics
+pub async fn count_users(db: &Pool<Any>) -> Result<u64, ApiError> {
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+        .fetch_one(db)
+        .await?;
+    Ok(count as u64)
+}
+
+pub(crate) fn parse_role(role: &str) -> Result<Role, ApiError> {
+    Role::parse(role)
+        .ok_or_else(|| ApiError::InternalError(anyhow::anyhow!("Unknown role '{role}'")))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::db::init_db;
+    use crate::db::test_db;
     use crate::logic::user::get_user_state;
 
     #[tokio::test]
     async fn test_get_user_state_not_found() {
-        let db = init_db().await.unwrap();
-        let result = get_user_state(&db, "non_existent").await;
+        let db = test_db().await.unwrap();
+        let result = get_user_state(&db, "non_existent", Duration::from_secs(60)).await;
         assert!(matches!(result, Err(ApiError::UserNotFound)));
     }
 }
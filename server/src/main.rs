@@ -19,9 +19,13 @@ async fn main() -> anyhow::Result<()> {
     let listener = tokio::net::TcpListener::bind(addr)
         .await
         .context("Failed to bind to address")?;
-    axum::serve(listener, app)
-        .await
-        .context("Server error")?;
+    // Peer addresses key the rate limits of the auth routes
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .context("Server error")?;
 
     Ok(())
 }
@@ -0,0 +1,178 @@
+//! Metrics of the HTTP API in the Prometheus text format, served at `/metrics`
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+pub const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Label of the requests that matched no route, so unknown paths don't each get a series
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Method and route template of requests, like `GET` and `/folders/{id}`
+type Route = (String, String);
+
+/// Counters of the requests served since startup, and totals of the database refreshed
+/// in the background
+#[derive(Debug, Default)]
+pub struct HttpMetrics {
+    /// Requests by route and status code
+    requests: Mutex<BTreeMap<(Route, u16), u64>>,
+    latencies: Mutex<BTreeMap<Route, Histogram>>,
+    pub users: AtomicU64,
+    /// Folders that aren't deleted
+    pub folders: AtomicU64,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    /// Requests at or under each of [`LATENCY_BUCKETS`], not cumulated
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl HttpMetrics {
+    /// Counts a request to `route` answered with `status` after `latency`
+    pub fn record(&self, method: &str, route: &str, status: u16, latency: Duration) {
+        let route = (method.to_owned(), route.to_owned());
+        let seconds = latency.as_secs_f64();
+
+        let mut latencies = self
+            .latencies
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let histogram = latencies.entry(route.clone()).or_default();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&bound| seconds <= bound) {
+            histogram.buckets[bucket] += 1;
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+        drop(latencies);
+
+        let mut requests = self.requests.lock().unwrap_or_else(PoisonError::into_inner);
+        *requests.entry((route, status)).or_default() += 1;
+    }
+
+    /// The metrics in the Prometheus text format
+    #[must_use]
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let header = |out: &mut String, name: &str, kind: &str, help: &str| {
+            let _ = writeln!(out, "# HELP backup_sync_{name} {help}");
+            let _ = writeln!(out, "# TYPE backup_sync_{name} {kind}");
+        };
+
+        header(&mut out, "users", "gauge", "Registered users");
+        let _ = writeln!(
+            out,
+            "backup_sync_users {}",
+            self.users.load(Ordering::Relaxed)
+        );
+        header(&mut out, "folders", "gauge", "Folders that aren't deleted");
+        let _ = writeln!(
+            out,
+            "backup_sync_folders {}",
+            self.folders.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "http_requests_total",
+            "counter",
+            "HTTP requests by route and status code",
+        );
+        let requests = self.requests.lock().unwrap_or_else(PoisonError::into_inner);
+        for (((method, route), status), count) in requests.iter() {
+            let _ = writeln!(
+                out,
+                "backup_sync_http_requests_total{{method=\"{method}\",route=\"{route}\",status=\"{status}\"}} {count}"
+            );
+        }
+        drop(requests);
+
+        header(
+            &mut out,
+            "http_request_duration_seconds",
+            "histogram",
+            "Time taken to answer HTTP requests by route",
+        );
+        let latencies = self
+            .latencies
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        for ((method, route), histogram) in latencies.iter() {
+            let labels = format!("method=\"{method}\",route=\"{route}\"");
+            let mut cumulated = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                cumulated += count;
+                let _ = writeln!(
+                    out,
+                    "backup_sync_http_request_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {cumulated}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "backup_sync_http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "backup_sync_http_request_duration_seconds_sum{{{labels}}} {}",
+                histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "backup_sync_http_request_duration_seconds_count{{{labels}}} {}",
+                histogram.count
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requests_are_counted_by_route_and_status() {
+        let metrics = HttpMetrics::default();
+        metrics.record("GET", "/folders", 200, Duration::from_millis(3));
+        metrics.record("GET", "/folders", 200, Duration::from_millis(30));
+        metrics.record("GET", "/folders", 401, Duration::from_secs(20));
+        metrics.record("POST", "/folders", 201, Duration::from_millis(3));
+        metrics.users.store(4, Ordering::Relaxed);
+
+        let text = metrics.to_prometheus();
+        assert!(text.contains("backup_sync_users 4\n"));
+        assert!(text.contains("backup_sync_folders 0\n"));
+        assert!(text.contains(
+            "backup_sync_http_requests_total{method=\"GET\",route=\"/folders\",status=\"200\"} 2\n"
+        ));
+        assert!(text.contains(
+            "backup_sync_http_requests_total{method=\"GET\",route=\"/folders\",status=\"401\"} 1\n"
+        ));
+        let get = "method=\"GET\",route=\"/folders\"";
+        assert!(text.contains(&format!(
+            "backup_sync_http_request_duration_seconds_bucket{{{get},le=\"0.005\"}} 1\n"
+        )));
+        assert!(text.contains(&format!(
+            "backup_sync_http_request_duration_seconds_bucket{{{get},le=\"0.05\"}} 2\n"
+        )));
+        assert!(text.contains(&format!(
+            "backup_sync_http_request_duration_seconds_bucket{{{get},le=\"10\"}} 2\n"
+        )));
+        assert!(text.contains(&format!(
+            "backup_sync_http_request_duration_seconds_bucket{{{get},le=\"+Inf\"}} 3\n"
+        )));
+        assert!(text.contains(&format!(
+            "backup_sync_http_request_duration_seconds_count{{{get}}} 3\n"
+        )));
+    }
+}
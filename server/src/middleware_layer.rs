@@ -1,13 +1,31 @@
-use crate::AppState;
+use crate::{API_PREFIX, AppState};
 use crate::auth::Claims;
-use crate::error::ApiError;
+use crate::error::{ApiError, ErrorBody};
+use crate::handlers::auth_handler::{LOGIN_PATHS, SIGNUP_PATHS};
+use crate::logic::idempotency::{self, KeyUse};
+use crate::metrics::UNMATCHED_ROUTE;
+use crate::rate_limit::BucketKey;
 use axum::{
-    extract::{Request, State},
-    http::header,
+    body::Body,
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
+    Json,
 };
-use jsonwebtoken::{DecodingKey, Validation, decode};
+use jsonwebtoken::{DecodingKey, Validation, decode, errors::ErrorKind};
+use std::net::SocketAddr;
+use std::time::Instant;
+use tower_http::request_id::RequestId;
+
+/// Routes whose POST requests can carry an `Idempotency-Key`, see [`idempotency`]
+pub const IDEMPOTENT_ROUTES: [&str; 3] = ["/computers", "/folders", "/folders/bulk"];
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// Set on responses replayed for an `Idempotency-Key`
+pub const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+/// Largest body of requests with an `Idempotency-Key`, buffered to be hashed
+const MAX_IDEMPOTENT_BODY: usize = 64 * 1024;
 
 pub async fn auth_middleware(
     State(state): State<AppState>,
@@ -36,9 +54,215 @@ pub async fn auth_middleware(
         &DecodingKey::from_secret(state.jwt_secret.as_bytes()),
         &Validation::default(),
     )
-    .map_err(|_| ApiError::AuthenticationFailed("Invalid token".to_string()))?;
+    .map_err(|e| match e.kind() {
+        ErrorKind::ExpiredSignature => ApiError::TokenExpired,
+        _ => ApiError::InvalidToken,
+    })?;
+
+    if let Some(jti) = &token_data.claims.jti
+        && crate::logic::token::is_access_token_revoked(&state.db, jti).await?
+    {
+        return Err(ApiError::TokenRevoked);
+    }
 
     req.extensions_mut().insert(token_data.claims);
 
     Ok(next.run(req).await)
 }
+
+/// Counts requests of authenticated routes against the user's quota, so has to run
+/// after [`auth_middleware`]
+pub async fn user_rate_limit(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let claims = req
+        .extensions()
+        .get::<Claims>()
+        .ok_or(ApiError::AuthenticationFailed("Missing token claims".to_string()))?;
+
+    let key = BucketKey::User(claims.sub.clone());
+    state
+        .rate_limiter
+        .take(&key, state.rate_limits.api)
+        .map_err(ApiError::RateLimited)?;
+
+    Ok(next.run(req).await)
+}
+
+/// Counts requests of the public auth routes against the quota of the caller's address,
+/// login attempts and signups against stricter ones of their own
+pub async fn ip_rate_limit(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let route = unversioned_route(&req);
+
+    let (key, quota) = match route {
+        Some(route) if LOGIN_PATHS.contains(&route) => {
+            (BucketKey::Login(ip), state.rate_limits.login)
+        }
+        Some(route) if SIGNUP_PATHS.contains(&route) => {
+            (BucketKey::Signup(ip), state.rate_limits.signup)
+        }
+        _ => (BucketKey::Auth(ip), state.rate_limits.auth),
+    };
+    state
+        .rate_limiter
+        .take(&key, quota)
+        .map_err(ApiError::RateLimited)?;
+
+    Ok(next.run(req).await)
+}
+
+/// Fills in the request id of error bodies, so has to run once the id is set
+pub async fn error_request_id(req: Request, next: Next) -> Response {
+    let request_id = req
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .map(str::to_owned);
+    let mut response = next.run(req).await;
+
+    let Some(mut body) = response.extensions_mut().remove::<ErrorBody>() else {
+        return response;
+    };
+    body.request_id = request_id;
+    let (parts, _) = response.into_parts();
+    (parts, Json(body)).into_response()
+}
+
+/// Counts requests and how long they took by route in the metrics, so has to run once
+/// the route is matched
+pub async fn record_metrics(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(UNMATCHED_ROUTE, MatchedPath::as_str)
+        .to_owned();
+    let started = Instant::now();
+    let response = next.run(req).await;
+
+    state
+        .metrics
+        .record(&method, &route, response.status().as_u16(), started.elapsed());
+    response
+}
+
+/// Answers a POST request to one of [`IDEMPOTENT_ROUTES`] with the response of an
+/// earlier request of the user carrying the same `Idempotency-Key` and body, the
+/// response being kept only when it succeeded. Keys belong to users, so this has to run
+/// after [`auth_middleware`].
+pub async fn idempotency(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let route = unversioned_route(&req)
+        .filter(|route| IDEMPOTENT_ROUTES.contains(route))
+        .map(str::to_owned);
+    let key = req.headers().get(IDEMPOTENCY_KEY);
+    let (Some(route), Some(key)) = (route, key) else {
+        return Ok(next.run(req).await);
+    };
+    if req.method() != Method::POST {
+        return Ok(next.run(req).await);
+    }
+    let key = key
+        .to_str()
+        .ok()
+        .filter(|key| !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN)
+        .ok_or_else(|| {
+            ApiError::invalid_field(
+                "Idempotency-Key",
+                format!("must be 1 to {MAX_IDEMPOTENCY_KEY_LEN} visible ASCII characters"),
+            )
+        })?
+        .to_owned();
+    let user_id = req
+        .extensions()
+        .get::<Claims>()
+        .ok_or(ApiError::AuthenticationFailed("Missing token claims".to_string()))?
+        .sub
+        .clone();
+
+    let (parts, body) = req.into_parts();
+    let body = axum::body::to_bytes(body, MAX_IDEMPOTENT_BODY).await.map_err(|_| {
+        ApiError::Rejected(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            format!("Requests with an Idempotency-Key take at most {MAX_IDEMPOTENT_BODY} bytes"),
+        )
+    })?;
+    let request_hash = idempotency::request_hash(&route, &body);
+    let claimed =
+        idempotency::claim_key(&state.db, &user_id, &key, &request_hash, state.idempotency_ttl)
+            .await?;
+    if let KeyUse::Replay { status, body } = claimed {
+        let status = StatusCode::from_u16(status).unwrap_or(StatusCode::OK);
+        let headers = [
+            (header::CONTENT_TYPE, "application/json"),
+            (header::HeaderName::from_static(IDEMPOTENT_REPLAYED), "true"),
+        ];
+        return Ok((status, headers, body).into_response());
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    let status = response.status();
+    let (parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(err) => {
+            release_idempotency_key(&state, &user_id, &key).await;
+            return Err(ApiError::InternalError(err.into()));
+        }
+    };
+    match std::str::from_utf8(&body) {
+        Ok(text) if status.is_success() => {
+            let completed =
+                idempotency::complete_key(&state.db, &user_id, &key, status.as_u16(), text).await;
+            if let Err(err) = completed {
+                tracing::error!("Failed to store the response of an Idempotency-Key: {:?}", err);
+            }
+        }
+        _ => release_idempotency_key(&state, &user_id, &key).await,
+    }
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+/// Lets a retry run the request of `key` again. Failing to is only logged, the key
+/// being freed anyway once its claim is taken as abandoned.
+async fn release_idempotency_key(state: &AppState, user_id: &str, key: &str) {
+    if let Err(err) = idempotency::release_key(&state.db, user_id, key).await {
+        tracing::error!("Failed to release an Idempotency-Key: {:?}", err);
+    }
+}
+
+/// Marks the responses of routes reached without [`API_PREFIX`] as deprecated, linking
+/// to the same route with it
+pub async fn deprecated_alias(req: Request, next: Next) -> Response {
+    let successor = format!("<{API_PREFIX}{}>; rel=\"successor-version\"", req.uri().path());
+    let mut response = next.run(req).await;
+
+    let headers = response.headers_mut();
+    headers.insert("deprecation", HeaderValue::from_static("true"));
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.insert(header::LINK, link);
+    }
+    response
+}
+
+/// The route `req` matched without [`API_PREFIX`], so versioned routes and their
+/// deprecated aliases are told apart by neither
+fn unversioned_route(req: &Request) -> Option<&str> {
+    let route = req.extensions().get::<MatchedPath>()?.as_str();
+    Some(route.strip_prefix(API_PREFIX).unwrap_or(route))
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Requests a caller may make per minute, all at once or spread over the minute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub per_minute: u32,
+}
+
+/// Quotas of the buckets requests are counted in
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimits {
+    /// Authenticated routes, per user
+    pub api: Quota,
+    /// Public auth routes other than login and signup, per IP address
+    pub auth: Quota,
+    /// Login attempts, per IP address
+    pub login: Quota,
+    /// Signups, per IP address
+    pub signup: Quota,
+}
+
+/// The bucket a request is counted in. The address is unknown when the server is not
+/// given the peer address of connections, in which case all such requests share it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BucketKey {
+    User(String),
+    Auth(Option<IpAddr>),
+    Login(Option<IpAddr>),
+    Signup(Option<IpAddr>),
+}
+
+/// Where the token buckets live. [`MemoryBuckets`] keeps them per process; a store
+/// shared by several servers can implement this as well.
+pub trait BucketStore: Send + Sync {
+    /// Takes a token from the bucket of `key`, or tells how long until one is available
+    fn take(&self, key: &BucketKey, quota: Quota) -> Result<(), Duration>;
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, quota: Quota, now: Instant) {
+        let per_second = f64::from(quota.per_minute) / 60.0;
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(f64::from(quota.per_minute));
+        self.refilled_at = now;
+    }
+}
+
+/// Buckets held in memory, so every process counts requests on its own
+#[derive(Debug, Default)]
+pub struct MemoryBuckets {
+    buckets: Mutex<HashMap<BucketKey, (TokenBucket, Quota)>>,
+}
+
+/// Buckets kept before full ones are dropped, as they are no different from new ones
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+impl BucketStore for MemoryBuckets {
+    fn take(&self, key: &BucketKey, quota: Quota) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        if buckets.len() >= MAX_IDLE_BUCKETS {
+            buckets.retain(|_, (bucket, quota)| {
+                bucket.refill(*quota, now);
+                bucket.tokens < f64::from(quota.per_minute)
+            });
+        }
+
+        let (bucket, _) = buckets.entry(key.clone()).or_insert_with(|| {
+            let bucket = TokenBucket {
+                tokens: f64::from(quota.per_minute),
+                refilled_at: now,
+            };
+            (bucket, quota)
+        });
+        bucket.refill(quota, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let per_second = f64::from(quota.per_minute) / 60.0;
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets_refill_per_key() {
+        let buckets = MemoryBuckets::default();
+        let quota = Quota { per_minute: 2 };
+        let alice = BucketKey::User("alice".to_string());
+        let bob = BucketKey::User("bob".to_string());
+
+        assert!(buckets.take(&alice, quota).is_ok());
+        assert!(buckets.take(&alice, quota).is_ok());
+        // Two a minute, so the next token comes within 30 seconds
+        let wait = buckets.take(&alice, quota).unwrap_err();
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30));
+        assert!(buckets.take(&bob, quota).is_ok());
+
+        let fast = Quota { per_minute: 1200 };
+        let key = BucketKey::Login(None);
+        for _ in 0..1200 {
+            buckets.take(&key, fast).unwrap();
+        }
+        assert!(buckets.take(&key, fast).is_err());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(buckets.take(&key, fast).is_ok());
+    }
+}
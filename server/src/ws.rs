@@ -0,0 +1,149 @@
+//! The websocket sync protocol of `backup_sync_ws`, served at `/ws` on the users,
+//! computers and folders of this server
+
+use crate::error::ApiError;
+use crate::logic;
+use backup_sync_protocol::User;
+use backup_sync_ws::auth::TokenValidator;
+use backup_sync_ws::broadcast::Broadcasts;
+use backup_sync_ws::journal::Journal;
+use backup_sync_ws::server::{ConnectionLimits, ServerConfig};
+use backup_sync_ws::state::{DirectoryChange, ServerState};
+use sqlx::{Any, Pool};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{RwLock, mpsc, oneshot, watch};
+
+/// State of the websocket connections, shared by all of them. Users are loaded into it
+/// from the database as their connections are upgraded, and the changes clients make
+/// over websocket are written back to the database in the order they are made.
+pub struct WsHub {
+    pub state: Arc<RwLock<ServerState>>,
+    pub broadcasts: Broadcasts,
+    pub limits: ConnectionLimits,
+    /// Asks the task writing changes to the database how many it wrote, once it wrote
+    /// those made before
+    flushes: mpsc::UnboundedSender<oneshot::Sender<u64>>,
+    /// Never turns true: connections end with the process
+    shutdown: watch::Sender<bool>,
+}
+
+impl WsHub {
+    /// Accepts the tokens signed with `jwt_secret`, like the HTTP routes, with the
+    /// defaults of the standalone websocket server. Spawns the task writing changes to
+    /// `db`, so this has to run within a Tokio runtime.
+    pub fn new(db: Pool<Any>, jwt_secret: &str) -> Self {
+        let config = ServerConfig::default();
+        let (changes_tx, changes) = mpsc::unbounded_channel();
+        let (flushes, flush_requests) = mpsc::unbounded_channel();
+        let mut state = ServerState::new();
+        state.journal = Journal::new(config.journal_capacity);
+        state.token_validator = Some(TokenValidator::new(jwt_secret));
+        state.rate_limit = config.rate_limit;
+        state.max_file_content_bytes = Some(config.max_file_content_bytes);
+        state.max_pending_operations = config.max_pending_operations;
+        state.directory_changes = Some(changes_tx);
+        tokio::spawn(mirror_changes(db, changes, flush_requests));
+
+        Self {
+            state: Arc::new(RwLock::new(state)),
+            broadcasts: Broadcasts::new(config.broadcast_capacity),
+            limits: config.connection_limits(),
+            flushes,
+            shutdown: watch::Sender::new(false),
+        }
+    }
+
+    pub fn shutdown_signal(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
+    }
+
+    /// Loads a user from the database into the state. The changes websocket clients made
+    /// are written first, for the user read back to have them: a change made while the
+    /// user is read has it read again, with the connections held off.
+    pub async fn load_user(
+        &self,
+        db: &Pool<Any>,
+        user_id: &str,
+        online_window: Duration,
+    ) -> Result<(), ApiError> {
+        let written = self.flush_changes().await;
+        let mut user = logic::directory::load_user(db, user_id, online_window).await?;
+        let mut state = self.state.write().await;
+        if self.flush_changes().await != written {
+            user = logic::directory::load_user(db, user_id, online_window).await?;
+        }
+        state.sync_user(user);
+        Ok(())
+    }
+
+    /// Number of changes written to the database, once those made so far are
+    async fn flush_changes(&self) -> u64 {
+        let (written_tx, written) = oneshot::channel();
+        if self.flushes.send(written_tx).is_err() {
+            return 0;
+        }
+        written.await.unwrap_or(0)
+    }
+
+    /// Overlays on `user`, as read from the database, what the connections know better.
+    /// A computer with an authenticated connection is online, whenever its last
+    /// heartbeat was. A folder loaded into the state since a connection of its user was
+    /// upgraded reports the progress tracked there, the operations of websocket clients
+    /// never reaching the database; other folders keep the progress recorded over HTTP.
+    pub async fn overlay_live_status(&self, user: &mut User) {
+        let state = self.state.read().await;
+        let Some(live) = state.get_user(&user.id) else {
+            return;
+        };
+
+        for computer in &mut user.computers {
+            computer.online |= live
+                .computers
+                .iter()
+                .any(|connected| connected.id == computer.id && connected.online);
+        }
+        for folder in &mut user.sync_folders {
+            if let Some(progress) = live.sync_folders.iter().find(|live| live.id == folder.id) {
+                folder.is_synced = progress.is_synced;
+                folder.pending_operations = progress.pending_operations;
+                folder.backup_status = progress.backup_status.clone();
+            }
+        }
+    }
+}
+
+/// Writes the changes websocket clients make to `db`, until the state is dropped. A
+/// flush is answered with the number of changes written once those queued are.
+async fn mirror_changes(
+    db: Pool<Any>,
+    mut changes: mpsc::UnboundedReceiver<DirectoryChange>,
+    mut flushes: mpsc::UnboundedReceiver<oneshot::Sender<u64>>,
+) {
+    let mut written = 0;
+    loop {
+        tokio::select! {
+            change = changes.recv() => {
+                let Some(change) = change else {
+                    return;
+                };
+                write_change(&db, &change).await;
+                written += 1;
+            }
+            Some(flush) = flushes.recv() => {
+                // Every change made before the flush was asked for is queued by now
+                while let Ok(change) = changes.try_recv() {
+                    write_change(&db, &change).await;
+                    written += 1;
+                }
+                let _ = flush.send(written);
+            }
+        }
+    }
+}
+
+async fn write_change(db: &Pool<Any>, change: &DirectoryChange) {
+    if let Err(err) = logic::directory::apply(db, change).await {
+        tracing::error!("Failed to write {:?} to the database: {:?}", change, err);
+    }
+}
@@ -0,0 +1,189 @@
+use axum::{
+    Router,
+    body::Body,
+    http::{Request, StatusCode},
+    response::Response,
+};
+use backup_sync_server::db::test_db;
+use backup_sync_server::handlers::auth_handler::{AuthResponse, LoginRequest, RegisterUserRequest};
+use backup_sync_server::{api_paths, create_app_with_db};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use tower::ServiceExt;
+
+/// The app on a database of its own, see [`test_db`]
+async fn app() -> Router {
+    create_app_with_db(test_db().await.unwrap()).unwrap()
+}
+
+const METHODS: [&str; 5] = ["get", "post", "put", "patch", "delete"];
+
+#[derive(serde::Deserialize)]
+struct OpenApi {
+    servers: Vec<Server>,
+    paths: BTreeMap<String, BTreeMap<String, Value>>,
+    components: Components,
+}
+
+#[derive(serde::Deserialize)]
+struct Server {
+    url: String,
+}
+
+#[derive(serde::Deserialize)]
+struct Components {
+    schemas: BTreeMap<String, Value>,
+}
+
+async fn status_of(app: &Router, method: &str, uri: &str, auth_header: Option<&str>) -> StatusCode {
+    let mut request = Request::builder()
+        .method(method.to_uppercase().as_str())
+        .uri(uri);
+    if let Some(auth_header) = auth_header {
+        request = request.header("Authorization", auth_header);
+    }
+    app.clone()
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+        .status()
+}
+
+async fn post_json(app: &Router, uri: &str, body: &impl serde::Serialize) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_string(body).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+async fn login(app: &Router) -> String {
+    let credentials = RegisterUserRequest {
+        name: "docs".to_string(),
+        password: "password123".to_string(),
+    };
+    let response = post_json(app, "/auth/signup", &credentials).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let credentials = LoginRequest {
+        name: credentials.name,
+        password: credentials.password,
+    };
+    let response = post_json(app, "/auth/login", &credentials).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let auth: AuthResponse = serde_json::from_slice(&body).unwrap();
+    format!("Bearer {}", auth.token)
+}
+
+fn collect_refs<'a>(value: &'a Value, refs: &mut Vec<&'a str>) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                match value {
+                    Value::String(reference) if key == "$ref" => refs.push(reference),
+                    _ => collect_refs(value, refs),
+                }
+            }
+        }
+        Value::Array(values) => values.iter().for_each(|value| collect_refs(value, refs)),
+        _ => {}
+    }
+}
+
+/// The documented paths are those routed, every documented operation is routed, no other
+/// method is routed on a documented path, and every schema referenced is defined
+#[tokio::test]
+async fn test_api_docs_match_router() {
+    // SAFETY: the only test of this binary, nothing else reads the environment meanwhile
+    unsafe {
+        std::env::set_var("API_DOCS", "1");
+        // Probing every method of every route takes more than a minute's login attempts
+        // and signups
+        std::env::set_var("RATE_LIMIT_LOGIN_PER_MINUTE", "1000");
+        std::env::set_var("RATE_LIMIT_SIGNUP_PER_MINUTE", "1000");
+    }
+    let app = app().await;
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/api-docs")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let document: Value = serde_json::from_slice(&body).unwrap();
+    let spec: OpenApi = serde_json::from_value(document.clone()).unwrap();
+    let auth_header = login(&app).await;
+    assert_eq!(spec.servers.len(), 1);
+    let prefix = &spec.servers[0].url;
+
+    let routed: BTreeSet<&str> = api_paths().into_iter().collect();
+    let documented: BTreeSet<&str> = spec.paths.keys().map(String::as_str).collect();
+    let undocumented: Vec<_> = routed.difference(&documented).collect();
+    assert!(
+        undocumented.is_empty(),
+        "routed but not documented: {undocumented:?}"
+    );
+    let unrouted: Vec<_> = documented.difference(&routed).collect();
+    assert!(
+        unrouted.is_empty(),
+        "documented but not routed: {unrouted:?}"
+    );
+
+    for (path, operations) in &spec.paths {
+        let uri = format!("{prefix}{}", path.replace("{id}", "some-id"));
+        for method in METHODS {
+            if operations.contains_key(method) {
+                // Unauthenticated, so handlers never answer a 404 of their own
+                let status = status_of(&app, method, &uri, None).await;
+                assert_ne!(
+                    status,
+                    StatusCode::NOT_FOUND,
+                    "{method} {path} is not routed"
+                );
+                assert_ne!(
+                    status,
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "{method} {path} is not routed"
+                );
+            } else {
+                // Authenticated, so protected paths get past the token check
+                let status = status_of(&app, method, &uri, Some(&auth_header)).await;
+                assert_eq!(
+                    status,
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "{method} {path} is routed but not documented"
+                );
+            }
+        }
+    }
+
+    let mut refs = Vec::new();
+    collect_refs(&document, &mut refs);
+    assert!(!refs.is_empty());
+    for reference in refs {
+        let name = reference
+            .strip_prefix("#/components/schemas/")
+            .unwrap_or_else(|| panic!("unexpected reference {reference}"));
+        assert!(
+            spec.components.schemas.contains_key(name),
+            "{reference} is not defined"
+        );
+    }
+}
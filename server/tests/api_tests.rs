@@ -1,17 +1,37 @@
 use axum::{
+    Router,
     body::Body,
     http::{Request, StatusCode},
+    response::Response,
 };
-use backup_sync_protocol::{Computer, SyncFolder, User};
-use backup_sync_server::create_app;
-use backup_sync_server::handlers::auth_handler::{AuthResponse, LoginRequest, RegisterUserRequest};
-use backup_sync_server::handlers::folder_handler::{CreateFolderRequest, JoinFolderRequest};
-use backup_sync_server::handlers::user_handler::CreateComputerRequest;
+use backup_sync_protocol::{Computer, ComputerId, FolderId, SyncFolder, User};
+use backup_sync_server::auth::{Claims, Role};
+use backup_sync_server::db::test_db;
+use backup_sync_server::error::ErrorBody;
+use backup_sync_server::handlers::admin_handler::{AdminFolder, AdminUser};
+use backup_sync_server::handlers::audit_handler::{AuditAction, AuditEntry};
+use backup_sync_server::handlers::auth_handler::{
+    AuthResponse, LoginRequest, RefreshRequest, RegisterUserRequest,
+};
+use backup_sync_server::handlers::folder_handler::{
+    CreateFolderRequest, FolderDetail, FolderOperations, JoinFolderRequest, MemberRole,
+    SwitchOriginRequest, UpdateFolderRequest,
+};
+use backup_sync_server::handlers::invite_handler::{CreateInviteRequest, Invite, InviteStatus};
+use backup_sync_server::handlers::pagination::Page;
+use backup_sync_server::handlers::user_handler::{CreateComputerRequest, UpdateComputerRequest};
+use backup_sync_server::{DEFAULT_SIGNUPS_PER_MINUTE, create_app_with_db};
+use jsonwebtoken::{DecodingKey, Validation, decode};
 use tower::ServiceExt;
 
+/// The app on a database of its own, see [`test_db`]
+async fn app() -> Router {
+    create_app_with_db(test_db().await.unwrap()).unwrap()
+}
+
 #[tokio::test]
 async fn test_full_flow() {
-    let app = create_app().await.unwrap();
+    let app = app().await;
 
     // 1. Register User
     let response = app
@@ -192,3 +212,1138 @@ async fn test_full_flow() {
     assert_eq!(user_state.sync_folders[0].backup_computers.len(), 1);
     assert_eq!(user_state.sync_folders[0].backup_computers[0], computer2_id);
 }
+
+async fn send(
+    app: &Router,
+    method: &str,
+    uri: &str,
+    auth_header: &str,
+    body: Option<String>,
+) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json")
+        .header("Authorization", auth_header);
+    let body = body.map(Body::from).unwrap_or_else(Body::empty);
+    app.clone()
+        .oneshot(request.body(body).unwrap())
+        .await
+        .unwrap()
+}
+
+async fn body_json<T: serde::de::DeserializeOwned>(response: Response) -> T {
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+/// Registers and logs in a user, returning its `Authorization` header value.
+async fn login_as(app: &Router, name: &str) -> String {
+    let credentials = serde_json::to_string(&RegisterUserRequest {
+        name: name.to_string(),
+        password: "password123".to_string(),
+    })
+    .unwrap();
+    let response = send(app, "POST", "/register", "", Some(credentials.clone())).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = send(app, "POST", "/login", "", Some(credentials)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let auth_response: AuthResponse = body_json(response).await;
+    format!("Bearer {}", auth_response.token)
+}
+
+async fn register_computer(app: &Router, auth_header: &str, name: &str) -> Computer {
+    let body = serde_json::to_string(&CreateComputerRequest {
+        name: name.to_string(),
+    })
+    .unwrap();
+    let response = send(app, "POST", "/computers", auth_header, Some(body)).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    body_json(response).await
+}
+
+async fn create_folder(
+    app: &Router,
+    auth_header: &str,
+    name: &str,
+    computer_id: &ComputerId,
+) -> SyncFolder {
+    let body = serde_json::to_string(&CreateFolderRequest {
+        name: name.to_string(),
+        computer_id: computer_id.clone(),
+    })
+    .unwrap();
+    let response = send(app, "POST", "/folders", auth_header, Some(body)).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    body_json(response).await
+}
+
+async fn join_folder(
+    app: &Router,
+    auth_header: &str,
+    folder_id: &FolderId,
+    computer_id: &ComputerId,
+) {
+    let body = serde_json::to_string(&JoinFolderRequest {
+        computer_id: computer_id.clone(),
+    })
+    .unwrap();
+    let uri = format!("/folders/{folder_id}/join");
+    let response = send(app, "POST", &uri, auth_header, Some(body)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+async fn list_folders(app: &Router, auth_header: &str) -> Vec<SyncFolder> {
+    let response = send(app, "GET", "/folders", auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let page: Page<SyncFolder> = body_json(response).await;
+    page.items
+}
+
+#[tokio::test]
+async fn test_delete_folder() {
+    let app = app().await;
+    let auth_header = login_as(&app, "owner").await;
+    let other_header = login_as(&app, "intruder").await;
+
+    let laptop = register_computer(&app, &auth_header, "MyLaptop").await;
+    let desktop = register_computer(&app, &auth_header, "MyDesktop").await;
+    let empty = create_folder(&app, &auth_header, "Empty", &laptop.id).await;
+    let backed_up = create_folder(&app, &auth_header, "Documents", &laptop.id).await;
+    join_folder(&app, &auth_header, &backed_up.id, &desktop.id).await;
+
+    let response = send(
+        &app,
+        "DELETE",
+        &format!("/folders/{}", empty.id),
+        &auth_header,
+        None,
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = send(
+        &app,
+        "DELETE",
+        &format!("/folders/{}", empty.id),
+        &auth_header,
+        None,
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let uri = format!("/folders/{}?force=true", backed_up.id);
+    let response = send(&app, "DELETE", &uri, &other_header, None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let uri = format!("/folders/{}", backed_up.id);
+    let response = send(&app, "DELETE", &uri, &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(list_folders(&app, &auth_header).await.len(), 1);
+
+    let uri = format!("/folders/{}?force=true", backed_up.id);
+    let response = send(&app, "DELETE", &uri, &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert!(list_folders(&app, &auth_header).await.is_empty());
+
+    let uri = format!("/computers/{}/folders", desktop.id);
+    let response = send(&app, "GET", &uri, &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let desktop_folders: Vec<SyncFolder> = body_json(response).await;
+    assert!(desktop_folders.is_empty());
+}
+
+#[tokio::test]
+async fn test_create_folders_in_bulk() {
+    let app = app().await;
+    let auth_header = login_as(&app, "owner").await;
+    let laptop = register_computer(&app, &auth_header, "MyLaptop").await;
+    let desktop = register_computer(&app, &auth_header, "MyDesktop").await;
+    create_folder(&app, &auth_header, "Documents", &laptop.id).await;
+    let bulk = |names: &[&str], computer_id: &ComputerId| {
+        let folders: Vec<CreateFolderRequest> = names
+            .iter()
+            .map(|name| CreateFolderRequest {
+                name: name.to_string(),
+                computer_id: computer_id.clone(),
+            })
+            .collect();
+        serde_json::to_string(&folders).unwrap()
+    };
+
+    let body = bulk(&["Pictures", "Projects", "Music"], &desktop.id);
+    let response = send(&app, "POST", "/folders/bulk", &auth_header, Some(body)).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created: Vec<SyncFolder> = body_json(response).await;
+    let names: Vec<&str> = created.iter().map(|folder| folder.name.as_str()).collect();
+    assert_eq!(names, ["Pictures", "Projects", "Music"]);
+    assert!(
+        created
+            .iter()
+            .all(|folder| folder.origin_computer == desktop.id)
+    );
+    assert_eq!(list_folders(&app, &auth_header).await.len(), 4);
+
+    // One name taken fails the whole batch
+    let body = bulk(&["Videos", "Documents", "Games", "Videos"], &laptop.id);
+    let response = send(&app, "POST", "/folders/bulk", &auth_header, Some(body)).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorBody = body_json(response).await;
+    assert_eq!(error.code, "validation_failed");
+    let fields: Vec<&str> = error
+        .details
+        .iter()
+        .map(|issue| issue.field.as_str())
+        .collect();
+    assert_eq!(fields, ["[1].name", "[3].name"]);
+    assert_eq!(list_folders(&app, &auth_header).await.len(), 4);
+
+    let other_header = login_as(&app, "intruder").await;
+    let body = bulk(&["Videos"], &laptop.id);
+    let response = send(&app, "POST", "/folders/bulk", &other_header, Some(body)).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_update_folder() {
+    let app = app().await;
+    let auth_header = login_as(&app, "owner").await;
+    let other_header = login_as(&app, "intruder").await;
+
+    let laptop = register_computer(&app, &auth_header, "MyLaptop").await;
+    let docs = create_folder(&app, &auth_header, "Documents", &laptop.id).await;
+    create_folder(&app, &auth_header, "Photos", &laptop.id).await;
+    let uri = format!("/folders/{}", docs.id);
+    let rename = |name: &str| {
+        Some(
+            serde_json::to_string(&UpdateFolderRequest {
+                name: Some(name.to_string()),
+            })
+            .unwrap(),
+        )
+    };
+
+    // An empty patch leaves the folder as it is
+    let response = send(&app, "PATCH", &uri, &auth_header, Some("{}".to_string())).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let folder: SyncFolder = body_json(response).await;
+    assert_eq!(folder.name, "Documents");
+
+    let response = send(&app, "PATCH", &uri, &auth_header, rename("Papers")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let folder: SyncFolder = body_json(response).await;
+    assert_eq!(folder.id, docs.id);
+    assert_eq!(folder.name, "Papers");
+    assert_eq!(folder.origin_computer, laptop.id);
+
+    let response = send(&app, "PATCH", &uri, &auth_header, rename("")).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = send(&app, "PATCH", &uri, &auth_header, rename(&"a".repeat(256))).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = send(&app, "PATCH", &uri, &auth_header, rename("Photos")).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = send(&app, "PATCH", &uri, &other_header, rename("Mine")).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let unknown = format!("/folders/{}", FolderId::new_v4());
+    let response = send(&app, "PATCH", &unknown, &auth_header, rename("Ghost")).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let names: Vec<String> = list_folders(&app, &auth_header)
+        .await
+        .into_iter()
+        .map(|folder| folder.name)
+        .collect();
+    assert!(names.contains(&"Papers".to_string()));
+    assert!(names.contains(&"Photos".to_string()));
+}
+
+#[tokio::test]
+async fn test_switch_origin_denials() {
+    let app = app().await;
+    let auth_header = login_as(&app, "owner").await;
+    let other_header = login_as(&app, "intruder").await;
+
+    let laptop = register_computer(&app, &auth_header, "MyLaptop").await;
+    let desktop = register_computer(&app, &auth_header, "MyDesktop").await;
+    let nas = register_computer(&app, &auth_header, "MyNas").await;
+    let docs = create_folder(&app, &auth_header, "Documents", &laptop.id).await;
+    join_folder(&app, &auth_header, &docs.id, &desktop.id).await;
+    let uri = format!("/folders/{}/switch-origin", docs.id);
+    let switch_to = |computer: &Computer| {
+        Some(
+            serde_json::to_string(&SwitchOriginRequest {
+                new_origin: computer.id.clone(),
+            })
+            .unwrap(),
+        )
+    };
+
+    let response = send(&app, "POST", &uri, &other_header, switch_to(&desktop)).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let unknown = format!("/folders/{}/switch-origin", FolderId::new_v4());
+    let response = send(&app, "POST", &unknown, &auth_header, switch_to(&desktop)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = send(&app, "POST", &uri, &auth_header, switch_to(&nas)).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: serde_json::Value = body_json(response).await;
+    assert_eq!(error["message"], "Only backup computers can become origin");
+
+    // Nothing has reported the folder as synced yet
+    let response = send(&app, "POST", &uri, &auth_header, switch_to(&desktop)).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let error: serde_json::Value = body_json(response).await;
+    assert_eq!(
+        error["message"],
+        "Folder has pending operations and is not fully synced"
+    );
+}
+
+#[tokio::test]
+async fn test_get_folder_detail() {
+    let app = app().await;
+    let auth_header = login_as(&app, "owner").await;
+    let other_header = login_as(&app, "intruder").await;
+
+    let laptop = register_computer(&app, &auth_header, "MyLaptop").await;
+    let desktop = register_computer(&app, &auth_header, "MyDesktop").await;
+    let nas = register_computer(&app, &auth_header, "MyNas").await;
+    let lonely = create_folder(&app, &auth_header, "Lonely", &laptop.id).await;
+    let docs = create_folder(&app, &auth_header, "Documents", &laptop.id).await;
+    join_folder(&app, &auth_header, &docs.id, &desktop.id).await;
+    join_folder(&app, &auth_header, &docs.id, &nas.id).await;
+
+    let uri = format!("/folders/{}", lonely.id);
+    let response = send(&app, "GET", &uri, &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let detail: FolderDetail = body_json(response).await;
+    assert_eq!(detail.folder.id, lonely.id);
+    assert_eq!(detail.folder.name, "Lonely");
+    assert_eq!(detail.members.len(), 1);
+    assert_eq!(detail.members[0].computer_id, laptop.id);
+    assert_eq!(detail.members[0].name, "MyLaptop");
+    assert_eq!(detail.members[0].role, MemberRole::Origin);
+    assert!(detail.members[0].joined_at.is_some());
+    assert!(detail.members[0].online);
+
+    let uri = format!("/folders/{}", docs.id);
+    let response = send(&app, "GET", &uri, &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let detail: serde_json::Value = body_json(response).await;
+    assert_eq!(detail["name"], "Documents");
+    assert_eq!(detail["pending_operations"], 0);
+    let members = detail["members"].as_array().unwrap();
+    assert_eq!(members.len(), 3);
+    assert_eq!(members[0]["role"], "origin");
+    let mut backups: Vec<&str> = members[1..]
+        .iter()
+        .inspect(|member| assert_eq!(member["role"], "backup"))
+        .map(|member| member["name"].as_str().unwrap())
+        .collect();
+    backups.sort_unstable();
+    assert_eq!(backups, ["MyDesktop", "MyNas"]);
+
+    let response = send(&app, "GET", &uri, &other_header, None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let unknown = format!("/folders/{}", FolderId::new_v4());
+    let response = send(&app, "GET", &unknown, &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_signup_and_login() {
+    let app = app().await;
+    let credentials = |password: &str| {
+        Some(serde_json::json!({ "username": "alice", "password": password }).to_string())
+    };
+
+    let response = send(&app, "POST", "/auth/signup", "", credentials("hunter22")).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created: serde_json::Value = body_json(response).await;
+    let user_id = created["id"].as_str().unwrap().to_string();
+
+    let response = send(&app, "POST", "/auth/signup", "", credentials("other")).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let response = send(&app, "POST", "/auth/login", "", credentials("wrong")).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = send(&app, "POST", "/auth/login", "", credentials("hunter22")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let auth_response: AuthResponse = body_json(response).await;
+    assert_eq!(auth_response.user_id.to_string(), user_id);
+
+    let auth_header = format!("Bearer {}", auth_response.token);
+    let response = send(&app, "GET", "/user/state", &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let user_state: User = body_json(response).await;
+    assert_eq!(user_state.name, "alice");
+}
+
+#[tokio::test]
+async fn test_signup_is_rate_limited() {
+    let app = app().await;
+    let credentials =
+        Some(serde_json::json!({ "username": "bob", "password": "password123" }).to_string());
+
+    let response = send(&app, "POST", "/auth/signup", "", credentials.clone()).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    for _ in 1..DEFAULT_SIGNUPS_PER_MINUTE {
+        let response = send(&app, "POST", "/auth/signup", "", credentials.clone()).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    let response = send(&app, "POST", "/auth/signup", "", credentials).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn test_refresh_rotates_tokens() {
+    let app = app().await;
+    let credentials =
+        Some(serde_json::json!({ "username": "carol", "password": "password123" }).to_string());
+    let response = send(&app, "POST", "/auth/signup", "", credentials.clone()).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = send(&app, "POST", "/auth/login", "", credentials).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let login: AuthResponse = body_json(response).await;
+    assert!(login.expires_in > 0);
+
+    let response = send(
+        &app,
+        "POST",
+        "/auth/refresh",
+        "",
+        refresh_body(&login.refresh_token),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let refreshed: AuthResponse = body_json(response).await;
+    assert_eq!(refreshed.user_id, login.user_id);
+    assert_ne!(refreshed.refresh_token, login.refresh_token);
+
+    let auth_header = format!("Bearer {}", refreshed.token);
+    let response = send(&app, "GET", "/user/state", &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // The presented refresh token was rotated out
+    let response = send(
+        &app,
+        "POST",
+        "/auth/refresh",
+        "",
+        refresh_body(&login.refresh_token),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = send(
+        &app,
+        "POST",
+        "/auth/refresh",
+        "",
+        refresh_body(&refreshed.refresh_token),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+async fn login_session(app: &Router, credentials: &str) -> AuthResponse {
+    let response = send(
+        app,
+        "POST",
+        "/auth/login",
+        "",
+        Some(credentials.to_string()),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    body_json(response).await
+}
+
+fn refresh_body(refresh_token: &str) -> Option<String> {
+    Some(
+        serde_json::to_string(&RefreshRequest {
+            refresh_token: refresh_token.to_string(),
+        })
+        .unwrap(),
+    )
+}
+
+#[tokio::test]
+async fn test_logout_revokes_tokens() {
+    let app = app().await;
+    let credentials =
+        serde_json::json!({ "username": "dave", "password": "password123" }).to_string();
+    let response = send(&app, "POST", "/auth/signup", "", Some(credentials.clone())).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let laptop = login_session(&app, &credentials).await;
+    let phone = login_session(&app, &credentials).await;
+    let laptop_header = format!("Bearer {}", laptop.token);
+    let phone_header = format!("Bearer {}", phone.token);
+
+    let response = send(
+        &app,
+        "POST",
+        "/auth/logout",
+        &laptop_header,
+        refresh_body(&laptop.refresh_token),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = send(
+        &app,
+        "POST",
+        "/auth/refresh",
+        "",
+        refresh_body(&laptop.refresh_token),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = send(&app, "GET", "/user/state", &laptop_header, None).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let error: serde_json::Value = body_json(response).await;
+    assert_eq!(error["code"], "token_revoked");
+
+    // The other session is untouched until the user logs out everywhere
+    let response = send(&app, "GET", "/user/state", &phone_header, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let tablet = login_session(&app, &credentials).await;
+
+    let response = send(&app, "POST", "/auth/logout-all", &phone_header, None).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    for session in [&phone, &tablet] {
+        let response = send(
+            &app,
+            "POST",
+            "/auth/refresh",
+            "",
+            refresh_body(&session.refresh_token),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+    let response = send(&app, "GET", "/user/state", &phone_header, None).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_list_endpoints_paginate() {
+    let app = app().await;
+    let auth_header = login_as(&app, "owner").await;
+    let other_header = login_as(&app, "intruder").await;
+    register_computer(&app, &other_header, "Not mine").await;
+
+    let mut laptop = None;
+    for i in 0..50 {
+        let computer = register_computer(&app, &auth_header, &format!("PC {i:02}")).await;
+        laptop.get_or_insert(computer);
+    }
+    let laptop = laptop.unwrap();
+    for i in 0..50 {
+        create_folder(&app, &auth_header, &format!("Folder {i:02}"), &laptop.id).await;
+    }
+
+    let computers = |uri: String| {
+        let app = app.clone();
+        let auth_header = auth_header.clone();
+        async move {
+            let response = send(&app, "GET", &uri, &auth_header, None).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            body_json::<Page<Computer>>(response).await
+        }
+    };
+
+    let mut names = Vec::new();
+    for offset in [0, 20, 40] {
+        let page = computers(format!("/computers?limit=20&offset={offset}")).await;
+        assert_eq!(page.total, 50);
+        assert_eq!((page.limit, page.offset), (20, offset));
+        names.extend(page.items.into_iter().map(|computer| computer.name));
+    }
+    let expected: Vec<String> = (0..50).map(|i| format!("PC {i:02}")).collect();
+    assert_eq!(names, expected);
+
+    let page = computers("/computers".to_string()).await;
+    assert_eq!(page.items.len(), 50);
+    assert_eq!(page.offset, 0);
+
+    let page = computers("/computers?offset=60".to_string()).await;
+    assert!(page.items.is_empty());
+    assert_eq!(page.total, 50);
+
+    let page = computers("/computers?sort=name&order=desc&limit=3".to_string()).await;
+    let names: Vec<String> = page.items.into_iter().map(|c| c.name).collect();
+    assert_eq!(names, ["PC 49", "PC 48", "PC 47"]);
+
+    let page = computers("/computers?name=pc%204".to_string()).await;
+    assert_eq!(page.total, 10);
+    assert_eq!(page.items.len(), 10);
+
+    let response = send(
+        &app,
+        "GET",
+        "/folders?limit=15&offset=45",
+        &auth_header,
+        None,
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let page: Page<SyncFolder> = body_json(response).await;
+    assert_eq!(page.total, 50);
+    assert_eq!(page.items.len(), 5);
+    assert_eq!(page.items[0].name, "Folder 45");
+
+    let uri = "/folders?name=older%201&sort=name&order=desc";
+    let response = send(&app, "GET", uri, &auth_header, None).await;
+    let page: Page<SyncFolder> = body_json(response).await;
+    assert_eq!(page.total, 10);
+    assert_eq!(page.items[0].name, "Folder 19");
+
+    for uri in [
+        "/folders?limit=0",
+        "/folders?limit=100000",
+        "/folders?offset=99999999",
+        "/folders?sort=password",
+        "/computers?limit=0",
+        "/computers?sort=user_id",
+    ] {
+        let response = send(&app, "GET", uri, &auth_header, None).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn test_rename_computer() {
+    let app = app().await;
+    let auth_header = login_as(&app, "owner").await;
+    let other_header = login_as(&app, "intruder").await;
+
+    let laptop = register_computer(&app, &auth_header, "Jonh's Laptop").await;
+    register_computer(&app, &auth_header, "Desktop").await;
+    let uri = format!("/computers/{}", laptop.id);
+    let rename = |name: &str| {
+        Some(
+            serde_json::to_string(&UpdateComputerRequest {
+                name: name.to_string(),
+            })
+            .unwrap(),
+        )
+    };
+
+    let response = send(&app, "PATCH", &uri, &auth_header, rename("John's Laptop")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let computer: Computer = body_json(response).await;
+    assert_eq!(computer.id, laptop.id);
+    assert_eq!(computer.name, "John's Laptop");
+
+    let response = send(&app, "PATCH", &uri, &other_header, rename("Mine now")).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = send(&app, "PATCH", &uri, &auth_header, rename(" ")).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = send(&app, "PATCH", &uri, &auth_header, rename("Desktop")).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let unknown = format!("/computers/{}", ComputerId::new_v4());
+    let response = send(&app, "PATCH", &unknown, &auth_header, rename("Ghost")).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = send(&app, "GET", "/computers?name=john", &auth_header, None).await;
+    let page: Page<Computer> = body_json(response).await;
+    assert_eq!(page.items.len(), 1);
+    assert_eq!(page.items[0].name, "John's Laptop");
+}
+
+#[tokio::test]
+async fn test_heartbeat_keeps_computer_online() {
+    let app = app().await;
+    let auth_header = login_as(&app, "owner").await;
+    let other_header = login_as(&app, "intruder").await;
+    let laptop = register_computer(&app, &auth_header, "MyLaptop").await;
+    assert!(laptop.last_seen.is_some());
+
+    let uri = format!("/computers/{}/heartbeat", laptop.id);
+    let response = send(&app, "POST", &uri, &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let computer: Computer = body_json(response).await;
+    assert!(computer.online);
+    assert!(computer.last_seen >= laptop.last_seen);
+
+    let response = send(&app, "GET", "/computers", &auth_header, None).await;
+    let page: Page<Computer> = body_json(response).await;
+    assert!(page.items[0].online);
+    assert_eq!(page.items[0].last_seen, computer.last_seen);
+
+    let response = send(&app, "POST", &uri, &other_header, None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let unknown = format!("/computers/{}/heartbeat", ComputerId::new_v4());
+    let response = send(&app, "POST", &unknown, &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_get_folder_operations() {
+    let app = app().await;
+    let auth_header = login_as(&app, "owner").await;
+    let other_header = login_as(&app, "intruder").await;
+
+    let laptop = register_computer(&app, &auth_header, "MyLaptop").await;
+    let desktop = register_computer(&app, &auth_header, "MyDesktop").await;
+    let docs = create_folder(&app, &auth_header, "Documents", &laptop.id).await;
+    join_folder(&app, &auth_header, &docs.id, &desktop.id).await;
+
+    let uri = format!("/folders/{}/operations", docs.id);
+    let response = send(&app, "GET", &uri, &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let operations: FolderOperations = body_json(response).await;
+    assert_eq!(operations.pending_operations, 0);
+    assert_eq!(operations.oldest_pending_at, None);
+    assert!(operations.recent.is_empty());
+    assert_eq!(operations.backups.len(), 1);
+    assert_eq!(operations.backups[0].computer_id, desktop.id);
+    assert_eq!(operations.backups[0].last_acked_operation, None);
+
+    let response = send(&app, "GET", &format!("{uri}?limit=0"), &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = send(&app, "GET", &uri, &other_header, None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let unknown = format!("/folders/{}/operations", FolderId::new_v4());
+    let response = send(&app, "GET", &unknown, &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_join_and_leave_rules() {
+    let app = app().await;
+    let auth_header = login_as(&app, "owner").await;
+    let other_header = login_as(&app, "intruder").await;
+
+    let laptop = register_computer(&app, &auth_header, "MyLaptop").await;
+    let desktop = register_computer(&app, &auth_header, "MyDesktop").await;
+    let stranger = register_computer(&app, &other_header, "TheirLaptop").await;
+    let docs = create_folder(&app, &auth_header, "Documents", &laptop.id).await;
+    let membership = |computer: &Computer| {
+        Some(
+            serde_json::to_string(&JoinFolderRequest {
+                computer_id: computer.id.clone(),
+            })
+            .unwrap(),
+        )
+    };
+    let join = format!("/folders/{}/join", docs.id);
+    let leave = format!("/folders/{}/leave", docs.id);
+
+    let response = send(&app, "POST", &join, &other_header, membership(&stranger)).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = send(&app, "POST", &join, &auth_header, membership(&laptop)).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let error: serde_json::Value = body_json(response).await;
+    assert_eq!(error["code"], "origin_cannot_join");
+
+    join_folder(&app, &auth_header, &docs.id, &desktop.id).await;
+    join_folder(&app, &auth_header, &docs.id, &desktop.id).await;
+    assert_eq!(
+        list_folders(&app, &auth_header).await[0]
+            .backup_computers
+            .len(),
+        1
+    );
+
+    let response = send(&app, "POST", &leave, &auth_header, membership(&laptop)).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let error: serde_json::Value = body_json(response).await;
+    assert_eq!(error["code"], "origin_cannot_leave");
+
+    let response = send(&app, "POST", &leave, &other_header, membership(&stranger)).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = send(&app, "POST", &leave, &auth_header, membership(&desktop)).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert!(
+        list_folders(&app, &auth_header).await[0]
+            .backup_computers
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn test_restore_removed_computer_and_folder() {
+    let app = app().await;
+    let auth_header = login_as(&app, "owner").await;
+    let other_header = login_as(&app, "intruder").await;
+    let laptop = register_computer(&app, &auth_header, "MyLaptop").await;
+    let desktop = register_computer(&app, &auth_header, "MyDesktop").await;
+    let folder = create_folder(&app, &auth_header, "Documents", &laptop.id).await;
+    join_folder(&app, &auth_header, &folder.id, &desktop.id).await;
+    let list_computers = || async {
+        let response = send(&app, "GET", "/computers", &auth_header, None).await;
+        let page: Page<Computer> = body_json(response).await;
+        page.items
+    };
+
+    let uri = format!("/computers/{}", desktop.id);
+    let response = send(&app, "DELETE", &uri, &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(list_computers().await.len(), 1);
+    assert!(
+        list_folders(&app, &auth_header).await[0]
+            .backup_computers
+            .is_empty()
+    );
+
+    let restore = format!("/computers/{}/restore", desktop.id);
+    let response = send(&app, "POST", &restore, &other_header, None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = send(&app, "POST", &restore, &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let computer: Computer = body_json(response).await;
+    assert_eq!(computer.id, desktop.id);
+    assert_eq!(list_computers().await.len(), 2);
+    assert_eq!(
+        list_folders(&app, &auth_header).await[0].backup_computers,
+        vec![desktop.id.clone()]
+    );
+    let response = send(&app, "POST", &restore, &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    // The origin takes its folders along
+    let uri = format!("/computers/{}", laptop.id);
+    let response = send(&app, "DELETE", &uri, &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert!(list_folders(&app, &auth_header).await.is_empty());
+    let restore_folder = format!("/folders/{}/restore", folder.id);
+    let response = send(&app, "POST", &restore_folder, &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let restore = format!("/computers/{}/restore", laptop.id);
+    let response = send(&app, "POST", &restore, &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(list_folders(&app, &auth_header).await.len(), 1);
+
+    let uri = format!("/folders/{}?force=true", folder.id);
+    let response = send(&app, "DELETE", &uri, &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert!(list_folders(&app, &auth_header).await.is_empty());
+    let response = send(&app, "POST", &restore_folder, &other_header, None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = send(&app, "POST", &restore_folder, &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let restored: SyncFolder = body_json(response).await;
+    assert_eq!(restored.backup_computers, vec![desktop.id]);
+    assert_eq!(list_folders(&app, &auth_header).await.len(), 1);
+}
+
+#[tokio::test]
+async fn test_audit_log_records_actions() {
+    let app = app().await;
+    let auth_header = login_as(&app, "owner").await;
+    let other_header = login_as(&app, "intruder").await;
+    let laptop = register_computer(&app, &auth_header, "MyLaptop").await;
+    let folder = create_folder(&app, &auth_header, "Documents", &laptop.id).await;
+
+    let credentials = serde_json::to_string(&LoginRequest {
+        name: "owner".to_string(),
+        password: "wrong password".to_string(),
+    })
+    .unwrap();
+    let response = send(&app, "POST", "/login", "", Some(credentials)).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let failed_request_id = response.headers()["x-request-id"]
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    let response = send(&app, "GET", "/user/audit", &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let entries: Vec<AuditEntry> = body_json(response).await;
+    let actions: Vec<_> = entries.iter().map(|entry| entry.action).collect();
+    assert_eq!(
+        actions,
+        [
+            AuditAction::LoginFailed,
+            AuditAction::FolderCreated,
+            AuditAction::ComputerRegistered,
+        ]
+    );
+    assert_eq!(entries[0].request_id, Some(failed_request_id));
+    assert_eq!(entries[1].computer_id, Some(laptop.id.clone()));
+    assert_eq!(entries[1].target, Some(folder.id.to_string()));
+    assert!(entries[1].request_id.is_some());
+
+    let uri = format!("/user/audit?limit=1&before={}", entries[1].id);
+    let response = send(&app, "GET", &uri, &auth_header, None).await;
+    let older: Vec<AuditEntry> = body_json(response).await;
+    assert_eq!(older.len(), 1);
+    assert_eq!(older[0].action, AuditAction::ComputerRegistered);
+
+    let response = send(&app, "GET", "/user/audit", &other_header, None).await;
+    let entries: Vec<AuditEntry> = body_json(response).await;
+    assert!(entries.is_empty());
+    let response = send(&app, "GET", "/user/audit?limit=0", &auth_header, None).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_folder_invites() {
+    let app = app().await;
+    let owner_header = login_as(&app, "owner").await;
+    let partner_header = login_as(&app, "partner").await;
+    let stranger_header = login_as(&app, "stranger").await;
+    let laptop = register_computer(&app, &owner_header, "MyLaptop").await;
+    let partner_laptop = register_computer(&app, &partner_header, "PartnerLaptop").await;
+    let stranger_laptop = register_computer(&app, &stranger_header, "StrangerLaptop").await;
+    let docs = create_folder(&app, &owner_header, "Documents", &laptop.id).await;
+    let photos = create_folder(&app, &owner_header, "Photos", &laptop.id).await;
+    let invite_to = |name: &str| {
+        Some(
+            serde_json::to_string(&CreateInviteRequest {
+                username: name.to_string(),
+            })
+            .unwrap(),
+        )
+    };
+    let membership = |computer: &Computer| {
+        Some(
+            serde_json::to_string(&JoinFolderRequest {
+                computer_id: computer.id.clone(),
+            })
+            .unwrap(),
+        )
+    };
+    let docs_invites = format!("/folders/{}/invites", docs.id);
+    let docs_join = format!("/folders/{}/join", docs.id);
+
+    // Joining takes an invite, which only the owner can send
+    let response = send(
+        &app,
+        "POST",
+        &docs_join,
+        &partner_header,
+        membership(&partner_laptop),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = send(
+        &app,
+        "POST",
+        &docs_invites,
+        &partner_header,
+        invite_to("partner"),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = send(
+        &app,
+        "POST",
+        &docs_invites,
+        &owner_header,
+        invite_to("nobody"),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = send(
+        &app,
+        "POST",
+        &docs_invites,
+        &owner_header,
+        invite_to("partner"),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let invite: Invite = body_json(response).await;
+    assert_eq!(invite.status, InviteStatus::Pending);
+    assert_eq!(invite.inviter, "owner");
+    let response = send(
+        &app,
+        "POST",
+        &docs_invites,
+        &owner_header,
+        invite_to("partner"),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let uri = format!("/folders/{}/invites", photos.id);
+    let response = send(&app, "POST", &uri, &owner_header, invite_to("stranger")).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let declined: Invite = body_json(response).await;
+
+    let response = send(&app, "GET", "/invites", &partner_header, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let invites: Vec<Invite> = body_json(response).await;
+    assert_eq!(invites.len(), 1);
+    assert_eq!(invites[0].id, invite.id);
+    assert_eq!(invites[0].folder_name, "Documents");
+
+    // A pending invite doesn't grant anything yet
+    let response = send(
+        &app,
+        "POST",
+        &docs_join,
+        &partner_header,
+        membership(&partner_laptop),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let accept = format!("/invites/{}/accept", invite.id);
+    let response = send(&app, "POST", &accept, &stranger_header, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = send(&app, "POST", &accept, &partner_header, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let accepted: Invite = body_json(response).await;
+    assert_eq!(accepted.status, InviteStatus::Accepted);
+    let response = send(&app, "POST", &accept, &partner_header, None).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = send(&app, "GET", "/invites", &partner_header, None).await;
+    let invites: Vec<Invite> = body_json(response).await;
+    assert!(invites.is_empty());
+
+    join_folder(&app, &partner_header, &docs.id, &partner_laptop.id).await;
+    let folders = list_folders(&app, &owner_header).await;
+    let shared = folders.iter().find(|folder| folder.id == docs.id).unwrap();
+    assert_eq!(shared.backup_computers.len(), 1);
+    assert_eq!(shared.backup_computers[0], partner_laptop.id);
+    // The invite is for one folder and one user
+    let uri = format!("/folders/{}/join", photos.id);
+    let response = send(
+        &app,
+        "POST",
+        &uri,
+        &partner_header,
+        membership(&partner_laptop),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = send(
+        &app,
+        "POST",
+        &docs_join,
+        &stranger_header,
+        membership(&stranger_laptop),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let decline = format!("/invites/{}/decline", declined.id);
+    let response = send(&app, "POST", &decline, &stranger_header, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let declined: Invite = body_json(response).await;
+    assert_eq!(declined.status, InviteStatus::Declined);
+    let accept = format!("/invites/{}/accept", declined.id);
+    let response = send(&app, "POST", &accept, &stranger_header, None).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let uri = format!("/folders/{}/join", photos.id);
+    let response = send(
+        &app,
+        "POST",
+        &uri,
+        &stranger_header,
+        membership(&stranger_laptop),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = send(&app, "GET", "/user/audit", &owner_header, None).await;
+    let entries: Vec<AuditEntry> = body_json(response).await;
+    assert_eq!(entries[0].action, AuditAction::InviteSent);
+    assert_eq!(
+        entries[0].target.as_deref(),
+        Some(photos.id.to_string().as_str())
+    );
+}
+
+/// The role claim of an `Authorization` header value, checked with the secret
+/// `create_app` falls back to without `JWT_SECRET`
+fn role_of(auth_header: &str) -> Role {
+    let token = auth_header.trim_start_matches("Bearer ");
+    decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(b"secret"),
+        &Validation::default(),
+    )
+    .unwrap()
+    .claims
+    .role
+}
+
+#[tokio::test]
+async fn test_admin_routes_need_admin_role() {
+    let db = test_db().await.unwrap();
+    let app = create_app_with_db(db.clone()).unwrap();
+    let user_header = login_as(&app, "user").await;
+    let admin_header = login_as(&app, "admin").await;
+    assert_eq!(role_of(&admin_header), Role::User);
+    let laptop = register_computer(&app, &user_header, "MyLaptop").await;
+    create_folder(&app, &user_header, "Documents", &laptop.id).await;
+
+    sqlx::query("UPDATE users SET role = 'admin' WHERE name = 'admin'")
+        .execute(&db)
+        .await
+        .unwrap();
+    // Tokens only carry the role they were issued with
+    let response = send(&app, "GET", "/admin/users", &admin_header, None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let credentials = serde_json::to_string(&LoginRequest {
+        name: "admin".to_string(),
+        password: "password123".to_string(),
+    })
+    .unwrap();
+    let admin = login_session(&app, &credentials).await;
+    let admin_header = format!("Bearer {}", admin.token);
+    assert_eq!(role_of(&admin_header), Role::Admin);
+
+    let response = send(&app, "GET", "/admin/users", &user_header, None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = send(&app, "GET", "/admin/folders", &user_header, None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = send(&app, "GET", "/admin/users", &admin_header, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let users: Page<AdminUser> = body_json(response).await;
+    let roles: Vec<_> = users
+        .items
+        .iter()
+        .map(|user| (user.name.as_str(), user.role))
+        .collect();
+    assert_eq!(roles, [("admin", Role::Admin), ("user", Role::User)]);
+    let user_id = users.items[1].id.clone();
+
+    let response = send(&app, "GET", "/admin/folders", &admin_header, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let folders: Page<AdminFolder> = body_json(response).await;
+    assert_eq!(folders.total, 1);
+    assert_eq!(folders.items[0].folder.name, "Documents");
+    assert_eq!(folders.items[0].owner, user_id);
+
+    // Admins still only see their own computers on the regular routes
+    let response = send(&app, "GET", "/computers", &admin_header, None).await;
+    let computers: Page<Computer> = body_json(response).await;
+    assert_eq!(computers.total, 0);
+
+    let uri = format!("/admin/users/{user_id}");
+    let promote = |role: &str| Some(serde_json::json!({ "role": role }).to_string());
+    let response = send(&app, "PATCH", &uri, &user_header, promote("admin")).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = send(&app, "PATCH", &uri, &admin_header, promote("admin")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let promoted: AdminUser = body_json(response).await;
+    assert_eq!(promoted.role, Role::Admin);
+    let own_uri = format!("/admin/users/{}", admin.user_id);
+    let response = send(&app, "PATCH", &own_uri, &admin_header, promote("user")).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let response = send(&app, "DELETE", &uri, &user_header, None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = send(&app, "DELETE", &uri, &admin_header, None).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = send(&app, "DELETE", &uri, &admin_header, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = send(&app, "GET", "/admin/folders", &admin_header, None).await;
+    let folders: Page<AdminFolder> = body_json(response).await;
+    assert_eq!(folders.total, 0);
+}
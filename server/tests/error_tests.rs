@@ -0,0 +1,135 @@
+use axum::{
+    Router,
+    body::Body,
+    http::{Request, StatusCode},
+    response::Response,
+};
+use backup_sync_protocol::Computer;
+use backup_sync_server::create_app_with_db;
+use backup_sync_server::db::test_db;
+use backup_sync_server::error::{ErrorBody, FieldIssue};
+use backup_sync_server::handlers::auth_handler::AuthResponse;
+use backup_sync_server::logic::folder::MAX_FOLDER_NAME_LEN;
+use tower::ServiceExt;
+
+/// The app on a database of its own, see [`test_db`]
+async fn app() -> Router {
+    create_app_with_db(test_db().await.unwrap()).unwrap()
+}
+
+async fn send(app: &Router, method: &str, uri: &str, auth_header: &str, body: &str) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json")
+        .header("Authorization", auth_header)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn body_json<T: serde::de::DeserializeOwned>(response: Response) -> T {
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+/// The error body of `response`, checking it carries the request id of the response
+async fn error_body(response: Response) -> ErrorBody {
+    let request_id = response.headers()["x-request-id"]
+        .to_str()
+        .unwrap()
+        .to_owned();
+    let body: ErrorBody = body_json(response).await;
+    assert_eq!(body.request_id.as_deref(), Some(request_id.as_str()));
+    body
+}
+
+async fn login(app: &Router) -> String {
+    let credentials = serde_json::json!({ "name": "alice", "password": "password123" });
+    let response = send(app, "POST", "/register", "", &credentials.to_string()).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = send(app, "POST", "/login", "", &credentials.to_string()).await;
+    let auth: AuthResponse = body_json(response).await;
+    format!("Bearer {}", auth.token)
+}
+
+#[tokio::test]
+async fn test_malformed_json_body() {
+    let app = app().await;
+
+    let response = send(&app, "POST", "/login", "", "{\"name\": ").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = error_body(response).await;
+    assert_eq!(body.code, "invalid_json");
+    assert!(body.details.is_empty());
+
+    let response = send(&app, "POST", "/login", "", r#"{"name": 42}"#).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = error_body(response).await;
+    assert_eq!(body.code, "validation_failed");
+    assert_eq!(body.details.len(), 1);
+    assert_eq!(body.details[0].field, "name");
+}
+
+#[tokio::test]
+async fn test_too_long_folder_name() {
+    let app = app().await;
+    let auth_header = login(&app).await;
+    let body = serde_json::json!({ "name": "laptop" }).to_string();
+    let response = send(&app, "POST", "/computers", &auth_header, &body).await;
+    let laptop: Computer = body_json(response).await;
+
+    let body = serde_json::json!({
+        "name": "a".repeat(MAX_FOLDER_NAME_LEN + 1),
+        "computer_id": laptop.id,
+    });
+    let response = send(&app, "POST", "/folders", &auth_header, &body.to_string()).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = error_body(response).await;
+    assert_eq!(body.code, "validation_failed");
+    assert_eq!(
+        body.details,
+        [FieldIssue {
+            field: "name".to_owned(),
+            issue: format!("Folder name must be at most {MAX_FOLDER_NAME_LEN} characters"),
+        }]
+    );
+}
+
+#[tokio::test]
+async fn test_bad_path_and_query_params() {
+    let app = app().await;
+    let auth_header = login(&app).await;
+
+    // Ids take any string, as long as it is UTF-8
+    let response = send(&app, "GET", "/folders/%FF", &auth_header, "").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = error_body(response).await;
+    assert_eq!(body.code, "validation_failed");
+    assert_eq!(body.details.len(), 1);
+    assert_eq!(body.details[0].field, "id");
+
+    let response = send(&app, "GET", "/folders?limit=many", &auth_header, "").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = error_body(response).await;
+    assert_eq!(body.code, "validation_failed");
+    assert_eq!(body.details[0].field, "limit");
+
+    let response = send(&app, "GET", "/folders?limit=0", &auth_header, "").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = error_body(response).await;
+    assert_eq!(body.details[0].field, "limit");
+}
+
+#[tokio::test]
+async fn test_errors_outside_handlers_share_the_body() {
+    let app = app().await;
+
+    let response = send(&app, "GET", "/folders", "", "").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let body = error_body(response).await;
+    assert_eq!(body.code, "authentication_failed");
+    assert!(!body.message.is_empty());
+}
@@ -0,0 +1,124 @@
+use axum::{
+    Router,
+    body::Body,
+    http::{Request, StatusCode},
+    response::Response,
+};
+use backup_sync_protocol::{Computer, SyncFolder};
+use backup_sync_server::create_app_with_db;
+use backup_sync_server::db::test_db;
+use backup_sync_server::error::ErrorBody;
+use backup_sync_server::handlers::auth_handler::AuthResponse;
+use backup_sync_server::handlers::pagination::Page;
+use tower::ServiceExt;
+
+/// Sends a POST request, with `idempotency_key` when given
+async fn post(
+    app: &Router,
+    uri: &str,
+    auth_header: &str,
+    idempotency_key: Option<&str>,
+    body: &serde_json::Value,
+) -> Response {
+    let mut request = Request::builder()
+        .method("POST")
+        .uri(uri)
+        .header("content-type", "application/json")
+        .header("Authorization", auth_header);
+    if let Some(key) = idempotency_key {
+        request = request.header("Idempotency-Key", key);
+    }
+    let request = request.body(Body::from(body.to_string())).unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn body_json<T: serde::de::DeserializeOwned>(response: Response) -> T {
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+async fn login(app: &Router, name: &str) -> String {
+    let credentials = serde_json::json!({ "name": name, "password": "password123" });
+    let response = post(app, "/register", "", None, &credentials).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = post(app, "/login", "", None, &credentials).await;
+    let auth: AuthResponse = body_json(response).await;
+    format!("Bearer {}", auth.token)
+}
+
+async fn list_folders(app: &Router, auth_header: &str) -> Vec<SyncFolder> {
+    let request = Request::builder()
+        .uri("/folders")
+        .header("Authorization", auth_header)
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let page: Page<SyncFolder> = body_json(response).await;
+    page.items
+}
+
+#[tokio::test]
+async fn test_retried_creations_are_replayed() {
+    let app = create_app_with_db(test_db().await.unwrap()).unwrap();
+    let auth_header = login(&app, "alice").await;
+
+    let laptop = serde_json::json!({ "name": "laptop" });
+    let response = post(&app, "/computers", &auth_header, Some("pc-1"), &laptop).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created: Computer = body_json(response).await;
+    let response = post(&app, "/computers", &auth_header, Some("pc-1"), &laptop).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(response.headers()["idempotent-replayed"], "true");
+    let replayed: Computer = body_json(response).await;
+    assert_eq!(replayed.id, created.id);
+
+    let docs = serde_json::json!({ "name": "docs", "computer_id": created.id });
+    let response = post(&app, "/folders", &auth_header, Some("docs-1"), &docs).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let folder: SyncFolder = body_json(response).await;
+    let response = post(&app, "/folders", &auth_header, Some("docs-1"), &docs).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let replayed: SyncFolder = body_json(response).await;
+    assert_eq!(replayed.id, folder.id);
+    assert_eq!(list_folders(&app, &auth_header).await.len(), 1);
+
+    // Without a key, every request runs
+    let response = post(&app, "/folders", &auth_header, None, &docs).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(list_folders(&app, &auth_header).await.len(), 2);
+}
+
+#[tokio::test]
+async fn test_reused_keys_are_rejected() {
+    let app = create_app_with_db(test_db().await.unwrap()).unwrap();
+    let auth_header = login(&app, "alice").await;
+    let laptop = serde_json::json!({ "name": "laptop" });
+    let response = post(&app, "/computers", &auth_header, None, &laptop).await;
+    let laptop: Computer = body_json(response).await;
+
+    let docs = serde_json::json!({ "name": "docs", "computer_id": laptop.id });
+    let response = post(&app, "/folders", &auth_header, Some("key"), &docs).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let photos = serde_json::json!({ "name": "photos", "computer_id": laptop.id });
+    let response = post(&app, "/folders", &auth_header, Some("key"), &photos).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let body: ErrorBody = body_json(response).await;
+    assert_eq!(body.code, "conflict");
+    assert_eq!(list_folders(&app, &auth_header).await.len(), 1);
+
+    // Failed requests don't keep their key, nor do keys of other users clash
+    let unnamed = serde_json::json!({ "name": "", "computer_id": laptop.id });
+    let response = post(&app, "/folders", &auth_header, Some("retry"), &unnamed).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = post(&app, "/folders", &auth_header, Some("retry"), &photos).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let bob_header = login(&app, "bob").await;
+    let desktop = serde_json::json!({ "name": "desktop" });
+    let response = post(&app, "/computers", &bob_header, Some("key"), &desktop).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = post(&app, "/folders", &auth_header, Some(""), &docs).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,94 @@
+use axum::{
+    Router,
+    body::Body,
+    http::{Request, StatusCode},
+    response::Response,
+};
+use backup_sync_server::create_app_with_db;
+use backup_sync_server::db::test_db;
+use backup_sync_server::handlers::auth_handler::AuthResponse;
+use std::time::Duration;
+use tower::ServiceExt;
+
+async fn send(app: &Router, method: &str, uri: &str, auth_header: &str, body: &str) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json")
+        .header("Authorization", auth_header)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn body_text(response: Response) -> String {
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+/// Requests are counted by route template and status, and the totals of the database
+/// show up once refreshed
+#[tokio::test]
+async fn test_metrics_are_scraped() {
+    // SAFETY: the only test of this binary, nothing else reads the environment meanwhile
+    unsafe {
+        std::env::set_var("METRICS_TOKEN", "scraper");
+    }
+    // Alice signs up before the app counts users for the first time, as it does right away
+    let db = test_db().await.unwrap();
+    let credentials = serde_json::json!({ "name": "alice", "password": "password123" });
+    let setup = create_app_with_db(db.clone()).unwrap();
+    let response = send(&setup, "POST", "/register", "", &credentials.to_string()).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let app = create_app_with_db(db).unwrap();
+
+    let response = send(&app, "POST", "/login", "", &credentials.to_string()).await;
+    let auth: AuthResponse = serde_json::from_str(&body_text(response).await).unwrap();
+    let auth_header = format!("Bearer {}", auth.token);
+    let response = send(&app, "GET", "/folders/some-id", &auth_header, "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = send(&app, "GET", "/folders", "", "").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = send(&app, "GET", "/metrics", "", "").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = send(&app, "GET", "/metrics", &auth_header, "").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // The totals are refreshed in the background, and may not be counted yet
+    let mut text = String::new();
+    for _ in 0..50 {
+        let response = send(&app, "GET", "/metrics", "Bearer scraper", "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        text = body_text(response).await;
+        if text.contains("backup_sync_users 1\n") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    for family in [
+        "# TYPE backup_sync_users gauge",
+        "# TYPE backup_sync_folders gauge",
+        "# TYPE backup_sync_http_requests_total counter",
+        "# TYPE backup_sync_http_request_duration_seconds histogram",
+    ] {
+        assert!(text.contains(family), "{family} missing from\n{text}");
+    }
+    assert!(text.contains("backup_sync_users 1\n"));
+    assert!(text.contains("backup_sync_folders 0\n"));
+    assert!(text.contains(
+        "backup_sync_http_requests_total{method=\"POST\",route=\"/login\",status=\"200\"} 1\n"
+    ));
+    assert!(text.contains(
+        "backup_sync_http_requests_total{method=\"GET\",route=\"/folders/{id}\",status=\"404\"} 1\n"
+    ));
+    assert!(text.contains(
+        "backup_sync_http_requests_total{method=\"GET\",route=\"/folders\",status=\"401\"} 1\n"
+    ));
+    assert!(text.contains(
+        "backup_sync_http_request_duration_seconds_count{method=\"POST\",route=\"/login\"} 1\n"
+    ));
+}
@@ -1,13 +1,23 @@
 use axum::{
+    Router,
     body::Body,
-    http::{header, Request},
+    http::{Request, StatusCode, header},
 };
-use backup_sync_server::create_app;
+use backup_sync_server::auth::{Claims, Role};
+use backup_sync_server::create_app_with_db;
+use backup_sync_server::db::test_db;
+use jsonwebtoken::{EncodingKey, Header, encode};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tower::ServiceExt;
 
+/// The app on a database of its own, see [`test_db`]
+async fn app() -> Router {
+    create_app_with_db(test_db().await.unwrap()).unwrap()
+}
+
 #[tokio::test]
 async fn test_request_id_middleware() {
-    let app = create_app().await.unwrap();
+    let app = app().await;
 
     let response = app
         .oneshot(
@@ -24,7 +34,7 @@ async fn test_request_id_middleware() {
 
 #[tokio::test]
 async fn test_propagate_request_id_middleware() {
-    let app = create_app().await.unwrap();
+    let app = app().await;
 
     let response = app
         .oneshot(
@@ -48,7 +58,7 @@ async fn test_propagate_request_id_middleware() {
 
 #[tokio::test]
 async fn test_cors_middleware() {
-    let app = create_app().await.unwrap();
+    let app = app().await;
 
     let response = app
         .oneshot(
@@ -72,3 +82,60 @@ async fn test_cors_middleware() {
         "*"
     );
 }
+
+fn token_expiring_at(exp: u64) -> String {
+    let claims = Claims {
+        sub: "user".to_string(),
+        exp: exp as usize,
+        jti: None,
+        role: Role::User,
+    };
+    // The secret create_app falls back to without JWT_SECRET
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(b"secret"),
+    )
+    .unwrap()
+}
+
+async fn auth_error(token: &str) -> (StatusCode, serde_json::Value) {
+    let app = app().await;
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/computers")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+#[tokio::test]
+async fn test_auth_middleware_rejects_expired_token() {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    // Past the default leeway for clock skew
+    let (status, body) = auth_error(&token_expiring_at(now - 3600)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["code"], "token_expired");
+}
+
+#[tokio::test]
+async fn test_auth_middleware_rejects_invalid_token() {
+    let (status, body) = auth_error("not-a-token").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["code"], "invalid_token");
+}
@@ -0,0 +1,102 @@
+use axum::{
+    Router,
+    body::Body,
+    http::{Request, StatusCode, header},
+    response::Response,
+};
+use backup_sync_server::create_app_with_db;
+use backup_sync_server::db::test_db;
+use backup_sync_server::handlers::auth_handler::AuthResponse;
+use tower::ServiceExt;
+
+async fn send(app: &Router, method: &str, uri: &str, auth_header: &str, body: &str) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json")
+        .header("Authorization", auth_header)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+fn credentials(name: &str, password: &str) -> String {
+    serde_json::json!({ "name": name, "password": password }).to_string()
+}
+
+/// Signs up and logs in `name`, returning its `Authorization` header value
+async fn sign_up(app: &Router, name: &str) -> String {
+    let credentials = credentials(name, "password123");
+    let response = send(app, "POST", "/auth/signup", "", &credentials).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = send(app, "POST", "/auth/login", "", &credentials).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let auth: AuthResponse = serde_json::from_slice(&body).unwrap();
+    format!("Bearer {}", auth.token)
+}
+
+fn retry_after(response: &Response) -> u64 {
+    response.headers()[header::RETRY_AFTER]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+/// Users get a bucket each on authenticated routes, while login attempts and signups have
+/// stricter ones apart from the other auth routes
+#[tokio::test]
+async fn test_rate_limits() {
+    // SAFETY: the only test of this binary, nothing else reads the environment meanwhile
+    unsafe {
+        std::env::set_var("RATE_LIMIT_API_PER_MINUTE", "5");
+        std::env::set_var("RATE_LIMIT_AUTH_PER_MINUTE", "5");
+        std::env::set_var("RATE_LIMIT_LOGIN_PER_MINUTE", "2");
+        std::env::set_var("RATE_LIMIT_SIGNUP_PER_MINUTE", "3");
+    }
+    let app = create_app_with_db(test_db().await.unwrap()).unwrap();
+
+    let alice = sign_up(&app, "alice").await;
+    for _ in 0..5 {
+        let response = send(&app, "GET", "/user/state", &alice, "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = send(&app, "GET", "/user/state", &alice, "").await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    // One request every 12 seconds
+    assert!((1..=12).contains(&retry_after(&response)));
+
+    let bob = sign_up(&app, "bob").await;
+    let response = send(&app, "GET", "/user/state", &bob, "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let wrong = credentials("alice", "wrong password");
+    let response = send(&app, "POST", "/login", "", &wrong).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    // One attempt every 30 seconds
+    assert!((1..=30).contains(&retry_after(&response)));
+
+    let response = send(
+        &app,
+        "POST",
+        "/auth/signup",
+        "",
+        &credentials("carol", "password123"),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = send(
+        &app,
+        "POST",
+        "/auth/signup",
+        "",
+        &credentials("dave", "password123"),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    // One signup every 20 seconds
+    assert!((1..=20).contains(&retry_after(&response)));
+}
@@ -0,0 +1,111 @@
+use axum::{
+    Router,
+    body::Body,
+    http::{Request, StatusCode},
+    response::Response,
+};
+use backup_sync_protocol::Computer;
+use backup_sync_server::db::test_db;
+use backup_sync_server::handlers::auth_handler::AuthResponse;
+use backup_sync_server::handlers::discovery_handler::ApiDiscovery;
+use backup_sync_server::handlers::pagination::Page;
+use backup_sync_server::{API_PREFIX, create_app_with_db};
+use tower::ServiceExt;
+
+async fn send(app: &Router, method: &str, uri: &str, auth_header: &str, body: &str) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json")
+        .header("Authorization", auth_header)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn body_json<T: serde::de::DeserializeOwned>(response: Response) -> T {
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+/// Every route answers under `/v1` and, flagged as deprecated, without it
+#[tokio::test]
+async fn test_versioned_and_legacy_paths() {
+    let app = create_app_with_db(test_db().await.unwrap()).unwrap();
+
+    let credentials = serde_json::json!({ "name": "alice", "password": "password123" });
+    let response = send(
+        &app,
+        "POST",
+        "/v1/auth/signup",
+        "",
+        &credentials.to_string(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert!(!response.headers().contains_key("deprecation"));
+    let response = send(&app, "POST", "/auth/login", "", &credentials.to_string()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["deprecation"], "true");
+    assert_eq!(
+        response.headers()["link"],
+        "</v1/auth/login>; rel=\"successor-version\""
+    );
+    let auth: AuthResponse = body_json(response).await;
+    let auth_header = format!("Bearer {}", auth.token);
+
+    let body = serde_json::json!({ "name": "laptop" }).to_string();
+    let response = send(&app, "POST", "/v1/computers", &auth_header, &body).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert!(!response.headers().contains_key("deprecation"));
+    let laptop: Computer = body_json(response).await;
+
+    for uri in ["/v1/computers", "/computers"] {
+        let response = send(&app, "GET", uri, &auth_header, "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let deprecated = response.headers().contains_key("deprecation");
+        assert_eq!(deprecated, !uri.starts_with(API_PREFIX), "{uri}");
+        let computers: Page<Computer> = body_json(response).await;
+        assert_eq!(computers.items.len(), 1);
+        assert_eq!(computers.items[0].id, laptop.id);
+    }
+
+    // Errors of legacy paths are flagged too, authentication failing before any handler
+    let response = send(&app, "GET", "/folders", "", "").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers()["deprecation"], "true");
+    let response = send(&app, "GET", "/v1/folders", "", "").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(!response.headers().contains_key("deprecation"));
+}
+
+#[tokio::test]
+async fn test_discovery_lists_routes() {
+    let app = create_app_with_db(test_db().await.unwrap()).unwrap();
+
+    let response = send(&app, "GET", API_PREFIX, "", "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let discovery: ApiDiscovery = body_json(response).await;
+    assert_eq!(discovery.api_version, "v1");
+    assert_eq!(discovery.server_version, env!("CARGO_PKG_VERSION"));
+    assert!(
+        discovery
+            .routes
+            .iter()
+            .any(|route| route.method == "POST" && route.path == "/v1/folders")
+    );
+    assert!(
+        discovery
+            .routes
+            .iter()
+            .all(|route| route.path.starts_with("/v1/"))
+    );
+    let legacy_login = discovery
+        .routes
+        .iter()
+        .find(|route| route.path == "/v1/login")
+        .unwrap();
+    assert!(legacy_login.deprecated);
+}
@@ -0,0 +1,266 @@
+use axum::{
+    Router,
+    body::Body,
+    http::{Request, StatusCode, header},
+    response::Response,
+};
+use backup_sync_protocol::{
+    ClientMessage, Computer, PROTOCOL_VERSION, ServerMessage, SyncFolder, User,
+};
+use backup_sync_server::create_app_with_db;
+use backup_sync_server::db::test_db;
+use backup_sync_server::handlers::auth_handler::AuthResponse;
+use backup_sync_server::handlers::pagination::Page;
+use futures_util::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tower::ServiceExt;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn send(app: &Router, method: &str, uri: &str, auth_header: &str, body: &str) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json")
+        .header("Authorization", auth_header)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn body_json<T: serde::de::DeserializeOwned>(response: Response) -> T {
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+/// Serves `app` on a local port, with the peer addresses `/ws` needs
+async fn serve(app: Router) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+    addr
+}
+
+async fn connect(addr: SocketAddr, token: Option<&str>) -> Result<Socket, WsError> {
+    let mut request = format!("ws://{addr}/ws").into_client_request().unwrap();
+    if let Some(token) = token {
+        request.headers_mut().insert(
+            header::AUTHORIZATION,
+            format!("Bearer {token}").parse().unwrap(),
+        );
+    }
+    tokio_tungstenite::connect_async(request)
+        .await
+        .map(|(socket, _)| socket)
+}
+
+async fn send_message(socket: &mut Socket, message: &ClientMessage) {
+    let text = serde_json::to_string(message).unwrap();
+    socket.send(Message::Text(text.into())).await.unwrap();
+}
+
+async fn receive(socket: &mut Socket) -> ServerMessage {
+    loop {
+        let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+            .await
+            .expect("no message from the server")
+            .unwrap()
+            .unwrap();
+        if let Message::Text(text) = message {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+/// Registers alice over HTTP with a computer named laptop
+async fn sign_up(app: &Router) -> (AuthResponse, Computer) {
+    let credentials = serde_json::json!({ "name": "alice", "password": "password123" });
+    let response = send(app, "POST", "/register", "", &credentials.to_string()).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = send(app, "POST", "/login", "", &credentials.to_string()).await;
+    let auth: AuthResponse = body_json(response).await;
+    let auth_header = format!("Bearer {}", auth.token);
+    let body = serde_json::json!({ "name": "laptop" }).to_string();
+    let response = send(app, "POST", "/computers", &auth_header, &body).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    (auth, body_json(response).await)
+}
+
+/// Connects `computer` over websocket and authenticates it
+async fn authenticate(
+    addr: SocketAddr,
+    auth: &AuthResponse,
+    computer: &Computer,
+) -> (Socket, User) {
+    let mut socket = connect(addr, Some(&auth.token)).await.unwrap();
+    assert!(matches!(
+        receive(&mut socket).await,
+        ServerMessage::Welcome { .. }
+    ));
+    send_message(
+        &mut socket,
+        &ClientMessage::Authenticate {
+            user_id: auth.user_id.clone(),
+            computer_id: computer.id.clone(),
+            protocol_version: PROTOCOL_VERSION,
+            token: auth.token.clone(),
+            last_applied: None,
+        },
+    )
+    .await;
+    let ServerMessage::Authenticated { user } = receive(&mut socket).await else {
+        panic!("not authenticated");
+    };
+    (socket, user)
+}
+
+/// A computer registered over HTTP authenticates over the websocket of the same server,
+/// and the folder it creates there is listed over HTTP
+#[tokio::test]
+async fn test_websocket_shares_users_with_http() {
+    let app = create_app_with_db(test_db().await.unwrap()).unwrap();
+    let addr = serve(app.clone()).await;
+    let (auth, laptop) = sign_up(&app).await;
+    let auth_header = format!("Bearer {}", auth.token);
+
+    let refused = connect(addr, None).await;
+    assert!(
+        matches!(&refused, Err(WsError::Http(response)) if response.status() == StatusCode::UNAUTHORIZED)
+    );
+
+    let (mut socket, user) = authenticate(addr, &auth, &laptop).await;
+    assert_eq!(user.name, "alice");
+    assert_eq!(user.computers.len(), 1);
+    assert_eq!(user.computers[0].id, laptop.id);
+
+    send_message(
+        &mut socket,
+        &ClientMessage::CreateSyncFolder {
+            name: "Documents".to_string(),
+        },
+    )
+    .await;
+    let ServerMessage::SyncFolderCreated { folder } = receive(&mut socket).await else {
+        panic!("folder not created");
+    };
+    assert_eq!(folder.origin_computer, laptop.id);
+
+    // Written to the database in the background
+    let mut listed = Vec::new();
+    for _ in 0..50 {
+        let response = send(&app, "GET", "/folders", &auth_header, "").await;
+        let page: Page<SyncFolder> = body_json(response).await;
+        listed = page.items;
+        if !listed.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].id, folder.id);
+    assert_eq!(listed[0].name, "Documents");
+    assert_eq!(listed[0].origin_computer, laptop.id);
+}
+
+/// Folders created over websocket are kept by the connections upgraded meanwhile, which
+/// load the user from the database
+#[tokio::test]
+async fn test_upgrade_keeps_folders_created_over_websocket() {
+    let app = create_app_with_db(test_db().await.unwrap()).unwrap();
+    let addr = serve(app.clone()).await;
+    let (auth, laptop) = sign_up(&app).await;
+    let auth_header = format!("Bearer {}", auth.token);
+    let body = serde_json::json!({ "name": "desktop" }).to_string();
+    let response = send(&app, "POST", "/computers", &auth_header, &body).await;
+    let desktop: Computer = body_json(response).await;
+    let (mut socket, _) = authenticate(addr, &auth, &laptop).await;
+
+    // Enough of them for some to be still queued for the database during the upgrade
+    for i in 0..50 {
+        send_message(
+            &mut socket,
+            &ClientMessage::CreateSyncFolder {
+                name: format!("Folder {i}"),
+            },
+        )
+        .await;
+    }
+    let (mut second, _) = authenticate(addr, &auth, &desktop).await;
+    let mut created = Vec::new();
+    while created.len() < 50 {
+        if let ServerMessage::SyncFolderCreated { folder } = receive(&mut socket).await {
+            created.push(folder.id);
+        }
+    }
+
+    send_message(&mut second, &ClientMessage::GetUserState).await;
+    let user = loop {
+        if let ServerMessage::UserState { user } = receive(&mut second).await {
+            break user;
+        }
+    };
+    let mut known: Vec<_> = user
+        .sync_folders
+        .into_iter()
+        .map(|folder| folder.id)
+        .collect();
+    known.sort();
+    created.sort();
+    assert_eq!(known, created);
+}
+
+/// The online flag of `/user/state` for `computer`
+async fn online_in_user_state(app: &Router, auth_header: &str, computer: &Computer) -> bool {
+    let response = send(app, "GET", "/user/state", auth_header, "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let user: User = body_json(response).await;
+    user.computers
+        .iter()
+        .find(|listed| listed.id == computer.id)
+        .unwrap()
+        .online
+}
+
+/// Computers are online in `/user/state` while connected over websocket, however long
+/// ago their last heartbeat was
+#[tokio::test]
+async fn test_user_state_reports_connected_computers_online() {
+    let db = test_db().await.unwrap();
+    let app = create_app_with_db(db.clone()).unwrap();
+    let addr = serve(app.clone()).await;
+    let (auth, laptop) = sign_up(&app).await;
+    let auth_header = format!("Bearer {}", auth.token);
+    sqlx::query("UPDATE computers SET last_seen = 0")
+        .execute(&db)
+        .await
+        .unwrap();
+    assert!(!online_in_user_state(&app, &auth_header, &laptop).await);
+
+    let (mut socket, _) = authenticate(addr, &auth, &laptop).await;
+    assert!(online_in_user_state(&app, &auth_header, &laptop).await);
+
+    socket.close(None).await.unwrap();
+    let mut online = true;
+    for _ in 0..50 {
+        online = online_in_user_state(&app, &auth_header, &laptop).await;
+        if !online {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(!online);
+}
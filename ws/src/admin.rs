@@ -0,0 +1,164 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use backup_sync_protocol::{ComputerId, FolderId, UserId};
+use serde::Serialize;
+
+use crate::state::ServerState;
+
+/// Operations pending for longer than this are listed as stuck, unless the request
+/// asks for another age with `?older_than=SECONDS`
+const DEFAULT_STUCK_AFTER: Duration = Duration::from_secs(60);
+
+/// A connection, see [`ServerState::connections_summary`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConnectionSummary {
+    pub addr: SocketAddr,
+    /// `None` until the connection authenticates
+    pub user_id: Option<UserId>,
+    pub computer_id: Option<ComputerId>,
+    /// Unix time the connection was accepted at
+    pub connected_since: i64,
+}
+
+/// A folder with what its backups have yet to answer, see
+/// [`ServerState::folders_summary`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FolderSummary {
+    pub folder_id: FolderId,
+    pub name: String,
+    pub origin_computer: ComputerId,
+    pub backup_computers: Vec<ComputerId>,
+    pub is_synced: bool,
+    /// Operations some backup has not acked or nacked yet
+    pub pending_operations: usize,
+    /// Seconds the oldest of those operations has been pending; `None` without any
+    pub oldest_pending_secs: Option<u64>,
+}
+
+/// The folders of a user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UserSummary {
+    pub user_id: UserId,
+    pub folders: Vec<FolderSummary>,
+}
+
+/// An operation pending for a while, see [`ServerState::stuck_operations`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StuckOperation {
+    pub folder_id: FolderId,
+    pub operation_id: u64,
+    /// Unix time the operation was forwarded to the backups at
+    pub pending_since: i64,
+    /// Backups that have not answered it, sorted
+    pub awaiting: Vec<ComputerId>,
+}
+
+/// Answer to an admin request: HTTP status and JSON body
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdminResponse {
+    pub status: &'static str,
+    pub body: String,
+}
+
+impl AdminResponse {
+    fn error(status: &'static str, message: &str) -> Self {
+        Self {
+            status,
+            body: serde_json::json!({ "error": message }).to_string(),
+        }
+    }
+
+    fn json(value: &impl Serialize) -> Self {
+        match serde_json::to_string(value) {
+            Ok(body) => Self {
+                status: "200 OK",
+                body,
+            },
+            Err(_) => Self::error("500 Internal Server Error", "Failed to encode the answer"),
+        }
+    }
+}
+
+/// Answers a request for `target`, a path under `/admin/` with its query, made with
+/// the `Authorization` header `authorization`. Admin requests are refused without an
+/// `admin_token` configured, and need it as a bearer token otherwise.
+#[must_use]
+pub fn respond(
+    state: &ServerState,
+    target: &str,
+    authorization: Option<&str>,
+    admin_token: Option<&str>,
+) -> AdminResponse {
+    let Some(admin_token) = admin_token else {
+        return AdminResponse::error("404 Not Found", "Admin API is not enabled");
+    };
+    let token = authorization.and_then(|value| value.strip_prefix("Bearer "));
+    if !token.is_some_and(|token| constant_time_eq(token.trim(), admin_token)) {
+        return AdminResponse::error("401 Unauthorized", "Invalid admin token");
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    match path {
+        "/admin/connections" => AdminResponse::json(&state.connections_summary()),
+        "/admin/folders" => AdminResponse::json(&state.folders_summary()),
+        "/admin/snapshot" => AdminResponse::json(&state.export_snapshot()),
+        "/admin/stuck" => {
+            let older_than = query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("older_than="))
+                .map(str::parse::<u64>)
+                .transpose();
+            match older_than {
+                Ok(secs) => {
+                    let older_than = secs.map_or(DEFAULT_STUCK_AFTER, Duration::from_secs);
+                    AdminResponse::json(&state.stuck_operations(older_than))
+                }
+                Err(_) => AdminResponse::error("400 Bad Request", "Invalid older_than"),
+            }
+        }
+        _ => AdminResponse::error("404 Not Found", "Unknown admin path"),
+    }
+}
+
+/// Compares tokens without leaking through timing how much of them matched
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admin_requests_need_the_token() {
+        let state = ServerState::new();
+
+        let disabled = respond(&state, "/admin/connections", Some("Bearer secret"), None);
+        assert_eq!(disabled.status, "404 Not Found");
+        for authorization in [None, Some("Bearer wrong"), Some("secret")] {
+            let refused = respond(&state, "/admin/connections", authorization, Some("secret"));
+            assert_eq!(refused.status, "401 Unauthorized");
+        }
+
+        let allowed = respond(
+            &state,
+            "/admin/connections",
+            Some("Bearer secret"),
+            Some("secret"),
+        );
+        assert_eq!(allowed.status, "200 OK");
+        assert_eq!(allowed.body, "[]");
+        let invalid = respond(
+            &state,
+            "/admin/stuck?older_than=soon",
+            Some("Bearer secret"),
+            Some("secret"),
+        );
+        assert_eq!(invalid.status, "400 Bad Request");
+    }
+}
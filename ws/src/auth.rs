@@ -0,0 +1,52 @@
+use std::fmt;
+
+use backup_sync_protocol::UserId;
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{DecodingKey, Validation, decode};
+use serde::Deserialize;
+
+/// Claims of the tokens issued by the HTTP server on login
+#[derive(Debug, Deserialize)]
+struct Claims {
+    /// Id of the user the token was issued to
+    sub: String,
+}
+
+/// Checks the tokens clients authenticate with, signed by the HTTP server with the
+/// secret both servers share
+#[derive(Clone)]
+pub struct TokenValidator {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl fmt::Debug for TokenValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenValidator").finish_non_exhaustive()
+    }
+}
+
+impl TokenValidator {
+    #[must_use]
+    pub fn new(secret: &str) -> Self {
+        Self {
+            key: DecodingKey::from_secret(secret.as_bytes()),
+            // Signature and expiry, with the default leeway for clock skew
+            validation: Validation::default(),
+        }
+    }
+
+    /// Accepts tokens with a valid signature, not expired, and issued to `user_id`
+    pub fn validate(&self, token: &str, user_id: &UserId) -> Result<(), &'static str> {
+        let claims = decode::<Claims>(token, &self.key, &self.validation)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => "Token expired",
+                _ => "Invalid token",
+            })?
+            .claims;
+        if claims.sub != user_id.as_str() {
+            return Err("Token was issued to another user");
+        }
+        Ok(())
+    }
+}
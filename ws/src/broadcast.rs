@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use backup_sync_protocol::UserId;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::state::BroadcastMessage;
+
+/// Broadcast channels, one per user with a connected computer. Connections subscribe to
+/// the channel of their user once authenticated, so they never see the messages of other
+/// users; a channel is created with its first subscription and dropped with its last.
+#[derive(Debug, Clone)]
+pub struct Broadcasts {
+    channels: Arc<Mutex<HashMap<UserId, broadcast::Sender<BroadcastMessage>>>>,
+    capacity: usize,
+}
+
+impl Broadcasts {
+    /// Channels buffering up to `capacity` messages for their slowest connection
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            channels: Arc::default(),
+            capacity,
+        }
+    }
+
+    /// Subscribes to the messages of a user, creating its channel if needed
+    #[must_use]
+    pub fn subscribe(&self, user_id: &UserId) -> Subscription {
+        let receiver = self
+            .lock()
+            .entry(user_id.clone())
+            .or_insert_with(|| broadcast::channel(self.capacity).0)
+            .subscribe();
+        Subscription {
+            receiver,
+            user_id: user_id.clone(),
+            broadcasts: self.clone(),
+        }
+    }
+
+    /// Sends a message on the channel of its user. It is dropped when no connection of
+    /// that user subscribed.
+    pub fn send(&self, message: BroadcastMessage) {
+        if let Some(sender) = self.lock().get(&message.user_id) {
+            let _ = sender.send(message);
+        }
+    }
+
+    /// Number of users with a channel
+    #[must_use]
+    pub fn channel_count(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Number of connections subscribed to the channel of a user
+    #[must_use]
+    pub fn subscriber_count(&self, user_id: &UserId) -> usize {
+        self.lock()
+            .get(user_id)
+            .map_or(0, broadcast::Sender::receiver_count)
+    }
+
+    fn lock(
+        &self,
+    ) -> std::sync::MutexGuard<'_, HashMap<UserId, broadcast::Sender<BroadcastMessage>>> {
+        // The map is left consistent by every operation, even one that panicked
+        self.channels.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Messages of a user for one connection; the channel goes away with its last subscription
+#[derive(Debug)]
+pub struct Subscription {
+    receiver: broadcast::Receiver<BroadcastMessage>,
+    user_id: UserId,
+    broadcasts: Broadcasts,
+}
+
+impl Subscription {
+    pub async fn recv(&mut self) -> Result<BroadcastMessage, RecvError> {
+        self.receiver.recv().await
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut channels = self.broadcasts.lock();
+        // This receiver is only dropped after, so it is the last one when counted alone
+        if channels
+            .get(&self.user_id)
+            .is_some_and(|sender| sender.receiver_count() <= 1)
+        {
+            channels.remove(&self.user_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use backup_sync_protocol::ServerMessage;
+
+    use super::*;
+    use crate::state::Audience;
+
+    fn message(user_id: &str, nonce: u64) -> BroadcastMessage {
+        BroadcastMessage {
+            user_id: user_id.into(),
+            message: ServerMessage::Pong { nonce },
+            audience: Audience::User { except: None },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions_only_get_their_user_messages() {
+        let broadcasts = Broadcasts::new(16);
+        let mut user1 = broadcasts.subscribe(&"user1".into());
+        let mut user2 = broadcasts.subscribe(&"user2".into());
+
+        broadcasts.send(message("user1", 1));
+        broadcasts.send(message("user2", 2));
+        broadcasts.send(message("user3", 3));
+
+        let received = user1.recv().await.unwrap();
+        assert!(matches!(received.message, ServerMessage::Pong { nonce: 1 }));
+        let received = user2.recv().await.unwrap();
+        assert!(matches!(received.message, ServerMessage::Pong { nonce: 2 }));
+        assert!(matches!(
+            user1.receiver.try_recv(),
+            Err(broadcast::error::TryRecvError::Empty)
+        ));
+        assert!(matches!(
+            user2.receiver.try_recv(),
+            Err(broadcast::error::TryRecvError::Empty)
+        ));
+    }
+
+    #[test]
+    fn test_channel_is_dropped_with_last_subscription() {
+        let broadcasts = Broadcasts::new(16);
+        let first = broadcasts.subscribe(&"user1".into());
+        let second = broadcasts.subscribe(&"user1".into());
+        assert_eq!(broadcasts.channel_count(), 1);
+        assert_eq!(broadcasts.subscriber_count(&"user1".into()), 2);
+
+        drop(first);
+        assert_eq!(broadcasts.subscriber_count(&"user1".into()), 1);
+        drop(second);
+        assert_eq!(broadcasts.channel_count(), 0);
+
+        // Nobody listens anymore, the message is dropped
+        broadcasts.send(message("user1", 1));
+        assert_eq!(broadcasts.channel_count(), 0);
+    }
+}
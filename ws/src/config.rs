@@ -0,0 +1,551 @@
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde::Deserialize;
+
+use crate::rate_limit::RateLimit;
+use crate::server::ServerConfig;
+use crate::tls::TlsConfig;
+
+/// Prefix of the environment variables read by [`ConfigLayer::from_env`]
+pub const ENV_PREFIX: &str = "BACKUP_SYNC_WS_";
+
+/// Settings from one source, each overriding the value below it when set: the defaults,
+/// then the config file, then the environment, then the command line.
+///
+/// In a file, the settings are keys of a TOML table:
+///
+/// ```toml
+/// addrs = ["0.0.0.0:9000", "[::]:9000"]
+/// broadcast_capacity = 500
+/// data_path = "/var/lib/backup-sync/ws.json"
+/// jwt_secret = "..."
+/// # Folder operations per second and connection; 0 for no limit
+/// rate_limit = 100
+/// ```
+///
+/// In the environment, they are the same names in upper case after [`ENV_PREFIX`], such
+/// as `BACKUP_SYNC_WS_BROADCAST_CAPACITY=500`, lists separated by commas.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigLayer {
+    pub addrs: Option<Vec<String>>,
+    /// Starts as long as one of `addrs` could be bound, instead of needing them all
+    pub best_effort_bind: Option<bool>,
+    pub broadcast_capacity: Option<usize>,
+    pub idle_timeout_secs: Option<u64>,
+    pub ping_interval_secs: Option<u64>,
+    pub max_missed_pongs: Option<u32>,
+    pub auth_timeout_secs: Option<u64>,
+    /// Connections waiting to authenticate at once; 0 for no limit
+    pub max_unauthenticated_connections: Option<usize>,
+    pub data_path: Option<PathBuf>,
+    pub persist_interval_ms: Option<u64>,
+    pub journal_capacity: Option<usize>,
+    /// Needs `tls_key` with it, in the same source
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub jwt_secret: Option<String>,
+    pub allow_insecure_auth: Option<bool>,
+    /// Folder operations per second and connection; 0 for no limit
+    pub rate_limit: Option<u32>,
+    pub rate_burst: Option<u32>,
+    pub max_message_bytes: Option<usize>,
+    pub max_file_content_bytes: Option<usize>,
+    /// Operations pending per folder before the origin is held back; 0 for no limit
+    pub max_pending_operations: Option<usize>,
+    /// Seconds before unanswered operations are dropped; 0 keeps them until answered
+    pub pending_ttl_secs: Option<u64>,
+    pub sweep_interval_secs: Option<u64>,
+    /// Keeps file content for backups to resync from; needs `data_path`
+    pub content_store: Option<bool>,
+    pub content_max_bytes: Option<u64>,
+    pub content_ttl_secs: Option<u64>,
+    pub shutdown_grace_secs: Option<u64>,
+    pub metrics_addr: Option<String>,
+    pub admin_token: Option<String>,
+}
+
+impl ConfigLayer {
+    /// Reads a TOML config file
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file: {path:?}"))?;
+        Self::parse(&content).with_context(|| format!("Invalid config file: {path:?}"))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        // toml's error already points at the offending key and line
+        toml::from_str(content).map_err(|e| anyhow!("{}", e.to_string().trim_end()))
+    }
+
+    /// Reads the `BACKUP_SYNC_WS_*` environment variables
+    pub fn from_env() -> Result<Self> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Reads the variables `lookup` finds, by their full name
+    pub fn from_vars(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let lookup = &lookup;
+        Ok(Self {
+            addrs: var::<String>(lookup, "ADDRS")?.map(|addrs| {
+                addrs
+                    .split(',')
+                    .map(|addr| addr.trim().to_string())
+                    .collect()
+            }),
+            best_effort_bind: var(lookup, "BEST_EFFORT_BIND")?,
+            broadcast_capacity: var(lookup, "BROADCAST_CAPACITY")?,
+            idle_timeout_secs: var(lookup, "IDLE_TIMEOUT_SECS")?,
+            ping_interval_secs: var(lookup, "PING_INTERVAL_SECS")?,
+            max_missed_pongs: var(lookup, "MAX_MISSED_PONGS")?,
+            auth_timeout_secs: var(lookup, "AUTH_TIMEOUT_SECS")?,
+            max_unauthenticated_connections: var(lookup, "MAX_UNAUTHENTICATED_CONNECTIONS")?,
+            data_path: var(lookup, "DATA_PATH")?,
+            persist_interval_ms: var(lookup, "PERSIST_INTERVAL_MS")?,
+            journal_capacity: var(lookup, "JOURNAL_CAPACITY")?,
+            tls_cert: var(lookup, "TLS_CERT")?,
+            tls_key: var(lookup, "TLS_KEY")?,
+            jwt_secret: var(lookup, "JWT_SECRET")?,
+            allow_insecure_auth: var(lookup, "ALLOW_INSECURE_AUTH")?,
+            rate_limit: var(lookup, "RATE_LIMIT")?,
+            rate_burst: var(lookup, "RATE_BURST")?,
+            max_message_bytes: var(lookup, "MAX_MESSAGE_BYTES")?,
+            max_file_content_bytes: var(lookup, "MAX_FILE_CONTENT_BYTES")?,
+            max_pending_operations: var(lookup, "MAX_PENDING_OPERATIONS")?,
+            pending_ttl_secs: var(lookup, "PENDING_TTL_SECS")?,
+            sweep_interval_secs: var(lookup, "SWEEP_INTERVAL_SECS")?,
+            content_store: var(lookup, "CONTENT_STORE")?,
+            content_max_bytes: var(lookup, "CONTENT_MAX_BYTES")?,
+            content_ttl_secs: var(lookup, "CONTENT_TTL_SECS")?,
+            shutdown_grace_secs: var(lookup, "SHUTDOWN_GRACE_SECS")?,
+            metrics_addr: var(lookup, "METRICS_ADDR")?,
+            admin_token: var(lookup, "ADMIN_TOKEN")?,
+        })
+    }
+}
+
+/// Value of the variable `BACKUP_SYNC_WS_{name}`, `None` when unset or empty
+fn var<T>(lookup: &impl Fn(&str) -> Option<String>, name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let name = format!("{ENV_PREFIX}{name}");
+    match lookup(&name) {
+        Some(value) if !value.is_empty() => value
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow!("{name}: invalid value {value:?}: {e}")),
+        _ => Ok(None),
+    }
+}
+
+impl ServerConfig {
+    /// The defaults, overridden by a TOML config file
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let config = Self::default().merge(ConfigLayer::from_file(path)?)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The defaults, overridden by the `BACKUP_SYNC_WS_*` environment variables
+    pub fn from_env() -> Result<Self> {
+        let config = Self::default().merge(ConfigLayer::from_env()?)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Applies the settings `layer` has on top of this config. A rate limit, pending
+    /// operations limit, unauthenticated connections limit or pending TTL of 0 removes
+    /// the limit; a burst alone changes that
+    /// of the current rate limit, and content limits alone those of the content store.
+    pub fn merge(mut self, layer: ConfigLayer) -> Result<Self> {
+        if let Some(addrs) = layer.addrs {
+            self.addrs = addrs;
+        }
+        if let Some(best_effort) = layer.best_effort_bind {
+            self.best_effort_bind = best_effort;
+        }
+        if let Some(capacity) = layer.broadcast_capacity {
+            self.broadcast_capacity = capacity;
+        }
+        if let Some(secs) = layer.idle_timeout_secs {
+            self.idle_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = layer.ping_interval_secs {
+            self.ping_interval = Duration::from_secs(secs);
+        }
+        if let Some(count) = layer.max_missed_pongs {
+            self.max_missed_pongs = count;
+        }
+        if let Some(secs) = layer.auth_timeout_secs {
+            self.auth_timeout = Duration::from_secs(secs);
+        }
+        if let Some(count) = layer.max_unauthenticated_connections {
+            self.max_unauthenticated_connections = (count > 0).then_some(count);
+        }
+        if layer.data_path.is_some() {
+            self.data_path = layer.data_path;
+        }
+        if let Some(ms) = layer.persist_interval_ms {
+            self.persist_interval = Duration::from_millis(ms);
+        }
+        if let Some(capacity) = layer.journal_capacity {
+            self.journal_capacity = capacity;
+        }
+        match (layer.tls_cert, layer.tls_key) {
+            (Some(cert_path), Some(key_path)) => {
+                self.tls = Some(TlsConfig {
+                    cert_path,
+                    key_path,
+                });
+            }
+            (None, None) => {}
+            _ => bail!("tls_cert and tls_key must be given together"),
+        }
+        if layer.jwt_secret.is_some() {
+            self.jwt_secret = layer.jwt_secret;
+        }
+        if let Some(allow) = layer.allow_insecure_auth {
+            self.allow_insecure_auth = allow;
+        }
+        match layer.rate_limit {
+            Some(0) => self.rate_limit = None,
+            Some(per_second) => {
+                let burst = self.rate_limit.unwrap_or_default().burst;
+                self.rate_limit = Some(RateLimit { per_second, burst });
+            }
+            None => {}
+        }
+        if let Some(burst) = layer.rate_burst
+            && let Some(limit) = &mut self.rate_limit
+        {
+            limit.burst = burst;
+        }
+        if let Some(bytes) = layer.max_message_bytes {
+            self.max_message_bytes = bytes;
+        }
+        if let Some(bytes) = layer.max_file_content_bytes {
+            self.max_file_content_bytes = bytes;
+        }
+        if let Some(count) = layer.max_pending_operations {
+            self.max_pending_operations = (count > 0).then_some(count);
+        }
+        if let Some(secs) = layer.pending_ttl_secs {
+            self.pending_ttl = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if let Some(secs) = layer.sweep_interval_secs {
+            self.sweep_interval = Duration::from_secs(secs);
+        }
+        match layer.content_store {
+            Some(true) => {
+                self.content_store.get_or_insert_default();
+            }
+            Some(false) => self.content_store = None,
+            None => {}
+        }
+        if let Some(content) = &mut self.content_store {
+            if let Some(bytes) = layer.content_max_bytes {
+                content.max_bytes = bytes;
+            }
+            if let Some(secs) = layer.content_ttl_secs {
+                content.ttl = Duration::from_secs(secs);
+            }
+        }
+        if let Some(secs) = layer.shutdown_grace_secs {
+            self.shutdown_grace = Duration::from_secs(secs);
+        }
+        if layer.metrics_addr.is_some() {
+            self.metrics_addr = layer.metrics_addr;
+        }
+        if layer.admin_token.is_some() {
+            self.admin_token = layer.admin_token;
+        }
+        Ok(self)
+    }
+
+    /// Refuses settings the server cannot run with, naming the first offending one
+    pub fn validate(&self) -> Result<()> {
+        if self.addrs.is_empty() {
+            bail!("addrs must have an address to listen on");
+        }
+        for addr in &self.addrs {
+            check_addr("addrs", addr)?;
+        }
+        if let Some(metrics_addr) = &self.metrics_addr {
+            check_addr("metrics_addr", metrics_addr)?;
+        }
+        let positive = [
+            ("broadcast_capacity", self.broadcast_capacity as u128),
+            ("idle_timeout", self.idle_timeout.as_millis()),
+            ("ping_interval", self.ping_interval.as_millis()),
+            ("max_missed_pongs", u128::from(self.max_missed_pongs)),
+            ("auth_timeout", self.auth_timeout.as_millis()),
+            ("persist_interval", self.persist_interval.as_millis()),
+            ("sweep_interval", self.sweep_interval.as_millis()),
+            ("max_message_bytes", self.max_message_bytes as u128),
+            (
+                "max_file_content_bytes",
+                self.max_file_content_bytes as u128,
+            ),
+        ];
+        if let Some((name, _)) = positive.iter().find(|(_, value)| *value == 0) {
+            bail!("{name} must be greater than 0");
+        }
+        if let Some(limit) = self.rate_limit
+            && limit.burst == 0
+        {
+            bail!("rate_burst must be greater than 0, or rate_limit 0 for no limit");
+        }
+        if let Some(content) = self.content_store {
+            if self.data_path.is_none() {
+                bail!("content_store needs a data_path to keep the content next to");
+            }
+            if content.max_bytes == 0 {
+                bail!("content_max_bytes must be greater than 0");
+            }
+            if content.ttl.is_zero() {
+                bail!("content_ttl must be greater than 0");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An address to listen on: an IP address or host name, and a port
+fn check_addr(name: &str, addr: &str) -> Result<()> {
+    if addr.parse::<SocketAddr>().is_ok() {
+        return Ok(());
+    }
+    match addr.rsplit_once(':') {
+        Some((host, port))
+            if !host.is_empty() && !host.contains(['[', ']']) && port.parse::<u16>().is_ok() =>
+        {
+            Ok(())
+        }
+        _ => bail!("{name}: invalid address {addr:?}, expected HOST:PORT"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::content::ContentLimits;
+
+    fn vars(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = pairs
+            .iter()
+            .map(|(name, value)| (format!("{ENV_PREFIX}{name}"), (*value).to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_environment_overrides_file_overrides_defaults() {
+        let file = ConfigLayer::parse(
+            r#"
+            addrs = ["127.0.0.1:7000"]
+            broadcast_capacity = 500
+            ping_interval_secs = 5
+            rate_limit = 50
+            "#,
+        )
+        .unwrap();
+        let env = ConfigLayer::from_vars(vars(&[
+            ("ADDRS", "0.0.0.0:8000, [::]:8000"),
+            ("BEST_EFFORT_BIND", "true"),
+            ("JWT_SECRET", "secret"),
+            ("RATE_BURST", "80"),
+            ("MAX_MESSAGE_BYTES", ""),
+        ]))
+        .unwrap();
+
+        let config = ServerConfig::default()
+            .merge(file)
+            .unwrap()
+            .merge(env)
+            .unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.addrs, ["0.0.0.0:8000", "[::]:8000"]);
+        assert!(config.best_effort_bind);
+        assert_eq!(config.broadcast_capacity, 500);
+        assert_eq!(config.ping_interval, Duration::from_secs(5));
+        assert_eq!(config.jwt_secret.as_deref(), Some("secret"));
+        assert_eq!(
+            config.rate_limit,
+            Some(RateLimit {
+                per_second: 50,
+                burst: 80,
+            })
+        );
+        // Left alone by every layer, empty variables included
+        let defaults = ServerConfig::default();
+        assert_eq!(config.max_message_bytes, defaults.max_message_bytes);
+        assert_eq!(config.idle_timeout, defaults.idle_timeout);
+    }
+
+    #[test]
+    fn test_zero_limits_are_removed() {
+        let layer = ConfigLayer::from_vars(vars(&[
+            ("RATE_LIMIT", "0"),
+            ("MAX_PENDING_OPERATIONS", "0"),
+            ("PENDING_TTL_SECS", "0"),
+            ("MAX_UNAUTHENTICATED_CONNECTIONS", "0"),
+        ]))
+        .unwrap();
+
+        let config = ServerConfig::default().merge(layer).unwrap();
+
+        assert_eq!(config.rate_limit, None);
+        assert_eq!(config.max_pending_operations, None);
+        assert_eq!(config.pending_ttl, None);
+        assert_eq!(config.max_unauthenticated_connections, None);
+    }
+
+    #[test]
+    fn test_content_limits_apply_to_enabled_store() {
+        let limits_only = ConfigLayer::from_vars(vars(&[("CONTENT_MAX_BYTES", "1024")])).unwrap();
+        let config = ServerConfig::default().merge(limits_only.clone()).unwrap();
+        assert_eq!(config.content_store, None);
+
+        let file = ConfigLayer::parse(
+            r#"
+            data_path = "state.json"
+            content_store = true
+            content_ttl_secs = 60
+            "#,
+        )
+        .unwrap();
+        let config = ServerConfig::default()
+            .merge(file)
+            .unwrap()
+            .merge(limits_only)
+            .unwrap();
+        config.validate().unwrap();
+        assert_eq!(
+            config.content_store,
+            Some(ContentLimits {
+                max_bytes: 1024,
+                ttl: Duration::from_secs(60),
+            })
+        );
+
+        let disabled = ConfigLayer::from_vars(vars(&[("CONTENT_STORE", "false")])).unwrap();
+        let config = config.merge(disabled).unwrap();
+        assert_eq!(config.content_store, None);
+    }
+
+    #[test]
+    fn test_invalid_sources_are_refused() {
+        let err = ConfigLayer::parse("broadcast_capacity = \"lots\"").unwrap_err();
+        assert!(err.to_string().contains("broadcast_capacity"), "{err}");
+        let err = ConfigLayer::parse("unknown = 1").unwrap_err();
+        assert!(err.to_string().contains("unknown"), "{err}");
+
+        let err = ConfigLayer::from_vars(vars(&[("BROADCAST_CAPACITY", "-1")])).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("BACKUP_SYNC_WS_BROADCAST_CAPACITY: invalid value \"-1\""),
+            "{err}"
+        );
+        let err = ConfigLayer::from_vars(vars(&[("ALLOW_INSECURE_AUTH", "yes")])).unwrap_err();
+        assert!(err.to_string().contains("ALLOW_INSECURE_AUTH"), "{err}");
+
+        let cert_only = ConfigLayer {
+            tls_cert: Some("cert.pem".into()),
+            ..ConfigLayer::default()
+        };
+        assert!(ServerConfig::default().merge(cert_only).is_err());
+    }
+
+    #[test]
+    fn test_validation_failures() {
+        ServerConfig::default().validate().unwrap();
+        for (config, expected) in [
+            (
+                ServerConfig {
+                    addrs: vec!["localhost".to_string()],
+                    ..ServerConfig::default()
+                },
+                "addrs: invalid address",
+            ),
+            (
+                ServerConfig {
+                    addrs: vec!["[::1]:9000".to_string(), "127.0.0.1:99999".to_string()],
+                    ..ServerConfig::default()
+                },
+                "addrs: invalid address",
+            ),
+            (
+                ServerConfig {
+                    addrs: Vec::new(),
+                    ..ServerConfig::default()
+                },
+                "addrs must have an address",
+            ),
+            (
+                ServerConfig {
+                    metrics_addr: Some(":9100".to_string()),
+                    ..ServerConfig::default()
+                },
+                "metrics_addr: invalid address",
+            ),
+            (
+                ServerConfig {
+                    broadcast_capacity: 0,
+                    ..ServerConfig::default()
+                },
+                "broadcast_capacity must be greater than 0",
+            ),
+            (
+                ServerConfig {
+                    ping_interval: Duration::ZERO,
+                    ..ServerConfig::default()
+                },
+                "ping_interval must be greater than 0",
+            ),
+            (
+                ServerConfig {
+                    max_message_bytes: 0,
+                    ..ServerConfig::default()
+                },
+                "max_message_bytes must be greater than 0",
+            ),
+            (
+                ServerConfig {
+                    rate_limit: Some(RateLimit {
+                        per_second: 10,
+                        burst: 0,
+                    }),
+                    ..ServerConfig::default()
+                },
+                "rate_burst must be greater than 0",
+            ),
+            (
+                ServerConfig {
+                    content_store: Some(ContentLimits::default()),
+                    ..ServerConfig::default()
+                },
+                "content_store needs a data_path",
+            ),
+        ] {
+            let err = config.validate().unwrap_err();
+            assert!(err.to_string().starts_with(expected), "{err}");
+        }
+
+        // Host names are resolved when binding
+        let named = ServerConfig {
+            addrs: vec!["localhost:9000".to_string()],
+            ..ServerConfig::default()
+        };
+        named.validate().unwrap();
+    }
+}
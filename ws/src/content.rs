@@ -0,0 +1,1138 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use backup_sync_protocol::{
+    Compression, FileOperation, FolderId, MAX_CHUNK_SIZE, RelativePath, TransferKind,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::state::unix_now;
+
+/// Largest content of a single operation the store decompresses by default, as backups do
+const MAX_DECOMPRESSED_LEN: usize = 256 * 1024 * 1024;
+
+/// Size of the chunks files are served in, that of the clients; smaller files are
+/// served in a single `CreateFile`
+pub const SERVED_CHUNK_SIZE: usize = 64 * 1024;
+
+/// First id of the transfers the store serves, far from those origins count from 1
+const SERVED_TRANSFER_IDS: u64 = 1 << 63;
+
+/// Limits of a [`ContentStore`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentLimits {
+    /// Bytes of content kept at most. Content past it is not stored, and its folder
+    /// cannot be served until the file is published again.
+    pub max_bytes: u64,
+    /// How long content no folder refers to anymore is kept, and unfinished transfers
+    /// are waited for
+    pub ttl: Duration,
+}
+
+impl Default for ContentLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 10 << 30,
+            ttl: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// What the store knows of one path of a folder
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IndexEntry {
+    Dir,
+    File {
+        hash: [u8; 32],
+        size: u64,
+        #[serde(default)]
+        mode: Option<u32>,
+        #[serde(default)]
+        readonly: Option<bool>,
+        #[serde(default)]
+        mtime: Option<i64>,
+    },
+    Symlink {
+        target: String,
+    },
+}
+
+/// The tree of a folder, as its origin published it
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FolderIndex {
+    pub entries: BTreeMap<RelativePath, IndexEntry>,
+    /// Paths whose content the store missed, such as files patched with a delta or too
+    /// large to keep. The folder cannot be served while it has any.
+    pub unknown: BTreeSet<RelativePath>,
+}
+
+/// A file of the store
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobInfo {
+    pub size: u64,
+    /// Unix time the last folder referring to the blob stopped doing so, `None` while
+    /// some still does
+    pub unreferenced_since: Option<i64>,
+}
+
+/// What the store keeps next to its blobs, saved with the state of the server
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentIndex {
+    /// Folders the store saw every operation of, from their first one
+    pub folders: BTreeMap<FolderId, FolderIndex>,
+    /// By hex Blake3 hash of their content
+    pub blobs: BTreeMap<String, BlobInfo>,
+}
+
+impl ContentIndex {
+    /// Replaces the saved index through a temp file next to it
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let temp_path = path.with_extension("json.tmp");
+        let mut file = fs::File::create(&temp_path)
+            .with_context(|| format!("Failed to create {temp_path:?}"))?;
+        file.write_all(&serde_json::to_vec(self)?)
+            .and_then(|()| file.sync_all())
+            .with_context(|| format!("Failed to write {temp_path:?}"))?;
+        fs::rename(&temp_path, path).with_context(|| format!("Failed to replace {path:?}"))
+    }
+}
+
+/// A transfer of file content being reassembled in the store
+#[derive(Debug)]
+struct StoredTransfer {
+    relative_path: RelativePath,
+    total_size: u64,
+    chunk_size: u64,
+    file: PathBuf,
+    received: HashSet<u64>,
+    /// Chunk count and hex hash, once the end of the transfer arrived
+    end: Option<(u64, String)>,
+    started_at: i64,
+}
+
+/// File content forwarded by origins, kept by Blake3 hash on disk so that backups can
+/// get it while the origin of their folder is offline. Each folder whose every
+/// operation went through the store has an index of its tree, whose files refer to the
+/// blobs; blobs no folder refers to are collected once the TTL elapsed.
+///
+/// Shared as [`SharedContent`], behind a lock of its own: the operations carrying content
+/// are stored once forwarded, without holding up the state while reading and writing
+/// files.
+#[derive(Debug)]
+pub struct ContentStore {
+    dir: PathBuf,
+    limits: ContentLimits,
+    index: ContentIndex,
+    /// Paths referring to each blob, over every folder
+    refs: HashMap<[u8; 32], usize>,
+    stored_bytes: u64,
+    transfers: HashMap<(FolderId, u64), StoredTransfer>,
+    /// Files of the transfers, named by their count
+    parts: u64,
+    next_transfer_id: u64,
+    /// Bytes the content of an operation may decompress to
+    max_content_len: usize,
+}
+
+impl ContentStore {
+    /// Directory of the store of a server keeping its state in `data_path`
+    #[must_use]
+    pub fn dir_for(data_path: &Path) -> PathBuf {
+        let mut name = data_path.file_name().unwrap_or_default().to_os_string();
+        name.push(".content");
+        data_path.with_file_name(name)
+    }
+
+    /// Opens the store in `dir`, created when missing. Blobs missing from the disk
+    /// make the paths referring to them unknown; blobs missing from the index, written
+    /// before a crash, are kept as unreferenced. Unfinished transfers are dropped, as
+    /// origins restart them when they reconnect.
+    pub fn open(dir: PathBuf, limits: ContentLimits) -> Result<Self> {
+        let blobs_dir = dir.join("blobs");
+        fs::create_dir_all(&blobs_dir)
+            .with_context(|| format!("Failed to create directory {blobs_dir:?}"))?;
+        let transfers_dir = dir.join("transfers");
+        if transfers_dir.exists() {
+            fs::remove_dir_all(&transfers_dir)
+                .with_context(|| format!("Failed to clear {transfers_dir:?}"))?;
+        }
+        fs::create_dir_all(&transfers_dir)
+            .with_context(|| format!("Failed to create directory {transfers_dir:?}"))?;
+
+        let index_path = dir.join("index.json");
+        let mut index: ContentIndex = match fs::read(&index_path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .with_context(|| format!("Failed to parse content index in {index_path:?}"))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ContentIndex::default(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {index_path:?}")),
+        };
+
+        let now = unix_now();
+        let mut on_disk = BTreeMap::new();
+        for entry in
+            fs::read_dir(&blobs_dir).with_context(|| format!("Failed to list {blobs_dir:?}"))?
+        {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if blake3::Hash::from_hex(&name).is_ok() {
+                on_disk.insert(name, entry.metadata()?.len());
+            } else {
+                // Left by a write that did not finish
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+        index.blobs.retain(|key, _| on_disk.contains_key(key));
+        for (key, size) in on_disk {
+            index.blobs.entry(key).or_insert(BlobInfo {
+                size,
+                unreferenced_since: Some(now),
+            });
+        }
+        for folder in index.folders.values_mut() {
+            let missing: Vec<RelativePath> = folder
+                .entries
+                .iter()
+                .filter(|(_, entry)| match entry {
+                    IndexEntry::File { hash, .. } => !index.blobs.contains_key(&hex(hash)),
+                    _ => false,
+                })
+                .map(|(path, _)| path.clone())
+                .collect();
+            for path in missing {
+                warn!(path = %path, "stored content is missing");
+                folder.entries.remove(&path);
+                folder.unknown.insert(path);
+            }
+        }
+
+        let mut refs: HashMap<[u8; 32], usize> = HashMap::new();
+        for folder in index.folders.values() {
+            for entry in folder.entries.values() {
+                if let IndexEntry::File { hash, .. } = entry {
+                    *refs.entry(*hash).or_default() += 1;
+                }
+            }
+        }
+        for (key, blob) in &mut index.blobs {
+            let referenced =
+                blake3::Hash::from_hex(key).is_ok_and(|hash| refs.contains_key(hash.as_bytes()));
+            if referenced {
+                blob.unreferenced_since = None;
+            } else if blob.unreferenced_since.is_none() {
+                blob.unreferenced_since = Some(now);
+            }
+        }
+        let stored_bytes = index.blobs.values().map(|blob| blob.size).sum();
+
+        Ok(Self {
+            dir,
+            limits,
+            index,
+            refs,
+            stored_bytes,
+            transfers: HashMap::new(),
+            parts: 0,
+            next_transfer_id: SERVED_TRANSFER_IDS,
+            max_content_len: MAX_DECOMPRESSED_LEN,
+        })
+    }
+
+    /// Caps the bytes the content of an operation, or a chunk of a transfer, decompresses to
+    pub fn set_max_content_len(&mut self, len: usize) {
+        self.max_content_len = len;
+    }
+
+    #[must_use]
+    pub fn index(&self) -> &ContentIndex {
+        &self.index
+    }
+
+    /// Where [`ContentIndex::save`] keeps the index
+    #[must_use]
+    pub fn index_path(&self) -> PathBuf {
+        self.dir.join("index.json")
+    }
+
+    /// Bytes of content stored
+    #[must_use]
+    pub fn stored_bytes(&self) -> u64 {
+        self.stored_bytes
+    }
+
+    /// Whether the whole tree of a folder is known, for a backup to be sent it
+    #[must_use]
+    pub fn can_serve(&self, folder_id: &FolderId) -> bool {
+        self.index
+            .folders
+            .get(folder_id)
+            .is_some_and(|folder| folder.unknown.is_empty())
+    }
+
+    /// Records an operation forwarded to the backups of a folder, with the sequence it
+    /// was forwarded with. Folders are indexed from their first operation only, as the
+    /// store would not know what came before.
+    pub fn record(&mut self, folder_id: &FolderId, sequence: u64, operation: &FileOperation) {
+        if sequence == 1 {
+            self.index.folders.entry(folder_id.clone()).or_default();
+        }
+        if !self.index.folders.contains_key(folder_id) {
+            return;
+        }
+        if let Err(e) = self.apply(folder_id, operation) {
+            warn!(%folder_id, "failed to store content: {e:#}");
+            for path in operation.relative_paths() {
+                self.set_unknown(folder_id, path);
+            }
+        }
+    }
+
+    fn apply(&mut self, folder_id: &FolderId, operation: &FileOperation) -> Result<()> {
+        match operation {
+            FileOperation::CreateFile {
+                relative_path,
+                content,
+                hash,
+                compression,
+            } => {
+                let content = decompress(content, *compression, self.max_content_len)?;
+                if blake3::hash(&content).as_bytes() != hash {
+                    bail!("Content of {relative_path} does not match its hash");
+                }
+                self.put(hash, &content)?;
+                self.set_file(folder_id, relative_path, *hash, content.len() as u64);
+            }
+            FileOperation::CreateDir { relative_path } => {
+                self.set_entry(folder_id, relative_path, IndexEntry::Dir);
+            }
+            FileOperation::RemoveFile { relative_path }
+            | FileOperation::RemoveDir { relative_path } => {
+                self.remove_tree(folder_id, relative_path);
+            }
+            FileOperation::RenameFile {
+                from_relative,
+                to_relative,
+            } => self.rename(folder_id, from_relative, to_relative)?,
+            FileOperation::CreateSymlink {
+                relative_path,
+                target,
+            } => {
+                let entry = IndexEntry::Symlink {
+                    target: target.clone(),
+                };
+                self.set_entry(folder_id, relative_path, entry);
+            }
+            FileOperation::SetMetadata {
+                relative_path,
+                mode: new_mode,
+                readonly: new_readonly,
+                mtime: new_mtime,
+            } => {
+                let entry = self
+                    .index
+                    .folders
+                    .get_mut(folder_id)
+                    .and_then(|folder| folder.entries.get_mut(relative_path));
+                if let Some(IndexEntry::File {
+                    mode,
+                    readonly,
+                    mtime,
+                    ..
+                }) = entry
+                {
+                    *mode = new_mode.or(*mode);
+                    *readonly = new_readonly.or(*readonly);
+                    *mtime = new_mtime.or(*mtime);
+                }
+            }
+            FileOperation::StartTransfer {
+                transfer_id,
+                relative_path,
+                kind: TransferKind::Content,
+                total_size,
+                chunk_size,
+            } => {
+                if *total_size > self.limits.max_bytes {
+                    bail!("Content of {total_size} bytes is over the size of the store");
+                }
+                if *chunk_size == 0 || *chunk_size > MAX_CHUNK_SIZE {
+                    bail!("Chunks of {chunk_size} bytes are not accepted");
+                }
+                self.parts += 1;
+                let file = self.dir.join("transfers").join(self.parts.to_string());
+                fs::File::create(&file).with_context(|| format!("Failed to create {file:?}"))?;
+                let transfer = StoredTransfer {
+                    relative_path: relative_path.clone(),
+                    total_size: *total_size,
+                    chunk_size: *chunk_size,
+                    file,
+                    received: HashSet::new(),
+                    end: None,
+                    started_at: unix_now(),
+                };
+                if let Some(replaced) = self
+                    .transfers
+                    .insert((folder_id.clone(), *transfer_id), transfer)
+                {
+                    let _ = fs::remove_file(replaced.file);
+                }
+            }
+            // The result depends on the copy of each backup
+            FileOperation::StartTransfer {
+                relative_path,
+                kind: TransferKind::Delta,
+                ..
+            } => self.set_unknown(folder_id, relative_path),
+            FileOperation::ApplyDelta {
+                relative_path,
+                hash,
+                ..
+            } => match self.index.blobs.get(&hex(hash)) {
+                // Patched into content stored already
+                Some(blob) => {
+                    let size = blob.size;
+                    self.set_file(folder_id, relative_path, *hash, size);
+                }
+                None => self.set_unknown(folder_id, relative_path),
+            },
+            FileOperation::FileChunk {
+                transfer_id,
+                chunk_index,
+                data,
+                compression,
+            } => {
+                let key = (folder_id.clone(), *transfer_id);
+                let Some(transfer) = self.transfers.get_mut(&key) else {
+                    return Ok(());
+                };
+                let written = write_chunk(
+                    transfer,
+                    *chunk_index,
+                    data,
+                    *compression,
+                    self.max_content_len,
+                );
+                self.continue_transfer(key, written);
+            }
+            FileOperation::EndTransfer {
+                transfer_id,
+                chunk_count,
+                expected_hash,
+            } => {
+                let key = (folder_id.clone(), *transfer_id);
+                if let Some(transfer) = self.transfers.get_mut(&key) {
+                    transfer.end = Some((*chunk_count, expected_hash.clone()));
+                    self.continue_transfer(key, Ok(()));
+                }
+            }
+            FileOperation::AbortTransfer { transfer_id, .. } => {
+                if let Some(transfer) = self.transfers.remove(&(folder_id.clone(), *transfer_id)) {
+                    let _ = fs::remove_file(transfer.file);
+                }
+            }
+            FileOperation::RequestSignature { .. } | FileOperation::SignatureResponse { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Stores a transfer once it has all of its chunks and its end. A failed step drops
+    /// the transfer, its file then being unknown.
+    fn continue_transfer(&mut self, key: (FolderId, u64), step: Result<()>) {
+        let complete = self.transfers.get(&key).is_some_and(|transfer| {
+            transfer
+                .end
+                .as_ref()
+                .is_some_and(|(chunk_count, _)| transfer.received.len() as u64 == *chunk_count)
+        });
+        if step.is_ok() && !complete {
+            return;
+        }
+        let Some(transfer) = self.transfers.remove(&key) else {
+            return;
+        };
+        let (folder_id, transfer_id) = key;
+        let stored = step.and_then(|()| self.put_transfer(&folder_id, &transfer));
+        let _ = fs::remove_file(&transfer.file);
+        if let Err(e) = stored {
+            warn!(%folder_id, transfer_id, "failed to store transfer: {e:#}");
+            self.set_unknown(&folder_id, &transfer.relative_path);
+        }
+    }
+
+    fn put_transfer(&mut self, folder_id: &FolderId, transfer: &StoredTransfer) -> Result<()> {
+        let (_, expected_hash) = transfer.end.as_ref().context("Transfer has no end")?;
+        let mut file = fs::File::open(&transfer.file)
+            .with_context(|| format!("Failed to open {:?}", transfer.file))?;
+        let mut hasher = blake3::Hasher::new();
+        let size = std::io::copy(&mut file, &mut hasher)?;
+        let hash = *hasher.finalize().as_bytes();
+        if hex(&hash) != *expected_hash || size != transfer.total_size {
+            bail!(
+                "Transfer of {} does not match its hash or size",
+                transfer.relative_path
+            );
+        }
+        let key = hex(&hash);
+        if !self.index.blobs.contains_key(&key) {
+            self.make_room(size)?;
+            fs::rename(&transfer.file, self.blob_path(&hash))
+                .with_context(|| format!("Failed to store {:?}", transfer.file))?;
+            self.add_blob(key, size);
+        }
+        self.set_file(folder_id, &transfer.relative_path, hash, size);
+        Ok(())
+    }
+
+    /// Writes a blob, unless it is stored already
+    fn put(&mut self, hash: &[u8; 32], content: &[u8]) -> Result<()> {
+        let key = hex(hash);
+        if self.index.blobs.contains_key(&key) {
+            return Ok(());
+        }
+        let size = content.len() as u64;
+        self.make_room(size)?;
+        let path = self.blob_path(hash);
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, content).with_context(|| format!("Failed to write {temp_path:?}"))?;
+        fs::rename(&temp_path, &path).with_context(|| format!("Failed to write {path:?}"))?;
+        self.add_blob(key, size);
+        Ok(())
+    }
+
+    /// Counts a blob just written, unreferenced until a path refers to it
+    fn add_blob(&mut self, key: String, size: u64) {
+        self.index.blobs.insert(
+            key,
+            BlobInfo {
+                size,
+                unreferenced_since: Some(unix_now()),
+            },
+        );
+        self.stored_bytes += size;
+    }
+
+    /// Drops blobs no folder refers to, the oldest first, until `size` more bytes fit
+    fn make_room(&mut self, size: u64) -> Result<()> {
+        while self.stored_bytes + size > self.limits.max_bytes {
+            let oldest = self
+                .index
+                .blobs
+                .iter()
+                .filter_map(|(key, blob)| Some((blob.unreferenced_since?, key.clone())))
+                .min();
+            let Some((_, key)) = oldest else {
+                bail!(
+                    "Content store is full: {} bytes stored, {size} more would be over {}",
+                    self.stored_bytes,
+                    self.limits.max_bytes
+                );
+            };
+            self.remove_blob(&key);
+        }
+        Ok(())
+    }
+
+    fn remove_blob(&mut self, key: &str) {
+        if let Some(blob) = self.index.blobs.remove(key) {
+            self.stored_bytes -= blob.size;
+            let _ = fs::remove_file(self.dir.join("blobs").join(key));
+        }
+    }
+
+    fn set_file(&mut self, folder_id: &FolderId, path: &RelativePath, hash: [u8; 32], size: u64) {
+        let entry = IndexEntry::File {
+            hash,
+            size,
+            mode: None,
+            readonly: None,
+            mtime: None,
+        };
+        self.set_entry(folder_id, path, entry);
+    }
+
+    /// Replaces what the folder has at `path`; a directory replaced by anything else
+    /// takes its children with it
+    fn set_entry(&mut self, folder_id: &FolderId, path: &RelativePath, entry: IndexEntry) {
+        if !self.index.folders.contains_key(folder_id) {
+            return;
+        }
+        if entry != IndexEntry::Dir {
+            self.remove_tree(folder_id, path);
+        }
+        if let IndexEntry::File { hash, .. } = &entry {
+            self.reference(hash);
+        }
+        let Some(folder) = self.index.folders.get_mut(folder_id) else {
+            return;
+        };
+        folder.unknown.remove(path);
+        if let Some(IndexEntry::File { hash, .. }) = folder.entries.insert(path.clone(), entry) {
+            self.unreference(&hash);
+        }
+    }
+
+    fn set_unknown(&mut self, folder_id: &FolderId, path: &RelativePath) {
+        self.remove_tree(folder_id, path);
+        if let Some(folder) = self.index.folders.get_mut(folder_id) {
+            folder.unknown.insert(path.clone());
+        }
+    }
+
+    /// Removes `path` and everything under it
+    fn remove_tree(&mut self, folder_id: &FolderId, path: &RelativePath) {
+        let Some(folder) = self.index.folders.get_mut(folder_id) else {
+            return;
+        };
+        let removed: Vec<RelativePath> = folder
+            .entries
+            .keys()
+            .filter(|entry| entry.starts_with(path.as_path()))
+            .cloned()
+            .collect();
+        folder
+            .unknown
+            .retain(|unknown| !unknown.starts_with(path.as_path()));
+        let hashes: Vec<[u8; 32]> = removed
+            .iter()
+            .filter_map(|entry| match folder.entries.remove(entry) {
+                Some(IndexEntry::File { hash, .. }) => Some(hash),
+                _ => None,
+            })
+            .collect();
+        for hash in hashes {
+            self.unreference(&hash);
+        }
+    }
+
+    /// Moves `from` and everything under it to `to`, replacing what was there. Renaming a
+    /// path the store does not know makes `to` unknown.
+    fn rename(
+        &mut self,
+        folder_id: &FolderId,
+        from: &RelativePath,
+        to: &RelativePath,
+    ) -> Result<()> {
+        let Some(folder) = self.index.folders.get(folder_id) else {
+            return Ok(());
+        };
+        let moved = |paths: Vec<&RelativePath>| -> Result<Vec<RelativePath>> {
+            paths
+                .into_iter()
+                .map(|path| {
+                    let rest = path.strip_prefix(from.as_path())?;
+                    let path = if rest.as_os_str().is_empty() {
+                        to.clone()
+                    } else {
+                        RelativePath::new(to.join(rest))?
+                    };
+                    Ok(path)
+                })
+                .collect()
+        };
+        let entries: Vec<(RelativePath, IndexEntry)> = folder
+            .entries
+            .iter()
+            .filter(|(path, _)| path.starts_with(from.as_path()))
+            .map(|(path, entry)| (path.clone(), entry.clone()))
+            .collect();
+        let unknown: Vec<&RelativePath> = folder
+            .unknown
+            .iter()
+            .filter(|path| path.starts_with(from.as_path()))
+            .collect();
+        if entries.is_empty() && unknown.is_empty() {
+            self.set_unknown(folder_id, to);
+            return Ok(());
+        }
+        let unknown = moved(unknown)?;
+        let paths = moved(entries.iter().map(|(path, _)| path).collect())?;
+
+        // Referenced before being unreferenced, for blobs not to look unused meanwhile
+        for (_, entry) in &entries {
+            if let IndexEntry::File { hash, .. } = entry {
+                self.reference(hash);
+            }
+        }
+        self.remove_tree(folder_id, from);
+        self.remove_tree(folder_id, to);
+        if let Some(folder) = self.index.folders.get_mut(folder_id) {
+            folder.entries.extend(
+                paths
+                    .into_iter()
+                    .zip(entries.into_iter().map(|(_, entry)| entry)),
+            );
+            folder.unknown.extend(unknown);
+        }
+        Ok(())
+    }
+
+    fn reference(&mut self, hash: &[u8; 32]) {
+        *self.refs.entry(*hash).or_default() += 1;
+        if let Some(blob) = self.index.blobs.get_mut(&hex(hash)) {
+            blob.unreferenced_since = None;
+        }
+    }
+
+    fn unreference(&mut self, hash: &[u8; 32]) {
+        let Some(count) = self.refs.get_mut(hash) else {
+            return;
+        };
+        *count -= 1;
+        if *count == 0 {
+            self.refs.remove(hash);
+            if let Some(blob) = self.index.blobs.get_mut(&hex(hash)) {
+                blob.unreferenced_since = Some(unix_now());
+            }
+        }
+    }
+
+    /// Drops the index of a deleted folder; the blobs only it referred to are collected
+    /// once the TTL elapsed
+    pub fn forget_folder(&mut self, folder_id: &FolderId) {
+        let Some(folder) = self.index.folders.remove(folder_id) else {
+            return;
+        };
+        for entry in folder.entries.values() {
+            if let IndexEntry::File { hash, .. } = entry {
+                self.unreference(hash);
+            }
+        }
+        let transfers: Vec<(FolderId, u64)> = self
+            .transfers
+            .keys()
+            .filter(|(transfer_folder, _)| transfer_folder == folder_id)
+            .cloned()
+            .collect();
+        for key in transfers {
+            if let Some(transfer) = self.transfers.remove(&key) {
+                let _ = fs::remove_file(transfer.file);
+            }
+        }
+    }
+
+    /// Operations recreating the whole tree of a folder, parents before their children,
+    /// grouped to be sent one batch per path; `None` when the store cannot serve the
+    /// folder. Files larger than [`SERVED_CHUNK_SIZE`] are split into transfers. The
+    /// content is read whole, as it is sent right away.
+    pub fn full_sync(&mut self, folder_id: &FolderId) -> Result<Option<Vec<Vec<FileOperation>>>> {
+        if !self.can_serve(folder_id) {
+            return Ok(None);
+        }
+        let entries: Vec<(RelativePath, IndexEntry)> = self.index.folders[folder_id]
+            .entries
+            .iter()
+            .map(|(path, entry)| (path.clone(), entry.clone()))
+            .collect();
+        let mut groups = Vec::with_capacity(entries.len());
+        for (relative_path, entry) in entries {
+            let operations = match entry {
+                IndexEntry::Dir => vec![FileOperation::CreateDir { relative_path }],
+                IndexEntry::Symlink { target } => vec![FileOperation::CreateSymlink {
+                    relative_path,
+                    target,
+                }],
+                IndexEntry::File {
+                    hash,
+                    mode,
+                    readonly,
+                    mtime,
+                    ..
+                } => {
+                    let path = self.blob_path(&hash);
+                    let content =
+                        fs::read(&path).with_context(|| format!("Failed to read {path:?}"))?;
+                    let mut operations = if content.len() > SERVED_CHUNK_SIZE {
+                        let transfer_id = self.next_transfer_id;
+                        self.next_transfer_id += 1;
+                        split(transfer_id, relative_path.clone(), &content)
+                    } else {
+                        vec![FileOperation::CreateFile {
+                            relative_path: relative_path.clone(),
+                            content,
+                            hash,
+                            compression: None,
+                        }]
+                    };
+                    if mode.is_some() || readonly.is_some() || mtime.is_some() {
+                        operations.push(FileOperation::SetMetadata {
+                            relative_path,
+                            mode,
+                            readonly,
+                            mtime,
+                        });
+                    }
+                    operations
+                }
+            };
+            groups.push(operations);
+        }
+        Ok(Some(groups))
+    }
+
+    /// The blob with this hash, when a file of the folder has it as content
+    #[must_use]
+    pub fn blob_of(&self, folder_id: &FolderId, hash: &[u8; 32]) -> Option<PathBuf> {
+        let folder = self.index.folders.get(folder_id)?;
+        folder
+            .entries
+            .values()
+            .any(|entry| matches!(entry, IndexEntry::File { hash: file, .. } if file == hash))
+            .then(|| self.blob_path(hash))
+    }
+
+    /// Removes the blobs no folder referred to for the TTL, and the transfers started
+    /// longer than the TTL ago. Returns the number of blobs removed.
+    pub fn collect_garbage(&mut self, now: i64) -> usize {
+        let before = now.saturating_sub(self.limits.ttl.as_secs() as i64);
+        let expired: Vec<String> = self
+            .index
+            .blobs
+            .iter()
+            .filter(|(_, blob)| blob.unreferenced_since.is_some_and(|since| since <= before))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            self.remove_blob(key);
+        }
+        let stalled: Vec<(FolderId, u64)> = self
+            .transfers
+            .iter()
+            .filter(|(_, transfer)| transfer.started_at <= before)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stalled {
+            if let Some(transfer) = self.transfers.remove(&key) {
+                debug!(folder_id = %key.0, transfer_id = key.1, "dropping stalled transfer");
+                let _ = fs::remove_file(transfer.file);
+            }
+        }
+        expired.len()
+    }
+
+    fn blob_path(&self, hash: &[u8; 32]) -> PathBuf {
+        self.dir.join("blobs").join(hex(hash))
+    }
+}
+
+/// A [`ContentStore`] shared by the connections of the server
+#[derive(Debug, Clone)]
+pub struct SharedContent(Arc<Mutex<ContentStore>>);
+
+impl SharedContent {
+    #[must_use]
+    pub fn new(store: ContentStore) -> Self {
+        Self(Arc::new(Mutex::new(store)))
+    }
+
+    /// The store, for as long as the guard is held; never across an await, nor while
+    /// waiting for the state
+    pub fn lock(&self) -> MutexGuard<'_, ContentStore> {
+        // A store left half updated by a panic only misses some content, which it
+        // then does not serve
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Writes a chunk where it goes in the file of its transfer
+fn write_chunk(
+    transfer: &mut StoredTransfer,
+    chunk_index: u64,
+    data: &[u8],
+    compression: Option<Compression>,
+    max_content_len: usize,
+) -> Result<()> {
+    let max_len = usize::try_from(transfer.chunk_size)
+        .unwrap_or(usize::MAX)
+        .min(max_content_len);
+    let data = decompress(data, compression, max_len)?;
+    let offset = chunk_index.saturating_mul(transfer.chunk_size);
+    if offset.saturating_add(data.len() as u64) > transfer.total_size {
+        bail!("Chunk {chunk_index} goes past the end of its transfer");
+    }
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .open(&transfer.file)
+        .with_context(|| format!("Failed to open {:?}", transfer.file))?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&data)?;
+    transfer.received.insert(chunk_index);
+    Ok(())
+}
+
+/// Operations writing `content` in chunks of [`SERVED_CHUNK_SIZE`]: a start, the chunks
+/// in order, then an end carrying the hash of the content
+fn split(transfer_id: u64, relative_path: RelativePath, content: &[u8]) -> Vec<FileOperation> {
+    let chunks: Vec<&[u8]> = content.chunks(SERVED_CHUNK_SIZE).collect();
+    let chunk_count = chunks.len() as u64;
+    let mut operations = Vec::with_capacity(chunks.len() + 2);
+    operations.push(FileOperation::StartTransfer {
+        transfer_id,
+        relative_path,
+        kind: TransferKind::Content,
+        total_size: content.len() as u64,
+        chunk_size: SERVED_CHUNK_SIZE as u64,
+    });
+    operations.extend(chunks.into_iter().enumerate().map(|(index, data)| {
+        FileOperation::FileChunk {
+            transfer_id,
+            chunk_index: index as u64,
+            data: data.to_vec(),
+            compression: None,
+        }
+    }));
+    operations.push(FileOperation::EndTransfer {
+        transfer_id,
+        chunk_count,
+        expected_hash: blake3::hash(content).to_hex().to_string(),
+    });
+    operations
+}
+
+/// Content as it was before compression, refusing content that decompresses to more
+/// than `max_len` bytes
+fn decompress(
+    data: &[u8],
+    compression: Option<Compression>,
+    max_len: usize,
+) -> Result<std::borrow::Cow<'_, [u8]>> {
+    match compression {
+        None => Ok(std::borrow::Cow::Borrowed(data)),
+        Some(Compression::Zstd { .. }) => {
+            let mut decompressed = Vec::new();
+            zstd::stream::read::Decoder::new(data)
+                .and_then(|decoder| {
+                    decoder
+                        .take(max_len as u64 + 1)
+                        .read_to_end(&mut decompressed)
+                })
+                .context("Failed to decompress zstd content")?;
+            if decompressed.len() > max_len {
+                bail!("Decompressed content is larger than {max_len} bytes");
+            }
+            Ok(std::borrow::Cow::Owned(decompressed))
+        }
+    }
+}
+
+fn hex(hash: &[u8; 32]) -> String {
+    blake3::Hash::from_bytes(*hash).to_hex().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relative(path: &str) -> RelativePath {
+        RelativePath::try_from(path).unwrap()
+    }
+
+    fn create_file(path: &str, content: &[u8]) -> FileOperation {
+        FileOperation::CreateFile {
+            relative_path: relative(path),
+            content: content.to_vec(),
+            hash: *blake3::hash(content).as_bytes(),
+            compression: None,
+        }
+    }
+
+    fn open(dir: &tempfile::TempDir) -> ContentStore {
+        ContentStore::open(dir.path().join("content"), ContentLimits::default()).unwrap()
+    }
+
+    #[test]
+    fn test_full_sync_recreates_the_recorded_tree() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut store = open(&dir);
+        let folder_id = FolderId::from("folder1");
+        let large = vec![7; SERVED_CHUNK_SIZE + 1];
+
+        store.record(
+            &folder_id,
+            1,
+            &FileOperation::CreateDir {
+                relative_path: relative("dir"),
+            },
+        );
+        store.record(&folder_id, 2, &create_file("dir/a.txt", b"a"));
+        store.record(&folder_id, 3, &create_file("dir/large.bin", &large));
+        store.record(
+            &folder_id,
+            4,
+            &FileOperation::RenameFile {
+                from_relative: relative("dir"),
+                to_relative: relative("moved"),
+            },
+        );
+
+        let groups = store.full_sync(&folder_id).unwrap().unwrap();
+        let paths: Vec<_> = groups
+            .iter()
+            .map(|operations| operations[0].relative_paths()[0].to_string())
+            .collect();
+        assert_eq!(paths, ["moved", "moved/a.txt", "moved/large.bin"]);
+        assert!(matches!(
+            &groups[1][..],
+            [FileOperation::CreateFile { content, .. }] if content == b"a"
+        ));
+        // A start, two chunks and an end
+        assert_eq!(groups[2].len(), 4);
+        assert_eq!(store.stored_bytes(), 1 + large.len() as u64);
+
+        let hash = blake3::hash(b"a");
+        assert!(store.blob_of(&folder_id, hash.as_bytes()).is_some());
+        assert!(store.blob_of(&"folder2".into(), hash.as_bytes()).is_none());
+    }
+
+    #[test]
+    fn test_folders_seen_from_their_middle_are_not_served() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut store = open(&dir);
+        let folder_id = FolderId::from("folder1");
+
+        store.record(&folder_id, 2, &create_file("a.txt", b"a"));
+        assert!(store.full_sync(&folder_id).unwrap().is_none());
+        assert_eq!(store.stored_bytes(), 0);
+
+        store.record(&folder_id, 1, &create_file("a.txt", b"a"));
+        store.record(
+            &folder_id,
+            2,
+            &FileOperation::StartTransfer {
+                transfer_id: 1,
+                relative_path: relative("b.txt"),
+                kind: TransferKind::Delta,
+                total_size: 10,
+                chunk_size: 10,
+            },
+        );
+        assert!(!store.can_serve(&folder_id));
+        store.record(&folder_id, 3, &create_file("b.txt", b"b"));
+        assert!(store.can_serve(&folder_id));
+    }
+
+    #[test]
+    fn test_unreferenced_blobs_are_collected_after_the_ttl() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut store = open(&dir);
+        let folder_id = FolderId::from("folder1");
+        store.record(&folder_id, 1, &create_file("a.txt", b"a"));
+        store.record(&folder_id, 2, &create_file("b.txt", b"b"));
+        store.record(
+            &folder_id,
+            3,
+            &FileOperation::RemoveFile {
+                relative_path: relative("a.txt"),
+            },
+        );
+
+        let ttl = ContentLimits::default().ttl.as_secs() as i64;
+        assert_eq!(store.collect_garbage(unix_now()), 0);
+        assert_eq!(store.collect_garbage(unix_now() + ttl), 1);
+        assert_eq!(store.stored_bytes(), 1);
+        assert!(!store.blob_path(blake3::hash(b"a").as_bytes()).exists());
+
+        store.forget_folder(&folder_id);
+        assert_eq!(store.collect_garbage(unix_now() + ttl), 1);
+        assert_eq!(store.stored_bytes(), 0);
+    }
+
+    #[test]
+    fn test_reopened_store_keeps_its_index() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut store = open(&dir);
+        let folder_id = FolderId::from("folder1");
+        store.record(&folder_id, 1, &create_file("a.txt", b"a"));
+        store.record(&folder_id, 2, &create_file("b.txt", b"b"));
+        store.index().save(&store.index_path()).unwrap();
+        fs::remove_file(store.blob_path(blake3::hash(b"b").as_bytes())).unwrap();
+
+        let mut reopened = open(&dir);
+        assert_eq!(reopened.stored_bytes(), 1);
+        assert_eq!(
+            reopened.index().folders[&folder_id].unknown,
+            BTreeSet::from([relative("b.txt")])
+        );
+        assert!(reopened.full_sync(&folder_id).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_full_store_refuses_referenced_content() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let limits = ContentLimits {
+            max_bytes: 2,
+            ..ContentLimits::default()
+        };
+        let mut store = ContentStore::open(dir.path().join("content"), limits).unwrap();
+        let folder_id = FolderId::from("folder1");
+        store.record(&folder_id, 1, &create_file("a.txt", b"aa"));
+        store.record(&folder_id, 2, &create_file("b.txt", b"b"));
+
+        assert_eq!(store.stored_bytes(), 2);
+        assert!(!store.can_serve(&folder_id));
+    }
+
+    #[test]
+    fn test_content_decompressing_past_the_limits_is_refused() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut store = open(&dir);
+        store.set_max_content_len(4);
+        let folder_id = FolderId::from("folder1");
+        let compression = Some(Compression::Zstd { level: 3 });
+        let compressed = zstd::encode_all(&[7; 8][..], 3).unwrap();
+
+        store.record(
+            &folder_id,
+            1,
+            &FileOperation::CreateFile {
+                relative_path: relative("a.bin"),
+                content: compressed.clone(),
+                hash: *blake3::hash(&[7; 8]).as_bytes(),
+                compression,
+            },
+        );
+        store.record(
+            &folder_id,
+            2,
+            &FileOperation::StartTransfer {
+                transfer_id: 1,
+                relative_path: relative("b.bin"),
+                kind: TransferKind::Content,
+                total_size: 8,
+                chunk_size: MAX_CHUNK_SIZE + 1,
+            },
+        );
+        store.record(
+            &folder_id,
+            3,
+            &FileOperation::StartTransfer {
+                transfer_id: 2,
+                relative_path: relative("c.bin"),
+                kind: TransferKind::Content,
+                total_size: 8,
+                chunk_size: 8,
+            },
+        );
+        store.record(
+            &folder_id,
+            4,
+            &FileOperation::FileChunk {
+                transfer_id: 2,
+                chunk_index: 0,
+                data: compressed,
+                compression,
+            },
+        );
+
+        assert!(store.transfers.is_empty());
+        assert_eq!(store.stored_bytes(), 0);
+        assert_eq!(
+            store.index().folders[&folder_id].unknown,
+            BTreeSet::from([relative("a.bin"), relative("b.bin"), relative("c.bin")])
+        );
+    }
+}
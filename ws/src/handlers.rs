@@ -1,13 +1,26 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 
 use anyhow::Result;
-use backup_sync_protocol::{ClientMessage, Computer, ServerMessage, SyncFolder};
+use backup_sync_protocol::{
+    ClientMessage, Computer, ComputerId, Encoding, FileOperation, FolderId, MAX_CHUNK_SIZE,
+    MIN_SUPPORTED_VERSION, Manifest, PROTOCOL_VERSION, RelativePath, ServerMessage, SyncFolder,
+    SyncFolderSummary, UserId,
+};
 use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
 
-use crate::state::{BroadcastMessage, ServerState, uuid_simple};
+use crate::broadcast::Broadcasts;
+use crate::journal::JournalEntry;
+use crate::paths;
+use crate::state::{
+    Acked, Audience, BroadcastMessage, DirectoryChange, RemoveComputerError, ServerState,
+};
 
-pub type BroadcastTx = tokio::sync::broadcast::Sender<BroadcastMessage>;
+/// Seconds a backup waits before asking again for a full sync the origin could not serve
+pub const FULL_SYNC_RETRY_SECS: u64 = 30;
 
 pub enum HandlerResponse {
     Send(ServerMessage),
@@ -15,6 +28,19 @@ pub enum HandlerResponse {
         response: ServerMessage,
         broadcast: BroadcastMessage,
     },
+    /// Send the message, then close the connection
+    Close(ServerMessage),
+    /// Send the messages in order, then the response
+    Replay {
+        messages: Vec<ServerMessage>,
+        response: ServerMessage,
+    },
+    /// Send the response, then the messages in order, then broadcast
+    Resume {
+        response: ServerMessage,
+        messages: Vec<ServerMessage>,
+        broadcast: BroadcastMessage,
+    },
     None,
 }
 
@@ -22,18 +48,110 @@ pub async fn handle_message(
     msg: ClientMessage,
     addr: SocketAddr,
     state: &Arc<RwLock<ServerState>>,
-    broadcast_tx: &BroadcastTx,
+    broadcast_tx: &Broadcasts,
 ) -> Result<HandlerResponse> {
+    let payload = match &msg {
+        ClientMessage::FolderOperation { operation, .. }
+        | ClientMessage::TargetedOperation { operation, .. } => operation.payload_len(),
+        ClientMessage::FolderOperationBatch { operations, .. } => {
+            operations.iter().map(FileOperation::payload_len).sum()
+        }
+        _ => 0,
+    };
+    if let Some(limit) = state.read().await.max_file_content_bytes
+        && payload > limit
+    {
+        warn!(payload, limit, "refusing too large file content");
+        return Ok(HandlerResponse::Send(ServerMessage::PayloadTooLarge {
+            size: payload as u64,
+            limit: limit as u64,
+            reason: format!(
+                "File content of {payload} bytes is over the limit of {limit} bytes; send it with StartTransfer and FileChunk instead"
+            ),
+        }));
+    }
+
+    let chunk_size = match &msg {
+        ClientMessage::FolderOperation { operation, .. }
+        | ClientMessage::TargetedOperation { operation, .. } => operation.chunk_size(),
+        ClientMessage::FolderOperationBatch { operations, .. } => operations
+            .iter()
+            .map(FileOperation::chunk_size)
+            .max()
+            .unwrap_or(0),
+        _ => 0,
+    };
+    if chunk_size > MAX_CHUNK_SIZE {
+        warn!(chunk_size, "refusing too large transfer chunks");
+        return Ok(HandlerResponse::Send(ServerMessage::PayloadTooLarge {
+            size: chunk_size,
+            limit: MAX_CHUNK_SIZE,
+            reason: format!(
+                "Chunks of {chunk_size} bytes are over the limit of {MAX_CHUNK_SIZE} bytes"
+            ),
+        }));
+    }
+
+    let unsafe_path = match &msg {
+        ClientMessage::FolderOperation { operation, .. }
+        | ClientMessage::TargetedOperation { operation, .. } => {
+            paths::check_operation(operation).err()
+        }
+        ClientMessage::FolderOperationBatch { operations, .. } => operations
+            .iter()
+            .find_map(|operation| paths::check_operation(operation).err()),
+        _ => None,
+    };
+    if let Some(reason) = unsafe_path {
+        return Ok(HandlerResponse::Send(ServerMessage::PathRejected {
+            reason,
+        }));
+    }
+
+    // Every one of them is broadcast, so a flood would make the receivers lag
+    if matches!(
+        msg,
+        ClientMessage::FolderOperation { .. }
+            | ClientMessage::FolderOperationBatch { .. }
+            | ClientMessage::TargetedOperation { .. }
+    ) && let Some(wait) = state.read().await.throttle(&addr)
+    {
+        debug!(retry_after = ?wait, "throttling folder operation");
+        return Ok(HandlerResponse::Send(ServerMessage::RateLimited {
+            retry_after_ms: u64::try_from(wait.as_millis()).unwrap_or(u64::MAX),
+        }));
+    }
+
     match msg {
+        ClientMessage::Hello { encoding } => handle_hello(addr, state, encoding).await,
+
         ClientMessage::Authenticate {
             user_id,
             computer_id,
-        } => handle_authenticate(addr, state, user_id, computer_id).await,
+            protocol_version,
+            token,
+            last_applied,
+        } => {
+            handle_authenticate(
+                addr,
+                state,
+                user_id,
+                computer_id,
+                protocol_version,
+                &token,
+                last_applied.unwrap_or_default(),
+            )
+            .await
+        }
 
         ClientMessage::RegisterComputer { name } => {
             handle_register_computer(addr, state, name).await
         }
 
+        ClientMessage::RemoveComputer { computer_id } => {
+            handle_remove_computer(addr, state, broadcast_tx, computer_id).await
+        }
+
         ClientMessage::CreateSyncFolder { name } => {
             handle_create_sync_folder(addr, state, name).await
         }
@@ -46,6 +164,15 @@ pub async fn handle_message(
             handle_leave_sync_folder(addr, state, folder_id).await
         }
 
+        ClientMessage::DeleteSyncFolder { folder_id } => {
+            handle_delete_sync_folder(addr, state, folder_id).await
+        }
+
+        ClientMessage::RenameSyncFolder {
+            folder_id,
+            new_name,
+        } => handle_rename_sync_folder(addr, state, folder_id, new_name).await,
+
         ClientMessage::RequestOriginSwitch { folder_id } => {
             handle_request_origin_switch(addr, state, folder_id).await
         }
@@ -55,43 +182,178 @@ pub async fn handle_message(
             operation,
         } => handle_folder_operation(addr, state, broadcast_tx, folder_id, operation).await,
 
+        ClientMessage::FolderOperationBatch {
+            folder_id,
+            operations,
+        } => handle_folder_operation_batch(addr, state, broadcast_tx, folder_id, operations).await,
+
+        ClientMessage::TargetedOperation {
+            folder_id,
+            computer_id,
+            operation,
+        } => {
+            handle_targeted_operation(addr, state, broadcast_tx, folder_id, computer_id, operation)
+                .await
+        }
+
+        ClientMessage::RequestSignature {
+            folder_id,
+            relative_path,
+        } => handle_request_signature(addr, state, broadcast_tx, folder_id, relative_path).await,
+
+        ClientMessage::SignatureResponse {
+            folder_id,
+            relative_path,
+            signature,
+        } => {
+            handle_signature_response(
+                addr,
+                state,
+                broadcast_tx,
+                folder_id,
+                relative_path,
+                signature,
+            )
+            .await
+        }
+
+        ClientMessage::RequestManifest { folder_id } => {
+            handle_request_manifest(addr, state, broadcast_tx, folder_id).await
+        }
+
+        ClientMessage::Manifest {
+            folder_id,
+            manifest,
+        } => handle_manifest(addr, state, broadcast_tx, folder_id, manifest).await,
+
         ClientMessage::Ack { operation_id } => {
-            println!("Client {addr} acknowledged operation {operation_id}");
-            Ok(HandlerResponse::None)
+            handle_ack(addr, state, broadcast_tx, operation_id).await
+        }
+
+        ClientMessage::Nack {
+            operation_id,
+            reason,
+        } => handle_nack(addr, state, broadcast_tx, operation_id, reason).await,
+
+        ClientMessage::GetFolderSequence { folder_id } => {
+            handle_get_folder_sequence(addr, state, folder_id).await
         }
 
+        ClientMessage::CatchUp {
+            folder_id,
+            last_sequence,
+        } => handle_catch_up(addr, state, folder_id, last_sequence).await,
+
         ClientMessage::RequestFullSync { folder_id } => {
-            println!("Client {addr} requested full sync for folder {folder_id}");
-            Ok(HandlerResponse::None)
+            handle_request_full_sync(addr, state, broadcast_tx, folder_id).await
         }
 
+        ClientMessage::FetchContent { folder_id, hash } => {
+            handle_fetch_content(addr, state, folder_id, hash).await
+        }
+
+        ClientMessage::Ping { nonce } => Ok(HandlerResponse::Send(ServerMessage::Pong { nonce })),
+
         ClientMessage::GetUserState => handle_get_user_state(addr, state).await,
+
+        ClientMessage::ListFolders => handle_list_folders(addr, state).await,
+
+        // Unwrapped by the connection before getting here
+        ClientMessage::Request { .. } => Ok(HandlerResponse::Send(ServerMessage::Error {
+            message: "Requests cannot be nested".to_string(),
+            trace_id: None,
+        })),
     }
 }
 
+/// Switches the connection to `encoding`; the answer is the first message sent with it.
+async fn handle_hello(
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+    encoding: Encoding,
+) -> Result<HandlerResponse> {
+    state.write().await.set_encoding(&addr, encoding);
+    debug!(?encoding, "switched encoding");
+    Ok(HandlerResponse::Send(ServerMessage::EncodingSelected {
+        encoding,
+    }))
+}
+
 async fn handle_authenticate(
     addr: SocketAddr,
     state: &Arc<RwLock<ServerState>>,
-    user_id: String,
-    computer_id: String,
+    user_id: UserId,
+    computer_id: ComputerId,
+    protocol_version: u32,
+    token: &str,
+    last_applied: BTreeMap<FolderId, u64>,
 ) -> Result<HandlerResponse> {
+    if protocol_version < MIN_SUPPORTED_VERSION {
+        warn!(protocol_version, "rejecting outdated client");
+        return Ok(HandlerResponse::Close(ServerMessage::Error {
+            message: format!(
+                "Protocol version {protocol_version} is not supported: the server speaks version {PROTOCOL_VERSION} and requires at least {MIN_SUPPORTED_VERSION}"
+            ),
+            trace_id: None,
+        }));
+    }
+
     let mut state_write = state.write().await;
 
+    if let Some(validator) = &state_write.token_validator
+        && let Err(reason) = validator.validate(token, &user_id)
+    {
+        warn!(%user_id, %computer_id, reason, "rejecting token");
+        return Ok(HandlerResponse::Send(ServerMessage::NotAuthenticated {
+            reason: reason.to_string(),
+        }));
+    }
+
     // Ensure user exists
     state_write.get_or_create_user(&user_id);
 
     // Try to authenticate
     match state_write.authenticate_connection(&addr, user_id.clone(), computer_id.clone()) {
-        Ok(()) => {
+        Ok(superseded) => {
             let user = state_write.get_user(&user_id).cloned();
+            let encoding = state_write.encoding(&addr);
+            // Only the folders this computer backs up, which others would not replay to
+            let mut messages = Vec::new();
+            for (folder_id, last_sequence) in last_applied {
+                if state_write.is_backup(&user_id, &folder_id, &computer_id) {
+                    let (replayed, sequence) =
+                        replay_since(&mut state_write, addr, &folder_id, last_sequence);
+                    messages.extend(replayed);
+                    messages.push(ServerMessage::FolderSequence {
+                        folder_id,
+                        sequence,
+                    });
+                }
+            }
             drop(state_write);
 
+            if let Some(previous) = superseded {
+                // Closed by its task on the next broadcast, the status change below
+                info!(%user_id, %computer_id, %previous, "superseding previous connection");
+            }
             if let Some(user) = user {
-                println!("User {user_id} authenticated on computer {computer_id} from {addr}");
-                Ok(HandlerResponse::Send(ServerMessage::Authenticated { user }))
+                info!(%user_id, %computer_id, protocol_version, ?encoding, "authenticated");
+                Ok(HandlerResponse::Resume {
+                    response: ServerMessage::Authenticated { user },
+                    messages,
+                    broadcast: BroadcastMessage {
+                        user_id,
+                        message: ServerMessage::ComputerStatusChanged {
+                            computer_id,
+                            online: true,
+                        },
+                        audience: Audience::User { except: Some(addr) },
+                    },
+                })
             } else {
                 Ok(HandlerResponse::Send(ServerMessage::Error {
                     message: "User not found after authentication".to_string(),
+                    trace_id: None,
                 }))
             }
         }
@@ -99,6 +361,7 @@ async fn handle_authenticate(
             drop(state_write);
             Ok(HandlerResponse::Send(ServerMessage::Error {
                 message: format!("Computer {computer_id} not registered for user {user_id}: {e}"),
+                trace_id: None,
             }))
         }
     }
@@ -115,21 +378,22 @@ async fn handle_register_computer(
         .and_then(|c| c.user_id.clone());
 
     if let Some(user_id) = user_id {
-        let computer_id = format!(
-            "{}_{}",
-            name.to_lowercase().replace(' ', "_"),
-            uuid_simple()
-        );
+        let computer_id = ComputerId::new_v4();
         let computer = Computer {
             id: computer_id.clone(),
             name,
             online: false,
+            last_seen: None,
         };
 
         state_write.register_computer(&user_id, computer.clone());
+        state_write.record_change(DirectoryChange::ComputerRegistered {
+            user_id: user_id.clone(),
+            computer: computer.clone(),
+        });
         drop(state_write);
 
-        println!("Registered computer {computer_id} for user {user_id}");
+        info!(%user_id, %computer_id, "registered computer");
         Ok(HandlerResponse::Send(ServerMessage::ComputerRegistered {
             computer,
         }))
@@ -137,6 +401,88 @@ async fn handle_register_computer(
         drop(state_write);
         Ok(HandlerResponse::Send(ServerMessage::Error {
             message: "Not authenticated".to_string(),
+            trace_id: None,
+        }))
+    }
+}
+
+/// Removes a computer of the user. The origins of the operations only it had left to ack
+/// get `OperationComplete`, the user the status of their folders.
+async fn handle_remove_computer(
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+    broadcast_tx: &Broadcasts,
+    computer_id: ComputerId,
+) -> Result<HandlerResponse> {
+    let mut state_write = state.write().await;
+    let conn_info = state_write
+        .get_connection(&addr)
+        .map(|c| (c.user_id.clone(), c.computer_id.clone()));
+
+    if let Some((Some(user_id), Some(_))) = conn_info {
+        let result = state_write.remove_computer(&user_id, &computer_id);
+        let mut statuses: Vec<ServerMessage> = Vec::new();
+        if let Ok(completed) = &result {
+            state_write.record_change(DirectoryChange::ComputerRemoved {
+                user_id: user_id.clone(),
+                computer_id: computer_id.clone(),
+            });
+            let mut folder_ids: Vec<&FolderId> = completed.iter().map(|(id, ..)| id).collect();
+            folder_ids.dedup();
+            statuses = folder_ids
+                .into_iter()
+                .filter_map(|folder_id| state_write.sync_status(&user_id, folder_id))
+                .collect();
+        }
+        drop(state_write);
+        match result {
+            Ok(completed) => {
+                info!(%user_id, %computer_id, "removed computer");
+                for (folder_id, operation_id, origin) in completed {
+                    info!(%folder_id, operation_id, "operation complete without removed backup");
+                    broadcast_tx.send(BroadcastMessage {
+                        user_id: user_id.clone(),
+                        message: ServerMessage::OperationComplete { operation_id },
+                        audience: Audience::Computers {
+                            computer_ids: vec![origin],
+                        },
+                    });
+                }
+                for status in statuses {
+                    broadcast_tx.send(BroadcastMessage {
+                        user_id: user_id.clone(),
+                        message: status,
+                        audience: Audience::User { except: None },
+                    });
+                }
+                let removed = ServerMessage::ComputerRemoved { computer_id };
+                Ok(HandlerResponse::Broadcast {
+                    response: removed.clone(),
+                    broadcast: BroadcastMessage {
+                        user_id,
+                        message: removed,
+                        audience: Audience::User { except: Some(addr) },
+                    },
+                })
+            }
+            Err(RemoveComputerError::NotFound) => Ok(HandlerResponse::Send(ServerMessage::Error {
+                message: format!("Computer {computer_id} not found"),
+                trace_id: None,
+            })),
+            Err(RemoveComputerError::OriginOf(blocking_folders)) => Ok(HandlerResponse::Send(
+                ServerMessage::ComputerRemovalDenied {
+                    computer_id,
+                    reason: "Computer is the origin of folders; switch their origin first"
+                        .to_string(),
+                    blocking_folders,
+                },
+            )),
+        }
+    } else {
+        drop(state_write);
+        Ok(HandlerResponse::Send(ServerMessage::Error {
+            message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
         }))
     }
 }
@@ -152,11 +498,7 @@ async fn handle_create_sync_folder(
         .map(|c| (c.user_id.clone(), c.computer_id.clone()));
 
     if let Some((Some(user_id), Some(computer_id))) = conn_info {
-        let folder_id = format!(
-            "{}_{}",
-            name.to_lowercase().replace(' ', "_"),
-            uuid_simple()
-        );
+        let folder_id = FolderId::new_v4();
         let folder = SyncFolder {
             id: folder_id.clone(),
             name,
@@ -164,12 +506,17 @@ async fn handle_create_sync_folder(
             backup_computers: Vec::new(),
             is_synced: true,
             pending_operations: 0,
+            backup_status: BTreeMap::new(),
         };
 
         state_write.create_sync_folder(&user_id, folder.clone());
+        state_write.record_change(DirectoryChange::FolderCreated {
+            user_id: user_id.clone(),
+            folder: folder.clone(),
+        });
         drop(state_write);
 
-        println!("Created sync folder {folder_id} for user {user_id}");
+        info!(%user_id, %folder_id, "created sync folder");
         Ok(HandlerResponse::Send(ServerMessage::SyncFolderCreated {
             folder,
         }))
@@ -177,6 +524,7 @@ async fn handle_create_sync_folder(
         drop(state_write);
         Ok(HandlerResponse::Send(ServerMessage::Error {
             message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
         }))
     }
 }
@@ -184,7 +532,7 @@ async fn handle_create_sync_folder(
 async fn handle_join_sync_folder(
     addr: SocketAddr,
     state: &Arc<RwLock<ServerState>>,
-    folder_id: String,
+    folder_id: FolderId,
 ) -> Result<HandlerResponse> {
     let mut state_write = state.write().await;
     let conn_info = state_write
@@ -193,8 +541,13 @@ async fn handle_join_sync_folder(
 
     if let Some((Some(user_id), Some(computer_id))) = conn_info {
         if let Some(folder) = state_write.join_sync_folder(&user_id, &folder_id, &computer_id) {
+            state_write.record_change(DirectoryChange::FolderJoined {
+                user_id,
+                folder_id: folder_id.clone(),
+                computer_id,
+            });
             drop(state_write);
-            println!("Computer joined sync folder {folder_id}");
+            info!(%folder_id, "joined sync folder");
             Ok(HandlerResponse::Send(ServerMessage::JoinedSyncFolder {
                 folder,
             }))
@@ -202,12 +555,14 @@ async fn handle_join_sync_folder(
             drop(state_write);
             Ok(HandlerResponse::Send(ServerMessage::Error {
                 message: format!("Folder {folder_id} not found"),
+                trace_id: None,
             }))
         }
     } else {
         drop(state_write);
         Ok(HandlerResponse::Send(ServerMessage::Error {
             message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
         }))
     }
 }
@@ -215,7 +570,7 @@ async fn handle_join_sync_folder(
 async fn handle_leave_sync_folder(
     addr: SocketAddr,
     state: &Arc<RwLock<ServerState>>,
-    folder_id: String,
+    folder_id: FolderId,
 ) -> Result<HandlerResponse> {
     let mut state_write = state.write().await;
     let conn_info = state_write
@@ -224,9 +579,14 @@ async fn handle_leave_sync_folder(
 
     if let Some((Some(user_id), Some(computer_id))) = conn_info {
         state_write.leave_sync_folder(&user_id, &folder_id, &computer_id);
+        state_write.record_change(DirectoryChange::FolderLeft {
+            user_id,
+            folder_id: folder_id.clone(),
+            computer_id,
+        });
         drop(state_write);
 
-        println!("Computer left sync folder {folder_id}");
+        info!(%folder_id, "left sync folder");
         Ok(HandlerResponse::Send(ServerMessage::LeftSyncFolder {
             folder_id,
         }))
@@ -234,6 +594,110 @@ async fn handle_leave_sync_folder(
         drop(state_write);
         Ok(HandlerResponse::Send(ServerMessage::Error {
             message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
+        }))
+    }
+}
+
+async fn handle_delete_sync_folder(
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+    folder_id: FolderId,
+) -> Result<HandlerResponse> {
+    let mut state_write = state.write().await;
+    let conn_info = state_write
+        .get_connection(&addr)
+        .map(|c| (c.user_id.clone(), c.computer_id.clone()));
+
+    if let Some((Some(user_id), Some(computer_id))) = conn_info {
+        match state_write.delete_sync_folder(&user_id, &folder_id, &computer_id) {
+            Ok(folder) => {
+                state_write.record_change(DirectoryChange::FolderDeleted {
+                    user_id: user_id.clone(),
+                    folder_id: folder_id.clone(),
+                });
+                drop(state_write);
+                info!(%folder_id, "deleted sync folder");
+                let deleted = ServerMessage::SyncFolderDeleted { folder_id };
+                // The folder is gone, so its backups are addressed directly
+                Ok(HandlerResponse::Broadcast {
+                    response: deleted.clone(),
+                    broadcast: BroadcastMessage {
+                        user_id,
+                        message: deleted,
+                        audience: Audience::Computers {
+                            computer_ids: folder.backup_computers,
+                        },
+                    },
+                })
+            }
+            Err(reason) => {
+                drop(state_write);
+                Ok(HandlerResponse::Send(
+                    ServerMessage::SyncFolderDeletionDenied {
+                        folder_id,
+                        reason: reason.to_string(),
+                    },
+                ))
+            }
+        }
+    } else {
+        drop(state_write);
+        Ok(HandlerResponse::Send(ServerMessage::Error {
+            message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
+        }))
+    }
+}
+
+async fn handle_rename_sync_folder(
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+    folder_id: FolderId,
+    new_name: String,
+) -> Result<HandlerResponse> {
+    let mut state_write = state.write().await;
+    let conn_info = state_write
+        .get_connection(&addr)
+        .map(|c| (c.user_id.clone(), c.computer_id.clone()));
+
+    if let Some((Some(user_id), Some(computer_id))) = conn_info {
+        match state_write.rename_folder(&user_id, &folder_id, &computer_id, &new_name) {
+            Ok(folder) => {
+                state_write.record_change(DirectoryChange::FolderRenamed {
+                    user_id: user_id.clone(),
+                    folder_id: folder_id.clone(),
+                    name: folder.name.clone(),
+                });
+                drop(state_write);
+                info!(%folder_id, "renamed sync folder");
+                let renamed = ServerMessage::SyncFolderRenamed {
+                    folder_id,
+                    new_name: folder.name,
+                };
+                // Every computer of the user shows folder names, not only its members
+                Ok(HandlerResponse::Broadcast {
+                    response: renamed.clone(),
+                    broadcast: BroadcastMessage {
+                        user_id,
+                        message: renamed,
+                        audience: Audience::User { except: Some(addr) },
+                    },
+                })
+            }
+            Err(e) => {
+                drop(state_write);
+                Ok(HandlerResponse::Send(ServerMessage::Error {
+                    message: format!("Cannot rename folder {folder_id}: {e}"),
+                    trace_id: None,
+                }))
+            }
+        }
+    } else {
+        drop(state_write);
+        Ok(HandlerResponse::Send(ServerMessage::Error {
+            message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
         }))
     }
 }
@@ -241,7 +705,7 @@ async fn handle_leave_sync_folder(
 async fn handle_request_origin_switch(
     addr: SocketAddr,
     state: &Arc<RwLock<ServerState>>,
-    folder_id: String,
+    folder_id: FolderId,
 ) -> Result<HandlerResponse> {
     let mut state_write = state.write().await;
     let conn_info = state_write
@@ -251,12 +715,26 @@ async fn handle_request_origin_switch(
     if let Some((Some(user_id), Some(computer_id))) = conn_info {
         match state_write.switch_origin(&user_id, &folder_id, &computer_id) {
             Ok(()) => {
+                state_write.record_change(DirectoryChange::OriginSwitched {
+                    user_id: user_id.clone(),
+                    folder_id: folder_id.clone(),
+                    new_origin: computer_id.clone(),
+                });
                 drop(state_write);
-                println!("Origin switched for folder {folder_id} to computer {computer_id}");
-                Ok(HandlerResponse::Send(ServerMessage::OriginSwitched {
-                    folder_id,
+                info!(%folder_id, origin = %computer_id, "switched origin");
+                let switched = ServerMessage::OriginSwitched {
+                    folder_id: folder_id.clone(),
                     new_origin: computer_id,
-                }))
+                };
+                // The previous origin is one of the backups now, and learns it with them
+                Ok(HandlerResponse::Broadcast {
+                    response: switched.clone(),
+                    broadcast: BroadcastMessage {
+                        user_id,
+                        message: switched,
+                        audience: Audience::FolderBackups { folder_id },
+                    },
+                })
             }
             Err(reason) => {
                 drop(state_write);
@@ -270,6 +748,7 @@ async fn handle_request_origin_switch(
         drop(state_write);
         Ok(HandlerResponse::Send(ServerMessage::Error {
             message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
         }))
     }
 }
@@ -277,8 +756,8 @@ async fn handle_request_origin_switch(
 async fn handle_folder_operation(
     addr: SocketAddr,
     state: &Arc<RwLock<ServerState>>,
-    broadcast_tx: &BroadcastTx,
-    folder_id: String,
+    broadcast_tx: &Broadcasts,
+    folder_id: FolderId,
     operation: backup_sync_protocol::FileOperation,
 ) -> Result<HandlerResponse> {
     let mut state_write = state.write().await;
@@ -291,78 +770,992 @@ async fn handle_folder_operation(
             drop(state_write);
             return Ok(HandlerResponse::Send(ServerMessage::Error {
                 message: "Only origin computer can send operations".to_string(),
+                trace_id: None,
             }));
         }
+        if !operation.continues_transfer() && state_write.is_backpressured(&folder_id) {
+            let response = backpressure(
+                &state_write,
+                user_id,
+                folder_id,
+                std::slice::from_ref(&operation),
+            );
+            drop(state_write);
+            return Ok(response);
+        }
 
         let operation_id = state_write.next_operation_id();
-        state_write.increment_pending_operations(&user_id, &folder_id);
-
-        let backup_count = state_write.get_backup_count(&user_id, &folder_id);
-        state_write.track_operation(&folder_id, operation_id, backup_count);
-
-        drop(state_write);
+        let sequence = state_write.next_folder_sequence(&folder_id);
+        let backups = state_write.get_backups(&user_id, &folder_id);
+        let complete = backups.is_empty();
+        if !complete {
+            state_write.increment_pending_operations(&user_id, &folder_id);
+            state_write.track_operation(&folder_id, operation_id, backups);
+        }
 
-        println!("Received operation {operation_id} for folder {folder_id}: {operation:?}");
+        state_write
+            .metrics
+            .operations_forwarded
+            .fetch_add(1, Ordering::Relaxed);
+        info!(%folder_id, operation_id, sequence, "forwarding operation");
+        let stored = state_write
+            .content
+            .clone()
+            .map(|content| (content, operation.clone()));
 
         let server_msg = ServerMessage::FolderOperation {
             folder_id: folder_id.clone(),
             operation_id,
+            sequence,
             operation,
         };
+        state_write.journal.record(
+            &folder_id,
+            JournalEntry {
+                operation_id,
+                sequence,
+                message: server_msg.clone(),
+            },
+        );
+        // Under the lock, for the operations of a folder to be queued in sequence order
+        broadcast_tx.send(BroadcastMessage {
+            user_id,
+            message: server_msg,
+            audience: Audience::FolderBackups {
+                folder_id: folder_id.clone(),
+            },
+        });
+        drop(state_write);
 
-        if let Ok(json) = serde_json::to_string(&server_msg) {
-            let _ = broadcast_tx.send(BroadcastMessage {
-                folder_id,
-                message: json,
-            });
+        // In order still, as the operations of a folder come from its origin one at a time
+        if let Some((content, operation)) = stored {
+            content.lock().record(&folder_id, sequence, &operation);
         }
-
-        Ok(HandlerResponse::Send(ServerMessage::OperationComplete {
-            operation_id,
-        }))
+        Ok(completion(complete, operation_id))
     } else {
         Ok(HandlerResponse::Send(ServerMessage::Error {
             message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
         }))
     }
 }
 
-async fn handle_get_user_state(
+async fn handle_folder_operation_batch(
     addr: SocketAddr,
     state: &Arc<RwLock<ServerState>>,
+    broadcast_tx: &Broadcasts,
+    folder_id: FolderId,
+    operations: Vec<backup_sync_protocol::FileOperation>,
 ) -> Result<HandlerResponse> {
-    let state_read = state.read().await;
-    let user_id = state_read
+    if operations.is_empty() {
+        return Ok(HandlerResponse::Send(ServerMessage::Error {
+            message: "Operation batch is empty".to_string(),
+            trace_id: None,
+        }));
+    }
+
+    let mut state_write = state.write().await;
+    let conn_info = state_write
         .get_connection(&addr)
-        .and_then(|c| c.user_id.clone());
+        .map(|c| (c.user_id.clone(), c.computer_id.clone()));
 
-    if let Some(user_id) = user_id {
-        if let Some(user) = state_read.get_user(&user_id) {
-            let user = user.clone();
-            drop(state_read);
-            Ok(HandlerResponse::Send(ServerMessage::UserState { user }))
-        } else {
-            drop(state_read);
-            Ok(HandlerResponse::Send(ServerMessage::Error {
-                message: "User not found".to_string(),
-            }))
+    if let Some((Some(user_id), Some(computer_id))) = conn_info {
+        if !state_write.is_origin(&user_id, &folder_id, &computer_id) {
+            drop(state_write);
+            return Ok(HandlerResponse::Send(ServerMessage::Error {
+                message: "Only origin computer can send operations".to_string(),
+                trace_id: None,
+            }));
+        }
+        if !operations.iter().all(FileOperation::continues_transfer)
+            && state_write.is_backpressured(&folder_id)
+        {
+            let response = backpressure(&state_write, user_id, folder_id, &operations);
+            drop(state_write);
+            return Ok(response);
+        }
+
+        // Tracked like a single operation, as backups ack the batch as a whole
+        let operation_id = state_write.next_operation_id();
+        let sequence = state_write.next_folder_sequence(&folder_id);
+        let backups = state_write.get_backups(&user_id, &folder_id);
+        let complete = backups.is_empty();
+        if !complete {
+            state_write.increment_pending_operations(&user_id, &folder_id);
+            state_write.track_operation(&folder_id, operation_id, backups);
+        }
+
+        state_write
+            .metrics
+            .operations_forwarded
+            .fetch_add(operations.len() as u64, Ordering::Relaxed);
+        info!(
+            %folder_id,
+            operation_id,
+            sequence,
+            operations = operations.len(),
+            "forwarding operation batch"
+        );
+        let stored = state_write
+            .content
+            .clone()
+            .map(|content| (content, operations.clone()));
+
+        let server_msg = ServerMessage::FolderOperationBatch {
+            folder_id: folder_id.clone(),
+            operation_id,
+            sequence,
+            operations,
+        };
+        state_write.journal.record(
+            &folder_id,
+            JournalEntry {
+                operation_id,
+                sequence,
+                message: server_msg.clone(),
+            },
+        );
+        // Under the lock, for the operations of a folder to be queued in sequence order
+        broadcast_tx.send(BroadcastMessage {
+            user_id,
+            message: server_msg,
+            audience: Audience::FolderBackups {
+                folder_id: folder_id.clone(),
+            },
+        });
+        drop(state_write);
+
+        // In order still, as the operations of a folder come from its origin one at a time
+        if let Some((content, operations)) = stored {
+            let mut content = content.lock();
+            for operation in &operations {
+                content.record(&folder_id, sequence, operation);
+            }
         }
+        Ok(completion(complete, operation_id))
     } else {
-        drop(state_read);
         Ok(HandlerResponse::Send(ServerMessage::Error {
-            message: "Not authenticated".to_string(),
+            message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
         }))
     }
 }
 
-pub async fn handle_disconnect(addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
+/// Refuses operations of a folder whose backups are too far behind. The origin is told
+/// to keep the paths they change for later, and every computer of the user that the
+/// folder is held back. Operations continuing a transfer are never refused, for backups
+/// not to be left with part of a file; backups fail those of a transfer whose start was
+/// refused, as an unknown transfer.
+fn backpressure(
+    state: &ServerState,
+    user_id: UserId,
+    folder_id: FolderId,
+    operations: &[FileOperation],
+) -> HandlerResponse {
+    let pending_operations = state.pending_count(&folder_id) as u64;
+    let limit = state.max_pending_operations.unwrap_or_default() as u64;
+    let paths: BTreeSet<&RelativePath> = operations
+        .iter()
+        .flat_map(FileOperation::relative_paths)
+        .collect();
+    debug!(%folder_id, pending_operations, limit, "holding back folder operation");
+    let response = ServerMessage::Backpressure {
+        folder_id: folder_id.clone(),
+        paths: paths.into_iter().cloned().collect(),
+        pending_operations,
+        limit,
+    };
+    match state.sync_status(&user_id, &folder_id) {
+        Some(status) => HandlerResponse::Broadcast {
+            response,
+            broadcast: BroadcastMessage {
+                user_id,
+                message: status,
+                audience: Audience::User { except: None },
+            },
+        },
+        None => HandlerResponse::Send(response),
+    }
+}
+
+/// Response to an operation just forwarded: the origin is told it is complete once its
+/// backups acked it, right away only if there is no backup to wait for.
+fn completion(complete: bool, operation_id: u64) -> HandlerResponse {
+    if complete {
+        HandlerResponse::Send(ServerMessage::OperationComplete { operation_id })
+    } else {
+        HandlerResponse::None
+    }
+}
+
+/// Forwards an operation to one backup of the folder only. It does not take a sequence
+/// number of its own, as the other backups would see a gap where there is none.
+async fn handle_targeted_operation(
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+    broadcast_tx: &Broadcasts,
+    folder_id: FolderId,
+    target: ComputerId,
+    operation: backup_sync_protocol::FileOperation,
+) -> Result<HandlerResponse> {
     let mut state_write = state.write().await;
-    if let Some(conn) = state_write.remove_connection(&addr)
-        && let (Some(user_id), Some(computer_id)) = (conn.user_id, conn.computer_id)
-    {
+    let conn_info = state_write
+        .get_connection(&addr)
+        .map(|c| (c.user_id.clone(), c.computer_id.clone()));
+
+    if let Some((Some(user_id), Some(computer_id))) = conn_info {
+        if !state_write.is_origin(&user_id, &folder_id, &computer_id) {
+            drop(state_write);
+            return Ok(HandlerResponse::Send(ServerMessage::Error {
+                message: "Only origin computer can send operations".to_string(),
+                trace_id: None,
+            }));
+        }
+        if !state_write.is_backup(&user_id, &folder_id, &target) {
+            drop(state_write);
+            return Ok(HandlerResponse::Send(ServerMessage::Error {
+                message: format!("Computer {target} is not a backup of folder {folder_id}"),
+                trace_id: None,
+            }));
+        }
+        let Some(target_addr) = state_write.connection_of(&user_id, &target) else {
+            drop(state_write);
+            return Ok(HandlerResponse::Send(ServerMessage::Error {
+                message: format!("Computer {target} is not connected"),
+                trace_id: None,
+            }));
+        };
+
+        let operation_id = state_write.next_operation_id();
+        let sequence = state_write.folder_sequence(&folder_id);
+        state_write.increment_pending_operations_for(&user_id, &folder_id, &target);
+        state_write.track_operation(&folder_id, operation_id, vec![target.clone()]);
         state_write
-            .computer_connections
-            .remove(&(user_id.clone(), computer_id.clone()));
-        state_write.set_computer_online(&user_id, &computer_id, false);
+            .metrics
+            .operations_forwarded
+            .fetch_add(1, Ordering::Relaxed);
+
+        info!(
+            %folder_id,
+            operation_id,
+            sequence,
+            target = %target,
+            "forwarding targeted operation"
+        );
+        // Sent by the origin as its current state, which the store keeps as well
+        let stored = state_write
+            .content
+            .clone()
+            .map(|content| (content, operation.clone()));
+
+        // After the operation whose sequence it reuses, see `handle_folder_operation`
+        broadcast_tx.send(BroadcastMessage {
+            user_id,
+            message: ServerMessage::FolderOperation {
+                folder_id: folder_id.clone(),
+                operation_id,
+                sequence,
+                operation,
+            },
+            audience: Audience::Connection { addr: target_addr },
+        });
+        drop(state_write);
+
+        if let Some((content, operation)) = stored {
+            content.lock().record(&folder_id, sequence, &operation);
+        }
+
+        Ok(HandlerResponse::None)
+    } else {
+        Ok(HandlerResponse::Send(ServerMessage::Error {
+            message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
+        }))
+    }
+}
+
+/// Asks the backups of a folder for the signature of a file on behalf of its origin
+async fn handle_request_signature(
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+    broadcast_tx: &Broadcasts,
+    folder_id: FolderId,
+    relative_path: RelativePath,
+) -> Result<HandlerResponse> {
+    let state_read = state.read().await;
+    let conn_info = state_read
+        .get_connection(&addr)
+        .map(|c| (c.user_id.clone(), c.computer_id.clone()));
+
+    if let Some((Some(user_id), Some(computer_id))) = conn_info {
+        let is_origin = state_read.is_origin(&user_id, &folder_id, &computer_id);
+        drop(state_read);
+        if !is_origin {
+            return Ok(HandlerResponse::Send(ServerMessage::Error {
+                message: "Only origin computer can request signatures".to_string(),
+                trace_id: None,
+            }));
+        }
+
+        debug!(%folder_id, relative = %relative_path, "requesting signatures");
+        broadcast_tx.send(BroadcastMessage {
+            user_id,
+            message: ServerMessage::SignatureRequested {
+                folder_id: folder_id.clone(),
+                relative_path,
+            },
+            audience: Audience::FolderBackups { folder_id },
+        });
+        Ok(HandlerResponse::None)
+    } else {
+        Ok(HandlerResponse::Send(ServerMessage::Error {
+            message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
+        }))
+    }
+}
+
+/// Routes the signature of a backup to the connection of the folder's origin only
+async fn handle_signature_response(
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+    broadcast_tx: &Broadcasts,
+    folder_id: FolderId,
+    relative_path: RelativePath,
+    signature: Vec<u8>,
+) -> Result<HandlerResponse> {
+    let state_read = state.read().await;
+    let conn_info = state_read
+        .get_connection(&addr)
+        .map(|c| (c.user_id.clone(), c.computer_id.clone()));
+
+    if let Some((Some(user_id), Some(computer_id))) = conn_info {
+        if !state_read.is_backup(&user_id, &folder_id, &computer_id) {
+            drop(state_read);
+            return Ok(HandlerResponse::Send(ServerMessage::Error {
+                message: "Only backup computers can send signatures".to_string(),
+                trace_id: None,
+            }));
+        }
+        let origin_addr = state_read
+            .get_folder(&user_id, &folder_id)
+            .and_then(|folder| state_read.connection_of(&user_id, &folder.origin_computer));
+        drop(state_read);
+        let Some(origin_addr) = origin_addr else {
+            return Ok(HandlerResponse::Send(ServerMessage::Error {
+                message: format!("Origin of folder {folder_id} is not connected"),
+                trace_id: None,
+            }));
+        };
+
+        broadcast_tx.send(BroadcastMessage {
+            user_id,
+            message: ServerMessage::SignatureResponse {
+                folder_id,
+                computer_id,
+                relative_path,
+                signature,
+            },
+            audience: Audience::Connection { addr: origin_addr },
+        });
+        Ok(HandlerResponse::None)
+    } else {
+        Ok(HandlerResponse::Send(ServerMessage::Error {
+            message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
+        }))
+    }
+}
+
+/// Asks the backups of a folder for the manifest of their copy on behalf of its origin
+async fn handle_request_manifest(
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+    broadcast_tx: &Broadcasts,
+    folder_id: FolderId,
+) -> Result<HandlerResponse> {
+    let state_read = state.read().await;
+    let conn_info = state_read
+        .get_connection(&addr)
+        .map(|c| (c.user_id.clone(), c.computer_id.clone()));
+
+    if let Some((Some(user_id), Some(computer_id))) = conn_info {
+        let is_origin = state_read.is_origin(&user_id, &folder_id, &computer_id);
+        drop(state_read);
+        if !is_origin {
+            return Ok(HandlerResponse::Send(ServerMessage::Error {
+                message: "Only origin computer can request manifests".to_string(),
+                trace_id: None,
+            }));
+        }
+
+        debug!(%folder_id, "requesting manifests");
+        broadcast_tx.send(BroadcastMessage {
+            user_id,
+            message: ServerMessage::ManifestRequested {
+                folder_id: folder_id.clone(),
+            },
+            audience: Audience::FolderBackups { folder_id },
+        });
+        Ok(HandlerResponse::None)
+    } else {
+        Ok(HandlerResponse::Send(ServerMessage::Error {
+            message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
+        }))
+    }
+}
+
+/// Asks the origin of a folder for its whole content on behalf of a backup. The origin
+/// sends it as targeted operations, which reach that backup only and are acked like any
+/// other operation. While the origin is offline, the server sends them itself from its
+/// content store, when it has the whole folder. The earlier failures of the backup no
+/// longer count once it asked.
+async fn handle_request_full_sync(
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+    broadcast_tx: &Broadcasts,
+    folder_id: FolderId,
+) -> Result<HandlerResponse> {
+    let mut state_write = state.write().await;
+    let conn_info = state_write
+        .get_connection(&addr)
+        .map(|c| (c.user_id.clone(), c.computer_id.clone()));
+
+    if let Some((Some(user_id), Some(computer_id))) = conn_info {
+        if !state_write.is_backup(&user_id, &folder_id, &computer_id) {
+            drop(state_write);
+            return Ok(HandlerResponse::Send(ServerMessage::Error {
+                message: "Only backup computers can request a full sync".to_string(),
+                trace_id: None,
+            }));
+        }
+        let origin_addr = state_write
+            .get_folder(&user_id, &folder_id)
+            .and_then(|folder| state_write.connection_of(&user_id, &folder.origin_computer));
+        let Some(origin_addr) = origin_addr else {
+            drop(state_write);
+            return Ok(full_sync_from_store(state, user_id, computer_id, folder_id).await);
+        };
+        state_write.clear_backup_failure(&user_id, &folder_id, &computer_id);
+        drop(state_write);
+
+        info!(%folder_id, "requesting full sync from origin");
+        broadcast_tx.send(BroadcastMessage {
+            user_id,
+            message: ServerMessage::FullSyncRequested {
+                folder_id,
+                computer_id,
+            },
+            audience: Audience::Connection { addr: origin_addr },
+        });
+        Ok(HandlerResponse::None)
+    } else {
+        Ok(HandlerResponse::Send(ServerMessage::Error {
+            message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
+        }))
+    }
+}
+
+/// Sends a backup the whole folder from the content store, as targeted operations of one
+/// path each, or tells it to ask again later when the store does not have it all. The
+/// store is read before locking the state.
+async fn full_sync_from_store(
+    state: &Arc<RwLock<ServerState>>,
+    user_id: UserId,
+    computer_id: ComputerId,
+    folder_id: FolderId,
+) -> HandlerResponse {
+    let content = state.read().await.content.clone();
+    let groups = match content.map(|content| content.lock().full_sync(&folder_id)) {
+        Some(Ok(Some(groups))) => groups,
+        Some(Err(e)) => {
+            warn!(%folder_id, "failed to read stored content: {e:#}");
+            Vec::new()
+        }
+        Some(Ok(None)) | None => Vec::new(),
+    };
+    if groups.is_empty() {
+        return HandlerResponse::Send(ServerMessage::FullSyncUnavailable {
+            reason: format!("Origin of folder {folder_id} is not connected"),
+            folder_id,
+            retry_after_secs: FULL_SYNC_RETRY_SECS,
+        });
+    }
+    let mut state_write = state.write().await;
+    state_write.clear_backup_failure(&user_id, &folder_id, &computer_id);
+
+    // After the last operation of the folder, like those of the origin
+    let sequence = state_write.folder_sequence(&folder_id);
+    let mut messages = Vec::with_capacity(groups.len());
+    for operations in groups {
+        let operation_id = state_write.next_operation_id();
+        state_write.increment_pending_operations_for(&user_id, &folder_id, &computer_id);
+        state_write.track_operation(&folder_id, operation_id, vec![computer_id.clone()]);
+        state_write
+            .metrics
+            .operations_forwarded
+            .fetch_add(operations.len() as u64, Ordering::Relaxed);
+        messages.push(ServerMessage::FolderOperationBatch {
+            folder_id: folder_id.clone(),
+            operation_id,
+            sequence,
+            operations,
+        });
+    }
+    info!(
+        %folder_id,
+        target = %computer_id,
+        operations = messages.len(),
+        "serving full sync from stored content"
+    );
+    HandlerResponse::Replay {
+        messages,
+        response: ServerMessage::FolderSequence {
+            folder_id,
+            sequence,
+        },
+    }
+}
+
+/// Answers with file content the server stores, for the origin or a backup of a folder
+/// with a file of that content
+async fn handle_fetch_content(
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+    folder_id: FolderId,
+    hash: [u8; 32],
+) -> Result<HandlerResponse> {
+    let state_read = state.read().await;
+    let conn_info = state_read
+        .get_connection(&addr)
+        .map(|c| (c.user_id.clone(), c.computer_id.clone()));
+
+    if let Some((Some(user_id), Some(computer_id))) = conn_info {
+        if !state_read.is_origin(&user_id, &folder_id, &computer_id)
+            && !state_read.is_backup(&user_id, &folder_id, &computer_id)
+        {
+            drop(state_read);
+            return Ok(HandlerResponse::Send(ServerMessage::Error {
+                message: format!("Folder {folder_id} not found"),
+                trace_id: None,
+            }));
+        }
+        let content = state_read.content.clone();
+        let limit = state_read.max_file_content_bytes;
+        drop(state_read);
+        let blob = content.and_then(|content| content.lock().blob_of(&folder_id, &hash));
+        let Some(path) = blob else {
+            return Ok(HandlerResponse::Send(ServerMessage::Error {
+                message: format!("Content not stored for folder {folder_id}"),
+                trace_id: None,
+            }));
+        };
+        let size = std::fs::metadata(&path)?.len();
+        if let Some(limit) = limit
+            && size > limit as u64
+        {
+            return Ok(HandlerResponse::Send(ServerMessage::PayloadTooLarge {
+                size,
+                limit: limit as u64,
+                reason: format!(
+                    "Stored content of {size} bytes is over the limit of {limit} bytes; ask for a full sync instead"
+                ),
+            }));
+        }
+        let content = std::fs::read(&path)?;
+        Ok(HandlerResponse::Send(ServerMessage::Content {
+            folder_id,
+            hash,
+            content,
+        }))
+    } else {
+        Ok(HandlerResponse::Send(ServerMessage::Error {
+            message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
+        }))
+    }
+}
+
+/// Routes the manifest of a backup to the connection of the folder's origin only
+async fn handle_manifest(
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+    broadcast_tx: &Broadcasts,
+    folder_id: FolderId,
+    manifest: Manifest,
+) -> Result<HandlerResponse> {
+    let state_read = state.read().await;
+    let conn_info = state_read
+        .get_connection(&addr)
+        .map(|c| (c.user_id.clone(), c.computer_id.clone()));
+
+    if let Some((Some(user_id), Some(computer_id))) = conn_info {
+        if !state_read.is_backup(&user_id, &folder_id, &computer_id) {
+            drop(state_read);
+            return Ok(HandlerResponse::Send(ServerMessage::Error {
+                message: "Only backup computers can send manifests".to_string(),
+                trace_id: None,
+            }));
+        }
+        let origin_addr = state_read
+            .get_folder(&user_id, &folder_id)
+            .and_then(|folder| state_read.connection_of(&user_id, &folder.origin_computer));
+        drop(state_read);
+        let Some(origin_addr) = origin_addr else {
+            return Ok(HandlerResponse::Send(ServerMessage::Error {
+                message: format!("Origin of folder {folder_id} is not connected"),
+                trace_id: None,
+            }));
+        };
+
+        debug!(
+            %folder_id,
+            files = manifest.entries.len(),
+            "forwarding manifest to origin"
+        );
+        broadcast_tx.send(BroadcastMessage {
+            user_id,
+            message: ServerMessage::Manifest {
+                folder_id,
+                computer_id,
+                manifest,
+            },
+            audience: Audience::Connection { addr: origin_addr },
+        });
+        Ok(HandlerResponse::None)
+    } else {
+        Ok(HandlerResponse::Send(ServerMessage::Error {
+            message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
+        }))
+    }
+}
+
+/// Counts the ack of a backup. The last ack an operation waits for completes it: the
+/// origin gets `OperationComplete`, and every computer of the user the new sync status.
+async fn handle_ack(
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+    broadcast_tx: &Broadcasts,
+    operation_id: u64,
+) -> Result<HandlerResponse> {
+    let mut state_write = state.write().await;
+    let conn_info = state_write
+        .get_connection(&addr)
+        .map(|c| (c.user_id.clone(), c.computer_id.clone()));
+
+    if let Some((Some(user_id), Some(computer_id))) = conn_info {
+        state_write
+            .metrics
+            .acks_received
+            .fetch_add(1, Ordering::Relaxed);
+        match state_write.record_backup_ack(&user_id, &computer_id, operation_id) {
+            Some(Acked::Complete { folder_id, origin }) => {
+                let status = state_write.sync_status(&user_id, &folder_id);
+                drop(state_write);
+                info!(%folder_id, operation_id, "operation complete");
+                broadcast_tx.send(BroadcastMessage {
+                    user_id: user_id.clone(),
+                    message: ServerMessage::OperationComplete { operation_id },
+                    audience: Audience::Computers {
+                        computer_ids: vec![origin],
+                    },
+                });
+                if let Some(status) = status {
+                    broadcast_tx.send(BroadcastMessage {
+                        user_id,
+                        message: status,
+                        audience: Audience::User { except: None },
+                    });
+                }
+            }
+            Some(Acked::Pending { folder_id }) => {
+                debug!(%folder_id, operation_id, "operation acked");
+            }
+            None => {
+                debug!(operation_id, "ignoring ack of unknown operation");
+            }
+        }
+        Ok(HandlerResponse::None)
+    } else {
+        Ok(HandlerResponse::Send(ServerMessage::Error {
+            message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
+        }))
+    }
+}
+
+async fn handle_nack(
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+    broadcast_tx: &Broadcasts,
+    operation_id: u64,
+    reason: String,
+) -> Result<HandlerResponse> {
+    let mut state_write = state.write().await;
+    let conn_info = state_write
+        .get_connection(&addr)
+        .map(|c| (c.user_id.clone(), c.computer_id.clone()));
+
+    if let Some((Some(user_id), Some(computer_id))) = conn_info {
+        state_write
+            .metrics
+            .nacks_received
+            .fetch_add(1, Ordering::Relaxed);
+        let failed = state_write.record_backup_nack(&user_id, &computer_id, operation_id, &reason);
+        drop(state_write);
+        match failed {
+            Some((folder_id, origin)) => {
+                warn!(%folder_id, operation_id, %reason, "backup failed operation");
+                broadcast_tx.send(BroadcastMessage {
+                    user_id,
+                    message: ServerMessage::OperationFailed {
+                        operation_id,
+                        computer_id,
+                        reason,
+                    },
+                    audience: Audience::Computers {
+                        computer_ids: vec![origin],
+                    },
+                });
+            }
+            None => {
+                debug!(operation_id, "ignoring nack of unknown operation");
+            }
+        }
+        Ok(HandlerResponse::None)
+    } else {
+        Ok(HandlerResponse::Send(ServerMessage::Error {
+            message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
+        }))
+    }
+}
+
+async fn handle_get_folder_sequence(
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+    folder_id: FolderId,
+) -> Result<HandlerResponse> {
+    let state_read = state.read().await;
+    let conn_info = state_read
+        .get_connection(&addr)
+        .map(|c| (c.user_id.clone(), c.computer_id.clone()));
+
+    if let Some((Some(user_id), Some(computer_id))) = conn_info {
+        let is_member = state_read.is_origin(&user_id, &folder_id, &computer_id)
+            || state_read.is_backup(&user_id, &folder_id, &computer_id);
+        let sequence = state_read.folder_sequence(&folder_id);
+        drop(state_read);
+        if is_member {
+            Ok(HandlerResponse::Send(ServerMessage::FolderSequence {
+                folder_id,
+                sequence,
+            }))
+        } else {
+            Ok(HandlerResponse::Send(ServerMessage::Error {
+                message: format!("Folder {folder_id} not found"),
+                trace_id: None,
+            }))
+        }
+    } else {
+        drop(state_read);
+        Ok(HandlerResponse::Send(ServerMessage::Error {
+            message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
+        }))
+    }
+}
+
+/// Replays to a backup the operations of a folder it missed, from the journal, then
+/// answers with the sequence of the folder. Operations broadcast meanwhile and already
+/// replayed are not sent again. When the journal no longer has them all, nothing is
+/// replayed and the backup, seeing it is behind, asks for a full sync.
+async fn handle_catch_up(
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+    folder_id: FolderId,
+    last_sequence: u64,
+) -> Result<HandlerResponse> {
+    let mut state_write = state.write().await;
+    let conn_info = state_write
+        .get_connection(&addr)
+        .map(|c| (c.user_id.clone(), c.computer_id.clone()));
+
+    if let Some((Some(user_id), Some(computer_id))) = conn_info {
+        if !state_write.is_backup(&user_id, &folder_id, &computer_id) {
+            drop(state_write);
+            return Ok(HandlerResponse::Send(ServerMessage::Error {
+                message: "Only backup computers can catch up on a folder".to_string(),
+                trace_id: None,
+            }));
+        }
+
+        let (messages, sequence) = replay_since(&mut state_write, addr, &folder_id, last_sequence);
+        drop(state_write);
+
+        Ok(HandlerResponse::Replay {
+            messages,
+            response: ServerMessage::FolderSequence {
+                folder_id,
+                sequence,
+            },
+        })
+    } else {
+        Ok(HandlerResponse::Send(ServerMessage::Error {
+            message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
+        }))
+    }
+}
+
+/// Operations of a folder journaled after `last_sequence`, none when the journal no
+/// longer has them all, with the sequence of the folder. Those replayed are not sent
+/// again when broadcast meanwhile.
+fn replay_since(
+    state: &mut ServerState,
+    addr: SocketAddr,
+    folder_id: &FolderId,
+    last_sequence: u64,
+) -> (Vec<ServerMessage>, u64) {
+    let sequence = state.folder_sequence(folder_id);
+    let messages = state.journal.since(folder_id, last_sequence, sequence);
+    if messages.is_some()
+        && let Some(conn) = state.get_connection_mut(&addr)
+    {
+        conn.replayed.insert(folder_id.clone(), sequence);
+    }
+    let messages = match messages {
+        Some(messages) => {
+            info!(%folder_id, operations = messages.len(), "replaying operations");
+            messages
+        }
+        None => {
+            info!(%folder_id, last_sequence, "missed operations are no longer journaled");
+            Vec::new()
+        }
+    };
+    (messages, sequence)
+}
+
+async fn handle_get_user_state(
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+) -> Result<HandlerResponse> {
+    let state_read = state.read().await;
+    let user_id = state_read
+        .get_connection(&addr)
+        .and_then(|c| c.user_id.clone());
+
+    if let Some(user_id) = user_id {
+        if let Some(user) = state_read.get_user(&user_id) {
+            let user = user.clone();
+            drop(state_read);
+            Ok(HandlerResponse::Send(ServerMessage::UserState { user }))
+        } else {
+            drop(state_read);
+            Ok(HandlerResponse::Send(ServerMessage::Error {
+                message: "User not found".to_string(),
+                trace_id: None,
+            }))
+        }
+    } else {
+        drop(state_read);
+        Ok(HandlerResponse::Send(ServerMessage::Error {
+            message: "Not authenticated".to_string(),
+            trace_id: None,
+        }))
+    }
+}
+
+async fn handle_list_folders(
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+) -> Result<HandlerResponse> {
+    let state_read = state.read().await;
+    let conn_info = state_read
+        .get_connection(&addr)
+        .map(|c| (c.user_id.clone(), c.computer_id.clone()));
+
+    if let Some((Some(user_id), Some(computer_id))) = conn_info {
+        let folders = state_read
+            .get_user(&user_id)
+            .map(|user| {
+                user.sync_folders
+                    .iter()
+                    .map(|folder| SyncFolderSummary {
+                        id: folder.id.clone(),
+                        name: folder.name.clone(),
+                        origin_computer: folder.origin_computer.clone(),
+                        is_origin: folder.origin_computer == computer_id,
+                        is_backup: folder.backup_computers.contains(&computer_id),
+                        is_synced: folder.is_synced,
+                        pending_operations: folder.pending_operations,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        drop(state_read);
+        Ok(HandlerResponse::Send(ServerMessage::FolderList { folders }))
+    } else {
+        drop(state_read);
+        Ok(HandlerResponse::Send(ServerMessage::Error {
+            message: "Not authenticated with a computer".to_string(),
+            trace_id: None,
+        }))
+    }
+}
+
+/// Forgets a closed connection. Its computer goes offline, and what waited for it as a
+/// backup settles without it: the origin gets `OperationComplete` for the operations only
+/// it had left to ack, and the user the status of the folders it fell behind on.
+pub async fn handle_disconnect(
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+    broadcast_tx: &Broadcasts,
+) {
+    let mut state_write = state.write().await;
+    if let Some(conn) = state_write.remove_connection(&addr)
+        && let (Some(user_id), Some(computer_id)) = (conn.user_id, conn.computer_id)
+    {
+        state_write
+            .computer_connections
+            .remove(&(user_id.clone(), computer_id.clone()));
+        let disconnected = state_write.disconnect_computer(&user_id, &computer_id);
+        let statuses: Vec<ServerMessage> = disconnected
+            .stale_folders
+            .iter()
+            .filter_map(|folder_id| state_write.sync_status(&user_id, folder_id))
+            .collect();
+        drop(state_write);
+
+        for (folder_id, operation_id, origin) in disconnected.completed {
+            info!(%folder_id, operation_id, "operation complete without disconnected backup");
+            broadcast_tx.send(BroadcastMessage {
+                user_id: user_id.clone(),
+                message: ServerMessage::OperationComplete { operation_id },
+                audience: Audience::Computers {
+                    computer_ids: vec![origin],
+                },
+            });
+        }
+        // The connection is gone already, so it is not part of the audience
+        for status in statuses {
+            broadcast_tx.send(BroadcastMessage {
+                user_id: user_id.clone(),
+                message: status,
+                audience: Audience::User { except: None },
+            });
+        }
+        broadcast_tx.send(BroadcastMessage {
+            user_id,
+            message: ServerMessage::ComputerStatusChanged {
+                computer_id,
+                online: false,
+            },
+            audience: Audience::User { except: None },
+        });
     }
 }
@@ -0,0 +1,155 @@
+use std::collections::{HashMap, VecDeque};
+
+use backup_sync_protocol::{FolderId, ServerMessage};
+
+/// Operations kept per folder when no capacity is configured
+pub const DEFAULT_JOURNAL_CAPACITY: usize = 1000;
+
+/// An operation sent to the backups of a folder, as it was broadcast
+#[derive(Debug, Clone)]
+pub struct JournalEntry {
+    pub operation_id: u64,
+    pub sequence: u64,
+    /// `FolderOperation` or `FolderOperationBatch`
+    pub message: ServerMessage,
+}
+
+/// The last operations of each folder, replayed to backups that reconnect after missing
+/// some. Entries are dropped once every backup answered them, and the oldest ones past
+/// the capacity of the folder; a backup that missed a dropped entry needs a full sync.
+#[derive(Debug)]
+pub struct Journal {
+    folders: HashMap<FolderId, VecDeque<JournalEntry>>,
+    capacity: usize,
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Self::new(DEFAULT_JOURNAL_CAPACITY)
+    }
+}
+
+impl Journal {
+    /// Journal keeping up to `capacity` operations per folder, none at all for 0
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            folders: HashMap::new(),
+            capacity,
+        }
+    }
+
+    #[must_use]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Appends the next operation of a folder; sequences only grow within a folder
+    pub fn record(&mut self, folder_id: &FolderId, entry: JournalEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        let entries = self.folders.entry(folder_id.clone()).or_default();
+        entries.push_back(entry);
+        while entries.len() > self.capacity {
+            entries.pop_front();
+        }
+    }
+
+    /// Messages of the operations after `last_sequence` up to `sequence`, the last one of
+    /// the folder, in order. `None` when some of them are no longer in the journal.
+    #[must_use]
+    pub fn since(
+        &self,
+        folder_id: &FolderId,
+        last_sequence: u64,
+        sequence: u64,
+    ) -> Option<Vec<ServerMessage>> {
+        if last_sequence >= sequence {
+            return Some(Vec::new());
+        }
+        let entries = self.folders.get(folder_id)?;
+        let first = entries.front()?;
+        if first.sequence > last_sequence + 1 {
+            return None;
+        }
+        Some(
+            entries
+                .iter()
+                .filter(|entry| entry.sequence > last_sequence)
+                .map(|entry| entry.message.clone())
+                .collect(),
+        )
+    }
+
+    /// Drops the oldest entries for as long as `is_pending` says no backup waits for them.
+    /// Stops at the first pending one, so that what is left has no gap.
+    pub fn truncate(&mut self, folder_id: &FolderId, is_pending: impl Fn(u64) -> bool) {
+        if let Some(entries) = self.folders.get_mut(folder_id) {
+            while entries
+                .front()
+                .is_some_and(|entry| !is_pending(entry.operation_id))
+            {
+                entries.pop_front();
+            }
+            if entries.is_empty() {
+                self.folders.remove(folder_id);
+            }
+        }
+    }
+
+    pub fn remove_folder(&mut self, folder_id: &FolderId) {
+        self.folders.remove(folder_id);
+    }
+
+    #[must_use]
+    pub fn len(&self, folder_id: &FolderId) -> usize {
+        self.folders.get(folder_id).map_or(0, VecDeque::len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(sequence: u64) -> JournalEntry {
+        JournalEntry {
+            operation_id: sequence * 10,
+            sequence,
+            message: ServerMessage::OperationComplete {
+                operation_id: sequence * 10,
+            },
+        }
+    }
+
+    #[test]
+    fn test_since_needs_every_missed_operation() {
+        let folder_id = FolderId::from("folder1");
+        let mut journal = Journal::new(2);
+        for sequence in 1..=3 {
+            journal.record(&folder_id, entry(sequence));
+        }
+
+        assert_eq!(journal.len(&folder_id), 2);
+        assert!(journal.since(&folder_id, 0, 3).is_none());
+        assert_eq!(journal.since(&folder_id, 1, 3).unwrap().len(), 2);
+        assert_eq!(journal.since(&folder_id, 3, 3).unwrap().len(), 0);
+        assert!(Journal::new(0).since(&folder_id, 0, 3).is_none());
+    }
+
+    #[test]
+    fn test_truncate_stops_at_first_pending_entry() {
+        let folder_id = FolderId::from("folder1");
+        let mut journal = Journal::default();
+        for sequence in 1..=3 {
+            journal.record(&folder_id, entry(sequence));
+        }
+
+        // The last entry is done but follows a pending one
+        journal.truncate(&folder_id, |operation_id| operation_id == 20);
+        assert_eq!(journal.len(&folder_id), 2);
+
+        journal.truncate(&folder_id, |_| false);
+        assert_eq!(journal.len(&folder_id), 0);
+    }
+}
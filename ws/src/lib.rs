@@ -1,3 +1,14 @@
+pub mod admin;
+pub mod auth;
+pub mod broadcast;
+pub mod config;
+pub mod content;
 pub mod handlers;
+pub mod journal;
+pub mod metrics;
+pub mod paths;
+pub mod rate_limit;
 pub mod server;
 pub mod state;
+pub mod storage;
+pub mod tls;
@@ -1,8 +1,301 @@
-use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use backup_sync_ws::config::ConfigLayer;
 use backup_sync_ws::server::{ServerConfig, run_server};
+use backup_sync_ws::state::ServerState;
+use backup_sync_ws::storage::{JsonFileStorage, Storage};
+use clap::{ArgAction, Parser, ValueEnum};
+use tokio::sync::watch;
+use tracing_subscriber::EnvFilter;
+
+/// Levels selectable with `-v`/`-q`, from the quietest to the most verbose
+const LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Pretty,
+    /// One JSON object per line, including the fields of the enclosing spans
+    Json,
+}
+
+/// Every setting but logging can also be given in the `--config` file or as a
+/// `BACKUP_SYNC_WS_*` environment variable; flags override the environment, which
+/// overrides the file.
+#[derive(Parser)]
+#[command(about = "WebSocket relay between origin and backup computers", version)]
+struct Cli {
+    /// Log output format
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
+
+    /// TOML file with the settings of the server, keyed like the flags with `_` for `-`,
+    /// durations in seconds as `*_secs`
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Address to accept connections on; repeat to listen on several, such as
+    /// `0.0.0.0:9000` and `[::]:9000` for IPv4 and IPv6 [default: 0.0.0.0:9000]
+    #[arg(long, value_name = "ADDR")]
+    addr: Vec<String>,
+
+    /// Start as long as one of the addresses could be listened on, instead of needing
+    /// them all
+    #[arg(long)]
+    best_effort_bind: bool,
+
+    /// Messages buffered per user for its slowest connection; raise it for users whose
+    /// origins send large bursts of operations [default: 100]
+    #[arg(long, value_name = "COUNT")]
+    broadcast_capacity: Option<usize>,
+
+    /// Seconds without any traffic after which a connection is dropped [default: 90]
+    #[arg(long, value_name = "SECONDS")]
+    idle_timeout: Option<u64>,
+
+    /// Seconds between the websocket pings sent to every connection [default: 30]
+    #[arg(long, value_name = "SECONDS")]
+    ping_interval: Option<u64>,
+
+    /// Pings in a row a connection may leave unanswered before it is dropped
+    /// [default: 2]
+    #[arg(long, value_name = "COUNT")]
+    max_missed_pongs: Option<u32>,
+
+    /// Seconds a connection has to authenticate after being accepted before it is
+    /// dropped [default: 30]
+    #[arg(long, value_name = "SECONDS")]
+    auth_timeout: Option<u64>,
+
+    /// Connections waiting to authenticate kept at once, those past it being closed
+    /// right away; 0 for no limit [default: 1024]
+    #[arg(long, value_name = "COUNT")]
+    max_unauthenticated_connections: Option<usize>,
+
+    /// File keeping users, computers and folders across restarts; without it they are
+    /// lost when the server stops
+    #[arg(long)]
+    data_path: Option<PathBuf>,
+
+    /// Operations kept per folder for backups that reconnect after missing some; those
+    /// that missed more need a full sync [default: 1000]
+    #[arg(long, value_name = "COUNT")]
+    journal_capacity: Option<usize>,
+
+    /// PEM certificate chain to serve wss:// with; plain ws:// without it
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Secret the HTTP server signs its tokens with, the `JWT_SECRET` it runs with
+    #[arg(long, value_name = "SECRET")]
+    jwt_secret: Option<String>,
+
+    /// Accept clients without checking their token, when no secret is given; for local
+    /// testing only
+    #[arg(long, conflicts_with = "jwt_secret")]
+    allow_insecure_auth: bool,
+
+    /// Folder operations a connection may send per second, on average; 0 for no limit
+    /// [default: 200]
+    #[arg(long, value_name = "PER_SECOND")]
+    rate_limit: Option<u32>,
+
+    /// Folder operations a connection may send in a row after a quiet period
+    /// [default: 1000]
+    #[arg(long, value_name = "COUNT")]
+    rate_burst: Option<u32>,
+
+    /// Largest message accepted, in bytes [default: 64 MiB]
+    #[arg(long, value_name = "BYTES")]
+    max_message_bytes: Option<usize>,
+
+    /// Largest file content accepted in one message, in bytes; larger files are sent in
+    /// chunks [default: 16 MiB]
+    #[arg(long, value_name = "BYTES")]
+    max_file_content_bytes: Option<usize>,
+
+    /// Operations a folder may have waiting on its backups before its origin is told to
+    /// hold new ones back; 0 for no limit [default: 10000]
+    #[arg(long, value_name = "COUNT")]
+    max_pending_operations: Option<usize>,
+
+    /// Seconds after which operations backups left unanswered are dropped, those backups
+    /// then needing a full sync; 0 keeps them until answered [default: 86400]
+    #[arg(long, value_name = "SECONDS")]
+    pending_ttl: Option<u64>,
+
+    /// Keep the file content origins send next to the data path, for backups to resync
+    /// from while their origin is offline
+    #[arg(long)]
+    content_store: bool,
+
+    /// Bytes of file content kept at most [default: 10 GiB]
+    #[arg(long, value_name = "BYTES")]
+    content_max_bytes: Option<u64>,
+
+    /// Seconds file content no folder uses anymore is kept [default: 86400]
+    #[arg(long, value_name = "SECONDS")]
+    content_ttl: Option<u64>,
+
+    /// Seconds connections have to close on shutdown before they are dropped
+    /// [default: 10]
+    #[arg(long, value_name = "SECONDS")]
+    shutdown_grace: Option<u64>,
+
+    /// Address to serve Prometheus metrics on over HTTP, such as 127.0.0.1:9100; not
+    /// served without it
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<String>,
+
+    /// Token the admin API served on the metrics address requires, as a bearer token;
+    /// the admin API is disabled without it
+    #[arg(long, value_name = "TOKEN")]
+    admin_token: Option<String>,
+
+    /// Write a snapshot of the state in the data path to this file and exit, without
+    /// serving
+    #[arg(long, value_name = "FILE", conflicts_with = "import_state")]
+    export_state: Option<PathBuf>,
+
+    /// Start from the state snapshot in this file, replacing the state in the data path
+    #[arg(long, value_name = "FILE")]
+    import_state: Option<PathBuf>,
+
+    /// Log more; repeat for even more detail
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Log less; repeat to only log errors
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "verbose")]
+    quiet: u8,
+}
+
+impl Cli {
+    /// The settings given as flags, which override every other source
+    fn overrides(&self) -> ConfigLayer {
+        ConfigLayer {
+            addrs: (!self.addr.is_empty()).then(|| self.addr.clone()),
+            best_effort_bind: self.best_effort_bind.then_some(true),
+            broadcast_capacity: self.broadcast_capacity,
+            idle_timeout_secs: self.idle_timeout,
+            ping_interval_secs: self.ping_interval,
+            max_missed_pongs: self.max_missed_pongs,
+            auth_timeout_secs: self.auth_timeout,
+            max_unauthenticated_connections: self.max_unauthenticated_connections,
+            data_path: self.data_path.clone(),
+            journal_capacity: self.journal_capacity,
+            tls_cert: self.tls_cert.clone(),
+            tls_key: self.tls_key.clone(),
+            jwt_secret: self.jwt_secret.clone(),
+            // An absent flag leaves the other sources alone
+            allow_insecure_auth: self.allow_insecure_auth.then_some(true),
+            rate_limit: self.rate_limit,
+            rate_burst: self.rate_burst,
+            max_message_bytes: self.max_message_bytes,
+            max_file_content_bytes: self.max_file_content_bytes,
+            max_pending_operations: self.max_pending_operations,
+            pending_ttl_secs: self.pending_ttl,
+            content_store: self.content_store.then_some(true),
+            content_max_bytes: self.content_max_bytes,
+            content_ttl_secs: self.content_ttl,
+            shutdown_grace_secs: self.shutdown_grace,
+            metrics_addr: self.metrics_addr.clone(),
+            admin_token: self.admin_token.clone(),
+            ..ConfigLayer::default()
+        }
+    }
+}
+
+/// The defaults, overridden by the config file, the environment and the flags in turn
+fn configure(cli: &Cli) -> Result<ServerConfig> {
+    let mut config = ServerConfig::default();
+    if let Some(path) = &cli.config {
+        config = config.merge(ConfigLayer::from_file(path)?)?;
+    }
+    let config = config
+        .merge(ConfigLayer::from_env()?)?
+        .merge(cli.overrides())?;
+    config.validate()?;
+    Ok(ServerConfig {
+        import_state: cli.import_state.clone(),
+        ..config
+    })
+}
+
+/// Writes the state saved in `data_path` as a snapshot to `path`
+fn export_state(data_path: &Path, path: &Path) -> Result<()> {
+    let persisted = JsonFileStorage::new(data_path.to_path_buf())
+        .load()?
+        .unwrap_or_default();
+    ServerState::from_persisted(persisted)
+        .export_snapshot()
+        .write(path)
+}
+
+fn init_logging(cli: &Cli) {
+    let index = (2 + usize::from(cli.verbose))
+        .saturating_sub(usize::from(cli.quiet))
+        .min(LEVELS.len() - 1);
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(LEVELS[index]));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match cli.log_format {
+        LogFormat::Pretty => builder.init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init(),
+    }
+}
+
+/// Waits for Ctrl-C, or SIGTERM on Unix as sent by service managers
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => tracing::warn!(error = %e, "failed to listen for SIGTERM"),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let config = ServerConfig::default();
-    run_server(config, None).await
+    let cli = Cli::parse();
+    init_logging(&cli);
+
+    let config = configure(&cli).context("Invalid configuration")?;
+    if let Some(path) = &cli.export_state {
+        // The data path may come from the config file or the environment as well
+        let data_path = config
+            .data_path
+            .as_deref()
+            .context("Exporting the state needs a data path")?;
+        export_state(data_path, path)?;
+        tracing::info!(path = ?path, "exported state snapshot");
+        return Ok(());
+    }
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        tracing::info!("shutdown requested");
+        let _ = shutdown_tx.send(true);
+    });
+    tracing::info!(addrs = ?config.addrs, "starting server");
+    run_server(config, None, shutdown_rx).await
 }
@@ -0,0 +1,294 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use backup_sync_protocol::ServerMessage;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::RwLock;
+use tracing::debug;
+
+use crate::admin;
+use crate::state::ServerState;
+
+/// Counters of the server since startup, kept in its state. They count through a shared
+/// reference, for handlers to count without locking the state for writing.
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub connections_accepted: AtomicU64,
+    pub operations_forwarded: AtomicU64,
+    pub acks_received: AtomicU64,
+    pub nacks_received: AtomicU64,
+    pub broadcast_lagged: AtomicU64,
+    pub throttled_messages: AtomicU64,
+    pub operations_expired: AtomicU64,
+    pub blobs_collected: AtomicU64,
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Counters {
+    /// Counts an answer refusing or failing a message, of the kind given by [`error_kind`]
+    pub fn record_error(&self, kind: &'static str) {
+        let mut errors = self.errors.lock().unwrap_or_else(PoisonError::into_inner);
+        *errors.entry(kind).or_default() += 1;
+    }
+
+    /// The value of every counter
+    #[must_use]
+    pub fn values(&self) -> Metrics {
+        let value = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Metrics {
+            connections_accepted: value(&self.connections_accepted),
+            operations_forwarded: value(&self.operations_forwarded),
+            acks_received: value(&self.acks_received),
+            nacks_received: value(&self.nacks_received),
+            broadcast_lagged: value(&self.broadcast_lagged),
+            throttled_messages: value(&self.throttled_messages),
+            operations_expired: value(&self.operations_expired),
+            blobs_collected: value(&self.blobs_collected),
+            errors: self
+                .errors
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone(),
+        }
+    }
+}
+
+/// Values of the [`Counters`] at one point in time
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Connections accepted, whether they authenticated or not
+    pub connections_accepted: u64,
+    /// Folder operations forwarded to backups, each operation of a batch counted
+    pub operations_forwarded: u64,
+    pub acks_received: u64,
+    pub nacks_received: u64,
+    /// Times a connection fell behind the broadcasts of its user and missed some
+    pub broadcast_lagged: u64,
+    /// Folder operations refused for going over the rate limit
+    pub throttled_messages: u64,
+    /// Pending operations dropped for staying unanswered past the pending TTL
+    pub operations_expired: u64,
+    /// Blobs of the content store removed once no folder referred to them for its TTL
+    pub blobs_collected: u64,
+    /// Answers refusing or failing a message, by variant of the answer
+    pub errors: BTreeMap<&'static str, u64>,
+}
+
+/// Kind of the error `response` is, `None` for answers of messages that succeeded
+#[must_use]
+pub fn error_kind(response: &ServerMessage) -> Option<&'static str> {
+    match response {
+        ServerMessage::Error { .. } => Some("Error"),
+        ServerMessage::PathRejected { .. } => Some("PathRejected"),
+        ServerMessage::NotAuthenticated { .. } => Some("NotAuthenticated"),
+        ServerMessage::AuthTimeout { .. } => Some("AuthTimeout"),
+        ServerMessage::RateLimited { .. } => Some("RateLimited"),
+        ServerMessage::Backpressure { .. } => Some("Backpressure"),
+        ServerMessage::PayloadTooLarge { .. } => Some("PayloadTooLarge"),
+        ServerMessage::Response { message, .. } => error_kind(message),
+        _ => None,
+    }
+}
+
+/// Metrics at one point in time, see [`ServerState::metrics_snapshot`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Open connections
+    pub connections: usize,
+    /// Open connections authenticated with a computer
+    pub authenticated_connections: usize,
+    pub counters: Metrics,
+}
+
+impl MetricsSnapshot {
+    /// The metrics in the Prometheus text format
+    #[must_use]
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP backup_sync_ws_{name} {help}");
+            let _ = writeln!(out, "# TYPE backup_sync_ws_{name} {kind}");
+            let _ = writeln!(out, "backup_sync_ws_{name} {value}");
+        };
+        let counters = &self.counters;
+        metric(
+            "connections",
+            "gauge",
+            "Open connections",
+            self.connections as u64,
+        );
+        metric(
+            "authenticated_connections",
+            "gauge",
+            "Open connections authenticated with a computer",
+            self.authenticated_connections as u64,
+        );
+        metric(
+            "connections_accepted_total",
+            "counter",
+            "Connections accepted",
+            counters.connections_accepted,
+        );
+        metric(
+            "operations_forwarded_total",
+            "counter",
+            "Folder operations forwarded to backups",
+            counters.operations_forwarded,
+        );
+        metric(
+            "acks_received_total",
+            "counter",
+            "Operations acked by backups",
+            counters.acks_received,
+        );
+        metric(
+            "nacks_received_total",
+            "counter",
+            "Operations backups failed to apply",
+            counters.nacks_received,
+        );
+        metric(
+            "broadcast_lagged_total",
+            "counter",
+            "Times a connection missed broadcasts for falling behind",
+            counters.broadcast_lagged,
+        );
+        metric(
+            "throttled_messages_total",
+            "counter",
+            "Folder operations refused over the rate limit",
+            counters.throttled_messages,
+        );
+        metric(
+            "operations_expired_total",
+            "counter",
+            "Pending operations dropped unanswered past the pending TTL",
+            counters.operations_expired,
+        );
+        metric(
+            "blobs_collected_total",
+            "counter",
+            "Stored blobs removed for no folder referring to them",
+            counters.blobs_collected,
+        );
+        out.push_str("# HELP backup_sync_ws_errors_total Messages refused or failed\n");
+        out.push_str("# TYPE backup_sync_ws_errors_total counter\n");
+        for (kind, count) in &counters.errors {
+            let _ = writeln!(
+                out,
+                "backup_sync_ws_errors_total{{kind=\"{kind}\"}} {count}"
+            );
+        }
+        out
+    }
+}
+
+/// Answers the HTTP requests on `listener`: requests under `/admin/` with the admin API
+/// of [`crate::admin`], guarded by `admin_token`, and any other with the metrics of the
+/// server, for Prometheus to scrape
+pub async fn serve(
+    listener: TcpListener,
+    state: Arc<RwLock<ServerState>>,
+    admin_token: Option<Arc<str>>,
+) {
+    while let Ok((stream, peer)) = listener.accept().await {
+        let state = Arc::clone(&state);
+        let admin_token = admin_token.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer(stream, &state, admin_token.as_deref()).await {
+                debug!(%peer, error = %e, "failed to serve metrics");
+            }
+        });
+    }
+}
+
+/// Answers a request once read up to the end of its headers
+async fn answer(
+    mut stream: TcpStream,
+    state: &RwLock<ServerState>,
+    admin_token: Option<&str>,
+) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    let read = tokio::time::timeout(Duration::from_secs(5), async {
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+            let n = stream.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buffer[..n]);
+        }
+        Ok::<_, std::io::Error>(())
+    })
+    .await;
+    match read {
+        Ok(result) => result?,
+        Err(_) => return Err(std::io::ErrorKind::TimedOut.into()),
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut lines = request.lines();
+    let target = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or("/");
+    let (status, content_type, body) = if target.starts_with("/admin/") {
+        let authorization = lines
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+            .map(|(_, value)| value.trim());
+        let response = admin::respond(&*state.read().await, target, authorization, admin_token);
+        (response.status, "application/json", response.body)
+    } else {
+        let body = state.read().await.metrics_snapshot().to_prometheus();
+        ("200 OK", "text/plain; version=0.0.4", body)
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors_are_counted_by_kind() {
+        let counters = Counters::default();
+        let responses = [
+            ServerMessage::Error {
+                message: "nope".to_string(),
+                trace_id: None,
+            },
+            ServerMessage::Response {
+                request_id: 1,
+                message: Box::new(ServerMessage::RateLimited { retry_after_ms: 5 }),
+            },
+            ServerMessage::Pong { nonce: 1 },
+        ];
+        for kind in responses.iter().filter_map(error_kind) {
+            counters.record_error(kind);
+        }
+        counters.acks_received.fetch_add(3, Ordering::Relaxed);
+
+        let snapshot = MetricsSnapshot {
+            connections: 2,
+            authenticated_connections: 1,
+            counters: counters.values(),
+        };
+        let text = snapshot.to_prometheus();
+        assert!(text.contains("backup_sync_ws_connections 2\n"));
+        assert!(text.contains("backup_sync_ws_acks_received_total 3\n"));
+        assert!(text.contains("backup_sync_ws_errors_total{kind=\"Error\"} 1\n"));
+        assert!(text.contains("backup_sync_ws_errors_total{kind=\"RateLimited\"} 1\n"));
+        assert_eq!(text.matches("backup_sync_ws_errors_total{").count(), 2);
+    }
+}
@@ -0,0 +1,144 @@
+use backup_sync_protocol::{FileOperation, MAX_PATH_LEN, RelativePath, RelativePathError};
+
+/// Checks the paths of an operation before it is forwarded to every backup of a folder,
+/// the server being the only trust boundary between an origin and its backups.
+///
+/// Paths inside the folder are [`RelativePath`]s, refused already when the message is
+/// decoded if absolute, going up with `..`, holding a NUL or too long. That leaves the
+/// targets of symlinks, which may be relative paths going up, as long as they do not
+/// leave the folder: a link out of it would have later operations written through it.
+pub fn check_operation(operation: &FileOperation) -> Result<(), String> {
+    match operation {
+        FileOperation::CreateSymlink {
+            relative_path,
+            target,
+        } => check_symlink_target(relative_path, target)
+            .map_err(|e| format!("Invalid target {target:?} of symlink {relative_path}: {e}")),
+        FileOperation::CreateFile { .. }
+        | FileOperation::CreateDir { .. }
+        | FileOperation::RemoveFile { .. }
+        | FileOperation::RemoveDir { .. }
+        | FileOperation::RenameFile { .. }
+        | FileOperation::SetMetadata { .. }
+        | FileOperation::StartTransfer { .. }
+        | FileOperation::FileChunk { .. }
+        | FileOperation::EndTransfer { .. }
+        | FileOperation::AbortTransfer { .. }
+        | FileOperation::ApplyDelta { .. }
+        | FileOperation::RequestSignature { .. }
+        | FileOperation::SignatureResponse { .. } => Ok(()),
+    }
+}
+
+/// A target is resolved from the directory of the link, which its `..` may go up from
+/// down to the root of the folder but not past it. Backslashes and drive letters are
+/// checked too, as they mean something to Windows backups.
+fn check_symlink_target(link: &RelativePath, target: &str) -> Result<(), RelativePathError> {
+    if target.is_empty() {
+        return Err(RelativePathError::Empty);
+    }
+    if target.contains('\0') {
+        return Err(RelativePathError::Nul);
+    }
+    if target.len() > MAX_PATH_LEN {
+        return Err(RelativePathError::TooLong);
+    }
+    let bytes = target.as_bytes();
+    if target.starts_with(['/', '\\'])
+        || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':')
+    {
+        return Err(RelativePathError::Absolute);
+    }
+    let mut depth = link.components().count().saturating_sub(1);
+    for component in target.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => depth = depth.checked_sub(1).ok_or(RelativePathError::ParentDir)?,
+            _ => depth += 1,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use backup_sync_protocol::ClientMessage;
+    use backup_sync_protocol::codec;
+
+    use super::*;
+
+    fn symlink(link: &str, target: &str) -> FileOperation {
+        FileOperation::CreateSymlink {
+            relative_path: RelativePath::try_from(link).unwrap(),
+            target: target.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_symlink_targets_stay_inside_the_folder() {
+        for (link, target) in [
+            ("link", "file.txt"),
+            ("link", "missing"),
+            ("sub/link", "../file.txt"),
+            ("a/b/link", "../../c/./file.txt"),
+        ] {
+            assert_eq!(check_operation(&symlink(link, target)), Ok(()), "{target}");
+        }
+
+        for (link, target) in [
+            ("link", "../escaped"),
+            ("sub/link", "../../escaped"),
+            ("sub/link", "a/../../../escaped"),
+            ("sub/link", "..\\..\\escaped"),
+            ("link", "/etc/passwd"),
+            ("link", "\\\\server\\share"),
+            ("link", "C:\\Windows"),
+            ("link", "c:relative"),
+            ("link", "file\0.txt"),
+            ("link", ""),
+        ] {
+            let err = check_operation(&symlink(link, target)).unwrap_err();
+            assert!(err.starts_with("Invalid target"), "{err}");
+        }
+
+        let long = "a/".repeat(MAX_PATH_LEN / 2 + 1);
+        assert!(check_operation(&symlink("link", &long)).is_err());
+    }
+
+    /// Every path of every operation is a `RelativePath`, refused when decoding; only
+    /// the path fields are given, as decoding fails on them before missing the others
+    #[test]
+    fn test_unsafe_operation_paths_do_not_decode() {
+        let long = "a".repeat(MAX_PATH_LEN + 1);
+        for path in [
+            "../escaped",
+            "sub/../../escaped",
+            "/etc/passwd",
+            "C:\\\\Windows\\\\system32",
+            "file\\u0000.txt",
+            long.as_str(),
+        ] {
+            let operations = [
+                format!(r#"{{"CreateFile":{{"relative_path":"{path}"}}}}"#),
+                format!(r#"{{"CreateDir":{{"relative_path":"{path}"}}}}"#),
+                format!(r#"{{"RemoveFile":{{"relative_path":"{path}"}}}}"#),
+                format!(r#"{{"RemoveDir":{{"relative_path":"{path}"}}}}"#),
+                format!(r#"{{"RenameFile":{{"from_relative":"{path}"}}}}"#),
+                format!(r#"{{"RenameFile":{{"from_relative":"ok","to_relative":"{path}"}}}}"#),
+                format!(r#"{{"CreateSymlink":{{"relative_path":"{path}"}}}}"#),
+                format!(r#"{{"SetMetadata":{{"relative_path":"{path}"}}}}"#),
+                format!(r#"{{"StartTransfer":{{"transfer_id":1,"relative_path":"{path}"}}}}"#),
+                format!(r#"{{"ApplyDelta":{{"transfer_id":1,"relative_path":"{path}"}}}}"#),
+                format!(r#"{{"RequestSignature":{{"relative_path":"{path}"}}}}"#),
+                format!(r#"{{"SignatureResponse":{{"relative_path":"{path}"}}}}"#),
+            ];
+            for operation in operations {
+                let message = format!(
+                    r#"{{"FolderOperation":{{"folder_id":"folder1","operation":{operation}}}}}"#
+                );
+                let err = codec::decode_text::<ClientMessage>(&message).unwrap_err();
+                assert!(err.invalid_path().is_some(), "{operation}: {err}");
+            }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::time::{Duration, Instant};
+
+/// How fast a connection may send folder operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Messages allowed per second, on average
+    pub per_second: u32,
+    /// Messages allowed in a row after a quiet period
+    pub burst: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            per_second: 200,
+            burst: 1000,
+        }
+    }
+}
+
+/// Token bucket of one connection: it holds up to `burst` tokens, refilled at
+/// `per_second`, and every message takes one
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Full bucket, so that a connection can start with a burst
+    #[must_use]
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: f64::from(limit.burst),
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Takes a token for a message, or tells how long until one is available
+    pub fn take(&mut self) -> Result<(), Duration> {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * f64::from(self.limit.per_second))
+            .min(f64::from(self.limit.burst));
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        if self.limit.per_second == 0 {
+            return Err(Duration::MAX);
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - self.tokens) / f64::from(self.limit.per_second),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_allows_burst_then_refills() {
+        let mut bucket = TokenBucket::new(RateLimit {
+            per_second: 10,
+            burst: 3,
+        });
+        let start = bucket.refilled_at;
+
+        for _ in 0..3 {
+            assert_eq!(bucket.take_at(start), Ok(()));
+        }
+        let wait = bucket.take_at(start).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(100));
+
+        assert_eq!(bucket.take_at(start + Duration::from_millis(100)), Ok(()));
+        assert!(bucket.take_at(start + Duration::from_millis(100)).is_err());
+
+        // Never more than the burst, however long it stayed quiet
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(bucket.take_at(later), Ok(()));
+        }
+        assert!(bucket.take_at(later).is_err());
+    }
+}
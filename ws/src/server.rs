@@ -1,162 +1,1011 @@
+use std::collections::{BTreeSet, HashMap};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
-use anyhow::Result;
-use backup_sync_protocol::{ClientMessage, ServerMessage};
-use futures_util::{SinkExt, StreamExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{RwLock, broadcast, oneshot};
-use tokio_tungstenite::tungstenite::Message;
+use anyhow::{Context, Result};
+use backup_sync_protocol::codec::{self, Encoding, Frame};
+use backup_sync_protocol::{
+    ClientMessage, FolderId, MIN_SUPPORTED_VERSION, PROTOCOL_VERSION, ServerMessage, UserId,
+};
+use futures_util::{FutureExt, SinkExt, StreamExt, future};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{RwLock, oneshot, watch};
+use tokio::task::JoinSet;
+use tokio::time::{Instant, MissedTickBehavior};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::error::CapacityError;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::protocol::frame::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::{Bytes, Error as WsError, Message};
+use tracing::{Instrument, Span, debug, error, field, info, info_span, warn};
 
+use crate::auth::TokenValidator;
+use crate::broadcast::{Broadcasts, Subscription};
+use crate::content::{ContentLimits, ContentStore, SharedContent};
 use crate::handlers::{HandlerResponse, handle_disconnect, handle_message};
-use crate::state::{BroadcastMessage, ServerState};
+use crate::journal::{DEFAULT_JOURNAL_CAPACITY, Journal};
+use crate::metrics;
+use crate::rate_limit::RateLimit;
+use crate::state::{Audience, BroadcastMessage, ServerState, unix_now};
+use crate::storage::{JsonFileStorage, PersistedState, StateSnapshot, Storage};
+use crate::tls::TlsConfig;
 
-pub type BroadcastTx = broadcast::Sender<BroadcastMessage>;
+/// Largest message accepted by default, in bytes
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 64 << 20;
+
+/// Largest file content accepted in one message by default, in bytes. Clients send files
+/// in chunks well below it.
+pub const DEFAULT_MAX_FILE_CONTENT_BYTES: usize = 16 << 20;
+
+/// Operations a folder may have pending by default. Backups that far behind most likely
+/// missed more than the journal holds, and need a full sync anyway.
+pub const DEFAULT_MAX_PENDING_OPERATIONS: usize = 10_000;
+
+/// Connections waiting to authenticate kept by default, far more than clients
+/// reconnecting at once after a restart of the server
+pub const DEFAULT_MAX_UNAUTHENTICATED_CONNECTIONS: usize = 1024;
+
+/// Id of the next message received, over every connection of the process
+static NEXT_TRACE_ID: AtomicU64 = AtomicU64::new(1);
 
 /// Server configuration
 #[derive(Debug, Clone)]
 pub struct ServerConfig {
-    pub addr: String,
+    /// Addresses to accept connections on, such as both `0.0.0.0:9000` and `[::]:9000`
+    /// for IPv4 and IPv6
+    pub addrs: Vec<String>,
+    /// Whether the server starts as long as one of `addrs` could be bound. Otherwise,
+    /// failing to bind any of them fails the startup.
+    pub best_effort_bind: bool,
+    /// Messages buffered per user for the slowest of its connections. One that falls
+    /// further behind is caught up from the journal, or resyncs fully, so this only needs
+    /// to cover the bursts of the busiest origin of a user.
     pub broadcast_capacity: usize,
+    /// Connections without any traffic for this long are dropped and their computer
+    /// marked offline; clients ping well within it
+    pub idle_timeout: Duration,
+    /// How often connections are sent websocket pings, which clients answer without
+    /// their application being involved
+    pub ping_interval: Duration,
+    /// Pings in a row a connection may leave unanswered before it is dropped as dead
+    pub max_missed_pongs: u32,
+    /// Connections that did not authenticate this long after being accepted are told
+    /// `AuthTimeout` and dropped
+    pub auth_timeout: Duration,
+    /// Connections not authenticated yet the server keeps at once; those past it are
+    /// closed as soon as accepted. `None` when unlimited.
+    pub max_unauthenticated_connections: Option<usize>,
+    /// File the state is kept in across restarts; `None` keeps it in memory only
+    pub data_path: Option<PathBuf>,
+    /// How often the state is saved to `data_path` when it changed
+    pub persist_interval: Duration,
+    /// Operations kept per folder for backups to catch up on when they reconnect
+    pub journal_capacity: usize,
+    /// Serves `wss://` with this certificate; `None` serves plain `ws://`
+    pub tls: Option<TlsConfig>,
+    /// Secret the HTTP server signs its tokens with; `Authenticate` is refused without
+    /// a valid token for its user
+    pub jwt_secret: Option<String>,
+    /// Accepts any `Authenticate` when no `jwt_secret` is set, for local testing only
+    pub allow_insecure_auth: bool,
+    /// Folder operations each connection may send; `None` does not limit them
+    pub rate_limit: Option<RateLimit>,
+    /// Largest message accepted, in bytes as received; larger ones close the connection
+    pub max_message_bytes: usize,
+    /// Largest file data carried by the operations of one message, in bytes
+    pub max_file_content_bytes: usize,
+    /// Operations a folder may have pending for its backups before the origin is told
+    /// to hold new ones back; `None` does not limit them
+    pub max_pending_operations: Option<usize>,
+    /// Operations left unanswered this long are dropped, and the backups that did not
+    /// answer them marked as needing a full sync; `None` keeps them until answered
+    pub pending_ttl: Option<Duration>,
+    /// How often pending operations are checked against `pending_ttl`, and unused
+    /// content collected
+    pub sweep_interval: Duration,
+    /// Keeps the file content origins send next to `data_path`, for backups to resync
+    /// while their origin is offline; `None` does not keep it
+    pub content_store: Option<ContentLimits>,
+    /// How long connections have to finish the message they are handling on shutdown,
+    /// before they are dropped
+    pub shutdown_grace: Duration,
+    /// Serves the metrics over HTTP on this address; `None` does not serve them
+    pub metrics_addr: Option<String>,
+    /// Bearer token of the admin API served alongside the metrics; `None` disables it
+    pub admin_token: Option<String>,
+    /// State snapshot the server starts from instead of the state in `data_path`, which
+    /// it then replaces
+    pub import_state: Option<PathBuf>,
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
-            addr: "0.0.0.0:9000".to_string(),
+            addrs: vec!["0.0.0.0:9000".to_string()],
+            best_effort_bind: false,
             broadcast_capacity: 100,
+            idle_timeout: Duration::from_secs(90),
+            ping_interval: Duration::from_secs(30),
+            max_missed_pongs: 2,
+            auth_timeout: Duration::from_secs(30),
+            max_unauthenticated_connections: Some(DEFAULT_MAX_UNAUTHENTICATED_CONNECTIONS),
+            data_path: None,
+            persist_interval: Duration::from_secs(1),
+            journal_capacity: DEFAULT_JOURNAL_CAPACITY,
+            tls: None,
+            jwt_secret: None,
+            allow_insecure_auth: false,
+            rate_limit: Some(RateLimit::default()),
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+            max_file_content_bytes: DEFAULT_MAX_FILE_CONTENT_BYTES,
+            max_pending_operations: Some(DEFAULT_MAX_PENDING_OPERATIONS),
+            pending_ttl: Some(Duration::from_secs(24 * 60 * 60)),
+            sweep_interval: Duration::from_secs(60),
+            content_store: None,
+            shutdown_grace: Duration::from_secs(10),
+            metrics_addr: None,
+            admin_token: None,
+            import_state: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    #[must_use]
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            idle_timeout: self.idle_timeout,
+            ping_interval: self.ping_interval,
+            max_missed_pongs: self.max_missed_pongs,
+            auth_timeout: self.auth_timeout,
+            max_unauthenticated: self.max_unauthenticated_connections,
+            max_message_bytes: self.max_message_bytes,
         }
     }
 }
 
 /// Signal sent when server is ready to accept connections
 pub struct ServerReady {
+    /// The first of `addrs`
     pub addr: SocketAddr,
+    /// Every address bound, in the order of `ServerConfig::addrs`, without those that
+    /// failed with `best_effort_bind`
+    pub addrs: Vec<SocketAddr>,
     pub state: Arc<RwLock<ServerState>>,
+    pub broadcasts: Broadcasts,
+    /// Whether connections go through TLS
+    pub tls: bool,
+    /// Address the metrics are served on, when they are
+    pub metrics_addr: Option<SocketAddr>,
 }
 
 /// Run the server accept loop (blocking)
 /// If `ready_tx` is provided, sends the bound address and state once the listener is ready
+///
+/// Once `shutdown` turns true, or its sender is dropped, the server stops accepting
+/// connections and says `Goodbye` to every client. It returns when they are all closed,
+/// or after `ServerConfig::shutdown_grace`, and the state is saved.
 pub async fn run_server(
     config: ServerConfig,
     ready_tx: Option<oneshot::Sender<ServerReady>>,
+    mut shutdown: watch::Receiver<bool>,
 ) -> Result<()> {
-    let listener = TcpListener::bind(&config.addr).await?;
-    let addr = listener.local_addr()?;
-    println!("Backup sync server listening on: {addr}");
+    config.validate()?;
+    if config.jwt_secret.is_none() && !config.allow_insecure_auth {
+        anyhow::bail!("A JWT secret is required, unless insecure authentication is allowed");
+    }
+    let acceptor = config.tls.as_ref().map(TlsConfig::acceptor).transpose()?;
+    let storage: Option<Arc<dyn Storage>> = config
+        .data_path
+        .clone()
+        .map(|path| Arc::new(JsonFileStorage::new(path)) as Arc<dyn Storage>);
+    let persisted = match &storage {
+        Some(storage) => storage.load()?.unwrap_or_default(),
+        None => PersistedState::default(),
+    };
+    let mut state = ServerState::from_persisted(persisted.clone());
+    if let Some(path) = &config.import_state {
+        state
+            .import_snapshot(StateSnapshot::read(path)?)
+            .map_err(anyhow::Error::msg)?;
+        info!(path = ?path, "imported state snapshot");
+    }
+    state.journal = Journal::new(config.journal_capacity);
+    state.token_validator = config.jwt_secret.as_deref().map(TokenValidator::new);
+    state.rate_limit = config.rate_limit;
+    state.max_file_content_bytes = Some(config.max_file_content_bytes);
+    state.max_pending_operations = config.max_pending_operations;
+    if let (Some(limits), Some(data_path)) = (config.content_store, &config.data_path) {
+        let dir = ContentStore::dir_for(data_path);
+        let mut content = ContentStore::open(dir.clone(), limits)?;
+        content.set_max_content_len(config.max_file_content_bytes);
+        info!(dir = ?dir, bytes = content.stored_bytes(), "opened content store");
+        state.content = Some(SharedContent::new(content));
+    }
+    let state = Arc::new(RwLock::new(state));
+    // Stopped once the connections are closed, for the state they leave to be saved
+    let persistence = storage.map(|storage| {
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let task = tokio::spawn(persist(
+            Arc::clone(&state),
+            storage,
+            persisted,
+            config.persist_interval,
+            stop_rx,
+        ));
+        (stop_tx, task)
+    });
+
+    let listeners = bind(&config.addrs, config.best_effort_bind).await?;
+    let addrs = listeners
+        .iter()
+        .map(TcpListener::local_addr)
+        .collect::<std::io::Result<Vec<_>>>()?;
+    for addr in &addrs {
+        info!(%addr, tls = acceptor.is_some(), "listening");
+    }
+
+    let metrics = match &config.metrics_addr {
+        Some(metrics_addr) => {
+            let listener = TcpListener::bind(metrics_addr).await?;
+            let metrics_addr = listener.local_addr()?;
+            info!(addr = %metrics_addr, "serving metrics");
+            let task = tokio::spawn(metrics::serve(
+                listener,
+                Arc::clone(&state),
+                config.admin_token.as_deref().map(Arc::from),
+            ));
+            Some((metrics_addr, task))
+        }
+        None => None,
+    };
+
+    let broadcast_tx = Broadcasts::new(config.broadcast_capacity);
 
-    let state = Arc::new(RwLock::new(ServerState::default()));
-    let (broadcast_tx, _) = broadcast::channel::<BroadcastMessage>(config.broadcast_capacity);
+    let sweeper = (config.pending_ttl.is_some() || config.content_store.is_some()).then(|| {
+        tokio::spawn(sweep(
+            Arc::clone(&state),
+            broadcast_tx.clone(),
+            config.pending_ttl,
+            config.sweep_interval,
+        ))
+    });
 
     // Signal that server is ready
     if let Some(tx) = ready_tx {
         let _ = tx.send(ServerReady {
-            addr,
+            addr: addrs[0],
+            addrs,
             state: Arc::clone(&state),
+            broadcasts: broadcast_tx.clone(),
+            tls: acceptor.is_some(),
+            metrics_addr: metrics.as_ref().map(|(addr, _)| *addr),
         });
     }
 
-    while let Ok((stream, addr)) = listener.accept().await {
+    let mut connections = JoinSet::new();
+    loop {
+        let accepts = listeners.iter().map(|listener| Box::pin(listener.accept()));
+        let (stream, addr) = tokio::select! {
+            (accepted, _, _) = future::select_all(accepts) => match accepted {
+                Ok(accepted) => accepted,
+                Err(_) => break,
+            },
+            // Reaps the tasks of closed connections
+            Some(_) = connections.join_next() => continue,
+            _ = shutdown.wait_for(|&stop| stop) => break,
+        };
         let state = Arc::clone(&state);
         let broadcast_tx = broadcast_tx.clone();
-        tokio::spawn(handle_connection(stream, addr, state, broadcast_tx));
+        // Filled in once the connection authenticates
+        let span = info_span!(
+            "connection",
+            peer = %addr,
+            user_id = field::Empty,
+            computer_id = field::Empty
+        );
+        let acceptor = acceptor.clone();
+        let limits = config.connection_limits();
+        let shutdown = shutdown.clone();
+        // The TLS handshake is done by the connection's task, not to hold up the others
+        connections.spawn(
+            async move {
+                match acceptor {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => {
+                            handle_connection(stream, addr, state, broadcast_tx, limits, shutdown)
+                                .await;
+                        }
+                        Err(e) => warn!(error = %e, "TLS handshake failed"),
+                    },
+                    None => {
+                        handle_connection(stream, addr, state, broadcast_tx, limits, shutdown)
+                            .await;
+                    }
+                }
+            }
+            .instrument(span),
+        );
     }
 
+    drop(listeners);
+    info!(connections = connections.len(), "shutting down");
+    let closed = tokio::time::timeout(config.shutdown_grace, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if closed.is_err() {
+        warn!(
+            connections = connections.len(),
+            grace = ?config.shutdown_grace,
+            "dropping connections still open after the grace period"
+        );
+        connections.shutdown().await;
+    }
+    if let Some((_, task)) = metrics {
+        task.abort();
+    }
+    if let Some(task) = sweeper {
+        task.abort();
+    }
+    if let Some((stop_tx, task)) = persistence {
+        let _ = stop_tx.send(true);
+        let _ = task.await;
+    }
+    info!("server stopped");
     Ok(())
 }
 
-pub async fn handle_connection(
-    stream: TcpStream,
-    addr: SocketAddr,
+/// Binds a listener per address, in order. With `best_effort`, addresses that cannot be
+/// bound are skipped as long as one could be.
+async fn bind(addrs: &[String], best_effort: bool) -> Result<Vec<TcpListener>> {
+    let mut listeners = Vec::with_capacity(addrs.len());
+    let mut failure = None;
+    for addr in addrs {
+        match TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to listen on {addr}"))
+        {
+            Ok(listener) => listeners.push(listener),
+            Err(e) if best_effort => {
+                warn!("skipping address: {e:#}");
+                failure = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    match failure {
+        Some(e) if listeners.is_empty() => Err(e),
+        _ => Ok(listeners),
+    }
+}
+
+/// Saves the state every `interval` when it differs from `saved`, the state in storage,
+/// and the index of the content store when it changed. Failed saves are retried on the
+/// next tick. Once `stop` turns true, the state is saved a last time.
+async fn persist(
     state: Arc<RwLock<ServerState>>,
-    broadcast_tx: BroadcastTx,
+    storage: Arc<dyn Storage>,
+    mut saved: PersistedState,
+    interval: Duration,
+    mut stop: watch::Receiver<bool>,
 ) {
-    println!("New connection from: {addr}");
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut saved_index = None;
+    loop {
+        let stopping = tokio::select! {
+            _ = ticks.tick() => false,
+            _ = stop.wait_for(|&stop| stop) => true,
+        };
+        let current = state.read().await.to_persisted();
+        if current != saved {
+            let storage = Arc::clone(&storage);
+            let to_save = current.clone();
+            match tokio::task::spawn_blocking(move || storage.save(&to_save)).await {
+                Ok(Ok(())) => saved = current,
+                Ok(Err(e)) => error!("failed to save server state: {e:#}"),
+                Err(e) => error!("failed to save server state: {e}"),
+            }
+        }
+        let content = state.read().await.content.clone();
+        let index = content.map(|content| {
+            let content = content.lock();
+            (content.index_path(), content.index().clone())
+        });
+        if let Some((path, index)) = index
+            && saved_index.as_ref() != Some(&index)
+        {
+            let to_save = index.clone();
+            match tokio::task::spawn_blocking(move || to_save.save(&path)).await {
+                Ok(Ok(())) => saved_index = Some(index),
+                Ok(Err(e)) => error!("failed to save content index: {e:#}"),
+                Err(e) => error!("failed to save content index: {e}"),
+            }
+        }
+        if stopping {
+            return;
+        }
+    }
+}
+
+/// Every `interval`, collects the content no folder refers to anymore, and drops the
+/// operations pending for longer than `ttl`, telling the users of their folders the new
+/// status of those folders
+async fn sweep(
+    state: Arc<RwLock<ServerState>>,
+    broadcast_tx: Broadcasts,
+    ttl: Option<Duration>,
+    interval: Duration,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let now = unix_now();
+        // Removing files holds up the store only
+        let content = state.read().await.content.clone();
+        let collected = content.map_or(0, |content| content.lock().collect_garbage(now));
+        if collected > 0 {
+            info!(blobs = collected, "collected unused content");
+            let state_read = state.read().await;
+            state_read
+                .metrics
+                .blobs_collected
+                .fetch_add(collected as u64, Ordering::Relaxed);
+        }
+        let Some(ttl) = ttl else {
+            continue;
+        };
+        let mut state_write = state.write().await;
+        let started_before = now.saturating_sub(ttl.as_secs() as i64);
+        let expired = state_write.expire_operations(started_before);
+        if expired.is_empty() {
+            continue;
+        }
+        let folders: BTreeSet<(UserId, FolderId)> = expired
+            .iter()
+            .map(|operation| (operation.user_id.clone(), operation.folder_id.clone()))
+            .collect();
+        for (user_id, folder_id) in folders {
+            let operations = expired
+                .iter()
+                .filter(|operation| operation.folder_id == folder_id)
+                .count();
+            warn!(%folder_id, operations, "expired operations left unanswered");
+            if let Some(status) = state_write.sync_status(&user_id, &folder_id) {
+                broadcast_tx.send(BroadcastMessage {
+                    user_id,
+                    message: status,
+                    audience: Audience::User { except: None },
+                });
+            }
+        }
+    }
+}
 
-    let ws_stream = match tokio_tungstenite::accept_async(stream).await {
-        Ok(ws) => ws,
-        Err(e) => {
-            eprintln!("WebSocket handshake failed for {addr}: {e}");
+/// Bounds of a single connection, from the `ServerConfig`
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionLimits {
+    pub idle_timeout: Duration,
+    pub ping_interval: Duration,
+    pub max_missed_pongs: u32,
+    pub auth_timeout: Duration,
+    pub max_unauthenticated: Option<usize>,
+    pub max_message_bytes: usize,
+}
+
+impl ConnectionLimits {
+    /// Configuration of the websocket of a connection. Messages over the limit are
+    /// refused as they are read, before being buffered whole.
+    #[must_use]
+    pub fn websocket(&self) -> WebSocketConfig {
+        WebSocketConfig::default()
+            .max_message_size(Some(self.max_message_bytes))
+            .max_frame_size(Some(self.max_message_bytes))
+    }
+}
+
+pub async fn handle_connection<S>(
+    stream: S,
+    addr: SocketAddr,
+    state: Arc<RwLock<ServerState>>,
+    broadcast_tx: Broadcasts,
+    limits: ConnectionLimits,
+    shutdown: watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    info!("new connection");
+    // Counted from the accept, for the handshake not to stall it either
+    let auth_deadline = Instant::now() + limits.auth_timeout;
+
+    let handshake = tokio_tungstenite::accept_async_with_config(stream, Some(limits.websocket()));
+    let ws_stream = match tokio::time::timeout_at(auth_deadline, handshake).await {
+        Ok(Ok(ws)) => ws,
+        Ok(Err(e)) => {
+            warn!(error = %e, "websocket handshake failed");
+            return;
+        }
+        Err(_) => {
+            info!("closing connection stuck in the websocket handshake");
             return;
         }
     };
+    serve_websocket(
+        ws_stream,
+        addr,
+        state,
+        broadcast_tx,
+        limits,
+        shutdown,
+        auth_deadline,
+    )
+    .await;
+}
 
+/// Serves a connection past its websocket handshake, such as one upgraded by another
+/// HTTP server, until it closes. It has until `auth_deadline` to authenticate.
+pub async fn serve_websocket<S>(
+    ws_stream: WebSocketStream<S>,
+    addr: SocketAddr,
+    state: Arc<RwLock<ServerState>>,
+    broadcast_tx: Broadcasts,
+    limits: ConnectionLimits,
+    mut shutdown: watch::Receiver<bool>,
+    auth_deadline: Instant,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
-    let mut broadcast_rx = broadcast_tx.subscribe();
+    // Subscribed to the broadcasts of its user once authenticated
+    let mut subscription = None;
 
-    // Register connection
-    state.write().await.register_connection(addr);
-
-    let welcome = ServerMessage::Welcome;
-    if let Ok(json) = serde_json::to_string(&welcome) {
-        let _ = ws_sender.send(Message::Text(json.into())).await;
+    // Counted and registered under the same lock, for a burst of connections to stay
+    // within the limit
+    let refused = {
+        let mut state_write = state.write().await;
+        let refused = limits
+            .max_unauthenticated
+            .is_some_and(|max| state_write.unauthenticated_connections() >= max);
+        if !refused {
+            state_write.register_connection(addr);
+        }
+        refused
+    };
+    if refused {
+        warn!("refusing connection, too many are waiting to authenticate");
+        let close = CloseFrame {
+            code: CloseCode::Again,
+            reason: "Too many connections waiting to authenticate".into(),
+        };
+        let _ = ws_sender.send(Message::Close(Some(close))).await;
+        return;
     }
 
+    let welcome = ServerMessage::Welcome {
+        protocol_version: PROTOCOL_VERSION,
+        min_supported: MIN_SUPPORTED_VERSION,
+    };
+    let _ = send_response(&mut ws_sender, &welcome, Encoding::Json).await;
+
+    let idle_timeout = limits.idle_timeout;
+    let idle = tokio::time::sleep(idle_timeout);
+    tokio::pin!(idle);
+    // Detects half-open connections, of clients that crashed or went to sleep, which
+    // neither send anything nor fail the reads
+    let mut pings =
+        tokio::time::interval_at(Instant::now() + limits.ping_interval, limits.ping_interval);
+    pings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut missed_pongs = 0;
+    let auth_timer = tokio::time::sleep_until(auth_deadline);
+    tokio::pin!(auth_timer);
+    // Sequence of the last operation of each folder broadcast to this connection, from
+    // where it is caught up if it falls behind
+    let mut delivered = HashMap::new();
+
     loop {
         tokio::select! {
             msg = ws_receiver.next() => {
+                // Any frame counts, including websocket pings
+                if let Some(Ok(_)) = msg {
+                    idle.as_mut().reset(Instant::now() + idle_timeout);
+                }
                 match msg {
+                    Some(Ok(Message::Pong(_))) => missed_pongs = 0,
                     Some(Ok(Message::Text(text))) => {
-                        match serde_json::from_str::<ClientMessage>(&text) {
-                            Ok(client_msg) => {
-                                match handle_message(client_msg, addr, &state, &broadcast_tx).await {
-                                    Ok(HandlerResponse::Send(response)) => {
-                                        if let Err(e) = send_response(&mut ws_sender, &response).await {
-                                            eprintln!("Error sending response to {addr}: {e}");
-                                        }
-                                    }
-                                    Ok(HandlerResponse::Broadcast { response, broadcast }) => {
-                                        if let Err(e) = send_response(&mut ws_sender, &response).await {
-                                            eprintln!("Error sending response to {addr}: {e}");
-                                        }
-                                        let _ = broadcast_tx.send(broadcast);
-                                    }
-                                    Ok(HandlerResponse::None) => {}
-                                    Err(e) => {
-                                        eprintln!("Error handling message from {addr}: {e}");
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                eprintln!("Failed to parse message from {addr}: {e}");
-                            }
+                        let decoded = codec::decode_text::<ClientMessage>(&text);
+                        if !handle_frame(decoded, addr, &state, &broadcast_tx, &mut ws_sender, &mut subscription).await {
+                            handle_disconnect(addr, &state, &broadcast_tx).await;
+                            break;
+                        }
+                    }
+                    Some(Ok(Message::Binary(bytes))) => {
+                        let decoded = codec::decode_binary::<ClientMessage>(&bytes);
+                        if !handle_frame(decoded, addr, &state, &broadcast_tx, &mut ws_sender, &mut subscription).await {
+                            handle_disconnect(addr, &state, &broadcast_tx).await;
+                            break;
                         }
                     }
                     Some(Ok(Message::Close(_))) | None => {
-                        println!("Client {addr} disconnected");
-                        handle_disconnect(addr, &state).await;
+                        info!("client disconnected");
+                        handle_disconnect(addr, &state, &broadcast_tx).await;
+                        break;
+                    }
+                    Some(Err(WsError::Capacity(CapacityError::MessageTooLong { size, max_size }))) => {
+                        warn!(size, max_size, "closing connection sending a too large message");
+                        let encoding = {
+                            let state_read = state.read().await;
+                            state_read.metrics.record_error("PayloadTooLarge");
+                            state_read.encoding(&addr)
+                        };
+                        handle_disconnect(addr, &state, &broadcast_tx).await;
+                        close_too_large(&mut ws_sender, encoding, size, max_size).await;
                         break;
                     }
                     Some(Err(e)) => {
-                        eprintln!("WebSocket error from {addr}: {e}");
-                        handle_disconnect(addr, &state).await;
+                        warn!(error = %e, "websocket error");
+                        handle_disconnect(addr, &state, &broadcast_tx).await;
                         break;
                     }
                     _ => {}
                 }
             }
-            Ok(broadcast_msg) = broadcast_rx.recv() => {
+            received = next_broadcast(&mut subscription) => {
+                let broadcast_msg = match received {
+                    Ok(broadcast_msg) => broadcast_msg,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "connection fell behind broadcasts, catching it up");
+                        let (messages, encoding) = {
+                            let mut state_write = state.write().await;
+                            state_write.metrics.broadcast_lagged.fetch_add(1, Ordering::Relaxed);
+                            (state_write.catch_up_lagged(&addr, &delivered), state_write.encoding(&addr))
+                        };
+                        for message in &messages {
+                            let _ = send_response(&mut ws_sender, message, encoding).await;
+                        }
+                        continue;
+                    }
+                    // The channel lives as long as any subscription to it
+                    Err(RecvError::Closed) => continue,
+                };
                 // Check if this connection should receive this folder's messages
-                let should_receive = {
+                let (should_receive, encoding, superseded) = {
                     let state_read = state.read().await;
-                    state_read.should_receive_broadcast(&addr, &broadcast_msg.folder_id)
+                    (
+                        state_read.should_receive_broadcast(&addr, &broadcast_msg),
+                        state_read.encoding(&addr),
+                        state_read.get_connection(&addr).is_none(),
+                    )
                 };
+                // Its computer authenticated on a newer connection, which removed this
+                // one from the state: closing it must not mark the computer offline
+                if superseded {
+                    info!("closing superseded connection");
+                    let _ = send_response(&mut ws_sender, &ServerMessage::Superseded, encoding).await;
+                    let _ = ws_sender.send(Message::Close(None)).await;
+                    break;
+                }
                 if should_receive {
-                    let _ = ws_sender.send(Message::Text(broadcast_msg.message.into())).await;
+                    if let (
+                        Audience::FolderBackups { folder_id },
+                        ServerMessage::FolderOperation { sequence, .. }
+                        | ServerMessage::FolderOperationBatch { sequence, .. },
+                    ) = (&broadcast_msg.audience, &broadcast_msg.message)
+                    {
+                        delivered.insert(folder_id.clone(), *sequence);
+                    }
+                    let _ = send_response(&mut ws_sender, &broadcast_msg.message, encoding).await;
+                }
+            }
+            // Handlers in flight are done, as the loop only gets here between messages.
+            // The state is kept as is, operations still waiting for their backups. The
+            // borrow of the flag is dropped right away, for the task to stay `Send`.
+            _ = shutdown.wait_for(|&stop| stop).map(drop) => {
+                info!("closing connection on shutdown");
+                let encoding = state.read().await.encoding(&addr);
+                let _ = send_response(&mut ws_sender, &ServerMessage::Goodbye, encoding).await;
+                let close = CloseFrame {
+                    code: CloseCode::Away,
+                    reason: "Server shutting down".into(),
+                };
+                let _ = ws_sender.send(Message::Close(Some(close))).await;
+                break;
+            }
+            _ = pings.tick() => {
+                if missed_pongs >= limits.max_missed_pongs {
+                    info!(missed_pongs, "closing unresponsive connection");
+                    handle_disconnect(addr, &state, &broadcast_tx).await;
+                    break;
+                }
+                missed_pongs += 1;
+                // Writes to a dead peer block once the socket buffer is full
+                let ping = ws_sender.send(Message::Ping(Bytes::new()));
+                if !matches!(tokio::time::timeout(limits.ping_interval, ping).await, Ok(Ok(()))) {
+                    info!("closing connection failing to take a ping");
+                    handle_disconnect(addr, &state, &broadcast_tx).await;
+                    break;
+                }
+            }
+            // Only armed until the connection authenticates, which subscribes it
+            () = &mut auth_timer, if subscription.is_none() => {
+                info!(auth_timeout = ?limits.auth_timeout, "closing connection that did not authenticate");
+                let encoding = {
+                    let state_read = state.read().await;
+                    state_read.metrics.record_error("AuthTimeout");
+                    state_read.encoding(&addr)
+                };
+                handle_disconnect(addr, &state, &broadcast_tx).await;
+                let timeout = ServerMessage::AuthTimeout {
+                    timeout_secs: limits.auth_timeout.as_secs(),
+                };
+                let _ = send_response(&mut ws_sender, &timeout, encoding).await;
+                let close = CloseFrame {
+                    code: CloseCode::Policy,
+                    reason: "Authentication timed out".into(),
+                };
+                let _ = ws_sender.send(Message::Close(Some(close))).await;
+                break;
+            }
+            () = &mut idle => {
+                info!(?idle_timeout, "closing idle connection");
+                handle_disconnect(addr, &state, &broadcast_tx).await;
+                let _ = ws_sender.send(Message::Close(None)).await;
+                break;
+            }
+        }
+    }
+}
+
+/// Tells a client its last message was over the size limit, then closes its connection
+async fn close_too_large<S: AsyncRead + AsyncWrite + Unpin>(
+    ws_sender: &mut WsSender<S>,
+    encoding: Encoding,
+    size: usize,
+    max_size: usize,
+) {
+    let too_large = ServerMessage::PayloadTooLarge {
+        size: size as u64,
+        limit: max_size as u64,
+        reason: format!(
+            "Message of {size} bytes is over the limit of {max_size} bytes; send file content with StartTransfer and FileChunk instead"
+        ),
+    };
+    let _ = send_response(ws_sender, &too_large, encoding).await;
+    let close = CloseFrame {
+        code: CloseCode::Size,
+        reason: "Message too large".into(),
+    };
+    let _ = ws_sender.send(Message::Close(Some(close))).await;
+}
+
+/// Next broadcast for a connection, never for one not authenticated yet
+async fn next_broadcast(
+    subscription: &mut Option<Subscription>,
+) -> Result<BroadcastMessage, RecvError> {
+    match subscription {
+        Some(subscription) => subscription.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Name of the variant of a message, for logs
+fn message_kind(message: &ClientMessage) -> &'static str {
+    match message {
+        ClientMessage::Hello { .. } => "Hello",
+        ClientMessage::Authenticate { .. } => "Authenticate",
+        ClientMessage::RegisterComputer { .. } => "RegisterComputer",
+        ClientMessage::RemoveComputer { .. } => "RemoveComputer",
+        ClientMessage::CreateSyncFolder { .. } => "CreateSyncFolder",
+        ClientMessage::JoinSyncFolder { .. } => "JoinSyncFolder",
+        ClientMessage::LeaveSyncFolder { .. } => "LeaveSyncFolder",
+        ClientMessage::DeleteSyncFolder { .. } => "DeleteSyncFolder",
+        ClientMessage::RenameSyncFolder { .. } => "RenameSyncFolder",
+        ClientMessage::RequestOriginSwitch { .. } => "RequestOriginSwitch",
+        ClientMessage::FolderOperation { .. } => "FolderOperation",
+        ClientMessage::FolderOperationBatch { .. } => "FolderOperationBatch",
+        ClientMessage::TargetedOperation { .. } => "TargetedOperation",
+        ClientMessage::RequestSignature { .. } => "RequestSignature",
+        ClientMessage::SignatureResponse { .. } => "SignatureResponse",
+        ClientMessage::RequestManifest { .. } => "RequestManifest",
+        ClientMessage::Manifest { .. } => "Manifest",
+        ClientMessage::Ack { .. } => "Ack",
+        ClientMessage::Nack { .. } => "Nack",
+        ClientMessage::GetFolderSequence { .. } => "GetFolderSequence",
+        ClientMessage::CatchUp { .. } => "CatchUp",
+        ClientMessage::RequestFullSync { .. } => "RequestFullSync",
+        ClientMessage::FetchContent { .. } => "FetchContent",
+        ClientMessage::GetUserState => "GetUserState",
+        ClientMessage::ListFolders => "ListFolders",
+        ClientMessage::Ping { .. } => "Ping",
+        ClientMessage::Request { .. } => "Request",
+    }
+}
+
+/// Folder a message is about, for logs
+fn message_folder(message: &ClientMessage) -> Option<&FolderId> {
+    match message {
+        ClientMessage::JoinSyncFolder { folder_id }
+        | ClientMessage::LeaveSyncFolder { folder_id }
+        | ClientMessage::DeleteSyncFolder { folder_id }
+        | ClientMessage::RenameSyncFolder { folder_id, .. }
+        | ClientMessage::RequestOriginSwitch { folder_id }
+        | ClientMessage::FolderOperation { folder_id, .. }
+        | ClientMessage::FolderOperationBatch { folder_id, .. }
+        | ClientMessage::TargetedOperation { folder_id, .. }
+        | ClientMessage::RequestSignature { folder_id, .. }
+        | ClientMessage::SignatureResponse { folder_id, .. }
+        | ClientMessage::RequestManifest { folder_id }
+        | ClientMessage::Manifest { folder_id, .. }
+        | ClientMessage::GetFolderSequence { folder_id }
+        | ClientMessage::CatchUp { folder_id, .. }
+        | ClientMessage::RequestFullSync { folder_id }
+        | ClientMessage::FetchContent { folder_id, .. } => Some(folder_id),
+        _ => None,
+    }
+}
+
+type WsSender<S> = futures_util::stream::SplitSink<WebSocketStream<S>, Message>;
+
+/// Handles one decoded frame and sends the response in the connection's encoding.
+/// Returns `false` once the connection was closed.
+async fn handle_frame<S: AsyncRead + AsyncWrite + Unpin>(
+    decoded: Result<ClientMessage, codec::CodecError>,
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+    broadcast_tx: &Broadcasts,
+    ws_sender: &mut WsSender<S>,
+    subscription: &mut Option<Subscription>,
+) -> bool {
+    // The id of a request is echoed on its response, whatever the outcome
+    let (request_id, decoded) = match decoded {
+        Ok(ClientMessage::Request {
+            request_id,
+            message,
+        }) => (Some(request_id), Ok(*message)),
+        decoded => (None, decoded),
+    };
+    let kind = decoded.as_ref().ok().map(message_kind);
+    // Everything logged about the message carries its id, which an error answering it
+    // quotes for the user to report
+    let trace_id = NEXT_TRACE_ID.fetch_add(1, Ordering::Relaxed);
+    let span = info_span!("message", trace_id);
+    if let Ok(message) = &decoded {
+        debug!(
+            parent: &span,
+            kind,
+            folder_id = message_folder(message).map(field::display),
+            request_id,
+            "received message"
+        );
+    }
+    let mut replay = Vec::new();
+    let mut resumed = Vec::new();
+    let (mut response, broadcast, close) = match decoded {
+        Ok(client_msg) => match handle_message(client_msg, addr, state, broadcast_tx)
+            .instrument(span.clone())
+            .await
+        {
+            Ok(HandlerResponse::Send(response)) => (response, None, false),
+            Ok(HandlerResponse::Broadcast {
+                response,
+                broadcast,
+            }) => (response, Some(broadcast), false),
+            Ok(HandlerResponse::Close(response)) => (response, None, true),
+            Ok(HandlerResponse::Replay { messages, response }) => {
+                replay = messages;
+                (response, None, false)
+            }
+            Ok(HandlerResponse::Resume {
+                response,
+                messages,
+                broadcast,
+            }) => {
+                resumed = messages;
+                (response, Some(broadcast), false)
+            }
+            Ok(HandlerResponse::None) => return true,
+            Err(e) => {
+                // Only requests wait for an answer
+                if request_id.is_none() {
+                    warn!(parent: &span, kind, error = %e, "failed to handle message");
+                    return true;
                 }
+                let response = ServerMessage::Error {
+                    message: e.to_string(),
+                    trace_id: None,
+                };
+                (response, None, false)
+            }
+        },
+        Err(e) => match e.invalid_path() {
+            Some(reason) => (ServerMessage::PathRejected { reason }, None, false),
+            // Usually a newer peer using a message or operation this
+            // server does not know; say so instead of dropping it
+            None => {
+                let response = ServerMessage::Error {
+                    message: format!("Unsupported message: {e}"),
+                    trace_id: None,
+                };
+                (response, None, false)
             }
+        },
+    };
+    if let Some(error) = metrics::error_kind(&response) {
+        state.read().await.metrics.record_error(error);
+    }
+    match &mut response {
+        ServerMessage::Error {
+            message,
+            trace_id: id,
+        } => {
+            *id = Some(trace_id);
+            warn!(parent: &span, kind, error = %message, "answering with an error");
         }
+        ServerMessage::PathRejected { reason } => {
+            warn!(parent: &span, kind, %reason, "rejecting path");
+        }
+        _ => {}
     }
+    let response = match request_id {
+        Some(request_id) => ServerMessage::Response {
+            request_id,
+            message: Box::new(response),
+        },
+        None => response,
+    };
+    let encoding = {
+        let state_read = state.read().await;
+        if subscription.is_none()
+            && let Some(conn) = state_read.get_connection(&addr)
+            && let (Some(user_id), Some(computer_id)) = (&conn.user_id, &conn.computer_id)
+        {
+            *subscription = Some(broadcast_tx.subscribe(user_id));
+            let span = Span::current();
+            span.record("user_id", field::display(user_id));
+            span.record("computer_id", field::display(computer_id));
+        }
+        state_read.encoding(&addr)
+    };
+    // Before anything broadcast meanwhile, which is only read after this frame
+    for message in &replay {
+        if let Err(e) = send_response(ws_sender, message, encoding).await {
+            warn!(error = %e, "failed to replay operation");
+        }
+    }
+    if let Err(e) = send_response(ws_sender, &response, encoding).await {
+        warn!(error = %e, "failed to send response");
+    }
+    for message in &resumed {
+        if let Err(e) = send_response(ws_sender, message, encoding).await {
+            warn!(error = %e, "failed to replay operation");
+        }
+    }
+    if let Some(broadcast) = broadcast {
+        broadcast_tx.send(broadcast);
+    }
+    if close {
+        debug!("closing connection");
+        let _ = ws_sender.send(Message::Close(None)).await;
+    }
+    !close
 }
 
-pub async fn send_response(
-    ws_sender: &mut futures_util::stream::SplitSink<
-        tokio_tungstenite::WebSocketStream<TcpStream>,
-        Message,
-    >,
+pub async fn send_response<S: AsyncRead + AsyncWrite + Unpin>(
+    ws_sender: &mut WsSender<S>,
     response: &ServerMessage,
+    encoding: Encoding,
 ) -> Result<()> {
-    let json = serde_json::to_string(response)?;
-    ws_sender.send(Message::Text(json.into())).await?;
+    let message = match codec::encode(response, encoding)? {
+        Frame::Text(text) => Message::Text(text.into()),
+        Frame::Binary(bytes) => Message::Binary(bytes.into()),
+    };
+    ws_sender.send(message).await?;
     Ok(())
 }
@@ -1,30 +1,197 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
-
-use backup_sync_protocol::{Computer, ComputerId, FolderId, SyncFolder, User, UserId};
-
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use backup_sync_protocol::{
+    Computer, ComputerId, Encoding, FolderId, ServerMessage, SyncFolder, User, UserId,
+};
+use tokio::sync::mpsc;
+
+use crate::admin::{ConnectionSummary, FolderSummary, StuckOperation, UserSummary};
+use crate::auth::TokenValidator;
+use crate::content::SharedContent;
+use crate::journal::Journal;
+use crate::metrics::{Counters, MetricsSnapshot};
+use crate::rate_limit::{RateLimit, TokenBucket};
+use crate::storage::{PersistedState, SNAPSHOT_SCHEMA_VERSION, StateSnapshot};
+
+/// A message for other connections of a user, encoded by each connection in its own
+/// encoding
 #[derive(Debug, Clone)]
 pub struct BroadcastMessage {
+    /// User whose channel carries the message
+    pub user_id: UserId,
+    pub message: ServerMessage,
+    pub audience: Audience,
+}
+
+/// Connections of the user a broadcast is delivered to
+#[derive(Debug, Clone)]
+pub enum Audience {
+    /// Backups of the folder at the time of delivery
+    FolderBackups { folder_id: FolderId },
+    /// Given computers, for folders that no longer exist
+    Computers { computer_ids: Vec<ComputerId> },
+    /// Every computer, except the connection that already got a response
+    User { except: Option<SocketAddr> },
+    /// A single connection, found with [`ServerState::connection_of`]
+    Connection { addr: SocketAddr },
+}
+
+/// Why a computer cannot be removed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RemoveComputerError {
+    NotFound,
+    /// The computer is the origin of these folders, which need another origin first
+    OriginOf(Vec<FolderId>),
+}
+
+/// What an ack changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Acked {
+    /// Other backups have yet to ack the operation
+    Pending { folder_id: FolderId },
+    /// The ack was the last one the operation waited for; its origin is to be told
+    Complete {
+        folder_id: FolderId,
+        origin: ComputerId,
+    },
+}
+
+/// What the disconnection of a computer changed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Disconnected {
+    /// Operations that only waited for the computer anymore, with their folder and origin
+    pub completed: Vec<(FolderId, u64, ComputerId)>,
+    /// Folders the computer backs up that it left with operations unanswered
+    pub stale_folders: Vec<FolderId>,
+}
+
+/// An operation dropped by [`ServerState::expire_operations`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpiredOperation {
+    pub user_id: UserId,
     pub folder_id: FolderId,
-    pub message: String,
+    pub operation_id: u64,
+    /// Backups that never answered it, sorted; each needs a full sync of the folder
+    pub awaiting: Vec<ComputerId>,
+}
+
+/// A change clients made to the computers and folders of their user, for a store kept
+/// besides the state, such as the database of the HTTP server, to follow
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirectoryChange {
+    ComputerRegistered {
+        user_id: UserId,
+        computer: Computer,
+    },
+    ComputerRemoved {
+        user_id: UserId,
+        computer_id: ComputerId,
+    },
+    FolderCreated {
+        user_id: UserId,
+        folder: SyncFolder,
+    },
+    FolderJoined {
+        user_id: UserId,
+        folder_id: FolderId,
+        computer_id: ComputerId,
+    },
+    FolderLeft {
+        user_id: UserId,
+        folder_id: FolderId,
+        computer_id: ComputerId,
+    },
+    FolderDeleted {
+        user_id: UserId,
+        folder_id: FolderId,
+    },
+    FolderRenamed {
+        user_id: UserId,
+        folder_id: FolderId,
+        name: String,
+    },
+    OriginSwitched {
+        user_id: UserId,
+        folder_id: FolderId,
+        new_origin: ComputerId,
+    },
 }
 
+/// Longest folder name accepted, in characters
+pub const MAX_FOLDER_NAME_LEN: usize = 255;
+
 #[derive(Debug)]
 pub struct ConnectedClient {
     pub user_id: Option<UserId>,
     pub computer_id: Option<ComputerId>,
     pub addr: SocketAddr,
+    /// Encoding of the messages sent to this connection
+    pub encoding: Encoding,
+    /// Sequence up to which the operations of each folder were replayed to this
+    /// connection; broadcasts of those operations are not sent to it again
+    pub replayed: HashMap<FolderId, u64>,
+    /// Limits the folder operations of this connection; `None` when unlimited. Locked on
+    /// its own, for counting an operation to only read the state.
+    pub rate_limiter: Option<Mutex<TokenBucket>>,
+    /// Unix time the connection was accepted at
+    pub connected_at: i64,
+}
+
+impl ConnectedClient {
+    /// Whether `message` is an operation this connection already got from a catch up
+    #[must_use]
+    pub fn was_replayed(&self, folder_id: &FolderId, message: &ServerMessage) -> bool {
+        let sequence = match message {
+            ServerMessage::FolderOperation { sequence, .. }
+            | ServerMessage::FolderOperationBatch { sequence, .. } => *sequence,
+            _ => return false,
+        };
+        self.replayed
+            .get(folder_id)
+            .is_some_and(|replayed| sequence <= *replayed)
+    }
 }
 
+/// State of the server, shared by the connections behind one lock. Handlers changing
+/// users, folders or operations take it for writing, whichever folder they change; only
+/// the content store, the rate limiters and the counters are locked on their own.
 #[derive(Debug, Default)]
 pub struct ServerState {
     pub users: HashMap<UserId, User>,
     pub connections: HashMap<SocketAddr, ConnectedClient>,
     /// Maps (`user_id`, `computer_id`) to socket address for routing
     pub computer_connections: HashMap<(UserId, ComputerId), SocketAddr>,
-    /// Pending operations per folder: `folder_id` -> (`operation_id`, `pending_acks`)
-    pub pending_operations: HashMap<FolderId, HashMap<u64, usize>>,
+    /// Pending operations per folder: `folder_id` -> (`operation_id`, backups that have
+    /// not acked or nacked it yet)
+    pub pending_operations: HashMap<FolderId, HashMap<u64, HashSet<ComputerId>>>,
+    /// Unix time each pending operation was forwarded at; operations restored from
+    /// storage count from the restart
+    pub operation_started: HashMap<u64, i64>,
     pub operation_counter: u64,
+    /// Sequence number of the last operation of each folder
+    pub folder_sequences: HashMap<FolderId, u64>,
+    /// Operations backups may still need to catch up on; kept in memory only
+    pub journal: Journal,
+    /// Checks the tokens of `Authenticate`; `None` accepts any
+    pub token_validator: Option<TokenValidator>,
+    /// Rate limit of the folder operations of each connection; `None` when unlimited
+    pub rate_limit: Option<RateLimit>,
+    /// Counters since startup
+    pub metrics: Counters,
+    /// Largest file data accepted in the operations of one message; `None` when unlimited
+    pub max_file_content_bytes: Option<usize>,
+    /// Operations a folder may have pending before new ones are refused with
+    /// `Backpressure`; `None` when unlimited
+    pub max_pending_operations: Option<usize>,
+    /// Content of the folders, for backups to resync while their origin is offline;
+    /// `None` when not stored
+    pub content: Option<SharedContent>,
+    /// Told of every [`DirectoryChange`], in the order they are made; `None` tells no one
+    pub directory_changes: Option<mpsc::UnboundedSender<DirectoryChange>>,
 }
 
 impl ServerState {
@@ -33,20 +200,202 @@ impl ServerState {
         Self::default()
     }
 
+    /// State restored from storage, without any connection
+    #[must_use]
+    pub fn from_persisted(persisted: PersistedState) -> Self {
+        let now = unix_now();
+        let operation_started = persisted
+            .pending_operations
+            .values()
+            .flat_map(|operations| operations.keys())
+            .map(|operation_id| (*operation_id, now))
+            .collect();
+        Self {
+            users: persisted.users.into_iter().collect(),
+            pending_operations: persisted
+                .pending_operations
+                .into_iter()
+                .map(|(folder_id, operations)| {
+                    let operations = operations
+                        .into_iter()
+                        .map(|(operation_id, awaiting)| {
+                            (operation_id, awaiting.into_iter().collect())
+                        })
+                        .collect();
+                    (folder_id, operations)
+                })
+                .collect(),
+            operation_started,
+            operation_counter: persisted.operation_counter,
+            folder_sequences: persisted.folder_sequences.into_iter().collect(),
+            ..Self::default()
+        }
+    }
+
+    /// The part of the state to keep across restarts. Computers are saved offline, as
+    /// they are after a restart; connecting them does not change what is saved.
+    #[must_use]
+    pub fn to_persisted(&self) -> PersistedState {
+        PersistedState {
+            users: self
+                .users
+                .iter()
+                .map(|(user_id, user)| {
+                    let mut user = user.clone();
+                    for computer in &mut user.computers {
+                        computer.online = false;
+                    }
+                    (user_id.clone(), user)
+                })
+                .collect(),
+            pending_operations: self
+                .pending_operations
+                .iter()
+                .map(|(folder_id, operations)| {
+                    let operations = operations
+                        .iter()
+                        .map(|(operation_id, awaiting)| {
+                            (*operation_id, awaiting.iter().cloned().collect())
+                        })
+                        .collect();
+                    (folder_id.clone(), operations)
+                })
+                .collect(),
+            operation_counter: self.operation_counter,
+            folder_sequences: self
+                .folder_sequences
+                .iter()
+                .map(|(folder_id, sequence)| (folder_id.clone(), *sequence))
+                .collect(),
+        }
+    }
+
+    /// The persisted part of the state, to be written to a file
+    #[must_use]
+    pub fn export_snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            schema_version: SNAPSHOT_SCHEMA_VERSION,
+            exported_at: unix_now(),
+            state: self.to_persisted(),
+        }
+    }
+
+    /// Replaces users, folders and the bookkeeping of operations with those of a
+    /// snapshot. Connections and limits are kept; the journal is emptied, its operations
+    /// being those of the replaced state.
+    pub fn import_snapshot(&mut self, snapshot: StateSnapshot) -> Result<(), &'static str> {
+        if snapshot.schema_version != SNAPSHOT_SCHEMA_VERSION {
+            return Err("Unsupported snapshot schema version");
+        }
+        let imported = Self::from_persisted(snapshot.state);
+        self.users = imported.users;
+        self.pending_operations = imported.pending_operations;
+        self.operation_started = imported.operation_started;
+        self.operation_counter = imported.operation_counter;
+        self.folder_sequences = imported.folder_sequences;
+        self.journal = Journal::new(self.journal.capacity());
+        Ok(())
+    }
+
+    /// Operations of a folder some backup has yet to answer
+    #[must_use]
+    pub fn pending_count(&self, folder_id: &FolderId) -> usize {
+        self.pending_operations
+            .get(folder_id)
+            .map_or(0, HashMap::len)
+    }
+
+    /// Whether a folder has as many operations pending as the server holds, new ones
+    /// being refused until its backups answer some
+    #[must_use]
+    pub fn is_backpressured(&self, folder_id: &FolderId) -> bool {
+        self.max_pending_operations
+            .is_some_and(|limit| self.pending_count(folder_id) >= limit)
+    }
+
+    /// `SyncStatusChanged` with the current status of a folder
+    #[must_use]
+    pub fn sync_status(&self, user_id: &UserId, folder_id: &FolderId) -> Option<ServerMessage> {
+        let folder = self.get_folder(user_id, folder_id)?;
+        Some(ServerMessage::SyncStatusChanged {
+            folder_id: folder_id.clone(),
+            is_synced: folder.is_synced,
+            pending_operations: folder.pending_operations,
+            backups: folder.backup_status.clone(),
+            backpressure: self.is_backpressured(folder_id),
+        })
+    }
+
     pub fn next_operation_id(&mut self) -> u64 {
         self.operation_counter += 1;
         self.operation_counter
     }
 
+    /// Assigns the next sequence number of a folder
+    pub fn next_folder_sequence(&mut self, folder_id: &FolderId) -> u64 {
+        let sequence = self.folder_sequences.entry(folder_id.clone()).or_default();
+        *sequence += 1;
+        *sequence
+    }
+
+    /// Sequence number of the last operation of a folder, 0 before the first one
+    #[must_use]
+    pub fn folder_sequence(&self, folder_id: &FolderId) -> u64 {
+        self.folder_sequences.get(folder_id).copied().unwrap_or(0)
+    }
+
     pub fn get_or_create_user(&mut self, user_id: &UserId) -> &mut User {
         self.users.entry(user_id.clone()).or_insert_with(|| User {
             id: user_id.clone(),
-            name: user_id.clone(),
+            name: user_id.to_string(),
             computers: Vec::new(),
             sync_folders: Vec::new(),
         })
     }
 
+    /// Replaces the computers and folders of a user with those of `user`, read from the
+    /// store kept besides the state. What only the state knows of them is kept: which
+    /// computers are connected, and how far behind the backups of each folder are.
+    pub fn sync_user(&mut self, user: User) {
+        let known = self.get_or_create_user(&user.id);
+        let online: HashSet<ComputerId> = known
+            .computers
+            .iter()
+            .filter(|computer| computer.online)
+            .map(|computer| computer.id.clone())
+            .collect();
+        let mut progress: HashMap<FolderId, SyncFolder> = known
+            .sync_folders
+            .drain(..)
+            .map(|folder| (folder.id.clone(), folder))
+            .collect();
+
+        known.name = user.name;
+        known.computers = user.computers;
+        for computer in &mut known.computers {
+            computer.online = online.contains(&computer.id);
+        }
+        known.sync_folders = user.sync_folders;
+        for folder in &mut known.sync_folders {
+            if let Some(previous) = progress.remove(&folder.id) {
+                folder.is_synced = previous.is_synced;
+                folder.pending_operations = previous.pending_operations;
+                folder.backup_status = previous.backup_status;
+                folder
+                    .backup_status
+                    .retain(|computer_id, _| folder.backup_computers.contains(computer_id));
+            }
+        }
+    }
+
+    /// Tells `directory_changes` of a change
+    pub fn record_change(&self, change: DirectoryChange) {
+        if let Some(changes) = &self.directory_changes {
+            // Nobody follows the changes anymore once the receiver is gone
+            let _ = changes.send(change);
+        }
+    }
+
     #[must_use]
     pub fn get_user(&self, user_id: &UserId) -> Option<&User> {
         self.users.get(user_id)
@@ -97,16 +446,167 @@ impl ServerState {
     }
 
     pub fn register_connection(&mut self, addr: SocketAddr) {
+        self.metrics
+            .connections_accepted
+            .fetch_add(1, Ordering::Relaxed);
         self.connections.insert(
             addr,
             ConnectedClient {
                 user_id: None,
                 computer_id: None,
                 addr,
+                encoding: Encoding::Json,
+                replayed: HashMap::new(),
+                rate_limiter: self
+                    .rate_limit
+                    .map(|limit| Mutex::new(TokenBucket::new(limit))),
+                connected_at: unix_now(),
             },
         );
     }
 
+    /// Counts a folder operation of a connection against its rate limit. Over the limit,
+    /// the operation is counted as throttled and the time until the next one is allowed
+    /// returned.
+    pub fn throttle(&self, addr: &SocketAddr) -> Option<Duration> {
+        let limiter = self.connections.get(addr)?.rate_limiter.as_ref()?;
+        let wait = limiter
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+            .err()?;
+        self.metrics
+            .throttled_messages
+            .fetch_add(1, Ordering::Relaxed);
+        Some(wait)
+    }
+
+    /// Open connections not authenticated with a computer yet
+    #[must_use]
+    pub fn unauthenticated_connections(&self) -> usize {
+        self.connections
+            .values()
+            .filter(|conn| conn.computer_id.is_none())
+            .count()
+    }
+
+    /// The counters, with the connections open right now
+    #[must_use]
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            connections: self.connections.len(),
+            authenticated_connections: self
+                .connections
+                .values()
+                .filter(|conn| conn.computer_id.is_some())
+                .count(),
+            counters: self.metrics.values(),
+        }
+    }
+
+    /// Every open connection, oldest first
+    #[must_use]
+    pub fn connections_summary(&self) -> Vec<ConnectionSummary> {
+        let mut connections: Vec<ConnectionSummary> = self
+            .connections
+            .values()
+            .map(|conn| ConnectionSummary {
+                addr: conn.addr,
+                user_id: conn.user_id.clone(),
+                computer_id: conn.computer_id.clone(),
+                connected_since: conn.connected_at,
+            })
+            .collect();
+        connections.sort_by_key(|conn| (conn.connected_since, conn.addr));
+        connections
+    }
+
+    /// The folders of every user, with the operations their backups have yet to answer
+    #[must_use]
+    pub fn folders_summary(&self) -> Vec<UserSummary> {
+        let now = unix_now();
+        let mut users: Vec<UserSummary> = self
+            .users
+            .values()
+            .map(|user| UserSummary {
+                user_id: user.id.clone(),
+                folders: user
+                    .sync_folders
+                    .iter()
+                    .map(|folder| {
+                        let pending = self.pending_operations.get(&folder.id);
+                        let oldest = pending
+                            .into_iter()
+                            .flat_map(HashMap::keys)
+                            .filter_map(|operation_id| self.operation_started.get(operation_id))
+                            .min();
+                        FolderSummary {
+                            folder_id: folder.id.clone(),
+                            name: folder.name.clone(),
+                            origin_computer: folder.origin_computer.clone(),
+                            backup_computers: folder.backup_computers.clone(),
+                            is_synced: folder.is_synced,
+                            pending_operations: pending.map_or(0, HashMap::len),
+                            oldest_pending_secs: oldest
+                                .map(|started| now.saturating_sub(*started).max(0) as u64),
+                        }
+                    })
+                    .collect(),
+            })
+            .collect();
+        users.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        users
+    }
+
+    /// Operations pending for longer than `older_than`, oldest first
+    #[must_use]
+    pub fn stuck_operations(&self, older_than: Duration) -> Vec<StuckOperation> {
+        self.pending_since(unix_now().saturating_sub(older_than.as_secs() as i64))
+    }
+
+    /// Operations pending since `started_before` or earlier, in seconds since the Unix
+    /// epoch, oldest first
+    fn pending_since(&self, started_before: i64) -> Vec<StuckOperation> {
+        let mut stuck: Vec<StuckOperation> = self
+            .pending_operations
+            .iter()
+            .flat_map(|(folder_id, operations)| {
+                operations
+                    .iter()
+                    .filter_map(move |(operation_id, awaiting)| {
+                        let pending_since = *self.operation_started.get(operation_id)?;
+                        if pending_since > started_before {
+                            return None;
+                        }
+                        let mut awaiting: Vec<ComputerId> = awaiting.iter().cloned().collect();
+                        awaiting.sort();
+                        Some(StuckOperation {
+                            folder_id: folder_id.clone(),
+                            operation_id: *operation_id,
+                            pending_since,
+                            awaiting,
+                        })
+                    })
+            })
+            .collect();
+        stuck.sort_by_key(|operation| (operation.pending_since, operation.operation_id));
+        stuck
+    }
+
+    /// Encoding of the messages sent to `addr`, JSON for unknown connections.
+    #[must_use]
+    pub fn encoding(&self, addr: &SocketAddr) -> Encoding {
+        self.connections
+            .get(addr)
+            .map_or(Encoding::Json, |conn| conn.encoding)
+    }
+
+    pub fn set_encoding(&mut self, addr: &SocketAddr, encoding: Encoding) {
+        if let Some(conn) = self.connections.get_mut(addr) {
+            conn.encoding = encoding;
+        }
+    }
+
     pub fn remove_connection(&mut self, addr: &SocketAddr) -> Option<ConnectedClient> {
         self.connections.remove(addr)
     }
@@ -120,12 +620,15 @@ impl ServerState {
         self.connections.get_mut(addr)
     }
 
+    /// Binds a connection to a computer of the user. A computer reconnecting before its
+    /// previous connection timed out supersedes it: the previous connection is removed,
+    /// and returned for it to be closed.
     pub fn authenticate_connection(
         &mut self,
         addr: &SocketAddr,
         user_id: UserId,
         computer_id: ComputerId,
-    ) -> Result<(), &'static str> {
+    ) -> Result<Option<SocketAddr>, &'static str> {
         // Check if computer exists for this user
         let computer_exists = self
             .get_user(&user_id)
@@ -135,6 +638,13 @@ impl ServerState {
             return Err("Computer not registered for user");
         }
 
+        let superseded = self
+            .connection_of(&user_id, &computer_id)
+            .filter(|previous| previous != addr);
+        if let Some(previous) = superseded {
+            self.connections.remove(&previous);
+        }
+
         if let Some(conn) = self.connections.get_mut(addr) {
             conn.user_id = Some(user_id.clone());
             conn.computer_id = Some(computer_id.clone());
@@ -144,7 +654,15 @@ impl ServerState {
             .insert((user_id.clone(), computer_id.clone()), *addr);
         self.set_computer_online(&user_id, &computer_id, true);
 
-        Ok(())
+        Ok(superseded)
+    }
+
+    /// Connection of a computer, `None` while it is offline
+    #[must_use]
+    pub fn connection_of(&self, user_id: &UserId, computer_id: &ComputerId) -> Option<SocketAddr> {
+        self.computer_connections
+            .get(&(user_id.clone(), computer_id.clone()))
+            .copied()
     }
 
     pub fn register_computer(&mut self, user_id: &UserId, computer: Computer) -> bool {
@@ -156,6 +674,84 @@ impl ServerState {
         }
     }
 
+    /// Removes a computer from the user, from the backups of every folder and from the
+    /// connection routing. Refused while the computer is the origin of a folder.
+    ///
+    /// Operations stop waiting for the computer like on a disconnection. Returns those it
+    /// was the last backup to answer, with their folder and origin, to notify.
+    pub fn remove_computer(
+        &mut self,
+        user_id: &UserId,
+        computer_id: &ComputerId,
+    ) -> Result<Vec<(FolderId, u64, ComputerId)>, RemoveComputerError> {
+        let user = self
+            .users
+            .get_mut(user_id)
+            .ok_or(RemoveComputerError::NotFound)?;
+        if !user.computers.iter().any(|c| &c.id == computer_id) {
+            return Err(RemoveComputerError::NotFound);
+        }
+        let blocking: Vec<FolderId> = user
+            .sync_folders
+            .iter()
+            .filter(|f| &f.origin_computer == computer_id)
+            .map(|f| f.id.clone())
+            .collect();
+        if !blocking.is_empty() {
+            return Err(RemoveComputerError::OriginOf(blocking));
+        }
+        let folder_ids: Vec<FolderId> = user
+            .sync_folders
+            .iter()
+            .filter(|f| f.backup_computers.contains(computer_id))
+            .map(|f| f.id.clone())
+            .collect();
+
+        let mut completed = Vec::new();
+        for folder_id in folder_ids {
+            let Some(operations) = self.release_backup(user_id, &folder_id, computer_id) else {
+                continue;
+            };
+            let Some(folder) = self.get_folder_mut(user_id, &folder_id) else {
+                continue;
+            };
+            folder.backup_status.remove(computer_id);
+            if !operations.is_empty()
+                && folder.pending_operations == 0
+                && folder
+                    .backup_status
+                    .values()
+                    .all(|status| status.last_failure.is_none())
+            {
+                folder.is_synced = true;
+            }
+            let origin = folder.origin_computer.clone();
+            completed.extend(
+                operations
+                    .into_iter()
+                    .map(|operation_id| (folder_id.clone(), operation_id, origin.clone())),
+            );
+        }
+
+        let user = self
+            .users
+            .get_mut(user_id)
+            .ok_or(RemoveComputerError::NotFound)?;
+        for folder in &mut user.sync_folders {
+            folder.backup_computers.retain(|c| c != computer_id);
+            folder.backup_status.remove(computer_id);
+        }
+        user.computers.retain(|c| &c.id != computer_id);
+        if let Some(addr) = self
+            .computer_connections
+            .remove(&(user_id.clone(), computer_id.clone()))
+            && let Some(conn) = self.connections.get_mut(&addr)
+        {
+            conn.computer_id = None;
+        }
+        Ok(completed)
+    }
+
     pub fn create_sync_folder(&mut self, user_id: &UserId, folder: SyncFolder) -> bool {
         if let Some(user) = self.get_user_mut(user_id) {
             user.sync_folders.push(folder);
@@ -190,6 +786,7 @@ impl ServerState {
     ) {
         if let Some(folder) = self.get_folder_mut(user_id, folder_id) {
             folder.backup_computers.retain(|c| c != computer_id);
+            folder.backup_status.remove(computer_id);
         }
     }
 
@@ -233,6 +830,7 @@ impl ServerState {
             let old_origin = folder.origin_computer.clone();
             folder.origin_computer = new_origin.clone();
             folder.backup_computers.retain(|c| c != new_origin);
+            folder.backup_status.remove(new_origin);
             folder.backup_computers.push(old_origin);
             Ok(())
         } else {
@@ -244,51 +842,461 @@ impl ServerState {
         if let Some(folder) = self.get_folder_mut(user_id, folder_id) {
             folder.pending_operations += 1;
             folder.is_synced = false;
+            for backup in &folder.backup_computers {
+                folder
+                    .backup_status
+                    .entry(backup.clone())
+                    .or_default()
+                    .pending_operations += 1;
+            }
+        }
+    }
+
+    /// Counts an operation sent to a single backup of the folder
+    pub fn increment_pending_operations_for(
+        &mut self,
+        user_id: &UserId,
+        folder_id: &FolderId,
+        computer_id: &ComputerId,
+    ) {
+        if let Some(folder) = self.get_folder_mut(user_id, folder_id) {
+            folder.pending_operations += 1;
+            folder.is_synced = false;
+            folder
+                .backup_status
+                .entry(computer_id.clone())
+                .or_default()
+                .pending_operations += 1;
+        }
+    }
+
+    /// Records that a backup acknowledged `operation_id`. Once every backup it was sent
+    /// to acked it, the operation is dropped and the folder is synced again when nothing
+    /// else is pending and no backup failed. Returns `None` for acks of operations that
+    /// are not pending, or that this computer already answered.
+    pub fn record_backup_ack(
+        &mut self,
+        user_id: &UserId,
+        computer_id: &ComputerId,
+        operation_id: u64,
+    ) -> Option<Acked> {
+        let folder_id = self.pending_folder_of(operation_id)?;
+        if !self.is_backup(user_id, &folder_id, computer_id) {
+            return None;
+        }
+        let complete = self.settle(&folder_id, operation_id, computer_id)?;
+
+        let folder = self.get_folder_mut(user_id, &folder_id)?;
+        let status = folder.backup_status.entry(computer_id.clone()).or_default();
+        status.last_acked_operation = status.last_acked_operation.max(Some(operation_id));
+        status.last_seen = Some(unix_now());
+        status.pending_operations = status.pending_operations.saturating_sub(1);
+        if !complete {
+            return Some(Acked::Pending { folder_id });
+        }
+        folder.pending_operations = folder.pending_operations.saturating_sub(1);
+        if folder.pending_operations == 0
+            && folder
+                .backup_status
+                .values()
+                .all(|status| status.last_failure.is_none())
+        {
+            folder.is_synced = true;
+        }
+        Some(Acked::Complete {
+            origin: folder.origin_computer.clone(),
+            folder_id,
+        })
+    }
+
+    /// Records that a backup failed to apply `operation_id`. The backup no longer counts
+    /// towards the operation, which is dropped once no backup is left to answer it, and
+    /// the folder stays out of sync. Returns the folder and its origin, to notify.
+    pub fn record_backup_nack(
+        &mut self,
+        user_id: &UserId,
+        computer_id: &ComputerId,
+        operation_id: u64,
+        reason: &str,
+    ) -> Option<(FolderId, ComputerId)> {
+        let folder_id = self.pending_folder_of(operation_id)?;
+        if !self.is_backup(user_id, &folder_id, computer_id) {
+            return None;
+        }
+        let complete = self.settle(&folder_id, operation_id, computer_id)?;
+
+        let folder = self.get_folder_mut(user_id, &folder_id)?;
+        if complete {
+            folder.pending_operations = folder.pending_operations.saturating_sub(1);
+        }
+        folder.is_synced = false;
+        let status = folder.backup_status.entry(computer_id.clone()).or_default();
+        status.last_seen = Some(unix_now());
+        status.pending_operations = status.pending_operations.saturating_sub(1);
+        status.last_failure = Some(reason.to_string());
+        Some((folder_id, folder.origin_computer.clone()))
+    }
+
+    /// Marks a computer offline and stops waiting for its answers: operations it was the
+    /// last backup to answer are completed, and the folders it left operations of
+    /// unanswered are out of sync until it catches up.
+    pub fn disconnect_computer(
+        &mut self,
+        user_id: &UserId,
+        computer_id: &ComputerId,
+    ) -> Disconnected {
+        let mut disconnected = Disconnected::default();
+        let Some(user) = self.users.get_mut(user_id) else {
+            return disconnected;
+        };
+        if let Some(computer) = user.computers.iter_mut().find(|c| &c.id == computer_id) {
+            computer.online = false;
+            computer.last_seen = Some(unix_now());
+        }
+        let folder_ids: Vec<FolderId> = user
+            .sync_folders
+            .iter()
+            .filter(|f| f.backup_computers.contains(computer_id))
+            .map(|f| f.id.clone())
+            .collect();
+
+        for folder_id in folder_ids {
+            let Some(completed) = self.release_backup(user_id, &folder_id, computer_id) else {
+                continue;
+            };
+            let Some(folder) = self.get_folder_mut(user_id, &folder_id) else {
+                continue;
+            };
+            folder.is_synced = false;
+            let origin = folder.origin_computer.clone();
+            disconnected.completed.extend(
+                completed
+                    .into_iter()
+                    .map(|operation_id| (folder_id.clone(), operation_id, origin.clone())),
+            );
+            disconnected.stale_folders.push(folder_id);
+        }
+        disconnected
+    }
+
+    /// Stops waiting for `computer_id` on the pending operations of `folder_id`. Returns
+    /// `None` when none waited for it, otherwise the operations it was the last backup
+    /// to answer, oldest first, which no longer count as pending.
+    fn release_backup(
+        &mut self,
+        user_id: &UserId,
+        folder_id: &FolderId,
+        computer_id: &ComputerId,
+    ) -> Option<Vec<u64>> {
+        let mut awaiting: Vec<u64> = self
+            .pending_operations
+            .get(folder_id)
+            .into_iter()
+            .flatten()
+            .filter(|(_, backups)| backups.contains(computer_id))
+            .map(|(operation_id, _)| *operation_id)
+            .collect();
+        if awaiting.is_empty() {
+            return None;
+        }
+        awaiting.sort_unstable();
+        let completed: Vec<u64> = awaiting
+            .into_iter()
+            .filter(|&operation_id| self.settle(folder_id, operation_id, computer_id) == Some(true))
+            .collect();
+        if let Some(folder) = self.get_folder_mut(user_id, folder_id) {
+            folder.pending_operations = folder
+                .pending_operations
+                .saturating_sub(completed.len() as u64);
+        }
+        Some(completed)
+    }
+
+    /// Messages catching a connection up on the folders it backs up, after it fell behind
+    /// the broadcasts of its user and missed some. `delivered` has the sequence of the
+    /// last operation of each folder sent to it. The operations after it are replayed from
+    /// the journal, then the folder's `FolderSequence` is sent, which a backup that could
+    /// not be caught up answers with a full sync request.
+    pub fn catch_up_lagged(
+        &mut self,
+        addr: &SocketAddr,
+        delivered: &HashMap<FolderId, u64>,
+    ) -> Vec<ServerMessage> {
+        let Some((Some(user_id), Some(computer_id))) = self
+            .get_connection(addr)
+            .map(|c| (c.user_id.clone(), c.computer_id.clone()))
+        else {
+            return Vec::new();
+        };
+        let folder_ids: Vec<FolderId> = self
+            .get_user(&user_id)
+            .into_iter()
+            .flat_map(|user| &user.sync_folders)
+            .filter(|f| f.backup_computers.contains(&computer_id))
+            .map(|f| f.id.clone())
+            .collect();
+
+        let mut messages = Vec::new();
+        for folder_id in folder_ids {
+            let sequence = self.folder_sequence(&folder_id);
+            let Some(conn) = self.connections.get_mut(addr) else {
+                break;
+            };
+            let last = delivered
+                .get(&folder_id)
+                .copied()
+                .max(conn.replayed.get(&folder_id).copied());
+            if let Some(replay) =
+                last.and_then(|last| self.journal.since(&folder_id, last, sequence))
+            {
+                messages.extend(replay);
+                conn.replayed.insert(folder_id.clone(), sequence);
+            }
+            messages.push(ServerMessage::FolderSequence {
+                folder_id,
+                sequence,
+            });
+        }
+        messages
+    }
+
+    /// Removes a backup from those an operation waits for. Returns whether it was the
+    /// last one, dropping the operation and the journal entries no backup waits for
+    /// anymore, or `None` if the operation did not wait for it.
+    fn settle(
+        &mut self,
+        folder_id: &FolderId,
+        operation_id: u64,
+        computer_id: &ComputerId,
+    ) -> Option<bool> {
+        let operations = self.pending_operations.get_mut(folder_id)?;
+        let awaiting = operations.get_mut(&operation_id)?;
+        if !awaiting.remove(computer_id) {
+            return None;
+        }
+        let complete = awaiting.is_empty();
+        if complete {
+            operations.remove(&operation_id);
+            self.operation_started.remove(&operation_id);
+            let operations = &*operations;
+            self.journal.truncate(folder_id, |operation_id| {
+                operations.contains_key(&operation_id)
+            });
+        }
+        Some(complete)
+    }
+
+    /// Drops the operations pending since `started_before` or earlier, in seconds since
+    /// the Unix epoch. Backups that stopped answering without disconnecting, or that
+    /// never ack, would otherwise keep them, and their folders out of sync, forever.
+    /// The backups that did not answer are marked as failed until they request a full
+    /// sync. Returns the dropped operations, oldest first.
+    pub fn expire_operations(&mut self, started_before: i64) -> Vec<ExpiredOperation> {
+        let mut expired = Vec::new();
+        for stuck in self.pending_since(started_before) {
+            if let Some(operations) = self.pending_operations.get_mut(&stuck.folder_id) {
+                operations.remove(&stuck.operation_id);
+            }
+            self.operation_started.remove(&stuck.operation_id);
+            let pending = self.pending_operations.get(&stuck.folder_id);
+            self.journal.truncate(&stuck.folder_id, |operation_id| {
+                pending.is_some_and(|operations| operations.contains_key(&operation_id))
+            });
+
+            let Some(user_id) = self.owner_of(&stuck.folder_id) else {
+                continue;
+            };
+            if let Some(folder) = self.get_folder_mut(&user_id, &stuck.folder_id) {
+                folder.pending_operations = folder.pending_operations.saturating_sub(1);
+                folder.is_synced = false;
+                for computer_id in &stuck.awaiting {
+                    let status = folder.backup_status.entry(computer_id.clone()).or_default();
+                    status.pending_operations = status.pending_operations.saturating_sub(1);
+                    status.last_failure = Some(format!(
+                        "Operation {} expired unanswered, a full sync is needed",
+                        stuck.operation_id
+                    ));
+                }
+            }
+            expired.push(ExpiredOperation {
+                user_id,
+                folder_id: stuck.folder_id,
+                operation_id: stuck.operation_id,
+                awaiting: stuck.awaiting,
+            });
+        }
+        self.metrics
+            .operations_expired
+            .fetch_add(expired.len() as u64, Ordering::Relaxed);
+        expired
+    }
+
+    /// Forgets the failures of a backup that requested a full sync of a folder, which
+    /// brings it to the state of the origin; the folder is synced again once the
+    /// operations of the full sync are acked
+    pub fn clear_backup_failure(
+        &mut self,
+        user_id: &UserId,
+        folder_id: &FolderId,
+        computer_id: &ComputerId,
+    ) {
+        if let Some(status) = self
+            .get_folder_mut(user_id, folder_id)
+            .and_then(|folder| folder.backup_status.get_mut(computer_id))
+        {
+            status.last_failure = None;
         }
     }
 
+    /// User a folder belongs to
+    fn owner_of(&self, folder_id: &FolderId) -> Option<UserId> {
+        self.users
+            .values()
+            .find(|user| user.sync_folders.iter().any(|f| &f.id == folder_id))
+            .map(|user| user.id.clone())
+    }
+
+    /// Folder of a pending operation
+    fn pending_folder_of(&self, operation_id: u64) -> Option<FolderId> {
+        self.pending_operations
+            .iter()
+            .find(|(_, operations)| operations.contains_key(&operation_id))
+            .map(|(folder_id, _)| folder_id.clone())
+    }
+
     #[must_use]
     pub fn get_backup_count(&self, user_id: &UserId, folder_id: &FolderId) -> usize {
         self.get_folder(user_id, folder_id)
             .map_or(0, |f| f.backup_computers.len())
     }
 
+    /// Backups of a folder, empty for unknown folders
+    #[must_use]
+    pub fn get_backups(&self, user_id: &UserId, folder_id: &FolderId) -> Vec<ComputerId> {
+        self.get_folder(user_id, folder_id)
+            .map(|f| f.backup_computers.clone())
+            .unwrap_or_default()
+    }
+
+    /// Waits for each of `backups` to ack or nack `operation_id`
     pub fn track_operation(
         &mut self,
         folder_id: &FolderId,
         operation_id: u64,
-        backup_count: usize,
+        backups: Vec<ComputerId>,
     ) {
         self.pending_operations
             .entry(folder_id.clone())
             .or_default()
-            .insert(operation_id, backup_count);
+            .insert(operation_id, backups.into_iter().collect());
+        self.operation_started.insert(operation_id, unix_now());
     }
 
     #[must_use]
-    pub fn should_receive_broadcast(&self, addr: &SocketAddr, folder_id: &FolderId) -> bool {
+    pub fn should_receive_broadcast(
+        &self,
+        addr: &SocketAddr,
+        broadcast: &BroadcastMessage,
+    ) -> bool {
         if let Some(conn) = self.connections.get(addr)
             && let (Some(user_id), Some(computer_id)) = (&conn.user_id, &conn.computer_id)
+            && user_id == &broadcast.user_id
         {
-            return self.is_backup(user_id, folder_id, computer_id);
+            return match &broadcast.audience {
+                Audience::FolderBackups { folder_id } => {
+                    self.is_backup(user_id, folder_id, computer_id)
+                        && !conn.was_replayed(folder_id, &broadcast.message)
+                }
+                Audience::Computers { computer_ids } => computer_ids.contains(computer_id),
+                Audience::User { except } => except.as_ref() != Some(addr),
+                Audience::Connection { addr: recipient } => recipient == addr,
+            };
         }
         false
     }
+
+    /// Renames a folder on behalf of its origin or one of its backups.
+    pub fn rename_folder(
+        &mut self,
+        user_id: &UserId,
+        folder_id: &FolderId,
+        computer_id: &ComputerId,
+        new_name: &str,
+    ) -> Result<SyncFolder, &'static str> {
+        let new_name = new_name.trim();
+        if new_name.is_empty() {
+            return Err("Folder name cannot be empty");
+        }
+        if new_name.chars().count() > MAX_FOLDER_NAME_LEN {
+            return Err("Folder name is too long");
+        }
+        if !self.is_origin(user_id, folder_id, computer_id)
+            && !self.is_backup(user_id, folder_id, computer_id)
+        {
+            return Err("Only the origin or a backup of the folder can rename it");
+        }
+
+        let folder = self
+            .get_folder_mut(user_id, folder_id)
+            .ok_or("Folder not found")?;
+        folder.name = new_name.to_string();
+        Ok(folder.clone())
+    }
+
+    /// Removes a folder and its pending operations. Only the origin can delete a folder,
+    /// and only once every operation was acknowledged by the backups.
+    pub fn delete_sync_folder(
+        &mut self,
+        user_id: &UserId,
+        folder_id: &FolderId,
+        computer_id: &ComputerId,
+    ) -> Result<SyncFolder, &'static str> {
+        let folder = self
+            .get_folder(user_id, folder_id)
+            .ok_or("Folder not found")?;
+        if &folder.origin_computer != computer_id {
+            return Err("Only the origin computer can delete a folder");
+        }
+        if folder.pending_operations > 0 {
+            return Err("Folder has pending operations");
+        }
+
+        let user = self.get_user_mut(user_id).ok_or("Folder not found")?;
+        let index = user
+            .sync_folders
+            .iter()
+            .position(|f| &f.id == folder_id)
+            .ok_or("Folder not found")?;
+        let folder = user.sync_folders.remove(index);
+        if let Some(operations) = self.pending_operations.remove(folder_id) {
+            for operation_id in operations.keys() {
+                self.operation_started.remove(operation_id);
+            }
+        }
+        self.folder_sequences.remove(folder_id);
+        self.journal.remove_folder(folder_id);
+        if let Some(content) = &self.content {
+            content.lock().forget_folder(folder_id);
+        }
+        Ok(folder)
+    }
 }
 
 #[must_use]
-pub fn uuid_simple() -> String {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    let duration = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-    format!("{:x}{:x}", duration.as_secs(), duration.subsec_nanos())
+pub(crate) fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
+
     use super::*;
 
     fn create_test_user(state: &mut ServerState, user_id: &str) {
-        state.get_or_create_user(&user_id.to_string());
+        state.get_or_create_user(&user_id.into());
     }
 
     #[test]
@@ -296,14 +1304,14 @@ mod tests {
         let mut state = ServerState::new();
 
         create_test_user(&mut state, "user1");
-        let user = state.get_user(&"user1".to_string()).unwrap();
+        let user = state.get_user(&"user1".into()).unwrap();
         assert_eq!(user.id, "user1");
         assert_eq!(user.name, "user1");
         assert!(user.computers.is_empty());
         assert!(user.sync_folders.is_empty());
 
         // Getting same user should return existing
-        let user2 = state.get_or_create_user(&"user1".to_string());
+        let user2 = state.get_or_create_user(&"user1".into());
         assert_eq!(user2.id, "user1");
     }
 
@@ -313,14 +1321,15 @@ mod tests {
         create_test_user(&mut state, "user1");
 
         let computer = Computer {
-            id: "comp1".to_string(),
+            id: "comp1".into(),
             name: "My Computer".to_string(),
             online: false,
+            last_seen: None,
         };
 
-        assert!(state.register_computer(&"user1".to_string(), computer));
+        assert!(state.register_computer(&"user1".into(), computer));
 
-        let user = state.get_user(&"user1".to_string()).unwrap();
+        let user = state.get_user(&"user1".into()).unwrap();
         assert_eq!(user.computers.len(), 1);
         assert_eq!(user.computers[0].id, "comp1");
     }
@@ -330,12 +1339,13 @@ mod tests {
         let mut state = ServerState::new();
 
         let computer = Computer {
-            id: "comp1".to_string(),
+            id: "comp1".into(),
             name: "My Computer".to_string(),
             online: false,
+            last_seen: None,
         };
 
-        assert!(!state.register_computer(&"nonexistent".to_string(), computer));
+        assert!(!state.register_computer(&"nonexistent".into(), computer));
     }
 
     #[test]
@@ -344,17 +1354,18 @@ mod tests {
         create_test_user(&mut state, "user1");
 
         let folder = SyncFolder {
-            id: "folder1".to_string(),
+            id: "folder1".into(),
             name: "My Folder".to_string(),
-            origin_computer: "comp1".to_string(),
+            origin_computer: "comp1".into(),
             backup_computers: vec![],
             is_synced: true,
             pending_operations: 0,
+            backup_status: BTreeMap::new(),
         };
 
-        assert!(state.create_sync_folder(&"user1".to_string(), folder));
+        assert!(state.create_sync_folder(&"user1".into(), folder));
 
-        let user = state.get_user(&"user1".to_string()).unwrap();
+        let user = state.get_user(&"user1".into()).unwrap();
         assert_eq!(user.sync_folders.len(), 1);
         assert_eq!(user.sync_folders[0].id, "folder1");
     }
@@ -365,24 +1376,21 @@ mod tests {
         create_test_user(&mut state, "user1");
 
         let folder = SyncFolder {
-            id: "folder1".to_string(),
+            id: "folder1".into(),
             name: "My Folder".to_string(),
-            origin_computer: "comp1".to_string(),
+            origin_computer: "comp1".into(),
             backup_computers: vec![],
             is_synced: true,
             pending_operations: 0,
+            backup_status: BTreeMap::new(),
         };
-        state.create_sync_folder(&"user1".to_string(), folder);
+        state.create_sync_folder(&"user1".into(), folder);
 
-        let result = state.join_sync_folder(
-            &"user1".to_string(),
-            &"folder1".to_string(),
-            &"comp2".to_string(),
-        );
+        let result = state.join_sync_folder(&"user1".into(), &"folder1".into(), &"comp2".into());
 
         assert!(result.is_some());
         let folder = result.unwrap();
-        assert!(folder.backup_computers.contains(&"comp2".to_string()));
+        assert!(folder.backup_computers.contains(&"comp2".into()));
         assert!(!folder.is_synced); // Should be marked as not synced
     }
 
@@ -392,25 +1400,22 @@ mod tests {
         create_test_user(&mut state, "user1");
 
         let folder = SyncFolder {
-            id: "folder1".to_string(),
+            id: "folder1".into(),
             name: "My Folder".to_string(),
-            origin_computer: "comp1".to_string(),
-            backup_computers: vec!["comp2".to_string()],
+            origin_computer: "comp1".into(),
+            backup_computers: vec!["comp2".into()],
             is_synced: true,
             pending_operations: 0,
+            backup_status: BTreeMap::new(),
         };
-        state.create_sync_folder(&"user1".to_string(), folder);
+        state.create_sync_folder(&"user1".into(), folder);
 
-        state.leave_sync_folder(
-            &"user1".to_string(),
-            &"folder1".to_string(),
-            &"comp2".to_string(),
-        );
+        state.leave_sync_folder(&"user1".into(), &"folder1".into(), &"comp2".into());
 
         let folder = state
-            .get_folder(&"user1".to_string(), &"folder1".to_string())
+            .get_folder(&"user1".into(), &"folder1".into())
             .unwrap();
-        assert!(!folder.backup_computers.contains(&"comp2".to_string()));
+        assert!(!folder.backup_computers.contains(&"comp2".into()));
     }
 
     #[test]
@@ -419,52 +1424,661 @@ mod tests {
         create_test_user(&mut state, "user1");
 
         let folder = SyncFolder {
-            id: "folder1".to_string(),
+            id: "folder1".into(),
             name: "My Folder".to_string(),
-            origin_computer: "comp1".to_string(),
+            origin_computer: "comp1".into(),
             backup_computers: vec![],
             is_synced: true,
             pending_operations: 0,
+            backup_status: BTreeMap::new(),
         };
-        state.create_sync_folder(&"user1".to_string(), folder);
+        state.create_sync_folder(&"user1".into(), folder);
 
-        assert!(state.is_folder_synced(&"user1".to_string(), &"folder1".to_string()));
+        assert!(state.is_folder_synced(&"user1".into(), &"folder1".into()));
 
         // Mark as not synced
-        if let Some(f) = state.get_folder_mut(&"user1".to_string(), &"folder1".to_string()) {
+        if let Some(f) = state.get_folder_mut(&"user1".into(), &"folder1".into()) {
             f.is_synced = false;
         }
-        assert!(!state.is_folder_synced(&"user1".to_string(), &"folder1".to_string()));
+        assert!(!state.is_folder_synced(&"user1".into(), &"folder1".into()));
     }
 
     #[test]
-    fn test_switch_origin_success() {
+    fn test_delete_sync_folder() {
         let mut state = ServerState::new();
         create_test_user(&mut state, "user1");
 
         let folder = SyncFolder {
-            id: "folder1".to_string(),
+            id: "folder1".into(),
             name: "My Folder".to_string(),
-            origin_computer: "comp1".to_string(),
-            backup_computers: vec!["comp2".to_string()],
+            origin_computer: "comp1".into(),
+            backup_computers: vec!["comp2".into()],
             is_synced: true,
             pending_operations: 0,
+            backup_status: BTreeMap::new(),
         };
-        state.create_sync_folder(&"user1".to_string(), folder);
+        state.create_sync_folder(&"user1".into(), folder);
+        state.track_operation(&"folder1".into(), 1, Vec::new());
 
-        let result = state.switch_origin(
-            &"user1".to_string(),
-            &"folder1".to_string(),
-            &"comp2".to_string(),
-        );
+        let deleted = state
+            .delete_sync_folder(&"user1".into(), &"folder1".into(), &"comp1".into())
+            .unwrap();
+
+        assert_eq!(deleted.backup_computers, vec!["comp2"]);
+        assert!(
+            state
+                .get_folder(&"user1".into(), &"folder1".into())
+                .is_none()
+        );
+        assert!(!state.pending_operations.contains_key("folder1"));
+    }
+
+    #[test]
+    fn test_delete_sync_folder_not_origin() {
+        let mut state = ServerState::new();
+        create_test_user(&mut state, "user1");
+
+        let folder = SyncFolder {
+            id: "folder1".into(),
+            name: "My Folder".to_string(),
+            origin_computer: "comp1".into(),
+            backup_computers: vec!["comp2".into()],
+            is_synced: true,
+            pending_operations: 0,
+            backup_status: BTreeMap::new(),
+        };
+        state.create_sync_folder(&"user1".into(), folder);
+
+        let result = state.delete_sync_folder(&"user1".into(), &"folder1".into(), &"comp2".into());
+
+        assert_eq!(
+            result.unwrap_err(),
+            "Only the origin computer can delete a folder"
+        );
+        assert!(
+            state
+                .get_folder(&"user1".into(), &"folder1".into())
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_delete_sync_folder_with_pending_operations() {
+        let mut state = ServerState::new();
+        create_test_user(&mut state, "user1");
+
+        let folder = SyncFolder {
+            id: "folder1".into(),
+            name: "My Folder".to_string(),
+            origin_computer: "comp1".into(),
+            backup_computers: vec!["comp2".into()],
+            is_synced: false,
+            pending_operations: 2,
+            backup_status: BTreeMap::new(),
+        };
+        state.create_sync_folder(&"user1".into(), folder);
+
+        let result = state.delete_sync_folder(&"user1".into(), &"folder1".into(), &"comp1".into());
+
+        assert_eq!(result.unwrap_err(), "Folder has pending operations");
+        assert!(
+            state
+                .get_folder(&"user1".into(), &"folder1".into())
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_rename_folder() {
+        let mut state = ServerState::new();
+        create_test_user(&mut state, "user1");
+
+        let folder = SyncFolder {
+            id: "folder1".into(),
+            name: "New Folder (3)".to_string(),
+            origin_computer: "comp1".into(),
+            backup_computers: vec!["comp2".into()],
+            is_synced: true,
+            pending_operations: 0,
+            backup_status: BTreeMap::new(),
+        };
+        state.create_sync_folder(&"user1".into(), folder);
+
+        let renamed = state
+            .rename_folder(
+                &"user1".into(),
+                &"folder1".into(),
+                &"comp2".into(),
+                "  Photos ",
+            )
+            .unwrap();
+
+        assert_eq!(renamed.name, "Photos");
+        let folder = state
+            .get_folder(&"user1".into(), &"folder1".into())
+            .unwrap();
+        assert_eq!(folder.name, "Photos");
+    }
+
+    #[test]
+    fn test_rename_folder_validation() {
+        let mut state = ServerState::new();
+        create_test_user(&mut state, "user1");
+
+        let folder = SyncFolder {
+            id: "folder1".into(),
+            name: "My Folder".to_string(),
+            origin_computer: "comp1".into(),
+            backup_computers: vec!["comp2".into()],
+            is_synced: true,
+            pending_operations: 0,
+            backup_status: BTreeMap::new(),
+        };
+        state.create_sync_folder(&"user1".into(), folder);
+        let user_id = UserId::from("user1");
+        let folder_id = FolderId::from("folder1");
+
+        let empty = state.rename_folder(&user_id, &folder_id, &"comp1".into(), "   ");
+        assert_eq!(empty.unwrap_err(), "Folder name cannot be empty");
+
+        let long_name = "a".repeat(MAX_FOLDER_NAME_LEN + 1);
+        let long = state.rename_folder(&user_id, &folder_id, &"comp1".into(), &long_name);
+        assert_eq!(long.unwrap_err(), "Folder name is too long");
+
+        let stranger = state.rename_folder(&user_id, &folder_id, &"comp3".into(), "Photos");
+        assert!(stranger.is_err());
+
+        let folder = state.get_folder(&user_id, &folder_id).unwrap();
+        assert_eq!(folder.name, "My Folder");
+    }
+
+    #[test]
+    fn test_remove_computer_cleans_memberships() {
+        let mut state = ServerState::new();
+        create_test_user(&mut state, "user1");
+        for id in ["comp1", "comp2"] {
+            state.register_computer(
+                &"user1".into(),
+                Computer {
+                    id: id.into(),
+                    name: id.to_string(),
+                    online: false,
+                    last_seen: None,
+                },
+            );
+        }
+        let folder = SyncFolder {
+            id: "folder1".into(),
+            name: "My Folder".to_string(),
+            origin_computer: "comp1".into(),
+            backup_computers: vec!["comp2".into()],
+            is_synced: true,
+            pending_operations: 0,
+            backup_status: BTreeMap::new(),
+        };
+        state.create_sync_folder(&"user1".into(), folder);
+        let addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        state.register_connection(addr);
+        state
+            .authenticate_connection(&addr, "user1".into(), "comp2".into())
+            .unwrap();
+
+        state
+            .remove_computer(&"user1".into(), &"comp2".into())
+            .unwrap();
+
+        let user = state.get_user(&"user1".into()).unwrap();
+        assert_eq!(user.computers.len(), 1);
+        assert!(user.sync_folders[0].backup_computers.is_empty());
+        assert!(state.computer_connections.is_empty());
+        assert!(state.get_connection(&addr).unwrap().computer_id.is_none());
+    }
+
+    #[test]
+    fn test_remove_computer_settles_its_operations() {
+        let mut state = ServerState::new();
+        let user_id = UserId::from("user1");
+        let folder_id = FolderId::from("folder1");
+        create_test_user(&mut state, "user1");
+        state.register_computer(
+            &user_id,
+            Computer {
+                id: "comp3".into(),
+                name: "comp3".to_string(),
+                online: true,
+                last_seen: None,
+            },
+        );
+        state.create_sync_folder(
+            &user_id,
+            SyncFolder {
+                id: folder_id.clone(),
+                name: "My Folder".to_string(),
+                origin_computer: "comp1".into(),
+                backup_computers: vec!["comp2".into(), "comp3".into()],
+                is_synced: true,
+                pending_operations: 0,
+                backup_status: BTreeMap::new(),
+            },
+        );
+        for operation_id in [1, 2] {
+            state.increment_pending_operations(&user_id, &folder_id);
+            state.track_operation(
+                &folder_id,
+                operation_id,
+                vec!["comp2".into(), "comp3".into()],
+            );
+        }
+        state.record_backup_ack(&user_id, &"comp2".into(), 1);
+
+        let completed = state.remove_computer(&user_id, &"comp3".into()).unwrap();
+
+        // Operation 1 only waited for comp3, operation 2 still waits for comp2
+        assert_eq!(completed, vec![(folder_id.clone(), 1, "comp1".into())]);
+        let folder = state.get_folder(&user_id, &folder_id).unwrap();
+        assert_eq!(folder.pending_operations, 1);
+        assert!(
+            !folder
+                .backup_status
+                .contains_key(&ComputerId::from("comp3"))
+        );
+        assert_eq!(
+            state.record_backup_ack(&user_id, &"comp2".into(), 2),
+            Some(Acked::Complete {
+                folder_id: folder_id.clone(),
+                origin: "comp1".into(),
+            })
+        );
+        let folder = state.get_folder(&user_id, &folder_id).unwrap();
+        assert_eq!(folder.pending_operations, 0);
+        assert!(folder.is_synced);
+        assert!(state.stuck_operations(Duration::ZERO).is_empty());
+    }
+
+    #[test]
+    fn test_remove_computer_blocked_by_origin() {
+        let mut state = ServerState::new();
+        create_test_user(&mut state, "user1");
+        state.register_computer(
+            &"user1".into(),
+            Computer {
+                id: "comp1".into(),
+                name: "My Computer".to_string(),
+                online: false,
+                last_seen: None,
+            },
+        );
+        for id in ["folder1", "folder2"] {
+            state.create_sync_folder(
+                &"user1".into(),
+                SyncFolder {
+                    id: id.into(),
+                    name: id.to_string(),
+                    origin_computer: "comp1".into(),
+                    backup_computers: vec![],
+                    is_synced: true,
+                    pending_operations: 0,
+                    backup_status: BTreeMap::new(),
+                },
+            );
+        }
+
+        let result = state.remove_computer(&"user1".into(), &"comp1".into());
+
+        assert_eq!(
+            result,
+            Err(RemoveComputerError::OriginOf(vec![
+                "folder1".into(),
+                "folder2".into()
+            ]))
+        );
+        let user = state.get_user(&"user1".into()).unwrap();
+        assert_eq!(user.computers.len(), 1);
+    }
+
+    #[test]
+    fn test_remove_unknown_computer() {
+        let mut state = ServerState::new();
+        create_test_user(&mut state, "user1");
+
+        let result = state.remove_computer(&"user1".into(), &"comp1".into());
+
+        assert_eq!(result, Err(RemoveComputerError::NotFound));
+    }
+
+    #[test]
+    fn test_backup_status_tracks_pending_and_acks() {
+        let mut state = ServerState::new();
+        let user_id = UserId::from("user1");
+        let folder_id = FolderId::from("folder1");
+        create_test_user(&mut state, "user1");
+        state.create_sync_folder(
+            &user_id,
+            SyncFolder {
+                id: folder_id.clone(),
+                name: "My Folder".to_string(),
+                origin_computer: "comp1".into(),
+                backup_computers: vec!["comp2".into(), "comp3".into()],
+                is_synced: true,
+                pending_operations: 0,
+                backup_status: BTreeMap::new(),
+            },
+        );
+        for operation_id in [1, 2] {
+            state.increment_pending_operations(&user_id, &folder_id);
+            state.track_operation(
+                &folder_id,
+                operation_id,
+                vec!["comp2".into(), "comp3".into()],
+            );
+        }
+
+        let acked = state.record_backup_ack(&user_id, &"comp2".into(), 2);
+
+        assert_eq!(
+            acked,
+            Some(Acked::Pending {
+                folder_id: folder_id.clone()
+            })
+        );
+        let status = &state
+            .get_folder(&user_id, &folder_id)
+            .unwrap()
+            .backup_status;
+        assert_eq!(status["comp2"].pending_operations, 1);
+        assert_eq!(status["comp2"].last_acked_operation, Some(2));
+        assert!(status["comp2"].last_seen.is_some());
+        assert_eq!(status["comp3"].pending_operations, 2);
+        assert_eq!(status["comp3"].last_acked_operation, None);
+
+        // Acks from computers that are not backups, of unknown operations, or repeated
+        // are ignored
+        assert_eq!(state.record_backup_ack(&user_id, &"comp1".into(), 1), None);
+        assert_eq!(state.record_backup_ack(&user_id, &"comp3".into(), 9), None);
+        assert_eq!(state.record_backup_ack(&user_id, &"comp2".into(), 2), None);
+        assert_eq!(
+            state
+                .get_folder(&user_id, &folder_id)
+                .unwrap()
+                .backup_status["comp2"]
+                .pending_operations,
+            1
+        );
+
+        let acked = state.record_backup_ack(&user_id, &"comp3".into(), 2);
+
+        assert_eq!(
+            acked,
+            Some(Acked::Complete {
+                folder_id: folder_id.clone(),
+                origin: "comp1".into()
+            })
+        );
+        assert!(!state.pending_operations[&folder_id].contains_key(&2));
+        let folder = state.get_folder(&user_id, &folder_id).unwrap();
+        assert_eq!(folder.pending_operations, 1);
+        assert!(!folder.is_synced);
+
+        state.leave_sync_folder(&user_id, &folder_id, &"comp3".into());
+        let status = &state
+            .get_folder(&user_id, &folder_id)
+            .unwrap()
+            .backup_status;
+        assert!(!status.contains_key("comp3"));
+    }
+
+    /// A folder with two backups and two operations pending for both
+    fn state_with_pending_operations() -> ServerState {
+        let mut state = ServerState::new();
+        let user_id = UserId::from("user1");
+        let folder_id = FolderId::from("folder1");
+        create_test_user(&mut state, "user1");
+        state.create_sync_folder(
+            &user_id,
+            SyncFolder {
+                id: folder_id.clone(),
+                name: "My Folder".to_string(),
+                origin_computer: "comp1".into(),
+                backup_computers: vec!["comp2".into(), "comp3".into()],
+                is_synced: true,
+                pending_operations: 0,
+                backup_status: BTreeMap::new(),
+            },
+        );
+        for _ in 0..2 {
+            let operation_id = state.next_operation_id();
+            state.next_folder_sequence(&folder_id);
+            state.increment_pending_operations(&user_id, &folder_id);
+            state.track_operation(
+                &folder_id,
+                operation_id,
+                vec!["comp2".into(), "comp3".into()],
+            );
+        }
+        state
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let state = state_with_pending_operations();
+        let snapshot = state.export_snapshot();
+        let json = serde_json::to_string(&snapshot).unwrap();
+
+        let mut imported = ServerState::new();
+        imported
+            .import_snapshot(serde_json::from_str(&json).unwrap())
+            .unwrap();
+
+        assert_eq!(imported.to_persisted(), state.to_persisted());
+        assert_eq!(imported.operation_counter, 2);
+        assert_eq!(imported.operation_started.len(), 2);
+
+        let mut outdated = state.export_snapshot();
+        outdated.schema_version += 1;
+        assert!(imported.import_snapshot(outdated).is_err());
+        assert_eq!(imported.to_persisted(), state.to_persisted());
+    }
+
+    #[test]
+    fn test_imported_pending_operations_settle_like_the_original() {
+        let user_id = UserId::from("user1");
+        let mut original = state_with_pending_operations();
+        let mut imported = ServerState::new();
+        imported
+            .import_snapshot(original.export_snapshot())
+            .unwrap();
+
+        for (computer_id, operation_id, ack) in [
+            ("comp2", 1, true),
+            ("comp3", 1, true),
+            ("comp2", 2, false),
+            ("comp2", 2, true),
+            ("comp1", 2, true),
+            ("comp3", 2, true),
+        ] {
+            let computer_id = ComputerId::from(computer_id);
+            if ack {
+                assert_eq!(
+                    imported.record_backup_ack(&user_id, &computer_id, operation_id),
+                    original.record_backup_ack(&user_id, &computer_id, operation_id),
+                );
+            } else {
+                assert_eq!(
+                    imported.record_backup_nack(&user_id, &computer_id, operation_id, "failed"),
+                    original.record_backup_nack(&user_id, &computer_id, operation_id, "failed"),
+                );
+            }
+            assert_eq!(
+                imported.to_persisted().pending_operations,
+                original.to_persisted().pending_operations
+            );
+            let folder_id = FolderId::from("folder1");
+            let imported = imported.get_folder(&user_id, &folder_id).unwrap();
+            let original = original.get_folder(&user_id, &folder_id).unwrap();
+            assert_eq!(imported.pending_operations, original.pending_operations);
+            assert_eq!(imported.is_synced, original.is_synced);
+            for (computer_id, status) in &original.backup_status {
+                let imported = &imported.backup_status[computer_id];
+                assert_eq!(imported.pending_operations, status.pending_operations);
+                assert_eq!(imported.last_acked_operation, status.last_acked_operation);
+                assert_eq!(imported.last_failure, status.last_failure);
+            }
+        }
+        assert_eq!(imported.next_operation_id(), original.next_operation_id());
+    }
+
+    #[test]
+    fn test_backup_nack_clears_pending_and_keeps_folder_unsynced() {
+        let mut state = ServerState::new();
+        let user_id = UserId::from("user1");
+        let folder_id = FolderId::from("folder1");
+        create_test_user(&mut state, "user1");
+        state.create_sync_folder(
+            &user_id,
+            SyncFolder {
+                id: folder_id.clone(),
+                name: "My Folder".to_string(),
+                origin_computer: "comp1".into(),
+                backup_computers: vec!["comp2".into(), "comp3".into()],
+                is_synced: true,
+                pending_operations: 0,
+                backup_status: BTreeMap::new(),
+            },
+        );
+        state.increment_pending_operations(&user_id, &folder_id);
+        state.track_operation(&folder_id, 1, vec!["comp2".into(), "comp3".into()]);
+
+        let notified = state.record_backup_nack(&user_id, &"comp2".into(), 1, "disk full");
+
+        assert_eq!(notified, Some((folder_id.clone(), "comp1".into())));
+        assert_eq!(state.pending_operations[&folder_id][&1].len(), 1);
+        let folder = state.get_folder(&user_id, &folder_id).unwrap();
+        assert_eq!(folder.pending_operations, 1);
+        assert_eq!(
+            folder.backup_status["comp2"].last_failure.as_deref(),
+            Some("disk full")
+        );
+        assert_eq!(folder.backup_status["comp2"].pending_operations, 0);
+
+        state.record_backup_nack(&user_id, &"comp3".into(), 1, "path invalid");
+
+        assert!(state.pending_operations[&folder_id].is_empty());
+        let folder = state.get_folder(&user_id, &folder_id).unwrap();
+        assert_eq!(folder.pending_operations, 0);
+        assert!(!folder.is_synced);
+        assert!(!state.is_folder_synced(&user_id, &folder_id));
+    }
+
+    #[test]
+    fn test_disconnect_computer_settles_its_operations() {
+        let mut state = ServerState::new();
+        let user_id = UserId::from("user1");
+        let folder_id = FolderId::from("folder1");
+        create_test_user(&mut state, "user1");
+        state.register_computer(
+            &user_id,
+            Computer {
+                id: "comp3".into(),
+                name: "comp3".to_string(),
+                online: true,
+                last_seen: None,
+            },
+        );
+        state.create_sync_folder(
+            &user_id,
+            SyncFolder {
+                id: folder_id.clone(),
+                name: "My Folder".to_string(),
+                origin_computer: "comp1".into(),
+                backup_computers: vec!["comp2".into(), "comp3".into()],
+                is_synced: true,
+                pending_operations: 0,
+                backup_status: BTreeMap::new(),
+            },
+        );
+        for operation_id in [1, 2] {
+            state.increment_pending_operations(&user_id, &folder_id);
+            state.track_operation(
+                &folder_id,
+                operation_id,
+                vec!["comp2".into(), "comp3".into()],
+            );
+        }
+        state.record_backup_ack(&user_id, &"comp2".into(), 1);
+
+        let disconnected = state.disconnect_computer(&user_id, &"comp3".into());
+
+        // Operation 1 only waited for comp3, operation 2 still waits for comp2
+        assert_eq!(
+            disconnected,
+            Disconnected {
+                completed: vec![(folder_id.clone(), 1, "comp1".into())],
+                stale_folders: vec![folder_id.clone()],
+            }
+        );
+        let folder = state.get_folder(&user_id, &folder_id).unwrap();
+        assert_eq!(folder.pending_operations, 1);
+        assert!(!folder.is_synced);
+        let computer = &state.get_user(&user_id).unwrap().computers[0];
+        assert!(!computer.online);
+        assert!(computer.last_seen.is_some());
+        assert_eq!(
+            state.record_backup_ack(&user_id, &"comp2".into(), 2),
+            Some(Acked::Complete {
+                folder_id: folder_id.clone(),
+                origin: "comp1".into(),
+            })
+        );
+
+        // Nothing waits for it anymore
+        assert_eq!(
+            state.disconnect_computer(&user_id, &"comp3".into()),
+            Disconnected::default()
+        );
+    }
+
+    #[test]
+    fn test_folder_sequences_are_independent() {
+        let mut state = ServerState::new();
+        let folder1 = FolderId::from("folder1");
+        let folder2 = FolderId::from("folder2");
+
+        let sequences: Vec<u64> = [&folder1, &folder2, &folder1, &folder1, &folder2]
+            .into_iter()
+            .map(|folder_id| state.next_folder_sequence(folder_id))
+            .collect();
+
+        assert_eq!(sequences, vec![1, 1, 2, 3, 2]);
+        assert_eq!(state.folder_sequence(&folder1), 3);
+        assert_eq!(state.folder_sequence(&folder2), 2);
+        assert_eq!(state.folder_sequence(&"folder3".into()), 0);
+    }
+
+    #[test]
+    fn test_switch_origin_success() {
+        let mut state = ServerState::new();
+        create_test_user(&mut state, "user1");
+
+        let folder = SyncFolder {
+            id: "folder1".into(),
+            name: "My Folder".to_string(),
+            origin_computer: "comp1".into(),
+            backup_computers: vec!["comp2".into()],
+            is_synced: true,
+            pending_operations: 0,
+            backup_status: BTreeMap::new(),
+        };
+        state.create_sync_folder(&"user1".into(), folder);
+
+        let result = state.switch_origin(&"user1".into(), &"folder1".into(), &"comp2".into());
 
         assert!(result.is_ok());
         let folder = state
-            .get_folder(&"user1".to_string(), &"folder1".to_string())
+            .get_folder(&"user1".into(), &"folder1".into())
             .unwrap();
         assert_eq!(folder.origin_computer, "comp2");
-        assert!(folder.backup_computers.contains(&"comp1".to_string()));
-        assert!(!folder.backup_computers.contains(&"comp2".to_string()));
+        assert!(folder.backup_computers.contains(&"comp1".into()));
+        assert!(!folder.backup_computers.contains(&"comp2".into()));
     }
 
     #[test]
@@ -473,20 +2087,17 @@ mod tests {
         create_test_user(&mut state, "user1");
 
         let folder = SyncFolder {
-            id: "folder1".to_string(),
+            id: "folder1".into(),
             name: "My Folder".to_string(),
-            origin_computer: "comp1".to_string(),
-            backup_computers: vec!["comp2".to_string()],
+            origin_computer: "comp1".into(),
+            backup_computers: vec!["comp2".into()],
             is_synced: false, // Not synced
             pending_operations: 0,
+            backup_status: BTreeMap::new(),
         };
-        state.create_sync_folder(&"user1".to_string(), folder);
+        state.create_sync_folder(&"user1".into(), folder);
 
-        let result = state.switch_origin(
-            &"user1".to_string(),
-            &"folder1".to_string(),
-            &"comp2".to_string(),
-        );
+        let result = state.switch_origin(&"user1".into(), &"folder1".into(), &"comp2".into());
 
         assert!(result.is_err());
         assert_eq!(
@@ -501,19 +2112,20 @@ mod tests {
         create_test_user(&mut state, "user1");
 
         let folder = SyncFolder {
-            id: "folder1".to_string(),
+            id: "folder1".into(),
             name: "My Folder".to_string(),
-            origin_computer: "comp1".to_string(),
-            backup_computers: vec!["comp2".to_string()],
+            origin_computer: "comp1".into(),
+            backup_computers: vec!["comp2".into()],
             is_synced: true,
             pending_operations: 0,
+            backup_status: BTreeMap::new(),
         };
-        state.create_sync_folder(&"user1".to_string(), folder);
+        state.create_sync_folder(&"user1".into(), folder);
 
         let result = state.switch_origin(
-            &"user1".to_string(),
-            &"folder1".to_string(),
-            &"comp3".to_string(), // Not a backup
+            &"user1".into(),
+            &"folder1".into(),
+            &"comp3".into(), // Not a backup
         );
 
         assert!(result.is_err());
@@ -529,18 +2141,77 @@ mod tests {
         create_test_user(&mut state, "user1");
 
         let computer = Computer {
-            id: "comp1".to_string(),
+            id: "comp1".into(),
             name: "My Computer".to_string(),
             online: false,
+            last_seen: None,
         };
-        state.register_computer(&"user1".to_string(), computer);
+        state.register_computer(&"user1".into(), computer);
 
-        state.set_computer_online(&"user1".to_string(), &"comp1".to_string(), true);
+        state.set_computer_online(&"user1".into(), &"comp1".into(), true);
 
-        let user = state.get_user(&"user1".to_string()).unwrap();
+        let user = state.get_user(&"user1".into()).unwrap();
         assert!(user.computers[0].online);
     }
 
+    #[test]
+    fn test_sync_user_keeps_connections_and_progress() {
+        let mut state = ServerState::new();
+        let user_id = UserId::from("user1");
+        let computer = |id: &str, online| Computer {
+            id: id.into(),
+            name: id.to_uppercase(),
+            online,
+            last_seen: None,
+        };
+        let folder = |id: &str, backups: Vec<ComputerId>| SyncFolder {
+            id: id.into(),
+            name: id.to_string(),
+            origin_computer: "comp1".into(),
+            backup_computers: backups,
+            is_synced: true,
+            pending_operations: 0,
+            backup_status: BTreeMap::new(),
+        };
+        create_test_user(&mut state, "user1");
+        state.register_computer(&user_id, computer("comp1", true));
+        state.register_computer(&user_id, computer("gone", false));
+        state.create_sync_folder(
+            &user_id,
+            folder("folder1", vec!["comp2".into(), "gone".into()]),
+        );
+        state.increment_pending_operations(&user_id, &"folder1".into());
+
+        let (changes_tx, mut changes) = mpsc::unbounded_channel();
+        state.directory_changes = Some(changes_tx);
+        state.sync_user(User {
+            id: user_id.clone(),
+            name: "Alice".to_string(),
+            computers: vec![computer("comp1", false), computer("comp2", true)],
+            sync_folders: vec![
+                folder("folder1", vec!["comp2".into()]),
+                folder("folder2", Vec::new()),
+            ],
+        });
+
+        let user = state.get_user(&user_id).unwrap();
+        assert_eq!(user.name, "Alice");
+        // Only connections tell computers are online
+        let online: Vec<_> = user
+            .computers
+            .iter()
+            .map(|c| (c.id.as_str(), c.online))
+            .collect();
+        assert_eq!(online, [("comp1", true), ("comp2", false)]);
+        let synced = state.get_folder(&user_id, &"folder1".into()).unwrap();
+        assert_eq!(synced.pending_operations, 1);
+        assert!(!synced.is_synced);
+        assert_eq!(synced.backup_status.keys().collect::<Vec<_>>(), ["comp2"]);
+        assert!(state.get_folder(&user_id, &"folder2".into()).is_some());
+        // Syncing is not a change of the clients
+        assert!(changes.try_recv().is_err());
+    }
+
     #[test]
     fn test_operation_counter() {
         let mut state = ServerState::new();
@@ -563,6 +2234,146 @@ mod tests {
         assert!(state.get_connection(&addr).is_none());
     }
 
+    #[test]
+    fn test_connections_summary() {
+        let mut state = ServerState::new();
+        create_test_user(&mut state, "user1");
+        state.register_computer(
+            &"user1".into(),
+            Computer {
+                id: "comp1".into(),
+                name: "comp1".to_string(),
+                online: false,
+                last_seen: None,
+            },
+        );
+        let addr1: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let addr2: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+        state.register_connection(addr1);
+        state.register_connection(addr2);
+        state.get_connection_mut(&addr2).unwrap().connected_at = 0;
+        state
+            .authenticate_connection(&addr1, "user1".into(), "comp1".into())
+            .unwrap();
+
+        let connections = state.connections_summary();
+        assert_eq!(connections.len(), 2);
+        assert_eq!(
+            connections[0],
+            ConnectionSummary {
+                addr: addr2,
+                user_id: None,
+                computer_id: None,
+                connected_since: 0,
+            }
+        );
+        assert_eq!(connections[1].addr, addr1);
+        assert_eq!(connections[1].user_id, Some("user1".into()));
+        assert_eq!(connections[1].computer_id, Some("comp1".into()));
+        assert!(connections[1].connected_since > 0);
+    }
+
+    #[test]
+    fn test_stuck_operations_and_folders_summary() {
+        let mut state = ServerState::new();
+        let user_id = UserId::from("user1");
+        let folder_id = FolderId::from("folder1");
+        create_test_user(&mut state, "user1");
+        state.create_sync_folder(
+            &user_id,
+            SyncFolder {
+                id: folder_id.clone(),
+                name: "My Folder".to_string(),
+                origin_computer: "comp1".into(),
+                backup_computers: vec!["comp2".into(), "comp3".into()],
+                is_synced: false,
+                pending_operations: 0,
+                backup_status: BTreeMap::new(),
+            },
+        );
+        for operation_id in [1, 2] {
+            state.increment_pending_operations(&user_id, &folder_id);
+            state.track_operation(
+                &folder_id,
+                operation_id,
+                vec!["comp3".into(), "comp2".into()],
+            );
+        }
+        // Operation 1 was forwarded ten minutes ago
+        let long_ago = unix_now() - 600;
+        state.operation_started.insert(1, long_ago);
+        state.record_backup_ack(&user_id, &"comp2".into(), 1);
+
+        assert_eq!(
+            state.stuck_operations(Duration::from_secs(60)),
+            vec![StuckOperation {
+                folder_id: folder_id.clone(),
+                operation_id: 1,
+                pending_since: long_ago,
+                awaiting: vec!["comp3".into()],
+            }]
+        );
+        assert_eq!(state.stuck_operations(Duration::ZERO).len(), 2);
+
+        let users = state.folders_summary();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].user_id, user_id);
+        let folder = &users[0].folders[0];
+        assert_eq!(folder.pending_operations, 2);
+        assert!(folder.oldest_pending_secs.is_some_and(|secs| secs >= 600));
+
+        // Settled operations are no longer pending, nor stuck
+        state.record_backup_ack(&user_id, &"comp3".into(), 1);
+        assert!(state.stuck_operations(Duration::from_secs(60)).is_empty());
+        assert!(!state.operation_started.contains_key(&1));
+        let folder = &state.folders_summary()[0].folders[0];
+        assert_eq!(folder.pending_operations, 1);
+        assert!(folder.oldest_pending_secs.is_some_and(|secs| secs < 600));
+    }
+
+    #[test]
+    fn test_expire_operations_marks_silent_backups_for_a_full_sync() {
+        let mut state = state_with_pending_operations();
+        let user_id = UserId::from("user1");
+        let folder_id = FolderId::from("folder1");
+        state.operation_started.insert(1, 1000);
+        state.operation_started.insert(2, 2000);
+        state.record_backup_ack(&user_id, &"comp2".into(), 1);
+
+        let expired = state.expire_operations(1500);
+
+        assert_eq!(
+            expired,
+            vec![ExpiredOperation {
+                user_id: user_id.clone(),
+                folder_id: folder_id.clone(),
+                operation_id: 1,
+                awaiting: vec!["comp3".into()],
+            }]
+        );
+        assert_eq!(state.pending_count(&folder_id), 1);
+        assert!(!state.operation_started.contains_key(&1));
+        assert_eq!(state.metrics_snapshot().counters.operations_expired, 1);
+        let folder = state.get_folder(&user_id, &folder_id).unwrap();
+        assert_eq!(folder.pending_operations, 1);
+        assert!(!folder.is_synced);
+        assert_eq!(folder.backup_status["comp3"].pending_operations, 1);
+        assert!(
+            folder.backup_status["comp3"]
+                .last_failure
+                .as_deref()
+                .is_some_and(|failure| failure.contains("full sync"))
+        );
+        assert_eq!(folder.backup_status["comp2"].last_failure, None);
+        assert!(state.expire_operations(1500).is_empty());
+
+        // Synced again once the backup asked for a full sync and nothing is pending
+        state.clear_backup_failure(&user_id, &folder_id, &"comp3".into());
+        state.record_backup_ack(&user_id, &"comp2".into(), 2);
+        state.record_backup_ack(&user_id, &"comp3".into(), 2);
+        assert!(state.is_folder_synced(&user_id, &folder_id));
+    }
+
     #[test]
     fn test_authenticate_connection() {
         let mut state = ServerState::new();
@@ -570,26 +2381,59 @@ mod tests {
 
         create_test_user(&mut state, "user1");
         let computer = Computer {
-            id: "comp1".to_string(),
+            id: "comp1".into(),
             name: "My Computer".to_string(),
             online: false,
+            last_seen: None,
         };
-        state.register_computer(&"user1".to_string(), computer);
+        state.register_computer(&"user1".into(), computer);
         state.register_connection(addr);
 
-        let result = state.authenticate_connection(&addr, "user1".to_string(), "comp1".to_string());
+        let result = state.authenticate_connection(&addr, "user1".into(), "comp1".into());
 
         assert!(result.is_ok());
 
         let conn = state.get_connection(&addr).unwrap();
-        assert_eq!(conn.user_id, Some("user1".to_string()));
-        assert_eq!(conn.computer_id, Some("comp1".to_string()));
+        assert_eq!(conn.user_id, Some("user1".into()));
+        assert_eq!(conn.computer_id, Some("comp1".into()));
 
         // Computer should be online
-        let user = state.get_user(&"user1".to_string()).unwrap();
+        let user = state.get_user(&"user1".into()).unwrap();
         assert!(user.computers[0].online);
     }
 
+    #[test]
+    fn test_authenticate_connection_supersedes_previous_one() {
+        let mut state = ServerState::new();
+        let old: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let new: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+        create_test_user(&mut state, "user1");
+        let computer = Computer {
+            id: "comp1".into(),
+            name: "My Computer".to_string(),
+            online: false,
+            last_seen: None,
+        };
+        state.register_computer(&"user1".into(), computer);
+        state.register_connection(old);
+        state.register_connection(new);
+
+        let first = state.authenticate_connection(&old, "user1".into(), "comp1".into());
+        let second = state.authenticate_connection(&new, "user1".into(), "comp1".into());
+
+        assert_eq!(first, Ok(None));
+        assert_eq!(second, Ok(Some(old)));
+        assert!(state.get_connection(&old).is_none());
+        assert_eq!(
+            state.connection_of(&"user1".into(), &"comp1".into()),
+            Some(new)
+        );
+
+        // Authenticating again on the same connection supersedes nothing
+        let again = state.authenticate_connection(&new, "user1".into(), "comp1".into());
+        assert_eq!(again, Ok(None));
+    }
+
     #[test]
     fn test_authenticate_connection_invalid_computer() {
         let mut state = ServerState::new();
@@ -598,47 +2442,75 @@ mod tests {
         create_test_user(&mut state, "user1");
         state.register_connection(addr);
 
-        let result =
-            state.authenticate_connection(&addr, "user1".to_string(), "nonexistent".to_string());
+        let result = state.authenticate_connection(&addr, "user1".into(), "nonexistent".into());
 
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_connection_audience() {
+        let mut state = ServerState::new();
+        let addr1: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let addr2: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+
+        create_test_user(&mut state, "user1");
+        for id in ["comp1", "comp2"] {
+            let computer = Computer {
+                id: id.into(),
+                name: id.to_string(),
+                online: false,
+                last_seen: None,
+            };
+            state.register_computer(&"user1".into(), computer);
+        }
+        state.register_connection(addr1);
+        state.register_connection(addr2);
+        state
+            .authenticate_connection(&addr1, "user1".into(), "comp1".into())
+            .unwrap();
+        state
+            .authenticate_connection(&addr2, "user1".into(), "comp2".into())
+            .unwrap();
+
+        let addr = state
+            .connection_of(&"user1".into(), &"comp2".into())
+            .unwrap();
+        assert_eq!(addr, addr2);
+        let broadcast = BroadcastMessage {
+            user_id: "user1".into(),
+            message: ServerMessage::Pong { nonce: 1 },
+            audience: Audience::Connection { addr },
+        };
+        assert!(!state.should_receive_broadcast(&addr1, &broadcast));
+        assert!(state.should_receive_broadcast(&addr2, &broadcast));
+
+        assert!(
+            state
+                .connection_of(&"user1".into(), &"comp3".into())
+                .is_none()
+        );
+    }
+
     #[test]
     fn test_is_origin_and_is_backup() {
         let mut state = ServerState::new();
         create_test_user(&mut state, "user1");
 
         let folder = SyncFolder {
-            id: "folder1".to_string(),
+            id: "folder1".into(),
             name: "My Folder".to_string(),
-            origin_computer: "comp1".to_string(),
-            backup_computers: vec!["comp2".to_string()],
+            origin_computer: "comp1".into(),
+            backup_computers: vec!["comp2".into()],
             is_synced: true,
             pending_operations: 0,
+            backup_status: BTreeMap::new(),
         };
-        state.create_sync_folder(&"user1".to_string(), folder);
-
-        assert!(state.is_origin(
-            &"user1".to_string(),
-            &"folder1".to_string(),
-            &"comp1".to_string()
-        ));
-        assert!(!state.is_origin(
-            &"user1".to_string(),
-            &"folder1".to_string(),
-            &"comp2".to_string()
-        ));
-
-        assert!(state.is_backup(
-            &"user1".to_string(),
-            &"folder1".to_string(),
-            &"comp2".to_string()
-        ));
-        assert!(!state.is_backup(
-            &"user1".to_string(),
-            &"folder1".to_string(),
-            &"comp1".to_string()
-        ));
+        state.create_sync_folder(&"user1".into(), folder);
+
+        assert!(state.is_origin(&"user1".into(), &"folder1".into(), &"comp1".into()));
+        assert!(!state.is_origin(&"user1".into(), &"folder1".into(), &"comp2".into()));
+
+        assert!(state.is_backup(&"user1".into(), &"folder1".into(), &"comp2".into()));
+        assert!(!state.is_backup(&"user1".into(), &"folder1".into(), &"comp1".into()));
     }
 }
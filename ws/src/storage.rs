@@ -0,0 +1,197 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use backup_sync_protocol::{ComputerId, FolderId, User, UserId};
+use serde::{Deserialize, Serialize};
+
+/// The part of [`ServerState`](crate::state::ServerState) that outlives the process:
+/// users with their computers and folders, and the bookkeeping of operations. Connections
+/// are left out, every computer is offline after a restart.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PersistedState {
+    pub users: BTreeMap<UserId, User>,
+    pub pending_operations: BTreeMap<FolderId, BTreeMap<u64, BTreeSet<ComputerId>>>,
+    pub operation_counter: u64,
+    pub folder_sequences: BTreeMap<FolderId, u64>,
+}
+
+/// Version of the [`StateSnapshot`] format, raised on any change older servers could
+/// not read
+pub const SNAPSHOT_SCHEMA_VERSION: u32 = 1;
+
+/// A [`PersistedState`] exported to a file, to look into the state of a server or to
+/// start another one from it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    pub schema_version: u32,
+    /// Unix time the snapshot was taken at
+    pub exported_at: i64,
+    /// Not flattened, as maps keyed by operation id do not read back when flattened
+    pub state: PersistedState,
+}
+
+impl StateSnapshot {
+    /// Reads a snapshot, refusing those of another schema version
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+        let version: SchemaVersion = serde_json::from_slice(&content)
+            .with_context(|| format!("Failed to parse state snapshot in {path:?}"))?;
+        if version.schema_version != SNAPSHOT_SCHEMA_VERSION {
+            anyhow::bail!(
+                "State snapshot {path:?} has schema version {}, expected {SNAPSHOT_SCHEMA_VERSION}",
+                version.schema_version
+            );
+        }
+        serde_json::from_slice(&content)
+            .with_context(|| format!("Failed to parse state snapshot in {path:?}"))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Failed to write {path:?}"))
+    }
+}
+
+/// Read first, for a snapshot of another version to be refused as such rather than
+/// as whatever field it lacks
+#[derive(Deserialize)]
+struct SchemaVersion {
+    schema_version: u32,
+}
+
+/// Where the server keeps its [`PersistedState`]
+pub trait Storage: Send + Sync {
+    /// The state saved last, `None` before the first save
+    fn load(&self) -> Result<Option<PersistedState>>;
+
+    /// Replaces the saved state. A crash while saving leaves the previous state intact.
+    fn save(&self, state: &PersistedState) -> Result<()>;
+}
+
+/// Keeps the state in a JSON file, replaced through a temp file next to it
+#[derive(Debug, Clone)]
+pub struct JsonFileStorage {
+    path: PathBuf,
+}
+
+impl JsonFileStorage {
+    #[must_use]
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        self.path.with_file_name(name)
+    }
+}
+
+impl Storage for JsonFileStorage {
+    fn load(&self) -> Result<Option<PersistedState>> {
+        let content = match fs::read(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", self.path)),
+        };
+        let state = serde_json::from_slice(&content)
+            .with_context(|| format!("Failed to parse server state in {:?}", self.path))?;
+        Ok(Some(state))
+    }
+
+    fn save(&self, state: &PersistedState) -> Result<()> {
+        if let Some(parent) = self.path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {parent:?}"))?;
+        }
+        let temp_path = self.temp_path();
+        let mut file = fs::File::create(&temp_path)
+            .with_context(|| format!("Failed to create {temp_path:?}"))?;
+        file.write_all(&serde_json::to_vec_pretty(state)?)
+            .and_then(|()| file.sync_all())
+            .with_context(|| format!("Failed to write {temp_path:?}"))?;
+        fs::rename(&temp_path, &self.path)
+            .with_context(|| format!("Failed to replace {:?}", self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use backup_sync_protocol::Computer;
+
+    use super::*;
+
+    #[test]
+    fn test_json_file_storage_round_trip() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let storage = JsonFileStorage::new(dir.path().join("data/state.json"));
+        assert_eq!(storage.load().unwrap(), None);
+
+        let user_id = UserId::from("user1");
+        let mut state = PersistedState {
+            operation_counter: 7,
+            ..PersistedState::default()
+        };
+        state.users.insert(
+            user_id.clone(),
+            User {
+                id: user_id,
+                name: "user1".to_string(),
+                computers: vec![Computer {
+                    id: "comp1".into(),
+                    name: "Computer 1".to_string(),
+                    online: false,
+                    last_seen: None,
+                }],
+                sync_folders: Vec::new(),
+            },
+        );
+        state
+            .pending_operations
+            .entry("folder1".into())
+            .or_default()
+            .insert(3, BTreeSet::from(["comp2".into()]));
+        storage.save(&state).unwrap();
+
+        assert_eq!(storage.load().unwrap(), Some(state));
+        let names: Vec<_> = fs::read_dir(dir.path().join("data"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["state.json"]);
+    }
+
+    #[test]
+    fn test_json_file_storage_refuses_corrupt_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("state.json");
+        fs::write(&path, "{ not json").unwrap();
+
+        assert!(JsonFileStorage::new(path).load().is_err());
+    }
+
+    #[test]
+    fn test_state_snapshot_schema_version() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("snapshot.json");
+        let snapshot = StateSnapshot {
+            schema_version: SNAPSHOT_SCHEMA_VERSION,
+            exported_at: 1_700_000_000,
+            state: PersistedState {
+                operation_counter: 7,
+                ..PersistedState::default()
+            },
+        };
+        snapshot.write(&path).unwrap();
+        assert_eq!(StateSnapshot::read(&path).unwrap(), snapshot);
+
+        fs::write(&path, r#"{"schema_version":99,"something":"else"}"#).unwrap();
+        let err = StateSnapshot::read(&path).unwrap_err();
+        assert!(err.to_string().contains("schema version 99"), "{err}");
+    }
+}
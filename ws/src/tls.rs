@@ -0,0 +1,40 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+/// Certificate and key the server accepts `wss://` connections with, both PEM encoded
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// Certificate chain, the server certificate first
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    /// Loads the certificate and key, failing on unreadable or mismatched files
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("Failed to read certificates from {:?}", self.cert_path))?;
+        if certs.is_empty() {
+            anyhow::bail!("No certificate in {:?}", self.cert_path);
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .with_context(|| format!("Failed to read private key from {:?}", self.key_path))?;
+
+        // Picked explicitly, as other crates of the build may enable another provider
+        let config = tokio_rustls::rustls::ServerConfig::builder_with_provider(Arc::new(
+            ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid certificate or private key")?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
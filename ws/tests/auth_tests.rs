@@ -0,0 +1,158 @@
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use backup_sync_protocol::{ClientMessage, Computer, PROTOCOL_VERSION, ServerMessage};
+use backup_sync_ws::server::{ServerConfig, run_server};
+use futures_util::{SinkExt, StreamExt};
+use jsonwebtoken::{EncodingKey, Header, encode};
+use serde::Serialize;
+use tokio::sync::{oneshot, watch};
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message;
+
+type WsStream =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+const SECRET: &str = "test-secret";
+
+/// Claims as the HTTP server issues them on login
+#[derive(Serialize)]
+struct Claims {
+    sub: String,
+    exp: u64,
+}
+
+/// Token for `user_id` signed with `secret`, valid for `lifetime` seconds from now
+fn token(secret: &str, user_id: &str, lifetime: i64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let claims = Claims {
+        sub: user_id.to_string(),
+        exp: now.saturating_add_signed(lifetime),
+    };
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )
+    .unwrap()
+}
+
+/// Server checking tokens, with `comp1` registered for `user1`; it shuts down once the
+/// returned sender is dropped
+async fn start_auth_server() -> (SocketAddr, watch::Sender<bool>) {
+    let config = ServerConfig {
+        addrs: vec!["127.0.0.1:0".to_string()],
+        jwt_secret: Some(SECRET.to_string()),
+        ..ServerConfig::default()
+    };
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(run_server(config, Some(ready_tx), shutdown_rx));
+    let ready = ready_rx.await.expect("Server failed to start");
+    ready
+        .state
+        .write()
+        .await
+        .get_or_create_user(&"user1".into())
+        .computers
+        .push(Computer {
+            id: "comp1".into(),
+            name: "Computer 1".to_string(),
+            online: false,
+            last_seen: None,
+        });
+    (ready.addr, shutdown_tx)
+}
+
+async fn receive_message(ws: &mut WsStream) -> ServerMessage {
+    let response = timeout(Duration::from_secs(5), ws.next())
+        .await
+        .expect("Timeout waiting for response")
+        .expect("Stream ended")
+        .expect("WebSocket error");
+    match response {
+        Message::Text(text) => serde_json::from_str(&text).unwrap(),
+        _ => panic!("Expected text message"),
+    }
+}
+
+/// Answer of the server to `Authenticate` as `user1` on `comp1` with `token`
+async fn authenticate(addr: SocketAddr, token: String) -> ServerMessage {
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
+        .await
+        .unwrap();
+    assert!(matches!(
+        receive_message(&mut ws).await,
+        ServerMessage::Welcome { .. }
+    ));
+    let authenticate = ClientMessage::Authenticate {
+        user_id: "user1".into(),
+        computer_id: "comp1".into(),
+        protocol_version: PROTOCOL_VERSION,
+        token,
+        last_applied: None,
+    };
+    let json = serde_json::to_string(&authenticate).unwrap();
+    ws.send(Message::Text(json.into())).await.unwrap();
+    receive_message(&mut ws).await
+}
+
+#[tokio::test]
+async fn test_valid_token_authenticates() {
+    let (addr, _shutdown) = start_auth_server().await;
+
+    let response = authenticate(addr, token(SECRET, "user1", 3600)).await;
+    assert!(
+        matches!(response, ServerMessage::Authenticated { .. }),
+        "{response:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_expired_token_is_rejected() {
+    let (addr, _shutdown) = start_auth_server().await;
+
+    // Past the default leeway for clock skew
+    match authenticate(addr, token(SECRET, "user1", -3600)).await {
+        ServerMessage::NotAuthenticated { reason } => assert_eq!(reason, "Token expired"),
+        response => panic!("Expected NotAuthenticated, got {response:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_token_of_another_user_is_rejected() {
+    let (addr, _shutdown) = start_auth_server().await;
+
+    match authenticate(addr, token(SECRET, "user2", 3600)).await {
+        ServerMessage::NotAuthenticated { reason } => {
+            assert_eq!(reason, "Token was issued to another user");
+        }
+        response => panic!("Expected NotAuthenticated, got {response:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_forged_or_missing_token_is_rejected() {
+    let (addr, _shutdown) = start_auth_server().await;
+
+    for token in [token("other-secret", "user1", 3600), String::new()] {
+        match authenticate(addr, token).await {
+            ServerMessage::NotAuthenticated { reason } => assert_eq!(reason, "Invalid token"),
+            response => panic!("Expected NotAuthenticated, got {response:?}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_server_without_secret_requires_insecure_flag() {
+    let config = ServerConfig {
+        addrs: vec!["127.0.0.1:0".to_string()],
+        ..ServerConfig::default()
+    };
+
+    let (_shutdown, shutdown_rx) = watch::channel(false);
+    assert!(run_server(config, None, shutdown_rx).await.is_err());
+}
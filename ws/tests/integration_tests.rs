@@ -1,14 +1,25 @@
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
-use backup_sync_protocol::{ClientMessage, Computer, FileOperation, ServerMessage, SyncFolder};
-use backup_sync_ws::server::{ServerConfig, run_server};
-use backup_sync_ws::state::ServerState;
+use backup_sync_protocol::codec::{self, Encoding, Frame};
+use backup_sync_protocol::{
+    ClientMessage, Computer, ComputerId, FileOperation, FolderId, MAX_CHUNK_SIZE, Manifest,
+    ManifestEntry, PROTOCOL_VERSION, RelativePath, ServerMessage, SyncFolder, TransferKind, UserId,
+};
+use backup_sync_ws::content::ContentLimits;
+use backup_sync_ws::journal::JournalEntry;
+use backup_sync_ws::rate_limit::RateLimit;
+use backup_sync_ws::server::{ServerConfig, ServerReady, run_server};
+use backup_sync_ws::state::{Audience, BroadcastMessage, ServerState};
+use backup_sync_ws::storage::{JsonFileStorage, Storage};
 use futures_util::{SinkExt, StreamExt};
-use tokio::sync::{RwLock, oneshot};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{RwLock, oneshot, watch};
 use tokio::time::timeout;
 use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 
 type WsStream =
     tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
@@ -17,11 +28,16 @@ type WsStream =
 // Test Utilities
 // ============================================================================
 
+fn relative(path: &str) -> RelativePath {
+    RelativePath::try_from(path).unwrap()
+}
+
 fn computer(id: &str, name: &str) -> Computer {
     Computer {
-        id: id.to_string(),
+        id: id.into(),
         name: name.to_string(),
         online: false,
+        last_seen: None,
     }
 }
 
@@ -33,35 +49,49 @@ fn sync_folder(
     is_synced: bool,
 ) -> SyncFolder {
     SyncFolder {
-        id: id.to_string(),
+        id: id.into(),
         name: name.to_string(),
-        origin_computer: origin.to_string(),
-        backup_computers: backups.into_iter().map(String::from).collect(),
+        origin_computer: origin.into(),
+        backup_computers: backups.into_iter().map(ComputerId::from).collect(),
         is_synced,
         pending_operations: if is_synced { 0 } else { 5 },
+        backup_status: BTreeMap::new(),
     }
 }
 
-async fn start_test_server() -> (SocketAddr, Arc<RwLock<ServerState>>) {
+/// Address and state of a running server, and the sender that shuts it down, which it
+/// also does once dropped at the end of the test
+type TestServer = (SocketAddr, Arc<RwLock<ServerState>>, watch::Sender<bool>);
+
+async fn start_test_server() -> TestServer {
+    start_test_server_with(ServerConfig::default()).await
+}
+
+async fn start_test_server_with(config: ServerConfig) -> TestServer {
     let config = ServerConfig {
-        addr: "127.0.0.1:0".to_string(),
-        broadcast_capacity: 100,
+        addrs: vec!["127.0.0.1:0".to_string()],
+        allow_insecure_auth: true,
+        ..config
     };
     let (ready_tx, ready_rx) = oneshot::channel();
-    tokio::spawn(run_server(config, Some(ready_tx)));
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(run_server(config, Some(ready_tx), shutdown_rx));
     let ready = ready_rx.await.expect("Server failed to start");
-    (ready.addr, ready.state)
+    (ready.addr, ready.state, shutdown_tx)
 }
 
 async fn connect_and_auth(addr: SocketAddr, user_id: &str, computer_id: &str) -> WsStream {
     let mut ws = connect_client(addr).await;
     let welcome = receive_message(&mut ws).await;
-    assert!(matches!(welcome, ServerMessage::Welcome));
+    assert!(matches!(welcome, ServerMessage::Welcome { .. }));
     let auth = send_and_receive(
         &mut ws,
         &ClientMessage::Authenticate {
-            user_id: user_id.to_string(),
-            computer_id: computer_id.to_string(),
+            user_id: user_id.into(),
+            computer_id: computer_id.into(),
+            protocol_version: PROTOCOL_VERSION,
+            token: String::new(),
+            last_applied: None,
         },
     )
     .await;
@@ -75,13 +105,28 @@ async fn connect_client(addr: SocketAddr) -> WsStream {
     ws_stream
 }
 
-async fn send_and_receive(ws: &mut WsStream, msg: &ClientMessage) -> ServerMessage {
+async fn send_message(ws: &mut WsStream, msg: &ClientMessage) {
     let json = serde_json::to_string(msg).unwrap();
     ws.send(Message::Text(json.into())).await.unwrap();
+}
+
+async fn send_and_receive(ws: &mut WsStream, msg: &ClientMessage) -> ServerMessage {
+    send_message(ws, msg).await;
     receive_message(ws).await
 }
 
+/// Next message, skipping the status changes of the user's other computers, which
+/// arrive whenever another connection of the test authenticates or leaves.
 async fn receive_message(ws: &mut WsStream) -> ServerMessage {
+    loop {
+        match receive_any(ws).await {
+            ServerMessage::ComputerStatusChanged { .. } => {}
+            message => return message,
+        }
+    }
+}
+
+async fn receive_any(ws: &mut WsStream) -> ServerMessage {
     let response = timeout(Duration::from_secs(5), ws.next())
         .await
         .expect("Timeout waiting for response")
@@ -93,24 +138,78 @@ async fn receive_message(ws: &mut WsStream) -> ServerMessage {
     }
 }
 
+/// Sends `msg` as a request and unwraps the response, checking that it answers `request_id`.
+async fn send_request(ws: &mut WsStream, request_id: u64, msg: ClientMessage) -> ServerMessage {
+    let request = ClientMessage::Request {
+        request_id,
+        message: Box::new(msg),
+    };
+    match send_and_receive(ws, &request).await {
+        ServerMessage::Response {
+            request_id: answered,
+            message,
+        } => {
+            assert_eq!(answered, request_id);
+            *message
+        }
+        response => panic!("Expected Response, got {:?}", response),
+    }
+}
+
+async fn send_binary(ws: &mut WsStream, msg: &ClientMessage) {
+    let Frame::Binary(bytes) = codec::encode(msg, Encoding::Postcard).unwrap() else {
+        panic!("Expected postcard to encode to binary");
+    };
+    ws.send(Message::Binary(bytes.into())).await.unwrap();
+}
+
+async fn receive_binary(ws: &mut WsStream) -> ServerMessage {
+    loop {
+        let response = timeout(Duration::from_secs(5), ws.next())
+            .await
+            .expect("Timeout waiting for response")
+            .expect("Stream ended")
+            .expect("WebSocket error");
+        match response {
+            Message::Binary(bytes) => match codec::decode_binary(&bytes).unwrap() {
+                ServerMessage::ComputerStatusChanged { .. } => {}
+                message => return message,
+            },
+            other => panic!("Expected binary message, got {:?}", other),
+        }
+    }
+}
+
+/// Waits for the server to have forwarded operations of `folder_id` up to `sequence`, as
+/// sending them does not wait for the server to handle them
+async fn wait_for_sequence(state: &Arc<RwLock<ServerState>>, folder_id: &str, sequence: u64) {
+    timeout(Duration::from_secs(5), async {
+        while state.read().await.folder_sequence(&folder_id.into()) < sequence {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Operations never forwarded");
+}
+
 // ============================================================================
 // Integration Tests
 // ============================================================================
 
 #[tokio::test]
 async fn test_welcome_message_on_connect() {
-    let (addr, _) = start_test_server().await;
+    let (addr, _, _shutdown) = start_test_server().await;
     let mut ws = connect_client(addr).await;
     let welcome = receive_message(&mut ws).await;
-    assert!(matches!(welcome, ServerMessage::Welcome));
+    assert!(matches!(welcome, ServerMessage::Welcome { .. }));
 }
 
 #[tokio::test]
 async fn test_register_computer_without_auth() {
-    let (addr, _) = start_test_server().await;
+    let (addr, _, _shutdown) = start_test_server().await;
     let mut ws = connect_client(addr).await;
     let welcome = receive_message(&mut ws).await;
-    assert!(matches!(welcome, ServerMessage::Welcome));
+    assert!(matches!(welcome, ServerMessage::Welcome { .. }));
 
     let response = send_and_receive(
         &mut ws,
@@ -120,38 +219,41 @@ async fn test_register_computer_without_auth() {
     )
     .await;
     match response {
-        ServerMessage::Error { message } => assert!(message.contains("Not authenticated")),
+        ServerMessage::Error { message, .. } => assert!(message.contains("Not authenticated")),
         _ => panic!("Expected error response, got {:?}", response),
     }
 }
 
 #[tokio::test]
 async fn test_authenticate_without_computer() {
-    let (addr, _) = start_test_server().await;
+    let (addr, _, _shutdown) = start_test_server().await;
     let mut ws = connect_client(addr).await;
     let welcome = receive_message(&mut ws).await;
-    assert!(matches!(welcome, ServerMessage::Welcome));
+    assert!(matches!(welcome, ServerMessage::Welcome { .. }));
 
     let response = send_and_receive(
         &mut ws,
         &ClientMessage::Authenticate {
             user_id: "user1".into(),
             computer_id: "nonexistent".into(),
+            protocol_version: PROTOCOL_VERSION,
+            token: String::new(),
+            last_applied: None,
         },
     )
     .await;
     match response {
-        ServerMessage::Error { message } => assert!(message.contains("not registered")),
+        ServerMessage::Error { message, .. } => assert!(message.contains("not registered")),
         _ => panic!("Expected error response, got {:?}", response),
     }
 }
 
 #[tokio::test]
 async fn test_full_registration_and_auth_flow() {
-    let (addr, state) = start_test_server().await;
+    let (addr, state, _shutdown) = start_test_server().await;
     let mut ws = connect_client(addr).await;
     let welcome = receive_message(&mut ws).await;
-    assert!(matches!(welcome, ServerMessage::Welcome));
+    assert!(matches!(welcome, ServerMessage::Welcome { .. }));
 
     {
         let mut s = state.write().await;
@@ -165,6 +267,9 @@ async fn test_full_registration_and_auth_flow() {
         &ClientMessage::Authenticate {
             user_id: "user1".into(),
             computer_id: "comp1".into(),
+            protocol_version: PROTOCOL_VERSION,
+            token: String::new(),
+            last_applied: None,
         },
     )
     .await;
@@ -178,9 +283,67 @@ async fn test_full_registration_and_auth_flow() {
     }
 }
 
+#[tokio::test]
+async fn test_reconnecting_computer_supersedes_stale_connection() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    // The backup reconnects after a network blip, before its old connection timed out
+    let mut ws_stale = connect_and_auth(addr, "user1", "comp2").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+
+    assert!(matches!(
+        receive_any(&mut ws_stale).await,
+        ServerMessage::Superseded
+    ));
+    let closed = timeout(Duration::from_secs(5), ws_stale.next())
+        .await
+        .expect("Timeout waiting for close");
+    assert!(matches!(closed, Some(Ok(Message::Close(_))) | None));
+
+    send_message(
+        &mut ws_origin,
+        &ClientMessage::FolderOperation {
+            folder_id: "folder1".into(),
+            operation: FileOperation::CreateDir {
+                relative_path: relative("dir"),
+            },
+        },
+    )
+    .await;
+    assert!(matches!(
+        receive_message(&mut ws_backup).await,
+        ServerMessage::FolderOperation { .. }
+    ));
+
+    // Closing the stale socket leaves the computer online, and nobody hears otherwise
+    drop(ws_stale);
+    let response = send_and_receive(&mut ws_backup, &ClientMessage::GetUserState).await;
+    let ServerMessage::UserState { user } = response else {
+        panic!("Expected UserState response, got {:?}", response);
+    };
+    let comp2 = user.computers.iter().find(|c| c.id == "comp2").unwrap();
+    assert!(comp2.online);
+    let s = state.read().await;
+    assert_eq!(s.connections.len(), 2);
+    assert_eq!(s.computer_connections.len(), 2);
+}
+
 #[tokio::test]
 async fn test_create_sync_folder() {
-    let (addr, state) = start_test_server().await;
+    let (addr, state, _shutdown) = start_test_server().await;
     {
         let mut s = state.write().await;
         s.get_or_create_user(&"user1".into())
@@ -210,10 +373,10 @@ async fn test_create_sync_folder() {
 
 #[tokio::test]
 async fn test_create_sync_folder_without_computer_auth() {
-    let (addr, state) = start_test_server().await;
+    let (addr, state, _shutdown) = start_test_server().await;
     let mut ws = connect_client(addr).await;
     let welcome = receive_message(&mut ws).await;
-    assert!(matches!(welcome, ServerMessage::Welcome));
+    assert!(matches!(welcome, ServerMessage::Welcome { .. }));
 
     {
         state.write().await.get_or_create_user(&"user1".into());
@@ -227,14 +390,14 @@ async fn test_create_sync_folder_without_computer_auth() {
     )
     .await;
     match response {
-        ServerMessage::Error { message } => assert!(message.contains("Not authenticated")),
+        ServerMessage::Error { message, .. } => assert!(message.contains("Not authenticated")),
         _ => panic!("Expected error response, got {:?}", response),
     }
 }
 
 #[tokio::test]
 async fn test_join_sync_folder() {
-    let (addr, state) = start_test_server().await;
+    let (addr, state, _shutdown) = start_test_server().await;
     {
         let mut s = state.write().await;
         let user = s.get_or_create_user(&"user1".into());
@@ -261,7 +424,7 @@ async fn test_join_sync_folder() {
     match response {
         ServerMessage::JoinedSyncFolder { folder } => {
             assert_eq!(folder.id, "folder1");
-            assert!(folder.backup_computers.contains(&"comp2".to_string()));
+            assert!(folder.backup_computers.contains(&"comp2".into()));
             assert!(!folder.is_synced);
         }
         _ => panic!("Expected JoinedSyncFolder response, got {:?}", response),
@@ -270,7 +433,7 @@ async fn test_join_sync_folder() {
 
 #[tokio::test]
 async fn test_leave_sync_folder() {
-    let (addr, state) = start_test_server().await;
+    let (addr, state, _shutdown) = start_test_server().await;
     {
         let mut s = state.write().await;
         let user = s.get_or_create_user(&"user1".into());
@@ -305,12 +468,12 @@ async fn test_leave_sync_folder() {
         .get_folder(&"user1".into(), &"folder1".into())
         .unwrap()
         .clone();
-    assert!(!folder.backup_computers.contains(&"comp2".to_string()));
+    assert!(!folder.backup_computers.contains(&"comp2".into()));
 }
 
 #[tokio::test]
 async fn test_origin_switch_success() {
-    let (addr, state) = start_test_server().await;
+    let (addr, state, _shutdown) = start_test_server().await;
     {
         let mut s = state.write().await;
         let user = s.get_or_create_user(&"user1".into());
@@ -352,13 +515,76 @@ async fn test_origin_switch_success() {
         .unwrap()
         .clone();
     assert_eq!(folder.origin_computer, "comp2");
-    assert!(folder.backup_computers.contains(&"comp1".to_string()));
-    assert!(!folder.backup_computers.contains(&"comp2".to_string()));
+    assert!(folder.backup_computers.contains(&"comp1".into()));
+    assert!(!folder.backup_computers.contains(&"comp2".into()));
+}
+
+#[tokio::test]
+async fn test_origin_switch_is_told_to_every_member() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.computers.push(computer("comp3", "Computer 3"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2", "comp3"],
+            true,
+        ));
+    }
+
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_requester = connect_and_auth(addr, "user1", "comp2").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp3").await;
+    let response = send_and_receive(
+        &mut ws_requester,
+        &ClientMessage::RequestOriginSwitch {
+            folder_id: "folder1".into(),
+        },
+    )
+    .await;
+
+    for (who, message) in [
+        ("requester", response),
+        ("previous origin", receive_message(&mut ws_origin).await),
+        ("other backup", receive_message(&mut ws_backup).await),
+    ] {
+        match message {
+            ServerMessage::OriginSwitched {
+                folder_id,
+                new_origin,
+            } => {
+                assert_eq!(folder_id, "folder1");
+                assert_eq!(new_origin, "comp2");
+            }
+            message => panic!("Expected OriginSwitched for the {who}, got {message:?}"),
+        }
+    }
+
+    // The previous origin is a backup now, refused as an origin
+    let response = send_and_receive(
+        &mut ws_origin,
+        &ClientMessage::FolderOperation {
+            folder_id: "folder1".into(),
+            operation: FileOperation::CreateDir {
+                relative_path: relative("dir"),
+            },
+        },
+    )
+    .await;
+    assert!(
+        matches!(response, ServerMessage::Error { .. }),
+        "{response:?}"
+    );
 }
 
 #[tokio::test]
 async fn test_origin_switch_denied_not_synced() {
-    let (addr, state) = start_test_server().await;
+    let (addr, state, _shutdown) = start_test_server().await;
     {
         let mut s = state.write().await;
         let user = s.get_or_create_user(&"user1".into());
@@ -393,7 +619,7 @@ async fn test_origin_switch_denied_not_synced() {
 
 #[tokio::test]
 async fn test_origin_switch_denied_not_backup() {
-    let (addr, state) = start_test_server().await;
+    let (addr, state, _shutdown) = start_test_server().await;
     {
         let mut s = state.write().await;
         let user = s.get_or_create_user(&"user1".into());
@@ -428,7 +654,7 @@ async fn test_origin_switch_denied_not_backup() {
 
 #[tokio::test]
 async fn test_folder_operation_from_origin() {
-    let (addr, state) = start_test_server().await;
+    let (addr, state, _shutdown) = start_test_server().await;
     {
         let mut s = state.write().await;
         let user = s.get_or_create_user(&"user1".into());
@@ -448,8 +674,10 @@ async fn test_folder_operation_from_origin() {
         &ClientMessage::FolderOperation {
             folder_id: "folder1".into(),
             operation: FileOperation::CreateFile {
-                relative_path: "test.txt".into(),
+                relative_path: relative("test.txt"),
                 content: vec![1, 2, 3],
+                hash: [0; 32],
+                compression: None,
             },
         },
     )
@@ -462,8 +690,77 @@ async fn test_folder_operation_from_origin() {
 }
 
 #[tokio::test]
-async fn test_folder_operation_from_non_origin_denied() {
-    let (addr, state) = start_test_server().await;
+async fn test_folder_operations_over_rate_limit_are_throttled() {
+    let (addr, state, _shutdown) = start_test_server_with(ServerConfig {
+        rate_limit: Some(RateLimit {
+            per_second: 1,
+            burst: 10,
+        }),
+        ..ServerConfig::default()
+    })
+    .await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec![],
+            true,
+        ));
+    }
+
+    let mut ws = connect_and_auth(addr, "user1", "comp1").await;
+    for i in 0..1000 {
+        send_message(
+            &mut ws,
+            &ClientMessage::FolderOperation {
+                folder_id: "folder1".into(),
+                operation: FileOperation::CreateDir {
+                    relative_path: relative(&format!("dir{i}")),
+                },
+            },
+        )
+        .await;
+    }
+
+    let mut completed = 0;
+    let mut throttled = 0;
+    for _ in 0..1000 {
+        match receive_message(&mut ws).await {
+            ServerMessage::OperationComplete { .. } => completed += 1,
+            ServerMessage::RateLimited { retry_after_ms } => {
+                assert!(retry_after_ms <= 1000);
+                throttled += 1;
+            }
+            message => panic!(
+                "Expected OperationComplete or RateLimited, got {:?}",
+                message
+            ),
+        }
+    }
+    // The burst, and the few tokens refilled while the flood is handled
+    assert!((10..20).contains(&completed), "{completed} completed");
+    assert_eq!(completed + throttled, 1000);
+    let s = state.read().await;
+    assert_eq!(s.metrics_snapshot().counters.throttled_messages, throttled);
+    assert_eq!(s.folder_sequence(&"folder1".into()), completed);
+
+    // Other messages are not limited
+    drop(s);
+    let response = send_and_receive(&mut ws, &ClientMessage::Ping { nonce: 7 }).await;
+    assert!(matches!(response, ServerMessage::Pong { nonce: 7 }));
+}
+
+#[tokio::test]
+async fn test_too_large_file_content_is_refused() {
+    let (addr, state, _shutdown) = start_test_server_with(ServerConfig {
+        max_file_content_bytes: 100,
+        ..ServerConfig::default()
+    })
+    .await;
     {
         let mut s = state.write().await;
         let user = s.get_or_create_user(&"user1".into());
@@ -477,58 +774,165 @@ async fn test_folder_operation_from_non_origin_denied() {
             true,
         ));
     }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
 
-    let mut ws = connect_and_auth(addr, "user1", "comp2").await;
     let response = send_and_receive(
-        &mut ws,
+        &mut ws_origin,
         &ClientMessage::FolderOperation {
             folder_id: "folder1".into(),
             operation: FileOperation::CreateFile {
-                relative_path: "test.txt".into(),
-                content: vec![1, 2, 3],
+                relative_path: relative("big.bin"),
+                content: vec![0; 200],
+                hash: [0; 32],
+                compression: None,
+            },
+        },
+    )
+    .await;
+    match response {
+        ServerMessage::PayloadTooLarge {
+            size,
+            limit,
+            reason,
+        } => {
+            assert_eq!((size, limit), (200, 100));
+            assert!(reason.contains("FileChunk"), "{reason}");
+        }
+        response => panic!("Expected PayloadTooLarge, got {:?}", response),
+    }
+
+    // Not forwarded: the next operation the backup gets is the one after
+    send_message(
+        &mut ws_origin,
+        &ClientMessage::FolderOperation {
+            folder_id: "folder1".into(),
+            operation: FileOperation::CreateDir {
+                relative_path: relative("dir"),
             },
         },
     )
     .await;
+    match receive_message(&mut ws_backup).await {
+        ServerMessage::FolderOperation {
+            sequence,
+            operation: FileOperation::CreateDir { .. },
+            ..
+        } => assert_eq!(sequence, 1),
+        message => panic!("Expected CreateDir, got {:?}", message),
+    }
+}
+
+#[tokio::test]
+async fn test_transfer_with_too_large_chunks_rejected() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.sync_folders
+            .push(sync_folder("folder1", "Folder", "comp1", vec![], true));
+    }
+    let mut ws = connect_and_auth(addr, "user1", "comp1").await;
 
+    let response = send_and_receive(
+        &mut ws,
+        &ClientMessage::FolderOperation {
+            folder_id: "folder1".into(),
+            operation: FileOperation::StartTransfer {
+                transfer_id: 1,
+                relative_path: relative("big.bin"),
+                kind: TransferKind::Content,
+                total_size: u64::MAX,
+                chunk_size: u64::MAX,
+            },
+        },
+    )
+    .await;
     match response {
-        ServerMessage::Error { message } => assert!(message.contains("origin")),
-        _ => panic!("Expected Error response, got {:?}", response),
+        ServerMessage::PayloadTooLarge { size, limit, .. } => {
+            assert_eq!((size, limit), (u64::MAX, MAX_CHUNK_SIZE));
+        }
+        response => panic!("Expected PayloadTooLarge, got {:?}", response),
     }
 }
 
 #[tokio::test]
-async fn test_get_user_state() {
-    let (addr, state) = start_test_server().await;
+async fn test_too_large_message_closes_connection() {
+    let (addr, state, _shutdown) = start_test_server_with(ServerConfig {
+        max_message_bytes: 1024,
+        ..ServerConfig::default()
+    })
+    .await;
+    {
+        let mut s = state.write().await;
+        s.get_or_create_user(&"user1".into())
+            .computers
+            .push(computer("comp1", "Computer 1"));
+    }
+    let mut ws = connect_and_auth(addr, "user1", "comp1").await;
+
+    ws.send(Message::Text("x".repeat(2000).into()))
+        .await
+        .unwrap();
+
+    match receive_message(&mut ws).await {
+        ServerMessage::PayloadTooLarge { size, limit, .. } => {
+            assert_eq!(limit, 1024);
+            assert!(size > 1024);
+        }
+        response => panic!("Expected PayloadTooLarge, got {:?}", response),
+    }
+    let closed = timeout(Duration::from_secs(5), ws.next())
+        .await
+        .expect("Timeout waiting for close");
+    assert!(matches!(closed, Some(Ok(Message::Close(_))) | None));
+    let s = state.read().await;
+    assert!(s.connections.is_empty());
+    assert!(!s.get_user(&"user1".into()).unwrap().computers[0].online);
+}
+
+#[tokio::test]
+async fn test_folder_operation_from_non_origin_denied() {
+    let (addr, state, _shutdown) = start_test_server().await;
     {
         let mut s = state.write().await;
         let user = s.get_or_create_user(&"user1".into());
         user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
         user.sync_folders.push(sync_folder(
             "folder1",
             "Shared Folder",
             "comp1",
-            vec![],
+            vec!["comp2"],
             true,
         ));
     }
 
-    let mut ws = connect_and_auth(addr, "user1", "comp1").await;
-    let response = send_and_receive(&mut ws, &ClientMessage::GetUserState).await;
+    let mut ws = connect_and_auth(addr, "user1", "comp2").await;
+    let response = send_and_receive(
+        &mut ws,
+        &ClientMessage::FolderOperation {
+            folder_id: "folder1".into(),
+            operation: FileOperation::CreateFile {
+                relative_path: relative("test.txt"),
+                content: vec![1, 2, 3],
+                hash: [0; 32],
+                compression: None,
+            },
+        },
+    )
+    .await;
 
     match response {
-        ServerMessage::UserState { user } => {
-            assert_eq!(user.id, "user1");
-            assert_eq!(user.computers.len(), 1);
-            assert_eq!(user.sync_folders.len(), 1);
-        }
-        _ => panic!("Expected UserState response, got {:?}", response),
+        ServerMessage::Error { message, .. } => assert!(message.contains("origin")),
+        _ => panic!("Expected Error response, got {:?}", response),
     }
 }
 
 #[tokio::test]
-async fn test_multiple_clients_broadcast() {
-    let (addr, state) = start_test_server().await;
+async fn test_folder_operation_batch_is_forwarded_as_one_operation() {
+    let (addr, state, _shutdown) = start_test_server().await;
     {
         let mut s = state.write().await;
         let user = s.get_or_create_user(&"user1".into());
@@ -542,38 +946,3240 @@ async fn test_multiple_clients_broadcast() {
             true,
         ));
     }
-
     let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
     let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
 
-    let response = send_and_receive(
+    send_message(
         &mut ws_origin,
-        &ClientMessage::FolderOperation {
+        &ClientMessage::FolderOperationBatch {
             folder_id: "folder1".into(),
-            operation: FileOperation::CreateFile {
-                relative_path: "broadcast_test.txt".into(),
-                content: vec![42],
-            },
+            operations: vec![
+                FileOperation::CreateDir {
+                    relative_path: relative("dir"),
+                },
+                FileOperation::CreateFile {
+                    relative_path: relative("dir/a.txt"),
+                    content: vec![1],
+                    hash: [0; 32],
+                    compression: None,
+                },
+                FileOperation::RemoveFile {
+                    relative_path: relative("old.txt"),
+                },
+            ],
         },
     )
     .await;
-    assert!(matches!(response, ServerMessage::OperationComplete { .. }));
 
-    let broadcast = receive_message(&mut ws_backup).await;
-    match broadcast {
-        ServerMessage::FolderOperation {
+    match receive_message(&mut ws_backup).await {
+        ServerMessage::FolderOperationBatch {
             folder_id,
-            operation,
+            sequence,
+            operations,
             ..
         } => {
+            assert_eq!(sequence, 1);
             assert_eq!(folder_id, "folder1");
-            match operation {
-                FileOperation::CreateFile { relative_path, .. } => {
-                    assert_eq!(relative_path.to_str().unwrap(), "broadcast_test.txt");
-                }
-                _ => panic!("Expected CreateFile operation"),
-            }
+            assert_eq!(operations.len(), 3);
+            assert!(matches!(operations[0], FileOperation::CreateDir { .. }));
+            assert!(matches!(operations[2], FileOperation::RemoveFile { .. }));
         }
-        _ => panic!("Expected FolderOperation broadcast, got {:?}", broadcast),
+        broadcast => panic!("Expected FolderOperationBatch, got {:?}", broadcast),
+    }
+
+    let s = state.read().await;
+    assert_eq!(s.pending_operations["folder1"].len(), 1);
+    let folder = s.get_folder(&"user1".into(), &"folder1".into()).unwrap();
+    assert_eq!(folder.pending_operations, 1);
+}
+
+#[tokio::test]
+async fn test_folder_operation_batch_validation() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+
+    let response = send_and_receive(
+        &mut ws_origin,
+        &ClientMessage::FolderOperationBatch {
+            folder_id: "folder1".into(),
+            operations: vec![],
+        },
+    )
+    .await;
+    match response {
+        ServerMessage::Error { message, .. } => assert!(message.contains("empty")),
+        _ => panic!("Expected Error response, got {:?}", response),
     }
+
+    let response = send_and_receive(
+        &mut ws_backup,
+        &ClientMessage::FolderOperationBatch {
+            folder_id: "folder1".into(),
+            operations: vec![FileOperation::CreateDir {
+                relative_path: relative("dir"),
+            }],
+        },
+    )
+    .await;
+    match response {
+        ServerMessage::Error { message, .. } => assert!(message.contains("origin")),
+        _ => panic!("Expected Error response, got {:?}", response),
+    }
+}
+
+#[tokio::test]
+async fn test_backup_status_follows_acks() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.computers.push(computer("comp3", "Computer 3"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2", "comp3"],
+            true,
+        ));
+    }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+
+    send_message(
+        &mut ws_origin,
+        &ClientMessage::FolderOperation {
+            folder_id: "folder1".into(),
+            operation: FileOperation::CreateDir {
+                relative_path: relative("dir"),
+            },
+        },
+    )
+    .await;
+    let ServerMessage::FolderOperation { operation_id, .. } = receive_message(&mut ws_backup).await
+    else {
+        panic!("Expected FolderOperation");
+    };
+    send_message(&mut ws_backup, &ClientMessage::Ack { operation_id }).await;
+
+    // Acks have no response; messages of a connection are handled in order
+    let response = send_and_receive(&mut ws_backup, &ClientMessage::GetUserState).await;
+    let ServerMessage::UserState { user } = response else {
+        panic!("Expected UserState response, got {:?}", response);
+    };
+    let status = &user.sync_folders[0].backup_status;
+    assert_eq!(status["comp2"].pending_operations, 0);
+    assert_eq!(status["comp2"].last_acked_operation, Some(operation_id));
+    assert_eq!(status["comp3"].pending_operations, 1);
+    assert_eq!(status["comp3"].last_acked_operation, None);
+}
+
+#[tokio::test]
+async fn test_nack_notifies_origin() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+
+    send_message(
+        &mut ws_origin,
+        &ClientMessage::FolderOperation {
+            folder_id: "folder1".into(),
+            operation: FileOperation::CreateDir {
+                relative_path: relative("dir"),
+            },
+        },
+    )
+    .await;
+    let ServerMessage::FolderOperation { operation_id, .. } = receive_message(&mut ws_backup).await
+    else {
+        panic!("Expected FolderOperation");
+    };
+    send_message(
+        &mut ws_backup,
+        &ClientMessage::Nack {
+            operation_id,
+            reason: "disk full".into(),
+        },
+    )
+    .await;
+
+    match receive_message(&mut ws_origin).await {
+        ServerMessage::OperationFailed {
+            operation_id: failed_id,
+            computer_id,
+            reason,
+        } => {
+            assert_eq!(failed_id, operation_id);
+            assert_eq!(computer_id, "comp2");
+            assert_eq!(reason, "disk full");
+        }
+        message => panic!("Expected OperationFailed, got {:?}", message),
+    }
+    let s = state.read().await;
+    let folder = s.get_folder(&"user1".into(), &"folder1".into()).unwrap();
+    assert_eq!(folder.pending_operations, 0);
+    assert!(!folder.is_synced);
+    assert_eq!(s.metrics_snapshot().counters.nacks_received, 1);
+    assert_eq!(s.metrics_snapshot().counters.acks_received, 0);
+}
+
+#[tokio::test]
+async fn test_metrics_are_served_over_http() {
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (_shutdown, shutdown_rx) = watch::channel(false);
+    tokio::spawn(run_server(
+        ServerConfig {
+            addrs: vec!["127.0.0.1:0".to_string()],
+            metrics_addr: Some("127.0.0.1:0".to_string()),
+            allow_insecure_auth: true,
+            ..ServerConfig::default()
+        },
+        Some(ready_tx),
+        shutdown_rx,
+    ));
+    let ready = ready_rx.await.expect("Server failed to start");
+    {
+        let mut s = ready.state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+    let mut ws_origin = connect_and_auth(ready.addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(ready.addr, "user1", "comp2").await;
+    let mut unauthenticated = connect_client(ready.addr).await;
+    assert!(matches!(
+        receive_message(&mut unauthenticated).await,
+        ServerMessage::Welcome { .. }
+    ));
+
+    let operation = ClientMessage::FolderOperation {
+        folder_id: "folder1".into(),
+        operation: FileOperation::CreateDir {
+            relative_path: relative("dir"),
+        },
+    };
+    // Only the origin may send operations
+    let response = send_and_receive(&mut ws_backup, &operation).await;
+    assert!(matches!(response, ServerMessage::Error { .. }));
+    send_message(&mut ws_origin, &operation).await;
+    let ServerMessage::FolderOperation { operation_id, .. } = receive_message(&mut ws_backup).await
+    else {
+        panic!("Expected FolderOperation");
+    };
+    send_message(&mut ws_backup, &ClientMessage::Ack { operation_id }).await;
+    assert!(matches!(
+        receive_message(&mut ws_origin).await,
+        ServerMessage::OperationComplete { .. }
+    ));
+
+    let mut stream = tokio::net::TcpStream::connect(ready.metrics_addr.unwrap())
+        .await
+        .unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    timeout(Duration::from_secs(5), stream.read_to_string(&mut response))
+        .await
+        .expect("Timeout waiting for metrics")
+        .unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    for line in [
+        "backup_sync_ws_connections 3\n",
+        "backup_sync_ws_authenticated_connections 2\n",
+        "backup_sync_ws_operations_forwarded_total 1\n",
+        "backup_sync_ws_acks_received_total 1\n",
+        "backup_sync_ws_errors_total{kind=\"Error\"} 1\n",
+    ] {
+        assert!(response.contains(line), "{line:?} missing from {response}");
+    }
+}
+
+/// Answer to an HTTP `GET` of `target` on `addr`, with `authorization` as header
+async fn http_get(addr: SocketAddr, target: &str, authorization: Option<&str>) -> String {
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let authorization = authorization
+        .map(|value| format!("Authorization: {value}\r\n"))
+        .unwrap_or_default();
+    let request = format!("GET {target} HTTP/1.1\r\nHost: localhost\r\n{authorization}\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    timeout(Duration::from_secs(5), stream.read_to_string(&mut response))
+        .await
+        .expect("Timeout waiting for HTTP response")
+        .unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_admin_api_requires_the_admin_token() {
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (_shutdown, shutdown_rx) = watch::channel(false);
+    tokio::spawn(run_server(
+        ServerConfig {
+            addrs: vec!["127.0.0.1:0".to_string()],
+            metrics_addr: Some("127.0.0.1:0".to_string()),
+            admin_token: Some("admin-secret".to_string()),
+            allow_insecure_auth: true,
+            ..ServerConfig::default()
+        },
+        Some(ready_tx),
+        shutdown_rx,
+    ));
+    let ready = ready_rx.await.expect("Server failed to start");
+    ready
+        .state
+        .write()
+        .await
+        .get_or_create_user(&"user1".into())
+        .computers
+        .push(computer("comp1", "Computer 1"));
+    let _ws = connect_and_auth(ready.addr, "user1", "comp1").await;
+    let metrics_addr = ready.metrics_addr.unwrap();
+
+    for authorization in [None, Some("Bearer wrong")] {
+        let response = http_get(metrics_addr, "/admin/connections", authorization).await;
+        assert!(
+            response.starts_with("HTTP/1.1 401 Unauthorized\r\n"),
+            "{response}"
+        );
+    }
+
+    let response = http_get(
+        metrics_addr,
+        "/admin/connections",
+        Some("Bearer admin-secret"),
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    let connections: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(connections.as_array().unwrap().len(), 1);
+    assert_eq!(connections[0]["user_id"], "user1");
+    assert_eq!(connections[0]["computer_id"], "comp1");
+
+    // The metrics stay open to scrapers
+    let response = http_get(metrics_addr, "/metrics", None).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+}
+
+#[tokio::test]
+async fn test_operation_completes_once_every_backup_acked() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.computers.push(computer("comp3", "Computer 3"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2", "comp3"],
+            true,
+        ));
+    }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup2 = connect_and_auth(addr, "user1", "comp2").await;
+    let mut ws_backup3 = connect_and_auth(addr, "user1", "comp3").await;
+
+    send_message(
+        &mut ws_origin,
+        &ClientMessage::FolderOperation {
+            folder_id: "folder1".into(),
+            operation: FileOperation::CreateDir {
+                relative_path: relative("dir"),
+            },
+        },
+    )
+    .await;
+    let mut operation_ids = Vec::new();
+    for ws in [&mut ws_backup2, &mut ws_backup3] {
+        let ServerMessage::FolderOperation { operation_id, .. } = receive_message(ws).await else {
+            panic!("Expected FolderOperation");
+        };
+        operation_ids.push(operation_id);
+    }
+    let operation_id = operation_ids[0];
+    assert_eq!(operation_ids[1], operation_id);
+
+    // A second ack of the same backup does not stand for the other one
+    send_message(&mut ws_backup2, &ClientMessage::Ack { operation_id }).await;
+    send_message(&mut ws_backup2, &ClientMessage::Ack { operation_id }).await;
+    assert!(
+        timeout(Duration::from_millis(200), receive_message(&mut ws_origin))
+            .await
+            .is_err()
+    );
+    send_message(&mut ws_backup3, &ClientMessage::Ack { operation_id }).await;
+
+    match receive_message(&mut ws_origin).await {
+        ServerMessage::OperationComplete {
+            operation_id: completed_id,
+        } => assert_eq!(completed_id, operation_id),
+        message => panic!("Expected OperationComplete, got {:?}", message),
+    }
+    match receive_message(&mut ws_backup2).await {
+        ServerMessage::SyncStatusChanged {
+            folder_id,
+            is_synced,
+            pending_operations,
+            backups,
+            backpressure,
+        } => {
+            assert_eq!(folder_id, "folder1");
+            assert!(is_synced);
+            assert_eq!(pending_operations, 0);
+            assert!(!backpressure);
+            assert_eq!(backups["comp3"].last_acked_operation, Some(operation_id));
+        }
+        message => panic!("Expected SyncStatusChanged, got {:?}", message),
+    }
+    assert!(state.read().await.pending_operations["folder1"].is_empty());
+    let metrics = state.read().await.metrics_snapshot();
+    assert_eq!(metrics.connections, 3);
+    assert_eq!(metrics.authenticated_connections, 3);
+    assert_eq!(metrics.counters.operations_forwarded, 1);
+    assert_eq!(metrics.counters.acks_received, 3);
+
+    let response = send_and_receive(
+        &mut ws_backup2,
+        &ClientMessage::RequestOriginSwitch {
+            folder_id: "folder1".into(),
+        },
+    )
+    .await;
+    assert!(
+        matches!(response, ServerMessage::OriginSwitched { .. }),
+        "{response:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_full_sync_is_served_by_origin_to_requesting_backup_only() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.computers.push(computer("comp3", "Computer 3"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2", "comp3"],
+            true,
+        ));
+    }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_fresh = connect_and_auth(addr, "user1", "comp2").await;
+    let mut ws_other = connect_and_auth(addr, "user1", "comp3").await;
+
+    send_message(
+        &mut ws_fresh,
+        &ClientMessage::RequestFullSync {
+            folder_id: "folder1".into(),
+        },
+    )
+    .await;
+    match receive_message(&mut ws_origin).await {
+        ServerMessage::FullSyncRequested {
+            folder_id,
+            computer_id,
+        } => {
+            assert_eq!(folder_id, "folder1");
+            assert_eq!(computer_id, "comp2");
+        }
+        message => panic!("Expected FullSyncRequested, got {:?}", message),
+    }
+
+    // The origin answers with the content of its two files
+    for (name, content) in [("a.txt", b"a"), ("b.txt", b"b")] {
+        send_message(
+            &mut ws_origin,
+            &ClientMessage::TargetedOperation {
+                folder_id: "folder1".into(),
+                computer_id: "comp2".into(),
+                operation: FileOperation::CreateFile {
+                    relative_path: relative(name),
+                    content: content.to_vec(),
+                    hash: [0; 32],
+                    compression: None,
+                },
+            },
+        )
+        .await;
+    }
+    let mut received = Vec::new();
+    while received.len() < 2 {
+        match receive_message(&mut ws_fresh).await {
+            ServerMessage::FolderOperation {
+                operation_id,
+                operation: FileOperation::CreateFile { relative_path, .. },
+                ..
+            } => {
+                received.push(relative_path.to_string());
+                send_message(&mut ws_fresh, &ClientMessage::Ack { operation_id }).await;
+            }
+            // Its own ack of the first file settling
+            ServerMessage::SyncStatusChanged { .. } => {}
+            message => panic!("Expected CreateFile, got {:?}", message),
+        }
+    }
+    assert_eq!(received, ["a.txt", "b.txt"]);
+    // The status of the folder reaches every member, its content the requester only
+    while let Ok(message) =
+        timeout(Duration::from_millis(200), receive_message(&mut ws_other)).await
+    {
+        assert!(
+            matches!(message, ServerMessage::SyncStatusChanged { .. }),
+            "{message:?}"
+        );
+    }
+
+    // Tracked like any operation, the acks complete them
+    let mut completed = 0;
+    while completed < 2 {
+        match receive_message(&mut ws_origin).await {
+            ServerMessage::OperationComplete { .. } => completed += 1,
+            ServerMessage::SyncStatusChanged { .. } => {}
+            message => panic!("Expected OperationComplete, got {:?}", message),
+        }
+    }
+    let s = state.read().await;
+    assert!(s.is_folder_synced(&"user1".into(), &"folder1".into()));
+}
+
+#[tokio::test]
+async fn test_full_sync_is_served_from_stored_content_while_origin_is_offline() {
+    let data_dir = tempfile::TempDir::new().unwrap();
+    let (addr, state, _shutdown) = start_test_server_with(ServerConfig {
+        data_path: Some(data_dir.path().join("state.json")),
+        content_store: Some(ContentLimits::default()),
+        ..ServerConfig::default()
+    })
+    .await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+    let content = b"stored while the backup was away".to_vec();
+    let hash = *blake3::hash(&content).as_bytes();
+
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    for operation in [
+        FileOperation::CreateDir {
+            relative_path: relative("dir"),
+        },
+        FileOperation::CreateFile {
+            relative_path: relative("dir/a.txt"),
+            content: content.clone(),
+            hash,
+            compression: None,
+        },
+    ] {
+        send_message(
+            &mut ws_origin,
+            &ClientMessage::FolderOperation {
+                folder_id: "folder1".into(),
+                operation,
+            },
+        )
+        .await;
+    }
+    wait_for_sequence(&state, "folder1", 2).await;
+    ws_origin.close(None).await.unwrap();
+    timeout(Duration::from_secs(5), async {
+        while state
+            .read()
+            .await
+            .connection_of(&"user1".into(), &"comp1".into())
+            .is_some()
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Origin never disconnected");
+
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+    send_message(
+        &mut ws_backup,
+        &ClientMessage::RequestFullSync {
+            folder_id: "folder1".into(),
+        },
+    )
+    .await;
+    let mut received = Vec::new();
+    loop {
+        match receive_message(&mut ws_backup).await {
+            ServerMessage::FolderOperationBatch {
+                operation_id,
+                operations,
+                ..
+            } => {
+                received.extend(operations);
+                send_message(&mut ws_backup, &ClientMessage::Ack { operation_id }).await;
+            }
+            ServerMessage::FolderSequence { sequence, .. } => {
+                assert_eq!(sequence, 2);
+                break;
+            }
+            message => panic!("Expected FolderOperationBatch, got {:?}", message),
+        }
+    }
+    assert!(matches!(
+        &received[..],
+        [
+            FileOperation::CreateDir { .. },
+            FileOperation::CreateFile { content: served, .. },
+        ] if *served == content
+    ));
+
+    send_message(
+        &mut ws_backup,
+        &ClientMessage::FetchContent {
+            folder_id: "folder1".into(),
+            hash,
+        },
+    )
+    .await;
+    loop {
+        match receive_message(&mut ws_backup).await {
+            ServerMessage::Content {
+                hash: served_hash,
+                content: served,
+                ..
+            } => {
+                assert_eq!(served_hash, hash);
+                assert_eq!(served, content);
+                break;
+            }
+            // Its acks of the served operations
+            ServerMessage::SyncStatusChanged { .. } => {}
+            message => panic!("Expected Content, got {:?}", message),
+        }
+    }
+    send_message(
+        &mut ws_backup,
+        &ClientMessage::FetchContent {
+            folder_id: "folder1".into(),
+            hash: [0; 32],
+        },
+    )
+    .await;
+    loop {
+        match receive_message(&mut ws_backup).await {
+            ServerMessage::Error { .. } => break,
+            ServerMessage::SyncStatusChanged { .. } => {}
+            message => panic!("Expected Error, got {:?}", message),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_backup_disconnecting_without_ack_no_longer_blocks_completion() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.computers.push(computer("comp3", "Computer 3"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2", "comp3"],
+            true,
+        ));
+    }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup2 = connect_and_auth(addr, "user1", "comp2").await;
+    let mut ws_backup3 = connect_and_auth(addr, "user1", "comp3").await;
+
+    send_message(
+        &mut ws_origin,
+        &ClientMessage::FolderOperation {
+            folder_id: "folder1".into(),
+            operation: FileOperation::CreateDir {
+                relative_path: relative("dir"),
+            },
+        },
+    )
+    .await;
+    let ServerMessage::FolderOperation { operation_id, .. } =
+        receive_message(&mut ws_backup2).await
+    else {
+        panic!("Expected FolderOperation");
+    };
+    assert!(matches!(
+        receive_message(&mut ws_backup3).await,
+        ServerMessage::FolderOperation { .. }
+    ));
+    // Gone without acking, as a crashed computer would
+    drop(ws_backup3);
+
+    // The folder waits for comp2 only, and misses the operation on comp3
+    let mut stale = false;
+    loop {
+        match receive_any(&mut ws_origin).await {
+            ServerMessage::SyncStatusChanged {
+                is_synced,
+                pending_operations,
+                ..
+            } => {
+                assert!(!is_synced);
+                assert_eq!(pending_operations, 1);
+                stale = true;
+            }
+            ServerMessage::ComputerStatusChanged {
+                computer_id,
+                online: false,
+            } => {
+                assert_eq!(computer_id, "comp3");
+                break;
+            }
+            // The backups coming online earlier
+            ServerMessage::ComputerStatusChanged { online: true, .. } => {}
+            message => panic!("Expected status changes, got {:?}", message),
+        }
+    }
+    assert!(stale);
+    {
+        let s = state.read().await;
+        let user = s.get_user(&"user1".into()).unwrap();
+        let comp3 = user.computers.iter().find(|c| c.id == "comp3").unwrap();
+        assert!(!comp3.online);
+        assert!(comp3.last_seen.is_some());
+    }
+
+    send_message(&mut ws_backup2, &ClientMessage::Ack { operation_id }).await;
+    loop {
+        match receive_message(&mut ws_origin).await {
+            ServerMessage::OperationComplete {
+                operation_id: completed_id,
+            } => {
+                assert_eq!(completed_id, operation_id);
+                break;
+            }
+            ServerMessage::SyncStatusChanged { .. } => {}
+            message => panic!("Expected OperationComplete, got {:?}", message),
+        }
+    }
+    let s = state.read().await;
+    assert!(
+        s.pending_operations
+            .values()
+            .all(|operations| operations.is_empty())
+    );
+}
+
+#[tokio::test]
+async fn test_full_sync_asks_to_retry_while_origin_is_offline() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+
+    let response = send_and_receive(
+        &mut ws_backup,
+        &ClientMessage::RequestFullSync {
+            folder_id: "folder1".into(),
+        },
+    )
+    .await;
+
+    match response {
+        ServerMessage::FullSyncUnavailable {
+            folder_id,
+            retry_after_secs,
+            ..
+        } => {
+            assert_eq!(folder_id, "folder1");
+            assert!(retry_after_secs > 0);
+        }
+        _ => panic!("Expected FullSyncUnavailable, got {:?}", response),
+    }
+}
+
+#[tokio::test]
+async fn test_get_user_state() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec![],
+            true,
+        ));
+    }
+
+    let mut ws = connect_and_auth(addr, "user1", "comp1").await;
+    let response = send_and_receive(&mut ws, &ClientMessage::GetUserState).await;
+
+    match response {
+        ServerMessage::UserState { user } => {
+            assert_eq!(user.id, "user1");
+            assert_eq!(user.computers.len(), 1);
+            assert_eq!(user.sync_folders.len(), 1);
+        }
+        _ => panic!("Expected UserState response, got {:?}", response),
+    }
+}
+
+#[tokio::test]
+async fn test_list_folders_without_auth() {
+    let (addr, _, _shutdown) = start_test_server().await;
+    let mut ws = connect_client(addr).await;
+    let welcome = receive_message(&mut ws).await;
+    assert!(matches!(welcome, ServerMessage::Welcome { .. }));
+
+    let response = send_and_receive(&mut ws, &ClientMessage::ListFolders).await;
+    match response {
+        ServerMessage::Error { message, .. } => assert!(message.contains("Not authenticated")),
+        _ => panic!("Expected error response, got {:?}", response),
+    }
+}
+
+#[tokio::test]
+async fn test_list_folders_role_flags() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Origin",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+        user.sync_folders.push(sync_folder(
+            "folder2",
+            "Backup",
+            "comp2",
+            vec!["comp1"],
+            true,
+        ));
+        user.sync_folders
+            .push(sync_folder("folder3", "Unrelated", "comp2", vec![], true));
+    }
+
+    let mut ws = connect_and_auth(addr, "user1", "comp1").await;
+    let response = send_and_receive(&mut ws, &ClientMessage::ListFolders).await;
+
+    match response {
+        ServerMessage::FolderList { folders } => {
+            let flags: Vec<_> = folders
+                .iter()
+                .map(|f| (f.id.as_str(), f.is_origin, f.is_backup))
+                .collect();
+            assert_eq!(
+                flags,
+                vec![
+                    ("folder1", true, false),
+                    ("folder2", false, true),
+                    ("folder3", false, false),
+                ]
+            );
+            assert_eq!(folders[1].origin_computer, "comp2");
+        }
+        _ => panic!("Expected FolderList response, got {:?}", response),
+    }
+}
+
+#[tokio::test]
+async fn test_multiple_clients_broadcast() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+
+    send_message(
+        &mut ws_origin,
+        &ClientMessage::FolderOperation {
+            folder_id: "folder1".into(),
+            operation: FileOperation::CreateFile {
+                relative_path: relative("broadcast_test.txt"),
+                content: vec![42],
+                hash: [0; 32],
+                compression: None,
+            },
+        },
+    )
+    .await;
+
+    let broadcast = receive_message(&mut ws_backup).await;
+    match broadcast {
+        ServerMessage::FolderOperation {
+            folder_id,
+            operation,
+            ..
+        } => {
+            assert_eq!(folder_id, "folder1");
+            match operation {
+                FileOperation::CreateFile { relative_path, .. } => {
+                    assert_eq!(relative_path.to_str().unwrap(), "broadcast_test.txt");
+                }
+                _ => panic!("Expected CreateFile operation"),
+            }
+        }
+        _ => panic!("Expected FolderOperation broadcast, got {:?}", broadcast),
+    }
+}
+
+#[tokio::test]
+async fn test_other_users_never_observe_operations() {
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (_shutdown, shutdown_rx) = watch::channel(false);
+    tokio::spawn(run_server(
+        ServerConfig {
+            addrs: vec!["127.0.0.1:0".to_string()],
+            allow_insecure_auth: true,
+            ..ServerConfig::default()
+        },
+        Some(ready_tx),
+        shutdown_rx,
+    ));
+    let ready = ready_rx.await.expect("Server failed to start");
+    let (addr, state, broadcasts) = (ready.addr, ready.state, ready.broadcasts);
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+        let other = s.get_or_create_user(&"user2".into());
+        other.computers.push(computer("comp2", "Computer 2"));
+    }
+
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+    let mut ws_other = connect_and_auth(addr, "user2", "comp2").await;
+    assert_eq!(broadcasts.channel_count(), 2);
+    assert_eq!(broadcasts.subscriber_count(&"user1".into()), 2);
+    assert_eq!(broadcasts.subscriber_count(&"user2".into()), 1);
+
+    send_message(
+        &mut ws_origin,
+        &ClientMessage::FolderOperation {
+            folder_id: "folder1".into(),
+            operation: FileOperation::CreateDir {
+                relative_path: relative("dir"),
+            },
+        },
+    )
+    .await;
+    assert!(matches!(
+        receive_message(&mut ws_backup).await,
+        ServerMessage::FolderOperation { .. }
+    ));
+    assert!(
+        timeout(Duration::from_millis(200), receive_message(&mut ws_other))
+            .await
+            .is_err()
+    );
+
+    // The channel of a user goes away with its last connection
+    ws_other.close(None).await.unwrap();
+    timeout(Duration::from_secs(5), async {
+        while broadcasts.channel_count() != 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Channel of user2 was not dropped");
+    assert_eq!(broadcasts.subscriber_count(&"user2".into()), 0);
+}
+
+#[tokio::test]
+async fn test_unknown_operation_is_rejected_with_error() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec![],
+            true,
+        ));
+    }
+
+    let mut ws = connect_and_auth(addr, "user1", "comp1").await;
+    let json = r#"{"FolderOperation":{"folder_id":"folder1","operation":{"SetXattr":{"relative_path":"a.txt"}}}}"#;
+    ws.send(Message::Text(json.into())).await.unwrap();
+
+    match receive_message(&mut ws).await {
+        ServerMessage::Error { message, .. } => {
+            assert!(message.contains("Unsupported"), "{message}")
+        }
+        response => panic!("Expected Error response, got {:?}", response),
+    }
+}
+
+#[tokio::test]
+async fn test_operation_with_path_outside_folder_is_rejected() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+
+    let mut origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut backup = connect_and_auth(addr, "user1", "comp2").await;
+    let json = r#"{"FolderOperation":{"folder_id":"folder1","operation":{"RemoveDir":{"relative_path":"../../etc/cron.d"}}}}"#;
+    origin.send(Message::Text(json.into())).await.unwrap();
+
+    match receive_message(&mut origin).await {
+        ServerMessage::PathRejected { reason } => {
+            assert!(reason.contains("../../etc/cron.d"), "{reason}")
+        }
+        response => panic!("Expected PathRejected, got {:?}", response),
+    }
+    assert!(
+        timeout(Duration::from_millis(200), receive_message(&mut backup))
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_symlink_out_of_folder_is_rejected() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+
+    let mut origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut backup = connect_and_auth(addr, "user1", "comp2").await;
+    // A link to outside of the folder would have the file written through it
+    let batch = ClientMessage::FolderOperationBatch {
+        folder_id: "folder1".into(),
+        operations: vec![
+            FileOperation::CreateSymlink {
+                relative_path: relative("etc"),
+                target: "/etc".to_string(),
+            },
+            FileOperation::CreateFile {
+                relative_path: relative("etc/passwd"),
+                content: Vec::new(),
+                hash: [0; 32],
+                compression: None,
+            },
+        ],
+    };
+    let single = ClientMessage::FolderOperation {
+        folder_id: "folder1".into(),
+        operation: FileOperation::CreateSymlink {
+            relative_path: relative("sub/up"),
+            target: "../../escaped".to_string(),
+        },
+    };
+    for message in [batch, single] {
+        match send_and_receive(&mut origin, &message).await {
+            ServerMessage::PathRejected { reason } => {
+                assert!(reason.contains("symlink"), "{reason}")
+            }
+            response => panic!("Expected PathRejected, got {response:?}"),
+        }
+    }
+    assert!(
+        timeout(Duration::from_millis(200), receive_message(&mut backup))
+            .await
+            .is_err()
+    );
+    assert_eq!(state.read().await.folder_sequence(&"folder1".into()), 0);
+}
+
+#[tokio::test]
+async fn test_set_metadata_is_forwarded_to_backup() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+    send_message(
+        &mut ws_origin,
+        &ClientMessage::FolderOperation {
+            folder_id: "folder1".into(),
+            operation: FileOperation::SetMetadata {
+                relative_path: relative("script.sh"),
+                mode: Some(0o755),
+                readonly: Some(false),
+                mtime: Some(1_700_000_000),
+            },
+        },
+    )
+    .await;
+
+    match receive_message(&mut ws_backup).await {
+        ServerMessage::FolderOperation {
+            operation:
+                FileOperation::SetMetadata {
+                    relative_path,
+                    mode,
+                    mtime,
+                    ..
+                },
+            ..
+        } => {
+            assert_eq!(relative_path.to_str().unwrap(), "script.sh");
+            assert_eq!(mode, Some(0o755));
+            assert_eq!(mtime, Some(1_700_000_000));
+        }
+        broadcast => panic!("Expected FolderOperation broadcast, got {:?}", broadcast),
+    }
+}
+
+#[tokio::test]
+async fn test_postcard_backup_receives_json_origin_operations() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_client(addr).await;
+    assert!(matches!(
+        receive_message(&mut ws_backup).await,
+        ServerMessage::Welcome { .. }
+    ));
+
+    // Asked for in JSON, answered in the new encoding
+    let json = serde_json::to_string(&ClientMessage::Hello {
+        encoding: Encoding::Postcard,
+    })
+    .unwrap();
+    ws_backup.send(Message::Text(json.into())).await.unwrap();
+    match receive_binary(&mut ws_backup).await {
+        ServerMessage::EncodingSelected { encoding } => assert_eq!(encoding, Encoding::Postcard),
+        response => panic!("Expected EncodingSelected response, got {:?}", response),
+    }
+
+    send_binary(
+        &mut ws_backup,
+        &ClientMessage::Authenticate {
+            user_id: "user1".into(),
+            computer_id: "comp2".into(),
+            protocol_version: PROTOCOL_VERSION,
+            token: String::new(),
+            last_applied: None,
+        },
+    )
+    .await;
+    assert!(matches!(
+        receive_binary(&mut ws_backup).await,
+        ServerMessage::Authenticated { .. }
+    ));
+
+    send_message(
+        &mut ws_origin,
+        &ClientMessage::FolderOperation {
+            folder_id: "folder1".into(),
+            operation: FileOperation::CreateFile {
+                relative_path: relative("binary.bin"),
+                content: vec![0, 159, 255],
+                hash: [7; 32],
+                compression: None,
+            },
+        },
+    )
+    .await;
+
+    match receive_binary(&mut ws_backup).await {
+        ServerMessage::FolderOperation {
+            operation: FileOperation::CreateFile { content, hash, .. },
+            ..
+        } => {
+            // Forwarded as is; only backups check the hash
+            assert_eq!(content, vec![0, 159, 255]);
+            assert_eq!(hash, [7; 32]);
+        }
+        broadcast => panic!("Expected FolderOperation broadcast, got {:?}", broadcast),
+    }
+}
+
+#[tokio::test]
+async fn test_undecodable_binary_frame_gets_error() {
+    let (addr, _, _shutdown) = start_test_server().await;
+    let mut ws = connect_client(addr).await;
+    receive_message(&mut ws).await;
+
+    ws.send(Message::Binary(vec![250, 250, 250].into()))
+        .await
+        .unwrap();
+
+    match receive_message(&mut ws).await {
+        ServerMessage::Error { message, .. } => {
+            assert!(message.contains("Unsupported"), "{message}")
+        }
+        response => panic!("Expected Error response, got {:?}", response),
+    }
+}
+
+#[tokio::test]
+async fn test_outdated_protocol_version_is_rejected_and_closed() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+    }
+
+    let mut ws = connect_client(addr).await;
+    match receive_message(&mut ws).await {
+        ServerMessage::Welcome {
+            protocol_version,
+            min_supported,
+        } => {
+            assert_eq!(protocol_version, PROTOCOL_VERSION);
+            assert!(min_supported <= protocol_version);
+        }
+        response => panic!("Expected Welcome, got {:?}", response),
+    }
+
+    let response = send_and_receive(
+        &mut ws,
+        &ClientMessage::Authenticate {
+            user_id: "user1".into(),
+            computer_id: "comp1".into(),
+            protocol_version: 0,
+            token: String::new(),
+            last_applied: None,
+        },
+    )
+    .await;
+    match response {
+        ServerMessage::Error { message, .. } => {
+            assert!(
+                message.contains("Protocol version 0 is not supported"),
+                "{message}"
+            )
+        }
+        response => panic!("Expected Error response, got {:?}", response),
+    }
+
+    // The server closes the connection instead of leaving the client waiting
+    let closed = timeout(Duration::from_secs(5), ws.next())
+        .await
+        .expect("Timeout waiting for close");
+    assert!(matches!(closed, Some(Ok(Message::Close(_))) | None));
+}
+
+#[tokio::test]
+async fn test_request_errors_carry_request_id() {
+    let (addr, _, _shutdown) = start_test_server().await;
+    let mut ws = connect_client(addr).await;
+    receive_message(&mut ws).await;
+
+    let response = send_request(
+        &mut ws,
+        7,
+        ClientMessage::CreateSyncFolder {
+            name: "Folder".to_string(),
+        },
+    )
+    .await;
+
+    assert!(matches!(response, ServerMessage::Error { .. }));
+}
+
+#[tokio::test]
+async fn test_interleaved_requests_are_correlated() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+    }
+    let mut ws = connect_and_auth(addr, "user1", "comp1").await;
+
+    // Both requests are in flight before reading any response
+    for (request_id, message) in [
+        (
+            1,
+            ClientMessage::CreateSyncFolder {
+                name: "Photos".to_string(),
+            },
+        ),
+        (
+            2,
+            ClientMessage::JoinSyncFolder {
+                folder_id: "missing".into(),
+            },
+        ),
+        (3, ClientMessage::GetUserState),
+    ] {
+        let request = ClientMessage::Request {
+            request_id,
+            message: Box::new(message),
+        };
+        let json = serde_json::to_string(&request).unwrap();
+        ws.send(Message::Text(json.into())).await.unwrap();
+    }
+
+    let mut responses = std::collections::HashMap::new();
+    for _ in 0..3 {
+        match receive_message(&mut ws).await {
+            ServerMessage::Response {
+                request_id,
+                message,
+            } => {
+                responses.insert(request_id, *message);
+            }
+            response => panic!("Expected Response, got {:?}", response),
+        }
+    }
+
+    assert!(matches!(
+        responses.remove(&1),
+        Some(ServerMessage::SyncFolderCreated { .. })
+    ));
+    assert!(matches!(
+        responses.remove(&2),
+        Some(ServerMessage::Error { .. })
+    ));
+    match responses.remove(&3) {
+        Some(ServerMessage::UserState { user }) => assert_eq!(user.sync_folders.len(), 1),
+        response => panic!("Expected UserState, got {:?}", response),
+    }
+
+    // Unwrapped messages still get unwrapped responses
+    let response = send_and_receive(&mut ws, &ClientMessage::GetUserState).await;
+    assert!(matches!(response, ServerMessage::UserState { .. }));
+}
+
+#[tokio::test]
+async fn test_delete_sync_folder_notifies_backups() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+
+    let response = send_and_receive(
+        &mut ws_backup,
+        &ClientMessage::DeleteSyncFolder {
+            folder_id: "folder1".into(),
+        },
+    )
+    .await;
+    match response {
+        ServerMessage::SyncFolderDeletionDenied { folder_id, reason } => {
+            assert_eq!(folder_id, "folder1");
+            assert!(reason.contains("origin"), "{reason}");
+        }
+        _ => panic!("Expected SyncFolderDeletionDenied, got {:?}", response),
+    }
+
+    let response = send_and_receive(
+        &mut ws_origin,
+        &ClientMessage::DeleteSyncFolder {
+            folder_id: "folder1".into(),
+        },
+    )
+    .await;
+    assert!(matches!(response, ServerMessage::SyncFolderDeleted { .. }));
+
+    match receive_message(&mut ws_backup).await {
+        ServerMessage::SyncFolderDeleted { folder_id } => assert_eq!(folder_id, "folder1"),
+        broadcast => panic!("Expected SyncFolderDeleted broadcast, got {:?}", broadcast),
+    }
+    let s = state.read().await;
+    assert!(s.get_user(&"user1".into()).unwrap().sync_folders.is_empty());
+}
+
+#[tokio::test]
+async fn test_delete_sync_folder_denied_with_pending_operations() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+    let mut ws = connect_and_auth(addr, "user1", "comp1").await;
+    send_message(
+        &mut ws,
+        &ClientMessage::FolderOperation {
+            folder_id: "folder1".into(),
+            operation: FileOperation::CreateDir {
+                relative_path: relative("dir"),
+            },
+        },
+    )
+    .await;
+
+    let response = send_and_receive(
+        &mut ws,
+        &ClientMessage::DeleteSyncFolder {
+            folder_id: "folder1".into(),
+        },
+    )
+    .await;
+
+    match response {
+        ServerMessage::SyncFolderDeletionDenied { reason, .. } => {
+            assert!(reason.contains("pending"), "{reason}")
+        }
+        _ => panic!("Expected SyncFolderDeletionDenied, got {:?}", response),
+    }
+}
+
+#[tokio::test]
+async fn test_rename_sync_folder_broadcasts_to_all_user_computers() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.computers.push(computer("comp3", "Computer 3"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "New Folder (3)",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+    let mut ws_other = connect_and_auth(addr, "user1", "comp3").await;
+
+    // Not a member of the folder
+    let response = send_and_receive(
+        &mut ws_other,
+        &ClientMessage::RenameSyncFolder {
+            folder_id: "folder1".into(),
+            new_name: "Hijacked".to_string(),
+        },
+    )
+    .await;
+    assert!(matches!(response, ServerMessage::Error { .. }));
+
+    let response = send_and_receive(
+        &mut ws_backup,
+        &ClientMessage::RenameSyncFolder {
+            folder_id: "folder1".into(),
+            new_name: "Photos".to_string(),
+        },
+    )
+    .await;
+    assert!(matches!(response, ServerMessage::SyncFolderRenamed { .. }));
+
+    for ws in [&mut ws_origin, &mut ws_other] {
+        match receive_message(ws).await {
+            ServerMessage::SyncFolderRenamed {
+                folder_id,
+                new_name,
+            } => {
+                assert_eq!(folder_id, "folder1");
+                assert_eq!(new_name, "Photos");
+            }
+            broadcast => panic!("Expected SyncFolderRenamed broadcast, got {:?}", broadcast),
+        }
+    }
+    let s = state.read().await;
+    assert_eq!(
+        s.get_folder(&"user1".into(), &"folder1".into())
+            .unwrap()
+            .name,
+        "Photos"
+    );
+}
+
+#[tokio::test]
+async fn test_remove_computer() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+
+    let response = send_and_receive(
+        &mut ws_backup,
+        &ClientMessage::RemoveComputer {
+            computer_id: "comp1".into(),
+        },
+    )
+    .await;
+    match response {
+        ServerMessage::ComputerRemovalDenied {
+            computer_id,
+            blocking_folders,
+            ..
+        } => {
+            assert_eq!(computer_id, "comp1");
+            assert_eq!(blocking_folders, vec!["folder1"]);
+        }
+        _ => panic!("Expected ComputerRemovalDenied, got {:?}", response),
+    }
+
+    // A computer can remove itself
+    let response = send_and_receive(
+        &mut ws_backup,
+        &ClientMessage::RemoveComputer {
+            computer_id: "comp2".into(),
+        },
+    )
+    .await;
+    assert!(matches!(response, ServerMessage::ComputerRemoved { .. }));
+
+    match receive_message(&mut ws_origin).await {
+        ServerMessage::ComputerRemoved { computer_id } => assert_eq!(computer_id, "comp2"),
+        broadcast => panic!("Expected ComputerRemoved broadcast, got {:?}", broadcast),
+    }
+    let s = state.read().await;
+    let user = s.get_user(&"user1".into()).unwrap();
+    assert_eq!(user.computers.len(), 1);
+    assert!(user.sync_folders[0].backup_computers.is_empty());
+}
+
+#[tokio::test]
+async fn test_ping_pong() {
+    let (addr, _, _shutdown) = start_test_server().await;
+    let mut ws = connect_client(addr).await;
+    let welcome = receive_message(&mut ws).await;
+    assert!(matches!(welcome, ServerMessage::Welcome { .. }));
+
+    let response = send_and_receive(&mut ws, &ClientMessage::Ping { nonce: 7 }).await;
+
+    assert!(matches!(response, ServerMessage::Pong { nonce: 7 }));
+}
+
+#[tokio::test]
+async fn test_server_listens_on_every_address() {
+    let config = ServerConfig {
+        addrs: vec!["127.0.0.1:0".to_string(), "[::1]:0".to_string()],
+        allow_insecure_auth: true,
+        ..ServerConfig::default()
+    };
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (_shutdown, shutdown_rx) = watch::channel(false);
+    tokio::spawn(run_server(config, Some(ready_tx), shutdown_rx));
+    let ready = ready_rx.await.expect("Server failed to start");
+    assert_eq!(ready.addrs.len(), 2);
+    assert_eq!(ready.addr, ready.addrs[0]);
+    assert!(ready.addrs[0].is_ipv4());
+    assert!(ready.addrs[1].is_ipv6());
+    {
+        let mut s = ready.state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+    }
+
+    let mut ws_v4 = connect_and_auth(ready.addrs[0], "user1", "comp1").await;
+    let mut ws_v6 = connect_and_auth(ready.addrs[1], "user1", "comp2").await;
+    for ws in [&mut ws_v4, &mut ws_v6] {
+        assert!(matches!(
+            send_and_receive(ws, &ClientMessage::GetUserState).await,
+            ServerMessage::UserState { .. }
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_failing_to_bind_one_address_is_fatal_unless_best_effort() {
+    let taken = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = ServerConfig {
+        addrs: vec![
+            "127.0.0.1:0".to_string(),
+            taken.local_addr().unwrap().to_string(),
+        ],
+        allow_insecure_auth: true,
+        ..ServerConfig::default()
+    };
+    let (_shutdown, shutdown_rx) = watch::channel(false);
+
+    let err = run_server(config.clone(), None, shutdown_rx.clone())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().starts_with("Failed to listen on"), "{err}");
+
+    let (ready_tx, ready_rx) = oneshot::channel();
+    tokio::spawn(run_server(
+        ServerConfig {
+            best_effort_bind: true,
+            ..config.clone()
+        },
+        Some(ready_tx),
+        shutdown_rx.clone(),
+    ));
+    let ready = ready_rx.await.expect("Server failed to start");
+    assert_eq!(ready.addrs, vec![ready.addr]);
+    let mut ws = connect_client(ready.addr).await;
+    assert!(matches!(
+        receive_message(&mut ws).await,
+        ServerMessage::Welcome { .. }
+    ));
+
+    // Best effort still needs one address
+    let err = run_server(
+        ServerConfig {
+            addrs: vec![taken.local_addr().unwrap().to_string()],
+            best_effort_bind: true,
+            ..config
+        },
+        None,
+        shutdown_rx,
+    )
+    .await
+    .unwrap_err();
+    assert!(err.to_string().starts_with("Failed to listen on"), "{err}");
+}
+
+#[tokio::test]
+async fn test_state_survives_restart_with_data_path() {
+    let data_dir = tempfile::TempDir::new().unwrap();
+    let config = ServerConfig {
+        addrs: vec!["127.0.0.1:0".to_string()],
+        data_path: Some(data_dir.path().join("state.json")),
+        persist_interval: Duration::from_millis(50),
+        allow_insecure_auth: true,
+        ..ServerConfig::default()
+    };
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (shutdown, shutdown_rx) = watch::channel(false);
+    let server = tokio::spawn(run_server(config.clone(), Some(ready_tx), shutdown_rx));
+    let ready = ready_rx.await.expect("Server failed to start");
+    {
+        let mut s = ready.state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+    }
+    let mut ws = connect_and_auth(ready.addr, "user1", "comp1").await;
+    let response = send_and_receive(
+        &mut ws,
+        &ClientMessage::CreateSyncFolder {
+            name: "Documents".to_string(),
+        },
+    )
+    .await;
+    let ServerMessage::SyncFolderCreated { folder } = response else {
+        panic!("Expected SyncFolderCreated, got {:?}", response);
+    };
+
+    let storage = JsonFileStorage::new(data_dir.path().join("state.json"));
+    let saved = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Ok(Some(saved)) = storage.load()
+                && !saved.users[&UserId::from("user1")].sync_folders.is_empty()
+            {
+                return saved;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("State was never saved");
+    assert!(!saved.users[&UserId::from("user1")].computers[0].online);
+    shutdown.send(true).unwrap();
+    server.await.unwrap().unwrap();
+    drop(ws);
+
+    let (addr, state, _shutdown) = start_test_server_with(config).await;
+    let mut ws = connect_and_auth(addr, "user1", "comp1").await;
+    let response = send_and_receive(&mut ws, &ClientMessage::ListFolders).await;
+    match response {
+        ServerMessage::FolderList { folders } => {
+            assert_eq!(folders.len(), 1);
+            assert_eq!(folders[0].id, folder.id);
+            assert!(folders[0].is_origin);
+        }
+        _ => panic!("Expected FolderList, got {:?}", response),
+    }
+    assert!(
+        state
+            .read()
+            .await
+            .get_folder(&"user1".into(), &folder.id)
+            .is_some()
+    );
+}
+
+#[tokio::test]
+async fn test_server_starts_from_imported_snapshot() {
+    let data_dir = tempfile::TempDir::new().unwrap();
+    let user_id = UserId::from("user1");
+    let folder_id = FolderId::from("folder1");
+    let mut exported = ServerState::new();
+    {
+        let user = exported.get_or_create_user(&user_id);
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Folder 1",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+    let operation_id = exported.next_operation_id();
+    exported.increment_pending_operations(&user_id, &folder_id);
+    exported.track_operation(&folder_id, operation_id, vec!["comp2".into()]);
+    let snapshot_path = data_dir.path().join("snapshot.json");
+    exported.export_snapshot().write(&snapshot_path).unwrap();
+
+    let (addr, _state, _shutdown) = start_test_server_with(ServerConfig {
+        data_path: Some(data_dir.path().join("state.json")),
+        persist_interval: Duration::from_millis(50),
+        import_state: Some(snapshot_path),
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+    send_message(&mut ws_backup, &ClientMessage::Ack { operation_id }).await;
+
+    match receive_message(&mut ws_origin).await {
+        ServerMessage::OperationComplete {
+            operation_id: completed,
+        } => assert_eq!(completed, operation_id),
+        response => panic!("Expected OperationComplete, got {:?}", response),
+    }
+
+    // The imported state replaces the one in the data path
+    let storage = JsonFileStorage::new(data_dir.path().join("state.json"));
+    timeout(Duration::from_secs(5), async {
+        loop {
+            if let Ok(Some(saved)) = storage.load()
+                && saved.operation_counter == operation_id
+                && saved
+                    .pending_operations
+                    .get(&folder_id)
+                    .is_none_or(BTreeMap::is_empty)
+            {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Imported state was never saved");
+}
+
+#[tokio::test]
+async fn test_shutdown_says_goodbye_and_closes_connections() {
+    let data_dir = tempfile::TempDir::new().unwrap();
+    let config = ServerConfig {
+        addrs: vec!["127.0.0.1:0".to_string()],
+        data_path: Some(data_dir.path().join("state.json")),
+        // Only the save on shutdown happens during the test
+        persist_interval: Duration::from_secs(3600),
+        allow_insecure_auth: true,
+        ..ServerConfig::default()
+    };
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (shutdown, shutdown_rx) = watch::channel(false);
+    let server = tokio::spawn(run_server(config, Some(ready_tx), shutdown_rx));
+    let ready = ready_rx.await.expect("Server failed to start");
+    ready
+        .state
+        .write()
+        .await
+        .get_or_create_user(&"user1".into())
+        .computers
+        .push(computer("comp1", "Computer 1"));
+    let mut ws = connect_and_auth(ready.addr, "user1", "comp1").await;
+    let response = send_and_receive(
+        &mut ws,
+        &ClientMessage::CreateSyncFolder {
+            name: "Documents".to_string(),
+        },
+    )
+    .await;
+    assert!(matches!(response, ServerMessage::SyncFolderCreated { .. }));
+
+    shutdown.send(true).unwrap();
+
+    assert!(matches!(
+        receive_message(&mut ws).await,
+        ServerMessage::Goodbye
+    ));
+    let closed = timeout(Duration::from_secs(5), ws.next())
+        .await
+        .expect("Timeout waiting for close");
+    match closed {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Away),
+        closed => panic!("Expected a close frame, got {closed:?}"),
+    }
+    timeout(Duration::from_secs(5), server)
+        .await
+        .expect("Server did not stop")
+        .unwrap()
+        .unwrap();
+    assert!(
+        tokio_tungstenite::connect_async(format!("ws://{}", ready.addr))
+            .await
+            .is_err()
+    );
+    let saved = JsonFileStorage::new(data_dir.path().join("state.json"))
+        .load()
+        .unwrap()
+        .expect("State was not saved on shutdown");
+    assert_eq!(saved.users[&UserId::from("user1")].sync_folders.len(), 1);
+}
+
+#[tokio::test]
+async fn test_idle_connection_goes_offline() {
+    let (addr, state, _shutdown) = start_test_server_with(ServerConfig {
+        idle_timeout: Duration::from_millis(300),
+        ..ServerConfig::default()
+    })
+    .await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+    }
+    let mut ws = connect_and_auth(addr, "user1", "comp1").await;
+
+    // Pings keep the connection alive past the timeout
+    for nonce in 0..3 {
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let response = send_and_receive(&mut ws, &ClientMessage::Ping { nonce }).await;
+        assert!(matches!(response, ServerMessage::Pong { .. }));
+    }
+    assert!(
+        state
+            .read()
+            .await
+            .computer_connections
+            .contains_key(&(UserId::from("user1"), ComputerId::from("comp1")))
+    );
+
+    // Then the client stops talking
+    let closed = timeout(Duration::from_secs(5), async {
+        loop {
+            match ws.next().await {
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => {}
+                Some(Err(_)) => break,
+            }
+        }
+    })
+    .await;
+    assert!(closed.is_ok(), "Server did not close the idle connection");
+
+    let s = state.read().await;
+    assert!(s.computer_connections.is_empty());
+    let user = s.get_user(&"user1".into()).unwrap();
+    assert!(!user.computers[0].online);
+}
+
+#[tokio::test]
+async fn test_unauthenticated_connection_is_dropped_after_auth_timeout() {
+    let (addr, state, _shutdown) = start_test_server_with(ServerConfig {
+        auth_timeout: Duration::from_millis(300),
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut ws = connect_client(addr).await;
+    assert!(matches!(
+        receive_message(&mut ws).await,
+        ServerMessage::Welcome { .. }
+    ));
+
+    // Then nothing is sent
+    assert!(matches!(
+        receive_message(&mut ws).await,
+        ServerMessage::AuthTimeout { .. }
+    ));
+    let closed = timeout(Duration::from_secs(5), async {
+        loop {
+            match ws.next().await {
+                Some(Ok(Message::Close(frame))) => {
+                    break frame.map(|frame| frame.code);
+                }
+                None | Some(Err(_)) => break None,
+                Some(Ok(_)) => {}
+            }
+        }
+    })
+    .await
+    .expect("Server did not close the unauthenticated connection");
+    assert_eq!(closed, Some(CloseCode::Policy));
+
+    let s = state.read().await;
+    assert_eq!(s.metrics_snapshot().connections, 0);
+    assert_eq!(
+        s.metrics_snapshot().counters.errors.get("AuthTimeout"),
+        Some(&1)
+    );
+}
+
+#[tokio::test]
+async fn test_authenticated_connection_outlives_auth_timeout() {
+    let (addr, state, _shutdown) = start_test_server_with(ServerConfig {
+        auth_timeout: Duration::from_millis(200),
+        ..ServerConfig::default()
+    })
+    .await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+    }
+    let mut ws = connect_and_auth(addr, "user1", "comp1").await;
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let response = send_and_receive(&mut ws, &ClientMessage::Ping { nonce: 1 }).await;
+    assert!(matches!(response, ServerMessage::Pong { nonce: 1 }));
+}
+
+#[tokio::test]
+async fn test_connections_over_unauthenticated_limit_are_closed() {
+    let (addr, state, _shutdown) = start_test_server_with(ServerConfig {
+        max_unauthenticated_connections: Some(1),
+        ..ServerConfig::default()
+    })
+    .await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+    }
+    let mut waiting = connect_client(addr).await;
+    assert!(matches!(
+        receive_message(&mut waiting).await,
+        ServerMessage::Welcome { .. }
+    ));
+
+    let mut refused = connect_client(addr).await;
+    match timeout(Duration::from_secs(5), refused.next()).await {
+        Ok(Some(Ok(Message::Close(Some(frame))))) => assert_eq!(frame.code, CloseCode::Again),
+        other => panic!("Expected the connection to be closed, got {:?}", other),
+    }
+
+    // Once the first one authenticated, there is room again
+    let auth = send_and_receive(
+        &mut waiting,
+        &ClientMessage::Authenticate {
+            user_id: "user1".into(),
+            computer_id: "comp1".into(),
+            protocol_version: PROTOCOL_VERSION,
+            token: String::new(),
+            last_applied: None,
+        },
+    )
+    .await;
+    assert!(matches!(auth, ServerMessage::Authenticated { .. }));
+    let mut accepted = connect_client(addr).await;
+    assert!(matches!(
+        receive_message(&mut accepted).await,
+        ServerMessage::Welcome { .. }
+    ));
+}
+
+#[tokio::test]
+async fn test_connection_missing_pongs_is_dropped() {
+    let (addr, state, _shutdown) = start_test_server_with(ServerConfig {
+        ping_interval: Duration::from_millis(100),
+        max_missed_pongs: 2,
+        ..ServerConfig::default()
+    })
+    .await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+    }
+    // Pongs are only sent while the stream is read, so this client looks dead
+    let _stalled = connect_and_auth(addr, "user1", "comp1").await;
+    let mut responsive = connect_and_auth(addr, "user1", "comp2").await;
+    let reader = tokio::spawn(async move { while let Some(Ok(_)) = responsive.next().await {} });
+
+    let reaped = timeout(Duration::from_secs(5), async {
+        loop {
+            if !state
+                .read()
+                .await
+                .computer_connections
+                .contains_key(&(UserId::from("user1"), ComputerId::from("comp1")))
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await;
+    assert!(
+        reaped.is_ok(),
+        "Server did not drop the unresponsive connection"
+    );
+
+    let s = state.read().await;
+    let user = s.get_user(&"user1".into()).unwrap();
+    assert!(!user.computers[0].online);
+    assert!(user.computers[1].online);
+    assert_eq!(s.connections.len(), 1);
+    assert!(!reader.is_finished());
+}
+
+#[tokio::test]
+async fn test_computer_status_changes_reach_other_connections() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        let other = s.get_or_create_user(&"user2".into());
+        other.computers.push(computer("comp3", "Computer 3"));
+    }
+    let mut ws_first = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_other_user = connect_and_auth(addr, "user2", "comp3").await;
+
+    let ws_second = connect_and_auth(addr, "user1", "comp2").await;
+    match receive_any(&mut ws_first).await {
+        ServerMessage::ComputerStatusChanged {
+            computer_id,
+            online,
+        } => {
+            assert_eq!(computer_id, "comp2");
+            assert!(online);
+        }
+        message => panic!("Expected ComputerStatusChanged, got {:?}", message),
+    }
+
+    drop(ws_second);
+    match receive_any(&mut ws_first).await {
+        ServerMessage::ComputerStatusChanged {
+            computer_id,
+            online,
+        } => {
+            assert_eq!(computer_id, "comp2");
+            assert!(!online);
+        }
+        message => panic!("Expected ComputerStatusChanged, got {:?}", message),
+    }
+
+    // Other users hear nothing
+    let response = send_and_receive(&mut ws_other_user, &ClientMessage::Ping { nonce: 1 }).await;
+    assert!(matches!(response, ServerMessage::Pong { nonce: 1 }));
+}
+
+#[tokio::test]
+async fn test_folder_sequences_are_independent_and_gap_free() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        for folder_id in ["folder1", "folder2"] {
+            user.sync_folders.push(sync_folder(
+                folder_id,
+                folder_id,
+                "comp1",
+                vec!["comp2"],
+                true,
+            ));
+        }
+    }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+
+    let mut received = Vec::new();
+    for folder_id in ["folder1", "folder2", "folder1", "folder1", "folder2"] {
+        send_message(
+            &mut ws_origin,
+            &ClientMessage::FolderOperation {
+                folder_id: folder_id.into(),
+                operation: FileOperation::CreateDir {
+                    relative_path: relative("dir"),
+                },
+            },
+        )
+        .await;
+        match receive_message(&mut ws_backup).await {
+            ServerMessage::FolderOperation {
+                folder_id,
+                sequence,
+                ..
+            } => received.push((folder_id, sequence)),
+            message => panic!("Expected FolderOperation, got {:?}", message),
+        }
+    }
+
+    let folder1: Vec<u64> = received
+        .iter()
+        .filter(|(folder_id, _)| folder_id == "folder1")
+        .map(|(_, sequence)| *sequence)
+        .collect();
+    let folder2: Vec<u64> = received
+        .iter()
+        .filter(|(folder_id, _)| folder_id == "folder2")
+        .map(|(_, sequence)| *sequence)
+        .collect();
+    assert_eq!(folder1, vec![1, 2, 3]);
+    assert_eq!(folder2, vec![1, 2]);
+
+    let response = send_and_receive(
+        &mut ws_backup,
+        &ClientMessage::GetFolderSequence {
+            folder_id: "folder2".into(),
+        },
+    )
+    .await;
+    assert!(matches!(
+        response,
+        ServerMessage::FolderSequence { sequence: 2, .. }
+    ));
+
+    let response = send_and_receive(
+        &mut ws_backup,
+        &ClientMessage::GetFolderSequence {
+            folder_id: "unknown".into(),
+        },
+    )
+    .await;
+    assert!(matches!(response, ServerMessage::Error { .. }));
+}
+
+/// Server with `comp1` the origin of `folder1` and `comp2` its backup, connected and
+/// caught up on it
+async fn start_lagging_server(
+    config: ServerConfig,
+) -> (ServerReady, WsStream, watch::Sender<bool>) {
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(run_server(
+        ServerConfig {
+            addrs: vec!["127.0.0.1:0".to_string()],
+            allow_insecure_auth: true,
+            ..config
+        },
+        Some(ready_tx),
+        shutdown_rx,
+    ));
+    let ready = ready_rx.await.expect("Server failed to start");
+    {
+        let mut s = ready.state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+    let mut ws_backup = connect_and_auth(ready.addr, "user1", "comp2").await;
+    let response = send_and_receive(
+        &mut ws_backup,
+        &ClientMessage::CatchUp {
+            folder_id: "folder1".into(),
+            last_sequence: 0,
+        },
+    )
+    .await;
+    assert!(matches!(
+        response,
+        ServerMessage::FolderSequence { sequence: 0, .. }
+    ));
+    (ready, ws_backup, shutdown_tx)
+}
+
+/// Journals and broadcasts `count` operations of `folder1` at once, without giving the
+/// connections a chance to keep up
+async fn flood_operations(ready: &ServerReady, count: usize) {
+    let mut s = ready.state.write().await;
+    let folder_id = FolderId::from("folder1");
+    for i in 0..count {
+        let operation_id = s.next_operation_id();
+        let sequence = s.next_folder_sequence(&folder_id);
+        let message = ServerMessage::FolderOperation {
+            folder_id: folder_id.clone(),
+            operation_id,
+            sequence,
+            operation: FileOperation::CreateDir {
+                relative_path: relative(&format!("dir{i}")),
+            },
+        };
+        s.journal.record(
+            &folder_id,
+            JournalEntry {
+                operation_id,
+                sequence,
+                message: message.clone(),
+            },
+        );
+        ready.broadcasts.send(BroadcastMessage {
+            user_id: "user1".into(),
+            message,
+            audience: Audience::FolderBackups {
+                folder_id: folder_id.clone(),
+            },
+        });
+    }
+}
+
+#[tokio::test]
+async fn test_lagging_backup_is_caught_up_from_journal() {
+    let (ready, mut ws_backup, _shutdown) = start_lagging_server(ServerConfig {
+        broadcast_capacity: 4,
+        ..ServerConfig::default()
+    })
+    .await;
+
+    flood_operations(&ready, 10).await;
+
+    // Every operation once and in order, those still buffered not sent again
+    for expected in 1..=10 {
+        match receive_message(&mut ws_backup).await {
+            ServerMessage::FolderOperation { sequence, .. } => assert_eq!(sequence, expected),
+            message => panic!("Expected FolderOperation, got {:?}", message),
+        }
+    }
+    assert!(matches!(
+        receive_message(&mut ws_backup).await,
+        ServerMessage::FolderSequence { sequence: 10, .. }
+    ));
+    assert!(
+        timeout(Duration::from_millis(200), receive_message(&mut ws_backup))
+            .await
+            .is_err()
+    );
+    assert_eq!(
+        ready
+            .state
+            .read()
+            .await
+            .metrics_snapshot()
+            .counters
+            .broadcast_lagged,
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_lagging_backup_past_the_journal_is_told_to_resync() {
+    let (ready, mut ws_backup, _shutdown) = start_lagging_server(ServerConfig {
+        broadcast_capacity: 4,
+        journal_capacity: 2,
+        ..ServerConfig::default()
+    })
+    .await;
+
+    flood_operations(&ready, 10).await;
+
+    // Ahead of what the backup applied, which makes it ask for a full sync
+    assert!(matches!(
+        receive_message(&mut ws_backup).await,
+        ServerMessage::FolderSequence { sequence: 10, .. }
+    ));
+}
+
+#[tokio::test]
+async fn test_reconnected_backup_catches_up_from_journal() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+    ws_backup.close(None).await.unwrap();
+    timeout(Duration::from_secs(5), async {
+        while state
+            .read()
+            .await
+            .connection_of(&"user1".into(), &"comp2".into())
+            .is_some()
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Backup never disconnected");
+
+    for name in ["a", "b", "c"] {
+        send_message(
+            &mut ws_origin,
+            &ClientMessage::FolderOperation {
+                folder_id: "folder1".into(),
+                operation: FileOperation::CreateDir {
+                    relative_path: relative(name),
+                },
+            },
+        )
+        .await;
+    }
+    wait_for_sequence(&state, "folder1", 3).await;
+
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+    send_message(
+        &mut ws_backup,
+        &ClientMessage::CatchUp {
+            folder_id: "folder1".into(),
+            last_sequence: 0,
+        },
+    )
+    .await;
+    let mut replayed = Vec::new();
+    for _ in 0..3 {
+        match receive_message(&mut ws_backup).await {
+            ServerMessage::FolderOperation {
+                operation_id,
+                sequence,
+                operation: FileOperation::CreateDir { relative_path },
+                ..
+            } => replayed.push((operation_id, sequence, relative_path)),
+            message => panic!("Expected FolderOperation, got {:?}", message),
+        }
+    }
+    let sequences: Vec<_> = replayed.iter().map(|(_, sequence, _)| *sequence).collect();
+    let paths: Vec<_> = replayed.iter().map(|(_, _, path)| path.clone()).collect();
+    assert_eq!(sequences, vec![1, 2, 3]);
+    assert_eq!(paths, vec![relative("a"), relative("b"), relative("c")]);
+    assert!(matches!(
+        receive_message(&mut ws_backup).await,
+        ServerMessage::FolderSequence { sequence: 3, .. }
+    ));
+
+    // Live operations keep coming after the replay
+    send_message(
+        &mut ws_origin,
+        &ClientMessage::FolderOperation {
+            folder_id: "folder1".into(),
+            operation: FileOperation::CreateDir {
+                relative_path: relative("d"),
+            },
+        },
+    )
+    .await;
+    assert!(matches!(
+        receive_message(&mut ws_backup).await,
+        ServerMessage::FolderOperation { sequence: 4, .. }
+    ));
+
+    // Acked operations leave the journal
+    for (operation_id, _, _) in replayed {
+        send_message(&mut ws_backup, &ClientMessage::Ack { operation_id }).await;
+    }
+    timeout(Duration::from_secs(5), async {
+        while state.read().await.journal.len(&"folder1".into()) != 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Journal was not truncated");
+}
+
+#[tokio::test]
+async fn test_backup_resumes_from_last_applied_on_authentication() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+    let create_dir = |name| ClientMessage::FolderOperation {
+        folder_id: "folder1".into(),
+        operation: FileOperation::CreateDir {
+            relative_path: relative(name),
+        },
+    };
+
+    for name in ["a", "b"] {
+        send_message(&mut ws_origin, &create_dir(name)).await;
+        loop {
+            match receive_message(&mut ws_backup).await {
+                ServerMessage::FolderOperation { operation_id, .. } => {
+                    send_message(&mut ws_backup, &ClientMessage::Ack { operation_id }).await;
+                    break;
+                }
+                ServerMessage::SyncStatusChanged { .. } => {}
+                message => panic!("Expected FolderOperation, got {:?}", message),
+            }
+        }
+    }
+    ws_backup.close(None).await.unwrap();
+    timeout(Duration::from_secs(5), async {
+        while state
+            .read()
+            .await
+            .connection_of(&"user1".into(), &"comp2".into())
+            .is_some()
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Backup never disconnected");
+
+    for name in ["c", "d"] {
+        send_message(&mut ws_origin, &create_dir(name)).await;
+    }
+    wait_for_sequence(&state, "folder1", 4).await;
+
+    let mut ws_backup = connect_client(addr).await;
+    assert!(matches!(
+        receive_message(&mut ws_backup).await,
+        ServerMessage::Welcome { .. }
+    ));
+    let auth = send_and_receive(
+        &mut ws_backup,
+        &ClientMessage::Authenticate {
+            user_id: "user1".into(),
+            computer_id: "comp2".into(),
+            protocol_version: PROTOCOL_VERSION,
+            token: String::new(),
+            last_applied: Some(BTreeMap::from([("folder1".into(), 2)])),
+        },
+    )
+    .await;
+    assert!(matches!(auth, ServerMessage::Authenticated { .. }));
+
+    // Exactly the operations missed, without asking to catch up
+    let mut replayed = Vec::new();
+    for _ in 0..2 {
+        match receive_message(&mut ws_backup).await {
+            ServerMessage::FolderOperation {
+                sequence,
+                operation: FileOperation::CreateDir { relative_path },
+                ..
+            } => replayed.push((sequence, relative_path)),
+            message => panic!("Expected FolderOperation, got {:?}", message),
+        }
+    }
+    assert_eq!(replayed, vec![(3, relative("c")), (4, relative("d"))]);
+    assert!(matches!(
+        receive_message(&mut ws_backup).await,
+        ServerMessage::FolderSequence { sequence: 4, .. }
+    ));
+
+    // Live operations follow, the replayed ones are not sent again
+    send_message(&mut ws_origin, &create_dir("e")).await;
+    loop {
+        match receive_message(&mut ws_backup).await {
+            ServerMessage::FolderOperation { sequence, .. } => {
+                assert_eq!(sequence, 5);
+                break;
+            }
+            ServerMessage::SyncStatusChanged { .. } => {}
+            message => panic!("Expected FolderOperation, got {:?}", message),
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_users_do_not_block_each_other() {
+    const USERS: usize = 8;
+    const OPERATIONS: u64 = 20;
+    let (addr, state, _shutdown) = start_test_server_with(ServerConfig {
+        rate_limit: None,
+        ..ServerConfig::default()
+    })
+    .await;
+    {
+        let mut s = state.write().await;
+        for user in 0..USERS {
+            let user = s.get_or_create_user(&format!("user{user}").as_str().into());
+            user.computers.push(computer("origin", "Origin"));
+            user.computers.push(computer("backup", "Backup"));
+            user.computers.push(computer("viewer", "Viewer"));
+            user.sync_folders.push(sync_folder(
+                &format!("folder{}", user.id),
+                "Shared Folder",
+                "origin",
+                vec!["backup"],
+                true,
+            ));
+        }
+    }
+
+    let mut tasks = tokio::task::JoinSet::new();
+    for user in 0..USERS {
+        let user_id = format!("user{user}");
+        let folder_id = format!("folder{user_id}");
+        let mut ws_origin = connect_and_auth(addr, &user_id, "origin").await;
+        let mut ws_backup = connect_and_auth(addr, &user_id, "backup").await;
+        let folder = folder_id.clone();
+        tasks.spawn(async move {
+            for operation in 0..OPERATIONS {
+                send_message(
+                    &mut ws_origin,
+                    &ClientMessage::FolderOperation {
+                        folder_id: folder.as_str().into(),
+                        operation: FileOperation::CreateDir {
+                            relative_path: relative(&format!("dir{operation}")),
+                        },
+                    },
+                )
+                .await;
+                send_message(&mut ws_origin, &ClientMessage::GetUserState).await;
+            }
+            ws_origin
+        });
+        tasks.spawn(async move {
+            // Replays after falling behind may send some again
+            let mut applied = std::collections::BTreeSet::new();
+            while applied.len() < OPERATIONS as usize {
+                if let ServerMessage::FolderOperation {
+                    operation_id,
+                    sequence,
+                    ..
+                } = receive_message(&mut ws_backup).await
+                    && applied.insert(sequence)
+                {
+                    send_message(&mut ws_backup, &ClientMessage::Ack { operation_id }).await;
+                }
+            }
+            ws_backup
+        });
+        // Another computer of the user keeps connecting and asking for the folders
+        tasks.spawn(async move {
+            let mut ws = connect_and_auth(addr, &user_id, "viewer").await;
+            for _ in 0..5 {
+                send_message(&mut ws, &ClientMessage::ListFolders).await;
+                ws.close(None).await.unwrap();
+                ws = connect_and_auth(addr, &user_id, "viewer").await;
+            }
+            ws
+        });
+    }
+    let mut connections = Vec::new();
+    timeout(Duration::from_secs(30), async {
+        while let Some(connection) = tasks.join_next().await {
+            connections.push(connection.unwrap());
+        }
+    })
+    .await
+    .expect("Connections never got through their messages");
+
+    timeout(Duration::from_secs(5), async {
+        loop {
+            let s = state.read().await;
+            let settled = (0..USERS).all(|user| {
+                let user_id: UserId = format!("user{user}").as_str().into();
+                let folder_id: FolderId = format!("folder{user_id}").as_str().into();
+                s.pending_count(&folder_id) == 0 && s.is_folder_synced(&user_id, &folder_id)
+            });
+            if settled {
+                break;
+            }
+            drop(s);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Folders never synced");
+    let s = state.read().await;
+    for user in 0..USERS {
+        let folder_id: FolderId = format!("folderuser{user}").as_str().into();
+        assert_eq!(s.folder_sequence(&folder_id), OPERATIONS);
+    }
+    let counters = s.metrics_snapshot().counters;
+    assert_eq!(counters.operations_forwarded, USERS as u64 * OPERATIONS);
+    assert_eq!(counters.acks_received, USERS as u64 * OPERATIONS);
+}
+
+#[tokio::test]
+async fn test_origin_is_held_back_at_the_pending_limit() {
+    let (addr, state, _shutdown) = start_test_server_with(ServerConfig {
+        max_pending_operations: Some(3),
+        ..ServerConfig::default()
+    })
+    .await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let create_dir = |name: &str| ClientMessage::FolderOperation {
+        folder_id: "folder1".into(),
+        operation: FileOperation::CreateDir {
+            relative_path: relative(name),
+        },
+    };
+
+    for name in ["a", "b", "c", "d", "e"] {
+        send_message(&mut ws_origin, &create_dir(name)).await;
+    }
+
+    let mut refused = Vec::new();
+    let mut statuses = 0;
+    while refused.len() < 2 || statuses < 2 {
+        match receive_message(&mut ws_origin).await {
+            ServerMessage::Backpressure {
+                folder_id,
+                paths,
+                pending_operations,
+                limit,
+            } => {
+                assert_eq!(folder_id, FolderId::from("folder1"));
+                assert_eq!((pending_operations, limit), (3, 3));
+                refused.extend(paths);
+            }
+            ServerMessage::SyncStatusChanged {
+                pending_operations,
+                backpressure,
+                ..
+            } => {
+                assert_eq!(pending_operations, 3);
+                assert!(backpressure);
+                statuses += 1;
+            }
+            message => panic!("Expected Backpressure, got {:?}", message),
+        }
+    }
+    assert_eq!(refused, vec![relative("d"), relative("e")]);
+    assert_eq!(state.read().await.pending_count(&"folder1".into()), 3);
+
+    // Acks of the backup make room again
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+    send_message(
+        &mut ws_backup,
+        &ClientMessage::CatchUp {
+            folder_id: "folder1".into(),
+            last_sequence: 0,
+        },
+    )
+    .await;
+    let ServerMessage::FolderOperation { operation_id, .. } = receive_message(&mut ws_backup).await
+    else {
+        panic!("Expected FolderOperation");
+    };
+    send_message(&mut ws_backup, &ClientMessage::Ack { operation_id }).await;
+    assert!(matches!(
+        receive_message(&mut ws_origin).await,
+        ServerMessage::OperationComplete { .. }
+    ));
+    assert!(matches!(
+        receive_message(&mut ws_origin).await,
+        ServerMessage::SyncStatusChanged {
+            backpressure: false,
+            ..
+        }
+    ));
+
+    send_message(&mut ws_origin, &create_dir("d")).await;
+    timeout(Duration::from_secs(5), async {
+        loop {
+            if let ServerMessage::FolderOperation {
+                sequence: 4,
+                operation: FileOperation::CreateDir { relative_path },
+                ..
+            } = receive_message(&mut ws_backup).await
+            {
+                assert_eq!(relative_path, relative("d"));
+                return;
+            }
+        }
+    })
+    .await
+    .expect("Operation was not forwarded");
+}
+
+#[tokio::test]
+async fn test_unanswered_operation_expires_and_full_sync_resyncs_folder() {
+    let (addr, state, _shutdown) = start_test_server_with(ServerConfig {
+        pending_ttl: Some(Duration::from_secs(1)),
+        sweep_interval: Duration::from_millis(100),
+        ..ServerConfig::default()
+    })
+    .await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+    send_message(
+        &mut ws_origin,
+        &ClientMessage::FolderOperation {
+            folder_id: "folder1".into(),
+            operation: FileOperation::CreateDir {
+                relative_path: relative("a"),
+            },
+        },
+    )
+    .await;
+
+    // The backup gets the operation and never answers it
+    assert!(matches!(
+        receive_message(&mut ws_backup).await,
+        ServerMessage::FolderOperation { .. }
+    ));
+    match receive_message(&mut ws_origin).await {
+        ServerMessage::SyncStatusChanged {
+            is_synced,
+            pending_operations,
+            backups,
+            ..
+        } => {
+            assert!(!is_synced);
+            assert_eq!(pending_operations, 0);
+            assert!(backups["comp2"].last_failure.is_some());
+        }
+        message => panic!("Expected SyncStatusChanged, got {:?}", message),
+    }
+    assert_eq!(state.read().await.pending_count(&"folder1".into()), 0);
+    assert_eq!(
+        state
+            .read()
+            .await
+            .metrics_snapshot()
+            .counters
+            .operations_expired,
+        1
+    );
+
+    // A full sync brings the backup, and the folder, back in sync
+    send_message(
+        &mut ws_backup,
+        &ClientMessage::RequestFullSync {
+            folder_id: "folder1".into(),
+        },
+    )
+    .await;
+    assert!(matches!(
+        receive_message(&mut ws_origin).await,
+        ServerMessage::FullSyncRequested { .. }
+    ));
+    send_message(
+        &mut ws_origin,
+        &ClientMessage::TargetedOperation {
+            folder_id: "folder1".into(),
+            computer_id: "comp2".into(),
+            operation: FileOperation::CreateDir {
+                relative_path: relative("a"),
+            },
+        },
+    )
+    .await;
+    let operation_id = loop {
+        if let ServerMessage::FolderOperation { operation_id, .. } =
+            receive_message(&mut ws_backup).await
+        {
+            break operation_id;
+        }
+    };
+    send_message(&mut ws_backup, &ClientMessage::Ack { operation_id }).await;
+    loop {
+        if let ServerMessage::SyncStatusChanged { is_synced, .. } =
+            receive_message(&mut ws_origin).await
+        {
+            assert!(is_synced);
+            break;
+        }
+    }
+    assert!(
+        state
+            .read()
+            .await
+            .is_folder_synced(&"user1".into(), &"folder1".into())
+    );
+}
+
+#[tokio::test]
+async fn test_catch_up_without_journal_reports_sequence_only() {
+    let (addr, state, _shutdown) = start_test_server_with(ServerConfig {
+        journal_capacity: 2,
+        ..ServerConfig::default()
+    })
+    .await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2"],
+            true,
+        ));
+    }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    for name in ["a", "b", "c"] {
+        send_message(
+            &mut ws_origin,
+            &ClientMessage::FolderOperation {
+                folder_id: "folder1".into(),
+                operation: FileOperation::CreateDir {
+                    relative_path: relative(name),
+                },
+            },
+        )
+        .await;
+    }
+    wait_for_sequence(&state, "folder1", 3).await;
+
+    // The first operation fell out of the journal
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+    let response = send_and_receive(
+        &mut ws_backup,
+        &ClientMessage::CatchUp {
+            folder_id: "folder1".into(),
+            last_sequence: 0,
+        },
+    )
+    .await;
+    assert!(matches!(
+        response,
+        ServerMessage::FolderSequence { sequence: 3, .. }
+    ));
+
+    // Only the newer ones are asked for
+    send_message(
+        &mut ws_backup,
+        &ClientMessage::CatchUp {
+            folder_id: "folder1".into(),
+            last_sequence: 1,
+        },
+    )
+    .await;
+    for expected in [2, 3] {
+        match receive_message(&mut ws_backup).await {
+            ServerMessage::FolderOperation { sequence, .. } => assert_eq!(sequence, expected),
+            message => panic!("Expected FolderOperation, got {:?}", message),
+        }
+    }
+    assert!(matches!(
+        receive_message(&mut ws_backup).await,
+        ServerMessage::FolderSequence { sequence: 3, .. }
+    ));
+}
+
+#[tokio::test]
+async fn test_signature_flow_is_routed_between_origin_and_one_backup() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.computers.push(computer("comp3", "Computer 3"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2", "comp3"],
+            true,
+        ));
+    }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+    let mut ws_other = connect_and_auth(addr, "user1", "comp3").await;
+
+    // Every backup is asked for its signature
+    send_message(
+        &mut ws_origin,
+        &ClientMessage::RequestSignature {
+            folder_id: "folder1".into(),
+            relative_path: relative("notes.txt"),
+        },
+    )
+    .await;
+    for ws in [&mut ws_backup, &mut ws_other] {
+        match receive_message(ws).await {
+            ServerMessage::SignatureRequested {
+                folder_id,
+                relative_path,
+            } => {
+                assert_eq!(folder_id, "folder1");
+                assert_eq!(relative_path, std::path::Path::new("notes.txt"));
+            }
+            message => panic!("Expected SignatureRequested, got {:?}", message),
+        }
+    }
+
+    // Only the origin gets the answer, tagged with the backup it comes from
+    send_message(
+        &mut ws_backup,
+        &ClientMessage::SignatureResponse {
+            folder_id: "folder1".into(),
+            relative_path: relative("notes.txt"),
+            signature: vec![1, 2, 3],
+        },
+    )
+    .await;
+    match receive_message(&mut ws_origin).await {
+        ServerMessage::SignatureResponse {
+            computer_id,
+            signature,
+            ..
+        } => {
+            assert_eq!(computer_id, "comp2");
+            assert_eq!(signature, vec![1, 2, 3]);
+        }
+        message => panic!("Expected SignatureResponse, got {:?}", message),
+    }
+
+    // The delta computed against it goes to that backup only
+    send_message(
+        &mut ws_origin,
+        &ClientMessage::TargetedOperation {
+            folder_id: "folder1".into(),
+            computer_id: "comp2".into(),
+            operation: FileOperation::ApplyDelta {
+                transfer_id: 1,
+                relative_path: relative("notes.txt"),
+                delta: vec![4, 5, 6],
+                hash: [0; 32],
+                compression: None,
+            },
+        },
+    )
+    .await;
+    match receive_message(&mut ws_backup).await {
+        ServerMessage::FolderOperation {
+            sequence,
+            operation: FileOperation::ApplyDelta { delta, .. },
+            ..
+        } => {
+            assert_eq!(sequence, 0);
+            assert_eq!(delta, vec![4, 5, 6]);
+        }
+        message => panic!("Expected FolderOperation, got {:?}", message),
+    }
+    assert!(
+        timeout(Duration::from_millis(200), receive_message(&mut ws_other))
+            .await
+            .is_err()
+    );
+
+    {
+        let s = state.read().await;
+        let folder = s.get_folder(&"user1".into(), &"folder1".into()).unwrap();
+        assert_eq!(folder.pending_operations, 1);
+        assert_eq!(folder.backup_status["comp2"].pending_operations, 1);
+        assert!(!folder.backup_status.contains_key("comp3"));
+    }
+
+    // Origin only, and only to backups of the folder
+    let response = send_and_receive(
+        &mut ws_backup,
+        &ClientMessage::RequestSignature {
+            folder_id: "folder1".into(),
+            relative_path: relative("notes.txt"),
+        },
+    )
+    .await;
+    assert!(matches!(response, ServerMessage::Error { .. }));
+    let response = send_and_receive(
+        &mut ws_origin,
+        &ClientMessage::TargetedOperation {
+            folder_id: "folder1".into(),
+            computer_id: "comp1".into(),
+            operation: FileOperation::CreateDir {
+                relative_path: relative("dir"),
+            },
+        },
+    )
+    .await;
+    assert!(matches!(response, ServerMessage::Error { .. }));
+    let response = send_and_receive(
+        &mut ws_origin,
+        &ClientMessage::SignatureResponse {
+            folder_id: "folder1".into(),
+            relative_path: relative("notes.txt"),
+            signature: vec![],
+        },
+    )
+    .await;
+    assert!(matches!(response, ServerMessage::Error { .. }));
+}
+
+#[tokio::test]
+async fn test_manifests_are_routed_between_origin_and_one_backup() {
+    let (addr, state, _shutdown) = start_test_server().await;
+    {
+        let mut s = state.write().await;
+        let user = s.get_or_create_user(&"user1".into());
+        user.computers.push(computer("comp1", "Computer 1"));
+        user.computers.push(computer("comp2", "Computer 2"));
+        user.computers.push(computer("comp3", "Computer 3"));
+        user.sync_folders.push(sync_folder(
+            "folder1",
+            "Shared Folder",
+            "comp1",
+            vec!["comp2", "comp3"],
+            true,
+        ));
+    }
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+    let mut ws_other = connect_and_auth(addr, "user1", "comp3").await;
+
+    // Every backup is asked for its manifest
+    send_message(
+        &mut ws_origin,
+        &ClientMessage::RequestManifest {
+            folder_id: "folder1".into(),
+        },
+    )
+    .await;
+    for ws in [&mut ws_backup, &mut ws_other] {
+        match receive_message(ws).await {
+            ServerMessage::ManifestRequested { folder_id } => assert_eq!(folder_id, "folder1"),
+            message => panic!("Expected ManifestRequested, got {:?}", message),
+        }
+    }
+
+    // Only the origin gets the answer, tagged with the backup it comes from
+    let manifest = Manifest {
+        entries: vec![
+            ManifestEntry {
+                relative_path: relative("notes.txt"),
+                size: 3,
+                mtime: Some(1_700_000_000),
+                hash: [1; 32],
+            },
+            ManifestEntry {
+                relative_path: relative("old.txt"),
+                size: 3,
+                mtime: None,
+                hash: [2; 32],
+            },
+        ],
+    };
+    send_message(
+        &mut ws_backup,
+        &ClientMessage::Manifest {
+            folder_id: "folder1".into(),
+            manifest: manifest.clone(),
+        },
+    )
+    .await;
+    match receive_message(&mut ws_origin).await {
+        ServerMessage::Manifest {
+            folder_id,
+            computer_id,
+            manifest: received,
+        } => {
+            assert_eq!(folder_id, "folder1");
+            assert_eq!(computer_id, "comp2");
+            assert_eq!(received, manifest);
+        }
+        message => panic!("Expected Manifest, got {:?}", message),
+    }
+    assert!(
+        timeout(Duration::from_millis(200), receive_message(&mut ws_other))
+            .await
+            .is_err()
+    );
+
+    // Origin only, and only from backups of the folder
+    let response = send_and_receive(
+        &mut ws_backup,
+        &ClientMessage::RequestManifest {
+            folder_id: "folder1".into(),
+        },
+    )
+    .await;
+    assert!(matches!(response, ServerMessage::Error { .. }));
+    let response = send_and_receive(
+        &mut ws_origin,
+        &ClientMessage::Manifest {
+            folder_id: "folder1".into(),
+            manifest,
+        },
+    )
+    .await;
+    assert!(matches!(response, ServerMessage::Error { .. }));
 }
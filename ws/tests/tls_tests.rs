@@ -0,0 +1,189 @@
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use backup_sync_protocol::{ClientMessage, Computer, PROTOCOL_VERSION, ServerMessage};
+use backup_sync_ws::server::{ServerConfig, ServerReady, run_server};
+use backup_sync_ws::tls::TlsConfig;
+use futures_util::{SinkExt, StreamExt};
+use tempfile::TempDir;
+use tokio::net::TcpStream;
+use tokio::sync::{oneshot, watch};
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_tungstenite::tungstenite::Message;
+
+/// Self-signed certificate for `localhost`, written as PEM files in `dir`
+fn self_signed(dir: &TempDir) -> (TlsConfig, CertificateDer<'static>) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let config = TlsConfig {
+        cert_path: dir.path().join("cert.pem"),
+        key_path: dir.path().join("key.pem"),
+    };
+    fs::write(&config.cert_path, certified.cert.pem()).unwrap();
+    fs::write(&config.key_path, certified.key_pair.serialize_pem()).unwrap();
+    (config, certified.cert.der().clone())
+}
+
+/// Server serving `tls`; it shuts down once the returned sender is dropped
+async fn start_tls_server(tls: TlsConfig) -> (ServerReady, watch::Sender<bool>) {
+    let config = ServerConfig {
+        addrs: vec!["127.0.0.1:0".to_string()],
+        tls: Some(tls),
+        allow_insecure_auth: true,
+        ..ServerConfig::default()
+    };
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(run_server(config, Some(ready_tx), shutdown_rx));
+    (ready_rx.await.expect("Server failed to start"), shutdown_tx)
+}
+
+/// Client trusting only `root`, the way a deployment with its own CA would be set up
+fn connector(root: CertificateDer<'static>) -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add(root).unwrap();
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+async fn receive_message<S>(ws: &mut tokio_tungstenite::WebSocketStream<S>) -> ServerMessage
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    loop {
+        let response = timeout(Duration::from_secs(5), ws.next())
+            .await
+            .expect("Timeout waiting for response")
+            .expect("Stream ended")
+            .expect("WebSocket error");
+        match response {
+            Message::Text(text) => match serde_json::from_str(&text).unwrap() {
+                ServerMessage::ComputerStatusChanged { .. } => {}
+                message => return message,
+            },
+            _ => panic!("Expected text message"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_wss_client_authenticates() {
+    let dir = TempDir::new().unwrap();
+    let (tls, root) = self_signed(&dir);
+    let (ready, _shutdown) = start_tls_server(tls).await;
+    assert!(ready.tls);
+    ready
+        .state
+        .write()
+        .await
+        .get_or_create_user(&"user1".into())
+        .computers
+        .push(Computer {
+            id: "comp1".into(),
+            name: "Computer 1".to_string(),
+            online: false,
+            last_seen: None,
+        });
+
+    let tcp = TcpStream::connect(ready.addr).await.unwrap();
+    let stream = connector(root)
+        .connect(ServerName::try_from("localhost").unwrap(), tcp)
+        .await
+        .unwrap();
+    let url = format!("wss://localhost:{}", ready.addr.port());
+    let (mut ws, _) = tokio_tungstenite::client_async(url, stream).await.unwrap();
+
+    assert!(matches!(
+        receive_message(&mut ws).await,
+        ServerMessage::Welcome { .. }
+    ));
+    let authenticate = ClientMessage::Authenticate {
+        user_id: "user1".into(),
+        computer_id: "comp1".into(),
+        protocol_version: PROTOCOL_VERSION,
+        token: String::new(),
+        last_applied: None,
+    };
+    let json = serde_json::to_string(&authenticate).unwrap();
+    ws.send(Message::Text(json.into())).await.unwrap();
+    assert!(matches!(
+        receive_message(&mut ws).await,
+        ServerMessage::Authenticated { .. }
+    ));
+}
+
+#[tokio::test]
+async fn test_plain_client_is_refused_by_tls_server() {
+    let dir = TempDir::new().unwrap();
+    let (tls, _) = self_signed(&dir);
+    let (ready, _shutdown) = start_tls_server(tls).await;
+
+    let result = timeout(
+        Duration::from_secs(5),
+        tokio_tungstenite::connect_async(format!("ws://{}", ready.addr)),
+    )
+    .await
+    .expect("Timeout waiting for handshake");
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_untrusted_certificate_is_rejected_by_client() {
+    let dir = TempDir::new().unwrap();
+    let (tls, _) = self_signed(&dir);
+    let (ready, _shutdown) = start_tls_server(tls).await;
+    let (_, other_root) = self_signed(&TempDir::new().unwrap());
+
+    let tcp = TcpStream::connect(ready.addr).await.unwrap();
+    let result = connector(other_root)
+        .connect(ServerName::try_from("localhost").unwrap(), tcp)
+        .await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_missing_certificate_fails_to_start() {
+    let dir = TempDir::new().unwrap();
+    let config = ServerConfig {
+        addrs: vec!["127.0.0.1:0".to_string()],
+        tls: Some(TlsConfig {
+            cert_path: dir.path().join("missing.pem"),
+            key_path: dir.path().join("missing.key"),
+        }),
+        allow_insecure_auth: true,
+        ..ServerConfig::default()
+    };
+
+    let (_shutdown, shutdown_rx) = watch::channel(false);
+    assert!(run_server(config, None, shutdown_rx).await.is_err());
+}
+
+#[tokio::test]
+async fn test_plain_server_reports_no_tls() {
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (_shutdown, shutdown_rx) = watch::channel(false);
+    tokio::spawn(run_server(
+        ServerConfig {
+            addrs: vec!["127.0.0.1:0".to_string()],
+            allow_insecure_auth: true,
+            ..ServerConfig::default()
+        },
+        Some(ready_tx),
+        shutdown_rx,
+    ));
+    let ready = ready_rx.await.expect("Server failed to start");
+    assert!(!ready.tls);
+    assert!(
+        tokio_tungstenite::connect_async(format!("ws://{}", ready.addr))
+            .await
+            .is_ok()
+    );
+}
@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use backup_sync_protocol::{
+    ClientMessage, Computer, FileOperation, PROTOCOL_VERSION, RelativePath, ServerMessage,
+    SyncFolder,
+};
+use backup_sync_ws::server::{ServerConfig, run_server};
+use backup_sync_ws::state::ServerState;
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::{RwLock, oneshot, watch};
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{Instrument, Span};
+use tracing_test::traced_test;
+
+type WsStream =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Server whose connections log within the span of the test, where `logs_contain` looks.
+/// It shuts down once the returned sender is dropped.
+async fn start_traced_server() -> (SocketAddr, Arc<RwLock<ServerState>>, watch::Sender<bool>) {
+    let config = ServerConfig {
+        addrs: vec!["127.0.0.1:0".to_string()],
+        allow_insecure_auth: true,
+        ..ServerConfig::default()
+    };
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(run_server(config, Some(ready_tx), shutdown_rx).instrument(Span::current()));
+    let ready = ready_rx.await.expect("Server failed to start");
+    (ready.addr, ready.state, shutdown_tx)
+}
+
+async fn send_message(ws: &mut WsStream, msg: &ClientMessage) {
+    let json = serde_json::to_string(msg).unwrap();
+    ws.send(Message::Text(json.into())).await.unwrap();
+}
+
+async fn receive_message(ws: &mut WsStream) -> ServerMessage {
+    loop {
+        let response = timeout(Duration::from_secs(5), ws.next())
+            .await
+            .expect("Timeout waiting for response")
+            .expect("Stream ended")
+            .expect("WebSocket error");
+        match response {
+            Message::Text(text) => match serde_json::from_str(&text).unwrap() {
+                ServerMessage::ComputerStatusChanged { .. } => {}
+                message => return message,
+            },
+            _ => panic!("Expected text message"),
+        }
+    }
+}
+
+async fn connect_and_auth(addr: SocketAddr, user_id: &str, computer_id: &str) -> WsStream {
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
+        .await
+        .unwrap();
+    assert!(matches!(
+        receive_message(&mut ws).await,
+        ServerMessage::Welcome { .. }
+    ));
+    send_message(
+        &mut ws,
+        &ClientMessage::Authenticate {
+            user_id: user_id.into(),
+            computer_id: computer_id.into(),
+            protocol_version: PROTOCOL_VERSION,
+            token: String::new(),
+            last_applied: None,
+        },
+    )
+    .await;
+    assert!(matches!(
+        receive_message(&mut ws).await,
+        ServerMessage::Authenticated { .. }
+    ));
+    ws
+}
+
+async fn add_folder(state: &RwLock<ServerState>) {
+    let mut s = state.write().await;
+    let user = s.get_or_create_user(&"user1".into());
+    for id in ["comp1", "comp2"] {
+        user.computers.push(Computer {
+            id: id.into(),
+            name: id.to_string(),
+            online: false,
+            last_seen: None,
+        });
+    }
+    user.sync_folders.push(SyncFolder {
+        id: "folder1".into(),
+        name: "Shared Folder".to_string(),
+        origin_computer: "comp1".into(),
+        backup_computers: vec!["comp2".into()],
+        is_synced: true,
+        pending_operations: 0,
+        backup_status: BTreeMap::new(),
+    });
+}
+
+fn create_dir(path: &str) -> ClientMessage {
+    ClientMessage::FolderOperation {
+        folder_id: "folder1".into(),
+        operation: FileOperation::CreateDir {
+            relative_path: RelativePath::try_from(path).unwrap(),
+        },
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_authenticate_logs_connection_fields() {
+    let (addr, state, _shutdown) = start_traced_server().await;
+    add_folder(&state).await;
+
+    let mut ws = connect_and_auth(addr, "user1", "comp1").await;
+    // Logged once the connection span knows who is connected
+    send_message(&mut ws, &ClientMessage::Ping { nonce: 1 }).await;
+    assert!(matches!(
+        receive_message(&mut ws).await,
+        ServerMessage::Pong { nonce: 1 }
+    ));
+
+    assert!(logs_contain("connection{peer="));
+    assert!(logs_contain("received message kind=\"Authenticate\""));
+    assert!(logs_contain(
+        "authenticated user_id=user1 computer_id=comp1"
+    ));
+    assert!(logs_contain(
+        "user_id=user1 computer_id=comp1}:message{trace_id="
+    ));
+    assert!(logs_contain(
+        "}: backup_sync_ws::server: received message kind=\"Ping\""
+    ));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_folder_operation_logs_message_fields() {
+    let (addr, state, _shutdown) = start_traced_server().await;
+    add_folder(&state).await;
+    let mut ws_origin = connect_and_auth(addr, "user1", "comp1").await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+
+    send_message(&mut ws_origin, &create_dir("dir")).await;
+    assert!(matches!(
+        receive_message(&mut ws_backup).await,
+        ServerMessage::FolderOperation { .. }
+    ));
+
+    assert!(logs_contain(
+        "received message kind=\"FolderOperation\" folder_id=folder1"
+    ));
+    assert!(logs_contain(
+        "forwarding operation folder_id=folder1 operation_id=1 sequence=1"
+    ));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_error_responses_are_logged_as_warnings() {
+    let (addr, state, _shutdown) = start_traced_server().await;
+    add_folder(&state).await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+
+    send_message(&mut ws_backup, &create_dir("dir")).await;
+    assert!(matches!(
+        receive_message(&mut ws_backup).await,
+        ServerMessage::Error { .. }
+    ));
+
+    assert!(logs_contain("WARN"));
+    assert!(logs_contain(
+        "answering with an error kind=\"FolderOperation\" error=Only origin computer can send operations"
+    ));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_error_responses_quote_the_logged_trace_id() {
+    let (addr, state, _shutdown) = start_traced_server().await;
+    add_folder(&state).await;
+    let mut ws_backup = connect_and_auth(addr, "user1", "comp2").await;
+
+    send_message(&mut ws_backup, &create_dir("dir")).await;
+    let ServerMessage::Error {
+        trace_id: Some(trace_id),
+        ..
+    } = receive_message(&mut ws_backup).await
+    else {
+        panic!("Expected an error with a trace id");
+    };
+
+    assert!(logs_contain(&format!(
+        "message{{trace_id={trace_id}}}: backup_sync_ws::server: answering with an error"
+    )));
+    assert!(logs_contain(&format!(
+        "message{{trace_id={trace_id}}}: backup_sync_ws::server: received message kind=\"FolderOperation\""
+    )));
+}